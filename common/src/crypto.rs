@@ -11,12 +11,31 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
+pub mod stream;
+
+use std::{fmt, str::FromStr};
+
 use rcgen::{
-    Certificate, CertificateParams, CertificateSigningRequest, CertificateSigningRequestParams,
-    CertifiedKey, Error, KeyPair, SanType,
+    Attribute, Certificate, CertificateParams, CertificateSigningRequest,
+    CertificateSigningRequestParams, CertifiedKey, Error, KeyPair, PublicKeyData, SanType,
+    SerialNumber, SignatureAlgorithm,
 };
+use ring::{
+    hkdf::{self, KeyType, HKDF_SHA256},
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+        ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P384_SHA384_ASN1, ECDSA_P384_SHA384_ASN1_SIGNING,
+        ED25519,
+    },
+};
+use time::OffsetDateTime;
 use x509_parser::{
-    certificate::X509Certificate, der_parser::asn1_rs::FromDer, extensions::GeneralName,
+    certificate::X509Certificate,
+    certification_request::X509CertificationRequest,
+    cri_attributes::{ChallengePassword, ParsedCriAttribute},
+    der_parser::asn1_rs::FromDer,
+    extensions::GeneralName,
 };
 
 /// Load a CA certificate and key pair from PEM strings.
@@ -27,9 +46,18 @@ use x509_parser::{
 pub fn load_ca_and_sign_cert(
     ca_cert_pem: &str,
     ca_key_pair_pem: &str,
+) -> Result<CertifiedKey, Error> {
+    load_ca_with_key_pair(ca_cert_pem, KeyPair::from_pem(ca_key_pair_pem)?)
+}
+
+/// Load a CA certificate from a PEM string together with its key pair, which can be a
+/// [remote](KeyPair::from_remote) key pair whose private key never leaves an HSM or a KMS.
+/// See [`load_ca_and_sign_cert`].
+pub fn load_ca_with_key_pair(
+    ca_cert_pem: &str,
+    ca_key_pair: KeyPair,
 ) -> Result<CertifiedKey, Error> {
     let params = CertificateParams::from_ca_cert_pem(ca_cert_pem)?;
-    let ca_key_pair = KeyPair::from_pem(ca_key_pair_pem)?;
     let cert = params.self_signed(&ca_key_pair)?;
     Ok(CertifiedKey {
         key_pair: ca_key_pair,
@@ -37,6 +65,20 @@ pub fn load_ca_and_sign_cert(
     })
 }
 
+/// The validity window of an issued certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+}
+
+impl Validity {
+    fn apply(&self, params: &mut CertificateParams) {
+        params.not_before = self.not_before;
+        params.not_after = self.not_after;
+    }
+}
+
 /// Create a client certificate and private key signed by the given CA.
 pub fn mk_client_certificate(ca_certified_key: &CertifiedKey) -> Result<CertifiedKey, Error> {
     // Create a client end entity cert issued by the CA.
@@ -44,10 +86,8 @@ pub fn mk_client_certificate(ca_certified_key: &CertifiedKey) -> Result<Certifie
     client_ee_params
         .distinguished_name
         .push(rcgen::DnType::CommonName, "Example Client");
-    client_ee_params.is_ca = rcgen::IsCa::NoCa;
-    client_ee_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
-    // TODO: Set the serial number to respect uniqueness requirements: https://www.rfc-editor.org/rfc/rfc5280#section-4.1.2.2
-    client_ee_params.serial_number = Some(rcgen::SerialNumber::from(vec![0xC0, 0xFF, 0xEE]));
+    CertificateProfile::ClientAuth.apply(&mut client_ee_params);
+    client_ee_params.serial_number = Some(mk_serial_number()?);
     let client_key = mk_ee_key_pair()?;
     let client_cert = client_ee_params.signed_by(
         &client_key,
@@ -62,12 +102,69 @@ pub fn mk_client_certificate(ca_certified_key: &CertifiedKey) -> Result<Certifie
 
 /// Create a server certificate and private key signed by the given CA.
 pub fn mk_server_certificate(ca_certified_key: &CertifiedKey) -> Result<CertifiedKey, Error> {
-    // Create a server end entity cert issued by the CA.
+    let server_ee_params =
+        CertificateParams::new(vec!["127.0.0.1".to_string(), "localhost".to_string()])?;
+    sign_server_certificate(
+        server_ee_params,
+        CertificateProfile::ServerAuth,
+        ca_certified_key,
+    )
+}
+
+/// Create a server certificate and private key signed by the given CA, valid only in the given window.
+pub fn mk_server_certificate_with_validity(
+    ca_certified_key: &CertifiedKey,
+    validity: &Validity,
+) -> Result<CertifiedKey, Error> {
+    mk_service_certificate(ca_certified_key, CertificateProfile::ServerAuth, validity)
+}
+
+/// Create a certificate for a service reachable at `localhost` for the given key pair, e.g. generated in advance to
+/// publish its pin before the rotation, signed by the given CA with the given profile, valid only in the given window.
+pub fn mk_service_certificate_for_key(
+    ca_certified_key: &CertifiedKey,
+    profile: CertificateProfile,
+    validity: &Validity,
+    key_pair: KeyPair,
+) -> Result<CertifiedKey, Error> {
     let mut server_ee_params =
         CertificateParams::new(vec!["127.0.0.1".to_string(), "localhost".to_string()])?;
-    server_ee_params.is_ca = rcgen::IsCa::NoCa;
-    server_ee_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
-    let ee_key = mk_ee_key_pair()?;
+    validity.apply(&mut server_ee_params);
+    sign_server_certificate_for_key(server_ee_params, profile, ca_certified_key, key_pair)
+}
+
+/// Create a certificate and private key for a service reachable at `localhost`, signed by the given CA with the
+/// given profile, valid only in the given window.
+pub fn mk_service_certificate(
+    ca_certified_key: &CertifiedKey,
+    profile: CertificateProfile,
+    validity: &Validity,
+) -> Result<CertifiedKey, Error> {
+    mk_service_certificate_for_key(ca_certified_key, profile, validity, mk_ee_key_pair()?)
+}
+
+fn sign_server_certificate(
+    server_ee_params: CertificateParams,
+    profile: CertificateProfile,
+    ca_certified_key: &CertifiedKey,
+) -> Result<CertifiedKey, Error> {
+    sign_server_certificate_for_key(
+        server_ee_params,
+        profile,
+        ca_certified_key,
+        mk_ee_key_pair()?,
+    )
+}
+
+fn sign_server_certificate_for_key(
+    mut server_ee_params: CertificateParams,
+    profile: CertificateProfile,
+    ca_certified_key: &CertifiedKey,
+    ee_key: KeyPair,
+) -> Result<CertifiedKey, Error> {
+    // Create a server end entity cert issued by the CA.
+    profile.apply(&mut server_ee_params);
+    server_ee_params.serial_number = Some(mk_serial_number()?);
     let server_cert =
         server_ee_params.signed_by(&ee_key, &ca_certified_key.cert, &ca_certified_key.key_pair)?;
     Ok(CertifiedKey {
@@ -97,6 +194,7 @@ pub fn mk_issuer_ca_from_keys(key_pair: &KeyPair) -> Result<Certificate, Error>
         .distinguished_name
         .push(rcgen::DnType::CommonName, "Example CA");
     ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    ca_params.serial_number = Some(mk_serial_number()?);
     ca_params.key_usages = vec![
         rcgen::KeyUsagePurpose::KeyCertSign,
         rcgen::KeyUsagePurpose::DigitalSignature,
@@ -106,13 +204,188 @@ pub fn mk_issuer_ca_from_keys(key_pair: &KeyPair) -> Result<Certificate, Error>
     Ok(ca_cert)
 }
 
-/// Create a new client certificate request with the given email address.
+/// Create an intermediate issuing CA certificate and private key signed by the given root CA.
+/// The intermediate CA can only sign end entity certificates, so that the root CA can be kept offline.
+pub fn mk_intermediate_ca(root_certified_key: &CertifiedKey) -> Result<CertifiedKey, Error> {
+    let mut ca_params = rcgen::CertificateParams::new(Vec::new())?;
+    ca_params
+        .distinguished_name
+        .push(rcgen::DnType::OrganizationName, "Rustls Server Acceptor");
+    ca_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "Example Intermediate CA");
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Constrained(0));
+    ca_params.serial_number = Some(mk_serial_number()?);
+    ca_params.key_usages = vec![
+        rcgen::KeyUsagePurpose::KeyCertSign,
+        rcgen::KeyUsagePurpose::DigitalSignature,
+        rcgen::KeyUsagePurpose::CrlSign,
+    ];
+    let ca_key = mk_ee_key_pair()?;
+    let ca_cert = ca_params.signed_by(
+        &ca_key,
+        &root_certified_key.cert,
+        &root_certified_key.key_pair,
+    )?;
+    Ok(CertifiedKey {
+        key_pair: ca_key,
+        cert: ca_cert,
+    })
+}
+
+/// The algorithms of the end entity key pairs, used to generate them and to check the certificate requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgorithm {
+    /// ECDSA on the P-256 curve with SHA-256.
+    #[default]
+    EcdsaP256,
+    /// ECDSA on the P-384 curve with SHA-384.
+    EcdsaP384,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    /// All the supported algorithms.
+    pub const ALL: [KeyAlgorithm; 3] = [
+        KeyAlgorithm::EcdsaP256,
+        KeyAlgorithm::EcdsaP384,
+        KeyAlgorithm::Ed25519,
+    ];
+
+    /// The name of the algorithm, as used in the configuration files and in the wasm bindings.
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyAlgorithm::EcdsaP256 => "ecdsa-p256",
+            KeyAlgorithm::EcdsaP384 => "ecdsa-p384",
+            KeyAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    /// The signature algorithm of the key pairs.
+    pub fn signature_algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            KeyAlgorithm::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyAlgorithm::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            KeyAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+        }
+    }
+
+    /// Returns the algorithm of the signature algorithm, if supported.
+    pub fn from_signature_algorithm(algorithm: &SignatureAlgorithm) -> Option<Self> {
+        KeyAlgorithm::ALL
+            .into_iter()
+            .find(|key_algorithm| key_algorithm.signature_algorithm() == algorithm)
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        KeyAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+            .ok_or_else(|| format!("Unsupported key algorithm `{}`", name))
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The issuance profiles of the end entity certificates, fixing their key usages, extended key usages and the
+/// Subject alt names they can have. The profile of a certificate request is enforced when signing it, whatever
+/// extensions the request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CertificateProfile {
+    /// A client authenticating with mutual TLS, identified only by its email.
+    #[default]
+    ClientAuth,
+    /// A TLS server, identified by its DNS names or IP addresses and the email of its operator.
+    ServerAuth,
+    /// The DS (Delivery Service), a TLS server which is also a client of the PKI.
+    DsService,
+}
+
+impl CertificateProfile {
+    /// All the supported profiles.
+    pub const ALL: [CertificateProfile; 3] = [
+        CertificateProfile::ClientAuth,
+        CertificateProfile::ServerAuth,
+        CertificateProfile::DsService,
+    ];
+
+    /// The name of the profile, as used in the configuration files and in the requests.
+    pub fn name(self) -> &'static str {
+        match self {
+            CertificateProfile::ClientAuth => "client-auth",
+            CertificateProfile::ServerAuth => "server-auth",
+            CertificateProfile::DsService => "ds-service",
+        }
+    }
+
+    /// The extended key usages of the certificates of the profile.
+    pub fn extended_key_usages(self) -> Vec<rcgen::ExtendedKeyUsagePurpose> {
+        match self {
+            CertificateProfile::ClientAuth => vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth],
+            CertificateProfile::ServerAuth => vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth],
+            CertificateProfile::DsService => vec![
+                rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+                rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+            ],
+        }
+    }
+
+    /// Whether the Subject alt names are allowed for the email: the email must be present and be the only one,
+    /// only the server profiles can have DNS names and IP addresses as well.
+    pub fn allows_subject_alt_names(self, subject_alt_names: &[SanType], email: &str) -> bool {
+        let mut has_email = false;
+        for san in subject_alt_names {
+            match san {
+                SanType::Rfc822Name(name) if name.as_str() == email => has_email = true,
+                SanType::DnsName(_) | SanType::IpAddress(_)
+                    if self != CertificateProfile::ClientAuth => {}
+                _ => return false,
+            }
+        }
+        has_email
+    }
+
+    /// Set the extensions of the profile in the parameters of an end entity certificate.
+    fn apply(self, params: &mut CertificateParams) {
+        params.is_ca = rcgen::IsCa::NoCa;
+        params.key_usages = vec![rcgen::KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = self.extended_key_usages();
+    }
+}
+
+impl FromStr for CertificateProfile {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        CertificateProfile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name)
+            .ok_or_else(|| format!("Unknown certificate profile `{}`", name))
+    }
+}
+
+impl fmt::Display for CertificateProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Create a new client certificate request with the given email address, for a new key pair of the given algorithm.
 /// The email is represented in the certificate as a Subject alt name as in RFC5280.
 /// See [`Rfc822Name`](rcgen::SanType::Rfc822Name) for more details.
 pub fn mk_client_certificate_request_params(
     email: &str,
+    algorithm: KeyAlgorithm,
 ) -> Result<(KeyPair, CertificateSigningRequest), Error> {
-    let key_pair = mk_ee_key_pair()?;
+    let key_pair = mk_ee_key_pair_for(algorithm)?;
     let mut params = CertificateParams::default();
     params.subject_alt_names = vec![SanType::Rfc822Name(email.try_into()?)];
     let certificate_request = params.serialize_request(&key_pair)?;
@@ -120,7 +393,37 @@ pub fn mk_client_certificate_request_params(
 }
 
 pub fn mk_ee_key_pair() -> Result<KeyPair, Error> {
-    KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)
+    mk_ee_key_pair_for(KeyAlgorithm::default())
+}
+
+/// Create a new key pair of the given algorithm.
+pub fn mk_ee_key_pair_for(algorithm: KeyAlgorithm) -> Result<KeyPair, Error> {
+    KeyPair::generate_for(algorithm.signature_algorithm())
+}
+
+/// Check that the key of the certificate request uses one of the allowed algorithms.
+fn check_key_algorithm(
+    params: &CertificateSigningRequestParams,
+    allowed: &[KeyAlgorithm],
+) -> Result<(), Error> {
+    match KeyAlgorithm::from_signature_algorithm(params.public_key.algorithm()) {
+        Some(algorithm) if allowed.contains(&algorithm) => Ok(()),
+        _ => Err(Error::UnsupportedSignatureAlgorithm),
+    }
+}
+
+/// The length in bytes of the serial numbers, 126 of the 128 bits are random.
+const SERIAL_NUMBER_LENGTH: usize = 16;
+
+/// Create a random serial number, unique with overwhelming probability as required by
+/// [RFC 5280](https://www.rfc-editor.org/rfc/rfc5280#section-4.1.2.2).
+/// The serial number is positive and always has the same length, it is not reused by renewals of the same key pair.
+pub fn mk_serial_number() -> Result<SerialNumber, Error> {
+    let mut serial = [0u8; SERIAL_NUMBER_LENGTH];
+    getrandom::getrandom(&mut serial).map_err(|_| Error::RingUnspecified)?;
+    // Clear the sign bit and set the next one, so that the DER encoding is neither negative nor shorter.
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+    Ok(SerialNumber::from_slice(&serial))
 }
 
 /// Sign the given certificate signing request.
@@ -128,38 +431,81 @@ pub fn sign_request(
     signing_request: CertificateSigningRequest,
     ca_certified_key: &CertifiedKey,
 ) -> Result<Certificate, Error> {
-    let params = CertificateSigningRequestParams::from_pem(&signing_request.pem()?)?;
+    let mut params = CertificateSigningRequestParams::from_pem(&signing_request.pem()?)?;
+    params.params.serial_number = Some(mk_serial_number()?);
     params.signed_by(&ca_certified_key.cert, &ca_certified_key.key_pair)
 }
 
 /// Sing the given certificate signing request from a PEM string.
+/// The key of the request must use one of the allowed algorithms.
 pub fn sign_request_from_pem(
     signing_request_pem: &str,
     ca_certified_key: &CertifiedKey,
+    key_algorithms: &[KeyAlgorithm],
 ) -> Result<Certificate, Error> {
-    let params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
+    let mut params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
+    check_key_algorithm(&params, key_algorithms)?;
+    params.params.serial_number = Some(mk_serial_number()?);
     params.signed_by(&ca_certified_key.cert, &ca_certified_key.key_pair)
 }
 
 /// Sign the given certificate signing request from a PEM string and check if the email is valid.
-/// The email is checked against the Subject alt names in the certificate signing request.
+/// The email is checked against the Subject alt names in the certificate signing request, as for a
+/// [`CertificateProfile::ClientAuth`] certificate.
+/// The certificate is valid only in the given window, and the key of the request must use one of the allowed algorithms.
 pub fn sign_request_from_pem_and_check_email(
     signing_request_pem: &str,
     ca_certified_key: &CertifiedKey,
     email: &str,
+    validity: &Validity,
+    key_algorithms: &[KeyAlgorithm],
 ) -> Result<Certificate, Error> {
-    let params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
-    let validate_email = params.params.subject_alt_names.iter().any(|san| {
-        if let SanType::Rfc822Name(s) = san {
-            return s.as_str() == email;
-        }
-        false
-    });
-    if !validate_email {
+    sign_request_from_pem_with_profile(
+        signing_request_pem,
+        ca_certified_key,
+        email,
+        CertificateProfile::ClientAuth,
+        validity,
+        key_algorithms,
+    )
+}
+
+/// Sign the given certificate signing request from a PEM string for the email, with the given profile.
+/// The Subject alt names of the request must be allowed by the profile, whose extensions replace the requested ones.
+/// The certificate is valid only in the given window, and the key of the request must use one of the allowed algorithms.
+pub fn sign_request_from_pem_with_profile(
+    signing_request_pem: &str,
+    ca_certified_key: &CertifiedKey,
+    email: &str,
+    profile: CertificateProfile,
+    validity: &Validity,
+    key_algorithms: &[KeyAlgorithm],
+) -> Result<Certificate, Error> {
+    let mut params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
+    check_key_algorithm(&params, key_algorithms)?;
+    if !profile.allows_subject_alt_names(&params.params.subject_alt_names, email) {
         return Err(Error::InvalidNameType);
-    } else {
-        sign_request_from_pem(signing_request_pem, ca_certified_key)
     }
+    profile.apply(&mut params.params);
+    validity.apply(&mut params.params);
+    params.params.serial_number = Some(mk_serial_number()?);
+    params.signed_by(&ca_certified_key.cert, &ca_certified_key.key_pair)
+}
+
+/// Retrieves all emails from the Subject alt names of a PEM-encoded certificate signing request.
+pub fn retrieve_emails_from_certificate_request(
+    signing_request_pem: &str,
+) -> Result<Vec<String>, Error> {
+    let params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
+    Ok(params
+        .params
+        .subject_alt_names
+        .iter()
+        .filter_map(|san| match san {
+            SanType::Rfc822Name(email) => Some(email.as_str().to_string()),
+            _ => None,
+        })
+        .collect())
 }
 
 /// Retrieves all emails from a PEM-encoded Certificate (using [`x509_parser`]).
@@ -203,6 +549,158 @@ pub fn check_signature(
     Ok::<bool, Box<dyn std::error::Error>>(cert.verify_signature(Some(issuer.public_key())).is_ok())
 }
 
+/// Check if the certificate, in PEM format, is within its validity window at the given time.
+pub fn check_validity(
+    certificate: &str,
+    at: OffsetDateTime,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let der = pem::parse(certificate)?;
+    let (_, cert) = X509Certificate::from_der(der.contents())?;
+    let at = x509_parser::time::ASN1Time::from(at);
+    Ok(cert.validity().not_before <= at && at <= cert.validity().not_after)
+}
+
+/// The context prepended to the registration challenges before signing them, so that the proofs of possession
+/// cannot be confused with the signatures of other protocols using the same key.
+const CHALLENGE_CONTEXT: &[u8] = b"shared-folder PKI registration challenge\0";
+
+/// Sign the registration challenge of the PKI with the PEM encoded private key of a certificate request,
+/// proving the possession of the key. The ECDSA signatures are ASN.1 DER encoded.
+pub fn sign_challenge(key_pair_pem: &str, challenge: &str) -> Result<Vec<u8>, String> {
+    let key_pair = KeyPair::from_pem(key_pair_pem).map_err(|e| e.to_string())?;
+    let message = [CHALLENGE_CONTEXT, challenge.as_bytes()].concat();
+    let pkcs8 = key_pair.serialize_der();
+    let rng = SystemRandom::new();
+    let ecdsa = |algorithm| {
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(algorithm, &pkcs8, &rng).map_err(|e| e.to_string())?;
+        key_pair
+            .sign(&rng, &message)
+            .map(|signature| signature.as_ref().to_vec())
+            .map_err(|e| e.to_string())
+    };
+    match KeyAlgorithm::from_signature_algorithm(key_pair.algorithm()) {
+        Some(KeyAlgorithm::EcdsaP256) => ecdsa(&ECDSA_P256_SHA256_ASN1_SIGNING),
+        Some(KeyAlgorithm::EcdsaP384) => ecdsa(&ECDSA_P384_SHA384_ASN1_SIGNING),
+        Some(KeyAlgorithm::Ed25519) => Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+            .map(|key_pair| key_pair.sign(&message).as_ref().to_vec())
+            .map_err(|e| e.to_string()),
+        None => Err(Error::UnsupportedSignatureAlgorithm.to_string()),
+    }
+}
+
+/// Check the signature of the registration challenge with the public key of the PEM encoded certificate request,
+/// see [`sign_challenge`].
+pub fn verify_challenge_signature(
+    signing_request_pem: &str,
+    challenge: &str,
+    signature: &[u8],
+) -> Result<bool, Error> {
+    let params = CertificateSigningRequestParams::from_pem(signing_request_pem)?;
+    let algorithm: &'static dyn ring::signature::VerificationAlgorithm =
+        match KeyAlgorithm::from_signature_algorithm(params.public_key.algorithm()) {
+            Some(KeyAlgorithm::EcdsaP256) => &ECDSA_P256_SHA256_ASN1,
+            Some(KeyAlgorithm::EcdsaP384) => &ECDSA_P384_SHA384_ASN1,
+            Some(KeyAlgorithm::Ed25519) => &ED25519,
+            None => return Err(Error::UnsupportedSignatureAlgorithm),
+        };
+    let message = [CHALLENGE_CONTEXT, challenge.as_bytes()].concat();
+    Ok(
+        UnparsedPublicKey::new(algorithm, params.public_key.der_bytes())
+            .verify(&message, signature)
+            .is_ok(),
+    )
+}
+
+/// The OID of the PKCS #9 `challengePassword` attribute of the certificate requests,
+/// see [RFC 2985](https://www.rfc-editor.org/rfc/rfc2985#section-5.4.1).
+const CHALLENGE_PASSWORD_OID: &[u64] = &[1, 2, 840, 113549, 1, 9, 7];
+
+/// Encode a DER tag-length-value, e.g. the challenge password as an `UTF8String`.
+fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+    if value.len() < 0x80 {
+        tlv.push(value.len() as u8);
+    } else {
+        let length = value.len().to_be_bytes();
+        let length = &length[length.iter().take_while(|byte| **byte == 0).count()..];
+        tlv.push(0x80 | length.len() as u8);
+        tlv.extend_from_slice(length);
+    }
+    tlv.extend_from_slice(value);
+    tlv
+}
+
+/// Create a new client certificate request as [`mk_client_certificate_request_params`], with the registration
+/// challenge of the PKI as its `challengePassword` attribute, as expected by the EST enrollment.
+/// The request is signed by its private key, so that the challenge proves the possession of the key.
+pub fn mk_client_certificate_request_with_challenge(
+    email: &str,
+    algorithm: KeyAlgorithm,
+    challenge: &str,
+) -> Result<(KeyPair, CertificateSigningRequest), Error> {
+    let key_pair = mk_ee_key_pair_for(algorithm)?;
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = vec![SanType::Rfc822Name(email.try_into()?)];
+    // The values of the attribute are a SET with a single UTF8String.
+    let challenge_password = Attribute {
+        oid: CHALLENGE_PASSWORD_OID,
+        values: der_tlv(0x31, &der_tlv(0x0c, challenge.as_bytes())),
+    };
+    let certificate_request =
+        params.serialize_request_with_attributes(&key_pair, vec![challenge_password])?;
+    Ok((key_pair, certificate_request))
+}
+
+/// Retrieves the `challengePassword` attribute of a PEM-encoded certificate signing request, if any,
+/// once the signature of the request is verified.
+pub fn retrieve_challenge_password_from_certificate_request(
+    signing_request_pem: &str,
+) -> Result<Option<String>, String> {
+    CertificateSigningRequestParams::from_pem(signing_request_pem).map_err(|e| e.to_string())?;
+    let der = pem::parse(signing_request_pem).map_err(|e| e.to_string())?;
+    let (_, request) =
+        X509CertificationRequest::from_der(der.contents()).map_err(|e| e.to_string())?;
+    let challenge_password = request
+        .certification_request_info
+        .iter_attributes()
+        .find_map(|attribute| match attribute.parsed_attribute() {
+            ParsedCriAttribute::ChallengePassword(ChallengePassword(password)) => {
+                Some(password.clone())
+            }
+            _ => None,
+        });
+    Ok(challenge_password)
+}
+
+/// The length of the output of HKDF, as a [`KeyType`] for `ring`.
+struct OutputLength(usize);
+
+impl KeyType for OutputLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Derive `len` bytes from the input keying material with HKDF-SHA256 ([RFC 5869](https://www.rfc-editor.org/rfc/rfc5869)),
+/// e.g. the key of a file from the secret of its folder. The salt may be empty, `len` is at most 255 * 32 bytes.
+pub fn hkdf_extract_expand(
+    salt: &[u8],
+    ikm: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    let prk = hkdf::Salt::new(HKDF_SHA256, salt).extract(ikm);
+    let info = [info];
+    let okm = prk
+        .expand(&info, OutputLength(len))
+        .map_err(|_| format!("Can't derive {} bytes with HKDF-SHA256", len))?;
+    let mut output = vec![0u8; len];
+    okm.fill(&mut output)
+        .map_err(|_| format!("Can't derive {} bytes with HKDF-SHA256", len))?;
+    Ok(output)
+}
+
 pub fn retrieve_der_pk_from_certificate(pem_certificate: &str) -> Result<Vec<u8>, String> {
     let (_, pem) =
         x509_parser::pem::parse_x509_pem(pem_certificate.as_bytes()).map_err(|e| e.to_string())?;
@@ -225,7 +723,7 @@ mod tests {
         let issuer = mk_issuer_ca()?;
 
         let (_, certificate_signing_request) =
-            mk_client_certificate_request_params("test@test.com")?;
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
         let cert = sign_request(certificate_signing_request, &issuer)?;
 
         assert!(check_signature(&cert.pem(), &issuer.cert.pem()).is_ok());
@@ -265,4 +763,237 @@ mod tests {
         assert!(check_signature(&server_cert.cert.pem(), &ca_certified_key.cert.pem()).is_ok());
         Ok(())
     }
+
+    #[test]
+    fn sign_with_loaded_intermediate_ca() -> Result<(), Error> {
+        let root = mk_issuer_ca()?;
+        let intermediate = mk_intermediate_ca(&root)?;
+        // The intermediate CA is loaded without the root CA, which is kept offline.
+        let loaded_intermediate = load_ca_and_sign_cert(
+            &intermediate.cert.pem(),
+            &intermediate.key_pair.serialize_pem(),
+        )?;
+        let client_cert = mk_client_certificate(&loaded_intermediate)?;
+
+        assert!(check_signature(&intermediate.cert.pem(), &root.cert.pem()).unwrap());
+        assert!(check_signature(&client_cert.cert.pem(), &intermediate.cert.pem()).unwrap());
+        assert!(!check_signature(&client_cert.cert.pem(), &root.cert.pem()).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn unique_serial_numbers() -> Result<(), Box<dyn std::error::Error>> {
+        let issuer = mk_issuer_ca()?;
+        let (_, certificate_signing_request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
+        let pem = certificate_signing_request.pem()?;
+        // Signing the same request twice must still give two distinct serial numbers.
+        let serials = [
+            sign_request_from_pem(&pem, &issuer, &KeyAlgorithm::ALL)?,
+            sign_request_from_pem(&pem, &issuer, &KeyAlgorithm::ALL)?,
+            mk_client_certificate(&issuer)?.cert,
+            mk_client_certificate(&issuer)?.cert,
+        ]
+        .iter()
+        .map(|cert| {
+            let (_, x509) = X509Certificate::from_der(cert.der()).unwrap();
+            x509.raw_serial().to_vec()
+        })
+        .collect::<Vec<_>>();
+        for (i, serial) in serials.iter().enumerate() {
+            assert_eq!(serial.len(), SERIAL_NUMBER_LENGTH);
+            assert!(serial[0] & 0x80 == 0);
+            assert!(!serials[i + 1..].contains(serial));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sign_request_with_validity() -> Result<(), Box<dyn std::error::Error>> {
+        let issuer = mk_issuer_ca()?;
+        let (_, certificate_signing_request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
+        let not_before = OffsetDateTime::from_unix_timestamp(1_700_000_000)?;
+        let validity = Validity {
+            not_before,
+            not_after: not_before + time::Duration::days(30),
+        };
+        let cert = sign_request_from_pem_and_check_email(
+            &certificate_signing_request.pem()?,
+            &issuer,
+            "test@test.com",
+            &validity,
+            &KeyAlgorithm::ALL,
+        )?;
+
+        assert!(check_validity(
+            &cert.pem(),
+            not_before + time::Duration::days(1)
+        )?);
+        assert!(!check_validity(
+            &cert.pem(),
+            not_before - time::Duration::days(1)
+        )?);
+        assert!(!check_validity(
+            &cert.pem(),
+            not_before + time::Duration::days(31)
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn sign_request_with_profiles() -> Result<(), Box<dyn std::error::Error>> {
+        let issuer = mk_issuer_ca()?;
+        let validity = Validity {
+            not_before: OffsetDateTime::now_utc(),
+            not_after: OffsetDateTime::now_utc() + time::Duration::days(1),
+        };
+        let key_pair = mk_ee_key_pair()?;
+        let mut params = CertificateParams::new(vec!["localhost".to_string()])?;
+        params
+            .subject_alt_names
+            .push(SanType::Rfc822Name("ops@test.com".try_into()?));
+        // The requested extensions are replaced by the ones of the profile.
+        params.key_usages = vec![rcgen::KeyUsagePurpose::KeyCertSign];
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::CodeSigning];
+        let pem = params.serialize_request(&key_pair)?.pem()?;
+        let sign = |profile, email| {
+            sign_request_from_pem_with_profile(
+                &pem,
+                &issuer,
+                email,
+                profile,
+                &validity,
+                &KeyAlgorithm::ALL,
+            )
+        };
+
+        // A client certificate is only for the email.
+        assert!(sign(CertificateProfile::ClientAuth, "ops@test.com").is_err());
+        assert!(sign(CertificateProfile::ServerAuth, "other@test.com").is_err());
+        let cert = sign(CertificateProfile::DsService, "ops@test.com")?;
+        let (_, x509) = X509Certificate::from_der(cert.der())?;
+        assert!(!x509.is_ca());
+        let extended_key_usage = x509.extended_key_usage()?.unwrap().value;
+        assert!(extended_key_usage.server_auth && extended_key_usage.client_auth);
+        assert!(!extended_key_usage.code_signing);
+        let key_usage = x509.key_usage()?.unwrap().value;
+        assert!(key_usage.digital_signature() && !key_usage.key_cert_sign());
+
+        for profile in CertificateProfile::ALL {
+            assert_eq!(profile.name().parse::<CertificateProfile>()?, profile);
+        }
+        assert!("code-signing".parse::<CertificateProfile>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn retrieve_emails_from_request() -> Result<(), Box<dyn std::error::Error>> {
+        let (_, certificate_signing_request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
+        let emails = retrieve_emails_from_certificate_request(&certificate_signing_request.pem()?)?;
+
+        assert_eq!(emails, vec!["test@test.com".to_string()]);
+        assert!(retrieve_emails_from_certificate_request("not a request").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sign_request_with_key_algorithms() -> Result<(), Box<dyn std::error::Error>> {
+        let issuer = mk_issuer_ca()?;
+        for algorithm in KeyAlgorithm::ALL {
+            assert_eq!(algorithm.name().parse::<KeyAlgorithm>()?, algorithm);
+            let (key_pair, certificate_signing_request) =
+                mk_client_certificate_request_params("test@test.com", algorithm)?;
+            assert_eq!(key_pair.algorithm(), algorithm.signature_algorithm());
+            let pem = certificate_signing_request.pem()?;
+
+            let cert = sign_request_from_pem(&pem, &issuer, &KeyAlgorithm::ALL)?;
+            assert!(check_signature(&cert.pem(), &issuer.cert.pem())?);
+            // Only the algorithms in the allow-list are accepted.
+            let only_p256 = sign_request_from_pem(&pem, &issuer, &[KeyAlgorithm::EcdsaP256]);
+            assert_eq!(only_p256.is_ok(), algorithm == KeyAlgorithm::EcdsaP256);
+        }
+        assert!("rsa".parse::<KeyAlgorithm>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sign_and_verify_challenge() -> Result<(), Box<dyn std::error::Error>> {
+        for algorithm in KeyAlgorithm::ALL {
+            let (key_pair, certificate_signing_request) =
+                mk_client_certificate_request_params("test@test.com", algorithm)?;
+            let pem = certificate_signing_request.pem()?;
+            let signature = sign_challenge(&key_pair.serialize_pem(), "challenge")?;
+
+            assert!(verify_challenge_signature(&pem, "challenge", &signature)?);
+            assert!(!verify_challenge_signature(
+                &pem,
+                "other challenge",
+                &signature
+            )?);
+            // The signature of another key, e.g. of a replayed certificate request, is rejected.
+            let other = mk_ee_key_pair_for(algorithm)?;
+            let other_signature = sign_challenge(&other.serialize_pem(), "challenge")?;
+            assert!(!verify_challenge_signature(
+                &pem,
+                "challenge",
+                &other_signature
+            )?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retrieve_challenge_password_from_request() -> Result<(), Box<dyn std::error::Error>> {
+        let issuer = mk_issuer_ca()?;
+        let long_challenge = "c".repeat(300);
+        for (algorithm, challenge) in KeyAlgorithm::ALL
+            .into_iter()
+            .map(|algorithm| (algorithm, "challenge"))
+            .chain([(KeyAlgorithm::default(), long_challenge.as_str())])
+        {
+            let (_, certificate_signing_request) = mk_client_certificate_request_with_challenge(
+                "test@test.com",
+                algorithm,
+                challenge,
+            )?;
+            let pem = certificate_signing_request.pem()?;
+
+            assert_eq!(
+                retrieve_challenge_password_from_certificate_request(&pem)?,
+                Some(challenge.to_string())
+            );
+            assert!(sign_request_from_pem(&pem, &issuer, &KeyAlgorithm::ALL).is_ok());
+        }
+        let (_, certificate_signing_request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
+        assert_eq!(
+            retrieve_challenge_password_from_certificate_request(
+                &certificate_signing_request.pem()?
+            )?,
+            None
+        );
+        assert!(retrieve_challenge_password_from_certificate_request("not a request").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hkdf_rfc5869_test_vector() -> Result<(), Box<dyn std::error::Error>> {
+        // Test case 1 of RFC 5869, Appendix A.
+        let ikm = [0x0b; 22];
+        let salt = (0x00..=0x0c).collect::<Vec<u8>>();
+        let info = (0xf0..=0xf9).collect::<Vec<u8>>();
+        let okm = hkdf_extract_expand(&salt, &ikm, &info, 42)?;
+        let expected =
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865";
+        let okm = okm.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(okm, expected);
+        assert_eq!(
+            hkdf_extract_expand(&[], &ikm, &[], 255 * 32)?.len(),
+            255 * 32
+        );
+        assert!(hkdf_extract_expand(&[], &ikm, &[], 255 * 32 + 1).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,225 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! Chunked AES-256-GCM encryption of the files, so that they can be encrypted and decrypted incrementally,
+//! e.g. in the browser before uploading them to the DS, without holding the whole file in memory.
+//! The chunks are sealed following the STREAM construction of
+//! [Hoang, Reyhanitabar, Rogaway and Vizár](https://eprint.iacr.org/2015/189): the nonce of a chunk is the random
+//! prefix of the stream, the big-endian counter of the chunk and a flag set only for the last chunk.
+//! The chunks can't be reordered, dropped or appended, and a truncated stream is detected because its last
+//! chunk is not flagged. The prefix is the header of the stream, sent before the chunks.
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// The length of the key, in bytes.
+pub const KEY_LENGTH: usize = 32;
+/// The length of the header of a stream, the random prefix of the nonces, in bytes.
+pub const HEADER_LENGTH: usize = NONCE_LEN - 5;
+/// The length of the authentication tag appended to each chunk, in bytes.
+pub const TAG_LENGTH: usize = 16;
+/// The recommended size of the plaintext chunks, in bytes.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The nonces of the chunks of a stream, see the module documentation.
+struct NonceSequence {
+    prefix: [u8; HEADER_LENGTH],
+    counter: u32,
+    finished: bool,
+}
+
+impl NonceSequence {
+    /// The nonce of the next chunk, failing after the last chunk or once the counter is exhausted.
+    fn next(&mut self, last: bool) -> Result<Nonce, String> {
+        if self.finished {
+            return Err("The stream is already finished".to_string());
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..HEADER_LENGTH].copy_from_slice(&self.prefix);
+        nonce[HEADER_LENGTH..NONCE_LEN - 1].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[NONCE_LEN - 1] = u8::from(last);
+        if last {
+            self.finished = true;
+        } else {
+            self.counter = self
+                .counter
+                .checked_add(1)
+                .ok_or_else(|| "Too many chunks in the stream".to_string())?;
+        }
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey, String> {
+    UnboundKey::new(&AES_256_GCM, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| format!("The key must be {} bytes long", KEY_LENGTH))
+}
+
+/// Encrypts the chunks of a stream, see the module documentation.
+pub struct StreamEncryptor {
+    key: LessSafeKey,
+    nonces: NonceSequence,
+}
+
+impl StreamEncryptor {
+    /// Start a new stream encrypted with the key, with a random header.
+    pub fn new(key: &[u8]) -> Result<Self, String> {
+        let mut prefix = [0u8; HEADER_LENGTH];
+        SystemRandom::new()
+            .fill(&mut prefix)
+            .map_err(|_| "The random number generator is not available".to_string())?;
+        Ok(StreamEncryptor {
+            key: aead_key(key)?,
+            nonces: NonceSequence {
+                prefix,
+                counter: 0,
+                finished: false,
+            },
+        })
+    }
+
+    /// The header of the stream, to be sent before the chunks and given to the [`StreamDecryptor`].
+    pub fn header(&self) -> [u8; HEADER_LENGTH] {
+        self.nonces.prefix
+    }
+
+    /// Encrypt a chunk which is not the last one, returning the ciphertext followed by the tag.
+    pub fn encrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.seal(chunk, false)
+    }
+
+    /// Encrypt the last chunk, possibly empty, finishing the stream.
+    pub fn encrypt_last_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.seal(chunk, true)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, String> {
+        let nonce = self.nonces.next(last)?;
+        let mut sealed = chunk.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| "Couldn't encrypt the chunk".to_string())?;
+        Ok(sealed)
+    }
+}
+
+/// Decrypts the chunks of a stream, in the order they were encrypted, see the module documentation.
+/// The stream can't be decrypted further once a chunk fails to decrypt.
+pub struct StreamDecryptor {
+    key: LessSafeKey,
+    nonces: NonceSequence,
+}
+
+impl StreamDecryptor {
+    /// Start decrypting the stream with the key and the header of the [`StreamEncryptor`].
+    pub fn new(key: &[u8], header: &[u8]) -> Result<Self, String> {
+        let prefix = header
+            .try_into()
+            .map_err(|_| format!("The header must be {} bytes long", HEADER_LENGTH))?;
+        Ok(StreamDecryptor {
+            key: aead_key(key)?,
+            nonces: NonceSequence {
+                prefix,
+                counter: 0,
+                finished: false,
+            },
+        })
+    }
+
+    /// Decrypt a chunk which is not the last one, failing if it was tampered with, reordered or is the last one.
+    pub fn decrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.open(chunk, false)
+    }
+
+    /// Decrypt the last chunk, failing if the stream was truncated.
+    pub fn decrypt_last_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.open(chunk, true)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, String> {
+        let nonce = self.nonces.next(last)?;
+        let mut opened = chunk.to_vec();
+        let length = match self.key.open_in_place(nonce, Aad::empty(), &mut opened) {
+            Ok(plaintext) => plaintext.len(),
+            Err(_) => {
+                // Never decrypt the next chunks, which would silently skip the invalid one.
+                self.nonces.finished = true;
+                return Err("Couldn't decrypt the chunk, it is invalid or out of order".to_string());
+            }
+        };
+        opened.truncate(length);
+        Ok(opened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const KEY: [u8; KEY_LENGTH] = [7; KEY_LENGTH];
+
+    fn encrypt(chunks: &[&str]) -> ([u8; HEADER_LENGTH], Vec<Vec<u8>>) {
+        let mut encryptor = StreamEncryptor::new(&KEY).unwrap();
+        let (last, chunks) = chunks.split_last().unwrap();
+        let mut sealed = chunks
+            .iter()
+            .map(|chunk| encryptor.encrypt_chunk(chunk.as_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        sealed.push(encryptor.encrypt_last_chunk(last.as_bytes()).unwrap());
+        assert!(encryptor.encrypt_chunk(b"after the end").is_err());
+        (encryptor.header(), sealed)
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let (header, sealed) = encrypt(&["first", "second", ""]);
+        assert_eq!(sealed[0].len(), 5 + TAG_LENGTH);
+        let mut decryptor = StreamDecryptor::new(&KEY, &header).unwrap();
+        assert_eq!(decryptor.decrypt_chunk(&sealed[0]).unwrap(), b"first");
+        assert_eq!(decryptor.decrypt_chunk(&sealed[1]).unwrap(), b"second");
+        assert!(decryptor.decrypt_last_chunk(&sealed[2]).unwrap().is_empty());
+        assert!(decryptor.decrypt_chunk(&sealed[2]).is_err());
+    }
+
+    #[test]
+    fn test_stream_tampering() {
+        let (header, sealed) = encrypt(&["first", "second", "third"]);
+        // Reordered chunks.
+        let mut decryptor = StreamDecryptor::new(&KEY, &header).unwrap();
+        assert!(decryptor.decrypt_chunk(&sealed[1]).is_err());
+        // Truncated stream: the last chunk received is not flagged as the last one.
+        let mut decryptor = StreamDecryptor::new(&KEY, &header).unwrap();
+        decryptor.decrypt_chunk(&sealed[0]).unwrap();
+        assert!(decryptor.decrypt_last_chunk(&sealed[1]).is_err());
+        // Modified chunk.
+        let mut modified = sealed[0].clone();
+        modified[0] ^= 1;
+        let mut decryptor = StreamDecryptor::new(&KEY, &header).unwrap();
+        assert!(decryptor.decrypt_chunk(&modified).is_err());
+        // The stream can't be decrypted further, even with the next valid chunk.
+        assert!(decryptor.decrypt_chunk(&sealed[1]).is_err());
+        assert!(decryptor.decrypt_last_chunk(&sealed[2]).is_err());
+        // Another stream, or another key.
+        let (other_header, _) = encrypt(&["first"]);
+        let mut decryptor = StreamDecryptor::new(&KEY, &other_header).unwrap();
+        assert!(decryptor.decrypt_chunk(&sealed[0]).is_err());
+        let mut decryptor = StreamDecryptor::new(&[8; KEY_LENGTH], &header).unwrap();
+        assert!(decryptor.decrypt_chunk(&sealed[0]).is_err());
+        assert!(StreamDecryptor::new(&KEY, &header[1..]).is_err());
+        assert!(StreamEncryptor::new(&KEY[1..]).is_err());
+    }
+}
@@ -13,13 +13,16 @@
 //
 use cfg_if::cfg_if;
 use crypto::{
-    check_signature, mk_client_certificate_request_params, retrieve_der_pk_from_certificate,
-    retrieve_emails_from_certificate,
+    check_signature, hkdf_extract_expand, mk_client_certificate_request_params,
+    retrieve_der_pk_from_certificate, retrieve_emails_from_certificate, sign_challenge,
+    stream::{StreamDecryptor, StreamEncryptor},
+    KeyAlgorithm,
 };
 use utils::set_panic_hook;
 use wasm_bindgen::prelude::*;
 
 pub mod crypto;
+pub mod metadata;
 pub mod pki;
 mod utils;
 
@@ -60,12 +63,18 @@ pub struct ClientCertificateRequest {
 /// Create a new client certificate request with the given email address.
 /// The email is represented in the certificate as a Subject alt name as in RFC882.
 /// See [`Rfc822Name`](rcgen::SanType::Rfc822Name) for more details.
+/// The key pair uses the given algorithm, one of `ecdsa-p256` (the default), `ecdsa-p384` and `ed25519`.
 pub fn mk_client_certificate_request_params_binding(
     email: &str,
+    algorithm: Option<String>,
 ) -> Result<ClientCertificateRequest, String> {
     set_panic_hook();
+    let algorithm = match algorithm {
+        Some(name) => name.parse::<KeyAlgorithm>()?,
+        None => KeyAlgorithm::default(),
+    };
     let (key_pair, params) =
-        mk_client_certificate_request_params(email).map_err(|e| e.to_string())?;
+        mk_client_certificate_request_params(email, algorithm).map_err(|e| e.to_string())?;
     let signing_request = params.pem().map_err(|e| e.to_string())?;
     Ok(ClientCertificateRequest {
         key_pair: key_pair.serialize_pem(),
@@ -99,3 +108,79 @@ pub fn parse_der_pk_from_certificate(certificate: &str) -> Result<Vec<u8>, Strin
     set_panic_hook();
     retrieve_der_pk_from_certificate(certificate)
 }
+
+#[wasm_bindgen(js_name = signChallenge)]
+/// Sign the registration challenge issued by the PKI with the PEM encoded private key of the certificate request,
+/// to prove the possession of the key when registering.
+pub fn sign_challenge_binding(key_pair: &str, challenge: &str) -> Result<Vec<u8>, String> {
+    set_panic_hook();
+    sign_challenge(key_pair, challenge)
+}
+
+#[wasm_bindgen(js_name = hkdfExtractExpand)]
+/// Derive `len` bytes from the input keying material with HKDF-SHA256, e.g. the key of a file from the secret of its
+/// folder, so that the clients derive the same keys as the Rust code.
+pub fn hkdf_extract_expand_binding(
+    salt: &[u8],
+    ikm: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    set_panic_hook();
+    hkdf_extract_expand(salt, ikm, info, len)
+}
+
+/// Encrypts a file chunk by chunk with AES-256-GCM, see [`crypto::stream`].
+/// The header must be sent before the chunks, the last chunk is encrypted with `encryptLastChunk`.
+#[wasm_bindgen(js_name = StreamEncryptor)]
+pub struct StreamEncryptorBinding(StreamEncryptor);
+
+#[wasm_bindgen(js_class = StreamEncryptor)]
+impl StreamEncryptorBinding {
+    /// Start a new stream encrypted with the 32 bytes key.
+    #[wasm_bindgen(constructor)]
+    pub fn new(key: &[u8]) -> Result<StreamEncryptorBinding, String> {
+        set_panic_hook();
+        StreamEncryptor::new(key).map(StreamEncryptorBinding)
+    }
+
+    /// The header of the stream, needed to decrypt it.
+    pub fn header(&self) -> Vec<u8> {
+        self.0.header().to_vec()
+    }
+
+    #[wasm_bindgen(js_name = encryptChunk)]
+    pub fn encrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.encrypt_chunk(chunk)
+    }
+
+    #[wasm_bindgen(js_name = encryptLastChunk)]
+    pub fn encrypt_last_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.encrypt_last_chunk(chunk)
+    }
+}
+
+/// Decrypts a file encrypted by the [`StreamEncryptorBinding`] chunk by chunk, in the same order.
+/// The last chunk is decrypted with `decryptLastChunk`, which fails if the file was truncated.
+#[wasm_bindgen(js_name = StreamDecryptor)]
+pub struct StreamDecryptorBinding(StreamDecryptor);
+
+#[wasm_bindgen(js_class = StreamDecryptor)]
+impl StreamDecryptorBinding {
+    /// Start decrypting the stream with the 32 bytes key and the header of the stream.
+    #[wasm_bindgen(constructor)]
+    pub fn new(key: &[u8], header: &[u8]) -> Result<StreamDecryptorBinding, String> {
+        set_panic_hook();
+        StreamDecryptor::new(key, header).map(StreamDecryptorBinding)
+    }
+
+    #[wasm_bindgen(js_name = decryptChunk)]
+    pub fn decrypt_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.decrypt_chunk(chunk)
+    }
+
+    #[wasm_bindgen(js_name = decryptLastChunk)]
+    pub fn decrypt_last_chunk(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.decrypt_last_chunk(chunk)
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The metadata of a shared folder, stored at the root of the folder, and its wire format.
+//! The metadata is encoded in canonical CBOR, the core deterministic encoding of
+//! [RFC 8949](https://www.rfc-editor.org/rfc/rfc8949#section-4.2.1): definite lengths, the shortest form of the
+//! integers and the map keys sorted by their encoding, i.e. by length then bytewise for the text keys.
+//! Two clients then encode the same metadata to the same bytes, and any other encoding is rejected.
+
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The version of the format of the [`Metadata`], increased on every incompatible change.
+pub const METADATA_VERSION: u32 = 1;
+
+/// The metadata of a file, encrypted with the folder key and stored in the [`Metadata`] of its folder.
+/// The fields are declared in the canonical order of their keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// The symmetric key encrypting the content of the file.
+    #[serde(with = "serde_bytes")]
+    pub file_key: Vec<u8>,
+    /// The name of the file to be displayed to the end user.
+    pub file_name: String,
+}
+
+/// The type of the encrypted [`FileMetadata`] object, opaque to the server.
+pub type EncryptedFileMetadata = Vec<u8>;
+
+/// The metadata of a shared folder, holding its cryptographic state.
+/// The fields are declared in the canonical order of their keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The version of the format, see [`METADATA_VERSION`].
+    pub version: u32,
+    /// For each file id, maps to the metadata of the file.
+    /// The index is the id of the file (a GUID).
+    #[serde(with = "canonical_map")]
+    pub file_metadatas: BTreeMap<String, EncryptedFileMetadata>,
+    /// All the folder keys that are encrypted for the user.
+    /// The map is indexed by the user's identity.
+    /// The value is the asymmetrically encrypted key of the folder that can be decrypted by the user's private key.
+    #[serde(with = "canonical_map")]
+    pub folder_keys_by_user: BTreeMap<String, Vec<u8>>,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            version: METADATA_VERSION,
+            file_metadatas: BTreeMap::new(),
+            folder_keys_by_user: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metadata {
+    /// Encode the metadata in canonical CBOR.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, String> {
+        to_canonical_cbor(self)
+    }
+
+    /// Decode the metadata from canonical CBOR, rejecting the other versions of the format.
+    pub fn from_cbor(encoded: &[u8]) -> Result<Self, String> {
+        let metadata: Metadata = from_canonical_cbor(encoded)?;
+        if metadata.version != METADATA_VERSION {
+            return Err(format!(
+                "Unsupported metadata version `{}`, expected `{}`",
+                metadata.version, METADATA_VERSION
+            ));
+        }
+        Ok(metadata)
+    }
+}
+
+impl FileMetadata {
+    /// Encode the file metadata in canonical CBOR, before encrypting it.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, String> {
+        to_canonical_cbor(self)
+    }
+
+    /// Decode the file metadata from canonical CBOR, after decrypting it.
+    pub fn from_cbor(encoded: &[u8]) -> Result<Self, String> {
+        from_canonical_cbor(encoded)
+    }
+}
+
+fn to_canonical_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::new();
+    ciborium::into_writer(value, &mut encoded).map_err(|e| e.to_string())?;
+    Ok(encoded)
+}
+
+/// Decode the value, checking that it was canonically encoded by encoding it again.
+fn from_canonical_cbor<T: Serialize + DeserializeOwned>(encoded: &[u8]) -> Result<T, String> {
+    let value: T = ciborium::from_reader(encoded).map_err(|e| e.to_string())?;
+    if to_canonical_cbor(&value)? != encoded {
+        return Err("The metadata is not encoded in canonical CBOR".to_string());
+    }
+    Ok(value)
+}
+
+/// The maps of byte strings, with the entries sorted by the canonical order of their keys.
+/// The keys of the same length are sorted bytewise, as in the [`BTreeMap`], but the shortest keys come first.
+mod canonical_map {
+    use std::collections::BTreeMap;
+
+    use serde::{ser::SerializeMap, Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(
+        map: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut entries = map.iter().collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+        let mut encoded = serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+            encoded.serialize_entry(key, Bytes::new(value))?;
+        }
+        encoded.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        let map = BTreeMap::<String, ByteBuf>::deserialize(deserializer)?;
+        Ok(map
+            .into_iter()
+            .map(|(key, value)| (key, value.into_vec()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// The metadata encoded in `testdata/metadata_v1.cbor`.
+    fn golden_metadata() -> Metadata {
+        let mut metadata = Metadata::default();
+        metadata
+            .file_metadatas
+            .insert("f1".to_string(), vec![0x01, 0x02]);
+        metadata
+            .folder_keys_by_user
+            .insert("alice@test.com".to_string(), vec![0xbb, 0xcc]);
+        metadata
+            .folder_keys_by_user
+            .insert("bob@test.com".to_string(), vec![0xaa]);
+        metadata
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let metadata = golden_metadata();
+        let encoded = metadata.to_cbor().unwrap();
+        assert_eq!(Metadata::from_cbor(&encoded).unwrap(), metadata);
+        let empty = Metadata::default();
+        assert_eq!(
+            Metadata::from_cbor(&empty.to_cbor().unwrap()).unwrap(),
+            empty
+        );
+
+        let file_metadata = FileMetadata {
+            file_key: vec![0x42; 32],
+            file_name: "report.pdf".to_string(),
+        };
+        let encoded = file_metadata.to_cbor().unwrap();
+        assert_eq!(FileMetadata::from_cbor(&encoded).unwrap(), file_metadata);
+    }
+
+    #[test]
+    fn test_metadata_golden_file() {
+        let golden = include_bytes!("../testdata/metadata_v1.cbor");
+        // The keys of the folders are sorted by length first: `bob@test.com` before `alice@test.com`.
+        assert_eq!(golden_metadata().to_cbor().unwrap(), golden);
+        assert_eq!(Metadata::from_cbor(golden).unwrap(), golden_metadata());
+    }
+
+    #[test]
+    fn test_reject_non_canonical_metadata() {
+        let golden = include_bytes!("../testdata/metadata_v1.cbor");
+        // The version encoded on 2 bytes instead of 1.
+        let mut non_canonical = golden[..9].to_vec();
+        non_canonical.extend_from_slice(&[0x18, 0x01]);
+        non_canonical.extend_from_slice(&golden[10..]);
+        assert!(Metadata::from_cbor(&non_canonical).is_err());
+
+        let mut next_version = golden_metadata();
+        next_version.version = METADATA_VERSION + 1;
+        assert!(Metadata::from_cbor(&next_version.to_cbor().unwrap()).is_err());
+        assert!(Metadata::from_cbor(b"not cbor").is_err());
+    }
+}
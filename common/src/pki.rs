@@ -18,31 +18,176 @@ use std::{
 
 use rcgen::CertifiedKey;
 
-use crate::crypto::{load_ca_and_sign_cert, mk_issuer_ca};
+use crate::crypto::{load_ca_and_sign_cert, mk_intermediate_ca, mk_issuer_ca};
 
-/// The following constants are used to store the CA certificate and key pair,
-/// which are used to sign the certificates.
+/// The following constants are the default paths of the CA certificate and key pair,
+/// which are used to sign the certificates, see [`CaPaths`].
 /// The path to the CA certificate file. It will be created if it does not exist.
 const CA_CERT_FILE_PATH: &str = "private/ca/ca_cert.pem";
 /// The path to the CA key file. It will be created if it does not exist.
 const CA_KEY_FILE_PATH: &str = "private/ca/ca_keys.pem";
+/// The path to the intermediate CA certificate file. If present, the intermediate CA signs the certificates.
+const INTERMEDIATE_CA_CERT_FILE_PATH: &str = "private/ca/intermediate_cert.pem";
+/// The path to the intermediate CA key file.
+const INTERMEDIATE_CA_KEY_FILE_PATH: &str = "private/ca/intermediate_keys.pem";
+/// The path to the chain of the issuing CA, from the issuing CA to the root CA. It is written at every start.
+const CA_CHAIN_FILE_PATH: &str = "private/ca/ca_chain.pem";
+
+/// The paths of the files of the CA, by default under `private/ca` in the working directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaPaths {
+    /// The root CA certificate file. It will be created if it does not exist.
+    pub cert: String,
+    /// The root CA key file. It will be created if it does not exist.
+    pub key: String,
+    /// The intermediate CA certificate file. If present, the intermediate CA signs the certificates.
+    pub intermediate_cert: String,
+    /// The intermediate CA key file.
+    pub intermediate_key: String,
+    /// The chain of the issuing CA, from the issuing CA to the root CA. It is written at every start.
+    pub chain: String,
+}
+
+impl Default for CaPaths {
+    fn default() -> Self {
+        CaPaths {
+            cert: CA_CERT_FILE_PATH.to_string(),
+            key: CA_KEY_FILE_PATH.to_string(),
+            intermediate_cert: INTERMEDIATE_CA_CERT_FILE_PATH.to_string(),
+            intermediate_key: INTERMEDIATE_CA_KEY_FILE_PATH.to_string(),
+            chain: CA_CHAIN_FILE_PATH.to_string(),
+        }
+    }
+}
+
+/// The CA issuing the certificates, with its chain up to the root CA.
+pub struct IssuingCa {
+    /// The certificate and key pair signing the certificates.
+    pub certified_key: CertifiedKey,
+    /// The PEM encoded certificates of the chain, from the issuing CA to the root CA.
+    /// It has a single element if the root CA is the issuing CA.
+    pub chain: Vec<String>,
+}
+
+/// Initialise the CA issuing the certificates.
+/// If the intermediate CA files are present, the intermediate CA signs the certificates and only the root CA
+/// certificate is read, so that the root CA key can be kept offline. Otherwise, if `intermediate` is set, a new
+/// intermediate CA is created and signed by the root CA, else the root CA from [`init_ca`] signs the certificates.
+pub fn init_issuing_ca(paths: &CaPaths, intermediate: bool) -> IssuingCa {
+    let issuing_ca = match (
+        fs::read_to_string(&paths.intermediate_cert),
+        fs::read_to_string(&paths.intermediate_key),
+    ) {
+        (Ok(intermediate_cert_pem), Ok(intermediate_key_pair_pem)) => {
+            log::debug!(
+                "The intermediate CA certificate and key pair were loaded from the files `{}` `{}`.",
+                paths.intermediate_cert,
+                paths.intermediate_key
+            );
+            // Never replace an intermediate CA silently, the root CA to sign a new one may be offline.
+            let certified_key =
+                load_ca_and_sign_cert(&intermediate_cert_pem, &intermediate_key_pair_pem)
+                    .expect("Error loading the intermediate CA certificate and key pair!");
+            let mut chain = vec![intermediate_cert_pem];
+            match fs::read_to_string(&paths.cert) {
+                Ok(root_cert_pem) => chain.push(root_cert_pem),
+                Err(e) => log::warn!(
+                    "Couldn't read the root CA certificate from file `{}`, the chain only contains the intermediate CA: `{}`",
+                    paths.cert,
+                    e
+                ),
+            }
+            IssuingCa {
+                certified_key,
+                chain,
+            }
+        }
+        _ if intermediate => {
+            let root_ca_ck = init_ca(paths);
+            log::info!("Generating a new intermediate CA certificate and key pair.");
+            let certified_key = mk_intermediate_ca(&root_ca_ck)
+                .expect("Error generating the intermediate CA certificate and key pair!");
+            let r1 = write_file(&paths.intermediate_cert, &certified_key.cert.pem());
+            let r2 = write_file(
+                &paths.intermediate_key,
+                &certified_key.key_pair.serialize_pem(),
+            );
+            if r1.is_err() || r2.is_err() {
+                log::warn!("Couldn't write the new intermediate CA credentials to the files, after restarting the server all the certificates issued to the clients will become invalid!");
+            }
+            let root_cert_pem =
+                fs::read_to_string(&paths.cert).unwrap_or_else(|_| root_ca_ck.cert.pem());
+            IssuingCa {
+                chain: vec![certified_key.cert.pem(), root_cert_pem],
+                certified_key,
+            }
+        }
+        _ => {
+            let root_ca_ck = init_ca(paths);
+            let root_cert_pem =
+                fs::read_to_string(&paths.cert).unwrap_or_else(|_| root_ca_ck.cert.pem());
+            IssuingCa {
+                certified_key: root_ca_ck,
+                chain: vec![root_cert_pem],
+            }
+        }
+    };
+    write_chain(paths, &issuing_ca.chain);
+    issuing_ca
+}
+
+/// Load the chain of the CA issuing the certificates, without reading its key pair.
+/// This is used when the key is kept outside of the server, e.g. in an HSM, so nothing is generated: the
+/// intermediate CA signs the certificates if its certificate is present, else the root CA.
+pub fn load_issuing_ca_chain(paths: &CaPaths) -> Result<Vec<String>, String> {
+    let root_cert_pem = fs::read_to_string(&paths.cert).map_err(|e| {
+        format!(
+            "Couldn't read the CA certificate from file `{}`: `{}`",
+            paths.cert, e
+        )
+    });
+    let chain = match fs::read_to_string(&paths.intermediate_cert) {
+        Ok(intermediate_cert_pem) => {
+            let mut chain = vec![intermediate_cert_pem];
+            match root_cert_pem {
+                Ok(root_cert_pem) => chain.push(root_cert_pem),
+                Err(e) => log::warn!("{}, the chain only contains the intermediate CA", e),
+            }
+            chain
+        }
+        Err(_) => vec![root_cert_pem?],
+    };
+    write_chain(paths, &chain);
+    Ok(chain)
+}
+
+/// Write the chain of the issuing CA to [`CaPaths::chain`].
+fn write_chain(paths: &CaPaths, chain: &[String]) {
+    if let Err(e) = write_file(&paths.chain, &chain.concat()) {
+        log::warn!(
+            "Couldn't write the CA chain to the file `{}`: `{}`",
+            paths.chain,
+            e
+        );
+    }
+}
 
 /// Initialise the CA certificate and key pair.
 /// If the files are present, load the CA certificate and key pair from the files.
 /// If the files are not present, generate a new CA certificate and key pair.
-pub fn init_ca() -> CertifiedKey {
+pub fn init_ca(paths: &CaPaths) -> CertifiedKey {
     // Check for existing CA certificate and key pair.
-    let ca_cert_pem = std::fs::read_to_string(CA_CERT_FILE_PATH).inspect_err(|e| {
+    let ca_cert_pem = std::fs::read_to_string(&paths.cert).inspect_err(|e| {
         log::info!(
             "Couldn't read the CA certificate from file `{}`: `{}`",
-            CA_CERT_FILE_PATH,
+            paths.cert,
             e
         )
     });
-    let ca_key_pair_pem = std::fs::read_to_string(CA_KEY_FILE_PATH).inspect_err(|e| {
+    let ca_key_pair_pem = std::fs::read_to_string(&paths.key).inspect_err(|e| {
         log::info!(
             "Couldn't read the CA key pair from file `{}`: `{}`",
-            CA_KEY_FILE_PATH,
+            paths.key,
             e
         )
     });
@@ -51,8 +196,8 @@ pub fn init_ca() -> CertifiedKey {
             load_ca_and_sign_cert(&ca_cert_pem, &ca_key_pair_pem).inspect_err(|e| {
                 log::error!("Couldn't load the old CA certificate and key pair: `{}`, generate a new pair. 
                 If you need them to debug, a backup of the files has been made and saved in the same location (.bkp)", e);
-                let _ = backup_file(CA_CERT_FILE_PATH);
-                let _ = backup_file(CA_KEY_FILE_PATH);
+                let _ = backup_file(&paths.cert);
+                let _ = backup_file(&paths.key);
             })
             .map(|ca_ck| (ca_ck, false))
             .unwrap_or((mk_issuer_ca().expect("Error generating fresh CA certificate and key pair!"), true))
@@ -66,16 +211,16 @@ pub fn init_ca() -> CertifiedKey {
     // files cannot be written to disk as we can still obtain the CA certificate from the REST endpoint.
     if fresh_certificate {
         log::debug!("Writing the new CA certificate and key pair to the files.");
-        let r2 = write_file(CA_CERT_FILE_PATH, &ca_ck.cert.pem());
-        let r1 = write_file(CA_KEY_FILE_PATH, &ca_ck.key_pair.serialize_pem());
+        let r2 = write_file(&paths.cert, &ca_ck.cert.pem());
+        let r1 = write_file(&paths.key, &ca_ck.key_pair.serialize_pem());
         if r1.is_err() || r2.is_err() {
             log::warn!("Couldn't write the new CA credentials to the files, after restarting the server all the certficates issued to the clients' will become invalid!",);
         }
     } else {
         log::debug!(
             "The CA certificate and key pair were loaded from the files `{}` `{}`.",
-            CA_CERT_FILE_PATH,
-            CA_KEY_FILE_PATH
+            paths.cert,
+            paths.key
         );
     }
     ca_ck
@@ -99,8 +244,3 @@ pub fn write_file(file_path: &str, content: &str) -> Result<(), Box<dyn error::E
     fs::write(file_path, content)?;
     Ok(())
 }
-
-/// Returns the paths to the CA certificate and key pair.
-pub fn get_ca_credential_paths() -> (String, String) {
-    (CA_CERT_FILE_PATH.to_string(), CA_KEY_FILE_PATH.to_string())
-}
@@ -0,0 +1,18 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- Tombstones of the deleted accounts, so that their emails can't be registered again.
+-- Only a SHA-256 hash of the email is kept, the personal data of the user are removed.
+CREATE TABLE IF NOT EXISTS deleted_users (
+    email_hash CHAR(64) NOT NULL PRIMARY KEY,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
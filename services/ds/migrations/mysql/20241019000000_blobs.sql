@@ -0,0 +1,20 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- Reference count of the deduplicated file contents of each folder, stored once by their SHA-256 hash.
+CREATE TABLE IF NOT EXISTS blobs (
+    folder_id INT UNSIGNED NOT NULL,
+    content_hash CHAR(64) NOT NULL,
+    ref_count INT UNSIGNED NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, content_hash)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
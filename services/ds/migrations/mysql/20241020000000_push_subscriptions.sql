@@ -0,0 +1,25 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The Web Push subscriptions of the users, to wake up their clients when they are not connected.
+-- The public key (P-256) and the authentication secret of the subscription are base64url encoded.
+CREATE TABLE IF NOT EXISTS push_subscriptions (
+    subscription_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    user_email VARCHAR(100) NOT NULL,
+    endpoint VARCHAR(1000) NOT NULL,
+    p256dh VARCHAR(100) NOT NULL,
+    auth VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT push_endpoint_unique UNIQUE (endpoint),
+    INDEX ( user_email )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
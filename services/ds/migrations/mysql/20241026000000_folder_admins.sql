@@ -0,0 +1,52 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- Every folder has an admin since the roles were introduced, but the folders created before have none, so that
+-- nobody can manage their members. The column is also missing in the databases created with an older setup script.
+SET @add_role = (
+    SELECT IF(
+        COUNT(*) = 0,
+        'ALTER TABLE folders_users ADD COLUMN role ENUM(''admin'', ''member'') NOT NULL DEFAULT ''member''',
+        'DO 0'
+    )
+    FROM information_schema.COLUMNS
+    WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'folders_users' AND COLUMN_NAME = 'role'
+);
+PREPARE add_role FROM @add_role;
+EXECUTE add_role;
+DEALLOCATE PREPARE add_role;
+
+-- Promote the creator of each folder without an admin: the actor of its first audited event if still a member.
+-- The order of the members is not recorded, so the folders older than the audit log fall back to the first member
+-- by email, who can then transfer the ownership.
+-- The grouped derived table is materialized, so that it can read the updated table.
+UPDATE folders_users
+JOIN (
+    SELECT
+        orphans.folder_id,
+        COALESCE(
+            (
+                SELECT audit_log.actor_email
+                FROM audit_log
+                JOIN folders_users AS members
+                    ON members.folder_id = audit_log.folder_id AND members.user_email = audit_log.actor_email
+                WHERE audit_log.folder_id = orphans.folder_id
+                ORDER BY audit_log.event_id
+                LIMIT 1
+            ),
+            MIN(orphans.user_email)
+        ) AS user_email
+    FROM folders_users AS orphans
+    GROUP BY orphans.folder_id
+    HAVING SUM(orphans.role = 'admin') = 0
+) AS folder_admins
+    ON folder_admins.folder_id = folders_users.folder_id AND folder_admins.user_email = folders_users.user_email
+SET folders_users.role = 'admin';
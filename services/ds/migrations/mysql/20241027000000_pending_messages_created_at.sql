@@ -0,0 +1,27 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The creation time of the pending messages, used to expire the messages of the users that don't come back.
+-- It is part of the initial schema, but missing in the databases created with an older setup script.
+SET @add_created_at = (
+    SELECT IF(
+        COUNT(*) = 0,
+        'ALTER TABLE pending_group_messages
+            ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            ADD INDEX ( created_at )',
+        'DO 0'
+    )
+    FROM information_schema.COLUMNS
+    WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'pending_group_messages' AND COLUMN_NAME = 'created_at'
+);
+PREPARE add_created_at FROM @add_created_at;
+EXECUTE add_created_at;
+DEALLOCATE PREPARE add_created_at;
@@ -0,0 +1,28 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The initial schema only creates the missing tables, so a database created with an older setup script keeps the
+-- older definition of its existing tables. Add the columns they are missing, the folder roles and the creation time
+-- of the pending messages are added by the previous migrations.
+SET @add_not_after = (
+    SELECT IF(
+        COUNT(*) = 0,
+        'ALTER TABLE key_packages
+            ADD COLUMN not_after BIGINT UNSIGNED NULL,
+            ADD INDEX ( not_after )',
+        'DO 0'
+    )
+    FROM information_schema.COLUMNS
+    WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'key_packages' AND COLUMN_NAME = 'not_after'
+);
+PREPARE add_not_after FROM @add_not_after;
+EXECUTE add_not_after;
+DEALLOCATE PREPARE add_not_after;
@@ -0,0 +1,14 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The database driver decodes the binary strings as text, failing on the encrypted display names that are not valid
+-- UTF-8 and then on every query reading the folders. Store them as BLOB, as in SQLite.
+ALTER TABLE folders MODIFY COLUMN display_blob BLOB NOT NULL DEFAULT (x'');
@@ -0,0 +1,23 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The blob each file points to, the references to the blobs are counted from these rows and never from the content
+-- of the objects. The files in the trash keep their row with the time of their deletion, 0 for the current files.
+-- The files stored before keep their blobs until their folder is deleted.
+CREATE TABLE IF NOT EXISTS file_blobs (
+    folder_id INT UNSIGNED NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    deleted_at BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    content_hash CHAR(64) NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, file_id, deleted_at)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
@@ -0,0 +1,126 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The initial schema of the DS for SQLite, mirroring the MySQL one for local development and testing.
+
+-- Table to store the users
+CREATE TABLE IF NOT EXISTS users (
+    user_email VARCHAR(100) NOT NULL PRIMARY KEY
+);
+
+-- Table to store the folders
+CREATE TABLE IF NOT EXISTS folders (
+    folder_id INTEGER PRIMARY KEY AUTOINCREMENT
+);
+
+-- Relationship table between folders to users (1 to many)
+CREATE TABLE IF NOT EXISTS folders_users (
+    folder_id INTEGER NOT NULL,
+    user_email VARCHAR(100) NOT NULL,
+    -- Only the admins can share the folder and manage its members.
+    role VARCHAR(16) NOT NULL DEFAULT 'member' CHECK (role IN ('admin', 'member')),
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id),
+    FOREIGN KEY (user_email) REFERENCES users(user_email),
+    PRIMARY KEY (folder_id, user_email)
+);
+CREATE INDEX IF NOT EXISTS folders_users_user_email ON folders_users ( user_email, folder_id );
+
+-- Store all pending messages for each user and folder.
+CREATE TABLE IF NOT EXISTS pending_group_messages (
+    message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    user_email VARCHAR(100) NOT NULL,
+    payload BLOB NOT NULL,
+    creator VARCHAR(100) NOT NULL,
+    -- Used to expire the messages of the users that don't come back.
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS pending_group_messages_user_email ON pending_group_messages ( user_email, folder_id );
+CREATE INDEX IF NOT EXISTS pending_group_messages_created_at ON pending_group_messages ( created_at );
+
+-- Store all application messages for each user and folder.
+CREATE TABLE IF NOT EXISTS application_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL,
+    payload BLOB,
+    FOREIGN KEY (message_id) REFERENCES pending_group_messages(message_id) ON DELETE CASCADE
+);
+
+-- Store all pending welcome messages foe each user and folder.
+CREATE TABLE IF NOT EXISTS welcome_messages (
+    message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    user_email VARCHAR(100) NOT NULL,
+    payload BLOB,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS welcome_messages_user_email ON welcome_messages ( user_email, folder_id );
+
+-- Store key packages
+CREATE TABLE IF NOT EXISTS key_packages (
+    key_package_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key_package BLOB,
+    user_email VARCHAR(100) NOT NULL,
+    -- The end of the lifetime of the key package in seconds since the Unix epoch, NULL if it couldn't be parsed.
+    not_after BIGINT NULL,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS key_packages_not_after ON key_packages ( not_after );
+
+-- Store the resumable file uploads in progress, each one mapped to a multipart upload of the object store.
+CREATE TABLE IF NOT EXISTS file_uploads (
+    upload_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    user_email VARCHAR(100) NOT NULL,
+    multipart_id VARCHAR(1024) NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
+);
+
+-- Store the parts already uploaded for each resumable upload.
+CREATE TABLE IF NOT EXISTS file_upload_parts (
+    upload_id INTEGER NOT NULL,
+    part_number INTEGER NOT NULL,
+    content_id VARCHAR(1024) NOT NULL,
+    size BIGINT NOT NULL,
+    PRIMARY KEY (upload_id, part_number),
+    FOREIGN KEY (upload_id) REFERENCES file_uploads(upload_id) ON DELETE CASCADE
+);
+
+-- Store the size of each file, to enforce the storage quotas (`usage` is a reserved word).
+CREATE TABLE IF NOT EXISTS storage_usage (
+    folder_id INTEGER NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    -- The user that uploaded the last version of the file.
+    user_email VARCHAR(100) NOT NULL,
+    size BIGINT NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, file_id)
+);
+CREATE INDEX IF NOT EXISTS storage_usage_user_email ON storage_usage ( user_email );
+
+-- Append-only log of the security-relevant events of each folder.
+-- The rows are not bound to the folders and users, so that the trail outlives them.
+CREATE TABLE IF NOT EXISTS audit_log (
+    event_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    actor_email VARCHAR(100) NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    -- The subject of the action, e.g. the file or the user the folder has been shared with.
+    target VARCHAR(255),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS audit_log_folder_id ON audit_log ( folder_id, event_id );
@@ -0,0 +1,27 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- Pending invitations to join a folder, the invitee becomes a member only once it accepts.
+-- The MLS proposal adding the invitee and its welcome message are published on acceptance, empty if missing.
+CREATE TABLE IF NOT EXISTS invitations (
+    invitation_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    inviter_email VARCHAR(100) NOT NULL,
+    invitee_email VARCHAR(100) NOT NULL,
+    proposal BLOB NOT NULL,
+    welcome BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (inviter_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    FOREIGN KEY (invitee_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT folder_invitee_unique UNIQUE (folder_id, invitee_email)
+);
+CREATE INDEX IF NOT EXISTS invitations_invitee_email ON invitations ( invitee_email );
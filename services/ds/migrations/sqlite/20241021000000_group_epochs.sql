@@ -0,0 +1,19 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The latest epoch of the MLS groups of each folder accepted in a proposal, older proposals are rejected.
+CREATE TABLE IF NOT EXISTS group_epochs (
+    folder_id INTEGER NOT NULL,
+    group_id VARCHAR(40) NOT NULL,
+    epoch BIGINT NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, group_id)
+);
@@ -0,0 +1,26 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The read-only share links of the folders, only the SHA-256 hash of their bearer token is stored.
+-- The links stop working once they expire, or their creator leaves the folder.
+CREATE TABLE IF NOT EXISTS share_links (
+    link_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    folder_id INTEGER NOT NULL,
+    creator_email VARCHAR(100) NOT NULL,
+    token_hash CHAR(64) NOT NULL,
+    -- The expiration time, in seconds since the Unix epoch.
+    expires_at BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (creator_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT share_link_token_unique UNIQUE (token_hash)
+);
+CREATE INDEX IF NOT EXISTS share_links_folder_id ON share_links ( folder_id );
@@ -0,0 +1,22 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The devices of the users, identified by the hex encoded SHA-256 fingerprint of their client certificate.
+-- A device is registered the first time a user authenticates with a new certificate.
+CREATE TABLE IF NOT EXISTS devices (
+    device_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_email VARCHAR(100) NOT NULL,
+    fingerprint CHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT device_fingerprint_unique UNIQUE (fingerprint)
+);
+CREATE INDEX IF NOT EXISTS devices_user_email ON devices ( user_email );
@@ -0,0 +1,28 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- The delivery receipts of the group messages, one for each receiver of a message. They are kept after the message
+-- is acked and removed from the queue of the receiver, so that the sender can check who processed it.
+-- The copies of a message sent to the members share the id of the first one as `proposal_id`.
+CREATE TABLE IF NOT EXISTS group_message_receipts (
+    message_id INTEGER PRIMARY KEY,
+    proposal_id INTEGER NOT NULL,
+    folder_id INTEGER NOT NULL,
+    sender_email VARCHAR(100) NOT NULL,
+    receiver_email VARCHAR(100) NOT NULL,
+    -- The time of the ack, in seconds since the Unix epoch, 0 until the message is acked.
+    acked_at BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS group_message_receipts_proposal_id ON group_message_receipts ( proposal_id );
+CREATE INDEX IF NOT EXISTS group_message_receipts_receiver_email ON group_message_receipts ( receiver_email, folder_id );
+CREATE INDEX IF NOT EXISTS group_message_receipts_created_at ON group_message_receipts ( created_at );
@@ -0,0 +1,28 @@
+-- Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+--
+-- This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+-- License as published by the Free Software Foundation, version 3.
+--
+-- This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+-- warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+--
+-- You should have received a copy of the GNU General Public License along with this program. If not, see <https://
+-- www.gnu.org/licenses/>.
+--
+-- Every folder has an admin since the roles were introduced, promote the creator of any folder without one:
+-- the actor of its first audited event if still a member, the first member by email otherwise.
+UPDATE folders_users
+SET role = 'admin'
+WHERE folder_id NOT IN (SELECT folder_id FROM folders_users WHERE role = 'admin')
+    AND user_email = COALESCE(
+        (
+            SELECT audit_log.actor_email
+            FROM audit_log
+            JOIN folders_users AS members
+                ON members.folder_id = audit_log.folder_id AND members.user_email = audit_log.actor_email
+            WHERE audit_log.folder_id = folders_users.folder_id
+            ORDER BY audit_log.event_id
+            LIMIT 1
+        ),
+        (SELECT MIN(members.user_email) FROM folders_users AS members WHERE members.folder_id = folders_users.folder_id)
+    );
@@ -0,0 +1,259 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! Streaming of the objects of a folder as a tar archive, in the GNU format so that
+//! the long file names and the files larger than 8 GiB are supported.
+
+use std::sync::Arc;
+
+use rocket::{
+    futures::StreamExt,
+    http::{ContentType, Header},
+    response::{
+        self,
+        stream::{ByteStream, ReaderStream},
+        Responder,
+    },
+    Request, Response,
+};
+
+use crate::{
+    bandwidth::{self, TokenBucket},
+    db::FolderEntity,
+    server::SyncStore,
+    storage,
+};
+
+/// The size of the blocks of a tar archive.
+const BLOCK_SIZE: usize = 512;
+/// The size of the name field of a header, the longer names are written in a preceding entry.
+const NAME_SIZE: usize = 100;
+/// The largest size that fits in the 11 octal digits of the size field, the larger sizes are written in binary.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+/// The name of the entries holding the long name of the following entry.
+const LONG_NAME: &str = "././@LongLink";
+/// The type of a regular file.
+const REGULAR_FILE: u8 = b'0';
+/// The type of the entries holding the long name of the following entry.
+const GNU_LONG_NAME: u8 = b'L';
+
+/// Write `value` as a NUL terminated octal number filling the field.
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+fn header(name: &[u8], size: u64, mtime: u64, typeflag: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    let name = &name[..name.len().min(NAME_SIZE)];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    if size > MAX_OCTAL_SIZE {
+        // GNU base-256 encoding, flagged by the highest bit of the first byte.
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    } else {
+        write_octal(&mut header[124..136], size);
+    }
+    write_octal(&mut header[136..148], mtime.min(MAX_OCTAL_SIZE));
+    header[156] = typeflag;
+    header[257..265].copy_from_slice(b"ustar  \0");
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    header
+}
+
+/// The zeros filling the last block of an entry of `size` bytes.
+pub fn padding(size: u64) -> Vec<u8> {
+    let remainder = (size % BLOCK_SIZE as u64) as usize;
+    vec![0u8; (BLOCK_SIZE - remainder) % BLOCK_SIZE]
+}
+
+/// The header of a regular file, preceded by the entry of its name if it is too long for the header.
+pub fn entry_header(name: &str, size: u64, mtime: u64) -> Vec<u8> {
+    let mut blocks = vec![];
+    if name.len() > NAME_SIZE {
+        let long_name = [name.as_bytes(), &[0]].concat();
+        blocks.extend_from_slice(&header(
+            LONG_NAME.as_bytes(),
+            long_name.len() as u64,
+            0,
+            GNU_LONG_NAME,
+        ));
+        blocks.extend_from_slice(&long_name);
+        blocks.extend_from_slice(&padding(long_name.len() as u64));
+    }
+    blocks.extend_from_slice(&header(name.as_bytes(), size, mtime, REGULAR_FILE));
+    blocks
+}
+
+/// The two empty blocks closing an archive.
+pub fn end_of_archive() -> Vec<u8> {
+    vec![0u8; 2 * BLOCK_SIZE]
+}
+
+/// The files of a folder streamed as a tar archive, each file read from the object store only when it is reached.
+/// The entries are named after their location in the object store, `<folder_id>/<file_id>`.
+/// The files deleted in the meantime are skipped, and the archive is truncated if a file can't be read,
+/// as the status has already been sent.
+#[derive(Debug)]
+pub struct FolderArchive {
+    pub store: SyncStore,
+    pub folder: FolderEntity,
+    pub file_ids: Vec<String>,
+    /// The bucket throttling the downloads of the user, if any.
+    pub bucket: Option<Arc<TokenBucket>>,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for FolderArchive {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        let FolderArchive {
+            store,
+            folder,
+            file_ids,
+            bucket,
+        } = self;
+        let folder_id = folder.folder_id;
+        let stream = ByteStream! {
+            for file_id in file_ids {
+                let file = {
+                    let store = store.lock().await;
+                    storage::open_file(&store, &folder, &file_id).await
+                };
+                let file = match file {
+                    Ok(file) => file,
+                    Err(object_store::Error::NotFound { .. }) => {
+                        log::debug!("Skipping the file `{}` deleted during the export", file_id);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("Couldn't open the file `{}` to export it: `{}`", file_id, e);
+                        return;
+                    }
+                };
+                let size = file.meta.size as u64;
+                let mtime = file.meta.last_modified.timestamp().max(0) as u64;
+                yield entry_header(&format!("{}/{}", folder_id, file_id), size, mtime);
+                let mut written = 0;
+                let mut chunks = Box::pin(bandwidth::throttle(file.into_stream(), bucket.clone()));
+                while let Some(chunk) = chunks.next().await {
+                    match chunk {
+                        Ok(chunk) => {
+                            written += chunk.len() as u64;
+                            yield chunk.to_vec();
+                        }
+                        Err(e) => {
+                            log::error!("Couldn't stream the file `{}` to export it: `{}`", file_id, e);
+                            return;
+                        }
+                    }
+                }
+                if written != size {
+                    log::error!("The file `{}` changed during the export", file_id);
+                    return;
+                }
+                yield padding(size);
+            }
+            yield end_of_archive();
+        };
+        let ByteStream(chunks) = stream;
+        Response::build()
+            .header(ContentType::new("application", "x-tar"))
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"folder-{}.tar\"", folder_id),
+            ))
+            .streamed_body(ReaderStream::from(chunks.map(std::io::Cursor::new)))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn field(header: &[u8], range: std::ops::Range<usize>) -> String {
+        String::from_utf8(header[range].to_vec())
+            .unwrap()
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    fn checksum_is_valid(header: &[u8]) -> bool {
+        let expected = u32::from_str_radix(field(header, 148..154).as_str(), 8).unwrap();
+        let sum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    *byte as u32
+                }
+            })
+            .sum();
+        expected == sum
+    }
+
+    #[test]
+    fn test_entry_header() {
+        let header = entry_header("42/file", 1000, 1_700_000_000);
+        assert_eq!(BLOCK_SIZE, header.len());
+        assert_eq!("42/file", field(&header, 0..100));
+        assert_eq!("00000001750", field(&header, 124..136));
+        assert_eq!("14524770400", field(&header, 136..148));
+        assert_eq!(REGULAR_FILE, header[156]);
+        assert_eq!("ustar  ", field(&header, 257..265));
+        assert!(checksum_is_valid(&header));
+    }
+
+    #[test]
+    fn test_long_name() {
+        let name = format!("42/{}", "a".repeat(200));
+        let blocks = entry_header(&name, 1, 0);
+        assert_eq!(3 * BLOCK_SIZE, blocks.len());
+        assert_eq!(LONG_NAME, field(&blocks, 0..100));
+        assert_eq!(GNU_LONG_NAME, blocks[156]);
+        assert!(checksum_is_valid(&blocks[..BLOCK_SIZE]));
+        assert_eq!(name, field(&blocks, BLOCK_SIZE..2 * BLOCK_SIZE));
+        assert_eq!(
+            &name.as_bytes()[..NAME_SIZE],
+            &blocks[2 * BLOCK_SIZE..2 * BLOCK_SIZE + NAME_SIZE]
+        );
+        assert!(checksum_is_valid(&blocks[2 * BLOCK_SIZE..]));
+    }
+
+    #[test]
+    fn test_large_size() {
+        let size = 10 * 1024 * 1024 * 1024;
+        let header = entry_header("42/file", size, 0);
+        assert_eq!(0x80, header[124]);
+        assert_eq!(size.to_be_bytes(), header[128..136]);
+        assert!(checksum_is_valid(&header));
+    }
+
+    #[test]
+    fn test_padding() {
+        assert!(padding(0).is_empty());
+        assert_eq!(511, padding(1).len());
+        assert!(padding(512).is_empty());
+        assert_eq!(12, padding(1012).len());
+    }
+}
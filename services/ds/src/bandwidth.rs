@@ -0,0 +1,339 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    data::ByteUnit,
+    futures::{Stream, StreamExt},
+    tokio::{
+        self,
+        io::{AsyncRead, ReadBuf},
+        time::Sleep,
+    },
+};
+
+/// The number of users tracked above which the idle ones are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The bandwidth configuration, loaded from the `bandwidth` table of the `DS_Rocket.toml` file.
+/// Each cap is optional, the transfers are not throttled when it is missing.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct BandwidthConfig {
+    /// The maximum number of bytes per second each user can upload, across all the connections of the user.
+    #[serde(default)]
+    pub upload: Option<ByteUnit>,
+    /// The maximum number of bytes per second each user can download, across all the connections of the user.
+    #[serde(default)]
+    pub download: Option<ByteUnit>,
+}
+
+/// A token bucket refilled at a constant rate, holding at most one second of transfer.
+/// A transfer can take more tokens than available, the following ones wait until the debt is repaid.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// The rate, in bytes per second.
+    rate: f64,
+    /// The available tokens and the time they were computed.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate, now)),
+        }
+    }
+
+    /// Take `bytes` tokens, returning how long to wait before the next transfer.
+    pub fn take(&self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("Bandwidth state corrupted!");
+        let (tokens, last) = *state;
+        let refilled = now.saturating_duration_since(last).as_secs_f64() * self.rate;
+        let tokens = (tokens + refilled).min(self.rate) - bytes as f64;
+        *state = (tokens, now.max(last));
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    /// Whether the bucket is full, i.e. the user didn't transfer anything for a while.
+    fn is_full(&self, now: Instant) -> bool {
+        let (tokens, last) = *self.state.lock().expect("Bandwidth state corrupted!");
+        tokens + now.saturating_duration_since(last).as_secs_f64() * self.rate >= self.rate
+    }
+}
+
+/// The buckets of the users, created on their first transfer.
+#[derive(Debug)]
+struct Buckets {
+    rate: Option<ByteUnit>,
+    buckets: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl Buckets {
+    fn new(rate: Option<ByteUnit>) -> Self {
+        Buckets {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, user_email: &str) -> Option<Arc<TokenBucket>> {
+        let rate = self.rate?;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("Bandwidth state corrupted!");
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.is_full(now));
+        }
+        let bucket = buckets
+            .entry(user_email.to_string())
+            .or_insert_with(|| Arc::new(TokenBucket::new(rate.as_u64(), now)));
+        Some(bucket.clone())
+    }
+}
+
+/// The bandwidth caps of the users, shared by all their connections, to be used as managed state in Rocket.
+#[derive(Debug)]
+pub struct Bandwidth {
+    uploads: Buckets,
+    downloads: Buckets,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig) -> Self {
+        Bandwidth {
+            uploads: Buckets::new(config.upload),
+            downloads: Buckets::new(config.download),
+        }
+    }
+
+    /// The bucket throttling the uploads of the user, if the uploads are capped.
+    pub fn upload(&self, user_email: &str) -> Option<Arc<TokenBucket>> {
+        self.uploads.get(user_email)
+    }
+
+    /// The bucket throttling the downloads of the user, if the downloads are capped.
+    pub fn download(&self, user_email: &str) -> Option<Arc<TokenBucket>> {
+        self.downloads.get(user_email)
+    }
+}
+
+/// Throttle a stream of chunks, waiting after each chunk until the bucket allows the next one.
+pub fn throttle<S, T, E>(
+    stream: S,
+    bucket: Option<Arc<TokenBucket>>,
+) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: AsRef<[u8]>,
+{
+    stream.then(move |chunk| {
+        let bucket = bucket.clone();
+        async move {
+            if let (Some(bucket), Ok(bytes)) = (&bucket, &chunk) {
+                tokio::time::sleep(bucket.take(bytes.as_ref().len())).await;
+            }
+            chunk
+        }
+    })
+}
+
+/// A reader throttled by a bucket, waiting before each read until the bucket allows it.
+pub struct ThrottledReader<R> {
+    inner: R,
+    bucket: Option<Arc<TokenBucket>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, bucket: Option<Arc<TokenBucket>>) -> Self {
+        ThrottledReader {
+            inner,
+            bucket,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(bucket) = &self.bucket {
+            let wait = bucket.take(buf.filled().len() - filled);
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A reader over a stream of chunks, e.g. the content of a file streamed from the object store.
+/// It fails with the error of the stream instead of ending early, so that a download failing midway aborts
+/// the response rather than truncating its body.
+pub struct ChunkReader<S, T> {
+    chunks: S,
+    chunk: Option<T>,
+    offset: usize,
+}
+
+impl<S, T> ChunkReader<S, T> {
+    pub fn new(chunks: S) -> Self {
+        ChunkReader {
+            chunks,
+            chunk: None,
+            offset: 0,
+        }
+    }
+}
+
+impl<S, T, E> AsyncRead for ChunkReader<S, T>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: AsRef<[u8]> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunk) = &this.chunk {
+                let remaining = &chunk.as_ref()[this.offset..];
+                if !remaining.is_empty() {
+                    let len = remaining.len().min(buf.remaining());
+                    buf.put_slice(&remaining[..len]);
+                    this.offset += len;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            match ready!(this.chunks.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => {
+                    this.chunk = Some(chunk);
+                    this.offset = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rocket::tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let bucket = TokenBucket::new(1000, now);
+        // A burst of one second is allowed.
+        assert_eq!(Duration::ZERO, bucket.take_at(1000, now));
+        assert_eq!(Duration::from_millis(500), bucket.take_at(500, now));
+        // The debt is repaid before the tokens are available again.
+        assert_eq!(
+            Duration::from_millis(100),
+            bucket.take_at(100, now + Duration::from_millis(500))
+        );
+        // The bucket never holds more than one second of transfer.
+        assert_eq!(
+            Duration::from_secs(1),
+            bucket.take_at(2000, now + Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let bandwidth = Bandwidth::new(BandwidthConfig {
+            upload: Some(ByteUnit::Kibibyte(1)),
+            download: None,
+        });
+        assert!(bandwidth.download("alice@test.com").is_none());
+        let alice = bandwidth.upload("alice@test.com").unwrap();
+        // The connections of a user share the same bucket.
+        assert!(Arc::ptr_eq(
+            &alice,
+            &bandwidth.upload("alice@test.com").unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            &alice,
+            &bandwidth.upload("bob@test.com").unwrap()
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn test_throttled_reader() {
+        let bucket = Arc::new(TokenBucket::new(100_000, Instant::now()));
+        let content = vec![1u8; 150_000];
+        let mut reader = ThrottledReader::new(&content[..], Some(bucket));
+        let mut read = vec![];
+        let start = Instant::now();
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(content, read);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[rocket::async_test]
+    async fn test_chunk_reader() {
+        let chunks = vec![
+            Ok::<_, io::Error>(vec![1u8; 10]),
+            Ok(vec![]),
+            Ok(vec![2u8; 5]),
+        ];
+        let mut reader = ChunkReader::new(rocket::futures::stream::iter(chunks));
+        let mut read = vec![];
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!([vec![1u8; 10], vec![2u8; 5]].concat(), read);
+
+        let chunks = vec![
+            Ok(vec![1u8; 10]),
+            Err(io::Error::other("failed")),
+            Ok(vec![2u8; 5]),
+        ];
+        let mut reader = ChunkReader::new(rocket::futures::stream::iter(chunks));
+        let mut read = vec![];
+        assert!(reader.read_to_end(&mut read).await.is_err());
+        assert_eq!(vec![1u8; 10], read);
+    }
+}
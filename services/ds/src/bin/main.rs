@@ -11,9 +11,19 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use ds::init_server_from_config;
+use ds::{init_server_from_config, tls_reload::TlsReload};
 
-#[rocket::launch]
-fn rocket() -> _ {
-    init_server_from_config()
+/// The DS server is launched again when its TLS certificate changes, see [`TlsReload`].
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
+    loop {
+        let rocket = init_server_from_config().launch().await?;
+        if !rocket
+            .state::<TlsReload>()
+            .is_some_and(TlsReload::requested)
+        {
+            return Ok(());
+        }
+        log::info!("Launching the server again with the new TLS certificate");
+    }
 }
@@ -11,32 +11,311 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use rocket_db_pools::{sqlx, Connection, Database};
-use sqlx::{mysql::MySqlQueryResult, Acquire, Execute};
+use sqlx::{Acquire, AnyConnection, Execute};
+
+use crate::db_retry::RetryConfig;
 
 /// The database connection pool.
+/// The backend is selected by the scheme of the configured url, either MySQL (`mysql://`) or SQLite (`sqlite:`).
+/// The drivers must be installed with [`sqlx::any::install_default_drivers`] before initialising the pool.
 // https://api.rocket.rs/v0.5/rocket_db_pools/
 #[derive(Database)]
 #[database("ds")]
-pub struct DbConn(pub sqlx::MySqlPool);
+pub struct DbConn(pub sqlx::AnyPool);
+
+/// The SQL dialect of the connected database, for the few statements that differ between the backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    MySql,
+    Sqlite,
+}
+
+impl Dialect {
+    fn of(connection: &AnyConnection) -> Self {
+        if connection.backend_name() == "SQLite" {
+            Dialect::Sqlite
+        } else {
+            Dialect::MySql
+        }
+    }
+
+    /// The expression converting the bound number of seconds since the Unix epoch to a timestamp.
+    fn seconds_to_timestamp(self) -> &'static str {
+        match self {
+            Dialect::MySql => "FROM_UNIXTIME(?)",
+            Dialect::Sqlite => "datetime(?, 'unixepoch')",
+        }
+    }
+
+    /// The expression converting a timestamp column to the number of seconds since the Unix epoch.
+    fn timestamp_to_seconds(self, column: &str) -> String {
+        match self {
+            Dialect::MySql => format!("CAST(UNIX_TIMESTAMP({}) AS UNSIGNED)", column),
+            Dialect::Sqlite => format!("CAST(strftime('%s', {}) AS INTEGER)", column),
+        }
+    }
+
+    /// The statement adding a reference to a blob, inserting it with a single reference if missing.
+    fn upsert_blob(self) -> &'static str {
+        match self {
+            Dialect::MySql => "INSERT INTO blobs(folder_id, content_hash, ref_count) VALUES (?, ?, 1) ON DUPLICATE KEY UPDATE ref_count = ref_count + 1",
+            Dialect::Sqlite => "INSERT INTO blobs(folder_id, content_hash, ref_count) VALUES (?, ?, 1) ON CONFLICT(folder_id, content_hash) DO UPDATE SET ref_count = ref_count + 1",
+        }
+    }
+
+    /// The clause locking the selected rows until the end of the transaction, SQLite locks the whole database on write.
+    fn for_update(self) -> &'static str {
+        match self {
+            Dialect::MySql => " FOR UPDATE",
+            Dialect::Sqlite => "",
+        }
+    }
+}
+
+/// The current time in seconds since the Unix epoch.
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64)
+}
+
+/// Execute an insert, returning the id generated by the database for the inserted row.
+/// The SQLite driver does not report it in the query result, so it is read back on the same connection.
+async fn insert_returning_id<'q>(
+    query: sqlx::query::Query<'q, sqlx::Any, sqlx::any::AnyArguments<'q>>,
+    connection: &mut AnyConnection,
+) -> Result<u64, sqlx::Error> {
+    let result = query.execute(&mut *connection).await?;
+    match result.last_insert_id() {
+        Some(id) => Ok(id as u64),
+        None => sqlx::query_scalar::<_, i64>("SELECT last_insert_rowid()")
+            .fetch_one(connection)
+            .await
+            .map(|id| id as u64),
+    }
+}
 
 #[derive(sqlx::FromRow, Clone, Debug)]
 pub struct UserEntity {
     pub user_email: String,
+    /// The device the user authenticated from, not stored in the `users` table, see [`get_or_insert_device`].
+    #[sqlx(skip)]
+    pub device_id: Option<u64>,
+}
+
+/// A device of a user, identified by the fingerprint of its client certificate.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct DeviceEntity {
+    #[sqlx(try_from = "i64")]
+    pub device_id: u64,
+    /// The hex encoded SHA-256 fingerprint of the client certificate.
+    pub fingerprint: String,
+    /// The time of the first use of the device, in seconds since the Unix epoch.
+    #[sqlx(try_from = "i64")]
+    pub created_at: u64,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct FolderEntity {
     /// The id of the folder, auto-generated by the DB.
+    #[sqlx(try_from = "i64")]
+    pub folder_id: u64,
+}
+
+/// Implement the sqlx traits for an enum stored as text in the database.
+/// The derived implementation only matches the binary `ENUM` type of MySQL, while the
+/// `ENUM` and `VARCHAR` columns are sent as strings.
+macro_rules! impl_text_type {
+    ($ty:ident { $($variant:ident => $name:literal),+ $(,)? }) => {
+        impl $ty {
+            /// The name of the value stored in the database.
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($ty::$variant => $name),+
+                }
+            }
+        }
+
+        impl sqlx::Type<sqlx::Any> for $ty {
+            fn type_info() -> sqlx::any::AnyTypeInfo {
+                <str as sqlx::Type<sqlx::Any>>::type_info()
+            }
+
+            fn compatible(ty: &sqlx::any::AnyTypeInfo) -> bool {
+                <str as sqlx::Type<sqlx::Any>>::compatible(ty)
+            }
+        }
+
+        impl<'q> sqlx::Encode<'q, sqlx::Any> for $ty {
+            fn encode_by_ref(&self, buf: &mut <sqlx::Any as sqlx::database::HasArguments<'q>>::ArgumentBuffer) -> sqlx::encode::IsNull {
+                <&str as sqlx::Encode<'q, sqlx::Any>>::encode(self.as_str(), buf)
+            }
+        }
+
+        impl<'r> sqlx::Decode<'r, sqlx::Any> for $ty {
+            fn decode(value: sqlx::any::AnyValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+                match <&str as sqlx::Decode<'r, sqlx::Any>>::decode(value)? {
+                    $($name => Ok($ty::$variant),)+
+                    other => Err(format!("Unknown {} `{}`", stringify!($ty), other).into()),
+                }
+            }
+        }
+    };
+}
+
+/// The role of a user in a folder.
+/// The creator of a folder is its admin, the users the folder is shared with are members.
+#[derive(
+    serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FolderRole {
+    /// Can share the folder and manage its members, besides reading and writing files.
+    Admin,
+    /// Can only read and write files.
+    Member,
+}
+
+impl_text_type!(FolderRole {
+    Admin => "admin",
+    Member => "member",
+});
+
+/// The security-relevant actions recorded in the audit log of a folder.
+#[derive(
+    serde::Serialize, serde::Deserialize, utoipa::ToSchema, Debug, Clone, Copy, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    FolderCreated,
+    /// The target is the user the folder has been shared with.
+    FolderShared,
+    /// The target is the removed user.
+    MemberRemoved,
+    /// The target is the file id.
+    FileUploaded,
+    /// The target is the file id.
+    FileDownloaded,
+    ProposalPublished,
+    /// The target is the member that became the admin of the folder.
+    OwnershipTransferred,
+    /// The target is the id of the share link.
+    LinkCreated,
+    /// The target is the id of the share link.
+    LinkRevoked,
+    /// The actor is the creator of the share link, the target is the file id.
+    LinkFileDownloaded,
+    FolderExported,
+}
+
+impl_text_type!(AuditAction {
+    FolderCreated => "folder_created",
+    FolderShared => "folder_shared",
+    MemberRemoved => "member_removed",
+    FileUploaded => "file_uploaded",
+    FileDownloaded => "file_downloaded",
+    ProposalPublished => "proposal_published",
+    OwnershipTransferred => "ownership_transferred",
+    LinkCreated => "link_created",
+    LinkRevoked => "link_revoked",
+    LinkFileDownloaded => "link_file_downloaded",
+    FolderExported => "folder_exported",
+});
+
+/// An entry of the audit log of a folder.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AuditEventEntity {
+    #[sqlx(try_from = "i64")]
+    pub event_id: u64,
+    pub actor_email: String,
+    pub action: AuditAction,
+    /// Read as an empty string when missing, as the `Any` driver can't decode `NULL` values from SQLite.
+    #[sqlx(try_from = "String")]
+    pub target: Option<String>,
+    /// The time of the event, in seconds since the Unix epoch.
+    #[sqlx(try_from = "i64")]
+    pub created_at: u64,
+}
+
+/// A pending invitation to join a folder.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct InvitationEntity {
+    #[sqlx(try_from = "i64")]
+    pub invitation_id: u64,
+    #[sqlx(try_from = "i64")]
+    pub folder_id: u64,
+    pub inviter_email: String,
+    /// The time of the invitation, in seconds since the Unix epoch.
+    #[sqlx(try_from = "i64")]
+    pub created_at: u64,
+}
+
+/// A read-only share link of a folder.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct ShareLinkEntity {
+    #[sqlx(try_from = "i64")]
+    pub link_id: u64,
+    #[sqlx(try_from = "i64")]
     pub folder_id: u64,
+    pub creator_email: String,
+    /// The expiration time, in seconds since the Unix epoch.
+    #[sqlx(try_from = "i64")]
+    pub expires_at: u64,
+}
+
+/// A Web Push subscription of a user.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct PushSubscriptionEntity {
+    #[sqlx(try_from = "i64")]
+    pub subscription_id: u64,
+    pub endpoint: String,
+    /// The P-256 public key of the subscription, base64url encoded.
+    pub p256dh: String,
+    /// The authentication secret of the subscription, base64url encoded.
+    pub auth: String,
+}
+
+/// The effects of an accepted invitation, to notify the users involved.
+#[derive(Debug, Clone)]
+pub struct AcceptedInvitation {
+    pub folder_id: u64,
+    /// The members that received the proposal adding the invitee, in the same order of the message ids.
+    pub receivers: Vec<String>,
+    pub message_ids: Vec<u64>,
+    /// The id of the welcome message published for the invitee, if any.
+    pub welcome_id: Option<u64>,
+}
+
+/// A user that has access to a folder.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct FolderMemberEntity {
+    pub user_email: String,
+    pub role: FolderRole,
+}
+
+/// A folder together with the number of users that have access to it.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct FolderSummaryEntity {
+    #[sqlx(try_from = "i64")]
+    pub folder_id: u64,
+    pub member_count: i64,
+    /// The display name of the folder encrypted by the clients, empty if not set.
+    pub display_blob: Vec<u8>,
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct PendingGroupMessageEntity {
     /// The id of the message, autogenerated by the DB. We can use it to order the messages when delivering to the clients.
+    #[sqlx(try_from = "i64")]
     pub message_id: u64,
+    #[sqlx(try_from = "i64")]
     pub folder_id: u64,
     pub user_email: String,
     pub payload: Vec<u8>,
@@ -44,10 +323,35 @@ pub struct PendingGroupMessageEntity {
     pub creator: String,
 }
 
+/// The delivery receipt of a group message for one of its receivers.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct MessageReceiptEntity {
+    /// The id of the copy of the message sent to the receiver.
+    #[sqlx(try_from = "i64")]
+    pub message_id: u64,
+    pub sender_email: String,
+    pub receiver_email: String,
+    /// The time of the ack, in seconds since the Unix epoch, 0 until the message is acked.
+    #[sqlx(try_from = "i64")]
+    pub acked_at: u64,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WelcomeMessageEntity {
+    /// The id of the message, autogenerated by the DB.
+    #[sqlx(try_from = "i64")]
+    pub message_id: u64,
+    #[sqlx(try_from = "i64")]
+    pub folder_id: u64,
+    pub payload: Vec<u8>,
+}
+
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct GroupMessageEntity {
     /// The id of the message, autogenerated by the DB. We can use it to order the messages when delivering to the clients.
+    #[sqlx(try_from = "i64")]
     pub message_id: u64,
+    #[sqlx(try_from = "i64")]
     pub folder_id: u64,
     pub user_email: String,
     pub payload: Vec<u8>,
@@ -56,34 +360,75 @@ pub struct GroupMessageEntity {
 
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct KeyPackageEntity {
+    #[sqlx(try_from = "i64")]
     pub key_package_id: u64,
     pub user_email: String,
     pub key_package: Vec<u8>,
 }
 
+/// A resumable upload of a file, mapped to a multipart upload of the object store.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct FileUploadEntity {
+    #[sqlx(try_from = "i64")]
+    pub upload_id: u64,
+    /// The id of the multipart upload in the object store.
+    pub multipart_id: String,
+}
+
+/// A part of a resumable upload already stored in the object store.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct FileUploadPartEntity {
+    #[sqlx(try_from = "i64")]
+    pub part_number: u32,
+    /// The id of the part returned by the object store, needed to complete the upload.
+    pub content_id: String,
+    /// The size of the part in bytes.
+    #[sqlx(try_from = "i64")]
+    pub size: u64,
+}
+
+/// The bytes stored by a user across all folders and the bytes stored in a folder.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct UsageEntity {
+    #[sqlx(try_from = "i64")]
+    pub user_bytes: u64,
+    #[sqlx(try_from = "i64")]
+    pub folder_bytes: u64,
+}
+
 /// The type of a DB connection (as a request guard).
 pub type DbConnection = Connection<DbConn>;
 
-/// The number of parameters in MySQL must fit in a `u16`.
-const BIND_LIMIT: usize = 65535;
+/// The number of parameters must fit in a `u16` in MySQL, and SQLite accepts at most 32766 of them.
+const BIND_LIMIT: usize = 32766;
+
+/// The columns of the pending messages, the `created_at` timestamp is only used to expire them.
+const PENDING_MESSAGE_COLUMNS: &str = "message_id, folder_id, user_email, payload, creator";
 
 /// Remove the entry from folders_relation for the given folder and user.
+/// If the user was the only admin of the folder, another member is promoted in its place.
+/// Returns true if the user was the last one with access to the folder, and therefore the folder has been removed too,
+/// and [`sqlx::Error::RowNotFound`] if the user is not a member of the folder.
 pub async fn remove_user_from_folder(
     folder_id: u64,
     email: &str,
     mut db: Connection<DbConn>,
-) -> Result<(), sqlx::Error> {
+) -> Result<bool, sqlx::Error> {
     let mut transaction = db.begin().await?;
     log::debug!(
         "Start to remove user `{}` from folder `{}`",
         email,
         folder_id
     );
-    let _ = sqlx::query("DELETE FROM folders_users WHERE folder_id = ? AND user_email = ?")
-        .bind(folder_id)
+    let removed = sqlx::query("DELETE FROM folders_users WHERE folder_id = ? AND user_email = ?")
+        .bind(folder_id as i64)
         .bind(email)
         .execute(&mut *transaction)
         .await?;
+    if removed.rows_affected() == 0 {
+        log::debug!("User `{}` is not a member of folder `{}`", email, folder_id);
+        return Err(sqlx::Error::RowNotFound);
+    }
     log::debug!(
         "Removed user `{}` from folder `{}` completed.",
         email,
@@ -91,12 +436,21 @@ pub async fn remove_user_from_folder(
     );
     // Cleanup the proposals and pending messages for the user in this folder.
     let _ = delete_all_messages_by_user_and_folder(email, folder_id, &mut transaction).await?;
+    insert_audit_event_transaction(
+        folder_id,
+        email,
+        AuditAction::MemberRemoved,
+        Some(email),
+        &mut transaction,
+    )
+    .await?;
+    promote_admin_if_missing(folder_id, email, &mut transaction).await?;
     let count = count_users_for_folder(folder_id, &mut transaction).await?;
     log::debug!("Users count for folder `{}`: `{}`", folder_id, count);
     if count == 0 {
         // remove also the folder if no users have access to it anymore
         let _ = sqlx::query("DELETE FROM folders WHERE folder_id = ?")
-            .bind(folder_id)
+            .bind(folder_id as i64)
             .execute(&mut *transaction)
             .await?;
         log::debug!("Removed folder `{}`", folder_id);
@@ -107,7 +461,121 @@ pub async fn remove_user_from_folder(
         folder_id
     );
     transaction.commit().await?;
-    Ok(())
+    Ok(count == 0)
+}
+
+/// Delete a folder of which the user is the only member, together with the pending messages of the user.
+/// Returns `false`, leaving the folder unchanged, if other users still have access to it,
+/// and [`sqlx::Error::RowNotFound`] if the user is not a member of the folder.
+pub async fn delete_folder(
+    folder_id: u64,
+    email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let select = format!(
+        "SELECT user_email FROM folders_users WHERE folder_id = ?{}",
+        Dialect::of(&transaction).for_update()
+    );
+    let members: Vec<String> = sqlx::query_scalar(&select)
+        .bind(folder_id as i64)
+        .fetch_all(&mut *transaction)
+        .await?;
+    if !members.iter().any(|member| member == email) {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    if members.len() > 1 {
+        return Ok(false);
+    }
+    delete_all_messages_by_user_and_folder(email, folder_id, &mut transaction).await?;
+    sqlx::query("DELETE FROM folders_users WHERE folder_id = ?")
+        .bind(folder_id as i64)
+        .execute(&mut *transaction)
+        .await?;
+    insert_audit_event_transaction(
+        folder_id,
+        email,
+        AuditAction::MemberRemoved,
+        Some(email),
+        &mut transaction,
+    )
+    .await?;
+    sqlx::query("DELETE FROM folders WHERE folder_id = ?")
+        .bind(folder_id as i64)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    log::debug!("Deleted folder `{}` of user `{}`", folder_id, email);
+    Ok(true)
+}
+
+/// Remove a member from the folder on behalf of an admin, together with the pending messages of the member.
+/// If a proposal is given (e.g. the commit removing the member from the group), it is published to
+/// the remaining members in the same transaction.
+/// Returns the ids of the published messages, or `None` if the admin has still pending messages to process.
+pub async fn remove_member_from_folder(
+    folder_id: u64,
+    admin_email: &str,
+    member_email: &str,
+    proposal: Option<&[u8]>,
+    mut db: Connection<DbConn>,
+) -> Result<Option<(Vec<String>, Vec<u64>)>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    log::debug!(
+        "Start to remove member `{}` from folder `{}` on behalf of `{}`",
+        member_email,
+        folder_id,
+        admin_email
+    );
+    let removed = sqlx::query("DELETE FROM folders_users WHERE folder_id = ? AND user_email = ?")
+        .bind(folder_id as i64)
+        .bind(member_email)
+        .execute(&mut *transaction)
+        .await?;
+    if removed.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    delete_all_messages_by_user_and_folder(member_email, folder_id, &mut transaction).await?;
+    insert_audit_event_transaction(
+        folder_id,
+        admin_email,
+        AuditAction::MemberRemoved,
+        Some(member_email),
+        &mut transaction,
+    )
+    .await?;
+    let mut published = (vec![], vec![]);
+    if let Some(payload) = proposal {
+        // The member is not part of the folder anymore, so it won't receive the proposal.
+        match insert_message_transaction(admin_email, folder_id, payload, &mut transaction).await {
+            Ok(result) => published = result,
+            Err(Ok(_)) => return Ok(None),
+            Err(Err(e)) => return Err(e),
+        }
+    }
+    transaction.commit().await?;
+    log::debug!(
+        "Remove member `{}` from folder `{}` completed.",
+        member_email,
+        folder_id
+    );
+    Ok(Some(published))
+}
+
+/// Check that the database is reachable.
+pub async fn ping(db: &DbConn) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(&**db).await.map(|_| ())
+}
+
+/// Create or update the schema of the database running the embedded migrations of the `migrations` folder
+/// for its backend.
+pub async fn run_migrations(db: &DbConn) -> Result<(), sqlx::migrate::MigrateError> {
+    let dialect = Dialect::of(&*db.acquire().await?);
+    let migrator = match dialect {
+        Dialect::MySql => sqlx::migrate!("./migrations/mysql"),
+        Dialect::Sqlite => sqlx::migrate!("./migrations/sqlite"),
+    };
+    migrator.run(&**db).await
 }
 
 /// Get the user by the email from the database.
@@ -130,43 +598,547 @@ pub async fn insert_user(email: &str, mut db: Connection<DbConn>) -> Result<(),
         .map(|_| ())
 }
 
-/// List all the users from the database.
-pub async fn list_users(mut db: Connection<DbConn>) -> Result<Vec<UserEntity>, sqlx::Error> {
-    sqlx::query_as::<_, UserEntity>("SELECT * FROM users")
-        .fetch_all(&mut **db)
+/// The tombstone of a deleted account, the hex encoded SHA-256 hash of its email.
+/// It identifies the account without keeping its personal data.
+fn email_tombstone(email: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, email.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether the account of the email has been deleted, in which case the email can't be registered again.
+pub async fn is_deleted_user(
+    email: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM deleted_users WHERE email_hash = ?")
+        .bind(email_tombstone(email))
+        .fetch_one(&mut ***db)
+        .await?;
+    Ok(count > 0)
+}
+
+/// Get the id of the device of the user with the client certificate fingerprint, registering it on first use.
+/// Returns [`sqlx::Error::RowNotFound`] if the certificate is the device of another user.
+pub async fn get_or_insert_device(
+    user_email: &str,
+    fingerprint: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    let select = || {
+        sqlx::query_as::<_, (i64, String)>(
+            "SELECT device_id, user_email FROM devices WHERE fingerprint = ?",
+        )
+        .bind(fingerprint)
+    };
+    let (device_id, owner) = match select().fetch_optional(&mut ***db).await? {
+        Some(device) => device,
+        None => {
+            let insert = sqlx::query("INSERT INTO devices (user_email, fingerprint) VALUES (?, ?)")
+                .bind(user_email)
+                .bind(fingerprint);
+            match insert_returning_id(insert, db).await {
+                Ok(device_id) => {
+                    log::debug!(
+                        "Registered the device `{}` of user `{}`",
+                        device_id,
+                        user_email
+                    );
+                    return Ok(device_id);
+                }
+                // Registered by a concurrent request.
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    select().fetch_one(&mut ***db).await?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    if owner != user_email {
+        log::debug!(
+            "The device `{}` belongs to another user than `{}`",
+            device_id,
+            user_email
+        );
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(device_id as u64)
+}
+
+/// List the devices of a user, ordered by id.
+pub async fn list_devices(
+    user_email: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<DeviceEntity>, sqlx::Error> {
+    let created_at = Dialect::of(db).timestamp_to_seconds("created_at");
+    sqlx::query_as::<_, DeviceEntity>(&format!(
+        "SELECT device_id, fingerprint, {} AS created_at FROM devices WHERE user_email = ? ORDER BY device_id",
+        created_at
+    ))
+    .bind(user_email)
+    .fetch_all(&mut ***db)
+    .await
+}
+
+/// Delete the account of a user, removing it from all its folders together with its pending messages,
+/// key packages and uploads. The email is replaced by its tombstone in the audit log and recorded
+/// among the deleted users.
+/// As in [`remove_user_from_folder`], a member is promoted in place of the user in the folders it was the only admin of,
+/// and the storage used by the files the user uploaded is accounted to an admin of their folder.
+/// Returns the ids of the folders of the user together with their remaining members, if no members
+/// are left the folder has been removed too.
+pub async fn delete_user(
+    email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<(u64, Vec<String>)>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    log::debug!("Start to delete user `{}`", email);
+    let tombstone = email_tombstone(email);
+    let folder_ids: Vec<i64> =
+        sqlx::query_scalar("SELECT folder_id FROM folders_users WHERE user_email = ?")
+            .bind(email)
+            .fetch_all(&mut *transaction)
+            .await?;
+    let mut folders = vec![];
+    for folder_id in folder_ids.into_iter().map(|folder_id| folder_id as u64) {
+        sqlx::query("DELETE FROM folders_users WHERE folder_id = ? AND user_email = ?")
+            .bind(folder_id as i64)
+            .bind(email)
+            .execute(&mut *transaction)
+            .await?;
+        delete_all_messages_by_user_and_folder(email, folder_id, &mut transaction).await?;
+        insert_audit_event_transaction(
+            folder_id,
+            &tombstone,
+            AuditAction::MemberRemoved,
+            Some(&tombstone),
+            &mut transaction,
+        )
+        .await?;
+        promote_admin_if_missing(folder_id, &tombstone, &mut transaction).await?;
+        let members = list_users_by_folder(folder_id, &mut transaction).await?;
+        if members.is_empty() {
+            sqlx::query("DELETE FROM folders WHERE folder_id = ?")
+                .bind(folder_id as i64)
+                .execute(&mut *transaction)
+                .await?;
+            log::debug!("Removed folder `{}`", folder_id);
+        }
+        folders.push((folder_id, members));
+    }
+    // The files uploaded by the user keep counting towards the quotas of their folders, on behalf of an admin.
+    sqlx::query(
+        "UPDATE storage_usage SET user_email = (
+            SELECT admins.user_email FROM folders_users admins
+            WHERE admins.folder_id = storage_usage.folder_id AND admins.role = ?
+            ORDER BY admins.user_email LIMIT 1
+        ) WHERE user_email = ? AND EXISTS (
+            SELECT 1 FROM folders_users admins
+            WHERE admins.folder_id = storage_usage.folder_id AND admins.role = ?
+        )",
+    )
+    .bind(FolderRole::Admin)
+    .bind(email)
+    .bind(FolderRole::Admin)
+    .execute(&mut *transaction)
+    .await?;
+    for table in [
+        "pending_group_messages",
+        "welcome_messages",
+        "key_packages",
+        "file_uploads",
+        "storage_usage",
+        "push_subscriptions",
+        "devices",
+    ] {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_email = ?", table))
+            .bind(email)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    sqlx::query("DELETE FROM share_links WHERE creator_email = ?")
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM group_message_receipts WHERE sender_email = ? OR receiver_email = ?")
+        .bind(email)
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM invitations WHERE inviter_email = ? OR invitee_email = ?")
+        .bind(email)
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("UPDATE audit_log SET actor_email = ? WHERE actor_email = ?")
+        .bind(&tombstone)
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("UPDATE audit_log SET target = ? WHERE target = ?")
+        .bind(&tombstone)
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM users WHERE user_email = ?")
+        .bind(email)
+        .execute(&mut *transaction)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    sqlx::query("INSERT INTO deleted_users (email_hash) VALUES (?)")
+        .bind(&tombstone)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    log::debug!("Delete user `{}` completed.", email);
+    Ok(folders)
+}
+
+/// List at most `limit` users from the database, ordered by email.
+/// Only the users with an email greater than `after` and starting with `prefix` are returned, if given.
+pub async fn list_users(
+    prefix: Option<&str>,
+    after: Option<&str>,
+    limit: u32,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<UserEntity>, sqlx::Error> {
+    let mut query_builder = sqlx::QueryBuilder::new("SELECT * FROM users WHERE TRUE");
+    if let Some(prefix) = prefix {
+        query_builder.push(" AND user_email LIKE ");
+        query_builder.push_bind(format!("{}%", escape_like(prefix)));
+        query_builder.push(" ESCAPE '!'");
+    }
+    if let Some(after) = after {
+        query_builder.push(" AND user_email > ");
+        query_builder.push_bind(after);
+    }
+    query_builder.push(" ORDER BY user_email LIMIT ");
+    query_builder.push_bind(limit as i64);
+    let query = query_builder.build_query_as::<UserEntity>();
+    log::debug!("Query: `{}`", query.sql());
+    query.fetch_all(&mut **db).await
+}
+
+/// Escape the wildcards of a LIKE pattern, so that the value is matched literally.
+/// The escape character is `!`, as SQLite has no default one and the backslash is special in MySQL string literals.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('!', "!!")
+        .replace('%', "!%")
+        .replace('_', "!_")
+}
+
+/// Get the folder by the id from the database.
+pub async fn get_folder_by_id(
+    email: &str,
+    folder_id: u64,
+    mut db: Connection<DbConn>,
+) -> Result<FolderEntity, sqlx::Error> {
+    sqlx::query_as::<_, FolderEntity>(
+        "
+    SELECT folders.folder_id FROM folders 
+    JOIN folders_users ON folders.folder_id = folders_users.folder_id 
+    WHERE folders.folder_id = ? AND folders_users.user_email = ?",
+    )
+    .bind(folder_id as i64)
+    .bind(&email)
+    .fetch_one(&mut **db)
+    .await
+}
+
+/// Get the role of the user in the folder.
+/// Returns [`sqlx::Error::RowNotFound`] if the user has no access to the folder.
+pub async fn get_folder_role(
+    email: &str,
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<FolderRole, sqlx::Error> {
+    sqlx::query_scalar::<_, FolderRole>(
+        "SELECT role FROM folders_users WHERE folder_id = ? AND user_email = ?",
+    )
+    .bind(folder_id as i64)
+    .bind(email)
+    .fetch_one(&mut ***db)
+    .await
+}
+
+/// Get the display name of the folder encrypted by the clients, empty if not set.
+pub async fn get_folder_display_blob(
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<u8>, sqlx::Error> {
+    sqlx::query_scalar("SELECT display_blob FROM folders WHERE folder_id = ?")
+        .bind(folder_id as i64)
+        .fetch_one(&mut ***db)
+        .await
+}
+
+/// Set the display name of the folder encrypted by the clients, an empty blob unsets it.
+pub async fn set_folder_display_blob(
+    folder_id: u64,
+    display_blob: &[u8],
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE folders SET display_blob = ? WHERE folder_id = ?")
+        .bind(display_blob)
+        .bind(folder_id as i64)
+        .execute(&mut ***db)
         .await
+        .map(|_| ())
+}
+
+/// Create a read-only share link of a folder, identified by the hash of its bearer token, returning its id.
+pub async fn insert_share_link(
+    folder_id: u64,
+    creator_email: &str,
+    token_hash: &str,
+    expires_at: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let link_id = insert_returning_id(
+        sqlx::query(
+            "INSERT INTO share_links(folder_id, creator_email, token_hash, expires_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(folder_id as i64)
+        .bind(creator_email)
+        .bind(token_hash)
+        .bind(expires_at as i64),
+        &mut transaction,
+    )
+    .await?;
+    insert_audit_event_transaction(
+        folder_id,
+        creator_email,
+        AuditAction::LinkCreated,
+        Some(&link_id.to_string()),
+        &mut transaction,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(link_id)
+}
+
+/// The columns of the active share links, whose creator is still a member of the folder.
+const ACTIVE_SHARE_LINKS: &str = "SELECT share_links.link_id, share_links.folder_id, share_links.creator_email, share_links.expires_at 
+    FROM share_links 
+        JOIN folders_users ON folders_users.folder_id = share_links.folder_id AND folders_users.user_email = share_links.creator_email 
+    WHERE share_links.expires_at > ?";
+
+/// List the active share links of a folder, ordered by id.
+pub async fn list_share_links(
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<ShareLinkEntity>, sqlx::Error> {
+    sqlx::query_as::<_, ShareLinkEntity>(&format!(
+        "{} AND share_links.folder_id = ? ORDER BY share_links.link_id",
+        ACTIVE_SHARE_LINKS
+    ))
+    .bind(unix_now())
+    .bind(folder_id as i64)
+    .fetch_all(&mut ***db)
+    .await
+}
+
+/// Get the active share link with the hash of the bearer token, or [`sqlx::Error::RowNotFound`]
+/// if it doesn't exist, expired or its creator left the folder.
+pub async fn get_share_link(
+    token_hash: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<ShareLinkEntity, sqlx::Error> {
+    sqlx::query_as::<_, ShareLinkEntity>(&format!(
+        "{} AND share_links.token_hash = ?",
+        ACTIVE_SHARE_LINKS
+    ))
+    .bind(unix_now())
+    .bind(token_hash)
+    .fetch_one(&mut ***db)
+    .await
+}
+
+/// Revoke a share link of a folder, if it has been created by `email` or `email` is an admin of the folder.
+/// Returns whether the link has been revoked.
+pub async fn delete_share_link(
+    folder_id: u64,
+    link_id: u64,
+    email: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let result = sqlx::query(
+        "DELETE FROM share_links WHERE folder_id = ? AND link_id = ? AND (creator_email = ? OR EXISTS (
+            SELECT 1 FROM folders_users WHERE folders_users.folder_id = ? AND folders_users.user_email = ? AND folders_users.role = ?))",
+    )
+    .bind(folder_id as i64)
+    .bind(link_id as i64)
+    .bind(email)
+    .bind(folder_id as i64)
+    .bind(email)
+    .bind(FolderRole::Admin)
+    .execute(&mut *transaction)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    insert_audit_event_transaction(
+        folder_id,
+        email,
+        AuditAction::LinkRevoked,
+        Some(&link_id.to_string()),
+        &mut transaction,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(true)
+}
+
+/// Delete the expired share links, returning how many were deleted.
+pub async fn delete_expired_share_links(db: &sqlx::AnyPool) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query("DELETE FROM share_links WHERE expires_at <= ?")
+        .bind(unix_now())
+        .execute(db)
+        .await?
+        .rows_affected())
+}
+
+/// Transfer the admin role of a folder to another member in a single transaction,
+/// the previous admin stays in the folder as a member.
+/// Returns the members of the folder, or [`sqlx::Error::RowNotFound`] if the sender is not an admin
+/// or the new admin is not a member of the folder.
+pub async fn transfer_folder_ownership(
+    folder_id: u64,
+    admin_email: &str,
+    member_email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    log::debug!(
+        "Start to transfer the ownership of folder `{}` from `{}` to `{}`",
+        folder_id,
+        admin_email,
+        member_email
+    );
+    let demoted = sqlx::query(
+        "UPDATE folders_users SET role = ? WHERE folder_id = ? AND user_email = ? AND role = ?",
+    )
+    .bind(FolderRole::Member)
+    .bind(folder_id as i64)
+    .bind(admin_email)
+    .bind(FolderRole::Admin)
+    .execute(&mut *transaction)
+    .await?;
+    if demoted.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    let promoted =
+        sqlx::query("UPDATE folders_users SET role = ? WHERE folder_id = ? AND user_email = ?")
+            .bind(FolderRole::Admin)
+            .bind(folder_id as i64)
+            .bind(member_email)
+            .execute(&mut *transaction)
+            .await?;
+    if promoted.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    insert_audit_event_transaction(
+        folder_id,
+        admin_email,
+        AuditAction::OwnershipTransferred,
+        Some(member_email),
+        &mut transaction,
+    )
+    .await?;
+    let members = list_users_by_folder(folder_id, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(members)
+}
+
+/// Promote a member of the folder to admin if none is left, e.g. once its only admin left the folder,
+/// so that its members can still be managed. As in the `folder_admins` migration, the first member by email
+/// is promoted, who can then transfer the ownership. The promotion is recorded as a transfer by `previous_admin`.
+/// Returns the promoted member, if any.
+async fn promote_admin_if_missing(
+    folder_id: u64,
+    previous_admin: &str,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+) -> Result<Option<String>, sqlx::Error> {
+    let select = format!(
+        "SELECT user_email, role FROM folders_users WHERE folder_id = ? ORDER BY user_email{}",
+        Dialect::of(&*transaction).for_update()
+    );
+    let members = sqlx::query_as::<_, FolderMemberEntity>(&select)
+        .bind(folder_id as i64)
+        .fetch_all(&mut **transaction)
+        .await?;
+    if members
+        .iter()
+        .any(|member| member.role == FolderRole::Admin)
+    {
+        return Ok(None);
+    }
+    let Some(member) = members.into_iter().next() else {
+        return Ok(None);
+    };
+    sqlx::query("UPDATE folders_users SET role = ? WHERE folder_id = ? AND user_email = ?")
+        .bind(FolderRole::Admin)
+        .bind(folder_id as i64)
+        .bind(&member.user_email)
+        .execute(&mut **transaction)
+        .await?;
+    insert_audit_event_transaction(
+        folder_id,
+        previous_admin,
+        AuditAction::OwnershipTransferred,
+        Some(&member.user_email),
+        transaction,
+    )
+    .await?;
+    log::debug!(
+        "Promoted `{}` to admin of folder `{}`",
+        member.user_email,
+        folder_id
+    );
+    Ok(Some(member.user_email))
 }
 
-/// Get the folder by the id from the database.
-pub async fn get_folder_by_id(
-    email: &str,
+/// List all the users that have access to the folder together with their role, ordered by email.
+pub async fn list_folder_members(
     folder_id: u64,
     mut db: Connection<DbConn>,
-) -> Result<FolderEntity, sqlx::Error> {
-    sqlx::query_as::<_, FolderEntity>(
-        "
-    SELECT * FROM folders 
-    JOIN folders_users ON folders.folder_id = folders_users.folder_id 
-    WHERE folders.folder_id = ? AND folders_users.user_email = ?",
+) -> Result<Vec<FolderMemberEntity>, sqlx::Error> {
+    sqlx::query_as::<_, FolderMemberEntity>(
+        "SELECT user_email, role FROM folders_users WHERE folder_id = ? ORDER BY user_email",
     )
-    .bind(&folder_id)
-    .bind(&email)
-    .fetch_one(&mut **db)
+    .bind(folder_id as i64)
+    .fetch_all(&mut **db)
     .await
 }
 
-/// List all the folders for a user from the database.
+/// List at most `limit` folders of a user from the database, ordered by id and starting after the folder `after`.
+/// Each folder is returned together with its number of members.
 pub async fn list_folders(
     email: &str,
+    after: Option<u64>,
+    limit: u32,
     mut db: Connection<DbConn>,
-) -> Result<Vec<FolderEntity>, sqlx::Error> {
-    sqlx::query_as::<_, FolderEntity>(
-        "SELECT * FROM folders 
-        JOIN folders_users ON folders.folder_id = folders_users.folder_id 
-        JOIN users ON users.user_email = folders_users.user_email 
-        WHERE users.user_email = ?",
+) -> Result<Vec<FolderSummaryEntity>, sqlx::Error> {
+    sqlx::query_as::<_, FolderSummaryEntity>(
+        "SELECT folders_users.folder_id, 
+            (SELECT COUNT(*) FROM folders_users AS members WHERE members.folder_id = folders_users.folder_id) AS member_count, 
+            folders.display_blob 
+        FROM folders_users 
+            JOIN folders ON folders.folder_id = folders_users.folder_id 
+        WHERE folders_users.user_email = ? AND folders_users.folder_id > ? 
+        ORDER BY folders_users.folder_id 
+        LIMIT ?",
     )
     .bind(&email)
+    .bind(after.unwrap_or(0) as i64)
+    .bind(limit as i64)
     .fetch_all(&mut **db)
     .await
 }
@@ -174,10 +1146,10 @@ pub async fn list_folders(
 /// List all the folders for a user from the database.
 async fn list_folders_for_user(
     email: &str,
-    db: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    db: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<FolderEntity>, sqlx::Error> {
     sqlx::query_as::<_, FolderEntity>(
-        "SELECT * 
+        "SELECT folders.folder_id 
         FROM folders 
             JOIN folders_users ON folders.folder_id = folders_users.folder_id 
             JOIN users ON users.user_email = folders_users.user_email 
@@ -191,11 +1163,11 @@ async fn list_folders_for_user(
 /// Count the number of users that have access to the folder.
 async fn count_users_for_folder(
     folder_id: u64,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<i64, sqlx::Error> {
     let count: Option<i64> =
         sqlx::query_scalar("SELECT COUNT(*) FROM folders_users WHERE folder_id = ?")
-            .bind(folder_id)
+            .bind(folder_id as i64)
             .fetch_optional(&mut **transaction)
             .await?;
     if let Some(count) = count {
@@ -221,7 +1193,7 @@ pub async fn list_users_for_folder(
 pub async fn list_users_for_folder_transaction(
     user_emails: &Vec<&str>,
     folder_id: u64,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<UserEntity>, sqlx::Error> {
     let chunks = user_emails.chunks(BIND_LIMIT);
     let mut users = Vec::with_capacity(user_emails.capacity());
@@ -238,17 +1210,17 @@ pub async fn list_users_for_folder_transaction(
 async fn unsafe_list_users_for_folder(
     emails: &[&str],
     folder_id: u64,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<UserEntity>, sqlx::Error> {
     let mut query_builder = sqlx::QueryBuilder::new(
-        "SELECT * 
+        "SELECT users.user_email 
         FROM folders 
             JOIN folders_users ON folders.folder_id = folders_users.folder_id 
             JOIN users ON users.user_email = folders_users.user_email 
         WHERE 
             folders.folder_id = ",
     );
-    query_builder.push_bind(folder_id);
+    query_builder.push_bind(folder_id as i64);
     query_builder.push(" AND users.user_email IN ");
     query_builder.push_tuples(emails, |mut b, user_email| {
         b.push_bind(user_email);
@@ -265,9 +1237,22 @@ pub async fn insert_folder_and_relation(
 ) -> Result<u64, Box<dyn Error + Send + Sync>> {
     log::debug!("Start to create a folder for user: `{}`", user_email);
     let mut transaction = db.begin().await?;
-    let folder_id = insert_folder(&mut transaction).await?.last_insert_id();
+    let folder_id = insert_folder(&mut transaction).await?;
     log::debug!("Inserted folder with id: `{}`", folder_id);
-    insert_folders_to_users(folder_id, &vec![user_email], &mut transaction).await?;
+    sqlx::query("INSERT INTO folders_users(folder_id, user_email, role) VALUES (?, ?, ?)")
+        .bind(folder_id as i64)
+        .bind(user_email)
+        .bind(FolderRole::Admin)
+        .execute(&mut *transaction)
+        .await?;
+    insert_audit_event_transaction(
+        folder_id,
+        user_email,
+        AuditAction::FolderCreated,
+        None,
+        &mut transaction,
+    )
+    .await?;
     log::debug!("Inserted folder to users completed.");
     transaction.commit().await?;
     Ok(folder_id)
@@ -323,12 +1308,177 @@ pub async fn insert_folder_users_relations(
         }
         message_ids = insert_message_result.unwrap().1;
     }
+    for user_email in to_add {
+        insert_audit_event_transaction(
+            folder_id,
+            owner_email,
+            AuditAction::FolderShared,
+            Some(user_email),
+            &mut transaction,
+        )
+        .await?;
+    }
     log::debug!("Inserted folder to users completed.");
     transaction.commit().await?;
     // The transaction is ended implicitely when the `transaction` object is dropped.
     Ok((is_owner, Some(message_ids)))
 }
 
+/// Invite a user to join a folder, the MLS proposal adding the user and its welcome message
+/// are kept until the invitation is accepted (empty if missing).
+/// Fails with a database error if the user has already been invited to the folder.
+pub async fn insert_invitation(
+    folder_id: u64,
+    inviter_email: &str,
+    invitee_email: &str,
+    proposal: &[u8],
+    welcome: &[u8],
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    log::debug!(
+        "Inserting an invitation for user `{}` to folder `{}`",
+        invitee_email,
+        folder_id
+    );
+    insert_returning_id(
+        sqlx::query(
+            "INSERT INTO invitations(folder_id, inviter_email, invitee_email, proposal, welcome) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(folder_id as i64)
+        .bind(inviter_email)
+        .bind(invitee_email)
+        .bind(proposal)
+        .bind(welcome),
+        db,
+    )
+    .await
+}
+
+/// List the pending invitations of a user, oldest first.
+pub async fn list_invitations_by_invitee(
+    invitee_email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<InvitationEntity>, sqlx::Error> {
+    let created_at = Dialect::of(&db).timestamp_to_seconds("created_at");
+    sqlx::query_as::<_, InvitationEntity>(&format!(
+        "SELECT invitation_id, folder_id, inviter_email, {} AS created_at
+        FROM invitations
+        WHERE invitee_email = ?
+        ORDER BY invitation_id",
+        created_at
+    ))
+    .bind(invitee_email)
+    .fetch_all(&mut **db)
+    .await
+}
+
+/// Accept an invitation: the invitee becomes a member of the folder, and the MLS proposal and the
+/// welcome message of the invitation are published in the same transaction.
+/// Returns `None` if the inviter has pending messages to process, as the proposal is then outdated.
+pub async fn accept_invitation(
+    invitation_id: u64,
+    invitee_email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<Option<AcceptedInvitation>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let (folder_id, inviter_email, proposal, welcome) =
+        sqlx::query_as::<_, (i64, String, Vec<u8>, Vec<u8>)>(
+            "SELECT folder_id, inviter_email, proposal, welcome FROM invitations WHERE invitation_id = ? AND invitee_email = ?",
+        )
+        .bind(invitation_id as i64)
+        .bind(invitee_email)
+        .fetch_one(&mut *transaction)
+        .await?;
+    let folder_id = folder_id as u64;
+    log::debug!(
+        "User `{}` accepts the invitation of `{}` to folder `{}`",
+        invitee_email,
+        inviter_email,
+        folder_id
+    );
+    if !list_users_by_folder(folder_id, &mut transaction)
+        .await?
+        .contains(&inviter_email)
+    {
+        log::debug!(
+            "The inviter `{}` left folder `{}`",
+            inviter_email,
+            folder_id
+        );
+        return Err(sqlx::Error::RowNotFound);
+    }
+    let mut accepted = AcceptedInvitation {
+        folder_id,
+        receivers: vec![],
+        message_ids: vec![],
+        welcome_id: None,
+    };
+    if !proposal.is_empty() {
+        // Publish the proposal before adding the invitee, that can't read the proposal adding itself.
+        match insert_message_transaction(&inviter_email, folder_id, &proposal, &mut transaction)
+            .await
+        {
+            Ok((users, message_ids)) => {
+                accepted.receivers = users
+                    .into_iter()
+                    .filter(|user| *user != inviter_email)
+                    .collect();
+                accepted.message_ids = message_ids;
+            }
+            Err(Ok(_)) => return Ok(None),
+            Err(Err(e)) => return Err(e),
+        }
+    }
+    insert_folders_to_users(folder_id, &vec![invitee_email], &mut transaction).await?;
+    if !welcome.is_empty() {
+        let welcome_id = insert_returning_id(
+            sqlx::query(
+                "INSERT INTO welcome_messages(user_email, folder_id, payload) VALUES (?, ?, ?)",
+            )
+            .bind(invitee_email)
+            .bind(folder_id as i64)
+            .bind(welcome),
+            &mut transaction,
+        )
+        .await?;
+        accepted.welcome_id = Some(welcome_id);
+    }
+    insert_audit_event_transaction(
+        folder_id,
+        &inviter_email,
+        AuditAction::FolderShared,
+        Some(invitee_email),
+        &mut transaction,
+    )
+    .await?;
+    sqlx::query("DELETE FROM invitations WHERE invitation_id = ?")
+        .bind(invitation_id as i64)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(Some(accepted))
+}
+
+/// Delete an invitation, either declined by the invitee or withdrawn by the inviter.
+pub async fn delete_invitation(
+    invitation_id: u64,
+    email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    let deleted = sqlx::query(
+        "DELETE FROM invitations WHERE invitation_id = ? AND (invitee_email = ? OR inviter_email = ?)",
+    )
+    .bind(invitation_id as i64)
+    .bind(email)
+    .bind(email)
+    .execute(&mut **db)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
 /// Safely get all [`UserEntity`] by their emails.
 /// If the array of users is to big, the query will be chunked.
 pub async fn get_users_by_emails(
@@ -358,7 +1508,7 @@ pub async fn get_users_by_emails(
 /// Use [`get_users_by_emails`] instead
 async fn unsafe_get_users_by_emails(
     user_emails: &[&str],
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<UserEntity>, sqlx::Error> {
     let mut query_builder = sqlx::QueryBuilder::new("SELECT * FROM users WHERE (user_email) IN");
     query_builder.push_tuples(user_emails, |mut b, user_email| {
@@ -370,19 +1520,21 @@ async fn unsafe_get_users_by_emails(
 
 /// Insert the folder in the database.
 async fn insert_folder(
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
-) -> Result<MySqlQueryResult, sqlx::Error> {
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+) -> Result<u64, sqlx::Error> {
     log::debug!("Creating a new folder");
-    sqlx::query("INSERT INTO folders () VALUES ()")
-        .execute(&mut **transaction)
-        .await
+    insert_returning_id(
+        sqlx::query("INSERT INTO folders (folder_id) VALUES (NULL)"),
+        transaction,
+    )
+    .await
 }
 
 /// Insert a row inside the relations `folder_users` table for each of the user_id.
 async fn insert_folders_to_users(
     folder_id: u64,
     user_emails: &Vec<&str>,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<(), sqlx::Error> {
     let chunks = user_emails.chunks(BIND_LIMIT);
     for chunk in chunks {
@@ -400,61 +1552,68 @@ async fn insert_folders_to_users(
 async fn unsafe_insert_folders_to_users(
     folder_id: u64,
     user_emails: &[&str],
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<(), sqlx::Error> {
     let values = user_emails.iter().map(|user_email| (folder_id, user_email));
     let mut query_builder =
         sqlx::QueryBuilder::new("INSERT INTO folders_users(folder_id, user_email)");
     let query = query_builder
         .push_values(values, |mut b, (folder_id, user_email)| {
-            b.push_bind(folder_id).push_bind(user_email);
+            b.push_bind(folder_id as i64).push_bind(user_email);
         })
         .build();
     query.execute(&mut **transaction).await.map(|_| ())
 }
 
-/// Delete the user from the database.
-async fn delete_user(email: &str, mut db: Connection<DbConn>) -> Result<(), sqlx::Error> {
-    sqlx::query("DELETE FROM users WHERE user_email = ?")
-        .bind(&email)
-        .execute(&mut **db)
-        .await
-        .map(|_| ())
+/// Returns the emails of all users that partecipate in a folder.
+pub async fn list_emails_by_folder(
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let users = list_users_by_folder(folder_id, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(users)
 }
 
 /// Returns all users that partecipate in a folder.
 async fn list_users_by_folder(
     folder_id: u64,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<String>, sqlx::Error> {
     log::debug!("Listing users for folder `{}`", folder_id);
     let query =
         sqlx::query_scalar::<_, String>("SELECT user_email FROM folders_users WHERE folder_id = ?")
-            .bind(&folder_id);
+            .bind(folder_id as i64);
     query.fetch_all(&mut **transaction).await
 }
 
+/// Insert a welcome message for a user that is already a member of the folder, returning its id.
 pub async fn insert_welcome(
     sender_email: &str,
     receiver_email: &str,
     folder_id: u64,
     payload: &[u8],
     db: &mut Connection<DbConn>,
-) -> Result<(), sqlx::Error> {
+) -> Result<u64, sqlx::Error> {
     let mut transaction = db.begin().await?;
     let users = list_users_by_folder(folder_id, &mut transaction).await?;
     if !users.contains(&sender_email.to_string()) || !users.contains(&receiver_email.to_string()) {
         return Err(sqlx::Error::RowNotFound);
     }
     log::debug!("Inserting a welcome message for user `{}`", receiver_email);
-    sqlx::query("INSERT INTO welcome_messages(user_email, folder_id, payload) VALUES (?, ?, ?)")
+    let message_id = insert_returning_id(
+        sqlx::query(
+            "INSERT INTO welcome_messages(user_email, folder_id, payload) VALUES (?, ?, ?)",
+        )
         .bind(receiver_email)
-        .bind(folder_id)
-        .bind(payload)
-        .execute(&mut *transaction)
-        .await?;
-    let _ = transaction.commit().await;
-    Ok(())
+        .bind(folder_id as i64)
+        .bind(payload),
+        &mut transaction,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(message_id)
 }
 
 /// Removes a message from the db. To be done only when the client acks that the message was processed.
@@ -464,22 +1623,26 @@ pub async fn delete_welcome(
     folder_id: u64,
     mut db: Connection<DbConn>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    let result = sqlx::query(
         "DELETE FROM welcome_messages WHERE message_id = ? AND user_email = ? AND folder_id = ?",
     )
-    .bind(message_id)
+    .bind(message_id as i64)
     .bind(user_email)
-    .bind(folder_id)
+    .bind(folder_id as i64)
     .execute(&mut **db)
-    .await
-    .map(|_| ())
+    .await?;
+    if result.rows_affected() == 0 {
+        Err(sqlx::Error::RowNotFound)
+    } else {
+        Ok(())
+    }
 }
 
 async fn insert_message_transaction(
     sender_email: &str,
     folder_id: u64,
     payload: &[u8],
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<(Vec<String>, Vec<u64>), Result<i64, sqlx::Error>> {
     let pending_messages =
         count_pending_messages_for_folder_and_user(folder_id, sender_email, transaction).await;
@@ -501,19 +1664,37 @@ async fn insert_message_transaction(
                                     "Inserting a pending group message for user `{}`",
                                     user
                                 );
-                                let res = sqlx::query(
-                                    "INSERT INTO pending_group_messages(user_email, folder_id, payload, creator) VALUES (?, ?, ?, ?)",
+                                let res = insert_returning_id(
+                                    sqlx::query(
+                                        "INSERT INTO pending_group_messages(user_email, folder_id, payload, creator) VALUES (?, ?, ?, ?)",
+                                    )
+                                    .bind(&user)
+                                    .bind(folder_id as i64)
+                                    .bind(payload)
+                                    .bind(sender_email),
+                                    transaction,
                                 )
-                                .bind(user)
-                                .bind(folder_id)
-                                .bind(payload)
-                                .bind(sender_email)
-                                .execute(&mut **transaction)
                                 .await;
                                 if let Err(e) = res {
                                     return Err(Err(e));
                                 }
-                                message_ids.push(res.unwrap().last_insert_id());
+                                let message_id = res.unwrap();
+                                // The copies of the message share the id of the first one.
+                                let proposal_id = *message_ids.first().unwrap_or(&message_id);
+                                if let Err(e) = sqlx::query(
+                                    "INSERT INTO group_message_receipts(message_id, proposal_id, folder_id, sender_email, receiver_email) VALUES (?, ?, ?, ?, ?)",
+                                )
+                                .bind(message_id as i64)
+                                .bind(proposal_id as i64)
+                                .bind(folder_id as i64)
+                                .bind(sender_email)
+                                .bind(&user)
+                                .execute(&mut **transaction)
+                                .await
+                                {
+                                    return Err(Err(e));
+                                }
+                                message_ids.push(message_id);
                             }
                         }
                         Ok((users, message_ids))
@@ -530,24 +1711,61 @@ async fn insert_message_transaction(
 
 /// Insert a message for a group in the queue of all other members apart from the sender.
 /// Returns an error and abort transaction if the sender has still pending messages in that folder.
+/// The transaction is retried if it fails because of a concurrent one, e.g. on a deadlock.
 pub async fn insert_message(
+    sender_email: &str,
+    folder_id: u64,
+    payload: &[u8],
+    retry: &RetryConfig,
+    db: &mut Connection<DbConn>,
+) -> Result<(Vec<String>, Vec<u64>), Result<i64, sqlx::Error>> {
+    let mut attempt = 0;
+    loop {
+        match insert_message_once(sender_email, folder_id, payload, db).await {
+            Err(Err(e)) if retry.retry(attempt, &e).await => attempt += 1,
+            res => return res,
+        }
+    }
+}
+
+async fn insert_message_once(
     sender_email: &str,
     folder_id: u64,
     payload: &[u8],
     db: &mut Connection<DbConn>,
 ) -> Result<(Vec<String>, Vec<u64>), Result<i64, sqlx::Error>> {
-    match db.begin().await {
-        Ok(mut transaction) => {
-            let users_and_msg_ids =
-                insert_message_transaction(sender_email, folder_id, payload, &mut transaction)
-                    .await;
-            let res = transaction.commit().await;
-            if let Err(e) = res {
-                return Err(Err(e));
+    let mut transaction = db.begin().await.map_err(Err)?;
+    let result = async {
+        let users_and_msg_ids =
+            insert_message_transaction(sender_email, folder_id, payload, &mut transaction).await?;
+        insert_audit_event_transaction(
+            folder_id,
+            sender_email,
+            AuditAction::ProposalPublished,
+            None,
+            &mut transaction,
+        )
+        .await
+        .map_err(Err)?;
+        Ok(users_and_msg_ids)
+    }
+    .await;
+    match result {
+        Ok(users_and_msg_ids) => {
+            transaction.commit().await.map_err(Err)?;
+            Ok(users_and_msg_ids)
+        }
+        Err(e) => {
+            // Never commit a partial insertion: e.g. a lock wait timeout only rolls back the failing statement,
+            // and the copies already inserted for some members would be inserted again by the retry.
+            if let Err(rollback) = transaction.rollback().await {
+                log::error!(
+                    "Couldn't roll back the insertion of the message: `{}`",
+                    rollback
+                );
             }
-            users_and_msg_ids
+            Err(e)
         }
-        Err(e) => Err(Err(e)),
     }
 }
 
@@ -555,103 +1773,350 @@ pub async fn insert_message(
 async fn count_pending_messages_for_folder_and_user(
     folder_id: u64,
     user_email: &str,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
-) -> Result<i64, sqlx::Error> {
-    log::debug!(
-        "Counting the number of pending messages for the user `{}`",
-        user_email
-    );
-    let count: Option<i64> = sqlx::query_scalar(
-        "SELECT COUNT(*) FROM pending_group_messages WHERE user_email = ? AND folder_id = ?",
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+) -> Result<i64, sqlx::Error> {
+    log::debug!(
+        "Counting the number of pending messages for the user `{}`",
+        user_email
+    );
+    let count: Option<i64> = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM pending_group_messages WHERE user_email = ? AND folder_id = ?",
+    )
+    .bind(user_email)
+    .bind(folder_id as i64)
+    .fetch_optional(&mut **transaction)
+    .await?;
+    if let Some(count) = count {
+        Ok(count)
+    } else {
+        log::error!("This should not happen!");
+        Err(sqlx::Error::RowNotFound)
+    }
+}
+
+/// Removes a message from the db. To be done only when the client acks that the message was processed.
+pub async fn delete_message(
+    message_id: u64,
+    user_email: &str,
+    folder_id: u64,
+    mut db: Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let first = sqlx::query_as::<_, PendingGroupMessageEntity>(&format!(
+        "SELECT {} FROM pending_group_messages WHERE user_email = ? AND folder_id = ? ORDER BY message_id ASC LIMIT 1",
+        PENDING_MESSAGE_COLUMNS
+    ))
+    .bind(user_email)
+    .bind(folder_id as i64)
+    .fetch_one(&mut *transaction)
+    .await?;
+    let result = if first.message_id < message_id {
+        Ok(false)
+    } else {
+        sqlx::query("DELETE FROM pending_group_messages WHERE message_id = ? AND user_email = ? AND folder_id = ?")
+            .bind(message_id as i64)
+            .bind(user_email)
+            .bind(folder_id as i64)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("UPDATE group_message_receipts SET acked_at = ? WHERE message_id = ? AND receiver_email = ? AND folder_id = ?")
+            .bind(unix_now())
+            .bind(message_id as i64)
+            .bind(user_email)
+            .bind(folder_id as i64)
+            .execute(&mut *transaction)
+            .await
+            .map(|_| true)
+    };
+    transaction.commit().await?;
+    result
+}
+
+/// Removes all the messages of a user for a given folder up to the given one included,
+/// once the client acks that they were processed. Returns the number of removed messages.
+pub async fn delete_messages_up_to(
+    message_id: u64,
+    user_email: &str,
+    folder_id: u64,
+    mut db: Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let deleted = sqlx::query(
+        "DELETE FROM pending_group_messages WHERE message_id <= ? AND user_email = ? AND folder_id = ?",
+    )
+    .bind(message_id as i64)
+    .bind(user_email)
+    .bind(folder_id as i64)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    sqlx::query(
+        "UPDATE group_message_receipts SET acked_at = ? WHERE message_id <= ? AND receiver_email = ? AND folder_id = ? AND acked_at = 0",
+    )
+    .bind(unix_now())
+    .bind(message_id as i64)
+    .bind(user_email)
+    .bind(folder_id as i64)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(deleted)
+}
+
+/// List the delivery receipts of the group message with the id of any of its copies, ordered by receiver.
+/// Returns [`sqlx::Error::RowNotFound`] if the message doesn't exist in the folder.
+pub async fn list_message_receipts(
+    message_id: u64,
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<MessageReceiptEntity>, sqlx::Error> {
+    let receipts = sqlx::query_as::<_, MessageReceiptEntity>(
+        "SELECT message_id, sender_email, receiver_email, acked_at 
+        FROM group_message_receipts 
+        WHERE folder_id = ? AND proposal_id = (SELECT proposal_id FROM group_message_receipts WHERE message_id = ?) 
+        ORDER BY receiver_email",
+    )
+    .bind(folder_id as i64)
+    .bind(message_id as i64)
+    .fetch_all(&mut ***db)
+    .await?;
+    if receipts.is_empty() {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(receipts)
+}
+
+/// Removes a message from the db. To be done only when the client acks that the message was processed.
+pub async fn delete_all_messages_by_user_and_folder(
+    user_email: &str,
+    folder_id: u64,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM pending_group_messages WHERE user_email = ? AND folder_id = ?")
+        .bind(user_email)
+        .bind(folder_id as i64)
+        .execute(&mut **transaction)
+        .await
+        .map(|_| ())
+}
+
+/// Delete the pending messages older than `max_age`.
+/// Returns the users and folders that had some of their messages deleted.
+pub async fn expire_pending_messages(
+    max_age: Duration,
+    db: &sqlx::AnyPool,
+) -> Result<Vec<(String, u64)>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let cutoff_timestamp = Dialect::of(&transaction).seconds_to_timestamp();
+    // Compute the cutoff once, so that the same messages are listed and deleted.
+    let cutoff = unix_now().saturating_sub(max_age.as_secs() as i64);
+    let expired = sqlx::query_as::<_, (String, i64)>(&format!(
+        "SELECT DISTINCT user_email, folder_id FROM pending_group_messages WHERE created_at < {}",
+        cutoff_timestamp
+    ))
+    .bind(cutoff)
+    .fetch_all(&mut *transaction)
+    .await?;
+    sqlx::query(&format!(
+        "DELETE FROM pending_group_messages WHERE created_at < {}",
+        cutoff_timestamp
+    ))
+    .bind(cutoff)
+    .execute(&mut *transaction)
+    .await?;
+    // The expired messages won't be acked anymore, their receipts are dropped with them.
+    sqlx::query(&format!(
+        "DELETE FROM group_message_receipts WHERE created_at < {}",
+        cutoff_timestamp
+    ))
+    .bind(cutoff)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(expired
+        .into_iter()
+        .map(|(user_email, folder_id)| (user_email, folder_id as u64))
+        .collect())
+}
+
+/// List all the folders, e.g. for the maintenance of their objects in the storage.
+pub async fn list_all_folders(db: &sqlx::AnyPool) -> Result<Vec<FolderEntity>, sqlx::Error> {
+    sqlx::query_as::<_, FolderEntity>("SELECT folder_id FROM folders")
+        .fetch_all(db)
+        .await
+}
+
+/// Register a Web Push subscription of a user, returning its id.
+/// An endpoint belongs to a single browser, so a previous subscription with the same endpoint is replaced.
+pub async fn insert_push_subscription(
+    user_email: &str,
+    endpoint: &str,
+    p256dh: &str,
+    auth: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    sqlx::query("DELETE FROM push_subscriptions WHERE endpoint = ?")
+        .bind(endpoint)
+        .execute(&mut *transaction)
+        .await?;
+    let subscription_id = insert_returning_id(
+        sqlx::query(
+            "INSERT INTO push_subscriptions(user_email, endpoint, p256dh, auth) VALUES (?, ?, ?, ?)",
+        )
+        .bind(user_email)
+        .bind(endpoint)
+        .bind(p256dh)
+        .bind(auth),
+        &mut transaction,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(subscription_id)
+}
+
+/// Delete a Web Push subscription of a user, returning whether it existed.
+pub async fn delete_push_subscription(
+    user_email: &str,
+    subscription_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM push_subscriptions WHERE user_email = ? AND subscription_id = ?")
+            .bind(user_email)
+            .bind(subscription_id as i64)
+            .execute(&mut ***db)
+            .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// List the Web Push subscriptions of a user.
+pub async fn list_push_subscriptions(
+    user_email: &str,
+    db: &sqlx::AnyPool,
+) -> Result<Vec<PushSubscriptionEntity>, sqlx::Error> {
+    sqlx::query_as::<_, PushSubscriptionEntity>(
+        "SELECT subscription_id, endpoint, p256dh, auth FROM push_subscriptions WHERE user_email = ?",
     )
     .bind(user_email)
-    .bind(folder_id)
-    .fetch_optional(&mut **transaction)
-    .await?;
-    if let Some(count) = count {
-        Ok(count)
-    } else {
-        log::error!("This should not happen!");
-        Err(sqlx::Error::RowNotFound)
-    }
+    .fetch_all(db)
+    .await
 }
 
-/// Removes a message from the db. To be done only when the client acks that the message was processed.
-pub async fn delete_message(
-    message_id: u64,
-    user_email: &str,
+/// Delete a Web Push subscription that expired or has been revoked by the push service.
+pub async fn delete_expired_push_subscription(
+    subscription_id: u64,
+    db: &sqlx::AnyPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM push_subscriptions WHERE subscription_id = ?")
+        .bind(subscription_id as i64)
+        .execute(db)
+        .await
+        .map(|_| ())
+}
+
+/// Returns the latest epoch accepted in a proposal for a group of the folder, 0 if none was accepted yet.
+pub async fn get_group_epoch(
     folder_id: u64,
-    mut db: Connection<DbConn>,
-) -> Result<bool, sqlx::Error> {
+    group_id: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    let epoch: Option<i64> =
+        sqlx::query_scalar("SELECT epoch FROM group_epochs WHERE folder_id = ? AND group_id = ?")
+            .bind(folder_id as i64)
+            .bind(group_id)
+            .fetch_optional(&mut ***db)
+            .await?;
+    Ok(epoch.unwrap_or(0) as u64)
+}
+
+/// Record the epoch of a proposal accepted for a group of the folder, if it is newer than the latest one.
+pub async fn record_group_epoch(
+    folder_id: u64,
+    group_id: &str,
+    epoch: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
     let mut transaction = db.begin().await?;
-    let first = sqlx::query_as::<_, PendingGroupMessageEntity>(
-        "SELECT * FROM pending_group_messages WHERE user_email = ? AND folder_id = ? ORDER BY message_id ASC LIMIT 1",
+    let exists: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM group_epochs WHERE folder_id = ? AND group_id = ?",
     )
-    .bind(user_email)
-    .bind(folder_id)
+    .bind(folder_id as i64)
+    .bind(group_id)
     .fetch_one(&mut *transaction)
     .await?;
-    let result = if first.message_id < message_id {
-        Ok(false)
+    let query = if exists > 0 {
+        "UPDATE group_epochs SET epoch = ? WHERE folder_id = ? AND group_id = ? AND epoch < ?"
     } else {
-        sqlx::query("DELETE FROM pending_group_messages WHERE message_id = ? AND user_email = ? AND folder_id = ?")
-            .bind(message_id)
-            .bind(user_email)
-            .bind(folder_id)
-            .execute(&mut *transaction)
-            .await
-            .map(|_| true)
+        "INSERT INTO group_epochs(epoch, folder_id, group_id) VALUES (?, ?, ?)"
     };
-    transaction.commit().await?;
-    result
+    let mut query = sqlx::query(query)
+        .bind(epoch as i64)
+        .bind(folder_id as i64)
+        .bind(group_id);
+    if exists > 0 {
+        query = query.bind(epoch as i64);
+    }
+    query.execute(&mut *transaction).await?;
+    transaction.commit().await
 }
 
-/// Removes a message from the db. To be done only when the client acks that the message was processed.
-pub async fn delete_all_messages_by_user_and_folder(
-    user_email: &str,
-    folder_id: u64,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
-) -> Result<(), sqlx::Error> {
-    sqlx::query("DELETE FROM pending_group_messages WHERE user_email = ? AND folder_id = ?")
-        .bind(user_email)
-        .bind(folder_id)
-        .execute(&mut **transaction)
-        .await
-        .map(|_| ())
+/// Whether the folder exists.
+pub async fn folder_exists(folder_id: u64, db: &sqlx::AnyPool) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folders WHERE folder_id = ?")
+        .bind(folder_id as i64)
+        .fetch_one(db)
+        .await?;
+    Ok(count > 0)
+}
+
+/// Delete the key packages whose lifetime ended, returning how many were deleted.
+pub async fn delete_expired_key_packages(db: &sqlx::AnyPool) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query("DELETE FROM key_packages WHERE not_after <= ?")
+        .bind(unix_now())
+        .execute(db)
+        .await?
+        .rows_affected())
 }
 
 /// Returns all pending messages of a user for a given folder. (uses the index internally).
 pub async fn list_pending_messages_by_folder_and_user(
     folder_id: u64,
     user_email: &str,
-    transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
 ) -> Result<Vec<PendingGroupMessageEntity>, sqlx::Error> {
-    sqlx::query_as::<_, PendingGroupMessageEntity>(
-        "SELECT * FROM pending_group_messages WHERE user_email = ? AND folder_id = ?",
-    )
+    sqlx::query_as::<_, PendingGroupMessageEntity>(&format!(
+        "SELECT {} FROM pending_group_messages WHERE user_email = ? AND folder_id = ?",
+        PENDING_MESSAGE_COLUMNS
+    ))
     .bind(user_email)
-    .bind(folder_id)
+    .bind(folder_id as i64)
     .fetch_all(&mut **transaction)
     .await
 }
 
-/// Returns all pending messages of a user for a given folder. (uses the index internally).
+/// Returns the eldest pending message of a user for a given folder, after the message `after` if given,
+/// e.g. the last one already fetched but not acked yet. (uses the index internally).
+/// Returns [`None`] if the message is not yet processable, as its application message is missing.
 pub async fn get_first_message_by_folder_and_user(
     folder_id: u64,
     user_email: &str,
+    after: Option<u64>,
     mut db: Connection<DbConn>,
 ) -> Result<Option<GroupMessageEntity>, sqlx::Error> {
     let mut transaction = db.begin().await?;
-    let pending = sqlx::query_as::<_, PendingGroupMessageEntity>(
-        "SELECT * FROM pending_group_messages WHERE user_email = ? AND folder_id = ? ORDER BY message_id ASC LIMIT 1",
-    )
+    let pending = sqlx::query_as::<_, PendingGroupMessageEntity>(&format!(
+        "SELECT {} FROM pending_group_messages WHERE user_email = ? AND folder_id = ? AND message_id > ? ORDER BY message_id ASC LIMIT 1",
+        PENDING_MESSAGE_COLUMNS
+    ))
     .bind(user_email)
-    .bind(folder_id)
+    .bind(folder_id as i64)
+    .bind(after.unwrap_or(0) as i64)
     .fetch_one(&mut *transaction)
     .await?;
     let application_msg_payload =
         sqlx::query_scalar("SELECT payload FROM application_messages WHERE message_id = ?")
-            .bind(pending.message_id)
+            .bind(pending.message_id as i64)
             .fetch_one(&mut *transaction)
             .await;
     if let Err(sqlx::Error::RowNotFound) = application_msg_payload {
@@ -671,39 +2136,143 @@ pub async fn get_first_message_by_folder_and_user(
     }))
 }
 
-/// Returns all pending messages of a user for a given folder. (uses the index internally).
+/// Returns the eldest pending messages of a user for a given folder, in order, up to `limit` of them.
+/// The list stops at the first message that is not yet processable, as its application message is missing.
+/// Returns also whether more messages are pending after the listed ones.
+pub async fn list_consumable_messages_by_folder_and_user(
+    folder_id: u64,
+    user_email: &str,
+    limit: u32,
+    mut db: Connection<DbConn>,
+) -> Result<(Vec<GroupMessageEntity>, bool), sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    // Fetch one more message to know if there are more pending.
+    let pending = sqlx::query_as::<_, PendingGroupMessageEntity>(&format!(
+        "SELECT {} FROM pending_group_messages WHERE user_email = ? AND folder_id = ? ORDER BY message_id ASC LIMIT ?",
+        PENDING_MESSAGE_COLUMNS
+    ))
+    .bind(user_email)
+    .bind(folder_id as i64)
+    .bind(limit as i64 + 1)
+    .fetch_all(&mut *transaction)
+    .await?;
+    if pending.is_empty() {
+        transaction.commit().await?;
+        return Ok((vec![], false));
+    }
+    let mut query_builder = sqlx::QueryBuilder::new(
+        "SELECT message_id, payload FROM application_messages WHERE message_id IN ",
+    );
+    query_builder.push_tuples(&pending, |mut b, pending_message| {
+        b.push_bind(pending_message.message_id as i64);
+    });
+    let query = query_builder.build_query_as::<(i64, Vec<u8>)>();
+    log::debug!("Query: `{}`", query.sql());
+    let mut application_payloads: HashMap<u64, Vec<u8>> = query
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|(message_id, payload)| (message_id as u64, payload))
+        .collect();
+    transaction.commit().await?;
+    let pending_count = pending.len();
+    let messages: Vec<GroupMessageEntity> = pending
+        .into_iter()
+        .take(limit as usize)
+        .map_while(|pending_message| {
+            application_payloads
+                .remove(&pending_message.message_id)
+                .map(|application_payload| GroupMessageEntity {
+                    message_id: pending_message.message_id,
+                    folder_id: pending_message.folder_id,
+                    user_email: pending_message.user_email,
+                    payload: pending_message.payload,
+                    application_payload,
+                })
+        })
+        .collect();
+    let has_more = pending_count > messages.len();
+    Ok((messages, has_more))
+}
+
+/// Get the eldest welcome message of a user for a given folder.
 pub async fn get_welcome_message_by_folder_and_user(
     folder_id: u64,
     user_email: &str,
     mut db: Connection<DbConn>,
-) -> Result<PendingGroupMessageEntity, sqlx::Error> {
-    sqlx::query_as::<_, PendingGroupMessageEntity>(
-        "SELECT * FROM welcome_messages WHERE user_email = ? AND folder_id = ?",
+) -> Result<WelcomeMessageEntity, sqlx::Error> {
+    sqlx::query_as::<_, WelcomeMessageEntity>(
+        "SELECT message_id, folder_id, payload FROM welcome_messages WHERE user_email = ? AND folder_id = ? ORDER BY message_id ASC LIMIT 1",
     )
     .bind(user_email)
-    .bind(folder_id)
+    .bind(folder_id as i64)
     .fetch_one(&mut **db)
     .await
 }
 
+/// Insert the key packages of a user, each with the end of its lifetime, in a single transaction,
+/// returning their ids in the same order.
 pub async fn insert_key_package(
     user_email: &str,
-    key_package: Vec<u8>,
+    key_packages: &[(&[u8], Option<u64>)],
     mut db: Connection<DbConn>,
-) -> Result<u64, sqlx::Error> {
-    sqlx::query("INSERT INTO key_packages(user_email, key_package) VALUES (?, ?)")
-        .bind(user_email)
-        .bind(key_package)
-        .execute(&mut **db)
-        .await
-        .map(|r| r.last_insert_id())
+) -> Result<Vec<u64>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let mut key_package_ids = Vec::with_capacity(key_packages.len());
+    for (key_package, not_after) in key_packages {
+        let key_package_id = insert_returning_id(
+            sqlx::query(
+                "INSERT INTO key_packages(user_email, key_package, not_after) VALUES (?, ?, ?)",
+            )
+            .bind(user_email)
+            .bind(key_package)
+            .bind(not_after.map(|not_after| not_after as i64)),
+            &mut transaction,
+        )
+        .await?;
+        key_package_ids.push(key_package_id);
+    }
+    transaction.commit().await?;
+    Ok(key_package_ids)
+}
+
+/// Count the key packages of a user that have not been consumed yet and are still valid.
+pub async fn count_key_packages(
+    user_email: &str,
+    mut db: Connection<DbConn>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM key_packages WHERE user_email = ? AND (not_after IS NULL OR not_after > ?)",
+    )
+    .bind(user_email)
+    .bind(unix_now())
+    .fetch_one(&mut **db)
+    .await
 }
 
+/// Consume the oldest valid key package of a user, if both the user and the requestor are members of the folder.
+/// The transaction is retried if it fails because of a concurrent one, e.g. two members consuming the same key package.
 pub async fn consume_key_package(
     user_email: &str,
     requestor: &str,
     folder_id: u64,
-    mut db: Connection<DbConn>,
+    retry: &RetryConfig,
+    db: &mut Connection<DbConn>,
+) -> Result<KeyPackageEntity, sqlx::Error> {
+    let mut attempt = 0;
+    loop {
+        match consume_key_package_once(user_email, requestor, folder_id, db).await {
+            Err(e) if retry.retry(attempt, &e).await => attempt += 1,
+            res => return res,
+        }
+    }
+}
+
+async fn consume_key_package_once(
+    user_email: &str,
+    requestor: &str,
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
 ) -> Result<KeyPackageEntity, sqlx::Error> {
     let mut transaction = db.begin().await?;
     log::debug!("Starting to retrieve the key package for {user_email} requested by {requestor}");
@@ -714,9 +2283,10 @@ pub async fn consume_key_package(
         return Err(e);
     }
     let key_package_entity = sqlx::query_as::<_, KeyPackageEntity>(
-        "SELECT * FROM key_packages WHERE user_email = (?) ORDER BY key_package_id ASC LIMIT 1",
+        "SELECT * FROM key_packages WHERE user_email = (?) AND (not_after IS NULL OR not_after > ?) ORDER BY key_package_id ASC LIMIT 1",
     )
     .bind(&user_email)
+    .bind(unix_now())
     .fetch_one(&mut *transaction)
     .await?;
     log::debug!(
@@ -724,7 +2294,7 @@ pub async fn consume_key_package(
         key_package_entity.key_package_id
     );
     sqlx::query("DELETE FROM key_packages WHERE key_package_id = ?")
-        .bind(key_package_entity.key_package_id)
+        .bind(key_package_entity.key_package_id as i64)
         .execute(&mut *transaction)
         .await?;
     log::debug!(
@@ -744,15 +2314,16 @@ pub async fn insert_application_message<'r>(
 ) -> Result<Vec<String>, sqlx::Error> {
     let mut transaction = db.begin().await?;
     // Retrieve all pending message ids.
-    let mut query_builder = sqlx::QueryBuilder::new(
-        "SELECT * FROM pending_group_messages WHERE pending_group_messages.folder_id = ",
-    );
-    query_builder.push_bind(folder_id);
+    let mut query_builder = sqlx::QueryBuilder::new(format!(
+        "SELECT {} FROM pending_group_messages WHERE pending_group_messages.folder_id = ",
+        PENDING_MESSAGE_COLUMNS
+    ));
+    query_builder.push_bind(folder_id as i64);
     query_builder.push(" AND pending_group_messages.user_email = ");
     query_builder.push_bind(sender_email);
     query_builder.push(" AND pending_group_messages.message_id IN ");
     query_builder.push_tuples(message_ids, |mut b, message_id| {
-        b.push_bind(message_id);
+        b.push_bind(*message_id as i64);
     });
     let query = query_builder.build_query_as::<PendingGroupMessageEntity>();
     log::debug!("Query: `{}`", query.sql());
@@ -763,7 +2334,7 @@ pub async fn insert_application_message<'r>(
         sqlx::QueryBuilder::new("INSERT INTO application_messages(message_id, payload)");
     let query = query_builder
         .push_values(values, |mut b, (message_id, payload)| {
-            b.push_bind(message_id).push_bind(payload);
+            b.push_bind(*message_id as i64).push_bind(payload);
         })
         .build();
     query.execute(&mut *transaction).await?;
@@ -774,3 +2345,363 @@ pub async fn insert_application_message<'r>(
     transaction.commit().await?;
     Ok(users_with_changes)
 }
+
+/// Insert a new resumable upload of a file, returning its id.
+pub async fn insert_file_upload(
+    user_email: &str,
+    folder_id: u64,
+    file_id: &str,
+    multipart_id: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<u64, sqlx::Error> {
+    insert_returning_id(
+        sqlx::query(
+            "INSERT INTO file_uploads(folder_id, file_id, user_email, multipart_id) VALUES (?, ?, ?, ?)",
+        )
+        .bind(folder_id as i64)
+        .bind(file_id)
+        .bind(user_email)
+        .bind(multipart_id),
+        db,
+    )
+    .await
+}
+
+/// Get a resumable upload of a file, only if the user is still a member of the folder.
+pub async fn get_file_upload(
+    user_email: &str,
+    upload_id: u64,
+    folder_id: u64,
+    file_id: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<FileUploadEntity, sqlx::Error> {
+    sqlx::query_as::<_, FileUploadEntity>(
+        "
+    SELECT file_uploads.upload_id, file_uploads.multipart_id
+    FROM file_uploads
+    JOIN folders_users ON file_uploads.folder_id = folders_users.folder_id
+    WHERE file_uploads.upload_id = ? AND file_uploads.folder_id = ? AND file_uploads.file_id = ? AND folders_users.user_email = ?",
+    )
+    .bind(upload_id as i64)
+    .bind(folder_id as i64)
+    .bind(file_id)
+    .bind(user_email)
+    .fetch_one(&mut ***db)
+    .await
+}
+
+/// Record a part of a resumable upload. Uploading the same part again replaces the previous one.
+pub async fn insert_file_upload_part(
+    upload_id: u64,
+    part_number: u32,
+    content_id: &str,
+    size: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "REPLACE INTO file_upload_parts(upload_id, part_number, content_id, size) VALUES (?, ?, ?, ?)",
+    )
+    .bind(upload_id as i64)
+    .bind(part_number as i64)
+    .bind(content_id)
+    .bind(size as i64)
+    .execute(&mut ***db)
+    .await
+    .map(|_| ())
+}
+
+/// List the parts of a resumable upload, ordered by part number.
+pub async fn list_file_upload_parts(
+    upload_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<FileUploadPartEntity>, sqlx::Error> {
+    sqlx::query_as::<_, FileUploadPartEntity>(
+        "SELECT part_number, content_id, size FROM file_upload_parts WHERE upload_id = ? ORDER BY part_number",
+    )
+    .bind(upload_id as i64)
+    .fetch_all(&mut ***db)
+    .await
+}
+
+/// Remove a resumable upload together with its parts, once it is completed or aborted.
+pub async fn delete_file_upload(
+    upload_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM file_uploads WHERE upload_id = ?")
+        .bind(upload_id as i64)
+        .execute(&mut ***db)
+        .await
+        .map(|_| ())
+}
+
+/// Get the bytes stored by a user and in a folder, without counting the files that are going to be replaced.
+pub async fn get_usage(
+    user_email: &str,
+    folder_id: u64,
+    file_ids: &[&str],
+    db: &mut Connection<DbConn>,
+) -> Result<UsageEntity, sqlx::Error> {
+    let placeholders = vec!["?"; file_ids.len().max(1)].join(", ");
+    let query = format!(
+        "
+    SELECT
+        CAST(COALESCE(SUM(CASE WHEN user_email = ? THEN size ELSE 0 END), 0) AS UNSIGNED) AS user_bytes,
+        CAST(COALESCE(SUM(CASE WHEN folder_id = ? THEN size ELSE 0 END), 0) AS UNSIGNED) AS folder_bytes
+    FROM storage_usage
+    WHERE (user_email = ? OR folder_id = ?) AND NOT (folder_id = ? AND file_id IN ({}))",
+        placeholders
+    );
+    let mut query = sqlx::query_as::<_, UsageEntity>(&query)
+        .bind(user_email)
+        .bind(folder_id as i64)
+        .bind(user_email)
+        .bind(folder_id as i64)
+        .bind(folder_id as i64);
+    if file_ids.is_empty() {
+        query = query.bind("");
+    }
+    for file_id in file_ids {
+        query = query.bind(*file_id);
+    }
+    query.fetch_one(&mut ***db).await
+}
+
+/// Record the size of a file, charged to the user that uploaded it last.
+pub async fn upsert_usage(
+    user_email: &str,
+    folder_id: u64,
+    file_id: &str,
+    size: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "REPLACE INTO storage_usage(folder_id, file_id, user_email, size) VALUES (?, ?, ?, ?)",
+    )
+    .bind(folder_id as i64)
+    .bind(file_id)
+    .bind(user_email)
+    .bind(size as i64)
+    .execute(&mut ***db)
+    .await
+    .map(|_| ())
+}
+
+/// Add a reference to the blob of a folder with the given content hash.
+/// Returns whether the blob was already referenced, otherwise its content must be stored.
+pub async fn acquire_blob(
+    folder_id: u64,
+    content_hash: &str,
+    db: &mut AnyConnection,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let upsert = Dialect::of(&transaction).upsert_blob();
+    sqlx::query(upsert)
+        .bind(folder_id as i64)
+        .bind(content_hash)
+        .execute(&mut *transaction)
+        .await?;
+    let ref_count: i64 =
+        sqlx::query_scalar("SELECT ref_count FROM blobs WHERE folder_id = ? AND content_hash = ?")
+            .bind(folder_id as i64)
+            .bind(content_hash)
+            .fetch_one(&mut *transaction)
+            .await?;
+    transaction.commit().await?;
+    Ok(ref_count > 1)
+}
+
+/// Remove a reference to the blob of a folder with the given content hash.
+/// Returns whether the blob is not referenced anymore, in which case its content must be deleted.
+pub async fn release_blob(
+    folder_id: u64,
+    content_hash: &str,
+    db: &mut AnyConnection,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    sqlx::query(
+        "UPDATE blobs SET ref_count = ref_count - 1 WHERE folder_id = ? AND content_hash = ? AND ref_count > 0",
+    )
+    .bind(folder_id as i64)
+    .bind(content_hash)
+    .execute(&mut *transaction)
+    .await?;
+    let deleted =
+        sqlx::query("DELETE FROM blobs WHERE folder_id = ? AND content_hash = ? AND ref_count = 0")
+            .bind(folder_id as i64)
+            .bind(content_hash)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+    transaction.commit().await?;
+    Ok(deleted > 0)
+}
+
+/// Point the current version of a file to the blob with the given content hash, or to no blob.
+/// Returns the content hash of the blob it was pointing to, whose reference must be released.
+pub async fn replace_file_blob(
+    folder_id: u64,
+    file_id: &str,
+    content_hash: Option<&str>,
+    db: &mut AnyConnection,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let select = format!(
+        "SELECT content_hash FROM file_blobs WHERE folder_id = ? AND file_id = ? AND deleted_at = 0{}",
+        Dialect::of(&transaction).for_update()
+    );
+    let previous: Option<String> = sqlx::query_scalar(&select)
+        .bind(folder_id as i64)
+        .bind(file_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM file_blobs WHERE folder_id = ? AND file_id = ? AND deleted_at = 0")
+        .bind(folder_id as i64)
+        .bind(file_id)
+        .execute(&mut *transaction)
+        .await?;
+    if let Some(content_hash) = content_hash {
+        sqlx::query(
+            "INSERT INTO file_blobs(folder_id, file_id, deleted_at, content_hash) VALUES (?, ?, 0, ?)",
+        )
+        .bind(folder_id as i64)
+        .bind(file_id)
+        .bind(content_hash)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(previous)
+}
+
+/// Move the blob of a file to its version in the trash, deleted at the given time in microseconds since the Unix epoch.
+/// With `deleted_at` 0 the blob of the file restored from that version of the trash is moved back.
+pub async fn move_file_blob(
+    folder_id: u64,
+    file_id: &str,
+    from_deleted_at: u64,
+    to_deleted_at: u64,
+    db: &mut AnyConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE file_blobs SET deleted_at = ? WHERE folder_id = ? AND file_id = ? AND deleted_at = ?",
+    )
+    .bind(to_deleted_at as i64)
+    .bind(folder_id as i64)
+    .bind(file_id)
+    .bind(from_deleted_at as i64)
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+/// Remove the blobs of the files of the trash of a folder deleted before the given time,
+/// in microseconds since the Unix epoch. Returns their content hashes, whose references must be released.
+pub async fn purge_file_blobs(
+    folder_id: u64,
+    deleted_before: u64,
+    db: &mut AnyConnection,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let select = format!(
+        "SELECT content_hash FROM file_blobs WHERE folder_id = ? AND deleted_at > 0 AND deleted_at < ?{}",
+        Dialect::of(&transaction).for_update()
+    );
+    let blobs = sqlx::query_scalar(&select)
+        .bind(folder_id as i64)
+        .bind(deleted_before as i64)
+        .fetch_all(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM file_blobs WHERE folder_id = ? AND deleted_at > 0 AND deleted_at < ?")
+        .bind(folder_id as i64)
+        .bind(deleted_before as i64)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(blobs)
+}
+
+/// Stop accounting the size of a deleted file.
+pub async fn delete_usage(
+    folder_id: u64,
+    file_id: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM storage_usage WHERE folder_id = ? AND file_id = ?")
+        .bind(folder_id as i64)
+        .bind(file_id)
+        .execute(&mut ***db)
+        .await
+        .map(|_| ())
+}
+
+/// Append an event to the audit log of the folder.
+pub async fn insert_audit_event(
+    folder_id: u64,
+    actor_email: &str,
+    action: AuditAction,
+    target: Option<&str>,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    insert_audit_event_transaction(folder_id, actor_email, action, target, &mut transaction)
+        .await?;
+    transaction.commit().await
+}
+
+/// Append an event to the audit log of the folder, as part of a bigger transaction.
+async fn insert_audit_event_transaction(
+    folder_id: u64,
+    actor_email: &str,
+    action: AuditAction,
+    target: Option<&str>,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Any>,
+) -> Result<(), sqlx::Error> {
+    log::debug!(
+        "Audit: `{}` performed `{}` on folder `{}` with target `{:?}`",
+        actor_email,
+        action.as_str(),
+        folder_id,
+        target
+    );
+    sqlx::query("INSERT INTO audit_log(folder_id, actor_email, action, target) VALUES (?, ?, ?, ?)")
+        .bind(folder_id as i64)
+        .bind(actor_email)
+        .bind(action)
+        .bind(target)
+        .execute(&mut **transaction)
+        .await
+        .map(|_| ())
+}
+
+/// List at most `limit` events of the audit log of the folder, in chronological order and starting after the event `after`.
+pub async fn list_audit_events(
+    folder_id: u64,
+    after: Option<u64>,
+    limit: u32,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<AuditEventEntity>, sqlx::Error> {
+    let created_at = Dialect::of(&db).timestamp_to_seconds("created_at");
+    sqlx::query_as::<_, AuditEventEntity>(&format!(
+        "SELECT event_id, actor_email, action, COALESCE(target, '') AS target, {} AS created_at 
+        FROM audit_log 
+        WHERE folder_id = ? AND event_id > ? 
+        ORDER BY event_id 
+        LIMIT ?",
+        created_at
+    ))
+    .bind(folder_id as i64)
+    .bind(after.unwrap_or(0) as i64)
+    .bind(limit as i64)
+    .fetch_all(&mut **db)
+    .await
+    .map(|events| {
+        events
+            .into_iter()
+            .map(|event| AuditEventEntity {
+                target: event.target.filter(|target| !target.is_empty()),
+                ..event
+            })
+            .collect()
+    })
+}
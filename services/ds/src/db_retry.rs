@@ -0,0 +1,196 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::time::Duration;
+
+use rocket::tokio;
+use sqlx::mysql::MySqlDatabaseError;
+
+/// The MySQL error raised when a transaction is chosen as the victim of a deadlock.
+const ER_LOCK_DEADLOCK: u16 = 1213;
+/// The MySQL error raised when a transaction waited too long for a lock.
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+/// The SQLSTATE of a serialization failure.
+const SERIALIZATION_FAILURE: &str = "40001";
+/// The primary SQLite result codes of a database or table locked by another connection.
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+/// The retry policy of the transactions failing because of a transient error, loaded from the `db_retry`
+/// table of the `DS_Rocket.toml` file. The transaction is retried with an exponential backoff.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RetryConfig {
+    /// The number of retries after the first attempt, no retry if zero.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// The delay before the first retry, in milliseconds, doubled at each retry.
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// The maximum delay between two retries, in milliseconds.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff() -> u64 {
+    20
+}
+
+fn default_max_backoff() -> u64 {
+    500
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: default_max_retries(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay before the retry following the failed `attempt`, starting from zero.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u64.saturating_pow(attempt))
+            .min(self.max_backoff);
+        Duration::from_millis(backoff)
+    }
+
+    /// Wait before retrying a transaction that failed at `attempt` with `error`.
+    /// Returns false without waiting if the error is not transient, or if there are no retries left.
+    pub async fn retry(&self, attempt: u32, error: &sqlx::Error) -> bool {
+        if attempt >= self.max_retries || !is_transient(error) {
+            return false;
+        }
+        let backoff = self.backoff(attempt);
+        log::warn!(
+            "Retrying a transaction in {:?} after a transient error: `{}`",
+            backoff,
+            error
+        );
+        tokio::time::sleep(backoff).await;
+        true
+    }
+}
+
+/// Returns true if the transaction failed because of a concurrent one and can be retried as is:
+/// a deadlock, a lock wait timeout, a serialization failure or a busy SQLite database.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(error) = error else {
+        return false;
+    };
+    if let Some(mysql) = error.try_downcast_ref::<MySqlDatabaseError>() {
+        return matches!(mysql.number(), ER_LOCK_DEADLOCK | ER_LOCK_WAIT_TIMEOUT)
+            || mysql.code() == Some(SERIALIZATION_FAILURE);
+    }
+    match error.code() {
+        Some(code) if code == SERIALIZATION_FAILURE => true,
+        // The extended SQLite result codes keep the primary code in the lowest byte.
+        Some(code) => code
+            .parse::<i32>()
+            .is_ok_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{borrow::Cow, error::Error, fmt};
+
+    use sqlx::error::{DatabaseError, ErrorKind};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct TestDatabaseError(&'static str);
+
+    impl fmt::Display for TestDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl Error for TestDatabaseError {}
+
+    impl DatabaseError for TestDatabaseError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(TestDatabaseError(code)))
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&database_error("40001")));
+        // SQLITE_BUSY, SQLITE_LOCKED and SQLITE_BUSY_SNAPSHOT.
+        assert!(is_transient(&database_error("5")));
+        assert!(is_transient(&database_error("6")));
+        assert!(is_transient(&database_error("517")));
+        // SQLITE_CONSTRAINT_UNIQUE.
+        assert!(!is_transient(&database_error("2067")));
+        assert!(!is_transient(&database_error("23000")));
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = RetryConfig::default();
+        assert_eq!(Duration::from_millis(20), config.backoff(0));
+        assert_eq!(Duration::from_millis(40), config.backoff(1));
+        assert_eq!(Duration::from_millis(500), config.backoff(5));
+        assert_eq!(Duration::from_millis(500), config.backoff(u32::MAX));
+    }
+
+    #[rocket::async_test]
+    async fn test_retry() {
+        let config = RetryConfig {
+            max_retries: 1,
+            initial_backoff: 1,
+            max_backoff: 1,
+        };
+        assert!(config.retry(0, &database_error("5")).await);
+        assert!(!config.retry(1, &database_error("5")).await);
+        assert!(!config.retry(0, &sqlx::Error::RowNotFound).await);
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::sync::Arc;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use rocket::{fairing::AdHoc, tokio};
+
+use crate::{
+    notifications::OfflineDelivery,
+    server::{Notification, NotificationEvent, SenderSentEventQueue},
+};
+
+/// The email notifications configuration, loaded from the `email` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailConfig {
+    /// Whether the emails are sent, they are disabled by default.
+    #[serde(default)]
+    pub enabled: bool,
+    /// The host name of the SMTP server.
+    pub smtp_host: String,
+    /// The port of the SMTP server, the default one of the security mode if missing.
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    /// How the connection with the SMTP server is secured.
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// The credentials to authenticate with the SMTP server, if it requires them.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The sender of the emails, e.g. `Shared Folder <noreply@example.com>`.
+    pub from: String,
+    #[serde(default)]
+    pub templates: EmailTemplates,
+}
+
+/// The security of the connection with the SMTP server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start of the connection, port 465 by default.
+    Tls,
+    /// A plaintext connection upgraded to TLS, port 587 by default.
+    #[default]
+    Starttls,
+    /// No encryption, only for a local relay, port 25 by default.
+    None,
+}
+
+/// A template of the emails, the `{email}` and `{folder_id}` placeholders are replaced with
+/// the email of the receiver and the id of the folder.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub body: String,
+}
+
+impl EmailTemplate {
+    fn render(&self, receiver: &str, folder_id: Option<u64>) -> (String, String) {
+        let folder_id = folder_id.map(|id| id.to_string()).unwrap_or_default();
+        let render = |text: &str| {
+            text.replace("{email}", receiver)
+                .replace("{folder_id}", &folder_id)
+        };
+        (render(&self.subject), render(&self.body))
+    }
+}
+
+/// The templates of the emails sent for each kind of event.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailTemplates {
+    /// Sent when a folder is shared with the receiver, or the receiver is invited to join it.
+    #[serde(default = "default_share_template")]
+    pub share: EmailTemplate,
+    /// Sent when the receiver is removed from a folder.
+    #[serde(default = "default_removal_template")]
+    pub removal: EmailTemplate,
+}
+
+fn default_share_template() -> EmailTemplate {
+    EmailTemplate {
+        subject: "A folder has been shared with you".to_string(),
+        body: "The folder {folder_id} has been shared with {email}.\n\
+            Open your Shared Folder client to access it."
+            .to_string(),
+    }
+}
+
+fn default_removal_template() -> EmailTemplate {
+    EmailTemplate {
+        subject: "You are no longer a member of a folder".to_string(),
+        body: "{email} is no longer a member of the folder {folder_id}.\n\
+            If you didn't leave it, you have been removed by an admin of the folder."
+            .to_string(),
+    }
+}
+
+impl Default for EmailTemplates {
+    fn default() -> Self {
+        EmailTemplates {
+            share: default_share_template(),
+            removal: default_removal_template(),
+        }
+    }
+}
+
+/// Sends the notification emails through the configured SMTP server.
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    templates: EmailTemplates,
+}
+
+impl Mailer {
+    pub fn from_config(config: &EmailConfig) -> Result<Self, String> {
+        let from = config
+            .from
+            .parse::<Mailbox>()
+            .map_err(|e| format!("Invalid sender `{}`: {}", config.from, e))?;
+        let mut builder = match config.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host),
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+            }
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.smtp_host,
+            )),
+        }
+        .map_err(|e| format!("Invalid SMTP server `{}`: {}", config.smtp_host, e))?;
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            (None, None) => (),
+            _ => return Err("Both the SMTP username and password are required".to_string()),
+        }
+        Ok(Mailer {
+            transport: builder.build(),
+            from,
+            templates: config.templates.clone(),
+        })
+    }
+
+    /// The template of the email notifying the event, if the receiver is notified by email.
+    fn template(&self, event: NotificationEvent) -> Option<&EmailTemplate> {
+        match event {
+            NotificationEvent::Share | NotificationEvent::Invitation => Some(&self.templates.share),
+            NotificationEvent::RemovedFromFolder => Some(&self.templates.removal),
+            _ => None,
+        }
+    }
+
+    fn message(
+        &self,
+        template: &EmailTemplate,
+        notification: &Notification,
+    ) -> Result<Message, String> {
+        let to = notification
+            .receiver
+            .parse::<Mailbox>()
+            .map_err(|e| format!("Invalid receiver: {}", e))?;
+        let (subject, body) = template.render(&notification.receiver, notification.folder_id);
+        Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Delivers the shares and removals of the users without a connected client by email.
+struct EmailDelivery {
+    mailer: Arc<Mailer>,
+}
+
+impl OfflineDelivery for EmailDelivery {
+    fn deliver(&self, notification: &Notification) {
+        let Some(template) = self.mailer.template(notification.event) else {
+            return;
+        };
+        let message = match self.mailer.message(template, notification) {
+            Ok(message) => message,
+            Err(e) => {
+                log::error!(
+                    "Couldn't build the email to `{}`: `{}`",
+                    notification.receiver,
+                    e
+                );
+                return;
+            }
+        };
+        let mailer = self.mailer.clone();
+        let receiver = notification.receiver.clone();
+        let event = notification.event;
+        tokio::spawn(async move {
+            match mailer.transport.send(message).await {
+                Ok(_) => log::debug!("Emailed `{:?}` to `{}`", event, receiver),
+                Err(e) => log::error!("Couldn't send the email to `{}`: `{}`", receiver, e),
+            }
+        });
+    }
+}
+
+/// A fairing registering the email as a delivery channel of the shares and removals of the offline users,
+/// if a mailer is configured.
+pub fn fairing(mailer: Option<Mailer>) -> AdHoc {
+    let mailer = mailer.map(Arc::new);
+    AdHoc::on_liftoff("Email notifications", move |rocket| {
+        Box::pin(async move {
+            let Some(mailer) = mailer else {
+                log::info!("Email notifications are disabled");
+                return;
+            };
+            let Some(queue) = rocket.state::<SenderSentEventQueue>() else {
+                log::error!(
+                    "The server state is not initialised, email notifications are disabled"
+                );
+                return;
+            };
+            queue.add_offline_delivery(Arc::new(EmailDelivery { mailer }));
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn config() -> EmailConfig {
+        EmailConfig {
+            enabled: true,
+            smtp_host: "localhost".to_string(),
+            smtp_port: Some(2525),
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "Shared Folder <noreply@example.com>".to_string(),
+            templates: EmailTemplates::default(),
+        }
+    }
+
+    fn notification(event: NotificationEvent) -> Notification {
+        Notification {
+            id: 1,
+            event,
+            folder_id: Some(42),
+            message_id: None,
+            receiver: "alice@example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = EmailTemplate {
+            subject: "Folder {folder_id}".to_string(),
+            body: "Hi {email}, folder {folder_id}".to_string(),
+        };
+        assert_eq!(
+            (
+                "Folder 42".to_string(),
+                "Hi alice@example.com, folder 42".to_string()
+            ),
+            template.render("alice@example.com", Some(42))
+        );
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert!(Mailer::from_config(&config()).is_ok());
+        let invalid_from = EmailConfig {
+            from: "not an email".to_string(),
+            ..config()
+        };
+        assert!(Mailer::from_config(&invalid_from).is_err());
+        let missing_password = EmailConfig {
+            username: Some("user".to_string()),
+            ..config()
+        };
+        assert!(Mailer::from_config(&missing_password).is_err());
+    }
+
+    #[test]
+    fn test_templates() {
+        let mailer = Mailer::from_config(&config()).unwrap();
+        assert!(mailer.template(NotificationEvent::Share).is_some());
+        assert!(mailer.template(NotificationEvent::Invitation).is_some());
+        assert!(mailer
+            .template(NotificationEvent::RemovedFromFolder)
+            .is_some());
+        assert!(mailer.template(NotificationEvent::Proposal).is_none());
+        let message = mailer
+            .message(
+                mailer.template(NotificationEvent::Share).unwrap(),
+                &notification(NotificationEvent::Share),
+            )
+            .unwrap();
+        assert_eq!(
+            vec!["alice@example.com".to_string()],
+            message
+                .envelope()
+                .to()
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+        );
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("The folder 42 has been shared with alice@example.com."));
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{sync::Arc, time::Duration};
+
+use redis::AsyncCommands;
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::{
+    fairing::AdHoc,
+    futures::StreamExt,
+    serde::json,
+    tokio::{
+        self,
+        sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    },
+    Shutdown,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifications::NotificationRelay,
+    server::{Notification, SenderSentEventQueue},
+};
+
+/// The number of notifications waiting to be published, above which they are dropped.
+const PUBLISH_BUFFER: usize = 1024;
+
+/// How long to wait before connecting again to the broker after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The notification fan-out configuration, loaded from the `fanout` table of the `DS_Rocket.toml` file.
+/// The notifications are only delivered to the clients connected to the same instance by default,
+/// a broker is required to run more than one instance of the DS.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum FanoutConfig {
+    /// The notifications are not forwarded, there is a single instance of the DS.
+    #[default]
+    Local,
+    /// The notifications are forwarded through the pub/sub of a Redis server.
+    Redis {
+        /// The URL of the Redis server, e.g. `redis://127.0.0.1:6379`.
+        url: String,
+        /// The pub/sub channel shared by the instances of the DS.
+        #[serde(default = "default_channel")]
+        channel: String,
+    },
+}
+
+fn default_channel() -> String {
+    "ssf-notifications".to_string()
+}
+
+/// A notification published to the other instances, tagged with the instance that sent it.
+#[derive(Serialize, Deserialize)]
+struct RelayedNotification {
+    origin: u64,
+    receiver: String,
+    notification: Notification,
+}
+
+fn encode(origin: u64, notification: &Notification) -> Option<String> {
+    json::to_string(&RelayedNotification {
+        origin,
+        receiver: notification.receiver.clone(),
+        notification: notification.clone(),
+    })
+    .ok()
+}
+
+/// Returns the notification of a published message, or `None` if it has been sent by this instance.
+fn decode(origin: u64, payload: &str) -> Result<Option<Notification>, String> {
+    let relayed = json::from_str::<RelayedNotification>(payload).map_err(|e| e.to_string())?;
+    if relayed.origin == origin {
+        return Ok(None);
+    }
+    let mut notification = relayed.notification;
+    notification.receiver = relayed.receiver;
+    Ok(Some(notification))
+}
+
+/// Forwards the notifications to a background task publishing them to Redis.
+struct RedisRelay {
+    origin: u64,
+    sender: Sender<String>,
+}
+
+impl NotificationRelay for RedisRelay {
+    fn publish(&self, notification: &Notification) {
+        let Some(payload) = encode(self.origin, notification) else {
+            log::error!(
+                "Couldn't serialise the notification to `{}`",
+                notification.receiver
+            );
+            return;
+        };
+        match self.sender.try_send(payload) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => log::warn!(
+                "Too many notifications waiting to be published, dropping the one to `{}`",
+                notification.receiver
+            ),
+            Err(TrySendError::Closed(_)) => (),
+        }
+    }
+}
+
+/// Publish the notifications of this instance, connecting again to Redis after an error.
+async fn publish(client: redis::Client, channel: String, mut rx: Receiver<String>) {
+    let mut connection = None;
+    while let Some(payload) = rx.recv().await {
+        if connection.is_none() {
+            match client.get_multiplexed_tokio_connection().await {
+                Ok(connected) => connection = Some(connected),
+                Err(e) => {
+                    log::error!(
+                        "Couldn't connect to Redis, dropping a notification: `{}`",
+                        e
+                    );
+                    continue;
+                }
+            }
+        }
+        let Some(redis) = connection.as_mut() else {
+            continue;
+        };
+        if let Err(e) = redis.publish::<_, _, ()>(&channel, payload).await {
+            log::error!("Couldn't publish a notification to Redis: `{}`", e);
+            connection = None;
+        }
+    }
+}
+
+/// Deliver the notifications published by the other instances to the connections of this instance,
+/// subscribing again after an error until the server shuts down.
+async fn subscribe(
+    client: redis::Client,
+    channel: String,
+    origin: u64,
+    queue: SenderSentEventQueue,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let subscribed = async {
+            let mut pubsub = client.get_async_pubsub().await?;
+            pubsub.subscribe(&channel).await?;
+            Ok::<_, redis::RedisError>(pubsub)
+        };
+        let pubsub = tokio::select! {
+            pubsub = subscribed => pubsub,
+            _ = &mut shutdown => return,
+        };
+        match pubsub {
+            Ok(pubsub) => {
+                log::info!("Subscribed to the notifications of channel `{}`", channel);
+                let mut messages = pubsub.into_on_message();
+                loop {
+                    let message = tokio::select! {
+                        message = messages.next() => message,
+                        _ = &mut shutdown => return,
+                    };
+                    let Some(message) = message else {
+                        break;
+                    };
+                    let payload = match message.get_payload::<String>() {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            log::warn!("Ignoring an invalid notification from Redis: `{}`", e);
+                            continue;
+                        }
+                    };
+                    match decode(origin, &payload) {
+                        Ok(Some(notification)) => {
+                            queue.send_relayed(notification);
+                        }
+                        Ok(None) => (),
+                        Err(e) => {
+                            log::warn!("Ignoring an invalid notification from Redis: `{}`", e)
+                        }
+                    }
+                }
+                log::warn!("The subscription to Redis was closed, subscribing again");
+            }
+            Err(e) => log::error!("Couldn't subscribe to the notifications in Redis: `{}`", e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => (),
+            _ = &mut shutdown => return,
+        }
+    }
+}
+
+/// A fairing forwarding the notifications to the other instances of the DS through the configured broker.
+pub fn fairing(config: FanoutConfig) -> AdHoc {
+    AdHoc::on_liftoff("Notification fan-out", move |rocket| {
+        Box::pin(async move {
+            let FanoutConfig::Redis { url, channel } = config else {
+                log::info!("The notifications are only delivered to the clients of this instance");
+                return;
+            };
+            let Some(queue) = rocket.state::<SenderSentEventQueue>() else {
+                log::error!(
+                    "The server state is not initialised, the notifications won't be forwarded"
+                );
+                return;
+            };
+            let client = match redis::Client::open(url.as_str()) {
+                Ok(client) => client,
+                Err(e) => {
+                    log::error!(
+                        "Invalid Redis URL, the notifications won't be forwarded: `{}`",
+                        e
+                    );
+                    return;
+                }
+            };
+            let mut origin = [0u8; 8];
+            if SystemRandom::new().fill(&mut origin).is_err() {
+                log::error!("Couldn't generate the id of the instance, the notifications won't be forwarded");
+                return;
+            }
+            let origin = u64::from_be_bytes(origin);
+            let (sender, rx) = mpsc::channel(PUBLISH_BUFFER);
+            queue.set_relay(Arc::new(RedisRelay { origin, sender }));
+            tokio::spawn(publish(client.clone(), channel.clone(), rx));
+            tokio::spawn(subscribe(
+                client,
+                channel,
+                origin,
+                queue.clone(),
+                rocket.shutdown(),
+            ));
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::server::NotificationEvent;
+
+    use super::*;
+
+    fn notification() -> Notification {
+        Notification {
+            id: 42,
+            event: NotificationEvent::Share,
+            folder_id: Some(1),
+            message_id: Some(2),
+            receiver: "alice@test.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_relayed_notification() {
+        let payload = encode(1, &notification()).unwrap();
+        // The notifications published by this instance are skipped.
+        assert!(decode(1, &payload).unwrap().is_none());
+        let relayed = decode(2, &payload).unwrap().unwrap();
+        assert_eq!("alice@test.com", relayed.receiver);
+        assert_eq!(NotificationEvent::Share, relayed.event);
+        assert_eq!(Some(1), relayed.folder_id);
+        assert_eq!(Some(2), relayed.message_id);
+        assert!(decode(2, "not a notification").is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let config: FanoutConfig =
+            json::from_str(r#"{"backend": "redis", "url": "redis://localhost"}"#).unwrap();
+        assert!(matches!(
+            config,
+            FanoutConfig::Redis { channel, .. } if channel == default_channel()
+        ));
+        let config: FanoutConfig = json::from_str(r#"{"backend": "local"}"#).unwrap();
+        assert!(matches!(config, FanoutConfig::Local));
+    }
+}
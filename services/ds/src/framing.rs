@@ -0,0 +1,144 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! Minimal parsing of the framing of the MLS messages published as proposals, see [RFC 9420](https://www.rfc-editor.org/rfc/rfc9420.html).
+//! Only the cleartext header of the messages is read, the DS doesn't hold any secret of the groups.
+
+use crate::key_package::{Reader, MLS_VERSION};
+
+/// The wire format of an `MLSMessage` carrying a `PublicMessage`.
+const WIRE_FORMAT_PUBLIC_MESSAGE: u16 = 1;
+/// The wire format of an `MLSMessage` carrying a `PrivateMessage`.
+const WIRE_FORMAT_PRIVATE_MESSAGE: u16 = 2;
+/// The prefix of the group of the admins of a folder, the group of its members is named after the folder id.
+const ADMIN_GROUP_PREFIX: &str = "ADMIN-";
+
+/// The framing validation configuration, loaded from the `framing` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct FramingConfig {
+    /// Whether the proposals must be `MLSMessage`s of the groups of the folder, they are opaque otherwise.
+    #[serde(default)]
+    pub validate_proposals: bool,
+}
+
+/// The group and epoch a handshake message has been created in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framing {
+    pub group_id: String,
+    pub epoch: u64,
+}
+
+/// Returns the framing of a `PublicMessage` or `PrivateMessage` serialized as an `MLSMessage`,
+/// or [`None`] if the payload is not a valid handshake message.
+pub fn framing(message: &[u8]) -> Option<Framing> {
+    let mut reader = Reader(message);
+    // MLSMessage
+    if reader.u16()? != MLS_VERSION {
+        return None;
+    }
+    match reader.u16()? {
+        WIRE_FORMAT_PUBLIC_MESSAGE | WIRE_FORMAT_PRIVATE_MESSAGE => (),
+        _ => return None,
+    }
+    // Both the FramedContent of a PublicMessage and a PrivateMessage start with the group id and the epoch.
+    let group_id = String::from_utf8(reader.opaque()?.to_vec()).ok()?;
+    let epoch = reader.u64()?;
+    // The epochs are stored as signed integers in the DB.
+    if i64::try_from(epoch).is_err() {
+        return None;
+    }
+    Some(Framing { group_id, epoch })
+}
+
+/// Check that a proposal is an MLS handshake message of one of the groups of the folder,
+/// the group of the members or the group of the admins, returning its framing.
+pub fn validate_proposal(proposal: &[u8], folder_id: u64) -> Result<Framing, &'static str> {
+    let framing =
+        framing(proposal).ok_or("The proposal is not a valid MLS public or private message.")?;
+    let member_group = folder_id.to_string();
+    let is_folder_group = framing.group_id == member_group
+        || framing.group_id.strip_prefix(ADMIN_GROUP_PREFIX) == Some(member_group.as_str());
+    if !is_folder_group {
+        return Err("The proposal doesn't belong to a group of the folder.");
+    }
+    Ok(framing)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn handshake_message(wire_format: u16, group_id: &[u8], epoch: u64) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&MLS_VERSION.to_be_bytes());
+        message.extend_from_slice(&wire_format.to_be_bytes());
+        message.push(group_id.len() as u8);
+        message.extend_from_slice(group_id);
+        message.extend_from_slice(&epoch.to_be_bytes());
+        // The rest of the content is not read.
+        message.extend_from_slice(&[1, 0, 0, 0, 1]);
+        message
+    }
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(
+            Some(Framing {
+                group_id: "42".to_string(),
+                epoch: 7
+            }),
+            framing(&handshake_message(WIRE_FORMAT_PUBLIC_MESSAGE, b"42", 7))
+        );
+        assert_eq!(
+            Some(Framing {
+                group_id: "ADMIN-42".to_string(),
+                epoch: 3
+            }),
+            framing(&handshake_message(
+                WIRE_FORMAT_PRIVATE_MESSAGE,
+                b"ADMIN-42",
+                3
+            ))
+        );
+    }
+
+    #[test]
+    fn test_framing_invalid_payload() {
+        assert_eq!(None, framing(b"P1"));
+        // Welcome messages and key packages are not proposals.
+        assert_eq!(None, framing(&handshake_message(3, b"42", 7)));
+        assert_eq!(None, framing(&handshake_message(5, b"42", 7)));
+        let message = handshake_message(WIRE_FORMAT_PUBLIC_MESSAGE, b"42", 7);
+        assert_eq!(None, framing(&message[..10]));
+        assert_eq!(
+            None,
+            framing(&handshake_message(
+                WIRE_FORMAT_PUBLIC_MESSAGE,
+                b"42",
+                u64::MAX
+            ))
+        );
+    }
+
+    #[test]
+    fn test_validate_proposal() {
+        let member = handshake_message(WIRE_FORMAT_PUBLIC_MESSAGE, b"42", 7);
+        let admin = handshake_message(WIRE_FORMAT_PRIVATE_MESSAGE, b"ADMIN-42", 7);
+        assert!(validate_proposal(&member, 42).is_ok());
+        assert!(validate_proposal(&admin, 42).is_ok());
+        assert!(validate_proposal(&member, 4).is_err());
+        assert!(validate_proposal(&admin, 420).is_err());
+        assert!(validate_proposal(b"P1", 42).is_err());
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ring::digest::{Context, SHA256};
+use rocket::{
+    data::{self, FromData},
+    fairing::{Fairing, Info, Kind},
+    form::Form,
+    fs::TempFile,
+    http::{ContentType, Method, Status},
+    outcome::Outcome,
+    Data, Request, Response,
+};
+
+use crate::{
+    server::{
+        BatchUpload, ErrorBody, MetadataUpload, ProposalMessageRequest, SessionOrCertificate,
+        Upload,
+    },
+    storage,
+};
+
+/// The header carrying the idempotency key of a request, chosen by the client.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The header set on the responses replayed from the cache.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// The maximum length of an idempotency key.
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// The number of cached responses above which the expired ones are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The idempotency configuration, loaded from the `idempotency` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct IdempotencyConfig {
+    /// How long the outcome of a request is kept to answer its retries, in seconds.
+    #[serde(default = "default_window")]
+    pub window: u64,
+}
+
+fn default_window() -> u64 {
+    24 * 60 * 60
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            window: default_window(),
+        }
+    }
+}
+
+/// The state of an idempotency key, bound to the request that first used it.
+#[derive(Clone)]
+enum Entry {
+    InFlight {
+        request: String,
+        started: Instant,
+    },
+    Completed {
+        request: String,
+        completed: Instant,
+        response: CachedResponse,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct CachedResponse {
+    status: Status,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// How a request has been handled by the fairing, cached in the request local state.
+#[derive(Default)]
+enum Tracking {
+    #[default]
+    Untracked,
+    /// The request carries a key, checked by the [`Idempotent`] data guard once the body is received.
+    Pending {
+        idempotency: Idempotency,
+        key: String,
+        request: String,
+    },
+    /// The outcome of the request is recorded under the key.
+    Recording(String),
+    /// The request doesn't reach the handler and is answered with the response.
+    Replaying(CachedResponse, bool),
+}
+
+/// Updates the tracking of the request, kept in its local state.
+fn set_tracking(req: &Request<'_>, tracking: Tracking) {
+    let state = req.local_cache(|| Mutex::new(Tracking::Untracked));
+    *state.lock().expect("Idempotency tracking corrupted!") = tracking;
+}
+
+/// Takes the tracking of the request, leaving it untracked.
+fn take_tracking(req: &Request<'_>) -> Tracking {
+    let state = req.local_cache(|| Mutex::new(Tracking::Untracked));
+    std::mem::take(&mut *state.lock().expect("Idempotency tracking corrupted!"))
+}
+
+/// A fairing making the retries of the upload, metadata and proposal requests safe.
+/// The outcome of a request carrying an `Idempotency-Key` header is cached per user for the configured window,
+/// and replayed to the retries with the same key, without reaching the handler again.
+/// A retry received while the request is still in flight is rejected with `409 Conflict`,
+/// and a key reused for a different request or body with `422 Unprocessable Entity`.
+/// The body is compared by the [`Idempotent`] data guard of the handlers, once it is received.
+///
+/// The server errors are not cached, so that the request can be retried.
+/// The cache is kept in memory, the retries must reach the same instance of the server.
+#[derive(Clone)]
+pub struct Idempotency {
+    window: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Idempotency {
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Idempotency {
+            window: Duration::from_secs(config.window),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry, now: Instant) -> bool {
+        let since = match entry {
+            Entry::InFlight { started, .. } => started,
+            Entry::Completed { completed, .. } => completed,
+        };
+        now.duration_since(*since) >= self.window
+    }
+
+    /// Start tracking `request` under `key`, returning the response to send instead if the key is already used.
+    fn begin(&self, key: &str, request: &str, now: Instant) -> Result<(), (CachedResponse, bool)> {
+        let mut entries = self.entries.lock().expect("Idempotency state corrupted!");
+        if entries.len() > PRUNE_THRESHOLD {
+            entries.retain(|_, entry| !self.is_expired(entry, now));
+        }
+        match entries.get(key) {
+            Some(entry) if !self.is_expired(entry, now) => match entry {
+                Entry::InFlight { request: first, .. }
+                | Entry::Completed { request: first, .. }
+                    if first != request =>
+                {
+                    Err((
+                        error_response(
+                            Status::UnprocessableEntity,
+                            "idempotency_key_reused",
+                            "The idempotency key has already been used for a different request.",
+                        ),
+                        false,
+                    ))
+                }
+                Entry::InFlight { .. } => Err((
+                    error_response(
+                        Status::Conflict,
+                        "idempotency_key_in_use",
+                        "A request with the same idempotency key is still in progress.",
+                    ),
+                    false,
+                )),
+                Entry::Completed { response, .. } => Err((response.clone(), true)),
+            },
+            _ => {
+                entries.insert(
+                    key.to_string(),
+                    Entry::InFlight {
+                        request: request.to_string(),
+                        started: now,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Record the outcome of the request tracked under `key`, or forget the key if it can be retried.
+    fn complete(&self, key: &str, response: CachedResponse, now: Instant) {
+        let mut entries = self.entries.lock().expect("Idempotency state corrupted!");
+        let Some(Entry::InFlight { request, .. }) = entries.remove(key) else {
+            return;
+        };
+        if is_final(response.status) {
+            entries.insert(
+                key.to_string(),
+                Entry::Completed {
+                    request,
+                    completed: now,
+                    response,
+                },
+            );
+        }
+    }
+
+    /// Forget the key of a request whose outcome is unknown, so that it can be retried.
+    fn forget(&self, key: &str) {
+        let mut entries = self.entries.lock().expect("Idempotency state corrupted!");
+        entries.remove(key);
+    }
+}
+
+/// Returns true if the endpoint accepts an idempotency key: the file uploads, the metadata updates and the proposals.
+fn is_idempotent_endpoint(method: Method, segments: &[&str]) -> bool {
+    matches!(
+        (method, segments),
+        (Method::Post, ["folders", _, "files", _])
+            | (Method::Post, ["folders", _, "files"])
+            | (Method::Post, ["folders", _, "metadatas"])
+            | (Method::Post, ["folders", _, "proposals"])
+    )
+}
+
+/// Whether the outcome of a request is final, otherwise a retry could succeed: the server errors,
+/// the authentication failures, the rate limited and the too large requests are not cached.
+fn is_final(status: Status) -> bool {
+    status.code < 500
+        && status != Status::Unauthorized
+        && status != Status::PayloadTooLarge
+        && status != Status::TooManyRequests
+}
+
+/// Only short printable keys are accepted, as they are kept in memory.
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH
+        && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn error_response(status: Status, code: &str, message: &str) -> CachedResponse {
+    let body = rocket::serde::json::to_string(&ErrorBody::new(code, message)).expect("valid JSON");
+    CachedResponse {
+        status,
+        content_type: Some(ContentType::JSON.to_string()),
+        body: body.into_bytes(),
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let segments: Vec<&str> = req.uri().path().segments().collect();
+        if !is_idempotent_endpoint(req.method(), &segments) {
+            return;
+        }
+        let Some(key) = req.headers().get_one(IDEMPOTENCY_KEY_HEADER) else {
+            return;
+        };
+        if !is_valid_key(key) {
+            log::debug!(
+                "Ignoring the invalid idempotency key of `{} {}`",
+                req.method(),
+                req.uri()
+            );
+            return;
+        }
+        // The keys are scoped per user, unauthenticated requests are left to the handler.
+        let Some(certificate) = req.guard::<SessionOrCertificate<'_>>().await.succeeded() else {
+            return;
+        };
+        let key = format!("{}\n{}", certificate.emails.join(","), key);
+        let request = format!("{} {}", req.method(), req.uri());
+        set_tracking(
+            req,
+            Tracking::Pending {
+                idempotency: self.clone(),
+                key,
+                request,
+            },
+        );
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        match take_tracking(req) {
+            // The handler didn't check the key, e.g. the request has been rejected before receiving its body.
+            Tracking::Untracked | Tracking::Pending { .. } => (),
+            Tracking::Recording(key) => {
+                let body = match res.body_mut().to_bytes().await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        log::error!("Couldn't read the response to cache it: `{}`", e);
+                        self.forget(&key);
+                        return;
+                    }
+                };
+                res.set_sized_body(body.len(), Cursor::new(body.clone()));
+                let response = CachedResponse {
+                    status: res.status(),
+                    content_type: res
+                        .content_type()
+                        .map(|content_type| content_type.to_string()),
+                    body,
+                };
+                self.complete(&key, response, Instant::now());
+            }
+            Tracking::Replaying(response, replayed) => {
+                res.set_status(response.status);
+                if let Some(content_type) = &response.content_type {
+                    res.set_raw_header("Content-Type", content_type.clone());
+                }
+                if replayed {
+                    res.set_raw_header(IDEMPOTENT_REPLAYED_HEADER, "true");
+                }
+                res.set_sized_body(response.body.len(), Cursor::new(response.body));
+            }
+        }
+    }
+}
+
+/// The digest of the fields of a request body, compared between the requests with the same idempotency key.
+pub struct BodyDigest(Context);
+
+impl BodyDigest {
+    fn new() -> Self {
+        BodyDigest(Context::new(&SHA256))
+    }
+
+    /// Adds a field, prefixed by its length so that the boundaries between the fields are kept.
+    pub fn field(&mut self, value: &[u8]) {
+        self.0.update(&(value.len() as u64).to_be_bytes());
+        self.0.update(value);
+    }
+
+    /// Adds an optional field, a missing field differs from an empty one.
+    pub fn optional(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.0.update(&[1]);
+                self.field(value.as_bytes());
+            }
+            None => self.0.update(&[0]),
+        }
+    }
+
+    /// Adds the content of an uploaded file.
+    pub async fn file(&mut self, file: &TempFile<'_>) -> std::io::Result<()> {
+        let content_hash = storage::content_hash_of_reader(file.open().await?).await?;
+        self.field(content_hash.as_bytes());
+        Ok(())
+    }
+
+    fn finish(self) -> String {
+        self.0
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// A request body whose retries are recognised by an idempotency key.
+#[rocket::async_trait]
+pub trait Fingerprint {
+    /// Adds the fields of the body to the digest.
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()>;
+}
+
+#[rocket::async_trait]
+impl<T: Fingerprint + Sync> Fingerprint for Form<T> {
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()> {
+        (**self).fingerprint(digest).await
+    }
+}
+
+#[rocket::async_trait]
+impl Fingerprint for ProposalMessageRequest<'_> {
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()> {
+        digest.field(self.proposal);
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Fingerprint for MetadataUpload<'_> {
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()> {
+        digest.field(self.metadata);
+        digest.optional(self.parent_etag.as_deref());
+        digest.optional(self.parent_version.as_deref());
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Fingerprint for Upload<'_> {
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()> {
+        digest.file(&self.file).await?;
+        digest.field(self.metadata);
+        digest.optional(self.parent_etag.as_deref());
+        digest.optional(self.parent_version.as_deref());
+        digest.optional(self.file_parent_etag.as_deref());
+        digest.optional(self.content_hash.as_deref());
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Fingerprint for BatchUpload<'_> {
+    async fn fingerprint(&self, digest: &mut BodyDigest) -> std::io::Result<()> {
+        digest.field(&(self.files.len() as u64).to_be_bytes());
+        for file in &self.files {
+            digest.field(file.file_id.as_bytes());
+            digest.file(&file.file).await?;
+            digest.optional(file.file_parent_etag.as_deref());
+            digest.optional(file.content_hash.as_deref());
+        }
+        digest.field(self.metadata);
+        digest.optional(self.parent_etag.as_deref());
+        digest.optional(self.parent_version.as_deref());
+        Ok(())
+    }
+}
+
+/// The data guard of the endpoints accepting an idempotency key, see [`Idempotency`].
+/// Once the body is received, it is compared with the one of the first request with the same key:
+/// a retry is answered from the cache and doesn't reach the handler.
+#[derive(Debug)]
+pub struct Idempotent<T>(pub T);
+
+impl<T> Deref for Idempotent<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: FromData<'r> + Fingerprint + Send + Sync> FromData<'r> for Idempotent<T> {
+    /// The error of the inner data guard, missing if the request is answered from the cache.
+    type Error = Option<T::Error>;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let body = match T::from_data(req, data).await {
+            Outcome::Success(body) => body,
+            Outcome::Error((status, e)) => return Outcome::Error((status, Some(e))),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+        let (idempotency, key, request) = match take_tracking(req) {
+            Tracking::Pending {
+                idempotency,
+                key,
+                request,
+            } => (idempotency, key, request),
+            untracked => {
+                set_tracking(req, untracked);
+                return Outcome::Success(Idempotent(body));
+            }
+        };
+        let mut digest = BodyDigest::new();
+        if let Err(e) = body.fingerprint(&mut digest).await {
+            log::error!(
+                "Couldn't read the body of `{}` to check its idempotency key: `{}`",
+                request,
+                e
+            );
+            return Outcome::Error((Status::InternalServerError, None));
+        }
+        let request = format!("{} {}", request, digest.finish());
+        match idempotency.begin(&key, &request, Instant::now()) {
+            Ok(()) => {
+                set_tracking(req, Tracking::Recording(key));
+                Outcome::Success(Idempotent(body))
+            }
+            Err((response, replayed)) => {
+                log::debug!("Answering `{}` from the idempotency cache", request);
+                set_tracking(req, Tracking::Replaying(response, replayed));
+                // The response of the catcher is replaced with the cached one, see `on_response`.
+                Outcome::Error((Status::Conflict, None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ok_response() -> CachedResponse {
+        CachedResponse {
+            status: Status::Created,
+            content_type: Some(ContentType::JSON.to_string()),
+            body: b"{}".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_idempotent_endpoints() {
+        assert!(is_idempotent_endpoint(
+            Method::Post,
+            &["folders", "1", "files", "file"]
+        ));
+        assert!(is_idempotent_endpoint(
+            Method::Post,
+            &["folders", "1", "files"]
+        ));
+        assert!(is_idempotent_endpoint(
+            Method::Post,
+            &["folders", "1", "metadatas"]
+        ));
+        assert!(is_idempotent_endpoint(
+            Method::Post,
+            &["folders", "1", "proposals"]
+        ));
+        assert!(!is_idempotent_endpoint(
+            Method::Post,
+            &["folders", "1", "files", "file", "restore"]
+        ));
+        assert!(!is_idempotent_endpoint(
+            Method::Patch,
+            &["folders", "1", "proposals"]
+        ));
+        assert!(!is_idempotent_endpoint(Method::Post, &["folders"]));
+    }
+
+    #[test]
+    fn test_replay() {
+        let idempotency = Idempotency::new(IdempotencyConfig { window: 60 });
+        let now = Instant::now();
+        let request = "POST /folders/1/metadatas";
+        assert!(idempotency.begin("a", request, now).is_ok());
+        let (in_flight, replayed) = idempotency.begin("a", request, now).unwrap_err();
+        assert_eq!(Status::Conflict, in_flight.status);
+        assert!(!replayed);
+        idempotency.complete("a", ok_response(), now);
+        assert_eq!(
+            Err((ok_response(), true)),
+            idempotency.begin("a", request, now + Duration::from_secs(30))
+        );
+        let (reused, _) = idempotency
+            .begin("a", "POST /folders/2/metadatas", now)
+            .unwrap_err();
+        assert_eq!(Status::UnprocessableEntity, reused.status);
+        // Other keys are not affected.
+        assert!(idempotency.begin("b", request, now).is_ok());
+        // The key can be used again after the window.
+        assert!(idempotency
+            .begin("a", request, now + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_server_errors_are_not_cached() {
+        let idempotency = Idempotency::new(IdempotencyConfig::default());
+        let now = Instant::now();
+        let request = "POST /folders/1/proposals";
+        assert!(idempotency.begin("a", request, now).is_ok());
+        idempotency.complete(
+            "a",
+            error_response(
+                Status::InternalServerError,
+                "internal_error",
+                "Internal Server Error",
+            ),
+            now,
+        );
+        assert!(idempotency.begin("a", request, now).is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! Minimal parsing of the MLS key packages published by the clients, see [RFC 9420](https://www.rfc-editor.org/rfc/rfc9420.html).
+//! The DS treats the key packages as opaque payloads, it only reads their lifetime to stop serving them once they expire.
+
+/// The protocol version `mls10`.
+pub(crate) const MLS_VERSION: u16 = 1;
+/// The wire format of an `MLSMessage` carrying a key package.
+const WIRE_FORMAT_KEY_PACKAGE: u16 = 5;
+/// The source of a leaf node published in a key package, the only one carrying a lifetime.
+const LEAF_NODE_SOURCE_KEY_PACKAGE: u8 = 1;
+
+/// The validity period of a key package, in seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lifetime {
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+/// Returns the lifetime of a key package serialized as an `MLSMessage`,
+/// or [`None`] if the payload is not a valid key package.
+pub fn lifetime(key_package_message: &[u8]) -> Option<Lifetime> {
+    let mut reader = Reader(key_package_message);
+    // MLSMessage
+    if reader.u16()? != MLS_VERSION || reader.u16()? != WIRE_FORMAT_KEY_PACKAGE {
+        return None;
+    }
+    // KeyPackage: version, cipher_suite and init_key.
+    reader.u16()?;
+    reader.u16()?;
+    reader.opaque()?;
+    // LeafNode: encryption_key and signature_key.
+    reader.opaque()?;
+    reader.opaque()?;
+    // Credential: both the basic and the x509 credentials are a single variable-length vector.
+    reader.u16()?;
+    reader.opaque()?;
+    // Capabilities: versions, cipher_suites, extensions, proposals and credentials.
+    for _ in 0..5 {
+        reader.opaque()?;
+    }
+    if reader.u8()? != LEAF_NODE_SOURCE_KEY_PACKAGE {
+        return None;
+    }
+    Some(Lifetime {
+        not_before: reader.u64()?,
+        not_after: reader.u64()?,
+    })
+}
+
+/// A cursor over a TLS presentation language encoded buffer.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    /// Reads a variable-length vector, prefixed by its length encoded as in section 2.1.2 of the RFC.
+    pub(crate) fn opaque(&mut self) -> Option<&'a [u8]> {
+        let first = self.u8()?;
+        let mut len = u64::from(first & 0x3f);
+        let extra = match first >> 6 {
+            0 => 0,
+            1 => 1,
+            2 => 3,
+            _ => return None,
+        };
+        for byte in self.take(extra)? {
+            len = (len << 8) | u64::from(*byte);
+        }
+        self.take(usize::try_from(len).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn opaque(bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = if bytes.len() < 64 {
+            vec![bytes.len() as u8]
+        } else {
+            (0x4000 | bytes.len() as u16).to_be_bytes().to_vec()
+        };
+        encoded.extend_from_slice(bytes);
+        encoded
+    }
+
+    fn key_package_message(leaf_node_source: u8, not_before: u64, not_after: u64) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&MLS_VERSION.to_be_bytes());
+        message.extend_from_slice(&WIRE_FORMAT_KEY_PACKAGE.to_be_bytes());
+        message.extend_from_slice(&MLS_VERSION.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+        message.extend(opaque(&[1; 32]));
+        message.extend(opaque(&[2; 32]));
+        message.extend(opaque(&[3; 65]));
+        message.extend_from_slice(&1u16.to_be_bytes());
+        message.extend(opaque(b"alice@test.com"));
+        for capability in [&[0, 1][..], &[0, 1, 0, 2], &[], &[], &[0, 1]] {
+            message.extend(opaque(capability));
+        }
+        message.push(leaf_node_source);
+        message.extend_from_slice(&not_before.to_be_bytes());
+        message.extend_from_slice(&not_after.to_be_bytes());
+        // Extensions and signatures are not read.
+        message.extend(opaque(&[]));
+        message.extend(opaque(&[4; 64]));
+        message
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let message = key_package_message(LEAF_NODE_SOURCE_KEY_PACKAGE, 10, 20);
+        assert_eq!(
+            Some(Lifetime {
+                not_before: 10,
+                not_after: 20
+            }),
+            lifetime(&message)
+        );
+    }
+
+    #[test]
+    fn test_lifetime_invalid_payload() {
+        assert_eq!(None, lifetime(b"asdadsads"));
+        assert_eq!(None, lifetime(&key_package_message(2, 10, 20)));
+        let message = key_package_message(LEAF_NODE_SOURCE_KEY_PACKAGE, 10, 20);
+        assert_eq!(None, lifetime(&message[..message.len() - 80]));
+    }
+}
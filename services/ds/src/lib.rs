@@ -11,59 +11,324 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
+mod archive;
+mod bandwidth;
 mod db;
+mod db_retry;
+mod email;
+mod fanout;
+mod framing;
+mod idempotency;
+mod key_package;
+mod limits;
+mod notifications;
+mod pki_verification;
+mod quota;
+mod rate_limit;
+mod reconciliation;
+mod request_id;
+mod retention;
+mod revocation;
 pub mod server;
+mod session;
+mod shutdown;
 mod storage;
+pub mod tls_reload;
+mod web_push;
+mod websocket;
 
-use rocket::figment::providers::{Format, Toml};
+use bandwidth::{Bandwidth, BandwidthConfig};
+use db_retry::RetryConfig;
+use email::{EmailConfig, Mailer};
+use fanout::FanoutConfig;
+use framing::FramingConfig;
+use idempotency::{Idempotency, IdempotencyConfig};
+use limits::UploadLimits;
+use pki_verification::{PkiVerificationConfig, PkiVerifier};
+use quota::QuotaConfig;
+use rate_limit::{RateLimitConfig, RateLimiter};
+use reconciliation::ReconciliationConfig;
+use request_id::RequestIdFairing;
+use retention::RetentionConfig;
+use revocation::{RevocationConfig, RevocationList};
+use rocket::data::Limits;
+use rocket::fairing::AdHoc;
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use rocket_db_pools::Database;
-use server::{Notification, SenderSentEventQueue};
-//use server::{WebSocketConnectedClients, WebSocketConnectedQueues};
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-};
+use server::{FoldersConfig, SenderSentEventQueue, ServerAdmins};
+use session::{SessionConfig, Sessions};
+use shutdown::GracefulShutdown;
+use std::{collections::HashSet, sync::Arc};
 use storage::StoreConfig;
-use tokio::sync::{broadcast::channel, Mutex};
+use tls_reload::{TlsReload, TlsReloadConfig};
+use tokio::sync::Mutex;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
+use web_push::{WebPush, WebPushConfig};
+
+/// The origins allowed by CORS when the `cors` table is missing from the configuration.
+const DEFAULT_ALLOWED_ORIGINS: [&str; 4] = [
+    "https://localhost:8000",
+    "https://localhost:8001",
+    "http://localhost:3000",
+    "https://127.0.0.1:8001",
+];
 
 /// Initialise the Rocket server.
 pub fn init_server_from_config() -> rocket::Rocket<rocket::Build> {
-    let _ = env_logger::try_init().inspect_err(|e| log::warn!("error `{}`", e));
+    let _ = request_id::init_logger().inspect_err(|e| log::warn!("error `{}`", e));
+    // The database backend is selected at runtime by the url in the configuration.
+    rocket_db_pools::sqlx::any::install_default_drivers();
 
     let figment = rocket::Config::figment()
         // Load the configuration file for the DS server.
-        .merge(Toml::file("DS_Rocket.toml").nested());
+        .merge(Toml::file("DS_Rocket.toml").nested())
+        // Let the environment variables override the configuration file, e.g. `ROCKET_CORS`.
+        .merge(Env::prefixed("ROCKET_").global());
 
     let storage_config = figment
         .extract::<StoreConfig>()
         .expect("valid storage configuration");
+    let multipart_storage: server::OptionalMultipartStore =
+        storage::initialise_multipart_store(&storage_config).expect("A valid Store instance!");
+    let lifecycle =
+        storage::initialise_lifecycle(&storage_config).expect("valid storage class configuration");
+    let quota_config = if figment.contains("quota") {
+        figment
+            .extract_inner::<QuotaConfig>("quota")
+            .expect("valid quota configuration")
+    } else {
+        QuotaConfig::default()
+    };
+    let rate_limit_config = if figment.contains("rate_limit") {
+        figment
+            .extract_inner::<RateLimitConfig>("rate_limit")
+            .expect("valid rate limit configuration")
+    } else {
+        RateLimitConfig::default()
+    };
+    let bandwidth_config = if figment.contains("bandwidth") {
+        figment
+            .extract_inner::<BandwidthConfig>("bandwidth")
+            .expect("valid bandwidth configuration")
+    } else {
+        BandwidthConfig::default()
+    };
+    let upload_limits = if figment.contains("upload") {
+        figment
+            .extract_inner::<UploadLimits>("upload")
+            .expect("valid upload configuration")
+    } else {
+        UploadLimits::default()
+    };
+    // The upload limits are enforced by Rocket while parsing the forms.
+    let limits = figment
+        .extract_inner::<Limits>("limits")
+        .unwrap_or_default();
+    let figment = figment.merge(Serialized::global("limits", upload_limits.apply(limits)));
+    let run_migrations = figment
+        .extract_inner::<bool>("run_migrations")
+        .unwrap_or(false);
+    let retention_config = if figment.contains("retention") {
+        figment
+            .extract_inner::<RetentionConfig>("retention")
+            .expect("valid retention configuration")
+    } else {
+        RetentionConfig::default()
+    };
+    let tls_reload_config = if figment.contains("tls_reload") {
+        figment
+            .extract_inner::<TlsReloadConfig>("tls_reload")
+            .expect("valid TLS reload configuration")
+    } else {
+        TlsReloadConfig::default()
+    };
+    // The certificate is watched when loaded from a file, e.g. renewed by the PKI.
+    let tls_certs_path = figment.extract_inner::<String>("tls.certs").ok();
+    let idempotency_config = if figment.contains("idempotency") {
+        figment
+            .extract_inner::<IdempotencyConfig>("idempotency")
+            .expect("valid idempotency configuration")
+    } else {
+        IdempotencyConfig::default()
+    };
+    let reconciliation_config = if figment.contains("reconciliation") {
+        figment
+            .extract_inner::<ReconciliationConfig>("reconciliation")
+            .expect("valid reconciliation configuration")
+    } else {
+        ReconciliationConfig::default()
+    };
+    let web_push: server::OptionalWebPush = if figment.contains("web_push") {
+        let config = figment
+            .extract_inner::<WebPushConfig>("web_push")
+            .expect("valid Web Push configuration");
+        Some(Arc::new(
+            WebPush::from_config(&config).expect("A valid Web Push configuration!"),
+        ))
+    } else {
+        None
+    };
+    let mailer = if figment.contains("email") {
+        let config = figment
+            .extract_inner::<EmailConfig>("email")
+            .expect("valid email configuration");
+        config
+            .enabled
+            .then(|| Mailer::from_config(&config).expect("A valid email configuration!"))
+    } else {
+        None
+    };
+    let fanout_config = if figment.contains("fanout") {
+        figment
+            .extract_inner::<FanoutConfig>("fanout")
+            .expect("valid fan-out configuration")
+    } else {
+        FanoutConfig::default()
+    };
+    let retry_config = if figment.contains("db_retry") {
+        figment
+            .extract_inner::<RetryConfig>("db_retry")
+            .expect("valid database retry configuration")
+    } else {
+        RetryConfig::default()
+    };
+    let folders_config = if figment.contains("folders") {
+        figment
+            .extract_inner::<FoldersConfig>("folders")
+            .expect("valid folders configuration")
+    } else {
+        FoldersConfig::default()
+    };
+    let framing_config = if figment.contains("framing") {
+        figment
+            .extract_inner::<FramingConfig>("framing")
+            .expect("valid framing configuration")
+    } else {
+        FramingConfig::default()
+    };
+    let revocation_config = if figment.contains("revocation") {
+        let mut config = figment
+            .extract_inner::<RevocationConfig>("revocation")
+            .expect("valid revocation configuration");
+        // The revocation list is signed by the CA of the client certificates by default.
+        if config.ca_cert.is_none() {
+            config.ca_cert = figment.extract_inner::<String>("tls.mutual.ca_certs").ok();
+        }
+        Some(config)
+    } else {
+        None
+    };
+    let pki_verifier: server::OptionalPkiVerifier = if figment.contains("pki_verification") {
+        let mut config = figment
+            .extract_inner::<PkiVerificationConfig>("pki_verification")
+            .expect("valid PKI verification configuration");
+        // The PKI is served with a certificate of the CA of the client certificates by default.
+        if config.ca_cert.is_none() {
+            config.ca_cert = figment.extract_inner::<String>("tls.mutual.ca_certs").ok();
+        }
+        Some(PkiVerifier::from_config(&config).expect("A valid PKI verification configuration!"))
+    } else {
+        None
+    };
+    let session_config = if figment.contains("session") {
+        figment
+            .extract_inner::<SessionConfig>("session")
+            .expect("valid session configuration")
+    } else {
+        SessionConfig::default()
+    };
+    let admins = figment
+        .extract_inner::<HashSet<String>>("admins")
+        .unwrap_or_default();
+    let signer: server::OptionalSigner =
+        storage::initialise_signer(&storage_config).expect("A valid Store instance!");
     let storage: server::SyncStore = Arc::new(Mutex::new(
         storage::initialise_object_store(storage_config).expect("A valid Store instance!"),
     ));
 
-    // TODO: configure through env variables.
-    let other_servers = vec![
-        "https://localhost:8000",
-        "https://localhost:8001",
-        "http://localhost:3000",
-        "https://127.0.0.1:8001",
-    ];
-    let cors = CorsOptions::default()
-        .allowed_origins(AllowedOrigins::some_exact(&other_servers))
+    let cors_options = if figment.contains("cors") {
+        figment
+            .extract_inner::<CorsOptions>("cors")
+            .expect("valid CORS configuration")
+    } else {
+        CorsOptions::default().allowed_origins(AllowedOrigins::some_exact(&DEFAULT_ALLOWED_ORIGINS))
+    };
+    let cors = cors_options
         .to_cors()
         .expect("The CORS configuration is invalid.");
 
     // Initialise the rocket server also mounting the swagger-ui.
     rocket::custom(figment)
         .attach(db::DbConn::init())
+        .attach(AdHoc::try_on_ignite(
+            "Database migrations",
+            move |rocket| async move {
+                if !run_migrations {
+                    return Ok(rocket);
+                }
+                let Some(db) = db::DbConn::fetch(&rocket) else {
+                    return Err(rocket);
+                };
+                match db::run_migrations(db).await {
+                    Ok(()) => Ok(rocket),
+                    Err(e) => {
+                        log::error!("Couldn't run the database migrations: `{}`", e);
+                        Err(rocket)
+                    }
+                }
+            },
+        ))
+        .attach(RequestIdFairing)
         .attach(cors)
+        .attach(RateLimiter::new(rate_limit_config))
+        .attach(GracefulShutdown::new())
+        .attach(Idempotency::new(idempotency_config))
+        .attach(retention::fairing(retention_config))
+        .attach(reconciliation::fairing(reconciliation_config))
+        .attach(web_push::fairing())
+        .attach(email::fairing(mailer))
+        .attach(fanout::fairing(fanout_config))
+        .attach(revocation::fairing(revocation_config))
+        .attach(storage::lifecycle_fairing(lifecycle))
+        .attach(tls_reload::fairing(tls_reload_config, tls_certs_path))
         .manage(storage)
-        //.manage(web_socket_clients)
-        //.manage(web_socket_queues)
-        .manage(channel::<Notification>(1024).0)
+        .manage(multipart_storage)
+        .manage(signer)
+        .manage(web_push)
+        .manage(quota_config)
+        .manage(retry_config)
+        .manage(Bandwidth::new(bandwidth_config))
+        .manage(framing_config)
+        .manage(folders_config)
+        .manage(RevocationList::default())
+        .manage(pki_verifier)
+        .manage(Sessions::from_config(&session_config).expect("A valid session configuration!"))
+        .manage(upload_limits)
+        .manage(ServerAdmins(admins))
+        .manage(TlsReload::new())
+        .manage(SenderSentEventQueue::new(64))
+        .attach(AdHoc::on_liftoff("Readiness check", |rocket| {
+            Box::pin(async move {
+                let db = db::DbConn::fetch(rocket);
+                let store = rocket.state::<server::SyncStore>();
+                let (Some(db), Some(store)) = (db, store) else {
+                    log::error!("The server state is not initialised");
+                    return;
+                };
+                let readiness = server::check_readiness(db, store, rocket.config()).await;
+                if readiness.is_ready() {
+                    log::info!("The server is ready to serve requests");
+                } else {
+                    log::error!(
+                        "The server is not ready to serve requests: `{:?}`",
+                        readiness
+                    );
+                }
+            })
+        }))
+        .register("/", rocket::catchers![server::payload_too_large])
         .mount(
             "/",
             SwaggerUi::new("/swagger-ui/<_..>")
@@ -71,28 +336,74 @@ pub fn init_server_from_config() -> rocket::Rocket<rocket::Build> {
         )
         .mount(
             "/",
-            rocket::routes![
+            request_id::scoped(rocket::routes![
                 server::openapi,
+                server::healthz,
+                server::readyz,
+                server::refresh_revocations,
+                server::reconcile,
                 server::create_user,
+                server::delete_self,
+                server::list_devices,
+                server::create_session,
+                server::get_vapid_public_key,
+                server::create_push_subscription,
+                server::delete_push_subscription,
                 server::create_folder,
                 server::list_users,
                 server::list_folders_for_user,
                 server::get_folder,
+                server::list_folder_members,
+                server::list_audit_events,
                 server::share_folder,
                 server::remove_self_from_folder,
+                server::remove_folder_member,
+                server::transfer_folder_ownership,
+                server::set_folder_name,
+                server::create_share_link,
+                server::list_share_links,
+                server::revoke_share_link,
+                server::delete_folder,
                 server::get_file,
+                server::get_file_with_link,
                 server::upload_file,
+                server::upload_files,
+                server::delete_file,
+                server::start_file_upload,
+                server::get_file_upload,
+                server::upload_file_part,
+                server::complete_file_upload,
+                server::abort_file_upload,
+                server::presign_file,
+                server::list_files,
+                server::export_folder,
+                server::list_trash,
+                server::restore_file,
                 server::get_metadata,
+                server::get_metadata_with_link,
+                server::list_metadata_versions,
                 server::post_metadata,
+                server::rollback_metadata,
                 server::publish_key_package,
+                server::count_key_packages,
                 server::fetch_key_package,
                 server::try_publish_proposal,
                 server::get_pending_proposal,
+                server::list_pending_proposals,
                 server::ack_message,
+                server::ack_messages,
+                server::list_message_receipts,
                 server::v2_share_folder,
+                server::v2_share_folder_welcome,
+                server::invite_to_folder,
+                server::list_invitations,
+                server::accept_invitation,
+                server::delete_invitation,
+                server::get_welcome,
+                server::ack_welcome,
                 server::try_publish_application_msg,
-                //server::echo_channel,
-                server::sse
-            ],
+                server::sse,
+                server::ws_notifications
+            ]),
         )
 }
@@ -0,0 +1,92 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use rocket::data::{ByteUnit, Limits};
+
+/// The room left in a form for the fields other than the file and the metadata, e.g. the etags.
+const OTHER_FIELDS_SIZE: ByteUnit = ByteUnit::Mebibyte(1);
+
+/// The upload size limits, loaded from the `upload` table of the `DS_Rocket.toml` file.
+/// They are enforced while the form is parsed, so that an upload is rejected as soon as it exceeds them.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UploadLimits {
+    /// The maximum size of an uploaded file.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: ByteUnit,
+    /// The maximum size of the metadata of a folder, and of the other binary fields of a form, e.g. the proposals.
+    #[serde(default = "default_max_metadata_size")]
+    pub max_metadata_size: ByteUnit,
+}
+
+fn default_max_file_size() -> ByteUnit {
+    ByteUnit::Gibibyte(5)
+}
+
+fn default_max_metadata_size() -> ByteUnit {
+    ByteUnit::Mebibyte(16)
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        UploadLimits {
+            max_file_size: default_max_file_size(),
+            max_metadata_size: default_max_metadata_size(),
+        }
+    }
+}
+
+impl UploadLimits {
+    /// Returns the Rocket data limits enforcing the upload limits on top of `limits`.
+    /// The files are [`rocket::fs::TempFile`]s, bound by the `file` limit, while the metadata are
+    /// byte slices, bound by the `bytes` limit.
+    pub fn apply(&self, limits: Limits) -> Limits {
+        limits
+            .limit("file", self.max_file_size)
+            .limit("bytes", self.max_metadata_size)
+            .limit(
+                "data-form",
+                self.max_file_size + self.max_metadata_size + OTHER_FIELDS_SIZE,
+            )
+    }
+
+    /// The description of the limits, returned when an upload exceeds them.
+    pub fn describe(&self) -> String {
+        format!(
+            "The upload exceeds the size limits: files can be up to {} and metadata up to {}",
+            self.max_file_size, self.max_metadata_size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let limits = UploadLimits {
+            max_file_size: ByteUnit::Mebibyte(10),
+            max_metadata_size: ByteUnit::Kibibyte(64),
+        }
+        .apply(Limits::default());
+        assert_eq!(Some(ByteUnit::Mebibyte(10)), limits.get("file"));
+        assert_eq!(Some(ByteUnit::Kibibyte(64)), limits.get("bytes"));
+        assert_eq!(
+            Some(ByteUnit::Mebibyte(11) + ByteUnit::Kibibyte(64)),
+            limits.get("data-form")
+        );
+        // The other limits are left untouched.
+        assert_eq!(Limits::default().get("json"), limits.get("json"));
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rocket::{
+    request::{FromRequest, Outcome},
+    tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    Request,
+};
+
+use crate::server::Notification;
+
+/// The number of notifications kept for each user to be replayed on reconnection.
+const HISTORY_LEN_PER_USER: usize = 128;
+
+/// How long a notification is kept in the history, a client reconnecting later can't replay it.
+const HISTORY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The number of users with a history above which the expired histories are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Delivers the notifications of the users without any connected client through another channel, e.g. Web Push.
+/// It is called while sending the notification, so the delivery itself should happen in the background.
+pub trait OfflineDelivery: Send + Sync {
+    fn deliver(&self, notification: &Notification);
+}
+
+/// Forwards the notifications sent by this instance to the other instances of the DS, e.g. through Redis pub/sub,
+/// so that they reach the clients connected to any instance.
+/// It is called while sending the notification, so the forwarding itself should happen in the background.
+pub trait NotificationRelay: Send + Sync {
+    fn publish(&self, notification: &Notification);
+}
+
+/// The queue used to fan-out the [`Notification`]s to the connected clients.
+/// Each connection of a user has its own bounded buffer, registered under the email of the user,
+/// so that a burst of notifications to a user doesn't affect the others.
+/// The most recent notifications of each user are also kept in memory, so that a client
+/// which lost its connection, or which was too slow to keep up, can replay the events it missed.
+/// The notifications that no connection received are handed to the registered [`OfflineDelivery`]s.
+/// If a [`NotificationRelay`] is set, the notifications are also forwarded to the other instances of the DS,
+/// which deliver them to their own connections. The history and the ids are kept by each instance.
+/// Cloning the queue returns a new handle to the same queue, e.g. to send notifications from a background task.
+#[derive(Clone)]
+pub struct NotificationQueue {
+    capacity: usize,
+    state: Arc<Mutex<Registry>>,
+    offline: Arc<Mutex<Vec<Arc<dyn OfflineDelivery>>>>,
+    relay: Arc<Mutex<Option<Arc<dyn NotificationRelay>>>>,
+}
+
+struct Registry {
+    /// The id to assign to the next notification.
+    next_id: u64,
+    /// The recent notifications of each user, with the time they were sent.
+    history: HashMap<String, VecDeque<(Instant, Notification)>>,
+    subscribers: HashMap<String, Vec<Subscriber>>,
+}
+
+/// The sending side of a connection.
+struct Subscriber {
+    sender: Sender<Notification>,
+    /// The number of notifications dropped because the buffer of the connection was full.
+    missed: Arc<AtomicU64>,
+}
+
+impl Registry {
+    fn history_after(&self, receiver: &str, last_event_id: u64) -> VecDeque<Notification> {
+        self.history
+            .get(receiver)
+            .map(|history| {
+                history
+                    .iter()
+                    .map(|(_, notification)| notification)
+                    .filter(|notification| notification.id > last_event_id)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drop the expired notifications, and the histories left empty of the users without any connection.
+    fn prune(&mut self, now: Instant) {
+        let subscribers = &self.subscribers;
+        self.history.retain(|receiver, history| {
+            while history
+                .front()
+                .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) > HISTORY_TTL)
+            {
+                history.pop_front();
+            }
+            !history.is_empty() || subscribers.contains_key(receiver)
+        });
+    }
+
+    /// Unregister the closed connections of `receiver`, and the user itself once it has none left.
+    fn remove_closed(&mut self, receiver: &str) {
+        if let Some(subscribers) = self.subscribers.get_mut(receiver) {
+            subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            if subscribers.is_empty() {
+                self.subscribers.remove(receiver);
+            }
+        }
+    }
+}
+
+impl NotificationQueue {
+    /// Create a new queue, buffering at most `capacity` notifications for each slow connection.
+    pub fn new(capacity: usize) -> Self {
+        // Start from the current time, so that the ids keep increasing across restarts
+        // and a client can't skip new events because of a stale `Last-Event-ID`.
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as u64);
+        NotificationQueue {
+            capacity,
+            state: Arc::new(Mutex::new(Registry {
+                next_id,
+                history: HashMap::new(),
+                subscribers: HashMap::new(),
+            })),
+            offline: Arc::new(Mutex::new(vec![])),
+            relay: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Register a channel to deliver the notifications of the users without any connected client.
+    pub fn add_offline_delivery(&self, delivery: Arc<dyn OfflineDelivery>) {
+        self.offline
+            .lock()
+            .expect("Notification registry corrupted!")
+            .push(delivery);
+    }
+
+    /// Set the channel forwarding the notifications to the other instances of the DS.
+    pub fn set_relay(&self, relay: Arc<dyn NotificationRelay>) {
+        *self.relay.lock().expect("Notification registry corrupted!") = Some(relay);
+    }
+
+    /// Assign an id to the notification, record it in the history of its receiver and push it to the
+    /// connections of the receiver. Returns the number of connections of this instance that received it.
+    /// The offline delivery is decided by this instance only, so a user connected only to another instance
+    /// may also be notified through the offline channels.
+    pub fn send(&self, notification: Notification) -> usize {
+        let (delivered, connected, notification) = self.push(notification);
+        if let Some(relay) = self
+            .relay
+            .lock()
+            .expect("Notification registry corrupted!")
+            .as_ref()
+        {
+            relay.publish(&notification);
+        }
+        if !connected {
+            let offline = self
+                .offline
+                .lock()
+                .expect("Notification registry corrupted!");
+            for delivery in offline.iter() {
+                delivery.deliver(&notification);
+            }
+        }
+        delivered
+    }
+
+    /// Push a notification forwarded by another instance to the connections of this instance,
+    /// without forwarding it again nor handing it to the offline channels.
+    pub fn send_relayed(&self, notification: Notification) -> usize {
+        self.push(notification).0
+    }
+
+    /// Returns the number of connections that received the notification, and whether the receiver has any connection.
+    fn push(&self, mut notification: Notification) -> (usize, bool, Notification) {
+        let mut state = self.state.lock().expect("Notification registry corrupted!");
+        notification.id = state.next_id;
+        state.next_id += 1;
+        let now = Instant::now();
+        if state.history.len() > PRUNE_THRESHOLD {
+            state.prune(now);
+        }
+        let history = state
+            .history
+            .entry(notification.receiver.clone())
+            .or_default();
+        if history.len() == HISTORY_LEN_PER_USER {
+            history.pop_front();
+        }
+        history.push_back((now, notification.clone()));
+        let Some(subscribers) = state.subscribers.get_mut(&notification.receiver) else {
+            return (0, false, notification);
+        };
+        // Push while holding the lock, so that the ids are received in increasing order.
+        let mut delivered = 0;
+        subscribers.retain(
+            |subscriber| match subscriber.sender.try_send(notification.clone()) {
+                Ok(()) => {
+                    delivered += 1;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    subscriber.missed.fetch_add(1, Ordering::SeqCst);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+        );
+        let connected = !subscribers.is_empty();
+        if !connected {
+            state.subscribers.remove(&notification.receiver);
+        }
+        (delivered, connected, notification)
+    }
+
+    /// Subscribe to the notifications of `receiver` sent from now on.
+    pub fn subscribe(&self, receiver: &str) -> Subscription {
+        self.subscribe_after(receiver, None)
+    }
+
+    /// Subscribe to the notifications of `receiver`, receiving first the ones that were sent after `last_event_id`.
+    pub fn subscribe_after(&self, receiver: &str, last_event_id: Option<u64>) -> Subscription {
+        let mut state = self.state.lock().expect("Notification registry corrupted!");
+        let (sender, rx) = channel(self.capacity);
+        let missed = Arc::new(AtomicU64::new(0));
+        let pending = match last_event_id {
+            Some(last_event_id) => state.history_after(receiver, last_event_id),
+            None => VecDeque::new(),
+        };
+        let subscribers = state.subscribers.entry(receiver.to_string()).or_default();
+        subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        subscribers.push(Subscriber {
+            sender,
+            missed: missed.clone(),
+        });
+        Subscription {
+            receiver: receiver.to_string(),
+            rx,
+            missed,
+            pending,
+            last_event_id: last_event_id.unwrap_or(0),
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// A connection receiving the notifications of a single user, in increasing id order.
+pub struct Subscription {
+    receiver: String,
+    rx: Receiver<Notification>,
+    missed: Arc<AtomicU64>,
+    /// The notifications to deliver before reading from the buffer.
+    pending: VecDeque<Notification>,
+    /// The id of the last delivered notification.
+    last_event_id: u64,
+    state: Arc<Mutex<Registry>>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Unregister the connection right away, instead of on the next notification to the user.
+        self.rx.close();
+        if let Ok(mut state) = self.state.lock() {
+            state.remove_closed(&self.receiver);
+        }
+    }
+}
+
+impl Subscription {
+    /// The email of the user receiving the notifications.
+    pub fn receiver(&self) -> &str {
+        &self.receiver
+    }
+
+    /// Wait for the next notification, or `None` if the queue has been dropped.
+    /// If some notifications were dropped because the buffer was full, they are recovered from the history.
+    pub async fn recv(&mut self) -> Option<Notification> {
+        loop {
+            if let Some(notification) = self.pending.pop_front() {
+                self.last_event_id = notification.id;
+                return Some(notification);
+            }
+            let notification = self.rx.recv().await?;
+            let missed = self.missed.swap(0, Ordering::SeqCst);
+            if missed > 0 {
+                log::warn!(
+                    "Dropped `{}` notifications for `{}`, replaying them from the history",
+                    missed,
+                    self.receiver
+                );
+                let state = self.state.lock().expect("Notification registry corrupted!");
+                self.pending = state.history_after(&self.receiver, self.last_event_id);
+            }
+            if self
+                .pending
+                .back()
+                .is_none_or(|last| last.id < notification.id)
+            {
+                self.pending.push_back(notification);
+            }
+            // Skip the notifications already replayed.
+            self.pending
+                .retain(|notification| notification.id > self.last_event_id);
+        }
+    }
+}
+
+/// The value of the `Last-Event-ID` header sent by reconnecting SSE clients, if any.
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let last_event_id = req
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.trim().parse::<u64>().ok());
+        Outcome::Success(LastEventId(last_event_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::server::NotificationEvent;
+
+    use super::*;
+
+    fn notification(receiver: &str, folder_id: u64) -> Notification {
+        Notification {
+            id: 0,
+            event: NotificationEvent::Proposal,
+            folder_id: Some(folder_id),
+            message_id: None,
+            receiver: receiver.to_string(),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_replay_after_last_event_id() {
+        let queue = NotificationQueue::new(16);
+        let mut rx = queue.subscribe("alice@test.com");
+        for folder_id in 0..3 {
+            queue.send(notification("alice@test.com", folder_id));
+            queue.send(notification("bob@test.com", folder_id));
+        }
+        let first = rx.recv().await.unwrap();
+        assert_eq!("alice@test.com", first.receiver);
+        assert_eq!(Some(0), first.folder_id);
+        let mut replay = queue.subscribe_after("alice@test.com", Some(first.id));
+        assert_eq!(Some(1), replay.recv().await.unwrap().folder_id);
+        assert_eq!(Some(2), replay.recv().await.unwrap().folder_id);
+        assert!(replay.pending.is_empty());
+        let fresh = queue.subscribe("alice@test.com");
+        assert!(fresh.pending.is_empty());
+    }
+
+    #[derive(Default)]
+    struct RecordingDelivery(Mutex<Vec<String>>);
+
+    impl OfflineDelivery for RecordingDelivery {
+        fn deliver(&self, notification: &Notification) {
+            self.0.lock().unwrap().push(notification.receiver.clone());
+        }
+    }
+
+    #[test]
+    fn test_offline_delivery() {
+        let queue = NotificationQueue::new(16);
+        let delivery = Arc::new(RecordingDelivery::default());
+        queue.add_offline_delivery(delivery.clone());
+        let _alice = queue.subscribe("alice@test.com");
+        assert_eq!(1, queue.send(notification("alice@test.com", 1)));
+        assert_eq!(0, queue.send(notification("bob@test.com", 1)));
+        assert_eq!(vec!["bob@test.com"], *delivery.0.lock().unwrap());
+    }
+
+    #[derive(Default)]
+    struct RecordingRelay(Mutex<Vec<String>>);
+
+    impl NotificationRelay for RecordingRelay {
+        fn publish(&self, notification: &Notification) {
+            self.0.lock().unwrap().push(notification.receiver.clone());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_relay() {
+        let queue = NotificationQueue::new(16);
+        let relay = Arc::new(RecordingRelay::default());
+        queue.set_relay(relay.clone());
+        let mut alice = queue.subscribe("alice@test.com");
+        // All the notifications are forwarded, as the receivers may be connected to other instances.
+        assert_eq!(1, queue.send(notification("alice@test.com", 1)));
+        assert_eq!(0, queue.send(notification("bob@test.com", 1)));
+        assert_eq!(
+            vec!["alice@test.com", "bob@test.com"],
+            *relay.0.lock().unwrap()
+        );
+        // The forwarded notifications are delivered, but not forwarded again.
+        assert_eq!(1, queue.send_relayed(notification("alice@test.com", 2)));
+        assert_eq!(2, relay.0.lock().unwrap().len());
+        assert_eq!(Some(1), alice.recv().await.unwrap().folder_id);
+        assert_eq!(Some(2), alice.recv().await.unwrap().folder_id);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let queue = NotificationQueue::new(16);
+        for folder_id in 0..(HISTORY_LEN_PER_USER as u64 + 10) {
+            queue.send(notification("alice@test.com", folder_id));
+        }
+        let subscription = queue.subscribe_after("alice@test.com", Some(0));
+        assert_eq!(HISTORY_LEN_PER_USER, subscription.pending.len());
+        assert_eq!(Some(10), subscription.pending[0].folder_id);
+    }
+
+    #[rocket::async_test]
+    async fn test_per_user_delivery() {
+        let queue = NotificationQueue::new(2);
+        let mut alice = queue.subscribe("alice@test.com");
+        let mut bob = queue.subscribe("bob@test.com");
+        // A burst to alice overflows her buffer, but doesn't affect bob.
+        for folder_id in 0..5 {
+            queue.send(notification("alice@test.com", folder_id));
+        }
+        assert_eq!(1, queue.send(notification("bob@test.com", 42)));
+        assert_eq!(Some(42), bob.recv().await.unwrap().folder_id);
+        assert_eq!(3, alice.missed.load(Ordering::SeqCst));
+        // The dropped notifications are recovered from the history, in order.
+        for folder_id in 0..5 {
+            assert_eq!(Some(folder_id), alice.recv().await.unwrap().folder_id);
+        }
+        queue.send(notification("alice@test.com", 5));
+        assert_eq!(Some(5), alice.recv().await.unwrap().folder_id);
+    }
+
+    #[test]
+    fn test_closed_subscriptions_are_removed() {
+        let queue = NotificationQueue::new(2);
+        let subscription = queue.subscribe("alice@test.com");
+        assert_eq!(1, queue.send(notification("alice@test.com", 0)));
+        let other = queue.subscribe("alice@test.com");
+        drop(subscription);
+        assert_eq!(
+            1,
+            queue.state.lock().unwrap().subscribers["alice@test.com"].len()
+        );
+        drop(other);
+        assert!(!queue
+            .state
+            .lock()
+            .unwrap()
+            .subscribers
+            .contains_key("alice@test.com"));
+        assert_eq!(0, queue.send(notification("alice@test.com", 1)));
+    }
+
+    #[test]
+    fn test_expired_history_is_pruned() {
+        let queue = NotificationQueue::new(2);
+        queue.send(notification("alice@test.com", 0));
+        queue.send(notification("bob@test.com", 0));
+        let _bob = queue.subscribe("bob@test.com");
+        let mut state = queue.state.lock().unwrap();
+        state.prune(Instant::now());
+        assert_eq!(2, state.history.len());
+        state.prune(Instant::now() + HISTORY_TTL + Duration::from_secs(1));
+        // The user still connected is kept, with an empty history.
+        assert!(!state.history.contains_key("alice@test.com"));
+        assert!(state.history["bob@test.com"].is_empty());
+    }
+}
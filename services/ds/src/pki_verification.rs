@@ -0,0 +1,204 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ring::digest::{digest, SHA256};
+
+/// The path of the verification endpoint of the PKI.
+const VERIFY_PATH: &str = "/ca/verify";
+
+/// The number of cached results above which the expired ones are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The online verification configuration, loaded from the `pki_verification` table of the `DS_Rocket.toml` file.
+/// The client certificates are only verified against the local CA file if the table is missing.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PkiVerificationConfig {
+    /// The base URL of the PKI, e.g. `https://localhost:8000`.
+    pub url: String,
+    /// The path of the CA certificate trusted for the TLS connection with the PKI, the CA of the client certificates if missing.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// How long the verdict of the PKI on a certificate is cached, in seconds.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    /// The timeout of the requests to the PKI, in milliseconds.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Whether the certificates are accepted when the PKI can't be reached, they are rejected by default.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_cache_ttl() -> u64 {
+    5 * 60
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+#[derive(serde::Serialize)]
+struct VerifyRequest {
+    certificate: String,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyResponse {
+    valid: bool,
+}
+
+/// Verifies the client certificates with the PKI, so that its policy, e.g. the revocations, is enforced
+/// as soon as the cached verdicts expire.
+/// The verdicts are cached in memory per certificate, the unreachable PKI is not cached.
+pub struct PkiVerifier {
+    client: reqwest::Client,
+    verify_url: String,
+    cache_ttl: Duration,
+    fail_open: bool,
+    cache: Mutex<HashMap<Vec<u8>, (bool, Instant)>>,
+}
+
+impl PkiVerifier {
+    pub fn from_config(config: &PkiVerificationConfig) -> Result<Self, String> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_millis(config.timeout));
+        if let Some(ca_cert) = &config.ca_cert {
+            let pem = std::fs::read(ca_cert)
+                .map_err(|e| format!("Couldn't read the CA certificate `{}`: {}", ca_cert, e))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid CA certificate `{}`: {}", ca_cert, e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Couldn't initialise the HTTP client: {}", e))?;
+        Ok(PkiVerifier {
+            client,
+            verify_url: format!("{}{}", config.url.trim_end_matches('/'), VERIFY_PATH),
+            cache_ttl: Duration::from_secs(config.cache_ttl),
+            fail_open: config.fail_open,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the cached verdict on the certificate with the fingerprint, if it has not expired.
+    fn cached(&self, fingerprint: &[u8], now: Instant) -> Option<bool> {
+        let cache = self.cache.lock().expect("Verification cache corrupted!");
+        cache
+            .get(fingerprint)
+            .filter(|(_, verified)| now.duration_since(*verified) < self.cache_ttl)
+            .map(|(valid, _)| *valid)
+    }
+
+    fn store(&self, fingerprint: Vec<u8>, valid: bool, now: Instant) {
+        let mut cache = self.cache.lock().expect("Verification cache corrupted!");
+        if cache.len() > PRUNE_THRESHOLD {
+            cache.retain(|_, (_, verified)| now.duration_since(*verified) < self.cache_ttl);
+        }
+        cache.insert(fingerprint, (valid, now));
+    }
+
+    /// Ask the PKI whether the DER encoded certificate is valid.
+    async fn request(&self, certificate: &[u8]) -> Result<bool, String> {
+        let request = VerifyRequest {
+            certificate: pem::encode(&pem::Pem::new("CERTIFICATE", certificate)),
+        };
+        let response = self
+            .client
+            .post(&self.verify_url)
+            .json(&request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let response = response
+            .json::<VerifyResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.valid)
+    }
+
+    /// Whether the DER encoded client certificate is valid according to the PKI.
+    pub async fn verify(&self, certificate: &[u8]) -> bool {
+        let fingerprint = digest(&SHA256, certificate).as_ref().to_vec();
+        if let Some(valid) = self.cached(&fingerprint, Instant::now()) {
+            return valid;
+        }
+        match self.request(certificate).await {
+            Ok(valid) => {
+                self.store(fingerprint, valid, Instant::now());
+                valid
+            }
+            Err(e) => {
+                log::error!(
+                    "Couldn't verify the client certificate with the PKI: `{}`",
+                    e
+                );
+                self.fail_open
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn verifier(url: &str, fail_open: bool) -> PkiVerifier {
+        PkiVerifier::from_config(&PkiVerificationConfig {
+            url: url.to_string(),
+            ca_cert: None,
+            cache_ttl: 60,
+            timeout: 100,
+            fail_open,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_url() {
+        assert_eq!(
+            "https://localhost:8000/ca/verify",
+            verifier("https://localhost:8000/", false).verify_url
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let verifier = verifier("https://localhost:8000", false);
+        let now = Instant::now();
+        assert_eq!(None, verifier.cached(b"a", now));
+        verifier.store(b"a".to_vec(), true, now);
+        verifier.store(b"b".to_vec(), false, now);
+        assert_eq!(
+            Some(true),
+            verifier.cached(b"a", now + Duration::from_secs(30))
+        );
+        assert_eq!(Some(false), verifier.cached(b"b", now));
+        // The verdicts expire.
+        assert_eq!(None, verifier.cached(b"a", now + Duration::from_secs(60)));
+    }
+
+    #[rocket::async_test]
+    async fn test_unreachable_pki() {
+        // Nothing listens on the port.
+        assert!(!verifier("http://127.0.0.1:9", false).verify(b"cert").await);
+        assert!(verifier("http://127.0.0.1:9", true).verify(b"cert").await);
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use rocket::data::ByteUnit;
+
+use crate::db::UsageEntity;
+
+/// The storage quota configuration, loaded from the `quota` table of the `DS_Rocket.toml` file.
+/// Each limit is optional, no limit is enforced when it is missing.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct QuotaConfig {
+    /// The maximum number of bytes a user can store across all the folders.
+    #[serde(default)]
+    pub per_user: Option<ByteUnit>,
+    /// The maximum number of bytes that can be stored in a single folder.
+    #[serde(default)]
+    pub per_folder: Option<ByteUnit>,
+}
+
+impl QuotaConfig {
+    /// Whether any limit is configured.
+    pub fn is_enforced(&self) -> bool {
+        self.per_user.is_some() || self.per_folder.is_some()
+    }
+
+    /// Check that `additional` bytes can be stored on top of the current `usage`.
+    /// Returns the description of the exceeded limit otherwise.
+    pub fn check(&self, usage: &UsageEntity, additional: u64) -> Result<(), String> {
+        if let Some(per_user) = self.per_user {
+            if usage.user_bytes.saturating_add(additional) > per_user.as_u64() {
+                return Err(format!(
+                    "The user storage quota of {} is exceeded",
+                    per_user
+                ));
+            }
+        }
+        if let Some(per_folder) = self.per_folder {
+            if usage.folder_bytes.saturating_add(additional) > per_folder.as_u64() {
+                return Err(format!(
+                    "The folder storage quota of {} is exceeded",
+                    per_folder
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn usage(user_bytes: u64, folder_bytes: u64) -> UsageEntity {
+        UsageEntity {
+            user_bytes,
+            folder_bytes,
+        }
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let quota = QuotaConfig::default();
+        assert!(!quota.is_enforced());
+        assert!(quota.check(&usage(u64::MAX, u64::MAX), u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_limits() {
+        let quota = QuotaConfig {
+            per_user: Some(ByteUnit::Kilobyte(10)),
+            per_folder: Some(ByteUnit::Kilobyte(5)),
+        };
+        assert!(quota.is_enforced());
+        assert!(quota.check(&usage(0, 0), 5_000).is_ok());
+        assert!(quota.check(&usage(0, 0), 5_001).is_err());
+        assert!(quota.check(&usage(9_000, 0), 1_000).is_ok());
+        assert!(quota.check(&usage(9_000, 0), 1_001).is_err());
+        assert!(quota.check(&usage(0, 4_000), 1_001).is_err());
+    }
+}
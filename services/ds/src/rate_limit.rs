@@ -0,0 +1,242 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, Method, Status},
+    Data, Request, Response,
+};
+
+use crate::server::{ErrorBody, SessionOrCertificate};
+
+/// The number of tracked clients above which the expired windows are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The rate limit configuration, loaded from the `rate_limit` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RateLimitConfig {
+    /// The maximum number of requests of a client certificate email in each period.
+    #[serde(default = "default_per_email")]
+    pub per_email: u32,
+    /// The maximum number of requests from an IP address in each period.
+    #[serde(default = "default_per_ip")]
+    pub per_ip: u32,
+    /// The length of the period in seconds.
+    #[serde(default = "default_period")]
+    pub period: u64,
+}
+
+fn default_per_email() -> u32 {
+    120
+}
+
+fn default_per_ip() -> u32 {
+    600
+}
+
+fn default_period() -> u64 {
+    60
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_email: default_per_email(),
+            per_ip: default_per_ip(),
+            period: default_period(),
+        }
+    }
+}
+
+/// The requests counted in the current period for a client.
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Marks a request that has been rejected, with the time after which the client can retry.
+struct Limited(Option<Duration>);
+
+/// A fairing limiting the rate of the requests to the upload, proposal and key package endpoints,
+/// per client certificate email and per IP address, using fixed windows.
+/// A request over the limit is answered with `429 Too Many Requests` and a `Retry-After` header,
+/// without reaching the handler.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request of the client identified by `key`, returning how long to wait if it exceeds the `limit`.
+    fn hit(&self, key: String, limit: u32, now: Instant) -> Result<(), Duration> {
+        let period = Duration::from_secs(self.config.period);
+        let mut windows = self.windows.lock().expect("Rate limiter state corrupted!");
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, window| now.duration_since(window.started) < period);
+        }
+        let window = windows.entry(key).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= period {
+            window.started = now;
+            window.count = 0;
+        }
+        window.count = window.count.saturating_add(1);
+        if window.count > limit {
+            Err(period.saturating_sub(now.duration_since(window.started)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns true if the endpoint is covered by the rate limiter: the file uploads,
+/// the proposals and the key packages, which fill the storage or the pending queues of other users.
+fn is_limited_endpoint(method: Method, segments: &[&str]) -> bool {
+    matches!(
+        (method, segments),
+        (Method::Post | Method::Put, ["folders", _, "files", _, ..])
+            | (Method::Post, ["folders", _, "files"])
+            | (Method::Post, ["folders", _, "metadatas"])
+            | (Method::Post | Method::Patch, ["folders", _, "proposals"])
+            | (Method::Post, ["folders", _, "keys"])
+            | (Method::Post, ["users", "keys"])
+    )
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let segments: Vec<&str> = req.uri().path().segments().collect();
+        if !is_limited_endpoint(req.method(), &segments) {
+            return;
+        }
+        let now = Instant::now();
+        let mut result = Ok(());
+        if let Some(ip) = req.client_ip() {
+            result = result.and(self.hit(format!("ip:{}", ip), self.config.per_ip, now));
+        }
+        if let Some(certificate) = req.guard::<SessionOrCertificate<'_>>().await.succeeded() {
+            for email in &certificate.emails {
+                result =
+                    result.and(self.hit(format!("email:{}", email), self.config.per_email, now));
+            }
+        }
+        if let Err(retry_after) = result {
+            log::debug!("Rate limiting request `{} {}`", req.method(), req.uri());
+            req.local_cache(|| Limited(Some(retry_after)));
+            // Divert the request, so that it doesn't reach the handler.
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse("/rate-limited").expect("valid URI"));
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Limited(Some(retry_after)) = req.local_cache(|| Limited(None)) {
+            // Round up, so that the client doesn't retry too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            let body = ErrorBody::new("rate_limited", "Too many requests, retry later.")
+                .with_retry_after(seconds);
+            let body = rocket::serde::json::to_string(&body).expect("valid JSON");
+            res.set_status(Status::TooManyRequests);
+            res.set_raw_header("Retry-After", seconds.to_string());
+            res.set_header(rocket::http::ContentType::JSON);
+            res.set_sized_body(body.len(), Cursor::new(body));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_limited_endpoints() {
+        assert!(is_limited_endpoint(
+            Method::Post,
+            &["folders", "1", "files", "file"]
+        ));
+        assert!(is_limited_endpoint(
+            Method::Put,
+            &["folders", "1", "files", "file", "uploads", "2", "parts", "0"]
+        ));
+        assert!(is_limited_endpoint(
+            Method::Post,
+            &["folders", "1", "files"]
+        ));
+        assert!(is_limited_endpoint(
+            Method::Post,
+            &["folders", "1", "proposals"]
+        ));
+        assert!(is_limited_endpoint(
+            Method::Post,
+            &["folders", "1", "metadatas"]
+        ));
+        assert!(is_limited_endpoint(Method::Post, &["folders", "1", "keys"]));
+        assert!(is_limited_endpoint(Method::Post, &["users", "keys"]));
+        assert!(!is_limited_endpoint(
+            Method::Get,
+            &["folders", "1", "files", "file"]
+        ));
+        assert!(!is_limited_endpoint(Method::Post, &["folders"]));
+        assert!(!is_limited_endpoint(
+            Method::Get,
+            &["users", "keys", "count"]
+        ));
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_email: 2,
+            per_ip: 2,
+            period: 60,
+        });
+        let now = Instant::now();
+        assert!(limiter.hit("a".to_string(), 2, now).is_ok());
+        assert!(limiter.hit("a".to_string(), 2, now).is_ok());
+        let retry_after = limiter
+            .hit("a".to_string(), 2, now + Duration::from_secs(20))
+            .unwrap_err();
+        assert_eq!(Duration::from_secs(40), retry_after);
+        // Other clients are not affected.
+        assert!(limiter.hit("b".to_string(), 2, now).is_ok());
+        // A new window starts after the period.
+        assert!(limiter
+            .hit("a".to_string(), 2, now + Duration::from_secs(60))
+            .is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{collections::HashSet, time::Duration};
+
+use rocket::{
+    fairing::AdHoc,
+    tokio::{self, sync::MutexGuard},
+};
+use rocket_db_pools::{sqlx, Database};
+
+use crate::{
+    db::{self, DbConn, FolderEntity},
+    server::{ReconciliationReport, SyncStore},
+    storage::{self, DynamicStore},
+};
+
+/// The reconciliation configuration, loaded from the `reconciliation` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct ReconciliationConfig {
+    /// How often to look for inconsistencies between the database and the object store, in seconds.
+    /// They are only looked for on demand, through the admin endpoint, if missing.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Whether the periodic scan repairs the inconsistencies, otherwise they are only logged.
+    #[serde(default)]
+    pub repair: bool,
+}
+
+/// A fairing spawning the background task that periodically reconciles the folders of the database
+/// with the objects of the object store, see [`reconcile`].
+pub fn fairing(config: ReconciliationConfig) -> AdHoc {
+    AdHoc::on_liftoff("Reconciliation", move |rocket| {
+        Box::pin(async move {
+            let Some(interval) = config.interval else {
+                log::info!("The storage is only reconciled on demand");
+                return;
+            };
+            let (Some(db), Some(store)) = (DbConn::fetch(rocket), rocket.state::<SyncStore>())
+            else {
+                log::error!("The server state is not initialised, the storage won't be reconciled");
+                return;
+            };
+            let pool = db.0.clone();
+            let store = store.clone();
+            let mut shutdown = rocket.shutdown();
+            // The first scan happens after a full period, so that the restarts don't trigger it.
+            let period = Duration::from_secs(interval.max(1));
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            match reconcile(&pool, &store, config.repair).await {
+                                Ok(report) if report.is_consistent() => {}
+                                Ok(report) => log::warn!("The storage is inconsistent: `{:?}`", report),
+                                Err(e) => log::error!("{}", e),
+                            }
+                        },
+                        _ = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Look for the inconsistencies between the folders of the database and the objects of the object store:
+/// the folders deleted from the database whose objects are left behind, and the existing folders
+/// whose metadata file is missing.
+/// If `repair` is set, the objects of the orphan folders are deleted and the missing metadata files
+/// are restored from the latest version of their history, when there is one.
+///
+/// A folder being created or deleted while scanning can be reported, but it is checked again before
+/// being repaired.
+pub async fn reconcile(
+    pool: &sqlx::AnyPool,
+    store: &SyncStore,
+    repair: bool,
+) -> Result<ReconciliationReport, String> {
+    // List the object store first, so that the folders created in the meantime are in the database.
+    let stored = storage::list_folder_ids(&store.lock().await)
+        .await
+        .map_err(|e| format!("Couldn't list the folders of the object store: `{}`", e))?;
+    let folders = db::list_all_folders(pool)
+        .await
+        .map_err(|e| format!("Couldn't list the folders of the database: `{}`", e))?;
+    let known: HashSet<u64> = folders.iter().map(|folder| folder.folder_id).collect();
+    let mut report = ReconciliationReport {
+        orphan_folders: stored
+            .into_iter()
+            .filter(|folder_id| !known.contains(folder_id))
+            .collect(),
+        ..Default::default()
+    };
+    for folder in folders {
+        // Lock the store for each folder, so that the requests are not blocked for the whole scan.
+        if is_metadata_missing(&store.lock().await, &folder).await {
+            report.missing_metadata.push(folder.folder_id);
+        }
+    }
+    if !repair {
+        return Ok(report);
+    }
+    for folder_id in &report.orphan_folders {
+        if repair_orphan_folder(*folder_id, pool, store).await {
+            report.repaired.push(*folder_id);
+        }
+    }
+    for folder_id in &report.missing_metadata {
+        if repair_missing_metadata(*folder_id, store).await {
+            report.repaired.push(*folder_id);
+        }
+    }
+    Ok(report)
+}
+
+/// Whether the metadata file of an existing folder is missing, the other errors are only logged.
+async fn is_metadata_missing(
+    object_store: &MutexGuard<'_, DynamicStore>,
+    folder: &FolderEntity,
+) -> bool {
+    match storage::read_metadata_version(object_store, folder).await {
+        Ok(_) => false,
+        Err(object_store::Error::NotFound { .. }) => true,
+        Err(e) => {
+            log::error!(
+                "Couldn't read the metadata file of folder `{}`: `{}`",
+                folder.folder_id,
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Delete the objects of a folder that doesn't exist in the database, returning whether they are deleted.
+async fn repair_orphan_folder(folder_id: u64, pool: &sqlx::AnyPool, store: &SyncStore) -> bool {
+    let store = store.lock().await;
+    match db::folder_exists(folder_id, pool).await {
+        Ok(false) => {}
+        Ok(true) => return false,
+        Err(e) => {
+            log::error!("Couldn't check if folder `{}` exists: `{}`", folder_id, e);
+            return false;
+        }
+    }
+    match storage::delete_folder(&store, &FolderEntity { folder_id }).await {
+        Ok(()) => {
+            log::info!("Deleted the objects of the orphan folder `{}`", folder_id);
+            true
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't delete the objects of the orphan folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Restore the missing metadata file of a folder from its history, returning whether it is restored.
+async fn repair_missing_metadata(folder_id: u64, store: &SyncStore) -> bool {
+    let store = store.lock().await;
+    let folder = FolderEntity { folder_id };
+    if !is_metadata_missing(&store, &folder).await {
+        return false;
+    }
+    match storage::restore_latest_metadata(&store, &folder).await {
+        Ok(true) => {
+            log::info!("Restored the metadata file of folder `{}`", folder_id);
+            true
+        }
+        Ok(false) => {
+            log::warn!(
+                "The metadata file of folder `{}` is missing and its history is empty",
+                folder_id
+            );
+            false
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't restore the metadata file of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::io::Write;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    route::{Handler, Outcome},
+    Data, Request, Response, Route,
+};
+
+/// The header used to receive and echo the id of a request.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The maximum length of a request id sent by a client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    /// The id of the request handled by the current task.
+    static REQUEST_ID: String;
+}
+
+/// The id of a request, cached in the request local state.
+struct RequestId(String);
+
+/// Returns the id of the request handled by the current task, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Initialise the logger, prefixing the messages logged while handling a request with its id.
+pub fn init_logger() -> Result<(), log::SetLoggerError> {
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let request_id = current().map(|id| format!(" {}", id)).unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {:<5} {}{}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            )
+        })
+        .try_init()
+}
+
+/// Wrap the handlers of the routes, so that the id of the request is available to [`current`] while they run.
+pub fn scoped(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Scoped(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// A fairing that assigns an id to every request and echoes it in the `X-Request-Id` header of the response.
+/// The id sent by the client in the same header is kept, so that a flow can be followed across the services.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let request_id = req
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(generate_request_id);
+        req.local_cache(|| RequestId(request_id));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let RequestId(request_id) = request_id(req);
+        res.set_raw_header(REQUEST_ID_HEADER, request_id.clone());
+    }
+}
+
+#[derive(Clone)]
+struct Scoped(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Scoped {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let RequestId(request_id) = request_id(req);
+        REQUEST_ID
+            .scope(request_id.clone(), self.0.handle(req, data))
+            .await
+    }
+}
+
+fn request_id<'r>(req: &'r Request<'_>) -> &'r RequestId {
+    req.local_cache(|| RequestId(generate_request_id()))
+}
+
+fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        log::error!("Couldn't generate a random request id");
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Only short printable ids are accepted from the clients, as they end up in the logs.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("3f2a-b1_c.9"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id with spaces"));
+        assert!(!is_valid_request_id("id\nforged log line"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+
+    #[test]
+    fn test_generate_request_id() {
+        let id = generate_request_id();
+        assert_eq!(id.len(), 32);
+        assert!(is_valid_request_id(&id));
+        assert_ne!(id, generate_request_id());
+    }
+}
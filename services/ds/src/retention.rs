@@ -0,0 +1,209 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::time::Duration;
+
+use rocket::{fairing::AdHoc, tokio};
+use rocket_db_pools::{sqlx, Database};
+use tokio::sync::MutexGuard;
+
+use crate::{
+    db::{self, DbConn, FolderEntity},
+    server::{self, Notification, NotificationEvent, SenderSentEventQueue, SyncStore},
+    storage::{self, DynamicStore},
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The retention configuration, loaded from the `retention` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RetentionConfig {
+    /// The number of days after which the pending messages expire, they are kept forever if missing.
+    #[serde(default)]
+    pub pending_messages_days: Option<u64>,
+    /// The number of days the deleted files are kept in the trash of their folder, they are kept forever if missing.
+    #[serde(default)]
+    pub trash_days: Option<u64>,
+    /// How often to delete the expired pending messages, key packages and trashed files, in seconds.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    60 * 60
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            pending_messages_days: None,
+            trash_days: None,
+            interval: default_interval(),
+        }
+    }
+}
+
+/// A fairing spawning the background task that deletes the expired key packages and share links, and
+/// expires the pending messages of the users that don't come back, so that they don't accumulate forever.
+/// The affected users are sent a [`NotificationEvent::StateReset`], as they can't process the following messages anymore.
+/// The files deleted since more than the retention window are removed from the trash as well.
+pub fn fairing(config: RetentionConfig) -> AdHoc {
+    AdHoc::on_liftoff("Retention", move |rocket| {
+        Box::pin(async move {
+            if config.pending_messages_days.is_none() {
+                log::info!("The pending messages never expire");
+            }
+            if config.trash_days.is_none() {
+                log::info!("The deleted files are kept in the trash forever");
+            }
+            let (Some(db), Some(queue), Some(store)) = (
+                DbConn::fetch(rocket),
+                rocket.state::<SenderSentEventQueue>(),
+                rocket.state::<SyncStore>(),
+            ) else {
+                log::error!(
+                    "The server state is not initialised, the expired data won't be deleted"
+                );
+                return;
+            };
+            let pool = db.0.clone();
+            let queue = queue.clone();
+            let store = store.clone();
+            let mut shutdown = rocket.shutdown();
+            let max_age = config
+                .pending_messages_days
+                .map(|days| Duration::from_secs(days.saturating_mul(SECONDS_PER_DAY)));
+            let trash_max_age = config
+                .trash_days
+                .map(|days| Duration::from_secs(days.saturating_mul(SECONDS_PER_DAY)));
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            delete_expired_key_packages(&pool).await;
+                            delete_expired_share_links(&pool).await;
+                            if let Some(max_age) = max_age {
+                                expire_pending_messages(max_age, &pool, &queue).await;
+                            }
+                            if let Some(trash_max_age) = trash_max_age {
+                                purge_trash(trash_max_age, &pool, &store).await;
+                            }
+                        },
+                        _ = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Delete the key packages whose lifetime ended, so that they are not handed out anymore.
+async fn delete_expired_key_packages(pool: &sqlx::AnyPool) {
+    match db::delete_expired_key_packages(pool).await {
+        Ok(0) => {}
+        Ok(deleted) => log::debug!("Deleted {} expired key packages", deleted),
+        Err(e) => log::error!("Couldn't delete the expired key packages: `{}`", e),
+    }
+}
+
+/// Delete the share links that expired, they can't be used anymore.
+async fn delete_expired_share_links(pool: &sqlx::AnyPool) {
+    match db::delete_expired_share_links(pool).await {
+        Ok(0) => {}
+        Ok(deleted) => log::debug!("Deleted {} expired share links", deleted),
+        Err(e) => log::error!("Couldn't delete the expired share links: `{}`", e),
+    }
+}
+
+/// Delete the pending messages older than `max_age` and notify the affected users.
+async fn expire_pending_messages(
+    max_age: Duration,
+    pool: &sqlx::AnyPool,
+    queue: &SenderSentEventQueue,
+) {
+    let expired = match db::expire_pending_messages(max_age, pool).await {
+        Ok(expired) => expired,
+        Err(e) => {
+            log::error!("Couldn't expire the pending messages: `{}`", e);
+            return;
+        }
+    };
+    for (user_email, folder_id) in expired {
+        log::debug!(
+            "Expired the pending messages of user `{}` in folder `{}`",
+            user_email,
+            folder_id
+        );
+        // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+        let _ = queue.send(Notification {
+            id: 0,
+            event: NotificationEvent::StateReset,
+            folder_id: Some(folder_id),
+            message_id: None,
+            receiver: user_email,
+        });
+    }
+}
+
+/// Permanently delete the files that are in the trash of their folder since more than `max_age`.
+async fn purge_trash(max_age: Duration, pool: &sqlx::AnyPool, store: &SyncStore) {
+    let folders = match db::list_all_folders(pool).await {
+        Ok(folders) => folders,
+        Err(e) => {
+            log::error!("Couldn't list the folders to purge their trash: `{}`", e);
+            return;
+        }
+    };
+    let deleted_before = storage::now_micros().saturating_sub(max_age.as_micros() as u64);
+    for folder in folders {
+        // Lock the store for each folder, so that the requests are not blocked for the whole purge.
+        let store = store.lock().await;
+        match storage::purge_trash(&store, &folder, deleted_before).await {
+            Ok(0) => {}
+            Ok(purged) => {
+                log::debug!(
+                    "Purged {} files from the trash of folder `{}`",
+                    purged,
+                    folder.folder_id
+                );
+                if let Err(e) = release_purged_blobs(&store, &folder, deleted_before, pool).await {
+                    log::error!(
+                        "Couldn't release the blobs purged from folder `{}`: `{}`",
+                        folder.folder_id,
+                        e
+                    );
+                }
+            }
+            Err(e) => log::error!(
+                "Couldn't purge the trash of folder `{}`: `{}`",
+                folder.folder_id,
+                e
+            ),
+        }
+    }
+}
+
+/// Drop the references to the blobs of the files purged from the trash of a folder.
+async fn release_purged_blobs(
+    store: &MutexGuard<'_, DynamicStore>,
+    folder: &FolderEntity,
+    deleted_before: u64,
+    pool: &sqlx::AnyPool,
+) -> Result<(), sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    for blob in db::purge_file_blobs(folder.folder_id, deleted_before, &mut conn).await? {
+        server::release_blob(store, folder, &blob, &mut conn).await;
+    }
+    Ok(())
+}
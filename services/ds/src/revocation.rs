@@ -0,0 +1,275 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use rocket::{
+    fairing::AdHoc,
+    tokio::{self, sync::Notify},
+};
+use x509_parser::{
+    certificate::X509Certificate, prelude::FromDer, revocation_list::CertificateRevocationList,
+};
+
+/// The PEM tag of a certificate revocation list.
+const CRL_PEM_TAG: &str = "X509 CRL";
+/// The PEM tag of a certificate.
+const CERTIFICATE_PEM_TAG: &str = "CERTIFICATE";
+
+/// The revocation configuration, loaded from the `revocation` table of the `DS_Rocket.toml` file.
+/// The client certificates are not checked against a revocation list if the table is missing.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RevocationConfig {
+    /// The path of the certificate revocation list (CRL), PEM or DER encoded.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The URL to fetch the certificate revocation list from, e.g. the one published by the PKI, instead of the path.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The path of the certificate of the CA signing the list, the CA of the client certificates if missing.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// How often to load the list again, in seconds.
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+fn default_refresh_interval() -> u64 {
+    5 * 60
+}
+
+/// The serial numbers of the revoked client certificates, to be used as managed state in Rocket.
+/// It is empty until the revocation list is loaded, or if the revocation is not configured.
+#[derive(Debug, Default, Clone)]
+pub struct RevocationList {
+    revoked: Arc<RwLock<HashSet<Vec<u8>>>>,
+    /// Wakes up the periodic loading of the list, to load it before the next interval.
+    refresh: Arc<Notify>,
+}
+
+impl RevocationList {
+    /// Whether the certificate with the serial number, as big endian bytes, is revoked.
+    pub fn is_revoked(&self, serial: &[u8]) -> bool {
+        self.revoked
+            .read()
+            .expect("Revocation list corrupted!")
+            .contains(serial)
+    }
+
+    /// Load the list again as soon as possible, e.g. when the PKI notifies a revocation.
+    /// The requests received while the list is loading are merged in a single reload.
+    pub fn refresh(&self) {
+        self.refresh.notify_one();
+    }
+
+    fn replace(&self, revoked: HashSet<Vec<u8>>) {
+        *self.revoked.write().expect("Revocation list corrupted!") = revoked;
+    }
+}
+
+/// Returns the DER content of `content`, decoding it if it is PEM encoded with the expected tag.
+fn der_content(content: &[u8], tag: &str) -> Result<Vec<u8>, String> {
+    if !content.trim_ascii_start().starts_with(b"-----BEGIN") {
+        return Ok(content.to_vec());
+    }
+    let pem = pem::parse(content).map_err(|e| e.to_string())?;
+    if pem.tag() != tag {
+        return Err(format!("Expected a `{}` PEM, found `{}`", tag, pem.tag()));
+    }
+    Ok(pem.into_contents())
+}
+
+/// Parse a certificate revocation list, checking that it is signed by the CA.
+/// Returns the serial numbers of the revoked certificates, as big endian bytes.
+fn parse_crl(content: &[u8], ca_cert: &[u8]) -> Result<HashSet<Vec<u8>>, String> {
+    let ca_cert = der_content(ca_cert, CERTIFICATE_PEM_TAG)?;
+    let (_, ca_cert) = X509Certificate::from_der(&ca_cert)
+        .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+    let crl = der_content(content, CRL_PEM_TAG)?;
+    let (_, crl) = CertificateRevocationList::from_der(&crl)
+        .map_err(|e| format!("Invalid revocation list: {}", e))?;
+    crl.verify_signature(ca_cert.public_key())
+        .map_err(|e| format!("The revocation list is not signed by the CA: {}", e))?;
+    if crl.next_update().is_some_and(|next_update| {
+        next_update.timestamp() < x509_parser::time::ASN1Time::now().timestamp()
+    }) {
+        log::warn!(
+            "The revocation list is past its next update, the PKI may not publish it anymore"
+        );
+    }
+    Ok(crl
+        .iter_revoked_certificates()
+        .map(|revoked| revoked.serial().to_bytes_be())
+        .collect())
+}
+
+/// Load the revocation list from the URL or the path of the configuration.
+async fn load(config: &RevocationConfig, client: &reqwest::Client) -> Result<Vec<u8>, String> {
+    match (&config.url, &config.path) {
+        (Some(url), _) => {
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            let content = response.bytes().await.map_err(|e| e.to_string())?;
+            Ok(content.to_vec())
+        }
+        (None, Some(path)) => tokio::fs::read(path).await.map_err(|e| e.to_string()),
+        (None, None) => {
+            Err("Either the path or the URL of the revocation list is required".to_string())
+        }
+    }
+}
+
+/// A fairing loading the revocation list periodically, if it is configured, or when a refresh is requested.
+/// The previous list is kept if it can't be loaded again.
+pub fn fairing(config: Option<RevocationConfig>) -> AdHoc {
+    AdHoc::on_liftoff("Certificate revocation", move |rocket| {
+        Box::pin(async move {
+            let Some(config) = config else {
+                log::info!("The client certificates are not checked against a revocation list");
+                return;
+            };
+            let Some(revocation_list) = rocket.state::<RevocationList>() else {
+                log::error!("The server state is not initialised, the client certificates won't be checked against the revocation list");
+                return;
+            };
+            let Some(ca_path) = &config.ca_cert else {
+                log::error!("The CA of the revocation list is not configured, the client certificates won't be checked against it");
+                return;
+            };
+            let ca_cert = match std::fs::read(ca_path) {
+                Ok(ca_cert) => ca_cert,
+                Err(e) => {
+                    log::error!("Couldn't read the CA certificate `{}`, the client certificates won't be checked against the revocation list: `{}`", ca_path, e);
+                    return;
+                }
+            };
+            let revocation_list = revocation_list.clone();
+            let client = reqwest::Client::new();
+            let mut shutdown = rocket.shutdown();
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(config.refresh_interval.max(1)));
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = revocation_list.refresh.notified() => {
+                            log::debug!("Loading the revocation list on request");
+                        }
+                        _ = &mut shutdown => break,
+                    }
+                    match load(&config, &client)
+                        .await
+                        .and_then(|crl| parse_crl(&crl, &ca_cert))
+                    {
+                        Ok(revoked) => {
+                            log::debug!(
+                                "Loaded the revocation list, `{}` certificates are revoked",
+                                revoked.len()
+                            );
+                            revocation_list.replace(revoked);
+                        }
+                        Err(e) => log::error!(
+                            "Couldn't load the revocation list, keeping the previous one: `{}`",
+                            e
+                        ),
+                    }
+                }
+            });
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, CertificateRevocationListParams, IsCa,
+        KeyIdMethod, KeyPair, KeyUsagePurpose, RevokedCertParams, SerialNumber,
+    };
+
+    use super::*;
+
+    fn ca() -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    fn crl(
+        ca: &rcgen::Certificate,
+        key: &KeyPair,
+        serials: &[u64],
+    ) -> rcgen::CertificateRevocationList {
+        CertificateRevocationListParams {
+            this_update: date_time_ymd(2024, 1, 1),
+            next_update: date_time_ymd(2124, 1, 1),
+            crl_number: SerialNumber::from(1u64),
+            issuing_distribution_point: None,
+            revoked_certs: serials
+                .iter()
+                .map(|serial| RevokedCertParams {
+                    serial_number: SerialNumber::from(*serial),
+                    revocation_time: date_time_ymd(2024, 1, 1),
+                    reason_code: None,
+                    invalidity_date: None,
+                })
+                .collect(),
+            key_identifier_method: KeyIdMethod::Sha256,
+        }
+        .signed_by(ca, key)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_crl() {
+        let (ca, key) = ca();
+        let crl = crl(&ca, &key, &[42, 4242]);
+        let revoked = parse_crl(crl.pem().unwrap().as_bytes(), ca.pem().as_bytes()).unwrap();
+        assert_eq!(
+            HashSet::from([vec![42u8], 4242u64.to_be_bytes()[6..].to_vec()]),
+            revoked
+        );
+        // DER encoded.
+        assert_eq!(revoked, parse_crl(crl.der(), ca.der()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_crl_of_another_ca() {
+        let (ca, _) = ca();
+        let (other_ca, other_key) = self::ca();
+        let crl = crl(&other_ca, &other_key, &[42]);
+        assert!(parse_crl(crl.der(), ca.der()).is_err());
+        assert!(parse_crl(crl.der(), other_ca.der()).is_ok());
+        // A certificate is not a revocation list.
+        assert!(parse_crl(ca.pem().as_bytes(), ca.der()).is_err());
+    }
+
+    #[test]
+    fn test_revocation_list() {
+        let revocation_list = RevocationList::default();
+        assert!(!revocation_list.is_revoked(&[42]));
+        revocation_list.replace(HashSet::from([vec![42u8]]));
+        assert!(revocation_list.is_revoked(&[42]));
+        assert!(!revocation_list.clone().is_revoked(&[43]));
+    }
+}
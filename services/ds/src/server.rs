@@ -11,76 +11,296 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use std::sync::Arc;
+use object_store::{multipart::PartId, GetResult};
+use std::{
+    collections::HashSet,
+    ops::Deref,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use rocket::tokio::select;
 use rocket::{
-    delete, form::Form, get, http::Status, mtls::{self, x509::GeneralName, Certificate}, outcome::try_outcome, patch, post, request::{FromRequest, Outcome}, response::{stream::{Event, EventStream}, Responder}, serde::json::Json, FromForm, Request, Shutdown, State
+    catch,
+    data::{ByteUnit, Data, Limits},
+    delete,
+    form::Form,
+    fs::TempFile,
+    futures::TryStreamExt,
+    get,
+    http::{ContentType, Status},
+    mtls::{self, x509::GeneralName, Certificate},
+    outcome::try_outcome,
+    patch, post, put,
+    request::{FromRequest, Outcome},
+    response::{
+        self,
+        stream::{Event, EventStream},
+        Responder,
+    },
+    serde::json::Json,
+    Config, FromForm, FromFormField, Request, Response, Shutdown, State,
 };
 use rocket_db_pools::Connection;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{
+    io::AsyncReadExt,
+    sync::{Mutex, MutexGuard},
+};
 use utoipa::{OpenApi, ToResponse, ToSchema};
-use rocket::tokio::sync::broadcast::{Sender, error::RecvError};
-use rocket::tokio::select;
 
-use crate::{db::{
-    self, consume_key_package, get_first_message_by_folder_and_user, get_folder_by_id, get_users_by_emails, insert_application_message, insert_folder_and_relation, insert_key_package, insert_message, insert_user, DbConn, FolderEntity, UserEntity
-}, storage::{self, DynamicStore, WriteInput}};
+pub use crate::db::{AuditAction, FolderRole};
+
+use crate::{
+    archive::FolderArchive,
+    bandwidth::{self, Bandwidth, ChunkReader, ThrottledReader, TokenBucket},
+    db::{
+        self, consume_key_package, get_first_message_by_folder_and_user, get_folder_by_id,
+        get_users_by_emails, insert_application_message, insert_folder_and_relation,
+        insert_key_package, insert_message, insert_user, DbConn, FolderEntity, ShareLinkEntity,
+        UserEntity,
+    },
+    db_retry::RetryConfig,
+    framing::{self, FramingConfig},
+    idempotency::Idempotent,
+    key_package,
+    limits::UploadLimits,
+    notifications::{LastEventId, NotificationQueue},
+    pki_verification::PkiVerifier,
+    quota::QuotaConfig,
+    reconciliation,
+    revocation::RevocationList,
+    session::{SessionClaims, Sessions},
+    storage::{
+        self, BatchFile, BatchWriteInput, CompleteUploadInput, DeleteInput, DynamicMultipartStore,
+        DynamicSigner, DynamicStore, RestoreInput, WriteInput,
+    },
+    web_push::{self, WebPush},
+    websocket::NotificationsWebSocket,
+};
 
 /// The syncronized store to be used as managed state in Rocket.
 /// This will protect
 pub type SyncStore = Arc<Mutex<DynamicStore>>;
 
-#[derive(Debug, Clone)]
+/// The store used for resumable uploads, to be used as managed state in Rocket.
+/// This is `None` when the object store doesn't support multipart uploads.
+pub type OptionalMultipartStore = Option<DynamicMultipartStore>;
+
+/// The signer for presigned URLs, to be used as managed state in Rocket.
+/// This is `None` when the object store doesn't support presigned URLs.
+pub type OptionalSigner = Option<DynamicSigner>;
+
+/// The Web Push sender, to be used as managed state in Rocket.
+/// This is `None` when Web Push is not configured.
+pub type OptionalWebPush = Option<Arc<WebPush>>;
+
+/// The verifier of the client certificates with the PKI, to be used as managed state in Rocket.
+/// This is `None` when the certificates are only verified against the local CA file.
+pub type OptionalPkiVerifier = Option<PkiVerifier>;
+
+/// The default limit for the size of a part of a resumable upload, if the `upload-part` limit is not configured.
+const DEFAULT_UPLOAD_PART_LIMIT: ByteUnit = ByteUnit::Mebibyte(64);
+/// The maximum number of parts of a resumable upload.
+const MAX_UPLOAD_PARTS: u32 = 10_000;
+
+/// The kind of event a [`Notification`] informs the client about.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    /// A new proposal (or its application message) is pending for the receiver in the folder.
+    Proposal,
+    /// The folder has been shared with the receiver.
+    Share,
+    /// A welcome message to join the group of the folder is pending for the receiver.
+    Welcome,
+    /// The receiver has been invited to join the folder, the invitation is pending until accepted or declined.
+    Invitation,
+    /// The admin role of the folder has been transferred to another member.
+    OwnershipTransferred,
+    /// The receiver has been removed from the folder, or left it from another client.
+    /// The client should discard the state of the group and the keys of the folder.
+    RemovedFromFolder,
+    /// A member left the folder, an admin should commit its removal from the group.
+    MemberLeft,
+    /// One of the key packages of the receiver has been consumed, a new one should be published.
+    KeyPackageConsumed,
+    /// A file of the folder has been uploaded, updated or restored from the trash.
+    FileUploaded,
+    /// A file of the folder has been deleted, i.e. moved to the trash.
+    FileDeleted,
+    /// The pending messages of the receiver in the folder expired, the client should rejoin the group
+    /// through a new welcome message or an external commit.
+    StateReset,
+    /// The server is shutting down and is closing the stream, the client should reconnect later.
+    ServerShutdown,
+}
+
+/// A notification pushed to the clients, serialised as JSON in both SSE and WebSocket transports.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
 pub struct Notification {
-    folder_id: Option<u64>,
-    receiver: String,
+    /// The id of the event, sent as the SSE `id` so that clients can resume the stream.
+    #[serde(skip)]
+    pub(crate) id: u64,
+    pub event: NotificationEvent,
+    pub folder_id: Option<u64>,
+    /// The id of the pending message the event refers to, if any.
+    pub message_id: Option<u64>,
+    #[serde(skip)]
+    pub(crate) receiver: String,
 }
-pub type SenderSentEventQueue = Sender::<Notification>;
+
+impl Notification {
+    /// The last notification sent to `receiver` before closing its stream on shutdown.
+    /// It is not recorded in the history, so it won't be replayed on reconnection.
+    pub(crate) fn server_shutdown(receiver: &str) -> Self {
+        Notification {
+            id: 0,
+            event: NotificationEvent::ServerShutdown,
+            folder_id: None,
+            message_id: None,
+            receiver: receiver.to_string(),
+        }
+    }
+}
+pub type SenderSentEventQueue = NotificationQueue;
 
 /// Documentation in OpenAPI format.
 #[derive(OpenApi)]
 #[openapi(
-    paths(openapi, 
-        create_user, 
-        create_folder, 
-        list_users, 
-        list_folders_for_user, 
-        share_folder, 
-        remove_self_from_folder, 
-        get_folder, 
+    paths(
+        openapi,
+        healthz,
+        readyz,
+        refresh_revocations,
+        reconcile,
+        create_user,
+        delete_self,
+        list_devices,
+        create_session,
+        get_vapid_public_key,
+        create_push_subscription,
+        delete_push_subscription,
+        create_folder,
+        list_users,
+        list_folders_for_user,
+        share_folder,
+        remove_self_from_folder,
+        remove_folder_member,
+        transfer_folder_ownership,
+        set_folder_name,
+        create_share_link,
+        list_share_links,
+        revoke_share_link,
+        delete_folder,
+        get_folder,
         upload_file,
+        upload_files,
         get_file,
+        delete_file,
+        start_file_upload,
+        get_file_upload,
+        upload_file_part,
+        complete_file_upload,
+        abort_file_upload,
+        presign_file,
+        list_files,
+        export_folder,
+        list_trash,
+        restore_file,
         get_metadata,
+        list_metadata_versions,
         post_metadata,
+        rollback_metadata,
         publish_key_package,
+        count_key_packages,
         fetch_key_package,
         try_publish_proposal,
         get_pending_proposal,
+        list_pending_proposals,
         try_publish_application_msg,
+        list_folder_members,
+        list_audit_events,
         v2_share_folder,
-        ack_message
+        v2_share_folder_welcome,
+        invite_to_folder,
+        list_invitations,
+        accept_invitation,
+        delete_invitation,
+        get_welcome,
+        ack_welcome,
+        ack_message,
+        ack_messages,
+        list_message_receipts
     ),
     components(schemas(
+        ErrorBody,
+        ReadinessResponse,
+        ReconciliationReport,
         CreateUserRequest,
+        Device,
+        ListDevicesResponse,
+        SessionResponse,
+        VapidPublicKeyResponse,
+        PushSubscriptionKeys,
+        PushSubscriptionRequest,
+        PushSubscriptionResponse,
         ListUsersResponse,
         ListFolderResponse,
+        FolderSummary,
+        FileEntry,
+        ListFilesResponse,
+        TrashedFile,
+        ListTrashResponse,
+        FileRestore,
         FolderResponse,
+        FolderRole,
+        ListFolderMembersResponse,
+        FolderMember,
+        AuditAction,
+        AuditEvent,
+        ListAuditEventsResponse,
+        MetadataVersion,
+        ListMetadataVersionsResponse,
         CreateFolderRequest,
         ShareFolderRequest,
+        TransferFolderRequest,
+        FolderNameUpload,
+        CreateShareLinkRequest,
+        ShareLinkResponse,
+        ShareLink,
+        ListShareLinksResponse,
         Upload,
         UploadFileResponse,
+        BatchUpload,
+        BatchUploadFile,
+        UploadFilesResponse,
         MetadataUpload,
+        MetadataRollback,
         FolderFileResponse,
         CreateKeyPackageRequest,
         FetchKeyPackageRequest,
         FetchKeyPackageResponse,
         CreateKeyPackageResponse,
+        KeyPackageCountResponse,
         ProposalMessageRequest,
         GroupMessage,
+        ListGroupMessagesResponse,
+        WelcomeMessage,
+        FileUploadResponse,
+        PresignOperation,
+        PresignedUrlResponse,
         ShareFolderRequestWithProposal,
+        InvitationRequest,
+        InvitationResponse,
+        Invitation,
+        ListInvitationsResponse,
         ApplicationMessageRequest,
-        ProposalResponse
+        ProposalResponse,
+        MessageReceipt,
+        ListMessageReceiptsResponse,
+        Notification,
+        NotificationEvent
     ))
 )]
 pub struct OpenApiDoc;
@@ -105,6 +325,129 @@ pub fn openapi() -> Json<utoipa::openapi::OpenApi> {
     Json(OpenApiDoc::openapi())
 }
 
+/// Liveness probe, the server process is up and handling requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The server is alive.")
+    )
+)]
+#[get("/healthz")]
+pub fn healthz() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe, the server can reach the database and the object store and its TLS credentials are loaded.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The server is ready to serve requests.", body = ReadinessResponse),
+        (status = 503, description = "Some of the dependencies of the server are not available.", body = ReadinessResponse),
+    )
+)]
+#[get("/readyz")]
+pub async fn readyz(
+    db: &DbConn,
+    store: &State<SyncStore>,
+    config: &Config,
+) -> SSFResponder<ReadinessResponse> {
+    let readiness = check_readiness(db, store, config).await;
+    if readiness.is_ready() {
+        SSFResponder::Ok(Json(readiness))
+    } else {
+        SSFResponder::ServiceUnavailable(Json(readiness))
+    }
+}
+
+/// Check the dependencies of the server, used by the readiness probe and during startup.
+pub async fn check_readiness(db: &DbConn, store: &SyncStore, config: &Config) -> ReadinessResponse {
+    let database = db::ping(db)
+        .await
+        .inspect_err(|e| log::error!("The database is not reachable: `{}`", e))
+        .is_ok();
+    let object_store = storage::ping(&store.lock().await)
+        .await
+        .inspect_err(|e| log::error!("The object store is not reachable: `{}`", e))
+        .is_ok();
+    ReadinessResponse {
+        database,
+        object_store,
+        tls: config.tls_enabled(),
+    }
+}
+
+/// Load the certificate revocation list again, without waiting for its periodic refresh.
+/// The PKI calls it on each revocation, so that the sessions of the revoked certificates are rejected right away.
+/// Anyone can trigger it, as the list is only trusted if signed by the CA.
+#[utoipa::path(
+    post,
+    path = "/revocations/refresh",
+    responses(
+        (status = 202, description = "The revocation list will be loaded again, if configured.")
+    )
+)]
+#[post("/revocations/refresh")]
+pub fn refresh_revocations(revocation_list: &State<RevocationList>) -> Status {
+    revocation_list.refresh();
+    Status::Accepted
+}
+
+/// Look for the inconsistencies between the folders of the database and the objects of the object store,
+/// optionally repairing them. Only the server admins can trigger it.
+#[utoipa::path(
+    post,
+    path = "/admin/reconcile",
+    params(
+        ("repair" = Option<bool>, Query, description = "Whether to repair the inconsistencies, they are only reported by default."),
+    ),
+    responses(
+        (status = 200, description = "The inconsistencies found, and the folders repaired.", body = ReconciliationReport),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the server admins can reconcile the storage."),
+        (status = 500, description = "Internal Server Error, couldn't scan the storage"),
+    )
+)]
+#[post("/admin/reconcile?<repair>")]
+pub async fn reconcile(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    pool: &DbConn,
+    repair: Option<bool>,
+    admins: &State<ServerAdmins>,
+    store: &State<SyncStore>,
+) -> SSFResponder<ReconciliationReport> {
+    log::debug!(
+        "Received client certificate to reconcile the storage, repair `{:?}`",
+        repair
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    if !admins.0.contains(&user_email) {
+        log::debug!("User `{}` is not a server admin", user_email);
+        return SSFResponder::Forbidden(ErrorBody::new(
+            "not_server_admin",
+            "Only the server admins can perform this operation.",
+        ));
+    }
+    // Release the connection, the scan uses the pool.
+    drop(db);
+    match reconciliation::reconcile(pool, store, repair.unwrap_or(false)).await {
+        Ok(report) => SSFResponder::Ok(Json(report)),
+        Err(e) => {
+            log::error!("{}", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
 /// The type of an empty response, to simplify development with the [`SSFResponder`].
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct EmptyResponse {}
@@ -115,17 +458,82 @@ pub struct CreateUserRequest {
     pub email: String,
 }
 
-/// Create a key package for a user.
+/// A device of the user, registered the first time the user authenticates with a client certificate.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct Device {
+    pub device_id: u64,
+    /// The hex encoded SHA-256 fingerprint of the client certificate of the device.
+    pub fingerprint: String,
+    /// The time of the first use of the device, in seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Whether the request has been sent from this device.
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ListDevicesResponse {
+    pub devices: Vec<Device>,
+}
+
+/// A new session token, to authenticate the requests to the data plane endpoints without a client certificate.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct SessionResponse {
+    /// The bearer token to send in the `Authorization` header.
+    pub token: String,
+    /// The expiration time, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// The public key identifying the server to the push services.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct VapidPublicKeyResponse {
+    /// The uncompressed P-256 public key, base64url encoded, to pass as `applicationServerKey` when subscribing.
+    pub public_key: String,
+}
+
+/// The keys of a Web Push subscription.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct PushSubscriptionKeys {
+    /// The P-256 public key of the client, base64url encoded.
+    pub p256dh: String,
+    /// The authentication secret of the client, base64url encoded.
+    pub auth: String,
+}
+
+/// A Web Push subscription, as returned by `PushSubscription.toJSON()` in the browsers.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct PushSubscriptionRequest {
+    /// The URL of the push service to send the messages to.
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct PushSubscriptionResponse {
+    /// The id of the subscription, to delete it.
+    pub subscription_id: u64,
+}
+
+/// Create one or more key packages for a user.
 #[derive(FromForm, ToSchema, Debug)]
 pub struct CreateKeyPackageRequest<'r> {
-    /// The metadata file to upload.
-    pub key_package: &'r [u8],
+    /// The key packages to upload, repeat the field to upload more than one.
+    pub key_package: Vec<&'r [u8]>,
 }
 
 #[derive(ToResponse, ToSchema, Serialize, Deserialize, Debug)]
 pub struct CreateKeyPackageResponse {
-    /// The id of the created key package.
+    /// The id of the first created key package.
     pub key_package_id: u64,
+    /// The ids of all the created key packages, in the same order as in the request.
+    pub key_package_ids: Vec<u64>,
+}
+
+/// The number of key packages of a user available on the server.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct KeyPackageCountResponse {
+    /// The number of key packages that have not been consumed yet.
+    pub count: u64,
 }
 
 /// Create the folder with the initial Metadata file.
@@ -178,10 +586,89 @@ pub struct GroupMessage {
     pub application_payload: Vec<u8>,
 }
 
+/// A page of the pending proposals of a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListGroupMessagesResponse {
+    /// The consumable proposals, in the order they need to be processed.
+    pub messages: Vec<GroupMessage>,
+    /// Whether more proposals are pending after these, either beyond the limit or not yet consumable.
+    pub has_more: bool,
+}
+
+/// A welcome message to join the group of a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct WelcomeMessage {
+    pub message_id: u64,
+    pub folder_id: u64,
+    pub payload: Vec<u8>,
+}
+
+/// The state of the dependencies of the server, it can serve requests only if all of them are available.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ReadinessResponse {
+    /// Whether the database is reachable.
+    pub database: bool,
+    /// Whether the object store is reachable.
+    pub object_store: bool,
+    /// Whether the TLS credentials are loaded.
+    pub tls: bool,
+}
+
+/// The inconsistencies found between the folders of the database and the objects of the object store.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default)]
+pub struct ReconciliationReport {
+    /// The folders deleted from the database whose objects are left in the object store.
+    pub orphan_folders: Vec<u64>,
+    /// The folders of the database without a metadata file in the object store.
+    pub missing_metadata: Vec<u64>,
+    /// The folders repaired: the objects of the orphan folders are deleted, the missing metadata files
+    /// are restored from their history.
+    pub repaired: Vec<u64>,
+}
+
+impl ReconciliationReport {
+    pub fn is_consistent(&self) -> bool {
+        self.orphan_folders.is_empty() && self.missing_metadata.is_empty()
+    }
+}
+
+/// The emails of the server admins, loaded from the `admins` list of the `DS_Rocket.toml` file.
+/// They can run the maintenance operations, e.g. the reconciliation of the storage.
+#[derive(Debug, Default)]
+pub struct ServerAdmins(pub HashSet<String>);
+
+/// The defaults of the folder endpoints, loaded from the `folders` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FoldersConfig {
+    /// Whether [`get_folder`] inlines the content of the metadata file when the request doesn't choose.
+    #[serde(default = "default_include_metadata")]
+    pub include_metadata: bool,
+}
+
+fn default_include_metadata() -> bool {
+    true
+}
+
+impl Default for FoldersConfig {
+    fn default() -> Self {
+        FoldersConfig {
+            include_metadata: default_include_metadata(),
+        }
+    }
+}
+
+impl ReadinessResponse {
+    pub fn is_ready(&self) -> bool {
+        self.database && self.object_store && self.tls
+    }
+}
+
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct ListUsersResponse {
     /// The emails of the users.
     pub emails: Vec<String>,
+    /// The cursor to request the next page of users, if there are more.
+    pub next_cursor: Option<String>,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
@@ -194,11 +681,126 @@ pub struct FolderResponse {
     pub version: Option<String>,
     // The optional content of the metadata file.
     pub metadata_content: Option<Vec<u8>>,
+    /// The role of the user in the folder.
+    pub role: Option<FolderRole>,
+    /// The display name of the folder encrypted by the clients, if set.
+    pub display_blob: Option<Vec<u8>>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListFolderMembersResponse {
+    /// The users that have access to the folder, ordered by email.
+    pub members: Vec<FolderMember>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListAuditEventsResponse {
+    /// The events, in chronological order.
+    pub events: Vec<AuditEvent>,
+    /// The cursor to request the next page of events, if there are more.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListMetadataVersionsResponse {
+    /// The versions, in chronological order.
+    pub versions: Vec<MetadataVersion>,
+    /// The cursor to request the next page of versions, if there are more.
+    pub next_cursor: Option<u64>,
+}
+
+/// A version of the metadata of a folder, kept in its history.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct MetadataVersion {
+    /// The identifier of the version.
+    pub id: u64,
+    /// The etag of the copy of the version.
+    pub etag: Option<String>,
+    /// The object store version of the copy of the version.
+    pub version: Option<String>,
+    /// The size of the (encrypted) metadata in bytes.
+    pub size: usize,
+    /// The time the version was written, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// An entry of the audit log of a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct AuditEvent {
+    pub id: u64,
+    /// The user that performed the action.
+    pub actor: String,
+    pub action: AuditAction,
+    /// The subject of the action, if any (e.g. the file or the user the folder has been shared with).
+    pub target: Option<String>,
+    /// The time of the event, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// A user that has access to a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct FolderMember {
+    pub email: String,
+    pub role: FolderRole,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
 pub struct ListFolderResponse {
     pub folders: Vec<u64>,
+    /// The cursor to request the next page of folders, if there are more.
+    pub next_cursor: Option<u64>,
+    /// The summaries of the folders, in the same order, if requested.
+    pub summaries: Option<Vec<FolderSummary>>,
+    /// The display names of the folders encrypted by the clients, in the same order, if set.
+    pub display_blobs: Vec<Option<Vec<u8>>>,
+}
+
+/// A summary of a folder, to render a folder list without fetching each folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct FolderSummary {
+    /// The id of the folder.
+    pub id: u64,
+    /// The etag of the latest metadata file.
+    pub etag: Option<String>,
+    /// The version of the latest metadata file.
+    pub version: Option<String>,
+    /// The number of users that have access to the folder.
+    pub member_count: u64,
+}
+
+/// A file stored in a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct FileEntry {
+    /// The file identifier.
+    pub file_id: String,
+    /// The size of the (encrypted) file in bytes.
+    pub size: usize,
+    /// The file etag.
+    pub etag: Option<String>,
+    /// The file version.
+    pub version: Option<String>,
+}
+
+/// A file in the trash of a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct TrashedFile {
+    /// The file identifier.
+    pub file_id: String,
+    /// The time the file was deleted, in microseconds since the Unix epoch, identifying it in the trash.
+    pub deleted_at: u64,
+    /// The size of the (encrypted) file in bytes.
+    pub size: usize,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListTrashResponse {
+    /// The files in the trash, ordered by file id and time of deletion.
+    pub files: Vec<TrashedFile>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListFilesResponse {
+    pub files: Vec<FileEntry>,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
@@ -207,6 +809,58 @@ pub struct ShareFolderRequest {
     pub emails: Vec<String>
 }
 
+/// The maximum size of the encrypted display name of a folder.
+const MAX_DISPLAY_BLOB_SIZE: usize = 1024;
+
+#[derive(FromForm, ToSchema, Debug)]
+pub struct FolderNameUpload<'r> {
+    /// The display name of the folder encrypted by the client, at most 1 KiB. An empty blob unsets it.
+    pub display_blob: &'r [u8],
+}
+
+/// The validity of a share link, if the client doesn't specify it.
+const DEFAULT_SHARE_LINK_VALIDITY: u64 = 7 * 24 * 60 * 60;
+/// The maximum validity of a share link.
+const MAX_SHARE_LINK_VALIDITY: u64 = 30 * 24 * 60 * 60;
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct CreateShareLinkRequest {
+    /// How long the link is valid, in seconds, 7 days by default and at most 30 days.
+    pub expires_in: Option<u64>,
+}
+
+/// A new share link, the token is only returned on creation.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ShareLinkResponse {
+    pub link_id: u64,
+    /// The bearer token to send in the `Authorization` header to read the folder.
+    pub token: String,
+    /// The expiration time, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// An active share link of a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ShareLink {
+    pub link_id: u64,
+    /// The member who created the link.
+    pub creator: String,
+    /// The expiration time, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListShareLinksResponse {
+    /// The active share links of the folder, ordered by id.
+    pub links: Vec<ShareLink>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct TransferFolderRequest {
+    /// The member that becomes the admin of the folder.
+    pub email: String,
+}
+
 #[derive(FromForm, ToSchema, Debug)]
 pub struct ShareFolderRequestWithProposal<'r> {
     /// The user to share the folder with.
@@ -215,6 +869,39 @@ pub struct ShareFolderRequestWithProposal<'r> {
     pub proposal: &'r [u8],
 }
 
+/// Invite a user to join a folder.
+#[derive(FromForm, ToSchema, Debug)]
+pub struct InvitationRequest<'r> {
+    /// The user to invite.
+    pub email: String,
+    /// The proposal adding the user to the group of the folder, published once the invitation is accepted.
+    pub proposal: Option<&'r [u8]>,
+    /// The welcome message for the user, published once the invitation is accepted.
+    pub welcome: Option<&'r [u8]>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct InvitationResponse {
+    pub invitation_id: u64,
+}
+
+/// A pending invitation to join a folder.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct Invitation {
+    pub id: u64,
+    pub folder_id: u64,
+    /// The user that sent the invitation.
+    pub inviter: String,
+    /// The time of the invitation, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListInvitationsResponse {
+    /// The pending invitations, oldest first.
+    pub invitations: Vec<Invitation>,
+}
+
 #[derive(FromForm, ToSchema, Debug)]
 pub struct MetadataUpload<'r> {
     /// The metadata file to upload.
@@ -225,17 +912,45 @@ pub struct MetadataUpload<'r> {
     pub parent_version: Option<String>,
 }
 
+#[derive(FromForm, ToSchema, Debug)]
+pub struct MetadataRollback {
+    /// The identifier of the version of the history to restore.
+    pub version_id: u64,
+    /// The current metadata etag, that is replaced by the restored version.
+    pub parent_etag: Option<String>,
+    /// The current metadata version, that is replaced by the restored version.
+    pub parent_version: Option<String>,
+}
+
+/// Restore a file from the trash of the folder.
+#[derive(FromForm, ToSchema, Debug)]
+pub struct FileRestore<'r> {
+    /// The time the file was deleted, as listed in the trash.
+    pub deleted_at: u64,
+    /// The metadata file including the restored file.
+    pub metadata: &'r [u8],
+    /// The previous metadata etag to which this file is related.
+    pub parent_etag: Option<String>,
+    /// The previous metadata version to which this file is related.
+    pub parent_version: Option<String>,
+}
+
 /// Upload a file to the server.
 #[derive(FromForm, ToSchema, Debug)]
 pub struct Upload<'r> {
-    /// The file to upload.
-    pub file: &'r [u8],
+    /// The file to upload. It is buffered on disk, so its size is bounded only by the `file` limit.
+    #[schema(value_type = String, format = Binary)]
+    pub file: TempFile<'r>,
     /// The metadata file to upload.
     pub metadata: &'r [u8],
     /// The previous metadata etag to which this file is related.
     pub parent_etag: Option<String>,
     /// The previous metadata version to which this file is related.
     pub parent_version: Option<String>,
+    /// The etag of the file that is replaced, if it already exists.
+    pub file_parent_etag: Option<String>,
+    /// The hex encoded SHA-256 of the file, checked before storing the file to detect a corrupted transfer.
+    pub content_hash: Option<String>,
 }
 
 /// When a file is uploaded successfully, an etag is returned with the latest version of the metadata file of the folder.
@@ -245,45 +960,227 @@ pub struct UploadFileResponse {
     pub etag: Option<String>,
     /// The metadata version. 
     pub version: Option<String>,
+    /// The hex encoded SHA-256 of the uploaded file, as received by the server.
+    #[serde(default)]
+    pub content_hash: Option<String>,
+}
+
+/// Upload several files to the server at once, together with the metadata including all of them.
+#[derive(FromForm, ToSchema, Debug)]
+pub struct BatchUpload<'r> {
+    /// The files to upload, with distinct ids.
+    pub files: Vec<BatchUploadFile<'r>>,
+    /// The metadata file to upload.
+    pub metadata: &'r [u8],
+    /// The previous metadata etag to which the files are related.
+    pub parent_etag: Option<String>,
+    /// The previous metadata version to which the files are related.
+    pub parent_version: Option<String>,
+}
+
+/// A file of a [`BatchUpload`], sent as the `files[<index>].<field>` fields of the form.
+#[derive(FromForm, ToSchema, Debug)]
+pub struct BatchUploadFile<'r> {
+    /// The file identifier.
+    pub file_id: String,
+    /// The file to upload.
+    #[schema(value_type = String, format = Binary)]
+    pub file: TempFile<'r>,
+    /// The etag of the file that is replaced, if it already exists.
+    pub file_parent_etag: Option<String>,
+    /// The hex encoded SHA-256 of the file, checked before storing the file to detect a corrupted transfer.
+    pub content_hash: Option<String>,
+}
+
+/// When the files are uploaded successfully, an etag is returned with the latest version of the metadata file of the folder.
+#[derive(ToSchema, Serialize, Debug, Deserialize)]
+pub struct UploadFilesResponse {
+    /// The metadata etag.
+    pub etag: Option<String>,
+    /// The metadata version.
+    pub version: Option<String>,
+    /// The hex encoded SHA-256 of the uploaded files, as received by the server, in the order of the request.
+    pub content_hashes: Vec<String>,
+}
+
+/// The operation allowed by a presigned URL.
+#[derive(FromFormField, ToSchema, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresignOperation {
+    /// Download the file.
+    Get,
+    /// Upload the file.
+    Put,
+}
+
+/// A presigned URL to transfer a file directly with the object store.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct PresignedUrlResponse {
+    /// The URL to use with the HTTP method of the requested operation.
+    pub url: String,
+    /// The number of seconds the URL is valid for.
+    pub expires_in: u64,
+}
+
+/// A resumable upload of a file, together with the parts already uploaded.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct FileUploadResponse {
+    /// The id of the upload, to be used to upload the parts.
+    pub upload_id: u64,
+    /// The numbers of the parts already uploaded, in increasing order.
+    pub parts: Vec<u32>,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
 pub struct FolderFileResponse {
     pub file: Vec<u8>,
     pub etag: Option<String>,
-    pub version: Option<String>
+    pub version: Option<String>,
+    /// The hex encoded SHA-256 of the file, to detect a corrupted transfer.
+    #[serde(default)]
+    pub content_hash: Option<String>,
 }
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
 pub struct ProposalResponse {
-    message_ids: Vec<u64>,
+    pub message_ids: Vec<u64>,
 }
 
-/// Custom responder.
-#[derive(Responder, Debug)]
-pub enum SSFResponder<R> {
-    #[response(status = 200, content_type = "json")]
-    Ok(Json<R>),
-    #[response(status = 200, content_type = "plain")]
-    EmptyOk(String),
-    #[response(status = 200)]
-    File(Vec<u8>),
+/// The delivery receipt of a group message for one of its receivers.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct MessageReceipt {
+    pub receiver: String,
+    /// The id of the copy of the message sent to the receiver.
+    pub message_id: u64,
+    /// The time the receiver acked the message, in seconds since the Unix epoch, missing until it does.
+    pub acked_at: Option<u64>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ListMessageReceiptsResponse {
+    pub sender: String,
+    pub receipts: Vec<MessageReceipt>,
+}
+
+/// The body of every error response, so that clients can branch on the `code` of the error.
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ErrorBody {
+    /// A stable identifier of the error, e.g. `folder_not_found`.
+    pub code: String,
+    /// A human readable description of the error.
+    pub message: String,
+    /// The number of seconds to wait before retrying the request, also sent in the `Retry-After` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Additional information about the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(code: &str, message: &str) -> Self {
+        ErrorBody {
+            code: code.to_string(),
+            message: message.to_string(),
+            retry_after: None,
+            details: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for ErrorBody {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let retry_after = self.retry_after;
+        let mut response = Json(self).respond_to(request)?;
+        if let Some(seconds) = retry_after {
+            response.set_raw_header("Retry-After", seconds.to_string());
+        }
+        Ok(response)
+    }
+}
+
+/// Custom responder.
+#[derive(Responder, Debug)]
+pub enum SSFResponder<R> {
+    #[response(status = 200, content_type = "json")]
+    Ok(Json<R>),
+    #[response(status = 200, content_type = "plain")]
+    EmptyOk(String),
+    #[response(status = 200)]
+    File(Vec<u8>),
+    #[response(status = 200)]
+    Stream(FileStream),
+    #[response(status = 200)]
+    Archive(FolderArchive),
     #[response(status = 201)]
     Created(Json<R>),
     #[response(status = 201, content_type = "plain")]
     EmptyCreated(String),
-    #[response(status = 400, content_type = "plain")]
-    BadRequest(String),
-    #[response(status = 401, content_type = "plain")]
-    Unauthorized(String),
-    #[response(status = 404, content_type = "plain")]
-    NotFound(String),
-    #[response(status = 429, content_type = "plain")]
-    RetryAfter(String),
-    #[response(status = 409, content_type = "plain")]
-    Conflict(String),
-    #[response(status = 500, content_type = "plain")]
-    InternalServerError(String),
+    #[response(status = 304)]
+    NotModified(()),
+    #[response(status = 400, content_type = "json")]
+    BadRequest(ErrorBody),
+    #[response(status = 401, content_type = "json")]
+    Unauthorized(ErrorBody),
+    #[response(status = 403, content_type = "json")]
+    Forbidden(ErrorBody),
+    #[response(status = 404, content_type = "json")]
+    NotFound(ErrorBody),
+    #[response(status = 429, content_type = "json")]
+    RetryAfter(ErrorBody),
+    #[response(status = 409, content_type = "json")]
+    Conflict(ErrorBody),
+    #[response(status = 412, content_type = "json")]
+    PreconditionFailed(ErrorBody),
+    #[response(status = 413, content_type = "json")]
+    PayloadTooLarge(ErrorBody),
+    #[response(status = 500, content_type = "json")]
+    InternalServerError(ErrorBody),
+    #[response(status = 501, content_type = "json")]
+    NotImplemented(ErrorBody),
+    #[response(status = 503, content_type = "json")]
+    ServiceUnavailable(Json<R>),
+    #[response(status = 507, content_type = "json")]
+    InsufficientStorage(ErrorBody),
+}
+
+/// A file streamed from the object store, without loading it in memory, throttled by the bucket of the user if any.
+/// The etag and version of the object are sent in the `ETag` and `X-Version` headers.
+#[derive(Debug)]
+pub struct FileStream(GetResult, Option<Arc<TokenBucket>>);
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for FileStream {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        let FileStream(result, bucket) = self;
+        let etag = result.meta.e_tag.clone();
+        let version = result.meta.version.clone();
+        let location = result.meta.location.clone();
+        // The status is already sent when the download fails, the error aborts the connection
+        // so that the client doesn't take the truncated body for the whole file.
+        let chunks = bandwidth::throttle(result.into_stream(), bucket).inspect_err(move |e| {
+            log::error!(
+                "Couldn't stream the file `{}` from the object store: `{}`",
+                location,
+                e
+            );
+        });
+        let mut response = Response::build();
+        response
+            .header(ContentType::Binary)
+            .streamed_body(ChunkReader::new(Box::pin(chunks)));
+        if let Some(etag) = etag {
+            response.raw_header("ETag", etag);
+        }
+        if let Some(version) = version {
+            response.raw_header("X-Version", version);
+        }
+        response.ok()
+    }
 }
 
 /// Create a new user checking that the client certificate contains the email that is used to create the account.
@@ -295,13 +1192,14 @@ pub enum SSFResponder<R> {
         (status = 201, description = "New account created."),
         (status = 400, description = "Bad request."),
         (status = 401, description = "Unauthorized user, please, set a valid client credential."),
+        (status = 403, description = "The account of this email has been deleted."),
         (status = 409, description = "Conflict.")
     )
 )]
 #[post("/users", format = "application/json", data = "<request>")]
 pub async fn create_user(
     client_certificate: CertificateWithEmails<'_>,
-    db: Connection<DbConn>,
+    mut db: Connection<DbConn>,
     request: Json<CreateUserRequest>,
 ) -> SSFResponder<EmptyResponse> {
     log::debug!(
@@ -310,8 +1208,27 @@ pub async fn create_user(
     );
     if !client_certificate.emails.contains(&request.email) {
         log::debug!("The client certificate is not containing the email to register as user");
-        return SSFResponder::BadRequest("The email you want to register with is not bound to the client certificate you authenticated with."
-            .to_string());
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "email_not_in_certificate",
+            "The email you want to register with is not bound to the client certificate you authenticated with.",
+        ));
+    }
+    match db::is_deleted_user(&request.email, &mut db).await {
+        Ok(false) => (),
+        Ok(true) => {
+            log::debug!("The account of user `{}` has been deleted", &request.email);
+            return SSFResponder::Forbidden(ErrorBody::new(
+                "account_deleted",
+                "The account of this email has been deleted",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't check the deleted users in the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
     }
     match insert_user(&request.email, db).await {
         Ok(_) => {
@@ -320,938 +1237,4910 @@ pub async fn create_user(
         }
         Err(e) => {
             log::debug!("Error inserting the user in the db: `{}`", e);
-            SSFResponder::Conflict("User already registered".to_string())
+            SSFResponder::Conflict(ErrorBody::new(
+                "user_already_registered",
+                "User already registered",
+            ))
         }
     }
 }
 
-/// List all the users.
+/// Delete the account of the authenticated user.
+/// The user leaves all its folders, the ones without other members are deleted together with their content.
+/// In the folders of which the user is the only admin, the first of the other members by email becomes its admin.
+/// The pending messages and key packages of the user are deleted, and its email can't be registered again.
 #[utoipa::path(
-    get,
-    path = "/users",
+    delete,
+    path = "/users/self",
     responses(
-        (status = 200, description = "List of users using the SSF.", body = ListUsersResponse),
+        (status = 200, description = "Account deleted."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 500, description = "Internal Server Error, couldn't delete the account"),
     )
 )]
-#[get("/users")]
-pub async fn list_users(
+#[delete("/users/self")]
+pub async fn delete_self(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-) -> SSFResponder<ListUsersResponse> {
+    store: &State<SyncStore>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<EmptyResponse> {
     log::debug!(
-        "Received client certificate to retrieve users, with emails `{:?}`",
-        &client_certificate.emails
+        "Received client certificate to delete the account with emails: {}.",
+        client_certificate.emails.join(","),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let users = db::list_users(db).await;
-    match users {
+    let user_email = known_user.unwrap().user_email;
+    match db::delete_user(&user_email, db).await {
+        Ok(folders) => {
+            let store = store.lock().await;
+            for (folder_id, members) in folders {
+                if !members.is_empty() {
+                    notify_members(
+                        NotificationEvent::MemberLeft,
+                        folder_id,
+                        Ok(members),
+                        &user_email,
+                        sse_queue,
+                    )
+                    .await;
+                    continue;
+                }
+                // Nobody can access the folder anymore, cleanup its content as well.
+                if let Err(e) = storage::delete_folder(&store, &FolderEntity { folder_id }).await {
+                    log::error!(
+                        "Couldn't delete the content of the removed folder `{}`: `{}`",
+                        folder_id,
+                        e
+                    );
+                }
+            }
+            log::info!("Deleted the account of user `{}`", user_email);
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
         Err(e) => {
-            log::error!("Couldn't retrieve the users from the DB: `{}`", e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+            log::error!(
+                "Couldn't delete the account of user `{}`: `{}`",
+                user_email,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
-        Ok(users) => SSFResponder::Ok(Json(ListUsersResponse {
-            emails: users.iter().map(|u| u.user_email.clone()).collect(),
-        })),
     }
 }
 
+/// List the devices of the authenticated user, each client certificate used by the user is a device.
 #[utoipa::path(
-    post,
-    request_body(content = CreateKeyPackageRequest, content_type = "multipart/form-data"),
-    path = "/users/keys",
+    get,
+    path = "/users/self/devices",
     responses(
-        (status = 201, description = "New key package created."),
+        (status = 200, description = "The devices of the user.", body = ListDevicesResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 500, description = "Internal Server Error")
+        (status = 500, description = "Internal Server Error, couldn't list the devices"),
     )
 )]
-#[post("/users/keys", data = "<request>")]
-pub async fn publish_key_package(
+#[get("/users/self/devices")]
+pub async fn list_devices(
     client_certificate: CertificateWithEmails<'_>,
-    request: Form<CreateKeyPackageRequest<'_>>,
     mut db: Connection<DbConn>,
-) ->  SSFResponder<CreateKeyPackageResponse> {
+) -> SSFResponder<ListDevicesResponse> {
     log::debug!(
-        "Received client certificate to publish a key package, user emails `{:?}`",
-        &client_certificate.emails,
+        "Received client certificate to list the devices with emails: {}.",
+        client_certificate.emails.join(","),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    match insert_key_package(&known_user.unwrap().user_email, request.key_package.to_vec(), db).await {
-        Ok(key_package_id) => {
-            SSFResponder::Created(Json(CreateKeyPackageResponse {
-                key_package_id
-            }))
-        },
-        Err(_) => {
-            SSFResponder::InternalServerError("Error occurred while trying to save the key package.".to_string())
+    let known_user = known_user.unwrap();
+    match db::list_devices(&known_user.user_email, &mut db).await {
+        Ok(devices) => SSFResponder::Ok(Json(ListDevicesResponse {
+            devices: devices
+                .into_iter()
+                .map(|device| Device {
+                    current: Some(device.device_id) == known_user.device_id,
+                    device_id: device.device_id,
+                    fingerprint: device.fingerprint,
+                    created_at: device.created_at,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!(
+                "Couldn't list the devices of user `{}`: `{}`",
+                known_user.user_email,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
     }
 }
 
+/// Create a short-lived session, once the client is authenticated with its certificate.
+/// The session token authenticates the requests to the data plane endpoints in place of the client certificate,
+/// e.g. from a browser, until it expires or the certificate it is bound to is revoked.
 #[utoipa::path(
     post,
-    params(
-        ("folder_id", description = "Folder id."),
-    ),
-    request_body = FetchKeyPackageRequest,
+    path = "/session",
     responses(
-        (status = 200, description = "Retrieved a key package.", body = FetchKeyPackageResponse),
+        (status = 201, description = "Session created.", body = SessionResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 500, description = "Internal Server Error")
     )
 )]
-#[post("/folders/<folder_id>/keys", data = "<request>")]
-pub async fn fetch_key_package(
+#[post("/session")]
+pub async fn create_session(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-    request: Json<FetchKeyPackageRequest>,
-    sse_queue: &State<SenderSentEventQueue>, 
-) -> SSFResponder<FetchKeyPackageResponse> {
+    sessions: &State<Sessions>,
+) -> SSFResponder<SessionResponse> {
     log::debug!(
-        "Received client certificate to retrieve a key package for `{:?}`, user emails `{:?}`",
-        &request.user_email,
-        &client_certificate.emails,
+        "Received client certificate to create a session with emails: {}.",
+        client_certificate.emails.join(","),
+    );
+    // The certificate is always present, the guard doesn't accept a session token.
+    let Some(cert) = client_certificate.cert.as_ref() else {
+        return SSFResponder::Unauthorized(ErrorBody::new(
+            "unknown_client",
+            "Client identity check failed, please check your TLS certificate.",
+        ));
+    };
+    let serial = cert.serial().to_bytes_be();
+    let (emails, fingerprint) = (
+        client_certificate.emails.clone(),
+        client_certificate.fingerprint.clone(),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
-    }
-    match consume_key_package(&request.user_email,  &known_user.unwrap().user_email, folder_id, db).await {
-        Ok(key_package_entity) => {
-            // Send a notification to inform the client to produce a new key package.
-            send_see(None, &request.user_email, sse_queue).await;
-            SSFResponder::Ok(Json(FetchKeyPackageResponse{
-                payload: key_package_entity.key_package
-            }))
-        }
-        Err(sqlx::Error::RowNotFound) => {
-            SSFResponder::NotFound("Key package not found, retry in some time.".to_string())
-        } 
-        Err(_) => {
-            SSFResponder::InternalServerError("Error while processing the query".to_string())
-        }
+        return unauthorized;
     }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let (token, expires_at) = sessions.issue(emails, &fingerprint, &serial, now);
+    log::debug!(
+        "User `{}` created a session expiring at `{}`",
+        known_user.unwrap().user_email,
+        expires_at
+    );
+    SSFResponder::Created(Json(SessionResponse { token, expires_at }))
 }
 
+/// Get the public key of the server, used by the clients to create their Web Push subscriptions.
 #[utoipa::path(
-    post,
-    params(
-        ("folder_id", description = "Folder id."),
-    ),
-    request_body(content = ProposalMessageRequest, content_type = "multipart/form-data"),
+    get,
+    path = "/push/vapid_public_key",
     responses(
-        (status = 200, description = "Create a proposal.", body = ProposalResponse),
+        (status = 200, description = "The VAPID public key of the server.", body = VapidPublicKeyResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 409, description = "Conflict: the user state is outdated, please fetch the pending proposals first."),
-        (status = 500, description = "Internal Server Error")
+        (status = 501, description = "Web Push is not configured."),
     )
 )]
-#[post("/folders/<folder_id>/proposals", data="<request>")]
-pub async fn try_publish_proposal(
+#[get("/push/vapid_public_key")]
+pub async fn get_vapid_public_key(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-    request: Form<ProposalMessageRequest<'_>>,
-    sse_queue: &State<SenderSentEventQueue>,     
-) -> SSFResponder<ProposalResponse> {
+    web_push: &State<OptionalWebPush>,
+) -> SSFResponder<VapidPublicKeyResponse> {
     log::debug!(
-        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`",
-        &folder_id,
-        &client_certificate.emails,
+        "Received client certificate to get the VAPID public key with emails: {}.",
+        client_certificate.emails.join(","),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
-    }
-    let email = &known_user.unwrap().user_email;
-    match db::insert_message(email, folder_id, request.proposal, &mut db).await {
-        Ok((receivers, message_ids)) => {
-            for email in &receivers {
-                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
-                send_see(Some(folder_id), email, sse_queue).await;
-            }
-            SSFResponder::Ok(Json(
-                ProposalResponse {
-                    message_ids
-                }
-            ))
-
-        }
-        Err(Ok(pending_msgs)) => {
-            log::debug!("Sending notification to fetch {pending_msgs} pending proposals to the user.");
-            // Used to indicate that the user has still pending proposals.
-            // for i in 0..pending_msgs {
-            send_see(Some(folder_id), email, sse_queue).await;
-            //}
-            SSFResponder::Conflict("Conflict: the user state is outdated, please fetch the pending proposals first.".to_string())
-
-        }
-        Err(Err(e)) => {
-            SSFResponder::InternalServerError("Error while trying to propose a change to the folder.".to_string())
-        }
+        return unauthorized;
     }
+    let Some(web_push) = web_push.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "web_push_unsupported",
+            "Web Push is not supported",
+        ));
+    };
+    SSFResponder::Ok(Json(VapidPublicKeyResponse {
+        public_key: web_push.public_key().to_string(),
+    }))
 }
 
-
+/// Register a Web Push subscription of the authenticated user.
+/// The proposals, shares, welcome messages and invitations targeting the user are pushed to its subscriptions
+/// when none of its clients is connected to the notification stream.
 #[utoipa::path(
-    patch,
-    params(
-        ("folder_id", description = "Folder id."),
-    ),
-    request_body(content = ApplicationMessageRequest, content_type = "multipart/form-data"),
+    post,
+    path = "/users/push_subscriptions",
+    request_body = PushSubscriptionRequest,
     responses(
-        (status = 200, description = "Added application message."),
+        (status = 201, description = "Subscription registered.", body = PushSubscriptionResponse),
+        (status = 400, description = "Invalid subscription."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error")
+        (status = 500, description = "Internal Server Error, couldn't register the subscription"),
+        (status = 501, description = "Web Push is not configured."),
     )
 )]
-#[patch("/folders/<folder_id>/proposals", data="<request>")]
-pub async fn try_publish_application_msg(
+#[post(
+    "/users/push_subscriptions",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn create_push_subscription(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-    request: Form<ApplicationMessageRequest<'_>>,
-    sse_queue: &State<SenderSentEventQueue>,     
-) -> SSFResponder<EmptyResponse> {
+    web_push: &State<OptionalWebPush>,
+    request: Json<PushSubscriptionRequest>,
+) -> SSFResponder<PushSubscriptionResponse> {
     log::debug!(
-        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`, `{:?}`",
-        &folder_id,
-        &client_certificate.emails,
-        &request,
+        "Received client certificate to register a push subscription with emails: {}.",
+        client_certificate.emails.join(","),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
+        return unauthorized;
     }
-    let email = &known_user.unwrap().user_email;
-    match insert_application_message(&request.message_ids, email, folder_id, request.payload, db).await {
-        Ok(receivers) => {
-            for email in &receivers {
-                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
-                send_see(Some(folder_id), email, sse_queue).await;
-            }
-            SSFResponder::EmptyCreated("Successful proposal.".to_string())
-        }
-        Err(sqlx::Error::RowNotFound) => {
-            log::debug!("The message to publish the application message for was not found.");
-            SSFResponder::NotFound("The message to publish the application message for was not found.".to_string())
+    if web_push.is_none() {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "web_push_unsupported",
+            "Web Push is not supported",
+        ));
+    }
+    if let Err(message) =
+        web_push::validate_subscription(&request.endpoint, &request.keys.p256dh, &request.keys.auth)
+    {
+        return SSFResponder::BadRequest(ErrorBody::new("invalid_push_subscription", message));
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::insert_push_subscription(
+        &user_email,
+        &request.endpoint,
+        &request.keys.p256dh,
+        &request.keys.auth,
+        &mut db,
+    )
+    .await
+    {
+        Ok(subscription_id) => {
+            log::info!(
+                "Registered the push subscription `{}` of user `{}`",
+                subscription_id,
+                user_email
+            );
+            SSFResponder::Created(Json(PushSubscriptionResponse { subscription_id }))
         }
         Err(e) => {
-            log::debug!("Error in publishing application message {:?}.", e);
-            SSFResponder::InternalServerError("Error while trying to propose a change to the folder.".to_string())
+            log::error!(
+                "Couldn't register the push subscription of user `{}`: `{}`",
+                user_email,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
     }
 }
 
-/* 
+/// Delete a Web Push subscription of the authenticated user.
 #[utoipa::path(
-    get,
+    delete,
+    path = "/users/push_subscriptions/{subscription_id}",
     params(
-        ("folder_id", description = "Folder id."),
+        ("subscription_id" = u64, Path, description = "The id of the subscription."),
     ),
     responses(
-        (status = 200, description = "Retrieved the eldest proposal.", body = GroupMessage),
+        (status = 200, description = "Subscription deleted."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error")
+        (status = 404, description = "Subscription not found."),
+        (status = 500, description = "Internal Server Error, couldn't delete the subscription"),
+        (status = 501, description = "Web Push is not configured."),
     )
 )]
-#[get("/folders/<folder_id>/welcomes")]
-pub async fn get_welcome(
+#[delete("/users/push_subscriptions/<subscription_id>")]
+pub async fn delete_push_subscription(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-) -> SSFResponder<GroupMessage> {
+    web_push: &State<OptionalWebPush>,
+    subscription_id: u64,
+) -> SSFResponder<EmptyResponse> {
     log::debug!(
-        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`",
-        &folder_id,
-        &client_certificate.emails,
+        "Received client certificate to delete the push subscription `{}` with emails: {}.",
+        subscription_id,
+        client_certificate.emails.join(","),
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
+        return unauthorized;
     }
-    let email = &known_user.unwrap().user_email;
-    match db::get_welcome_message_by_folder_and_user(folder_id, &email, db).await {
-        Ok(welcome_message) => {
-            SSFResponder::Ok(Json(GroupMessage {
-                message_id: welcome_message.message_id,
-                folder_id: welcome_message.folder_id,
-                payload: welcome_message.payload
-            }))
-        }
-        Err(sqlx::Error::RowNotFound) => {
-            SSFResponder::NotFound("No welcome message found.".to_string())
-        }
-        Err(_) => {
-            SSFResponder::InternalServerError("Internal server error".to_string())
+    if web_push.is_none() {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "web_push_unsupported",
+            "Web Push is not supported",
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::delete_push_subscription(&user_email, subscription_id, &mut db).await {
+        Ok(true) => SSFResponder::Ok(Json(EmptyResponse {})),
+        Ok(false) => SSFResponder::NotFound(ErrorBody::new(
+            "push_subscription_not_found",
+            "Push subscription not found",
+        )),
+        Err(e) => {
+            log::error!(
+                "Couldn't delete the push subscription of user `{}`: `{}`",
+                user_email,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
     }
 }
-    */
 
+/// The number of elements returned in a page when the client doesn't specify a limit.
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+/// The maximum number of elements returned in a page.
+const MAX_PAGE_LIMIT: u32 = 1000;
+
+/// Returns the page size to use given the limit requested by the client.
+fn page_limit(limit: Option<u32>) -> u32 {
+    limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+}
 
+/// List the users, one page at a time ordered by email.
 #[utoipa::path(
     get,
+    path = "/users",
     params(
-        ("folder_id", description = "Folder id."),
+        ("limit" = Option<u32>, Query, description = "The maximum number of users to return, 100 by default."),
+        ("cursor" = Option<String>, Query, description = "The `next_cursor` returned with the previous page."),
+        ("prefix" = Option<String>, Query, description = "Only return the users whose email starts with the prefix."),
     ),
     responses(
-        (status = 200, description = "Retrieved the eldest proposal.", body = GroupMessage),
+        (status = 200, description = "List of users using the SSF.", body = ListUsersResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 429, description = "Too many requests."),
-        (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error")
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
     )
 )]
-#[get("/folders/<folder_id>/proposals")]
-pub async fn get_pending_proposal(
+#[get("/users?<limit>&<cursor>&<prefix>")]
+pub async fn list_users(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-) -> SSFResponder<GroupMessage> {
+    limit: Option<u32>,
+    cursor: Option<&str>,
+    prefix: Option<&str>,
+) -> SSFResponder<ListUsersResponse> {
     log::debug!(
-        "Received client certificate to get pending proposals for folder `{:?}`, user emails `{:?}`",
-        &folder_id,
-        &client_certificate.emails,
+        "Received client certificate to retrieve users, with emails `{:?}`",
+        &client_certificate.emails
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
+        return unauthorized;
     }
-    let email = &known_user.unwrap().user_email;
-    match get_first_message_by_folder_and_user(folder_id, &email, db).await {
-        Ok(Some(pending_proposal)) => {
-            SSFResponder::Ok(Json(GroupMessage {
-                message_id: pending_proposal.message_id,
-                folder_id: pending_proposal.folder_id,
-                payload: pending_proposal.payload,
-                application_payload: pending_proposal.application_payload
-            }))
-        }
-        Ok(None) => {
-            SSFResponder::RetryAfter("The first pending proposal is still not consumable, retry after.".to_string())
-        }
-        Err(sqlx::Error::RowNotFound) => {
-            SSFResponder::NotFound("No more pending proposals found.".to_string())
+    let limit = page_limit(limit);
+    // Fetch one more user to know if there is a next page.
+    let users = db::list_users(prefix, cursor, limit + 1, db).await;
+    match users {
+        Err(e) => {
+            log::error!("Couldn't retrieve the users from the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
-        Err(_) => {
-            SSFResponder::InternalServerError("Internal server error".to_string())
+        Ok(users) => {
+            let mut emails: Vec<String> = users.into_iter().map(|u| u.user_email).collect();
+            let next_cursor = if emails.len() > limit as usize {
+                emails.truncate(limit as usize);
+                emails.last().cloned()
+            } else {
+                None
+            };
+            SSFResponder::Ok(Json(ListUsersResponse {
+                emails,
+                next_cursor,
+            }))
         }
     }
 }
 
-/* 
-/// Delete a welcome message.
 #[utoipa::path(
-    delete,
-    params(
-        ("folder_id", description="The folder id."),
-        ("message_id", description="The welcome message to delete.")
-    ),
+    post,
+    request_body(content = CreateKeyPackageRequest, content_type = "multipart/form-data"),
+    path = "/users/keys",
     responses(
-        (status = 200, description = "Welcome message removed from the db."),
+        (status = 201, description = "New key packages created.", body = CreateKeyPackageResponse),
+        (status = 400, description = "Bad request: no key package provided or a key package already expired."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error, couldn't delete the message"),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[delete("/folders/<folder_id>/welcomes/<message_id>")]
-pub async fn ack_welcome(
+#[post("/users/keys", data = "<request>")]
+pub async fn publish_key_package(
     client_certificate: CertificateWithEmails<'_>,
+    request: Form<CreateKeyPackageRequest<'_>>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-    message_id: u64,
-) -> SSFResponder<EmptyResponse> {
+) ->  SSFResponder<CreateKeyPackageResponse> {
     log::debug!(
-        "Received client certificate to ack a welcome message for folder `{:?}`, user emails `{:?}`",
-        &folder_id,
+        "Received client certificate to publish a key package, user emails `{:?}`",
         &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
+        return unauthorized;
     }
-    let email = &known_user.unwrap().user_email;
-    match db::delete_welcome(message_id, email, folder_id, db).await {
-        Ok(_) => SSFResponder::EmptyOk("Message deleted".to_string()),
-        Err(sqlx::Error::RowNotFound) => {
-            log::error!("Error while trying to remove the message with id {message_id} from folder {folder_id}");
-            SSFResponder::NotFound("Couldn't fine the message".to_string())
+    if request.key_package.is_empty() {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "missing_key_packages",
+            "At least one key package is required.",
+        ));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    // The key packages which can't be parsed are stored without expiry, as the DS treats them as opaque.
+    let mut key_packages = Vec::with_capacity(request.key_package.len());
+    for key_package in &request.key_package {
+        let not_after = key_package::lifetime(key_package).map(|lifetime| lifetime.not_after);
+        if not_after.is_some_and(|not_after| not_after <= now) {
+            return SSFResponder::BadRequest(ErrorBody::new(
+                "key_package_expired",
+                "The key package already expired.",
+            ));
         }
-        Err(_) => SSFResponder::InternalServerError("Internal error while trying to delete message".to_string())
+        key_packages.push((*key_package, not_after));
+    }
+    match insert_key_package(&known_user.unwrap().user_email, &key_packages, db).await {
+        Ok(key_package_ids) => SSFResponder::Created(Json(CreateKeyPackageResponse {
+            key_package_id: key_package_ids[0],
+            key_package_ids,
+        })),
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Error occurred while trying to save the key package.",
+        )),
     }
 }
-    */
 
-
-/// Delete a proposal message.
+/// Count the key packages of the user which have not been consumed yet,
+/// so that the client can publish new ones before running out of them.
 #[utoipa::path(
-    delete,
-    params(
-        ("folder_id", description="The folder id."),
-        ("message_id", description="The message to delete.")
-    ),
+    get,
+    path = "/users/keys/count",
     responses(
-        (status = 200, description = "Message removed from the queue."),
+        (status = 200, description = "The number of available key packages.", body = KeyPackageCountResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error, couldn't delete the message"),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[delete("/folders/<folder_id>/proposals/<message_id>")]
-pub async fn ack_message(
+#[get("/users/keys/count")]
+pub async fn count_key_packages(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    folder_id: u64,
-    message_id: u64,
-) -> SSFResponder<EmptyResponse> {
+) -> SSFResponder<KeyPackageCountResponse> {
     log::debug!(
-        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`",
-        &folder_id,
+        "Received client certificate to count the key packages, user emails `{:?}`",
         &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
-        return unauthorized
+        return unauthorized;
     }
-    let email = &known_user.unwrap().user_email;
-    match db::delete_message(message_id, email, folder_id, db).await {
-        Ok(true) => SSFResponder::EmptyOk("Message deleted".to_string()),
-        Ok(false) => SSFResponder::BadRequest("There are older messages to be acked first.".to_string()),
-        Err(sqlx::Error::RowNotFound) => {
-            log::error!("Error while trying to remove the message with id {message_id} from folder {folder_id}");
-            SSFResponder::NotFound("Couldn't fine the message".to_string())
+    match db::count_key_packages(&known_user.unwrap().user_email, db).await {
+        Ok(count) => SSFResponder::Ok(Json(KeyPackageCountResponse {
+            count: count as u64,
+        })),
+        Err(e) => {
+            log::error!("Couldn't count the key packages: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Error occurred while trying to count the key packages.",
+            ))
         }
-        Err(_) => SSFResponder::InternalServerError("Internal error while trying to delete message".to_string())
-        
     }
 }
 
-
-/// Create a new folder and link it to the user.
 #[utoipa::path(
     post,
-    request_body(content = CreateFolderRequest, content_type = "multipart/form-data"),
-    path = "/folders",
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body = FetchKeyPackageRequest,
     responses(
-        (status = 201, description = "New folder created.", body = FolderResponse),
+        (status = 200, description = "Retrieved a key package.", body = FetchKeyPackageResponse),
         (status = 401, description = "Unkwown or unauthorized user."),
         (status = 500, description = "Internal Server Error")
     )
 )]
-#[post("/folders", data = "<request>")]
-pub async fn create_folder(
+#[post("/folders/<folder_id>/keys", data = "<request>")]
+pub async fn fetch_key_package(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
-    store: &State<SyncStore>,
-    request: Form<CreateFolderRequest<'_>>,
-) -> SSFResponder<FolderResponse> {
+    folder_id: u64,
+    request: Json<FetchKeyPackageRequest>,
+    sse_queue: &State<SenderSentEventQueue>,
+    retry: &State<RetryConfig>,
+) -> SSFResponder<FetchKeyPackageResponse> {
     log::debug!(
-        "Received client certificate to create a folder, user emails `{:?}`",
+        "Received client certificate to retrieve a key package for `{:?}`, user emails `{:?}`",
+        &request.user_email,
         &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    match insert_folder_and_relation(&known_user.unwrap().user_email, db).await {
-        Ok(result) => {
-            log::debug!("Created folder with id `{}`, proceed creating the empty metadata file.", result);
-            let store = store.lock().await;
-            let metadata = storage::init_metadata(&store, FolderEntity {
-                folder_id: result,
-            }, request.metadata.to_vec()).await;
-            if let Ok((etag, version)) = metadata {
-                return SSFResponder::Created(Json(FolderResponse { id: result, etag, version, metadata_content: None }));
-            } else {
-                log::error!("Couldn't create the metadata file for the folder `{}`", result);
-                return SSFResponder::InternalServerError("Internal Server Error".to_string());
-            }
-        },
-        Err(e) => {
-            log::error!("Couldn't create a new folder: `{}", e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+    match consume_key_package(
+        &request.user_email,
+        &known_user.unwrap().user_email,
+        folder_id,
+        retry,
+        &mut db,
+    )
+    .await
+    {
+        Ok(key_package_entity) => {
+            // Send a notification to inform the client to produce a new key package.
+            send_see(
+                NotificationEvent::KeyPackageConsumed,
+                None,
+                None,
+                &request.user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(FetchKeyPackageResponse {
+                payload: key_package_entity.key_package,
+            }))
         }
+        Err(sqlx::Error::RowNotFound) => SSFResponder::NotFound(ErrorBody::new(
+            "key_package_not_found",
+            "Key package not found, retry in some time.",
+        )),
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Error while processing the query",
+        )),
     }
 }
 
-/// List all the folders in which the user participates.
 #[utoipa::path(
-    get,
-    path = "/folders",
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key chosen by the client, the retries with the same key get the outcome of the first request."),
+    ),
+    request_body(content = ProposalMessageRequest, content_type = "multipart/form-data"),
     responses(
-        (status = 200, description = "List of folders.", body = ListFolderResponse),
+        (status = 200, description = "Create a proposal.", body = ProposalResponse),
+        (status = 400, description = "The proposal is not an MLS message of the groups of the folder, if the framing is validated."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 404, description = "Folder not found, if the framing is validated."),
+        (status = 409, description = "Conflict: the user state is outdated, please fetch the pending proposals first, or the epoch of the proposal is older than the latest one."),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[get("/folders")]
-pub async fn list_folders_for_user(
-    client_certificate: CertificateWithEmails<'_>,
+#[post("/folders/<folder_id>/proposals", data = "<request>")]
+pub async fn try_publish_proposal(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
-) -> SSFResponder<ListFolderResponse> {
+    folder_id: u64,
+    request: Idempotent<Form<ProposalMessageRequest<'_>>>,
+    sse_queue: &State<SenderSentEventQueue>,
+    framing_config: &State<FramingConfig>,
+    retry: &State<RetryConfig>,
+) -> SSFResponder<ProposalResponse> {
     log::debug!(
-        "Received client certificate to retrieve folders, with emails `{:?}`",
-        &client_certificate.emails
+        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let folders = db::list_folders(&known_user.unwrap().user_email, db).await;
-    match folders {
-        Err(e) => {
-            log::error!("Couldn't retrieve the folders from the DB: `{}`", e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+    let email = &known_user.unwrap().user_email;
+    // Reject the garbage early, instead of letting it poison the queue of every member.
+    let framing = if framing_config.validate_proposals {
+        let framing = match framing::validate_proposal(request.proposal, folder_id) {
+            Ok(framing) => framing,
+            Err(message) => {
+                return SSFResponder::BadRequest(ErrorBody::new("invalid_mls_message", message))
+            }
+        };
+        match db::get_folder_role(email, folder_id, &mut db).await {
+            Ok(_) => (),
+            Err(sqlx::Error::RowNotFound) => {
+                return SSFResponder::NotFound(ErrorBody::new(
+                    "folder_not_found",
+                    "Folder not found",
+                ))
+            }
+            Err(e) => {
+                log::error!(
+                    "Couldn't check the membership of `{}` in folder `{}`: `{}`",
+                    email,
+                    folder_id,
+                    e
+                );
+                return SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ));
+            }
         }
-        Ok(folders) => SSFResponder::Ok(Json(ListFolderResponse {
-            folders: folders
+        match db::get_group_epoch(folder_id, &framing.group_id, &mut db).await {
+            Ok(latest) if framing.epoch < latest => {
+                log::debug!(
+                    "Rejecting a proposal of epoch `{}` in group `{}`, the latest is `{}`",
+                    framing.epoch,
+                    framing.group_id,
+                    latest
+                );
+                return SSFResponder::Conflict(ErrorBody::new("stale_epoch", "The epoch of the proposal is older than the latest one of the group, please fetch the pending proposals first."));
+            }
+            Ok(_) => Some(framing),
+            Err(e) => {
+                log::error!(
+                    "Couldn't get the epoch of group `{}`: `{}`",
+                    framing.group_id,
+                    e
+                );
+                return SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ));
+            }
+        }
+    } else {
+        None
+    };
+    match db::insert_message(email, folder_id, request.proposal, retry, &mut db).await {
+        Ok((receivers, message_ids)) => {
+            if let Some(framing) = framing {
+                if let Err(e) =
+                    db::record_group_epoch(folder_id, &framing.group_id, framing.epoch, &mut db)
+                        .await
+                {
+                    log::error!(
+                        "Couldn't record the epoch of group `{}`: `{}`",
+                        framing.group_id,
+                        e
+                    );
+                }
+            }
+            // The pending messages are created for all the receivers but the sender, in the same order.
+            let receivers_message_ids = receivers
                 .iter()
-                .map(|f| f.folder_id)
-                .collect(),
-        })),
+                .filter(|receiver| *receiver != email)
+                .zip(message_ids.iter());
+            for (receiver, message_id) in receivers_message_ids {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(
+                    NotificationEvent::Proposal,
+                    Some(folder_id),
+                    Some(*message_id),
+                    receiver,
+                    sse_queue,
+                )
+                .await;
+            }
+            SSFResponder::Ok(Json(ProposalResponse { message_ids }))
+        }
+        Err(Ok(pending_msgs)) => {
+            log::debug!(
+                "Sending notification to fetch {pending_msgs} pending proposals to the user."
+            );
+            // Used to indicate that the user has still pending proposals.
+            // for i in 0..pending_msgs {
+            send_see(
+                NotificationEvent::Proposal,
+                Some(folder_id),
+                None,
+                email,
+                sse_queue,
+            )
+            .await;
+            //}
+            SSFResponder::Conflict(ErrorBody::new(
+                "state_outdated",
+                "Conflict: the user state is outdated, please fetch the pending proposals first.",
+            ))
+        }
+        Err(Err(e)) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Error while trying to propose a change to the folder.",
+        )),
     }
 }
 
-/// List all the users.
 #[utoipa::path(
-    get,
+    patch,
     params(
         ("folder_id", description = "Folder id."),
     ),
+    request_body(content = ApplicationMessageRequest, content_type = "multipart/form-data"),
     responses(
-        (status = 200, description = "The requested folder.", body = FolderResponse),
+        (status = 200, description = "Added application message."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Folder not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 404, description = "Not found."),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[get("/folders/<folder_id>")]
-pub async fn get_folder(
-    client_certificate: CertificateWithEmails<'_>,
+#[patch("/folders/<folder_id>/proposals", data = "<request>")]
+pub async fn try_publish_application_msg(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
-    store: &State<SyncStore>,
-) -> SSFResponder<FolderResponse> {
+    request: Form<ApplicationMessageRequest<'_>>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<EmptyResponse> {
     log::debug!(
-        "Received client certificate to retrieve folder with id `{}`",
-        folder_id
+        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`, `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
+        &request,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let folder = get_folder_by_id(&known_user.unwrap().user_email, folder_id, db).await;
-    match folder {
-        Ok(folder) => {
-            let store = store.lock().await;
-            let metadata = storage::read_metadata(&store, &folder).await;
-            if let Ok((content, obj_meta)) = metadata {
-                return SSFResponder::Ok(Json(FolderResponse {
-                    etag: obj_meta.e_tag,
-                    version: obj_meta.version,
-                    id: folder.folder_id,
-                    metadata_content: Some(content),
-                }));
-            } else {
-                log::error!("Couldn't retrieve the metadata from the object store");
-                return SSFResponder::InternalServerError("Internal Server Error".to_string());
+    let email = &known_user.unwrap().user_email;
+    match insert_application_message(&request.message_ids, email, folder_id, request.payload, db)
+        .await
+    {
+        Ok(receivers) => {
+            for email in &receivers {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(
+                    NotificationEvent::Proposal,
+                    Some(folder_id),
+                    None,
+                    email,
+                    sse_queue,
+                )
+                .await;
             }
-        },
+            SSFResponder::EmptyCreated("Successful proposal.".to_string())
+        }
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found", folder_id);
-            SSFResponder::NotFound("Folder not found".to_string())
+            log::debug!("The message to publish the application message for was not found.");
+            SSFResponder::NotFound(ErrorBody::new(
+                "message_not_found",
+                "The message to publish the application message for was not found.",
+            ))
         }
         Err(e) => {
-            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+            log::debug!("Error in publishing application message {:?}.", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Error while trying to propose a change to the folder.",
+            ))
         }
     }
 }
 
-/// Share a folder with other users.
-/// If some of the users already can see the folder, they will be ignored.
+/// Get the eldest welcome message of the user for a folder, needed to join the group of the folder.
 #[utoipa::path(
-    patch, 
+    get,
     params(
         ("folder_id", description = "Folder id."),
     ),
-    request_body = ShareFolderRequest,
     responses(
-        (status = 200, description = "Folder shared."),
+        (status = 200, description = "Retrieved the eldest welcome message.", body = WelcomeMessage),
         (status = 401, description = "Unkwown or unauthorized user."),
         (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[patch("/folders/<folder_id>", data = "<request>")]
-pub async fn share_folder(
-    client_certificate: CertificateWithEmails<'_>,
+#[get("/folders/<folder_id>/welcomes")]
+pub async fn get_welcome(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
-    sse_queue: &State<SenderSentEventQueue>, 
     folder_id: u64,
-    mut request: Json<ShareFolderRequest>,
-) -> SSFResponder<EmptyResponse> {
+) -> SSFResponder<WelcomeMessage> {
     log::debug!(
-        "Received client certificate to share folder with id `{}`",
-        folder_id
+        "Received client certificate to get a welcome message for folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let owner_email = known_user.unwrap().user_email;
-    request.emails.push(owner_email.clone());
-    let emails = request.emails.iter().map(AsRef::as_ref).collect();
-    let result = db::insert_folder_users_relations(folder_id, &owner_email, emails, None, db).await;
-    match result {
-        Ok(_) => {
-            log::debug!("Should send a notification to all receivers of the folder {:?}", &request.emails);
-            // This is only for the baseline, for GRaPPA is redundant. use v2 instead.
-            for email in &request.emails {
-                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
-                send_see(Some(folder_id), email, sse_queue).await;
-            }
-            SSFResponder::Ok(Json(EmptyResponse {}))
-        },
-        Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found", folder_id);
-            SSFResponder::NotFound("Folder not found".to_string())
-        }
-        Err(e) => {
-            log::error!("Couldn't share the folder with id `{}`: `{}`", folder_id, e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
-        }
+    let email = &known_user.unwrap().user_email;
+    match db::get_welcome_message_by_folder_and_user(folder_id, email, db).await {
+        Ok(welcome_message) => SSFResponder::Ok(Json(WelcomeMessage {
+            message_id: welcome_message.message_id,
+            folder_id: welcome_message.folder_id,
+            payload: welcome_message.payload,
+        })),
+        Err(sqlx::Error::RowNotFound) => SSFResponder::NotFound(ErrorBody::new(
+            "welcome_not_found",
+            "No welcome message found.",
+        )),
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Internal server error",
+        )),
     }
 }
 
-
-/// Share a folder with another user.
+/// Get the eldest pending proposal of the user for a folder.
+/// With `after`, the eldest one after the given message is returned instead, so that the client can fetch the
+/// next proposal while the previous ones are still processed, before acking them.
 #[utoipa::path(
-    patch, 
+    get,
     params(
         ("folder_id", description = "Folder id."),
+        ("after" = Option<u64>, Query, description = "The last message already fetched, the eldest pending proposal is returned if missing."),
     ),
-    request_body(content = ShareFolderRequestWithProposal, content_type = "multipart/form-data"),
     responses(
-        (status = 200, description = "Folder shared.", body = ProposalResponse),
+        (status = 200, description = "Retrieved the eldest proposal.", body = GroupMessage),
         (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 429, description = "Too many requests."),
         (status = 404, description = "Not found."),
-        (status = 409, description = "Conflict: client status out of sync."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 500, description = "Internal Server Error")
     )
 )]
-#[patch("/v2/folders/<folder_id>", data = "<request>")]
-pub async fn v2_share_folder(
-    client_certificate: CertificateWithEmails<'_>,
+#[get("/folders/<folder_id>/proposals?<after>")]
+pub async fn get_pending_proposal(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
-    sse_queue: &State<SenderSentEventQueue>, 
     folder_id: u64,
-    request: Form<ShareFolderRequestWithProposal<'_>>,
-) -> SSFResponder<ProposalResponse> {
+    after: Option<u64>,
+) -> SSFResponder<GroupMessage> {
     log::debug!(
-        "Received client certificate to share folder with id `{}`",
-        folder_id
+        "Received client certificate to get pending proposals for folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let owner = known_user.unwrap().user_email;
-    let emails = vec![request.email.as_str(), owner.as_str()];
-    let result = db::insert_folder_users_relations(folder_id, &owner, emails, Some(request.proposal), db).await;
-    match result {
-        Ok((users, Some(message_ids))) if users.len() > 0 => {
-            log::debug!("Should send a notification to the all the receivers of the proposal.");
-            for user in users {
-                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
-                send_see(Some(folder_id), &user, sse_queue).await;
-            }
-            SSFResponder::Ok(Json(ProposalResponse {
-                message_ids
-            }))
-        },
-        Ok(_) => {
-            log::debug!("The sender {owner} is not in sync with pending messages!");
-            SSFResponder::Conflict("Not in sync, please first process the proposals that are pending!.".to_string())
-        },
-        Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found", folder_id);
-            SSFResponder::NotFound("Folder not found".to_string())
-        },
-        Err(e) => {
-            log::error!("Couldn't share the folder with id `{}`: `{}`", folder_id, e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
-        }
+    let email = &known_user.unwrap().user_email;
+    match get_first_message_by_folder_and_user(folder_id, &email, after, db).await {
+        Ok(Some(pending_proposal)) => SSFResponder::Ok(Json(GroupMessage {
+            message_id: pending_proposal.message_id,
+            folder_id: pending_proposal.folder_id,
+            payload: pending_proposal.payload,
+            application_payload: pending_proposal.application_payload,
+        })),
+        Ok(None) => SSFResponder::RetryAfter(ErrorBody::new(
+            "proposal_not_consumable",
+            "The first pending proposal is still not consumable, retry after.",
+        )),
+        Err(sqlx::Error::RowNotFound) => SSFResponder::NotFound(ErrorBody::new(
+            "no_pending_proposals",
+            "No more pending proposals found.",
+        )),
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Internal server error",
+        )),
     }
 }
 
-/*
-/// Share a folder with another user.
+/// List the eldest pending proposals of the user for a folder, to catch up with a single request.
+/// The list stops before the first proposal that is not consumable yet.
 #[utoipa::path(
-    patch, 
+    get,
     params(
         ("folder_id", description = "Folder id."),
+        ("limit" = Option<u32>, Query, description = "The maximum number of proposals to return, 100 by default."),
     ),
-    request_body(content = ShareFolderRequestWithProposal, content_type = "multipart/form-data"),
     responses(
-        (status = 200, description = "Folder shared."),
+        (status = 200, description = "Retrieved the eldest consumable proposals.", body = ListGroupMessagesResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error")
+    )
+)]
+#[get("/folders/<folder_id>/proposals/all?<limit>")]
+pub async fn list_pending_proposals(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    limit: Option<u32>,
+) -> SSFResponder<ListGroupMessagesResponse> {
+    log::debug!(
+        "Received client certificate to list pending proposals for folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let email = &known_user.unwrap().user_email;
+    match db::list_consumable_messages_by_folder_and_user(folder_id, email, page_limit(limit), db)
+        .await
+    {
+        Ok((pending_proposals, has_more)) => SSFResponder::Ok(Json(ListGroupMessagesResponse {
+            messages: pending_proposals
+                .into_iter()
+                .map(|pending_proposal| GroupMessage {
+                    message_id: pending_proposal.message_id,
+                    folder_id: pending_proposal.folder_id,
+                    payload: pending_proposal.payload,
+                    application_payload: pending_proposal.application_payload,
+                })
+                .collect(),
+            has_more,
+        })),
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the pending proposals of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal server error",
+            ))
+        }
+    }
+}
+
+/// Delete a welcome message, once the client joined the group.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description="The folder id."),
+        ("message_id", description="The welcome message to delete.")
+    ),
+    responses(
+        (status = 200, description = "Welcome message removed from the db."),
         (status = 401, description = "Unkwown or unauthorized user."),
         (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 500, description = "Internal Server Error, couldn't delete the message"),
     )
 )]
-#[patch("/v2/folders/<folder_id>/welcomes", data = "<request>")]
-pub async fn v2_share_folder_welcome(
-    client_certificate: CertificateWithEmails<'_>,
+#[delete("/folders/<folder_id>/welcomes/<message_id>")]
+pub async fn ack_welcome(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
-    sse_queue: &State<SenderSentEventQueue>, 
     folder_id: u64,
-    request: Form<ShareFolderRequestWithProposal<'_>>,
+    message_id: u64,
 ) -> SSFResponder<EmptyResponse> {
     log::debug!(
-        "Received client certificate to publish welcome for folder with id `{}`",
-        folder_id
+        "Received client certificate to ack a welcome message for folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let owner = known_user.unwrap().user_email;
-    let receiver = request.email.as_str();
-    let result = db::insert_welcome(&owner, receiver, folder_id, request.proposal, &mut db).await;
-    match result {
-        Ok(()) => {
-            log::debug!("Should send a notification to the receiver of the folder {:?}", &request.email);
-            // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
-            send_see(Some(folder_id), &request.email, sse_queue).await;
-            SSFResponder::Ok(Json(EmptyResponse {}))
-        },
+    let email = &known_user.unwrap().user_email;
+    match db::delete_welcome(message_id, email, folder_id, db).await {
+        Ok(_) => SSFResponder::EmptyOk("Message deleted".to_string()),
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found", folder_id);
-            SSFResponder::NotFound("Folder not found".to_string())
-        },
-        Err(e) => {
-            log::error!("Couldn't send a welcome message for folder id `{}`: `{}`", folder_id, e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+            log::error!("Error while trying to remove the message with id {message_id} from folder {folder_id}");
+            SSFResponder::NotFound(ErrorBody::new(
+                "message_not_found",
+                "Couldn't find the message",
+            ))
         }
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Internal error while trying to delete message",
+        )),
     }
 }
-    */
-
 
+/// Delete a proposal message.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description="The folder id."),
+        ("message_id", description="The message to delete.")
+    ),
+    responses(
+        (status = 200, description = "Message removed from the queue."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Not found."),
+        (status = 500, description = "Internal Server Error, couldn't delete the message"),
+    )
+)]
+#[delete("/folders/<folder_id>/proposals/<message_id>")]
+pub async fn ack_message(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    message_id: u64,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to propose a change in folder `{:?}`, user emails `{:?}`",
+        &folder_id,
+        &client_certificate.emails,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let email = &known_user.unwrap().user_email;
+    match db::delete_message(message_id, email, folder_id, db).await {
+        Ok(true) => SSFResponder::EmptyOk("Message deleted".to_string()),
+        Ok(false) => SSFResponder::BadRequest(ErrorBody::new(
+            "older_messages_pending",
+            "There are older messages to be acked first.",
+        )),
+        Err(sqlx::Error::RowNotFound) => {
+            log::error!("Error while trying to remove the message with id {message_id} from folder {folder_id}");
+            SSFResponder::NotFound(ErrorBody::new(
+                "message_not_found",
+                "Couldn't find the message",
+            ))
+        }
+        Err(_) => SSFResponder::InternalServerError(ErrorBody::new(
+            "internal_error",
+            "Internal error while trying to delete message",
+        )),
+    }
+}
 
-/// Unshare a folder with other users.
+/// Delete all the proposal messages up to the given one included, once the client processed them.
 #[utoipa::path(
     delete,
     params(
         ("folder_id", description="The folder id."),
+        ("up_to" = u64, Query, description="The last message to delete, usually the last one of the listed proposals.")
     ),
     responses(
-        (status = 200, description = "User removed from folder."),
+        (status = 200, description = "Messages removed from the queue."),
         (status = 401, description = "Unkwown or unauthorized user."),
         (status = 404, description = "Not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+        (status = 500, description = "Internal Server Error, couldn't delete the messages"),
     )
 )]
-#[delete("/folders/<folder_id>")]
-pub async fn remove_self_from_folder(
-    client_certificate: CertificateWithEmails<'_>,
+#[delete("/folders/<folder_id>/proposals?<up_to>")]
+pub async fn ack_messages(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
+    up_to: u64,
 ) -> SSFResponder<EmptyResponse> {
     log::debug!(
-        "Received client certificate to unshare folder with id `{}`",
+        "Received client certificate to ack the messages in folder `{:?}` up to `{}`, user emails `{:?}`",
+        &folder_id,
+        up_to,
+        &client_certificate.emails,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let email = &known_user.unwrap().user_email;
+    match db::delete_messages_up_to(up_to, email, folder_id, db).await {
+        Ok(0) => SSFResponder::NotFound(ErrorBody::new(
+            "message_not_found",
+            "Couldn't find the messages",
+        )),
+        Ok(deleted) => SSFResponder::EmptyOk(format!("{} messages deleted", deleted)),
+        Err(e) => {
+            log::error!("Error while trying to remove the messages up to {up_to} from folder {folder_id}: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal error while trying to delete messages",
+            ))
+        }
+    }
+}
+
+/// List the delivery receipts of a group message, to check which members have processed it, e.g. a key rotation.
+/// The message is identified by the id of any of its copies, as returned when it was published.
+/// Only the sender of the message and the admins of the folder can see its receipts.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description="The folder id."),
+        ("message_id", description="The id of a copy of the message.")
+    ),
+    responses(
+        (status = 200, description = "The receipts of the message.", body = ListMessageReceiptsResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "The user is neither the sender of the message nor an admin of the folder."),
+        (status = 404, description = "Folder or message not found."),
+        (status = 500, description = "Internal Server Error, couldn't list the receipts"),
+    )
+)]
+#[get("/folders/<folder_id>/proposals/<message_id>/receipts")]
+pub async fn list_message_receipts(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    message_id: u64,
+) -> SSFResponder<ListMessageReceiptsResponse> {
+    log::debug!(
+        "Received client certificate to list the receipts of message `{}` in folder `{}`",
+        message_id,
         folder_id
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    let result = db::remove_user_from_folder(folder_id, &known_user.unwrap().user_email, db).await;
-    match result {
-        Ok(_) => SSFResponder::Ok(Json(EmptyResponse {})),
+    let email = known_user.unwrap().user_email;
+    let role = match db::get_folder_role(&email, folder_id, &mut db).await {
+        Ok(role) => role,
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found", folder_id);
-            SSFResponder::NotFound("Folder not found".to_string())
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the role of user `{}` in folder `{}`: `{}`",
+                email,
+                folder_id,
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let receipts = match db::list_message_receipts(message_id, folder_id, &mut db).await {
+        Ok(receipts) => receipts,
+        Err(sqlx::Error::RowNotFound) => {
+            return SSFResponder::NotFound(ErrorBody::new(
+                "message_not_found",
+                "Couldn't find the message",
+            ));
         }
         Err(e) => {
-            log::error!("Couldn't unshare the folder with id `{}`: `{}`", folder_id, e);
-            SSFResponder::InternalServerError("Internal Server Error".to_string())
+            log::error!(
+                "Couldn't list the receipts of message `{}`: `{}`",
+                message_id,
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
+    };
+    let sender = receipts[0].sender_email.clone();
+    if sender != email && !matches!(role, FolderRole::Admin) {
+        log::debug!(
+            "User `{}` can't see the receipts of message `{}`",
+            email,
+            message_id
+        );
+        return SSFResponder::Forbidden(ErrorBody::new(
+            "not_message_sender",
+            "Only the sender of the message and the folder admins can see its receipts.",
+        ));
     }
+    SSFResponder::Ok(Json(ListMessageReceiptsResponse {
+        sender,
+        receipts: receipts
+            .into_iter()
+            .map(|receipt| MessageReceipt {
+                receiver: receipt.receiver_email,
+                message_id: receipt.message_id,
+                acked_at: (receipt.acked_at > 0).then_some(receipt.acked_at),
+            })
+            .collect(),
+    }))
 }
 
-/// Get a file from the cloud storage.
+/// Create a new folder and link it to the user.
+#[utoipa::path(
+    post,
+    request_body(content = CreateFolderRequest, content_type = "multipart/form-data"),
+    path = "/folders",
+    responses(
+        (status = 201, description = "New folder created.", body = FolderResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error")
+    )
+)]
+#[post("/folders", data = "<request>")]
+pub async fn create_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    store: &State<SyncStore>,
+    request: Form<CreateFolderRequest<'_>>,
+) -> SSFResponder<FolderResponse> {
+    log::debug!(
+        "Received client certificate to create a folder, user emails `{:?}`",
+        &client_certificate.emails,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    match insert_folder_and_relation(&known_user.unwrap().user_email, db).await {
+        Ok(result) => {
+            log::debug!(
+                "Created folder with id `{}`, proceed creating the empty metadata file.",
+                result
+            );
+            let store = store.lock().await;
+            let metadata = storage::init_metadata(
+                &store,
+                FolderEntity { folder_id: result },
+                request.metadata.to_vec(),
+            )
+            .await;
+            if let Ok((etag, version)) = metadata {
+                return SSFResponder::Created(Json(FolderResponse {
+                    id: result,
+                    etag,
+                    version,
+                    metadata_content: None,
+                    role: Some(FolderRole::Admin),
+                    display_blob: None,
+                }));
+            } else {
+                log::error!(
+                    "Couldn't create the metadata file for the folder `{}`",
+                    result
+                );
+                return SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ));
+            }
+        }
+        Err(e) => {
+            log::error!("Couldn't create a new folder: `{}", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// List the folders in which the user participates, one page at a time ordered by id.
+/// Optionally include a summary of each folder.
+#[utoipa::path(
+    get,
+    path = "/folders",
+    params(
+        ("limit" = Option<u32>, Query, description = "The maximum number of folders to return, 100 by default."),
+        ("cursor" = Option<u64>, Query, description = "The `next_cursor` returned with the previous page."),
+        ("summary" = Option<bool>, Query, description = "Include the latest metadata etag and version and the member count of each folder."),
+    ),
+    responses(
+        (status = 200, description = "List of folders.", body = ListFolderResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+    )
+)]
+#[get("/folders?<limit>&<cursor>&<summary>")]
+pub async fn list_folders_for_user(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    limit: Option<u32>,
+    cursor: Option<u64>,
+    summary: Option<bool>,
+    store: &State<SyncStore>,
+) -> SSFResponder<ListFolderResponse> {
+    log::debug!(
+        "Received client certificate to retrieve folders, with emails `{:?}`",
+        &client_certificate.emails
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let limit = page_limit(limit);
+    // Fetch one more folder to know if there is a next page.
+    let folders = db::list_folders(&known_user.unwrap().user_email, cursor, limit + 1, db).await;
+    let mut folders = match folders {
+        Err(e) => {
+            log::error!("Couldn't retrieve the folders from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+        Ok(folders) => folders,
+    };
+    let next_cursor = if folders.len() > limit as usize {
+        folders.truncate(limit as usize);
+        folders.last().map(|f| f.folder_id)
+    } else {
+        None
+    };
+    let summaries = if summary.unwrap_or(false) {
+        let store = store.lock().await;
+        let mut summaries = Vec::with_capacity(folders.len());
+        for folder in &folders {
+            let folder_entity = FolderEntity {
+                folder_id: folder.folder_id,
+            };
+            let (etag, version) = match storage::read_metadata_version(&store, &folder_entity).await
+            {
+                Ok(meta) => (meta.e_tag, meta.version),
+                Err(object_store::Error::NotFound { .. }) => (None, None),
+                Err(e) => {
+                    log::error!(
+                        "Couldn't retrieve the metadata version of folder `{}`: `{}`",
+                        folder.folder_id,
+                        e
+                    );
+                    return SSFResponder::InternalServerError(ErrorBody::new(
+                        "internal_error",
+                        "Internal Server Error",
+                    ));
+                }
+            };
+            summaries.push(FolderSummary {
+                id: folder.folder_id,
+                etag,
+                version,
+                member_count: folder.member_count as u64,
+            });
+        }
+        Some(summaries)
+    } else {
+        None
+    };
+    SSFResponder::Ok(Json(ListFolderResponse {
+        folders: folders.iter().map(|f| f.folder_id).collect(),
+        next_cursor,
+        summaries,
+        display_blobs: folders
+            .into_iter()
+            .map(|f| optional_display_blob(f.display_blob))
+            .collect(),
+    }))
+}
+
+/// Get a folder together with its metadata.
+/// The metadata is not sent again if it still has the etag in the `If-None-Match` header.
+/// With `include_metadata=false` only the etag and version of the metadata are returned, without reading its content.
 #[utoipa::path(
     get,
     params(
         ("folder_id", description = "Folder id."),
-        ("file_id", description = "File identifier."),
+        ("include_metadata" = Option<bool>, Query, description = "Whether to inline the content of the metadata, the server default if missing."),
+        ("If-None-Match" = Option<String>, Header, description = "The etag of the metadata already known by the client."),
+        ("If-Match" = Option<String>, Header, description = "The etag the metadata is expected to have."),
     ),
     responses(
-        (status = 200, description = "The requested file.", body = FolderFileResponse),
+        (status = 200, description = "The requested folder.", body = FolderResponse),
+        (status = 304, description = "The metadata didn't change."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "File not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the file"),
+        (status = 404, description = "Folder not found."),
+        (status = 412, description = "The metadata doesn't have the expected etag."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
     )
 )]
-#[get("/folders/<folder_id>/files/<file_id>")]
-pub async fn get_file(
+#[get("/folders/<folder_id>?<include_metadata>")]
+pub async fn get_folder(
     client_certificate: CertificateWithEmails<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
-    file_id: &str,
+    include_metadata: Option<bool>,
+    conditions: EtagConditions,
     store: &State<SyncStore>,
-) -> SSFResponder<FolderFileResponse> {
+    folders_config: &State<FoldersConfig>,
+) -> SSFResponder<FolderResponse> {
     log::debug!(
-        "Received client certificate to read a file in folder with id `{}`",
+        "Received client certificate to retrieve folder with id `{}`",
         folder_id
-    );    
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let role = db::get_folder_role(&known_user.unwrap().user_email, folder_id, &mut db).await;
+    match role {
+        Ok(role) => {
+            let display_blob = match db::get_folder_display_blob(folder_id, &mut db).await {
+                Ok(blob) => optional_display_blob(blob),
+                Err(e) => {
+                    log::error!(
+                        "Couldn't retrieve the display name of the folder from the DB: `{}`",
+                        e
+                    );
+                    return SSFResponder::InternalServerError(ErrorBody::new(
+                        "internal_error",
+                        "Internal Server Error",
+                    ));
+                }
+            };
+            let folder = FolderEntity { folder_id };
+            let store = store.lock().await;
+            let metadata = if include_metadata.unwrap_or(folders_config.include_metadata) {
+                storage::read_metadata_if(
+                    &store,
+                    &folder,
+                    conditions.if_match,
+                    conditions.if_none_match,
+                )
+                .await
+                .map(|(content, obj_meta)| (Some(content), obj_meta))
+            } else {
+                storage::head_metadata_if(
+                    &store,
+                    &folder,
+                    conditions.if_match,
+                    conditions.if_none_match,
+                )
+                .await
+                .map(|obj_meta| (None, obj_meta))
+            };
+            match metadata {
+                Ok((content, obj_meta)) => SSFResponder::Ok(Json(FolderResponse {
+                    etag: obj_meta.e_tag,
+                    version: obj_meta.version,
+                    id: folder.folder_id,
+                    metadata_content: content,
+                    role: Some(role),
+                    display_blob,
+                })),
+                Err(object_store::Error::NotModified { .. }) => SSFResponder::NotModified(()),
+                Err(object_store::Error::Precondition { .. }) => {
+                    SSFResponder::PreconditionFailed(ErrorBody::new(
+                        "metadata_etag_mismatch",
+                        "The metadata doesn't have the expected etag.",
+                    ))
+                }
+                Err(e) => {
+                    log::error!(
+                        "Couldn't retrieve the metadata from the object store: `{}`",
+                        e
+                    );
+                    SSFResponder::InternalServerError(ErrorBody::new(
+                        "internal_error",
+                        "Internal Server Error",
+                    ))
+                }
+            }
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Folder with id `{}` not found", folder_id);
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// The display name of a folder as returned to the clients, the empty blob means that it is not set.
+fn optional_display_blob(blob: Vec<u8>) -> Option<Vec<u8>> {
+    (!blob.is_empty()).then_some(blob)
+}
+
+/// Set the display name of a folder, encrypted by the client, so that the folders can be listed without
+/// downloading their metadata. Any member of the folder can rename it.
+#[utoipa::path(
+    patch,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body(content = FolderNameUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Display name updated."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 413, description = "The display name is larger than 1 KiB."),
+        (status = 500, description = "Internal Server Error, couldn't update the display name"),
+    )
+)]
+#[patch("/folders/<folder_id>/name", data = "<request>")]
+pub async fn set_folder_name(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    request: Form<FolderNameUpload<'_>>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to rename folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    if request.display_blob.len() > MAX_DISPLAY_BLOB_SIZE {
+        return SSFResponder::PayloadTooLarge(ErrorBody::new(
+            "display_blob_too_large",
+            &format!(
+                "The display name exceeds the limit of {} bytes",
+                MAX_DISPLAY_BLOB_SIZE
+            ),
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    match db::set_folder_display_blob(folder_id, request.display_blob, &mut db).await {
+        Ok(()) => SSFResponder::Ok(Json(EmptyResponse {})),
+        Err(e) => {
+            log::error!(
+                "Couldn't update the display name of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Create a read-only share link of a folder, a bearer token granting access to its files and metadata
+/// without a client certificate, e.g. for external collaborators. The content stays encrypted, the key
+/// has to be conveyed out of band. The link stops working once it expires, is revoked or its creator leaves the folder.
+#[utoipa::path(
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body = CreateShareLinkRequest,
+    responses(
+        (status = 201, description = "Share link created.", body = ShareLinkResponse),
+        (status = 400, description = "The validity of the link is invalid."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 500, description = "Internal Server Error, couldn't create the link"),
+    )
+)]
+#[post(
+    "/folders/<folder_id>/links",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn create_share_link(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    request: Json<CreateShareLinkRequest>,
+) -> SSFResponder<ShareLinkResponse> {
+    log::debug!(
+        "Received client certificate to create a share link of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let expires_in = request.expires_in.unwrap_or(DEFAULT_SHARE_LINK_VALIDITY);
+    if expires_in == 0 || expires_in > MAX_SHARE_LINK_VALIDITY {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_link_validity",
+            &format!(
+                "The validity of a link must be between 1 and {} seconds",
+                MAX_SHARE_LINK_VALIDITY
+            ),
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let token = match new_share_link_token() {
+        Ok(token) => token,
+        Err(_) => {
+            log::error!("Couldn't generate the token of a share link");
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let expires_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+        + expires_in;
+    match db::insert_share_link(
+        folder_id,
+        &user_email,
+        &storage::content_hash(token.as_bytes()),
+        expires_at,
+        &mut db,
+    )
+    .await
+    {
+        Ok(link_id) => {
+            log::info!(
+                "User `{}` created the share link `{}` of folder `{}`",
+                user_email,
+                link_id,
+                folder_id
+            );
+            SSFResponder::Created(Json(ShareLinkResponse {
+                link_id,
+                token,
+                expires_at,
+            }))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't create the share link of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// List the active share links of a folder, only the members of the folder can list them.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    responses(
+        (status = 200, description = "The active share links.", body = ListShareLinksResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 500, description = "Internal Server Error, couldn't list the links"),
+    )
+)]
+#[get("/folders/<folder_id>/links")]
+pub async fn list_share_links(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+) -> SSFResponder<ListShareLinksResponse> {
+    log::debug!(
+        "Received client certificate to list the share links of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    match db::list_share_links(folder_id, &mut db).await {
+        Ok(links) => SSFResponder::Ok(Json(ListShareLinksResponse {
+            links: links
+                .into_iter()
+                .map(|link| ShareLink {
+                    link_id: link.link_id,
+                    creator: link.creator_email,
+                    expires_at: link.expires_at,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!(
+                "Couldn't list the share links of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Revoke a share link of a folder, only its creator and the admins of the folder can revoke it.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("link_id", description = "Share link id."),
+    ),
+    responses(
+        (status = 200, description = "Share link revoked."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Share link not found, or the user can't revoke it."),
+        (status = 500, description = "Internal Server Error, couldn't revoke the link"),
+    )
+)]
+#[delete("/folders/<folder_id>/links/<link_id>")]
+pub async fn revoke_share_link(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    link_id: u64,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to revoke the share link `{}` of folder with id `{}`",
+        link_id,
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::delete_share_link(folder_id, link_id, &user_email, &mut db).await {
+        Ok(true) => {
+            log::info!(
+                "User `{}` revoked the share link `{}` of folder `{}`",
+                user_email,
+                link_id,
+                folder_id
+            );
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Ok(false) => SSFResponder::NotFound(ErrorBody::new(
+            "share_link_not_found",
+            "Share link not found",
+        )),
+        Err(e) => {
+            log::error!("Couldn't revoke the share link `{}`: `{}`", link_id, e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Returns a new random bearer token for a share link, base64url encoded.
+fn new_share_link_token() -> Result<String, ring::error::Unspecified> {
+    let mut token = [0u8; 32];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut token)?;
+    Ok(base64::Engine::encode(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+        token,
+    ))
+}
+
+/// Transfer the admin role of a folder to another member, e.g. before leaving it.
+/// The sender stays in the folder as a member, and all the other members are notified.
+#[utoipa::path(
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body = TransferFolderRequest,
+    responses(
+        (status = 200, description = "Ownership transferred."),
+        (status = 400, description = "Bad request: the new admin must be another member."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the folder admins can transfer the ownership."),
+        (status = 404, description = "Folder or member not found."),
+        (status = 500, description = "Internal Server Error, couldn't transfer the ownership"),
+    )
+)]
+#[post(
+    "/folders/<folder_id>/transfer",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn transfer_folder_ownership(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>,
+    folder_id: u64,
+    request: Json<TransferFolderRequest>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to transfer the ownership of folder with id `{}` to `{}`",
+        folder_id,
+        request.email
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let admin_email = known_user.unwrap().user_email;
+    if admin_email == request.email {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_transfer_target",
+            "The new admin must be another member of the folder.",
+        ));
+    }
+    if let Err(response) = check_folder_admin(&admin_email, folder_id, &mut db).await {
+        return response;
+    }
+    match db::transfer_folder_ownership(folder_id, &admin_email, &request.email, db).await {
+        Ok(members) => {
+            notify_members(
+                NotificationEvent::OwnershipTransferred,
+                folder_id,
+                Ok(members),
+                &admin_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "User `{}` is not a member of folder `{}`",
+                request.email,
+                folder_id
+            );
+            SSFResponder::NotFound(ErrorBody::new("member_not_found", "Member not found"))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't transfer the ownership of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// List the users that have access to the folder together with their role.
+/// Only the members of the folder can list them.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    responses(
+        (status = 200, description = "The members of the folder.", body = ListFolderMembersResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the members"),
+    )
+)]
+#[get("/folders/<folder_id>/users")]
+pub async fn list_folder_members(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+) -> SSFResponder<ListFolderMembersResponse> {
+    log::debug!(
+        "Received client certificate to list the members of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::list_folder_members(folder_id, db).await {
+        Ok(members) if members.iter().any(|member| member.user_email == user_email) => {
+            SSFResponder::Ok(Json(ListFolderMembersResponse {
+                members: members
+                    .into_iter()
+                    .map(|member| FolderMember {
+                        email: member.user_email,
+                        role: member.role,
+                    })
+                    .collect(),
+            }))
+        }
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the members of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// List the audit log of a folder, one page at a time in chronological order.
+/// Only the members of the folder can read it.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("limit" = Option<u32>, Query, description = "The maximum number of events to return, 100 by default."),
+        ("cursor" = Option<u64>, Query, description = "The `next_cursor` returned with the previous page."),
+    ),
+    responses(
+        (status = 200, description = "The events of the folder.", body = ListAuditEventsResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the events"),
+    )
+)]
+#[get("/folders/<folder_id>/audit?<limit>&<cursor>")]
+pub async fn list_audit_events(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    limit: Option<u32>,
+    cursor: Option<u64>,
+) -> SSFResponder<ListAuditEventsResponse> {
+    log::debug!(
+        "Received client certificate to list the audit log of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let limit = page_limit(limit);
+    // Fetch one more event to know if there is a next page.
+    let mut events = match db::list_audit_events(folder_id, cursor, limit + 1, db).await {
+        Ok(events) => events,
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the audit log of folder `{}`: `{}`",
+                folder_id,
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let next_cursor = if events.len() > limit as usize {
+        events.truncate(limit as usize);
+        events.last().map(|event| event.event_id)
+    } else {
+        None
+    };
+    SSFResponder::Ok(Json(ListAuditEventsResponse {
+        events: events
+            .into_iter()
+            .map(|event| AuditEvent {
+                id: event.event_id,
+                actor: event.actor_email,
+                action: event.action,
+                target: event.target,
+                timestamp: event.created_at,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
+
+/// Share a folder with other users, only the folder admins can share it.
+/// If some of the users already can see the folder, they will be ignored.
+#[utoipa::path(
+    patch, 
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body = ShareFolderRequest,
+    responses(
+        (status = 200, description = "Folder shared."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the folder admins can share it."),
+        (status = 404, description = "Not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+    )
+)]
+#[patch("/folders/<folder_id>", data = "<request>")]
+pub async fn share_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>, 
+    folder_id: u64,
+    mut request: Json<ShareFolderRequest>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to share folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let owner_email = known_user.unwrap().user_email;
+    if let Err(response) = check_folder_admin(&owner_email, folder_id, &mut db).await {
+        return response;
+    }
+    request.emails.push(owner_email.clone());
+    let emails = request.emails.iter().map(AsRef::as_ref).collect();
+    let result = db::insert_folder_users_relations(folder_id, &owner_email, emails, None, db).await;
+    match result {
+        Ok(_) => {
+            log::debug!(
+                "Should send a notification to all receivers of the folder {:?}",
+                &request.emails
+            );
+            // This is only for the baseline, for GRaPPA is redundant. use v2 instead.
+            for email in &request.emails {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(
+                    NotificationEvent::Share,
+                    Some(folder_id),
+                    None,
+                    email,
+                    sse_queue,
+                )
+                .await;
+            }
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Folder with id `{}` not found", folder_id);
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't share the folder with id `{}`: `{}`", folder_id, e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Share a folder with another user, only the folder admins can share it.
+/// The user becomes a member right away, see [`invite_to_folder`] to let the user accept the invitation first.
+#[utoipa::path(
+    patch, 
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body(content = ShareFolderRequestWithProposal, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Folder shared.", body = ProposalResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the folder admins can share it."),
+        (status = 404, description = "Not found."),
+        (status = 409, description = "Conflict: client status out of sync."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+    )
+)]
+#[patch("/v2/folders/<folder_id>", data = "<request>")]
+pub async fn v2_share_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>, 
+    folder_id: u64,
+    request: Form<ShareFolderRequestWithProposal<'_>>,
+) -> SSFResponder<ProposalResponse> {
+    log::debug!(
+        "Received client certificate to share folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let owner = known_user.unwrap().user_email;
+    if let Err(response) = check_folder_admin(&owner, folder_id, &mut db).await {
+        return response;
+    }
+    let emails = vec![request.email.as_str(), owner.as_str()];
+    let result =
+        db::insert_folder_users_relations(folder_id, &owner, emails, Some(request.proposal), db)
+            .await;
+    match result {
+        Ok((users, Some(message_ids))) if users.len() > 0 => {
+            log::debug!("Should send a notification to the all the receivers of the proposal.");
+            for user in users {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(
+                    NotificationEvent::Share,
+                    Some(folder_id),
+                    None,
+                    &user,
+                    sse_queue,
+                )
+                .await;
+            }
+            SSFResponder::Ok(Json(ProposalResponse { message_ids }))
+        }
+        Ok(_) => {
+            log::debug!("The sender {owner} is not in sync with pending messages!");
+            SSFResponder::Conflict(ErrorBody::new(
+                "state_outdated",
+                "Not in sync, please first process the proposals that are pending!.",
+            ))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Folder with id `{}` not found", folder_id);
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't share the folder with id `{}`: `{}`", folder_id, e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Publish the welcome message for a user the folder has been shared with, see [`v2_share_folder`].
+/// The proposal field of the request contains the welcome message.
+#[utoipa::path(
+    patch, 
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body(content = ShareFolderRequestWithProposal, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Welcome message published."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+    )
+)]
+#[patch("/v2/folders/<folder_id>/welcomes", data = "<request>")]
+pub async fn v2_share_folder_welcome(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>, 
+    folder_id: u64,
+    request: Form<ShareFolderRequestWithProposal<'_>>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to publish welcome for folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let owner = known_user.unwrap().user_email;
+    let receiver = request.email.as_str();
+    let result = db::insert_welcome(&owner, receiver, folder_id, request.proposal, &mut db).await;
+    match result {
+        Ok(message_id) => {
+            log::debug!(
+                "Should send a notification to the receiver of the folder {:?}",
+                &request.email
+            );
+            // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+            send_see(
+                NotificationEvent::Welcome,
+                Some(folder_id),
+                Some(message_id),
+                &request.email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Folder with id `{}` not found", folder_id);
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't send a welcome message for folder id `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Invite a user to join a folder, only the folder admins can invite.
+/// Unlike [`v2_share_folder`], the user becomes a member only when it accepts the invitation,
+/// see [`accept_invitation`]. The proposal adding the user and its welcome message are published then.
+#[utoipa::path(
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body(content = InvitationRequest, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Invitation created.", body = InvitationResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the folder admins can invite."),
+        (status = 404, description = "Folder or invited user not found."),
+        (status = 409, description = "Conflict: the user is already a member or has already been invited."),
+        (status = 500, description = "Internal Server Error, couldn't create the invitation"),
+    )
+)]
+#[post("/folders/<folder_id>/invitations", data = "<request>")]
+pub async fn invite_to_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>,
+    folder_id: u64,
+    request: Form<InvitationRequest<'_>>,
+) -> SSFResponder<InvitationResponse> {
+    log::debug!(
+        "Received client certificate to invite `{}` to folder with id `{}`",
+        request.email,
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let inviter = known_user.unwrap().user_email;
+    if let Err(response) = check_folder_admin(&inviter, folder_id, &mut db).await {
+        return response;
+    }
+    match get_users_by_emails(&vec![request.email.as_str()], &mut db).await {
+        Ok(users) if !users.is_empty() => (),
+        Ok(_) => {
+            log::debug!("The invited user `{}` is not registered", request.email);
+            return SSFResponder::NotFound(ErrorBody::new("user_not_found", "User not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the invited user from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    match db::get_folder_role(&request.email, folder_id, &mut db).await {
+        Ok(_) => {
+            return SSFResponder::Conflict(ErrorBody::new(
+                "already_member",
+                "The user is already a member of the folder",
+            ))
+        }
+        Err(sqlx::Error::RowNotFound) => (),
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let proposal = request.proposal.unwrap_or_default();
+    let welcome = request.welcome.unwrap_or_default();
+    match db::insert_invitation(
+        folder_id,
+        &inviter,
+        &request.email,
+        proposal,
+        welcome,
+        &mut db,
+    )
+    .await
+    {
+        Ok(invitation_id) => {
+            // If the send fails, it just means that the client is not online, they will fetch the invitations upon initialisation.
+            send_see(
+                NotificationEvent::Invitation,
+                Some(folder_id),
+                None,
+                &request.email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Created(Json(InvitationResponse { invitation_id }))
+        }
+        Err(sqlx::Error::Database(e)) => {
+            log::debug!(
+                "User `{}` has already been invited to folder `{}`: `{}`",
+                request.email,
+                folder_id,
+                e
+            );
+            SSFResponder::Conflict(ErrorBody::new(
+                "already_invited",
+                "The user has already been invited to the folder",
+            ))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't invite `{}` to folder `{}`: `{}`",
+                request.email,
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// List the pending invitations of the user.
+#[utoipa::path(
+    get,
+    path = "/invitations",
+    responses(
+        (status = 200, description = "The pending invitations.", body = ListInvitationsResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the invitations"),
+    )
+)]
+#[get("/invitations")]
+pub async fn list_invitations(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+) -> SSFResponder<ListInvitationsResponse> {
+    log::debug!(
+        "Received client certificate to list the invitations with emails: {}.",
+        client_certificate.emails.join(","),
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::list_invitations_by_invitee(&user_email, db).await {
+        Ok(invitations) => SSFResponder::Ok(Json(ListInvitationsResponse {
+            invitations: invitations
+                .into_iter()
+                .map(|invitation| Invitation {
+                    id: invitation.invitation_id,
+                    folder_id: invitation.folder_id,
+                    inviter: invitation.inviter_email,
+                    timestamp: invitation.created_at,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the invitations of user `{}`: `{}`",
+                user_email,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Accept an invitation, joining the folder.
+/// The proposal adding the user is published to the other members, and the welcome message to the user.
+#[utoipa::path(
+    post,
+    params(
+        ("invitation_id", description = "Invitation id."),
+    ),
+    responses(
+        (status = 200, description = "Invitation accepted.", body = ProposalResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Invitation not found."),
+        (status = 409, description = "Conflict: the proposal of the invitation is outdated, a new invitation is needed."),
+        (status = 500, description = "Internal Server Error, couldn't accept the invitation"),
+    )
+)]
+#[post("/invitations/<invitation_id>/accept")]
+pub async fn accept_invitation(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>,
+    invitation_id: u64,
+) -> SSFResponder<ProposalResponse> {
+    log::debug!(
+        "Received client certificate to accept the invitation with id `{}`",
+        invitation_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::accept_invitation(invitation_id, &user_email, db).await {
+        Ok(Some(accepted)) => {
+            // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+            for (receiver, message_id) in accepted.receivers.iter().zip(accepted.message_ids.iter())
+            {
+                send_see(
+                    NotificationEvent::Proposal,
+                    Some(accepted.folder_id),
+                    Some(*message_id),
+                    receiver,
+                    sse_queue,
+                )
+                .await;
+            }
+            if let Some(welcome_id) = accepted.welcome_id {
+                send_see(
+                    NotificationEvent::Welcome,
+                    Some(accepted.folder_id),
+                    Some(welcome_id),
+                    &user_email,
+                    sse_queue,
+                )
+                .await;
+            }
+            SSFResponder::Ok(Json(ProposalResponse {
+                message_ids: accepted.message_ids,
+            }))
+        }
+        Ok(None) => {
+            log::debug!("The proposal of invitation `{}` is outdated", invitation_id);
+            SSFResponder::Conflict(ErrorBody::new(
+                "invitation_outdated",
+                "The invitation is outdated, please ask for a new one.",
+            ))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Invitation with id `{}` not found for user `{}`",
+                invitation_id,
+                user_email
+            );
+            SSFResponder::NotFound(ErrorBody::new(
+                "invitation_not_found",
+                "Invitation not found",
+            ))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't accept the invitation with id `{}`: `{}`",
+                invitation_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Decline an invitation, or withdraw it if the user is the one that sent it.
+#[utoipa::path(
+    delete,
+    params(
+        ("invitation_id", description = "Invitation id."),
+    ),
+    responses(
+        (status = 200, description = "Invitation deleted."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Invitation not found."),
+        (status = 500, description = "Internal Server Error, couldn't delete the invitation"),
+    )
+)]
+#[delete("/invitations/<invitation_id>")]
+pub async fn delete_invitation(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    invitation_id: u64,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to delete the invitation with id `{}`",
+        invitation_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::delete_invitation(invitation_id, &user_email, db).await {
+        Ok(()) => SSFResponder::Ok(Json(EmptyResponse {})),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Invitation with id `{}` not found for user `{}`",
+                invitation_id,
+                user_email
+            );
+            SSFResponder::NotFound(ErrorBody::new(
+                "invitation_not_found",
+                "Invitation not found",
+            ))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't delete the invitation with id `{}`: `{}`",
+                invitation_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Leave a folder. The other members are notified, so that an admin can commit the removal from the group.
+/// If the user is the only admin of the folder, the first of the other members by email becomes its admin.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description="The folder id."),
+    ),
+    responses(
+        (status = 200, description = "User removed from folder."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the users"),
+    )
+)]
+#[delete("/folders/<folder_id>")]
+pub async fn remove_self_from_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    store: &State<SyncStore>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to unshare folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let members = db::list_emails_by_folder(folder_id, &mut db).await;
+    let result = db::remove_user_from_folder(folder_id, &user_email, db).await;
+    if result.is_ok() {
+        // The user has actually been removed, the other clients of the user can discard the folder as well.
+        send_see(
+            NotificationEvent::RemovedFromFolder,
+            Some(folder_id),
+            None,
+            &user_email,
+            sse_queue,
+        )
+        .await;
+    }
+    match result {
+        Ok(true) => {
+            // Nobody can access the folder anymore, cleanup its content as well.
+            let store = store.lock().await;
+            if let Err(e) = storage::delete_folder(&store, &FolderEntity { folder_id }).await {
+                log::error!(
+                    "Couldn't delete the content of the removed folder `{}`: `{}`",
+                    folder_id,
+                    e
+                );
+            }
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Ok(false) => {
+            notify_members(
+                NotificationEvent::MemberLeft,
+                folder_id,
+                members,
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(EmptyResponse {}))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Folder with id `{}` not found", folder_id);
+            SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't unshare the folder with id `{}`: `{}`",
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Remove another user from a folder, only the folder admins can remove its members.
+/// The pending messages of the removed user are deleted. Optionally, the commit removing the user from the group
+/// can be published to the remaining members in the same request.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description = "The folder id."),
+        ("email", description = "The email of the user to remove."),
+    ),
+    request_body(content = ProposalMessageRequest, description = "The optional proposal to publish to the remaining members.", content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "User removed from folder.", body = ProposalResponse),
+        (status = 400, description = "Bad request: use the remove self endpoint to leave a folder."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 403, description = "Only the folder admins can remove its members."),
+        (status = 404, description = "Not found."),
+        (status = 409, description = "Conflict: client status out of sync."),
+        (status = 500, description = "Internal Server Error, couldn't remove the user"),
+    )
+)]
+#[delete("/folders/<folder_id>/users/<email>", data = "<request>")]
+pub async fn remove_folder_member(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>,
+    folder_id: u64,
+    email: &str,
+    content_type: Option<&ContentType>,
+    request: Option<Form<ProposalMessageRequest<'_>>>,
+) -> SSFResponder<ProposalResponse> {
+    log::debug!(
+        "Received client certificate to remove user `{}` from folder with id `{}`",
+        email,
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let admin = known_user.unwrap().user_email;
+    if admin == email {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "use_remove_self",
+            "Use the remove self endpoint to leave the folder.",
+        ));
+    }
+    if request.is_none() && content_type.is_some_and(|content_type| content_type.is_form_data()) {
+        return SSFResponder::BadRequest(ErrorBody::new("invalid_proposal", "Invalid proposal."));
+    }
+    if let Err(response) = check_folder_admin(&admin, folder_id, &mut db).await {
+        return response;
+    }
+    let proposal = request.as_ref().map(|request| request.proposal);
+    match db::remove_member_from_folder(folder_id, &admin, email, proposal, db).await {
+        Ok(Some((receivers, message_ids))) => {
+            // The pending messages are created for all the receivers but the sender, in the same order.
+            let receivers_message_ids = receivers
+                .iter()
+                .filter(|receiver| **receiver != admin)
+                .zip(message_ids.iter());
+            for (receiver, message_id) in receivers_message_ids {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(
+                    NotificationEvent::Proposal,
+                    Some(folder_id),
+                    Some(*message_id),
+                    receiver,
+                    sse_queue,
+                )
+                .await;
+            }
+            send_see(
+                NotificationEvent::RemovedFromFolder,
+                Some(folder_id),
+                None,
+                email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(ProposalResponse { message_ids }))
+        }
+        Ok(None) => {
+            log::debug!("The sender {admin} is not in sync with pending messages!");
+            send_see(
+                NotificationEvent::Proposal,
+                Some(folder_id),
+                None,
+                &admin,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Conflict(ErrorBody::new(
+                "state_outdated",
+                "Conflict: the user state is outdated, please fetch the pending proposals first.",
+            ))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!("User `{}` not found in folder `{}`", email, folder_id);
+            SSFResponder::NotFound(ErrorBody::new(
+                "member_not_found",
+                "User not found in the folder",
+            ))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't remove user `{}` from folder `{}`: `{}`",
+                email,
+                folder_id,
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Delete a folder together with all its content.
+/// Only the last member of a folder can delete it, the other members should first remove themselves from the folder.
+/// The folder is deleted from the database before its content is removed from the storage, the objects left behind
+/// on a failure are removed by the reconciliation.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description="The folder id."),
+    ),
+    responses(
+        (status = 200, description = "Folder and its content deleted."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Not found."),
+        (status = 409, description = "Conflict: other users still have access to the folder."),
+        (status = 500, description = "Internal Server Error, couldn't delete the folder"),
+    )
+)]
+#[delete("/folders/<folder_id>/content")]
+pub async fn delete_folder(
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    store: &State<SyncStore>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to delete folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    // The membership is checked in the same transaction that deletes the folder.
+    match db::delete_folder(folder_id, &user_email, db).await {
+        Ok(true) => (),
+        Ok(false) => {
+            log::debug!(
+                "Folder with id `{}` is still shared with other users",
+                folder_id
+            );
+            return SSFResponder::Conflict(ErrorBody::new(
+                "folder_still_shared",
+                "Other users still have access to the folder.",
+            ));
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't delete the folder with id `{}`: `{}`",
+                folder_id,
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let object_store = store.lock().await;
+    if let Err(e) = storage::delete_folder(&object_store, &FolderEntity { folder_id }).await {
+        log::error!(
+            "Couldn't delete the content of folder `{}`: `{}`",
+            folder_id,
+            e
+        );
+    }
+    SSFResponder::Ok(Json(EmptyResponse {}))
+}
+
+/// Get a file from the cloud storage.
+/// The file is streamed in the response body, its etag and version are sent in the `ETag` and `X-Version` headers.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("Authorization" = Option<String>, Header, description = "`Bearer <token>` of a share link of the folder, to read it without a client certificate."),
+    ),
+    responses(
+        (status = 200, description = "The requested file.", body = [u8], content_type = "application/octet-stream",
+            headers(
+                ("ETag" = Option<String>, description = "The etag of the file."),
+                ("X-Version" = Option<String>, description = "The version of the file."),
+            )
+        ),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "File not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the file"),
+    )
+)]
+#[get("/folders/<folder_id>/files/<file_id>")]
+pub async fn get_file(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    store: &State<SyncStore>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to read a file in folder with id `{}`",
+        folder_id
+    );    
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let folder = match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => FolderEntity { folder_id },
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let file = match open_file(store, &folder, file_id).await {
+        Ok(file) => file,
+        Err(error) => return error,
+    };
+    audit(
+        folder_id,
+        &user_email,
+        AuditAction::FileDownloaded,
+        Some(file_id),
+        &mut db,
+    )
+    .await;
+    SSFResponder::Stream(FileStream(file, bandwidth.download(&user_email)))
+}
+
+/// Get a file from the cloud storage with a share link, passed as a bearer token instead of a client certificate.
+#[get("/folders/<folder_id>/files/<file_id>", rank = 2)]
+pub async fn get_file_with_link(
+    token: ShareLinkToken,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    store: &State<SyncStore>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received share link to read a file in folder with id `{}`",
+        folder_id
+    );
+    let link = match authorize_share_link(&token, folder_id, &mut db).await {
+        Ok(link) => link,
+        Err(unauthorized) => return unauthorized,
+    };
+    let file = match open_file(store, &FolderEntity { folder_id }, file_id).await {
+        Ok(file) => file,
+        Err(error) => return error,
+    };
+    audit(
+        folder_id,
+        &link.creator_email,
+        AuditAction::LinkFileDownloaded,
+        Some(file_id),
+        &mut db,
+    )
+    .await;
+    // The downloads through a link count towards the bandwidth of its creator.
+    SSFResponder::Stream(FileStream(file, bandwidth.download(&link.creator_email)))
+}
+
+/// Open a file of the folder, returning the error response if it can't be read.
+async fn open_file(
+    store: &State<SyncStore>,
+    folder: &FolderEntity,
+    file_id: &str,
+) -> Result<GetResult, SSFResponder<EmptyResponse>> {
+    let store = store.lock().await;
+    storage::open_file(&store, folder, file_id)
+        .await
+        .map_err(|e| match e {
+            object_store::Error::NotFound { path: _, source: _ } => {
+                log::debug!(
+                    "File with id `{}` not found in folder `{}`",
+                    file_id,
+                    folder.folder_id
+                );
+                SSFResponder::NotFound(ErrorBody::new("file_not_found", "File not found"))
+            }
+            _ => {
+                log::error!("Couldn't retrieve the file from the object store: `{}`", e);
+                SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ))
+            }
+        })
+}
+
+/// List the files stored in a folder, without reading the metadata.
+/// This allows clients to recover the folder content even if the metadata is corrupted.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    responses(
+        (status = 200, description = "The files of the folder.", body = ListFilesResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't list the files"),
+    )
+)]
+#[get("/folders/<folder_id>/files")]
+pub async fn list_files(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    store: &State<SyncStore>,
+) -> SSFResponder<ListFilesResponse> {
+    log::debug!(
+        "Received client certificate to list the files in folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let folder = match get_folder_by_id(&user_email, folder_id, db).await {
+        Ok(folder) => folder,
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let store = store.lock().await;
+    match storage::list_files(&store, &folder).await {
+        Ok(files) => SSFResponder::Ok(Json(ListFilesResponse {
+            files: files
+                .into_iter()
+                .map(|file| FileEntry {
+                    file_id: file.location.filename().unwrap_or_default().to_string(),
+                    size: file.size,
+                    etag: file.e_tag,
+                    version: file.version,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!("Couldn't list the files from the object store: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Export a folder as a tar archive of its metadata and files, as stored in the object store,
+/// so that members can take a full encrypted backup of the folder in one request.
+/// The entries are named `<folder_id>/metadata` and `<folder_id>/<file_id>`, the history of the metadata
+/// and the trash are not included. The archive is streamed, a file is read only when it is reached.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    responses(
+        (status = 200, description = "The tar archive of the folder.", body = [u8], content_type = "application/x-tar"),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't list the files"),
+    )
+)]
+#[get("/folders/<folder_id>/export")]
+pub async fn export_folder(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    store: &State<SyncStore>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to export the folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let folder = match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => FolderEntity { folder_id },
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let files = {
+        let store = store.lock().await;
+        storage::list_files(&store, &folder).await
+    };
+    let files = match files {
+        Ok(files) => files,
+        Err(e) => {
+            log::error!("Couldn't list the files from the object store: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let file_ids = std::iter::once(storage::METADATA_FILE_NAME.to_string())
+        .chain(
+            files
+                .into_iter()
+                .map(|file| file.location.filename().unwrap_or_default().to_string()),
+        )
+        .collect();
+    audit(
+        folder_id,
+        &user_email,
+        AuditAction::FolderExported,
+        None,
+        &mut db,
+    )
+    .await;
+    let bucket = bandwidth.download(&user_email);
+    SSFResponder::Archive(FolderArchive {
+        store: store.inner().clone(),
+        folder,
+        file_ids,
+        bucket,
+    })
+}
+
+/// Check that the user is an admin of the folder.
+/// Returns the response to send back to the client otherwise.
+async fn check_folder_admin<R>(
+    email: &str,
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), SSFResponder<R>> {
+    match db::get_folder_role(email, folder_id, db).await {
+        Ok(FolderRole::Admin) => Ok(()),
+        Ok(FolderRole::Member) => {
+            log::debug!("User `{}` is not an admin of folder `{}`", email, folder_id);
+            Err(SSFResponder::Forbidden(ErrorBody::new(
+                "not_folder_admin",
+                "Only the folder admins can perform this operation.",
+            )))
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                email
+            );
+            Err(SSFResponder::NotFound(ErrorBody::new(
+                "folder_not_found",
+                "Folder not found",
+            )))
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't retrieve the role of user `{}` in folder `{}`: `{}`",
+                email,
+                folder_id,
+                e
+            );
+            Err(SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            )))
+        }
+    }
+}
+
+/// Check that `size` bytes can be stored for `file_ids`, replacing their previous version if any,
+/// without exceeding the storage quota of the user or of the folder.
+async fn check_quota<R>(
+    quota: &QuotaConfig,
+    user_email: &str,
+    folder_id: u64,
+    file_ids: &[&str],
+    size: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<(), SSFResponder<R>> {
+    let usage = db::get_usage(user_email, folder_id, file_ids, db)
+        .await
+        .map_err(|e| {
+            log::error!("Couldn't retrieve the storage usage from the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        })?;
+    quota.check(&usage, size).map_err(|exceeded| {
+        log::debug!(
+            "Rejecting `{}` bytes for user `{}` in folder `{}`: {}",
+            size,
+            user_email,
+            folder_id,
+            exceeded
+        );
+        SSFResponder::InsufficientStorage(ErrorBody::new("quota_exceeded", &exceeded))
+    })
+}
+
+/// Hashes an uploaded file, checking it against the content hash sent by the client, if any.
+/// The file is verified before storing it, so that a corrupted file never becomes the current version.
+async fn hash_upload<R>(
+    file: &TempFile<'_>,
+    expected: Option<&str>,
+    file_id: &str,
+) -> Result<String, SSFResponder<R>> {
+    let content_hash = match file.open().await {
+        Ok(file) => storage::content_hash_of_reader(file).await,
+        Err(e) => Err(e),
+    };
+    let content_hash = content_hash.map_err(|e| {
+        log::error!("Couldn't hash the uploaded file: `{}`", e);
+        SSFResponder::InternalServerError(ErrorBody::new("internal_error", "Internal Server Error"))
+    })?;
+    if let Some(expected) = expected {
+        if !expected.trim().eq_ignore_ascii_case(&content_hash) {
+            log::debug!(
+                "The uploaded file `{}` has hash `{}` instead of `{}`",
+                file_id,
+                content_hash,
+                expected
+            );
+            return Err(SSFResponder::BadRequest(ErrorBody::new("content_hash_mismatch", "The hash of the received file doesn't match the content_hash, please retry the upload.")));
+        }
+    }
+    Ok(content_hash)
+}
+
+/// Stores the content of an uploaded file once per folder, the file only points to it.
+/// The reference to the blob must be released with [`release_blob`] if the file is not written.
+#[allow(clippy::too_many_arguments)]
+async fn store_blob<R>(
+    object_store: &MutexGuard<'_, DynamicStore>,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    file: &TempFile<'_>,
+    content_hash: &str,
+    user_email: &str,
+    bandwidth: &Bandwidth,
+    db: &mut sqlx::AnyConnection,
+) -> Result<(), SSFResponder<R>> {
+    let folder_id = folder_entity.folder_id;
+    match db::acquire_blob(folder_id, content_hash, db).await {
+        Ok(true) => {
+            log::debug!(
+                "The content of file `{}` is already stored in folder `{}`",
+                file_id,
+                folder_id
+            );
+            Ok(())
+        }
+        Ok(false) => {
+            let written = match file.open().await {
+                Ok(file) => {
+                    // The form is received before reaching the handler, so the upload is throttled while it is copied to the object store.
+                    let file = ThrottledReader::new(file, bandwidth.upload(user_email));
+                    storage::write_blob(object_store, folder_entity, content_hash, Box::new(file))
+                        .await
+                }
+                Err(e) => Err(object_store::Error::Generic {
+                    store: "ds",
+                    source: Box::new(e),
+                }),
+            };
+            if let Err(e) = written {
+                log::error!("Internal server error while writing a blob to S3: `{}`", e);
+                release_blob(object_store, folder_entity, content_hash, db).await;
+                return Err(SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                )));
+            }
+            Ok(())
+        }
+        Err(e) => {
+            log::error!(
+                "Couldn't reference the blob `{}` in folder `{}`: `{}`",
+                content_hash,
+                folder_id,
+                e
+            );
+            Err(SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            )))
+        }
+    }
+}
+
+/// Upload a file to the cloud storage.
+#[utoipa::path(
+    post,
+    request_body(content = Upload, content_type = "multipart/form-data"),
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key chosen by the client, the retries with the same key get the outcome of the first request."),
+    ),
+    responses(
+        (status = 201, description = "File uploaded.", body = UploadFileResponse),
+        (status = 400, description = "Invalid file id, or the received file doesn't match the content hash."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 409, description = "Conflict: the metadata or the file changed in the meantime."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the file"),
+        (status = 507, description = "The storage quota of the user or of the folder is exceeded."),
+    )
+)]
+#[post("/folders/<folder_id>/files/<file_id>", data = "<upload>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    upload: Idempotent<Form<Upload<'_>>>,
+    state: &State<SyncStore>,
+    quota: &State<QuotaConfig>,
+    sse_queue: &State<SenderSentEventQueue>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<UploadFileResponse> {
+    log::debug!(
+        "Received client certificate to upload a file in folder with id `{}` with parameters `{:?}`.",
+        folder_id,
+        upload,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    // Protect against metadata override.
+    if storage::is_metadata_file_name(file_id) {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    let members = match db::list_emails_by_folder(folder_id, &mut db).await {
+        Ok(members) if members.contains(&user_email) => members,
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let size = upload.file.len();
+    if let Err(response) =
+        check_quota(quota, &user_email, folder_id, &[file_id], size, &mut db).await
+    {
+        return response;
+    }
+    let content_hash =
+        match hash_upload(&upload.file, upload.content_hash.as_deref(), file_id).await {
+            Ok(content_hash) => content_hash,
+            Err(response) => return response,
+        };
+    let folder_entity = FolderEntity { folder_id };
+    let object_store = state.lock().await;
+    if let Err(response) = store_blob(
+        &object_store,
+        &folder_entity,
+        file_id,
+        &upload.file,
+        &content_hash,
+        &user_email,
+        bandwidth,
+        &mut db,
+    )
+    .await
+    {
+        return response;
+    }
+    let result = storage::write(
+        &object_store,
+        WriteInput {
+            folder_entity: folder_entity.clone(),
+            file_id,
+            file_to_write: Some(Box::new(std::io::Cursor::new(storage::blob_pointer(
+                &content_hash,
+            )))),
+            metadata_file: upload.metadata.to_vec(),
+            parent_etag: upload
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: upload
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+            file_parent_etag: upload
+                .file_parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+        },
+    )
+    .await;
+    match &result {
+        Ok(_) => {
+            replace_file_blob(
+                &object_store,
+                &folder_entity,
+                file_id,
+                Some(&content_hash),
+                &mut db,
+            )
+            .await
+        }
+        Err(_) => release_blob(&object_store, &folder_entity, &content_hash, &mut db).await,
+    }
+    match result {
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while writing a file to S3, the metadata or file version you want to update doesn't match");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while writing a file to S3: `{}`",
+                e.to_string()
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => {
+            if let Err(e) = db::upsert_usage(&user_email, folder_id, file_id, size, &mut db).await {
+                log::error!(
+                    "Couldn't account the size of file `{}` in folder `{}`: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            audit(
+                folder_id,
+                &user_email,
+                AuditAction::FileUploaded,
+                Some(file_id),
+                &mut db,
+            )
+            .await;
+            notify_members(
+                NotificationEvent::FileUploaded,
+                folder_id,
+                Ok(members),
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Created(Json(UploadFileResponse {
+                etag,
+                version,
+                content_hash: Some(content_hash),
+            }))
+        }
+    }
+}
+
+/// Upload several files to the cloud storage in a single request, together with the metadata including all of them.
+/// The files are written first and then the metadata, with the same conditions as [`upload_file`]:
+/// if any of them fails, the files already written are rolled back and the folder is left unchanged.
+#[utoipa::path(
+    post,
+    request_body(content = BatchUpload, content_type = "multipart/form-data"),
+    params(
+        ("folder_id", description = "Folder id."),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key chosen by the client, the retries with the same key get the outcome of the first request."),
+    ),
+    responses(
+        (status = 201, description = "Files uploaded.", body = UploadFilesResponse),
+        (status = 400, description = "No files, invalid or repeated file ids, or a received file doesn't match its content hash."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 409, description = "Conflict: the metadata or one of the files changed in the meantime."),
+        (status = 500, description = "Internal Server Error, couldn't write the files"),
+        (status = 507, description = "The storage quota of the user or of the folder is exceeded."),
+    )
+)]
+#[post("/folders/<folder_id>/files", data = "<upload>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_files(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    upload: Idempotent<Form<BatchUpload<'_>>>,
+    state: &State<SyncStore>,
+    quota: &State<QuotaConfig>,
+    sse_queue: &State<SenderSentEventQueue>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<UploadFilesResponse> {
+    log::debug!(
+        "Received client certificate to upload files in folder with id `{}` with parameters `{:?}`.",
+        folder_id,
+        upload,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    if upload.files.is_empty() {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "no_files",
+            "At least one file must be uploaded.",
+        ));
+    }
+    let file_ids: Vec<&str> = upload
+        .files
+        .iter()
+        .map(|file| file.file_id.as_str())
+        .collect();
+    // Protect against metadata override.
+    if file_ids
+        .iter()
+        .any(|file_id| storage::is_metadata_file_name(file_id))
+    {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    if file_ids.iter().collect::<HashSet<_>>().len() != file_ids.len() {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "duplicate_file_id",
+            "Each file can be uploaded only once in a batch.",
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    let members = match db::list_emails_by_folder(folder_id, &mut db).await {
+        Ok(members) if members.contains(&user_email) => members,
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let size = upload.files.iter().map(|file| file.file.len()).sum();
+    if let Err(response) =
+        check_quota(quota, &user_email, folder_id, &file_ids, size, &mut db).await
+    {
+        return response;
+    }
+    let mut content_hashes = Vec::with_capacity(upload.files.len());
+    for file in &upload.files {
+        match hash_upload(&file.file, file.content_hash.as_deref(), &file.file_id).await {
+            Ok(content_hash) => content_hashes.push(content_hash),
+            Err(response) => return response,
+        }
+    }
+    let folder_entity = FolderEntity { folder_id };
+    let object_store = state.lock().await;
+    for (index, (file, content_hash)) in upload.files.iter().zip(&content_hashes).enumerate() {
+        if let Err(response) = store_blob(
+            &object_store,
+            &folder_entity,
+            &file.file_id,
+            &file.file,
+            content_hash,
+            &user_email,
+            bandwidth,
+            &mut db,
+        )
+        .await
+        {
+            for stored in &content_hashes[..index] {
+                release_blob(&object_store, &folder_entity, stored, &mut db).await;
+            }
+            return response;
+        }
+    }
+    let result = storage::write_batch(
+        &object_store,
+        BatchWriteInput {
+            folder_entity: folder_entity.clone(),
+            files: upload
+                .files
+                .iter()
+                .zip(&content_hashes)
+                .map(|(file, content_hash)| BatchFile {
+                    file_id: &file.file_id,
+                    content: Box::new(std::io::Cursor::new(storage::blob_pointer(content_hash))),
+                    file_parent_etag: file
+                        .file_parent_etag
+                        .clone()
+                        .map(|etag| etag.trim().to_string()),
+                })
+                .collect(),
+            metadata_file: upload.metadata.to_vec(),
+            parent_etag: upload
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: upload
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+        },
+    )
+    .await;
+    for (file, content_hash) in upload.files.iter().zip(&content_hashes) {
+        match &result {
+            Ok(_) => {
+                replace_file_blob(
+                    &object_store,
+                    &folder_entity,
+                    &file.file_id,
+                    Some(content_hash),
+                    &mut db,
+                )
+                .await
+            }
+            Err(_) => release_blob(&object_store, &folder_entity, content_hash, &mut db).await,
+        }
+    }
+    match result {
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while writing a batch of files to S3, the metadata or file versions you want to update don't match");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while writing a batch of files to S3: `{}`",
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => {
+            for file in &upload.files {
+                if let Err(e) = db::upsert_usage(
+                    &user_email,
+                    folder_id,
+                    &file.file_id,
+                    file.file.len(),
+                    &mut db,
+                )
+                .await
+                {
+                    log::error!(
+                        "Couldn't account the size of file `{}` in folder `{}`: `{}`",
+                        file.file_id,
+                        folder_id,
+                        e
+                    );
+                }
+                audit(
+                    folder_id,
+                    &user_email,
+                    AuditAction::FileUploaded,
+                    Some(&file.file_id),
+                    &mut db,
+                )
+                .await;
+            }
+            notify_members(
+                NotificationEvent::FileUploaded,
+                folder_id,
+                Ok(members),
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Created(Json(UploadFilesResponse {
+                etag,
+                version,
+                content_hashes,
+            }))
+        }
+    }
+}
+
+/// Delete a file from the cloud storage, moving it to the trash of the folder until it is restored or
+/// its retention window ends.
+/// The updated metadata of the folder, without the deleted file, is written together with the deletion.
+#[utoipa::path(
+    delete,
+    request_body(content = MetadataUpload, content_type = "multipart/form-data"),
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+    ),
+    responses(
+        (status = 200, description = "File deleted.", body = UploadFileResponse),
+        (status = 400, description = "Bad request: missing parent etag and version."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "File not found."),
+        (status = 409, description = "Conflict: the metadata version you want to update doesn't match."),
+        (status = 500, description = "Internal Server Error, couldn't delete the file"),
+    )
+)]
+#[delete("/folders/<folder_id>/files/<file_id>", data = "<metadata_upload>")]
+pub async fn delete_file(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    metadata_upload: Form<MetadataUpload<'_>>,
+    state: &State<SyncStore>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<UploadFileResponse> {
+    log::debug!(
+        "Received client certificate to delete file `{}` in folder with id `{}` with parameters `{:?}`.",
+        file_id,
+        folder_id,
+        metadata_upload,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    // Protect against metadata deletion.
+    if storage::is_metadata_file_name(file_id) {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    if metadata_upload.parent_etag.is_none() && metadata_upload.parent_version.is_none() {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "missing_parent_metadata",
+            "The parent etag or version of the metadata is required!",
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    let members = match db::list_emails_by_folder(folder_id, &mut db).await {
+        Ok(members) if members.contains(&user_email) => members,
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let folder_entity = FolderEntity { folder_id };
+    let deleted_at = storage::now_micros();
+    let object_store = state.lock().await;
+    let result = storage::delete(
+        &object_store,
+        DeleteInput {
+            folder_entity,
+            file_id,
+            deleted_at,
+            metadata_file: metadata_upload.metadata.to_vec(),
+            parent_etag: metadata_upload
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: metadata_upload
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+        },
+    )
+    .await;
+    match result {
+        Err(object_store::Error::NotFound { .. }) => {
+            log::debug!(
+                "File with id `{}` not found in folder `{}`",
+                file_id,
+                folder_id
+            );
+            SSFResponder::NotFound(ErrorBody::new("file_not_found", "File not found"))
+        }
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while deleting a file from S3, the metadata version you want to update doesn't match");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while deleting a file from S3: `{}`",
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => {
+            if let Err(e) = db::delete_usage(folder_id, file_id, &mut db).await {
+                log::error!(
+                    "Couldn't remove the size of file `{}` in folder `{}`: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            if let Err(e) = db::move_file_blob(folder_id, file_id, 0, deleted_at, &mut db).await {
+                log::error!(
+                    "Couldn't move the blob of file `{}` in folder `{}` to the trash: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            notify_members(
+                NotificationEvent::FileDeleted,
+                folder_id,
+                Ok(members),
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Ok(Json(UploadFileResponse {
+                etag,
+                version,
+                content_hash: None,
+            }))
+        }
+    }
+}
+
+/// List the files in the trash of a folder, that can be restored until their retention window ends.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    responses(
+        (status = 200, description = "The files in the trash.", body = ListTrashResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't list the trash"),
+    )
+)]
+#[get("/folders/<folder_id>/trash")]
+pub async fn list_trash(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    store: &State<SyncStore>,
+) -> SSFResponder<ListTrashResponse> {
+    log::debug!(
+        "Received client certificate to list the trash of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let folder = match get_folder_by_id(&user_email, folder_id, db).await {
+        Ok(folder) => folder,
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let store = store.lock().await;
+    match storage::list_trash(&store, &folder).await {
+        Ok(files) => SSFResponder::Ok(Json(ListTrashResponse {
+            files: files
+                .into_iter()
+                .map(|(file_id, deleted_at, meta)| TrashedFile {
+                    file_id,
+                    deleted_at,
+                    size: meta.size,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!("Couldn't list the trash from the object store: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Restore a file from the trash of a folder.
+/// The updated metadata of the folder, including the restored file, is written together with the restore.
+#[utoipa::path(
+    post,
+    request_body(content = FileRestore, content_type = "multipart/form-data"),
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+    ),
+    responses(
+        (status = 201, description = "File restored.", body = UploadFileResponse),
+        (status = 400, description = "Bad request: missing parent etag and version."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "File not found in the trash."),
+        (status = 409, description = "Conflict: the metadata version you want to update doesn't match, or a file with the same id exists."),
+        (status = 507, description = "Insufficient storage: the restore would exceed the storage quota."),
+        (status = 500, description = "Internal Server Error, couldn't restore the file"),
+    )
+)]
+#[post(
+    "/folders/<folder_id>/files/<file_id>/restore",
+    data = "<file_restore>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn restore_file(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    file_restore: Form<FileRestore<'_>>,
+    state: &State<SyncStore>,
+    quota: &State<QuotaConfig>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<UploadFileResponse> {
+    log::debug!(
+        "Received client certificate to restore file `{}` in folder with id `{}` with parameters `{:?}`.",
+        file_id,
+        folder_id,
+        file_restore,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    if storage::is_metadata_file_name(file_id) {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    if file_restore.parent_etag.is_none() && file_restore.parent_version.is_none() {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "missing_parent_metadata",
+            "The parent etag or version of the metadata is required!",
+        ));
+    }
+    let user_email = known_user.unwrap().user_email;
+    let members = match db::list_emails_by_folder(folder_id, &mut db).await {
+        Ok(members) if members.contains(&user_email) => members,
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let folder_entity = FolderEntity { folder_id };
+    let object_store = state.lock().await;
+    let trashed = match storage::list_trash(&object_store, &folder_entity).await {
+        Ok(files) => files.into_iter().find(|(trashed_id, deleted_at, _)| {
+            trashed_id == file_id && *deleted_at == file_restore.deleted_at
+        }),
+        Err(e) => {
+            log::error!("Couldn't list the trash from the object store: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let Some((_, _, meta)) = trashed else {
+        log::debug!(
+            "File with id `{}` not found in the trash of folder `{}`",
+            file_id,
+            folder_id
+        );
+        return SSFResponder::NotFound(ErrorBody::new("file_not_found", "File not found"));
+    };
+    let size = meta.size as u64;
+    if let Err(exceeded) =
+        check_quota(quota, &user_email, folder_id, &[file_id], size, &mut db).await
+    {
+        return exceeded;
+    }
+    let result = storage::restore(
+        &object_store,
+        RestoreInput {
+            folder_entity,
+            file_id,
+            deleted_at: file_restore.deleted_at,
+            metadata_file: file_restore.metadata.to_vec(),
+            parent_etag: file_restore
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: file_restore
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+        },
+    )
+    .await;
+    match result {
+        Err(object_store::Error::NotFound { .. }) => {
+            log::debug!(
+                "File with id `{}` not found in the trash of folder `{}`",
+                file_id,
+                folder_id
+            );
+            SSFResponder::NotFound(ErrorBody::new("file_not_found", "File not found"))
+        }
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while restoring a file, the metadata version doesn't match or the file exists");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while restoring a file from the trash: `{}`",
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => {
+            if let Err(e) = db::upsert_usage(&user_email, folder_id, file_id, size, &mut db).await {
+                log::error!(
+                    "Couldn't account the size of file `{}` in folder `{}`: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            if let Err(e) =
+                db::move_file_blob(folder_id, file_id, file_restore.deleted_at, 0, &mut db).await
+            {
+                log::error!(
+                    "Couldn't move the blob of file `{}` in folder `{}` back from the trash: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            notify_members(
+                NotificationEvent::FileUploaded,
+                folder_id,
+                Ok(members),
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Created(Json(UploadFileResponse {
+                etag,
+                version,
+                content_hash: None,
+            }))
+        }
+    }
+}
+
+/// Start a resumable upload of a file, to upload it in parts.
+/// Clients can resume an interrupted upload by checking the parts already uploaded with [`get_file_upload`].
+#[utoipa::path(
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+    ),
+    responses(
+        (status = 201, description = "Upload started.", body = FileUploadResponse),
+        (status = 400, description = "Bad request: invalid file id."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 500, description = "Internal Server Error, couldn't start the upload"),
+        (status = 501, description = "Resumable uploads are not supported by the storage."),
+    )
+)]
+#[post("/folders/<folder_id>/files/<file_id>/uploads")]
+pub async fn start_file_upload(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    multipart_store: &State<OptionalMultipartStore>,
+) -> SSFResponder<FileUploadResponse> {
+    log::debug!(
+        "Received client certificate to start the upload of file `{}` in folder with id `{}`.",
+        file_id,
+        folder_id,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    // Protect against metadata override.
+    if storage::is_metadata_file_name(file_id) {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    let Some(multipart_store) = multipart_store.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "resumable_uploads_unsupported",
+            "Resumable uploads are not supported",
+        ));
+    };
+    let user_email = known_user.unwrap().user_email;
+    match db::list_emails_by_folder(folder_id, &mut db).await {
+        Ok(members) if members.contains(&user_email) => {}
+        Ok(_) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let folder_entity = FolderEntity { folder_id };
+    let multipart_id = match storage::start_upload(multipart_store, &folder_entity, file_id).await {
+        Ok(multipart_id) => multipart_id,
+        Err(e) => {
+            log::error!(
+                "Couldn't start a multipart upload in the object store: `{}`",
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    match db::insert_file_upload(&user_email, folder_id, file_id, &multipart_id, &mut db).await {
+        Ok(upload_id) => SSFResponder::Created(Json(FileUploadResponse {
+            upload_id,
+            parts: vec![],
+        })),
+        Err(e) => {
+            log::error!("Couldn't store the upload in the DB: `{}`", e);
+            let _ = storage::abort_upload(multipart_store, &folder_entity, file_id, &multipart_id)
+                .await;
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Get a resumable upload of a file, with the parts already uploaded.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("upload_id", description = "Upload id."),
+    ),
+    responses(
+        (status = 200, description = "The upload.", body = FileUploadResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Upload not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the upload"),
+    )
+)]
+#[get("/folders/<folder_id>/files/<file_id>/uploads/<upload_id>")]
+pub async fn get_file_upload(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    upload_id: u64,
+) -> SSFResponder<FileUploadResponse> {
+    log::debug!(
+        "Received client certificate to get the upload `{}` of file `{}` in folder with id `{}`.",
+        upload_id,
+        file_id,
+        folder_id,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let upload = match db::get_file_upload(&user_email, upload_id, folder_id, file_id, &mut db)
+        .await
+    {
+        Ok(upload) => upload,
+        Err(sqlx::Error::RowNotFound) => {
+            return SSFResponder::NotFound(ErrorBody::new("upload_not_found", "Upload not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    match db::list_file_upload_parts(upload.upload_id, &mut db).await {
+        Ok(parts) => SSFResponder::Ok(Json(FileUploadResponse {
+            upload_id,
+            parts: parts.iter().map(|part| part.part_number).collect(),
+        })),
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload parts from the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Upload a part of a resumable upload. The parts are numbered from 0, and all of them but the last
+/// one must be at least 5 MiB. Uploading a part again replaces it.
+#[utoipa::path(
+    put,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("upload_id", description = "Upload id."),
+        ("part_number", description = "The part number, starting from 0."),
+    ),
+    request_body(content = [u8], content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Part uploaded."),
+        (status = 400, description = "Bad request: invalid part number."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Upload not found."),
+        (status = 413, description = "The part is too large."),
+        (status = 500, description = "Internal Server Error, couldn't upload the part"),
+        (status = 501, description = "Resumable uploads are not supported by the storage."),
+        (status = 507, description = "The storage quota of the user or of the folder is exceeded."),
+    )
+)]
+#[put(
+    "/folders/<folder_id>/files/<file_id>/uploads/<upload_id>/parts/<part_number>",
+    data = "<part>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file_part(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    upload_id: u64,
+    part_number: u32,
+    limits: &Limits,
+    part: Data<'_>,
+    multipart_store: &State<OptionalMultipartStore>,
+    quota: &State<QuotaConfig>,
+    bandwidth: &State<Bandwidth>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to upload part `{}` of upload `{}` in folder with id `{}`.",
+        part_number,
+        upload_id,
+        folder_id,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    if part_number >= MAX_UPLOAD_PARTS {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_part_number",
+            &format!("The part number must be lower than {}", MAX_UPLOAD_PARTS),
+        ));
+    }
+    let Some(multipart_store) = multipart_store.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "resumable_uploads_unsupported",
+            "Resumable uploads are not supported",
+        ));
+    };
+    let user_email = known_user.unwrap().user_email;
+    let upload = match db::get_file_upload(&user_email, upload_id, folder_id, file_id, &mut db)
+        .await
+    {
+        Ok(upload) => upload,
+        Err(sqlx::Error::RowNotFound) => {
+            return SSFResponder::NotFound(ErrorBody::new("upload_not_found", "Upload not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let limit = limits
+        .get("upload-part")
+        .unwrap_or(DEFAULT_UPLOAD_PART_LIMIT);
+    // Read one more byte than the limit, to tell a part of the maximum size from a larger one.
+    let mut part_reader = ThrottledReader::new(
+        part.open(limit + ByteUnit::Byte(1)),
+        bandwidth.upload(&user_email),
+    );
+    let mut part = vec![];
+    let part = match part_reader.read_to_end(&mut part).await {
+        Ok(_) if part.len() as u64 <= limit.as_u64() => part,
+        Ok(_) => {
+            return SSFResponder::PayloadTooLarge(ErrorBody::new(
+                "part_too_large",
+                &format!("The part exceeds the limit of {}", limit),
+            ))
+        }
+        Err(e) => {
+            log::error!("Couldn't read the uploaded part: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    // Account for the other parts of the same upload, as they will be part of the same file.
+    let other_parts_size = match db::list_file_upload_parts(upload.upload_id, &mut db).await {
+        Ok(parts) => parts
+            .iter()
+            .filter(|other| other.part_number != part_number)
+            .map(|other| other.size)
+            .sum::<u64>(),
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload parts from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let size = part.len() as u64;
+    if let Err(response) = check_quota(
+        quota,
+        &user_email,
+        folder_id,
+        &[file_id],
+        other_parts_size + size,
+        &mut db,
+    )
+    .await
+    {
+        return response;
+    }
+    let folder_entity = FolderEntity { folder_id };
+    let part_id = match storage::upload_part(
+        multipart_store,
+        &folder_entity,
+        file_id,
+        &upload.multipart_id,
+        part_number,
+        part,
+    )
+    .await
+    {
+        Ok(part_id) => part_id,
+        Err(e) => {
+            log::error!("Couldn't upload the part to the object store: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    match db::insert_file_upload_part(
+        upload.upload_id,
+        part_number,
+        &part_id.content_id,
+        size,
+        &mut db,
+    )
+    .await
+    {
+        Ok(()) => SSFResponder::EmptyOk("Part uploaded".to_string()),
+        Err(e) => {
+            log::error!("Couldn't store the upload part in the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
+}
+
+/// Complete a resumable upload, once all the parts are uploaded.
+/// The metadata of the folder is updated as for [`upload_file`].
+#[utoipa::path(
+    post,
+    request_body(content = MetadataUpload, content_type = "multipart/form-data"),
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("upload_id", description = "Upload id."),
+    ),
+    responses(
+        (status = 201, description = "File uploaded.", body = UploadFileResponse),
+        (status = 400, description = "Bad request: some parts are missing."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Upload not found."),
+        (status = 409, description = "Conflict: the metadata version you want to update doesn't match."),
+        (status = 500, description = "Internal Server Error, couldn't complete the upload"),
+        (status = 501, description = "Resumable uploads are not supported by the storage."),
+    )
+)]
+#[post(
+    "/folders/<folder_id>/files/<file_id>/uploads/<upload_id>/complete",
+    data = "<metadata_upload>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_file_upload(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    upload_id: u64,
+    metadata_upload: Form<MetadataUpload<'_>>,
+    state: &State<SyncStore>,
+    multipart_store: &State<OptionalMultipartStore>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> SSFResponder<UploadFileResponse> {
+    log::debug!(
+        "Received client certificate to complete the upload `{}` in folder with id `{}` with parameters `{:?}`.",
+        upload_id,
+        folder_id,
+        metadata_upload,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let Some(multipart_store) = multipart_store.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "resumable_uploads_unsupported",
+            "Resumable uploads are not supported",
+        ));
+    };
+    let user_email = known_user.unwrap().user_email;
+    let upload = match db::get_file_upload(&user_email, upload_id, folder_id, file_id, &mut db)
+        .await
+    {
+        Ok(upload) => upload,
+        Err(sqlx::Error::RowNotFound) => {
+            return SSFResponder::NotFound(ErrorBody::new("upload_not_found", "Upload not found"))
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let parts = match db::list_file_upload_parts(upload.upload_id, &mut db).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            log::error!("Couldn't retrieve the upload parts from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    if parts.is_empty()
+        || parts
+            .iter()
+            .enumerate()
+            .any(|(i, part)| part.part_number as usize != i)
+    {
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "missing_upload_parts",
+            "Some parts of the file are missing!",
+        ));
+    }
+    let size = parts.iter().map(|part| part.size).sum::<u64>();
+    let members = db::list_emails_by_folder(folder_id, &mut db).await;
+    let object_store = state.lock().await;
+    let result = storage::complete_upload(
+        &object_store,
+        multipart_store,
+        CompleteUploadInput {
+            folder_entity: FolderEntity { folder_id },
+            file_id,
+            multipart_id: upload.multipart_id,
+            parts: parts
+                .into_iter()
+                .map(|part| PartId {
+                    content_id: part.content_id,
+                })
+                .collect(),
+            metadata_file: metadata_upload.metadata.to_vec(),
+            parent_etag: metadata_upload
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: metadata_upload
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+        },
+    )
+    .await;
+    match result {
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while completing an upload to S3, the metadata version you want to update doesn't match");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while completing an upload to S3: `{}`",
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => {
+            if let Err(e) = db::delete_file_upload(upload.upload_id, &mut db).await {
+                log::error!(
+                    "Couldn't remove the completed upload `{}` from the DB: `{}`",
+                    upload.upload_id,
+                    e
+                );
+            }
+            // The file is not deduplicated anymore, release the blob of its previous version.
+            replace_file_blob(
+                &object_store,
+                &FolderEntity { folder_id },
+                file_id,
+                None,
+                &mut db,
+            )
+            .await;
+            if let Err(e) = db::upsert_usage(&user_email, folder_id, file_id, size, &mut db).await {
+                log::error!(
+                    "Couldn't account the size of file `{}` in folder `{}`: `{}`",
+                    file_id,
+                    folder_id,
+                    e
+                );
+            }
+            audit(
+                folder_id,
+                &user_email,
+                AuditAction::FileUploaded,
+                Some(file_id),
+                &mut db,
+            )
+            .await;
+            notify_members(
+                NotificationEvent::FileUploaded,
+                folder_id,
+                members,
+                &user_email,
+                sse_queue,
+            )
+            .await;
+            SSFResponder::Created(Json(UploadFileResponse {
+                etag,
+                version,
+                content_hash: None,
+            }))
+        }
+    }
+}
+
+/// Abort a resumable upload, removing the parts already uploaded.
+#[utoipa::path(
+    delete,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("file_id", description = "File identifier."),
+        ("upload_id", description = "Upload id."),
+    ),
+    responses(
+        (status = 200, description = "Upload aborted."),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Upload not found."),
+        (status = 500, description = "Internal Server Error, couldn't abort the upload"),
+        (status = 501, description = "Resumable uploads are not supported by the storage."),
+    )
+)]
+#[delete("/folders/<folder_id>/files/<file_id>/uploads/<upload_id>")]
+pub async fn abort_file_upload(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    file_id: &str,
+    upload_id: u64,
+    multipart_store: &State<OptionalMultipartStore>,
+) -> SSFResponder<EmptyResponse> {
+    log::debug!(
+        "Received client certificate to abort the upload `{}` in folder with id `{}`.",
+        upload_id,
+        folder_id,
+    );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
+    let Some(multipart_store) = multipart_store.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "resumable_uploads_unsupported",
+            "Resumable uploads are not supported",
+        ));
+    };
     let user_email = known_user.unwrap().user_email;
-    let folder = match get_folder_by_id(&user_email, folder_id, db).await {
-        Ok(folder) => folder,
+    let upload = match db::get_file_upload(&user_email, upload_id, folder_id, file_id, &mut db)
+        .await
+    {
+        Ok(upload) => upload,
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found for user `{}`", folder_id, user_email);
-            return SSFResponder::Unauthorized("This user doesn't have access to the requested folder".to_string());
+            return SSFResponder::NotFound(ErrorBody::new("upload_not_found", "Upload not found"))
         }
         Err(e) => {
-            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
-            return SSFResponder::InternalServerError("Internal Server Error".to_string());
+            log::error!("Couldn't retrieve the upload from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
     };
-    let store = store.lock().await;
-    let file = match storage::read_file(&store, &folder, file_id).await {
-        Ok(file) => file,
+    let folder_entity = FolderEntity { folder_id };
+    match storage::abort_upload(
+        multipart_store,
+        &folder_entity,
+        file_id,
+        &upload.multipart_id,
+    )
+    .await
+    {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
         Err(e) => {
-            match e {
-                object_store::Error::NotFound { path: _, source: _} => {
-                    log::debug!("File with id `{}` not found in folder `{}`", file_id, folder_id);
-                    return SSFResponder::NotFound("File not found".to_string());
-                },
-                _ => {
-                    log::error!("Couldn't retrieve the file from the object store: `{}`", e);
-                    return SSFResponder::InternalServerError("Internal Server Error".to_string());
-                }
-            }
+            log::error!(
+                "Couldn't abort the multipart upload in the object store: `{}`",
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
-    };
-    SSFResponder::Ok(Json(FolderFileResponse {
-        file: file.0,
-        etag: file.1.e_tag,
-        version: file.1.version,
-    }))
+    }
+    match db::delete_file_upload(upload.upload_id, &mut db).await {
+        Ok(()) => SSFResponder::EmptyOk("Upload aborted".to_string()),
+        Err(e) => {
+            log::error!("Couldn't remove the upload from the DB: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+    }
 }
 
-/// Upload a file to the cloud storage.
+/// Issue a short-lived presigned URL to download or upload a file directly from the object store, bypassing the DS.
+/// An upload through a presigned URL doesn't update the metadata of the folder, which must be
+/// written separately with [`post_metadata`] using the usual conditional update.
+/// It also bypasses the deduplication of the content: if the file was uploaded through the DS, its previous
+/// content stays referenced until the file is replaced through the DS or deleted.
+/// The uploads are refused when the storage quotas are enforced, as their size is neither checked nor accounted.
 #[utoipa::path(
-    post,
-    request_body(content = Upload, content_type = "multipart/form-data"),
+    get,
     params(
         ("folder_id", description = "Folder id."),
         ("file_id", description = "File identifier."),
+        ("op" = PresignOperation, Query, description = "The operation to allow, `get` or `put`."),
     ),
     responses(
-        (status = 201, description = "File uploaded."),
+        (status = 200, description = "The presigned URL.", body = PresignedUrlResponse),
+        (status = 400, description = "Bad request: invalid file id."),
         (status = 401, description = "Unkwown or unauthorized user."),
-        (status = 404, description = "Folder not found."),
-        (status = 500, description = "Internal Server Error, couldn't retrieve the file"),
+        (status = 403, description = "Uploads through presigned URLs are disabled by the storage quotas."),
+        (status = 500, description = "Internal Server Error, couldn't presign the URL"),
+        (status = 501, description = "Presigned URLs are not supported by the storage."),
     )
 )]
-#[post("/folders/<folder_id>/files/<file_id>", data = "<upload>")]
-pub async fn upload_file(
-    client_certificate: CertificateWithEmails<'_>,
+#[get("/folders/<folder_id>/files/<file_id>/presign?<op>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn presign_file(
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
     file_id: &str,
-    upload: Form<Upload<'_>>,
-    state: &State<SyncStore>
-) -> SSFResponder<UploadFileResponse>  {
+    op: PresignOperation,
+    signer: &State<OptionalSigner>,
+    state: &State<SyncStore>,
+    quota: &State<QuotaConfig>,
+) -> SSFResponder<PresignedUrlResponse> {
     log::debug!(
-        "Received client certificate to upload a file in folder with id `{}` with parameters `{:?}`.",
+        "Received client certificate to presign `{:?}` for file `{}` in folder with id `{}`.",
+        op,
+        file_id,
         folder_id,
-        upload,
     );
     let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
     if let Err(unauthorized) = known_user {
         return unauthorized;
     }
-    // Protect against metadata override.
+    // Protect against metadata override, which must go through the conditional update.
     if storage::is_metadata_file_name(file_id) {
-        return SSFResponder::BadRequest("The file_id is invalid!".to_string());
+        return SSFResponder::BadRequest(ErrorBody::new(
+            "invalid_file_id",
+            "The file_id is invalid!",
+        ));
+    }
+    let Some(signer) = signer.inner() else {
+        return SSFResponder::NotImplemented(ErrorBody::new(
+            "presigned_urls_unsupported",
+            "Presigned URLs are not supported",
+        ));
+    };
+    if op == PresignOperation::Put && quota.is_enforced() {
+        return SSFResponder::Forbidden(ErrorBody::new("presigned_upload_disabled", "Uploads through presigned URLs bypass the storage quotas, upload the file through the DS."));
     }
     let user_email = known_user.unwrap().user_email;
     let folder_entity = match get_folder_by_id(&user_email, folder_id, db).await {
         Ok(folder) => folder,
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found for user `{}`", folder_id, user_email);
-            return SSFResponder::Unauthorized("This user doesn't have access to the requested folder".to_string());
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
         }
         Err(e) => {
             log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
-            return SSFResponder::InternalServerError("Internal Server Error".to_string());
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
     };
-    let object_store = state.lock().await;
-    let result = storage::write(&object_store, WriteInput {
-        folder_entity,
-        file_id, 
-        file_to_write: Some(upload.file.to_vec()),
-        metadata_file: upload.metadata.to_vec(),
-        parent_etag: upload.parent_etag.clone().map(|etag| etag.trim().to_string()),
-        parent_version: upload.parent_version.clone().map(|version| version.trim().to_string()),
-    }).await;
-    match result {
-        Err(object_store::Error::Precondition {..} | object_store::Error::AlreadyExists {..})  => {
-            log::debug!("Precondition failed while writing a file to S3, the metadata version you want to update doesn't match");
-            SSFResponder::Conflict("Precondition failed".to_string())
-        },
+    let (method, blob) = match op {
+        PresignOperation::Get => {
+            // The content of a deduplicated file is downloaded from its blob.
+            let object_store = state.lock().await;
+            match storage::get_file_blob(&object_store, &folder_entity, file_id).await {
+                Ok(blob) => (http::Method::GET, blob),
+                Err(object_store::Error::NotFound { .. }) => (http::Method::GET, None),
+                Err(e) => {
+                    log::error!(
+                        "Couldn't read file `{}` to presign the URL: `{}`",
+                        file_id,
+                        e
+                    );
+                    return SSFResponder::InternalServerError(ErrorBody::new(
+                        "internal_error",
+                        "Internal Server Error",
+                    ));
+                }
+            }
+        }
+        PresignOperation::Put => (http::Method::PUT, None),
+    };
+    match storage::presign(signer, &folder_entity, file_id, blob.as_deref(), method).await {
+        Ok(url) => SSFResponder::Ok(Json(PresignedUrlResponse {
+            url,
+            expires_in: storage::PRESIGNED_URL_EXPIRATION.as_secs(),
+        })),
         Err(e) => {
-            log::error!("Internal server error while writing a file to S3: `{}`", e.to_string());
-            SSFResponder::InternalServerError("".to_string())
-        },
-        Ok((etag, version)) => {
-            SSFResponder::Created(Json(UploadFileResponse {
-               etag, version 
-            }))
+            log::error!("Couldn't presign the URL: `{}`", e);
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
         }
     }
-
 }
 
 /// Get the metadata of a folder. The metadata contain the list of files and their metadata.
+/// The metadata is not sent again if it still has the etag in the `If-None-Match` header.
 #[utoipa::path(
     get,
     params(
         ("folder_id", description = "Folder id."),
+        ("If-None-Match" = Option<String>, Header, description = "The etag of the metadata already known by the client."),
+        ("If-Match" = Option<String>, Header, description = "The etag the metadata is expected to have."),
+        ("Authorization" = Option<String>, Header, description = "`Bearer <token>` of a share link of the folder, to read it without a client certificate."),
     ),
     responses(
         (status = 200, description = "The requested folder's metadata.", body = FolderFileResponse),
+        (status = 304, description = "The metadata didn't change."),
         (status = 401, description = "Unkwown or unauthorized user."),
         (status = 404, description = "File not found."),
+        (status = 412, description = "The metadata doesn't have the expected etag."),
         (status = 500, description = "Internal Server Error, couldn't retrieve the file"),
     )
 )]
 #[get("/folders/<folder_id>/metadatas")]
 pub async fn get_metadata(
-    client_certificate: CertificateWithEmails<'_>,
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
+    conditions: EtagConditions,
     store: &State<SyncStore>,
 ) -> SSFResponder<FolderFileResponse> {
     log::debug!(
@@ -1266,44 +6155,196 @@ pub async fn get_metadata(
     let folder = match get_folder_by_id(&user_email, folder_id, db).await {
         Ok(folder) => folder,
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found for user `{}`", folder_id, user_email);
-            return SSFResponder::Unauthorized("This user doesn't have access to the requested folder".to_string());
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
         }
         Err(e) => {
             log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
-            return SSFResponder::InternalServerError("Internal Server Error".to_string());
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
     };
+    read_metadata(store, &folder, conditions).await
+}
+
+/// Get the metadata of a folder with a share link, passed as a bearer token instead of a client certificate.
+#[get("/folders/<folder_id>/metadatas", rank = 2)]
+pub async fn get_metadata_with_link(
+    token: ShareLinkToken,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    conditions: EtagConditions,
+    store: &State<SyncStore>,
+) -> SSFResponder<FolderFileResponse> {
+    log::debug!(
+        "Received share link to read the metadata of folder with id `{}`",
+        folder_id
+    );
+    if let Err(unauthorized) = authorize_share_link(&token, folder_id, &mut db).await {
+        return unauthorized;
+    }
+    read_metadata(store, &FolderEntity { folder_id }, conditions).await
+}
+
+/// Read the metadata of the folder if it satisfies the conditions.
+async fn read_metadata(
+    store: &State<SyncStore>,
+    folder: &FolderEntity,
+    conditions: EtagConditions,
+) -> SSFResponder<FolderFileResponse> {
     let store = store.lock().await;
-    let metadata = match storage::read_metadata(&store, &folder).await {
+    let metadata = match storage::read_metadata_if(
+        &store,
+        folder,
+        conditions.if_match,
+        conditions.if_none_match,
+    )
+    .await
+    {
         Ok(metadata) => metadata,
-        Err(e) => {
-            match e {
-                object_store::Error::NotFound { path: _, source: _} => {
-                    log::debug!("Metadata not found in folder `{}`", folder_id);
-                    return SSFResponder::NotFound("Metadata not found".to_string());
-                },
-                _ => {
-                    log::error!("Couldn't retrieve the metadata from the object store: `{}`", e);
-                    return SSFResponder::InternalServerError("Internal Server Error".to_string());
-                }
+        Err(e) => match e {
+            object_store::Error::NotFound { path: _, source: _ } => {
+                log::debug!("Metadata not found in folder `{}`", folder.folder_id);
+                return SSFResponder::NotFound(ErrorBody::new(
+                    "metadata_not_found",
+                    "Metadata not found",
+                ));
             }
-        }
+            object_store::Error::NotModified { path: _, source: _ } => {
+                log::debug!("Metadata not modified in folder `{}`", folder.folder_id);
+                return SSFResponder::NotModified(());
+            }
+            object_store::Error::Precondition { path: _, source: _ } => {
+                return SSFResponder::PreconditionFailed(ErrorBody::new(
+                    "metadata_etag_mismatch",
+                    "The metadata doesn't have the expected etag.",
+                ));
+            }
+            _ => {
+                log::error!(
+                    "Couldn't retrieve the metadata from the object store: `{}`",
+                    e
+                );
+                return SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ));
+            }
+        },
     };
     SSFResponder::Ok(Json(FolderFileResponse {
+        content_hash: Some(storage::content_hash(&metadata.0)),
         file: metadata.0,
         etag: metadata.1.e_tag,
         version: metadata.1.version,
     }))
 }
 
-
+/// List the previous versions of the metadata of a folder, one page at a time in chronological order.
+/// Only the members of the folder can list them.
+#[utoipa::path(
+    get,
+    params(
+        ("folder_id", description = "Folder id."),
+        ("limit" = Option<u32>, Query, description = "The maximum number of versions to return, 100 by default."),
+        ("cursor" = Option<u64>, Query, description = "The `next_cursor` returned with the previous page."),
+    ),
+    responses(
+        (status = 200, description = "The versions of the metadata.", body = ListMetadataVersionsResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder not found."),
+        (status = 500, description = "Internal Server Error, couldn't retrieve the versions"),
+    )
+)]
+#[get("/folders/<folder_id>/metadatas/versions?<limit>&<cursor>")]
+pub async fn list_metadata_versions(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    limit: Option<u32>,
+    cursor: Option<u64>,
+    store: &State<SyncStore>,
+) -> SSFResponder<ListMetadataVersionsResponse> {
+    log::debug!(
+        "Received client certificate to list the metadata versions of folder with id `{}`",
+        folder_id
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    match db::get_folder_role(&user_email, folder_id, &mut db).await {
+        Ok(_) => (),
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    }
+    let limit = page_limit(limit) as usize;
+    let store = store.lock().await;
+    let mut versions =
+        match storage::list_metadata_versions(&store, &FolderEntity { folder_id }, cursor).await {
+            Ok(versions) => versions,
+            Err(e) => {
+                log::error!(
+                    "Couldn't list the metadata versions of folder `{}`: `{}`",
+                    folder_id,
+                    e
+                );
+                return SSFResponder::InternalServerError(ErrorBody::new(
+                    "internal_error",
+                    "Internal Server Error",
+                ));
+            }
+        };
+    let next_cursor = if versions.len() > limit {
+        versions.truncate(limit);
+        versions.last().map(|(version_id, _)| *version_id)
+    } else {
+        None
+    };
+    SSFResponder::Ok(Json(ListMetadataVersionsResponse {
+        versions: versions
+            .into_iter()
+            .map(|(version_id, meta)| MetadataVersion {
+                id: version_id,
+                etag: meta.e_tag,
+                version: meta.version,
+                size: meta.size,
+                timestamp: meta.last_modified.timestamp() as u64,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
 
 /// Upload a new version of the metadata of a folder. The metadata contain the list of files and their metadata.
 #[utoipa::path(
     post,
     params(
         ("folder_id", description = "Folder id."),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key chosen by the client, the retries with the same key get the outcome of the first request."),
     ),
     request_body(content = MetadataUpload, content_type = "multipart/form-data"),
     responses(
@@ -1315,10 +6356,10 @@ pub async fn get_metadata(
 )]
 #[post("/folders/<folder_id>/metadatas", data = "<metadata_upload>")]
 pub async fn post_metadata(
-    client_certificate: CertificateWithEmails<'_>,
+    client_certificate: SessionOrCertificate<'_>,
     mut db: Connection<DbConn>,
     folder_id: u64,
-    metadata_upload: Form<MetadataUpload<'_>>,
+    metadata_upload: Idempotent<Form<MetadataUpload<'_>>>,
     state: &State<SyncStore>,
 ) -> SSFResponder<UploadFileResponse> {
     log::debug!(
@@ -1334,46 +6375,218 @@ pub async fn post_metadata(
     let folder_entity = match get_folder_by_id(&user_email, folder_id, db).await {
         Ok(folder) => folder,
         Err(sqlx::Error::RowNotFound) => {
-            log::debug!("Folder with id `{}` not found for user `{}`", folder_id, user_email);
-            return SSFResponder::Unauthorized("This user doesn't have access to the requested folder".to_string());
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::Unauthorized(ErrorBody::new(
+                "folder_access_denied",
+                "This user doesn't have access to the requested folder",
+            ));
         }
         Err(e) => {
             log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
-            return SSFResponder::InternalServerError("Internal Server Error".to_string());
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
         }
     };
     let object_store = state.lock().await;
-    let result = storage::write(&object_store, WriteInput {
-        folder_entity,
-        file_id: "", // Ignored since file to write is None.
-        file_to_write: None,
-        metadata_file: metadata_upload.metadata.to_vec(),
-        parent_etag: metadata_upload.parent_etag.clone().map(|etag| etag.trim().to_string()),
-        parent_version: metadata_upload.parent_version.clone().map(|version| version.trim().to_string()),
-    }).await;
+    let result = storage::write(
+        &object_store,
+        WriteInput {
+            folder_entity,
+            file_id: "", // Ignored since file to write is None.
+            file_to_write: None,
+            metadata_file: metadata_upload.metadata.to_vec(),
+            parent_etag: metadata_upload
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: metadata_upload
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+            file_parent_etag: None,
+        },
+    )
+    .await;
     match result {
-        Err(object_store::Error::Precondition {..} | object_store::Error::AlreadyExists {..})  => {
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
             log::debug!("Precondition failed while writing metadata to S3, the metadata version you want to update doesn't match");
-            SSFResponder::Conflict("Precondition failed".to_string())
-        },
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while writing a file to S3: `{}`",
+                e.to_string()
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
+        Ok((etag, version)) => SSFResponder::Created(Json(UploadFileResponse {
+            etag,
+            version,
+            content_hash: None,
+        })),
+    }
+}
+
+/// Restore a previous version of the metadata of a folder, publishing it again as the new metadata.
+/// As for any other update, the current etag or version must be given and still match.
+#[utoipa::path(
+    post,
+    params(
+        ("folder_id", description = "Folder id."),
+    ),
+    request_body(content = MetadataRollback, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Metadata version restored.", body = UploadFileResponse),
+        (status = 401, description = "Unkwown or unauthorized user."),
+        (status = 404, description = "Folder or version not found."),
+        (status = 409, description = "The current metadata doesn't match the given etag or version."),
+        (status = 500, description = "Internal Server Error, couldn't restore the version"),
+    )
+)]
+#[post("/folders/<folder_id>/metadatas/rollback", data = "<rollback>")]
+pub async fn rollback_metadata(
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    folder_id: u64,
+    rollback: Form<MetadataRollback>,
+    state: &State<SyncStore>,
+) -> SSFResponder<UploadFileResponse> {
+    log::debug!(
+        "Received client certificate to roll back the metadata of folder with id `{}` with parameters `{:?}`.",
+        folder_id,
+        rollback,
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await;
+    if let Err(unauthorized) = known_user {
+        return unauthorized;
+    }
+    let user_email = known_user.unwrap().user_email;
+    let folder_entity = match get_folder_by_id(&user_email, folder_id, db).await {
+        Ok(folder) => folder,
+        Err(sqlx::Error::RowNotFound) => {
+            log::debug!(
+                "Folder with id `{}` not found for user `{}`",
+                folder_id,
+                user_email
+            );
+            return SSFResponder::NotFound(ErrorBody::new("folder_not_found", "Folder not found"));
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the folder from the DB: `{}`", e);
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let object_store = state.lock().await;
+    let metadata_file = match storage::read_metadata_from_history(
+        &object_store,
+        &folder_entity,
+        rollback.version_id,
+    )
+    .await
+    {
+        Ok(metadata_file) => metadata_file,
+        Err(object_store::Error::NotFound { .. }) => {
+            log::debug!(
+                "Metadata version `{}` not found in folder `{}`",
+                rollback.version_id,
+                folder_id
+            );
+            return SSFResponder::NotFound(ErrorBody::new(
+                "version_not_found",
+                "Version not found",
+            ));
+        }
         Err(e) => {
-            log::error!("Internal server error while writing a file to S3: `{}`", e.to_string());
-            SSFResponder::InternalServerError("".to_string())
+            log::error!(
+                "Couldn't read the metadata version `{}` of folder `{}`: `{}`",
+                rollback.version_id,
+                folder_id,
+                e
+            );
+            return SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ));
+        }
+    };
+    let result = storage::write(
+        &object_store,
+        WriteInput {
+            folder_entity,
+            file_id: "", // Ignored since file to write is None.
+            file_to_write: None,
+            metadata_file,
+            parent_etag: rollback
+                .parent_etag
+                .clone()
+                .map(|etag| etag.trim().to_string()),
+            parent_version: rollback
+                .parent_version
+                .clone()
+                .map(|version| version.trim().to_string()),
+            file_parent_etag: None,
         },
+    )
+    .await;
+    match result {
+        Err(
+            object_store::Error::Precondition { .. } | object_store::Error::AlreadyExists { .. },
+        ) => {
+            log::debug!("Precondition failed while restoring the metadata, the metadata version you want to replace doesn't match");
+            SSFResponder::Conflict(ErrorBody::new("precondition_failed", "Precondition failed"))
+        }
+        Err(e) => {
+            log::error!(
+                "Internal server error while restoring the metadata: `{}`",
+                e
+            );
+            SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            ))
+        }
         Ok((etag, version)) => {
+            log::info!(
+                "User `{}` restored the metadata version `{}` of folder `{}`",
+                user_email,
+                rollback.version_id,
+                folder_id
+            );
             SSFResponder::Created(Json(UploadFileResponse {
-               etag, version 
+                etag,
+                version,
+                content_hash: None,
             }))
         }
     }
 }
 
-
 /// Push notifications using server sent events.
-/// The notification sends the folder_id of the folder where an event occurred, so that the client can fetch the new state.
-// This mechanism can be enhanced with more information. Let's keep it simple for now.
+/// Each event is a JSON encoded [`Notification`], describing what happened and in which folder,
+/// so that the client can fetch the new state.
+/// Reconnecting clients sending the `Last-Event-ID` header receive first the events they missed.
 #[get("/notifications")]
-pub async fn sse<'a>(mut shutdown: Shutdown, client_certificate: CertificateWithEmails<'_>,  mut db: Connection<DbConn>, sse_queue: &'a State<SenderSentEventQueue>) -> EventStream![Event + 'a] {
+pub async fn sse<'a>(
+    mut shutdown: Shutdown,
+    client_certificate: SessionOrCertificate<'_>,
+    mut db: Connection<DbConn>,
+    last_event_id: LastEventId,
+    sse_queue: &'a State<SenderSentEventQueue>,
+) -> EventStream![Event + 'a] {
     log::debug!(
         "Received client certificate to register for notifications with emails: {}.",
         client_certificate.emails.join(","),
@@ -1383,23 +6596,25 @@ pub async fn sse<'a>(mut shutdown: Shutdown, client_certificate: CertificateWith
         match user {
             Ok(known_user) => {
                 log::debug!("The user is found: {}, registering for SSE.", known_user.user_email);
-                let mut rx = sse_queue.subscribe();
+                // The notifications missed since the `Last-Event-ID` are received first.
+                let mut rx = sse_queue.subscribe_after(&known_user.user_email, last_event_id.0);
                 loop {
                     let msg = select! {
                         msg = rx.recv() => match msg {
-                            Ok(msg) if msg.receiver == known_user.user_email => msg.folder_id,
-                            Ok(_) => continue,
-                            Err(RecvError::Closed) => {
+                            Some(msg) => msg,
+                            None => {
                                 log::debug!("SSE Closing stream");
                                 break
                             },
-                            Err(RecvError::Lagged(_)) => continue,
                         },
-                        _ = &mut shutdown => break,
+                        _ = &mut shutdown => {
+                            log::debug!("SSE Closing stream on shutdown");
+                            yield Event::json(&Notification::server_shutdown(&known_user.user_email));
+                            break
+                        },
                     };
                     log::debug!("SSE Notification: {:?}", msg);
-                    // -1 indicates that a key package has been consumed.
-                    yield Event::data(msg.map_or("-1".to_string(), |folder_id| folder_id.to_string()));
+                    yield Event::json(&msg).id(msg.id.to_string());
                 }
             },
             Err(_) => {
@@ -1410,15 +6625,168 @@ pub async fn sse<'a>(mut shutdown: Shutdown, client_certificate: CertificateWith
     }
 }
 
+/// Push notifications using a WebSocket, as an alternative to [`sse`].
+/// The client is authenticated once, when opening the connection, using its TLS client certificate.
+/// The messages carry the same content as the server sent events.
+#[get("/ws/notifications")]
+pub async fn ws_notifications(
+    shutdown: Shutdown,
+    client_certificate: CertificateWithEmails<'_>,
+    mut db: Connection<DbConn>,
+    sse_queue: &State<SenderSentEventQueue>,
+) -> Result<NotificationsWebSocket, SSFResponder<EmptyResponse>> {
+    log::debug!(
+        "Received client certificate to register for WebSocket notifications with emails: {}.",
+        client_certificate.emails.join(","),
+    );
+    let known_user = get_known_user_or_unauthorized(client_certificate, &mut db).await?;
+    log::debug!(
+        "The user is found: {}, registering for WebSocket notifications.",
+        known_user.user_email
+    );
+    Ok(NotificationsWebSocket::new(
+        sse_queue.subscribe(&known_user.user_email),
+        shutdown,
+    ))
+}
 
-async fn send_see(folder_id: Option<u64>, email: &str, sse_queue: &State<SenderSentEventQueue>) {
+async fn send_see(
+    event: NotificationEvent,
+    folder_id: Option<u64>,
+    message_id: Option<u64>,
+    email: &str,
+    sse_queue: &State<SenderSentEventQueue>,
+) {
     let notification = Notification {
+        id: 0,
+        event,
         folder_id,
+        message_id,
         receiver: email.to_owned(),
     };
-    let result = sse_queue.send(notification);
-    if let Err(e) = result {
-        log::debug!("Error while trying to send the notification: {:?}", e);
+    if sse_queue.send(notification) == 0 {
+        log::debug!(
+            "No connected client of `{}` to receive the `{:?}` notification",
+            email,
+            event
+        );
+    }
+}
+
+/// Append an event to the audit log of the folder.
+/// A failure is only logged, so that the request is not failed once its effects are persisted.
+async fn audit(
+    folder_id: u64,
+    actor_email: &str,
+    action: AuditAction,
+    target: Option<&str>,
+    db: &mut Connection<DbConn>,
+) {
+    if let Err(e) = db::insert_audit_event(folder_id, actor_email, action, target, db).await {
+        log::error!(
+            "Couldn't record `{:?}` by `{}` in the audit log of folder `{}`: `{}`",
+            action,
+            actor_email,
+            folder_id,
+            e
+        );
+    }
+}
+
+/// Drop a reference to a blob of the folder, deleting its content once no file points to it anymore.
+/// A failure is only logged: the blob is kept, and deleted together with the folder at the latest.
+pub(crate) async fn release_blob(
+    object_store: &MutexGuard<'_, DynamicStore>,
+    folder_entity: &FolderEntity,
+    content_hash: &str,
+    db: &mut sqlx::AnyConnection,
+) {
+    match db::release_blob(folder_entity.folder_id, content_hash, db).await {
+        Ok(true) => {
+            if let Err(e) = storage::delete_blob(object_store, folder_entity, content_hash).await {
+                log::error!(
+                    "Couldn't delete the blob `{}` of folder `{}`: `{}`",
+                    content_hash,
+                    folder_entity.folder_id,
+                    e
+                );
+            }
+        }
+        Ok(false) => {}
+        Err(e) => log::error!(
+            "Couldn't release the blob `{}` of folder `{}`: `{}`",
+            content_hash,
+            folder_entity.folder_id,
+            e
+        ),
+    }
+}
+
+/// Point the written file to the blob with the given content hash, or to no blob, and release the blob it pointed to.
+/// A failure is only logged: the blobs are kept, and deleted together with the folder at the latest.
+async fn replace_file_blob(
+    object_store: &MutexGuard<'_, DynamicStore>,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    content_hash: Option<&str>,
+    db: &mut sqlx::AnyConnection,
+) {
+    match db::replace_file_blob(folder_entity.folder_id, file_id, content_hash, db).await {
+        Ok(Some(previous)) => release_blob(object_store, folder_entity, &previous, db).await,
+        Ok(None) => {}
+        Err(e) => log::error!(
+            "Couldn't record the blob of file `{}` in folder `{}`: `{}`",
+            file_id,
+            folder_entity.folder_id,
+            e
+        ),
+    }
+}
+
+/// Notify all the members of a folder, apart from the sender, about a change in the folder.
+async fn notify_members(
+    event: NotificationEvent,
+    folder_id: u64,
+    members: Result<Vec<String>, sqlx::Error>,
+    sender: &str,
+    sse_queue: &State<SenderSentEventQueue>,
+) {
+    match members {
+        Ok(members) => {
+            for member in members.iter().filter(|member| *member != sender) {
+                // If the send fails, it just means that the client is not online, they will fetch the new state upon initialisation.
+                send_see(event, Some(folder_id), None, member, sse_queue).await;
+            }
+        }
+        Err(e) => log::error!(
+            "Couldn't notify the members of folder `{}` about `{:?}`: `{}`",
+            folder_id,
+            event,
+            e
+        ),
+    }
+}
+
+/// The `If-Match` and `If-None-Match` headers of a conditional request, compared with the etag of the object.
+/// Lets the clients skip downloading content they already have.
+#[derive(Debug, Default)]
+pub struct EtagConditions {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for EtagConditions {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(EtagConditions {
+            if_match: headers.get_one("If-Match").map(|etag| etag.to_string()),
+            if_none_match: headers
+                .get_one("If-None-Match")
+                .map(|etag| etag.to_string()),
+        })
     }
 }
 
@@ -1426,8 +6794,11 @@ async fn send_see(folder_id: Option<u64>, email: &str, sse_queue: &State<SenderS
 /// If no emails are found in the Certificate, send back an [`Status::Unauthorized`] request.    
 /// This is a wrapper around the [`Certificate`] guard.
 pub struct CertificateWithEmails<'r> {
-    cert: Certificate<'r>,
-    emails: Vec<String>,
+    /// The client certificate, [`None`] when the client is authenticated with a session token.
+    cert: Option<Certificate<'r>>,
+    pub(crate) emails: Vec<String>,
+    /// The hex encoded SHA-256 fingerprint of the client certificate, identifying the device of the client.
+    pub(crate) fingerprint: String,
 }
 
 #[rocket::async_trait]
@@ -1436,6 +6807,24 @@ impl<'r> FromRequest<'r> for CertificateWithEmails<'r> {
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let cert = try_outcome!(req.guard::<Certificate<'r>>().await);
+        if let Some(revocation_list) = req.rocket().state::<RevocationList>() {
+            if revocation_list.is_revoked(&cert.serial().to_bytes_be()) {
+                log::warn!(
+                    "Rejecting the revoked client certificate `{}`",
+                    cert.serial()
+                );
+                return Outcome::Forward(Status::Unauthorized);
+            }
+        }
+        if let Some(Some(verifier)) = req.rocket().state::<OptionalPkiVerifier>() {
+            if !verifier.verify(cert.as_bytes()).await {
+                log::warn!(
+                    "The PKI rejected the client certificate `{}`",
+                    cert.serial()
+                );
+                return Outcome::Forward(Status::Unauthorized);
+            }
+        }
         let emails: Vec<String> = cert
             .subject_alternative_name()
             .iter()
@@ -1450,26 +6839,152 @@ impl<'r> FromRequest<'r> for CertificateWithEmails<'r> {
             .map(|e| e.to_string())
             .collect();
         if emails.len() > 0 {
-            Outcome::Success(CertificateWithEmails { cert, emails })
+            let fingerprint = storage::content_hash(cert.as_bytes());
+            Outcome::Success(CertificateWithEmails {
+                cert: Some(cert),
+                emails,
+                fingerprint,
+            })
         } else {
             Outcome::Forward(Status::Unauthorized)
         }
     }
 }
 
+/// A request guard authenticating a client with its TLS client certificate or, if it doesn't present one,
+/// with a session token in the `Authorization` header, see [`create_session`].
+/// The session tokens are only accepted by the data plane endpoints: the files, the metadata and the group messages.
+pub struct SessionOrCertificate<'r>(CertificateWithEmails<'r>);
+
+impl<'r> Deref for SessionOrCertificate<'r> {
+    type Target = CertificateWithEmails<'r>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'r> From<SessionOrCertificate<'r>> for CertificateWithEmails<'r> {
+    fn from(session: SessionOrCertificate<'r>) -> Self {
+        session.0
+    }
+}
+
+/// Returns the claims of the valid session token of the request, if any.
+fn session_claims(req: &Request<'_>) -> Option<SessionClaims> {
+    let token = req
+        .headers()
+        .get_one("Authorization")?
+        .strip_prefix("Bearer ")?
+        .trim();
+    let sessions = req.rocket().state::<Sessions>()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let claims = sessions.verify(token, now)?;
+    // The session ends as soon as the certificate it is bound to is revoked.
+    if let Some(revocation_list) = req.rocket().state::<RevocationList>() {
+        if revocation_list.is_revoked(&claims.serial()?) {
+            log::warn!(
+                "Rejecting the session of the revoked client certificate `{}`",
+                claims.fingerprint
+            );
+            return None;
+        }
+    }
+    Some(claims)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionOrCertificate<'r> {
+    type Error = mtls::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.guard::<CertificateWithEmails<'r>>().await {
+            Outcome::Success(certificate) => Outcome::Success(SessionOrCertificate(certificate)),
+            outcome => match session_claims(req) {
+                Some(claims) => Outcome::Success(SessionOrCertificate(CertificateWithEmails {
+                    cert: None,
+                    emails: claims.emails,
+                    fingerprint: claims.fingerprint,
+                })),
+                None => outcome.map(SessionOrCertificate),
+            },
+        }
+    }
+}
+
+/// A request guard extracting the bearer token of a share link from the `Authorization` header.
+/// Forwards the request if the header is missing, so that the routes authenticated with a client certificate are tried first.
+pub struct ShareLinkToken(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ShareLinkToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => Outcome::Success(ShareLinkToken(token.trim().to_string())),
+            None => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+/// Returns the share link of the token if it grants access to the folder, or an error if it doesn't.
+async fn authorize_share_link<R>(
+    token: &ShareLinkToken,
+    folder_id: u64,
+    db: &mut Connection<DbConn>,
+) -> Result<ShareLinkEntity, SSFResponder<R>> {
+    match db::get_share_link(&storage::content_hash(token.0.as_bytes()), db).await {
+        Ok(link) if link.folder_id == folder_id => Ok(link),
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            log::debug!("Invalid share link for folder `{}`", folder_id);
+            Err(SSFResponder::Unauthorized(ErrorBody::new(
+                "invalid_share_link",
+                "The share link is invalid, expired or revoked.",
+            )))
+        }
+        Err(e) => {
+            log::error!("Couldn't retrieve the share link from the DB: `{}`", e);
+            Err(SSFResponder::InternalServerError(ErrorBody::new(
+                "internal_error",
+                "Internal Server Error",
+            )))
+        }
+    }
+}
+
+/// Describe the upload limits when a form exceeds them, as Rocket rejects it before it reaches the handler.
+#[catch(413)]
+pub fn payload_too_large(req: &Request<'_>) -> ErrorBody {
+    let message = req
+        .rocket()
+        .state::<UploadLimits>()
+        .cloned()
+        .unwrap_or_default()
+        .describe();
+    ErrorBody::new("payload_too_large", &message)
+}
+
 /// Returns the user entity associated with the client certificate from mTLS or an error if the client is not registered.
-async fn get_known_user_or_unauthorized<R>(
-    client_certificate: CertificateWithEmails<'_>,
+async fn get_known_user_or_unauthorized<'r, R>(
+    client_certificate: impl Into<CertificateWithEmails<'r>>,
     db: &mut Connection<DbConn>,
 ) -> Result<UserEntity, SSFResponder<R>> {
-    get_known_user(client_certificate, db).await.map_err(|_| {
-        SSFResponder::Unauthorized(
-            "Client identity check failed, please check your TLS certificate.".to_string(),
-        )
-    })
+    get_known_user(client_certificate.into(), db)
+        .await
+        .map_err(|_| {
+            SSFResponder::Unauthorized(ErrorBody::new(
+                "unknown_client",
+                "Client identity check failed, please check your TLS certificate.",
+            ))
+        })
 }
 
-/// Returns the user entity associated with the client certificate from mTLS or an error.
+/// Returns the user entity associated with the client certificate from mTLS, together with its device, or an error.
 async fn get_known_user(
     client_certificate: CertificateWithEmails<'_>,
     db: &mut Connection<DbConn>,
@@ -1488,10 +7003,16 @@ async fn get_known_user(
         &client_certificate.emails,
         users.iter().map(|u| &u.user_email)
     );
-    if users.len() == 1 {
-        Ok(users.get(0).unwrap().clone())
-    } else {
+    if users.len() != 1 {
         log::debug!("Trying to get the client from the db, found `{:?}`", users);
-        Err(sqlx::Error::RowNotFound)
+        return Err(sqlx::Error::RowNotFound);
     }
+    let user = users.get(0).unwrap().clone();
+    // The certificate identifies the device of the user, registered on first use.
+    let device_id =
+        db::get_or_insert_device(&user.user_email, &client_certificate.fingerprint, db).await?;
+    Ok(UserEntity {
+        device_id: Some(device_id),
+        ..user
+    })
 }
@@ -0,0 +1,199 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// The minimum length of the key signing the session tokens, in bytes.
+const MIN_KEY_LENGTH: usize = 32;
+
+/// The session configuration, loaded from the `session` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SessionConfig {
+    /// The key signing the session tokens, base64url encoded. A random key is generated if missing,
+    /// it must be shared when more than one instance of the DS is running.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// How long a session token is valid, in seconds.
+    #[serde(default = "default_lifetime")]
+    pub lifetime: u64,
+}
+
+fn default_lifetime() -> u64 {
+    15 * 60
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            key: None,
+            lifetime: default_lifetime(),
+        }
+    }
+}
+
+/// The claims of a session token.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SessionClaims {
+    /// The emails of the client certificate.
+    pub emails: Vec<String>,
+    /// The hex encoded SHA-256 fingerprint of the client certificate the session is bound to.
+    pub fingerprint: String,
+    /// The serial number of the client certificate, base64url encoded, to reject the session once it is revoked.
+    pub serial: String,
+    /// The expiration time of the token, in seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+impl SessionClaims {
+    /// The serial number of the client certificate, as big endian bytes.
+    pub fn serial(&self) -> Option<Vec<u8>> {
+        URL_SAFE_NO_PAD.decode(&self.serial).ok()
+    }
+}
+
+/// Issues and verifies the short-lived session tokens, to be used as managed state in Rocket.
+/// A token is issued to a client authenticated with its certificate, and authenticates it
+/// in its place until it expires: `base64url(claims).base64url(HMAC-SHA256(claims))`.
+pub struct Sessions {
+    key: hmac::Key,
+    lifetime: u64,
+}
+
+impl Sessions {
+    pub fn from_config(config: &SessionConfig) -> Result<Self, String> {
+        let key = match &config.key {
+            Some(key) => URL_SAFE_NO_PAD
+                .decode(key.trim_end_matches('='))
+                .map_err(|e| format!("Invalid session key: {}", e))?,
+            None => {
+                log::info!("No session key is configured, the session tokens are only valid on this instance until it restarts");
+                let mut key = vec![0u8; MIN_KEY_LENGTH];
+                SystemRandom::new()
+                    .fill(&mut key)
+                    .map_err(|_| "Couldn't generate the session key".to_string())?;
+                key
+            }
+        };
+        if key.len() < MIN_KEY_LENGTH {
+            return Err(format!(
+                "The session key must be at least {} bytes long",
+                MIN_KEY_LENGTH
+            ));
+        }
+        Ok(Sessions {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            lifetime: config.lifetime,
+        })
+    }
+
+    /// Issue a token for the client with the certificate fingerprint, returning it with its expiration time.
+    pub fn issue(
+        &self,
+        emails: Vec<String>,
+        fingerprint: &str,
+        serial: &[u8],
+        now: u64,
+    ) -> (String, u64) {
+        let claims = SessionClaims {
+            emails,
+            fingerprint: fingerprint.to_string(),
+            serial: URL_SAFE_NO_PAD.encode(serial),
+            exp: now + self.lifetime,
+        };
+        let payload =
+            URL_SAFE_NO_PAD.encode(rocket::serde::json::to_string(&claims).expect("valid JSON"));
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        (
+            format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag)),
+            claims.exp,
+        )
+    }
+
+    /// Returns the claims of the token, if it is signed by this server and not expired.
+    pub fn verify(&self, token: &str, now: u64) -> Option<SessionClaims> {
+        let (payload, tag) = token.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, payload.as_bytes(), &tag).ok()?;
+        let claims = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let claims = rocket::serde::json::from_slice::<SessionClaims>(&claims).ok()?;
+        (now < claims.exp).then_some(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn sessions() -> Sessions {
+        Sessions::from_config(&SessionConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let sessions = sessions();
+        let (token, expires_at) =
+            sessions.issue(vec!["alice@example.com".to_string()], "cafe", &[42], 1000);
+        assert_eq!(1000 + default_lifetime(), expires_at);
+        let claims = sessions.verify(&token, 1000).unwrap();
+        assert_eq!(vec!["alice@example.com".to_string()], claims.emails);
+        assert_eq!(Some(vec![42]), claims.serial());
+        assert_eq!("cafe", claims.fingerprint);
+        // The token expires.
+        assert_eq!(None, sessions.verify(&token, expires_at));
+    }
+
+    #[test]
+    fn test_verify_invalid_token() {
+        let sessions = sessions();
+        let (token, _) = sessions.issue(vec!["alice@example.com".to_string()], "cafe", &[42], 0);
+        // Signed by another server.
+        assert_eq!(None, self::sessions().verify(&token, 0));
+        // Tampered with.
+        let (_, tag) = token.split_once('.').unwrap();
+        let (forged, _) = sessions.issue(vec!["mallory@example.com".to_string()], "cafe", &[42], 0);
+        let (payload, _) = forged.split_once('.').unwrap();
+        assert_eq!(None, sessions.verify(&format!("{}.{}", payload, tag), 0));
+        // Not a session token, e.g. the token of a share link.
+        assert_eq!(None, sessions.verify("c2hhcmUtbGluaw", 0));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let key = URL_SAFE_NO_PAD.encode([7u8; 32]);
+        let config = SessionConfig {
+            key: Some(key),
+            lifetime: 60,
+        };
+        let (token, _) = Sessions::from_config(&config).unwrap().issue(
+            vec!["alice@example.com".to_string()],
+            "cafe",
+            &[42],
+            0,
+        );
+        // The instances sharing the key accept each other tokens.
+        assert!(Sessions::from_config(&config)
+            .unwrap()
+            .verify(&token, 0)
+            .is_some());
+        let short_key = SessionConfig {
+            key: Some(URL_SAFE_NO_PAD.encode([7u8; 16])),
+            lifetime: 60,
+        };
+        assert!(Sessions::from_config(&short_key).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    io::Cursor,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, ContentType, Method, Status},
+    tokio::{sync::Notify, time::timeout},
+    Data, Orbit, Request, Response, Rocket,
+};
+
+use crate::server::ErrorBody;
+
+/// The number of seconds after which the clients rejected during the shutdown should retry.
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// A fairing draining the requests that modify the state of the server on shutdown.
+/// Once the shutdown is requested, the new write requests (e.g. proposals and uploads) are rejected,
+/// while the shutdown waits, at most for the configured grace period, for the in-flight ones to complete,
+/// so that they don't leave half-published commits behind.
+/// Read requests, including the notification streams, are not tracked.
+#[derive(Default)]
+pub struct GracefulShutdown {
+    state: Arc<InFlight>,
+}
+
+#[derive(Default)]
+struct InFlight {
+    draining: AtomicBool,
+    requests: AtomicUsize,
+    idle: Notify,
+}
+
+/// How a request has been handled by the fairing, cached in the request local state.
+enum Tracking {
+    Untracked,
+    InFlight,
+    Rejected,
+}
+
+impl GracefulShutdown {
+    pub fn new() -> Self {
+        GracefulShutdown::default()
+    }
+
+    /// Wait until all the in-flight write requests completed.
+    async fn wait_idle(&self) {
+        loop {
+            // Register for the notification before checking, so that it can't be missed.
+            let idle = self.state.idle.notified();
+            if self.state.requests.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+fn is_write(method: Method) -> bool {
+    !matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+#[rocket::async_trait]
+impl Fairing for GracefulShutdown {
+    fn info(&self) -> Info {
+        Info {
+            name: "Graceful shutdown",
+            kind: Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if !is_write(req.method()) {
+            return;
+        }
+        if self.state.draining.load(Ordering::SeqCst) {
+            log::debug!(
+                "Rejecting request `{} {}` while shutting down",
+                req.method(),
+                req.uri()
+            );
+            req.local_cache(|| Tracking::Rejected);
+            // Divert the request, so that it doesn't reach the handler.
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse("/shutting-down").expect("valid URI"));
+            return;
+        }
+        self.state.requests.fetch_add(1, Ordering::SeqCst);
+        req.local_cache(|| Tracking::InFlight);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        match req.local_cache(|| Tracking::Untracked) {
+            Tracking::Untracked => (),
+            Tracking::InFlight => {
+                if self.state.requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    self.state.idle.notify_waiters();
+                }
+            }
+            Tracking::Rejected => {
+                let body = ErrorBody::new(
+                    "server_shutting_down",
+                    "The server is shutting down, retry later.",
+                )
+                .with_retry_after(RETRY_AFTER_SECONDS);
+                let body = rocket::serde::json::to_string(&body).expect("valid JSON");
+                res.set_status(Status::ServiceUnavailable);
+                res.set_raw_header("Retry-After", RETRY_AFTER_SECONDS.to_string());
+                res.set_header(ContentType::JSON);
+                res.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        self.state.draining.store(true, Ordering::SeqCst);
+        let grace = Duration::from_secs(rocket.config().shutdown.grace as u64);
+        let in_flight = self.state.requests.load(Ordering::SeqCst);
+        log::info!(
+            "Shutting down, waiting for `{}` in-flight write requests",
+            in_flight
+        );
+        match timeout(grace, self.wait_idle()).await {
+            Ok(()) => log::info!("All the in-flight write requests completed"),
+            Err(_) => log::warn!(
+                "`{}` write requests still in-flight after the grace period",
+                self.state.requests.load(Ordering::SeqCst)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_is_write() {
+        assert!(is_write(Method::Post));
+        assert!(is_write(Method::Patch));
+        assert!(is_write(Method::Delete));
+        assert!(!is_write(Method::Get));
+        assert!(!is_write(Method::Options));
+    }
+
+    #[rocket::async_test]
+    async fn test_wait_idle() {
+        let shutdown = GracefulShutdown::new();
+        shutdown.wait_idle().await;
+        shutdown.state.requests.store(1, Ordering::SeqCst);
+        let state = shutdown.state.clone();
+        let completion = rocket::tokio::spawn(async move {
+            state.requests.fetch_sub(1, Ordering::SeqCst);
+            state.idle.notify_waiters();
+        });
+        timeout(Duration::from_secs(5), shutdown.wait_idle())
+            .await
+            .expect("the in-flight request completed");
+        completion.await.unwrap();
+    }
+}
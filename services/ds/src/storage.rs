@@ -11,36 +11,113 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use std::{env, time::Duration};
+use std::{
+    env, fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::Method;
 
 use object_store::{
-    aws::{AmazonS3, AmazonS3Builder, DynamoCommit, S3ConditionalPut},
+    aws::{
+        AmazonS3, AmazonS3Builder, AwsAuthorizer, AwsCredential, DynamoCommit, S3ConditionalPut,
+    },
     local::LocalFileSystem,
+    memory::InMemory,
+    multipart::{MultipartStore, PartId},
     path::Path,
-    ClientOptions, ObjectMeta, ObjectStore, PutMode, PutPayload, UpdateVersion,
+    signer::Signer,
+    ClientOptions, GetOptions, GetResult, GetResultPayload, MultipartId, ObjectMeta, ObjectStore,
+    PutMode, PutMultipartOpts, PutOptions, PutPayload, PutResult, TagSet, UpdateVersion,
+    WriteMultipart,
+};
+use rocket::{
+    fairing::AdHoc,
+    futures::{stream, StreamExt, TryStreamExt},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::MutexGuard,
 };
-use tokio::sync::MutexGuard;
 
 use crate::db::FolderEntity;
 
 /// The dynamic store type. This is used to abstract the object store implementation.
 pub type DynamicStore = Box<dyn ObjectStore + Send + Sync + 'static>;
 
+/// The dynamic store used for resumable uploads, which need direct access to the multipart API of the object store.
+pub type DynamicMultipartStore = Box<dyn MultipartStore + Send + Sync + 'static>;
+
+/// The dynamic signer used to issue presigned URLs, letting clients transfer files directly with the object store.
+pub type DynamicSigner = Box<dyn Signer>;
+
+/// How long a presigned URL stays valid.
+pub const PRESIGNED_URL_EXPIRATION: Duration = Duration::from_secs(15 * 60);
+
 /// The configuration provider of the object store for [`Rocket`](https://rocket.rs/guide/v0.5/configuration/#extracting-values).
 /// The configuration is loaded from the `DS_Rocket.toml` file.
 /// This structure should be used with the [`AdHoc`](https://rocket.rs/v0.5-rc/guide/fairings/#ad-hoc-fairings) fairing.
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Default, serde::Deserialize)]
 #[non_exhaustive]
 pub struct StoreConfig {
     /// fallback on file system active?
     #[serde(default = "Default::default")]
     fs_fallback: bool,
+    /// Keep the files in memory instead, they are lost when the server stops.
+    /// Used for local development and tests together with an in-memory database, the S3 storage is then ignored.
+    #[serde(default = "Default::default")]
+    in_memory: bool,
     /// The S3 storage configuration.
     s3_storage: Option<S3Config>,
+    /// The in-memory store, shared by the object store and the store for resumable uploads.
+    #[serde(skip)]
+    memory_store: Arc<InMemory>,
+}
+
+impl StoreConfig {
+    /// The S3 storage configuration, unless the files are kept in memory.
+    fn s3(&self) -> Option<&S3Config> {
+        self.s3_storage.as_ref().filter(|_| !self.in_memory)
+    }
+}
+
+/// The in-memory store used for resumable uploads, writing to the same instance of the object store.
+#[derive(Debug)]
+struct SharedInMemory(Arc<InMemory>);
+
+#[rocket::async_trait]
+impl MultipartStore for SharedInMemory {
+    async fn create_multipart(&self, path: &Path) -> object_store::Result<MultipartId> {
+        self.0.create_multipart(path).await
+    }
+
+    async fn put_part(
+        &self,
+        path: &Path,
+        id: &MultipartId,
+        part_idx: usize,
+        data: PutPayload,
+    ) -> object_store::Result<PartId> {
+        self.0.put_part(path, id, part_idx, data).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        path: &Path,
+        id: &MultipartId,
+        parts: Vec<PartId>,
+    ) -> object_store::Result<PutResult> {
+        self.0.complete_multipart(path, id, parts).await
+    }
+
+    async fn abort_multipart(&self, path: &Path, id: &MultipartId) -> object_store::Result<()> {
+        self.0.abort_multipart(path, id).await
+    }
 }
 
 /// The S3 configuration.
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 #[non_exhaustive]
 pub struct S3Config {
     /// The S3 bucket name.
@@ -51,18 +128,318 @@ pub struct S3Config {
     pub access_key_id: String,
     /// The S3 secret access key.
     pub secret_access_key: String,
+    /// The maximum number of retries of a failed request.
+    #[serde(default = "default_s3_max_retries")]
+    pub max_retries: usize,
+    /// The time after which a failed request is not retried anymore, in seconds.
+    #[serde(default = "default_s3_retry_timeout")]
+    pub retry_timeout: u64,
+    /// Whether to accept invalid TLS certificates, e.g. the self-signed certificate of a local Localstack instance.
+    /// It must never be enabled in production.
+    #[serde(default)]
+    pub allow_invalid_certificates: bool,
+    /// The DynamoDB table used to perform the conditional updates of the metadata files.
+    pub dynamo_table: String,
+    /// How long a conditional update waits for a concurrent one to complete, in milliseconds.
+    #[serde(default = "default_dynamo_timeout")]
+    pub dynamo_timeout: u64,
+    /// The storage class the file contents are moved to, one of [`S3_STORAGE_CLASSES`], e.g. `GLACIER_IR`.
+    /// Only the blobs are moved: the metadata files, their history and the pointers to the blobs
+    /// always stay in the standard storage class.
+    /// The class is applied by a lifecycle rule of the bucket, see [`S3Lifecycle`].
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// How many days after their upload the file contents are moved to `storage_class`.
+    /// S3 requires at least 30 days for `STANDARD_IA` and `ONEZONE_IA`.
+    #[serde(default)]
+    pub storage_class_after_days: u32,
+    /// The storage class the replaced versions of the file contents are moved to, one of [`S3_NONCURRENT_STORAGE_CLASSES`].
+    /// These versions are never read by the server, so they can be archived, e.g. in `DEEP_ARCHIVE`.
+    #[serde(default)]
+    pub noncurrent_storage_class: Option<String>,
+    /// How many days after being replaced the versions of the file contents are moved to `noncurrent_storage_class`.
+    #[serde(default = "default_noncurrent_after_days")]
+    pub noncurrent_after_days: u32,
+}
+
+fn default_s3_max_retries() -> usize {
+    10
 }
 
+fn default_s3_retry_timeout() -> u64 {
+    3 * 60
+}
+
+fn default_dynamo_timeout() -> u64 {
+    10_000
+}
+
+fn default_noncurrent_after_days() -> u32 {
+    30
+}
+
+/// The storage classes the file contents can be moved to, which can still be read without restoring them first.
+pub const S3_STORAGE_CLASSES: [&str; 4] = [
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER_IR",
+];
+
+/// The storage classes the replaced versions of the file contents can be moved to, including the archive ones.
+pub const S3_NONCURRENT_STORAGE_CLASSES: [&str; 6] = [
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER_IR",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+];
+
+/// The storage classes which S3 only accepts for objects older than [`S3_INFREQUENT_ACCESS_MIN_DAYS`].
+const S3_INFREQUENT_ACCESS_CLASSES: [&str; 2] = ["STANDARD_IA", "ONEZONE_IA"];
+const S3_INFREQUENT_ACCESS_MIN_DAYS: u32 = 30;
+
+/// The tag of the blobs holding the content of the files, matched by the lifecycle rule of the bucket.
+const CONTENT_TAG_KEY: &str = "ssf-object";
+const CONTENT_TAG_VALUE: &str = "content";
+/// The id of the lifecycle rule moving the file contents to cheaper storage classes.
+const LIFECYCLE_RULE_ID: &str = "ssf-file-contents";
+/// The region used to sign the requests, the default one of [`AmazonS3Builder`] used for the other requests.
+const S3_REGION: &str = "us-east-1";
+
+/// The tags of the blobs holding the content of the files.
+fn content_tags() -> TagSet {
+    let mut tags = TagSet::default();
+    tags.push(CONTENT_TAG_KEY, CONTENT_TAG_VALUE);
+    tags
+}
+
+/// The lifecycle configuration of the bucket, moving the file contents to the configured storage classes.
+/// Only the blobs written by [`write_blob`] are moved: the contents completed with a resumable
+/// upload or uploaded with a presigned URL stay in the standard storage class.
+#[derive(Debug, Clone)]
+pub struct S3Lifecycle {
+    config: S3Config,
+    rules: String,
+}
+
+impl S3Lifecycle {
+    /// Returns the lifecycle configuration of the bucket, or `None` if no storage class is configured.
+    /// Returns an error if a storage class is unknown or not allowed after the configured days.
+    pub fn from_config(config: &S3Config) -> Result<Option<Self>, String> {
+        if config.storage_class.is_none() && config.noncurrent_storage_class.is_none() {
+            return Ok(None);
+        }
+        let mut rule = format!(
+            "<ID>{}</ID><Filter><Tag><Key>{}</Key><Value>{}</Value></Tag></Filter><Status>Enabled</Status>",
+            LIFECYCLE_RULE_ID, CONTENT_TAG_KEY, CONTENT_TAG_VALUE
+        );
+        if let Some(storage_class) = &config.storage_class {
+            check_storage_class(
+                storage_class,
+                &S3_STORAGE_CLASSES,
+                config.storage_class_after_days,
+            )?;
+            rule.push_str(&format!(
+                "<Transition><Days>{}</Days><StorageClass>{}</StorageClass></Transition>",
+                config.storage_class_after_days, storage_class
+            ));
+        }
+        if let Some(storage_class) = &config.noncurrent_storage_class {
+            check_storage_class(
+                storage_class,
+                &S3_NONCURRENT_STORAGE_CLASSES,
+                config.noncurrent_after_days,
+            )?;
+            rule.push_str(&format!(
+                "<NoncurrentVersionTransition><NoncurrentDays>{}</NoncurrentDays><StorageClass>{}</StorageClass></NoncurrentVersionTransition>",
+                config.noncurrent_after_days, storage_class
+            ));
+        }
+        Ok(Some(S3Lifecycle {
+            config: config.clone(),
+            rules: format!(
+                "<LifecycleConfiguration xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Rule>{}</Rule></LifecycleConfiguration>",
+                rule
+            ),
+        }))
+    }
+
+    /// Replaces the lifecycle configuration of the bucket, removing any other lifecycle rule of the bucket.
+    pub async fn apply(&self) -> Result<(), String> {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(self.config.allow_invalid_certificates)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let url = format!(
+            "{}/{}?lifecycle",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.bucket
+        );
+        let checksum = ring::digest::digest(&ring::digest::SHA256, self.rules.as_bytes());
+        // S3 requires a checksum of the lifecycle configuration.
+        let mut request = client
+            .put(url)
+            .header("x-amz-sdk-checksum-algorithm", "SHA256")
+            .header(
+                "x-amz-checksum-sha256",
+                base64::Engine::encode(&base64::engine::general_purpose::STANDARD, checksum),
+            )
+            .body(self.rules.clone())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let credential = AwsCredential {
+            key_id: self.config.access_key_id.clone(),
+            secret_key: self.config.secret_access_key.clone(),
+            token: None,
+        };
+        AwsAuthorizer::new(&credential, "s3", S3_REGION).authorize(&mut request, None);
+        let response = client.execute(request).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("`{}`: `{}`", status, body));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the file contents can be moved to `storage_class`, one of `allowed`, after `days`.
+fn check_storage_class(storage_class: &str, allowed: &[&str], days: u32) -> Result<(), String> {
+    if !allowed.contains(&storage_class) {
+        return Err(format!(
+            "Unsupported storage class `{}`, expected one of `{:?}`",
+            storage_class, allowed
+        ));
+    }
+    if S3_INFREQUENT_ACCESS_CLASSES.contains(&storage_class) && days < S3_INFREQUENT_ACCESS_MIN_DAYS
+    {
+        return Err(format!(
+            "The storage class `{}` requires at least {} days",
+            storage_class, S3_INFREQUENT_ACCESS_MIN_DAYS
+        ));
+    }
+    Ok(())
+}
+
+/// Initialise the lifecycle configuration of the bucket from the configuration.
+/// Returns `None` if the object store is not S3 or no storage class is configured.
+pub fn initialise_lifecycle(config: &StoreConfig) -> Result<Option<S3Lifecycle>, String> {
+    match config.s3() {
+        Some(s3_config) => S3Lifecycle::from_config(s3_config),
+        None => Ok(None),
+    }
+}
+
+/// Applies the lifecycle configuration of the bucket once the server is started.
+/// A failure is only logged, the file contents then stay in their current storage class.
+pub fn lifecycle_fairing(lifecycle: Option<S3Lifecycle>) -> AdHoc {
+    AdHoc::on_liftoff("S3 lifecycle", move |_| {
+        Box::pin(async move {
+            let Some(lifecycle) = lifecycle else {
+                return;
+            };
+            match lifecycle.apply().await {
+                Ok(()) => log::info!("Applied the lifecycle configuration of the bucket"),
+                Err(e) => log::error!(
+                    "Couldn't apply the lifecycle configuration of the bucket: `{}`",
+                    e
+                ),
+            }
+        })
+    })
+}
+
+/// The size of the parts used to upload large files, which is also the minimum part size accepted by S3.
+/// Files smaller than this are uploaded with a single request.
+const UPLOAD_PART_SIZE: usize = 5 * 1024 * 1024;
+/// The maximum number of parts of a file being uploaded concurrently.
+const UPLOAD_MAX_CONCURRENT_PARTS: usize = 4;
+
+/// The content of a file to write, read as a stream so that large files are never fully loaded in memory.
+pub type FileReader<'r> = Box<dyn AsyncRead + Send + Unpin + 'r>;
+
 /// The parameters for writing a file in the storage.
 /// The file content is optional to allow for metadata only updates.
-#[derive(Debug)]
 pub struct WriteInput<'r> {
     /// The folder entity.
     pub folder_entity: FolderEntity,
     /// The file id.
     pub file_id: &'r str,
     /// The optional file content.
-    pub file_to_write: Option<Vec<u8>>,
+    pub file_to_write: Option<FileReader<'r>>,
+    /// The metadata file metadata.
+    pub metadata_file: Vec<u8>,
+    /// The previous etag of the metadata file to which change applies.
+    pub parent_etag: Option<String>,
+    /// The previous version of the metadata file to which change applies.
+    pub parent_version: Option<String>,
+    /// The etag of the file that is replaced. If missing, the file is written only if it doesn't exist yet.
+    pub file_parent_etag: Option<String>,
+}
+
+impl fmt::Debug for WriteInput<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteInput")
+            .field("folder_entity", &self.folder_entity)
+            .field("file_id", &self.file_id)
+            .field("file_to_write", &self.file_to_write.is_some())
+            .field("metadata_file", &self.metadata_file)
+            .field("parent_etag", &self.parent_etag)
+            .field("parent_version", &self.parent_version)
+            .field("file_parent_etag", &self.file_parent_etag)
+            .finish()
+    }
+}
+
+/// A file of a [`BatchWriteInput`].
+pub struct BatchFile<'r> {
+    /// The file id.
+    pub file_id: &'r str,
+    /// The file content.
+    pub content: FileReader<'r>,
+    /// The etag of the file that is replaced. If missing, the file is written only if it doesn't exist yet.
+    pub file_parent_etag: Option<String>,
+}
+
+impl fmt::Debug for BatchFile<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchFile")
+            .field("file_id", &self.file_id)
+            .field("file_parent_etag", &self.file_parent_etag)
+            .finish()
+    }
+}
+
+/// The parameters for writing several files in the storage at once.
+/// The metadata file, including all the files, is updated after them, as for [`WriteInput`].
+#[derive(Debug)]
+pub struct BatchWriteInput<'r> {
+    /// The folder entity.
+    pub folder_entity: FolderEntity,
+    /// The files to write, with distinct ids.
+    pub files: Vec<BatchFile<'r>>,
+    /// The metadata file metadata.
+    pub metadata_file: Vec<u8>,
+    /// The previous etag of the metadata file to which change applies.
+    pub parent_etag: Option<String>,
+    /// The previous version of the metadata file to which change applies.
+    pub parent_version: Option<String>,
+}
+
+/// The parameters for completing a resumable upload.
+/// The metadata file is updated together with the completion, as for [`WriteInput`].
+#[derive(Debug)]
+pub struct CompleteUploadInput<'r> {
+    /// The folder entity.
+    pub folder_entity: FolderEntity,
+    /// The file id.
+    pub file_id: &'r str,
+    /// The id of the multipart upload.
+    pub multipart_id: MultipartId,
+    /// The uploaded parts, in order.
+    pub parts: Vec<PartId>,
     /// The metadata file metadata.
     pub metadata_file: Vec<u8>,
     /// The previous etag of the metadata file to which change applies.
@@ -71,6 +448,42 @@ pub struct WriteInput<'r> {
     pub parent_version: Option<String>,
 }
 
+/// The parameters for deleting a file from the storage.
+/// The metadata file is updated together with the deletion, as for [`WriteInput`].
+#[derive(Debug)]
+pub struct DeleteInput<'r> {
+    /// The folder entity.
+    pub folder_entity: FolderEntity,
+    /// The file id.
+    pub file_id: &'r str,
+    /// The time of the deletion, in microseconds since the Unix epoch, identifying the file in the trash.
+    pub deleted_at: u64,
+    /// The metadata file without the deleted file.
+    pub metadata_file: Vec<u8>,
+    /// The previous etag of the metadata file to which change applies.
+    pub parent_etag: Option<String>,
+    /// The previous version of the metadata file to which change applies.
+    pub parent_version: Option<String>,
+}
+
+/// The parameters for restoring a file from the trash of a folder.
+/// The metadata file is updated together with the restore, as for [`WriteInput`].
+#[derive(Debug)]
+pub struct RestoreInput<'r> {
+    /// The folder entity.
+    pub folder_entity: FolderEntity,
+    /// The file id.
+    pub file_id: &'r str,
+    /// The time the file was deleted, identifying it in the trash.
+    pub deleted_at: u64,
+    /// The metadata file including the restored file.
+    pub metadata_file: Vec<u8>,
+    /// The previous etag of the metadata file to which change applies.
+    pub parent_etag: Option<String>,
+    /// The previous version of the metadata file to which change applies.
+    pub parent_version: Option<String>,
+}
+
 /// Initialise the S3 object store.
 fn initialise_s3(config: S3Config) -> Result<AmazonS3, String> {
     AmazonS3Builder::new()
@@ -80,15 +493,16 @@ fn initialise_s3(config: S3Config) -> Result<AmazonS3, String> {
         .with_bucket_name(config.bucket)
         .with_retry(object_store::RetryConfig {
             backoff: object_store::BackoffConfig::default(),
-            max_retries: 1,
-            retry_timeout: Duration::from_secs(60),
+            max_retries: config.max_retries,
+            retry_timeout: Duration::from_secs(config.retry_timeout),
         })
-        // We are testing with a local instance using Localstack!
-        .with_client_options(ClientOptions::new().with_allow_invalid_certificates(true))
+        .with_client_options(
+            ClientOptions::new().with_allow_invalid_certificates(config.allow_invalid_certificates),
+        )
         // Use the etag to perform optimistic concurrency. Other option would be to use a Dynamo table.
         .with_conditional_put(S3ConditionalPut::Dynamo(
-            DynamoCommit::new("test-table".to_string())
-                .with_timeout(10_000)
+            DynamoCommit::new(config.dynamo_table)
+                .with_timeout(config.dynamo_timeout)
                 .with_max_clock_skew_rate(2),
         ))
         .build()
@@ -107,10 +521,14 @@ fn initialise_fs() -> Result<LocalFileSystem, String> {
 /// Initialise the object store from the configuration.
 /// If the configuration is invalid an error is returned.
 pub fn initialise_object_store(config: StoreConfig) -> Result<DynamicStore, String> {
-    if let Some(s3_config) = config.s3_storage {
-        let object_store = initialise_s3(s3_config)?;
+    if let Some(s3_config) = config.s3() {
+        let object_store = initialise_s3(s3_config.clone())?;
         return Ok(Box::new(object_store));
     } else {
+        if config.in_memory {
+            let object_store: Arc<dyn ObjectStore> = config.memory_store;
+            return Ok(Box::new(object_store));
+        }
         if config.fs_fallback {
             return Ok(Box::new(initialise_fs()?));
         }
@@ -118,11 +536,148 @@ pub fn initialise_object_store(config: StoreConfig) -> Result<DynamicStore, Stri
     }
 }
 
+/// Initialise the store for resumable uploads from the configuration.
+/// Returns `None` if the configured object store doesn't support them, as it is the case for the local file system.
+pub fn initialise_multipart_store(
+    config: &StoreConfig,
+) -> Result<Option<DynamicMultipartStore>, String> {
+    match config.s3() {
+        Some(s3_config) => Ok(Some(Box::new(initialise_s3(s3_config.clone())?))),
+        None if config.in_memory => Ok(Some(Box::new(SharedInMemory(config.memory_store.clone())))),
+        None => Ok(None),
+    }
+}
+
+/// Initialise the signer for presigned URLs from the configuration.
+/// Returns `None` if the configured object store doesn't support them, as it is the case for the local file system.
+pub fn initialise_signer(config: &StoreConfig) -> Result<Option<DynamicSigner>, String> {
+    match config.s3() {
+        Some(s3_config) => Ok(Some(Box::new(initialise_s3(s3_config.clone())?))),
+        None => Ok(None),
+    }
+}
+
 /// The metadata file name.
 /// The metadata file is stored directly in the root of the bucket/<folder_id>/
-const METADATA_FILE_NAME: &'static str = "metadata";
+pub const METADATA_FILE_NAME: &str = "metadata";
+/// The folder keeping a copy of each version of the metadata file, to audit and restore the changes.
+/// It is stored in the root of the folder as well: bucket/<folder_id>/.history/<version_id>
+const METADATA_HISTORY_FOLDER_NAME: &str = ".history";
+/// The folder keeping the deleted files until they are restored or their retention window ends.
+/// It is stored in the root of the folder as well: bucket/<folder_id>/.trash/<file_id>/<deleted_at>
+const TRASH_FOLDER_NAME: &str = ".trash";
+/// The folder keeping a copy of the files replaced by a batch upload until the metadata file is written,
+/// to restore them if the batch fails: bucket/<folder_id>/.batch/<file_id>
+const BATCH_FOLDER_NAME: &str = ".batch";
+/// The folder keeping the content of the files uploaded through the server, once for each content hash,
+/// so that identical files of a folder are stored only once: bucket/<folder_id>/.blobs/<content_hash>
+/// The location of each of these files holds instead a pointer to its blob, see [`blob_pointer`].
+const BLOBS_FOLDER_NAME: &str = ".blobs";
+/// Whether the name is reserved for the metadata file, its history, the trash, the batch copies or the blobs,
+/// and can't be used for the files.
 pub fn is_metadata_file_name(name: &str) -> bool {
     name == METADATA_FILE_NAME
+        || name == METADATA_HISTORY_FOLDER_NAME
+        || name == TRASH_FOLDER_NAME
+        || name == BATCH_FOLDER_NAME
+        || name == BLOBS_FOLDER_NAME
+}
+
+/// The prefix of the content of a pointer to a blob, followed by the hex encoded content hash.
+const BLOB_POINTER_PREFIX: &[u8] = b"ssf-blob:sha256:";
+/// The size of a pointer to a blob.
+const BLOB_POINTER_SIZE: usize = BLOB_POINTER_PREFIX.len() + 64;
+
+/// Returns the content of a file pointing to the blob with the given content hash.
+/// Pointers are written in place of the files, so that etags, conditional updates and the trash
+/// keep working on the location of each file, while the content is shared.
+/// The pointers are only followed to read the content, which never leaves the blobs of the folder:
+/// the references to the blobs are counted from the database, as a pointer can be forged by a client.
+pub fn blob_pointer(content_hash: &str) -> Vec<u8> {
+    [BLOB_POINTER_PREFIX, content_hash.as_bytes()].concat()
+}
+
+/// Returns the content hash of the blob if `content` is a pointer, see [`blob_pointer`].
+fn parse_blob_pointer(content: &[u8]) -> Option<String> {
+    let content_hash = content.strip_prefix(BLOB_POINTER_PREFIX)?;
+    if content_hash.len() != 64 || !content_hash.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    String::from_utf8(content_hash.to_vec()).ok()
+}
+
+/// Returns the content hash of the blob the object at `location` points to, if it is a pointer.
+async fn read_blob_pointer<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    meta: &ObjectMeta,
+) -> Result<Option<String>, object_store::Error> {
+    if meta.size != BLOB_POINTER_SIZE {
+        return Ok(None);
+    }
+    let content = object_store.get(&meta.location).await?.bytes().await?;
+    Ok(parse_blob_pointer(&content))
+}
+
+/// Returns the content hash of the blob the current version of a file points to, if any.
+/// Returns [`object_store::Error::NotFound`] if the file doesn't exist.
+pub async fn get_file_blob<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+) -> Result<Option<String>, object_store::Error> {
+    let meta = object_store
+        .head(&get_location_for_file(folder_entity, file_id))
+        .await?;
+    read_blob_pointer(object_store, &meta).await
+}
+
+/// Writes the content of a blob, overwriting it if it already exists as the content is the same.
+pub async fn write_blob<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    content_hash: &str,
+    content: FileReader<'_>,
+) -> Result<(), object_store::Error> {
+    let location = get_location_for_blob(folder_entity, content_hash);
+    log::debug!("Attempting to write blob `{}`", &location);
+    put_stream(
+        object_store,
+        &location,
+        content,
+        PutMode::Overwrite,
+        content_tags(),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Deletes a blob that is no longer referenced by any file.
+pub async fn delete_blob<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    content_hash: &str,
+) -> Result<(), object_store::Error> {
+    let location = get_location_for_blob(folder_entity, content_hash);
+    log::debug!("Attempting to delete blob `{}`", &location);
+    match object_store.delete(&location).await {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the size of the pointers with the size of their blobs.
+async fn resolve_blob_size<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    mut meta: ObjectMeta,
+) -> Result<ObjectMeta, object_store::Error> {
+    if let Some(content_hash) = read_blob_pointer(object_store, &meta).await? {
+        let blob = object_store
+            .head(&get_location_for_blob(folder_entity, &content_hash))
+            .await?;
+        meta.size = blob.size;
+    }
+    Ok(meta)
 }
 
 /// Initialise an empty metadata file for a folder.
@@ -142,30 +697,554 @@ pub async fn init_metadata<'a>(
             // This prevents the client from re-creating a new metadata file from scratch during a file upload operation.
             parent_etag: None,
             parent_version: None,
+            file_parent_etag: None,
         },
     )
     .await
 }
 
 /// Writes a file in the folder together with the updated metadata.
+/// The file is replaced only if it still has the given parent etag, or created only if it doesn't exist yet,
+/// so that concurrent changes to the same file are rejected.
 /// The object_store reference is syncrhonized with a mutex.
 pub async fn write<'a>(
     object_store: &MutexGuard<'a, DynamicStore>,
     write_input: WriteInput<'_>,
 ) -> Result<(Option<String>, Option<String>), object_store::Error> {
     log::debug!("Attempting to write to object store `{:?}`.", &write_input);
+    let file_location = get_location_for_file(&write_input.folder_entity, write_input.file_id);
+    let file_mode = file_put_mode(write_input.file_parent_etag);
+    if write_input.file_to_write.is_some() {
+        // Check the file before writing the metadata, so that a conflict on the file leaves the folder unchanged.
+        check_put_mode(object_store, &file_location, &file_mode).await?;
+    }
+    let put_result = write_metadata(
+        object_store,
+        &write_input.folder_entity,
+        write_input.metadata_file,
+        write_input.parent_etag,
+        write_input.parent_version,
+    )
+    .await?;
+    if let Some(file) = write_input.file_to_write {
+        log::debug!("Attempting to write file `{}`", &file_location);
+        put_stream(
+            object_store,
+            &file_location,
+            file,
+            file_mode,
+            TagSet::default(),
+        )
+        .await?;
+    }
+    Ok((put_result.e_tag, put_result.version))
+}
+
+/// Writes several files in the folder and then the metadata including all of them.
+/// Each file is replaced or created with the same conditions as [`write`], and the metadata file is
+/// updated only if it still has the given parent etag or version. If any of the writes fails,
+/// the files already written are rolled back, so that the folder is left unchanged. The content of the
+/// restored files is the same, their etag changes only with the object stores not deriving it from the content.
+pub async fn write_batch<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    batch_input: BatchWriteInput<'_>,
+) -> Result<(Option<String>, Option<String>), object_store::Error> {
+    log::debug!(
+        "Attempting to write a batch to object store `{:?}`.",
+        &batch_input
+    );
+    let folder_entity = &batch_input.folder_entity;
+    let mut files = Vec::with_capacity(batch_input.files.len());
+    for file in batch_input.files {
+        let location = get_location_for_file(folder_entity, file.file_id);
+        let mode = file_put_mode(file.file_parent_etag);
+        // Check all the files before writing any of them, so that a conflict leaves the folder unchanged.
+        check_put_mode(object_store, &location, &mode).await?;
+        let backup = matches!(mode, PutMode::Update(_))
+            .then(|| get_location_for_batch_copy(folder_entity, file.file_id));
+        files.push((location, backup, mode, file.content));
+    }
+    let mut written = Vec::with_capacity(files.len());
+    for (location, backup, mode, content) in files {
+        if let Some(backup) = &backup {
+            if let Err(e) = object_store.copy(&location, backup).await {
+                rollback_batch(object_store, written).await;
+                return Err(e);
+            }
+        }
+        written.push((location.clone(), backup));
+        log::debug!("Attempting to write file `{}`", &location);
+        if let Err(e) = put_stream(object_store, &location, content, mode, TagSet::default()).await
+        {
+            rollback_batch(object_store, written).await;
+            return Err(e);
+        }
+    }
+    let put_result = match write_metadata(
+        object_store,
+        folder_entity,
+        batch_input.metadata_file,
+        batch_input.parent_etag,
+        batch_input.parent_version,
+    )
+    .await
+    {
+        Ok(put_result) => put_result,
+        Err(e) => {
+            rollback_batch(object_store, written).await;
+            return Err(e);
+        }
+    };
+    for backup in written.iter().filter_map(|(_, backup)| backup.as_ref()) {
+        if let Err(e) = object_store.delete(backup).await {
+            log::error!("Couldn't delete the copy `{}` of a batch: `{}`", backup, e);
+        }
+    }
+    Ok((put_result.e_tag, put_result.version))
+}
+
+/// Restores the files written by a failed batch: the replaced files from their copy, the created ones are deleted.
+async fn rollback_batch<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    written: Vec<(Path, Option<Path>)>,
+) {
+    for (location, backup) in written {
+        log::debug!("Rolling back file `{}` of a failed batch", &location);
+        let result = match backup {
+            Some(backup) => object_store.rename(&backup, &location).await,
+            None => match object_store.delete(&location).await {
+                Err(object_store::Error::NotFound { .. }) => Ok(()),
+                result => result,
+            },
+        };
+        if let Err(e) = result {
+            log::error!(
+                "Couldn't roll back file `{}` of a failed batch: `{}`",
+                location,
+                e
+            );
+        }
+    }
+}
+
+/// The mode of the put of a file, replacing the version with the given etag or creating it if missing.
+fn file_put_mode(file_parent_etag: Option<String>) -> PutMode {
+    match file_parent_etag {
+        Some(e_tag) => PutMode::Update(UpdateVersion {
+            e_tag: Some(e_tag),
+            version: None,
+        }),
+        None => PutMode::Create,
+    }
+}
+
+/// Returns the hex encoded SHA-256 digest of `content`, used by the clients to detect corrupted transfers.
+pub fn content_hash(content: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, content).as_ref())
+}
+
+/// Returns the hex encoded SHA-256 digest of the content of `reader`, see [`content_hash`].
+pub async fn content_hash_of_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+) -> std::io::Result<String> {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hex(context.finish().as_ref()));
+        }
+        context.update(&buffer[..read]);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Checks that the object at `location` can be written with the given mode, without writing it.
+/// Returns the same errors as a conditional put on the object store.
+async fn check_put_mode<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    location: &Path,
+    mode: &PutMode,
+) -> Result<(), object_store::Error> {
+    let head = match object_store.head(location).await {
+        Ok(meta) => Some(meta),
+        Err(object_store::Error::NotFound { .. }) => None,
+        Err(e) => return Err(e),
+    };
+    match (mode, head) {
+        (PutMode::Create, Some(_)) => Err(object_store::Error::AlreadyExists {
+            path: location.to_string(),
+            source: "The file already exists".into(),
+        }),
+        (PutMode::Update(version), head) => match head {
+            Some(meta) if meta.e_tag == version.e_tag => Ok(()),
+            _ => Err(object_store::Error::Precondition {
+                path: location.to_string(),
+                source: format!("The file doesn't have the etag `{:?}`", version.e_tag).into(),
+            }),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Writes the content read from `reader` to `location` with the given mode.
+/// Files larger than [`UPLOAD_PART_SIZE`] are written using a multipart upload, so that only
+/// a bounded number of parts is kept in memory at any time. The object is tagged with `tags`,
+/// which are matched by the lifecycle rule of the bucket, see [`S3Lifecycle`]. Multipart uploads can't be conditional,
+/// so the mode of the put must have been checked before with [`check_put_mode`].
+async fn put_stream<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    location: &Path,
+    mut reader: FileReader<'_>,
+    mode: PutMode,
+    tags: TagSet,
+) -> Result<PutResult, object_store::Error> {
+    let mut part = Vec::with_capacity(UPLOAD_PART_SIZE);
+    (&mut reader)
+        .take(UPLOAD_PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .await
+        .map_err(read_error)?;
+    if part.len() < UPLOAD_PART_SIZE {
+        let payload = PutPayload::from_bytes(part.into());
+        return match object_store
+            .put_opts(
+                location,
+                payload.clone(),
+                PutOptions {
+                    mode,
+                    tags,
+                    ..Default::default()
+                },
+            )
+            .await
+        {
+            // The local file system doesn't support conditional updates, rely on the check done before.
+            Err(object_store::Error::NotImplemented) => object_store.put(location, payload).await,
+            result => result,
+        };
+    }
+    log::debug!("Using a multipart upload to write `{}`", location);
+    let upload = object_store
+        .put_multipart_opts(
+            location,
+            PutMultipartOpts {
+                tags,
+                ..Default::default()
+            },
+        )
+        .await?;
+    let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_PART_SIZE);
+    loop {
+        writer.write(&part);
+        part.clear();
+        let read = (&mut reader)
+            .take(UPLOAD_PART_SIZE as u64)
+            .read_to_end(&mut part)
+            .await;
+        match read {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                writer.abort().await?;
+                return Err(read_error(e));
+            }
+        }
+        if let Err(e) = writer.wait_for_capacity(UPLOAD_MAX_CONCURRENT_PARTS).await {
+            writer.abort().await?;
+            return Err(e);
+        }
+    }
+    writer.finish().await
+}
+
+/// Maps an error reading the file to upload to an object store error.
+fn read_error(e: std::io::Error) -> object_store::Error {
+    object_store::Error::Generic {
+        store: "ds",
+        source: Box::new(e),
+    }
+}
+
+/// Moves a file of the folder to its trash together with the update of the metadata.
+/// The metadata is written first, so that a concurrent change of the folder aborts the deletion.
+/// Returns [`object_store::Error::NotFound`] if the file doesn't exist.
+pub async fn delete<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    delete_input: DeleteInput<'_>,
+) -> Result<(Option<String>, Option<String>), object_store::Error> {
+    log::debug!(
+        "Attempting to delete from object store `{:?}`.",
+        &delete_input
+    );
+    let file_location = get_location_for_file(&delete_input.folder_entity, delete_input.file_id);
+    object_store.head(&file_location).await?;
+    let put_result = write_metadata(
+        object_store,
+        &delete_input.folder_entity,
+        delete_input.metadata_file,
+        delete_input.parent_etag,
+        delete_input.parent_version,
+    )
+    .await?;
+    let trash_location = get_location_for_trashed_file(
+        &delete_input.folder_entity,
+        delete_input.file_id,
+        delete_input.deleted_at,
+    );
+    log::debug!(
+        "Attempting to move file `{}` to `{}`",
+        &file_location,
+        &trash_location
+    );
+    object_store.rename(&file_location, &trash_location).await?;
+    Ok((put_result.e_tag, put_result.version))
+}
+
+/// Restores a file of the folder from its trash together with the update of the metadata.
+/// As for [`delete`], the metadata is written first.
+/// Returns [`object_store::Error::NotFound`] if the file is not in the trash and
+/// [`object_store::Error::AlreadyExists`] if a file with the same id has been uploaded in the meantime.
+pub async fn restore<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    restore_input: RestoreInput<'_>,
+) -> Result<(Option<String>, Option<String>), object_store::Error> {
+    log::debug!(
+        "Attempting to restore from the trash of the object store `{:?}`.",
+        &restore_input
+    );
+    let trash_location = get_location_for_trashed_file(
+        &restore_input.folder_entity,
+        restore_input.file_id,
+        restore_input.deleted_at,
+    );
+    object_store.head(&trash_location).await?;
+    let file_location = get_location_for_file(&restore_input.folder_entity, restore_input.file_id);
+    match object_store.head(&file_location).await {
+        Ok(_) => {
+            return Err(object_store::Error::AlreadyExists {
+                path: file_location.to_string(),
+                source: "The file has been uploaded again after its deletion".into(),
+            })
+        }
+        Err(object_store::Error::NotFound { .. }) => (),
+        Err(e) => return Err(e),
+    }
+    let put_result = write_metadata(
+        object_store,
+        &restore_input.folder_entity,
+        restore_input.metadata_file,
+        restore_input.parent_etag,
+        restore_input.parent_version,
+    )
+    .await?;
+    log::debug!(
+        "Attempting to move file `{}` back to `{}`",
+        &trash_location,
+        &file_location
+    );
+    object_store.rename(&trash_location, &file_location).await?;
+    Ok((put_result.e_tag, put_result.version))
+}
+
+/// Lists the files in the trash of a folder, ordered by file id and then by time of deletion.
+/// Each file is returned with the time it was deleted, in microseconds since the Unix epoch.
+pub async fn list_trash<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+) -> Result<Vec<(String, u64, ObjectMeta)>, object_store::Error> {
+    let prefix = get_location_for_trash(folder_entity);
+    log::debug!("Attempting to list the trash in `{}`", &prefix);
+    let objects: Vec<ObjectMeta> = object_store.list(Some(&prefix)).try_collect().await?;
+    let mut trashed: Vec<(String, u64, ObjectMeta)> = vec![];
+    for meta in objects {
+        let Some((file_id, deleted_at)) = parse_trashed_location(&prefix, &meta.location) else {
+            continue;
+        };
+        let meta = resolve_blob_size(object_store, folder_entity, meta).await?;
+        trashed.push((file_id, deleted_at, meta));
+    }
+    trashed.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    Ok(trashed)
+}
+
+/// Returns the file id and the time of deletion of a file in the trash.
+fn parse_trashed_location(prefix: &Path, location: &Path) -> Option<(String, u64)> {
+    let mut parts = location.prefix_match(prefix)?;
+    let file_id = parts.next()?.as_ref().to_string();
+    let deleted_at = parts.next()?.as_ref().parse::<u64>().ok()?;
+    Some((file_id, deleted_at))
+}
+
+/// Permanently deletes the files of the trash of a folder deleted before the given time,
+/// in microseconds since the Unix epoch. Returns how many files were deleted.
+pub async fn purge_trash<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    deleted_before: u64,
+) -> Result<usize, object_store::Error> {
+    let prefix = get_location_for_trash(folder_entity);
+    let objects: Vec<ObjectMeta> = object_store.list(Some(&prefix)).try_collect().await?;
+    let expired: Vec<Path> = objects
+        .into_iter()
+        .filter(|meta| {
+            matches!(
+                parse_trashed_location(&prefix, &meta.location),
+                Some((_, deleted_at)) if deleted_at < deleted_before
+            )
+        })
+        .map(|meta| meta.location)
+        .collect();
+    let count = expired.len();
+    if count > 0 {
+        log::debug!(
+            "Attempting to purge {} files from the trash of folder `{}`",
+            count,
+            folder_entity.folder_id
+        );
+        object_store
+            .delete_stream(stream::iter(expired.into_iter().map(Ok)).boxed())
+            .try_collect::<Vec<Path>>()
+            .await?;
+    }
+    Ok(count)
+}
+
+/// Starts a resumable upload of a file, returning the id of the multipart upload.
+pub async fn start_upload(
+    multipart_store: &DynamicMultipartStore,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+) -> Result<MultipartId, object_store::Error> {
+    let file_location = get_location_for_file(folder_entity, file_id);
+    log::debug!(
+        "Attempting to start a multipart upload to `{}`",
+        &file_location
+    );
+    multipart_store.create_multipart(&file_location).await
+}
+
+/// Uploads a part of a resumable upload. The parts are numbered from 0.
+pub async fn upload_part(
+    multipart_store: &DynamicMultipartStore,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    multipart_id: &MultipartId,
+    part_number: u32,
+    part: Vec<u8>,
+) -> Result<PartId, object_store::Error> {
+    let file_location = get_location_for_file(folder_entity, file_id);
+    log::debug!(
+        "Attempting to upload part `{}` of `{}`",
+        part_number,
+        &file_location
+    );
+    multipart_store
+        .put_part(
+            &file_location,
+            multipart_id,
+            part_number as usize,
+            PutPayload::from_bytes(part.into()),
+        )
+        .await
+}
+
+/// Completes a resumable upload together with the update of the metadata.
+/// The metadata is written first, so that a concurrent change of the folder leaves the upload pending.
+pub async fn complete_upload<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    multipart_store: &DynamicMultipartStore,
+    complete_input: CompleteUploadInput<'_>,
+) -> Result<(Option<String>, Option<String>), object_store::Error> {
+    log::debug!(
+        "Attempting to complete an upload to object store `{:?}`.",
+        &complete_input
+    );
+    let put_result = write_metadata(
+        object_store,
+        &complete_input.folder_entity,
+        complete_input.metadata_file,
+        complete_input.parent_etag,
+        complete_input.parent_version,
+    )
+    .await?;
+    let file_location =
+        get_location_for_file(&complete_input.folder_entity, complete_input.file_id);
+    multipart_store
+        .complete_multipart(
+            &file_location,
+            &complete_input.multipart_id,
+            complete_input.parts,
+        )
+        .await?;
+    Ok((put_result.e_tag, put_result.version))
+}
+
+/// Aborts a resumable upload, removing the parts already uploaded.
+pub async fn abort_upload(
+    multipart_store: &DynamicMultipartStore,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    multipart_id: &MultipartId,
+) -> Result<(), object_store::Error> {
+    let file_location = get_location_for_file(folder_entity, file_id);
+    log::debug!("Attempting to abort the upload to `{}`", &file_location);
+    multipart_store
+        .abort_multipart(&file_location, multipart_id)
+        .await
+}
+
+/// Issues a presigned URL to read (`GET`) or write (`PUT`) a file directly in the object store,
+/// valid for [`PRESIGNED_URL_EXPIRATION`].
+/// The URL points to the blob with content hash `blob` if given, for files pointing to a blob.
+pub async fn presign(
+    signer: &DynamicSigner,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    blob: Option<&str>,
+    method: Method,
+) -> Result<String, object_store::Error> {
+    let file_location = match blob {
+        Some(content_hash) => get_location_for_blob(folder_entity, content_hash),
+        None => get_location_for_file(folder_entity, file_id),
+    };
+    log::debug!(
+        "Attempting to presign `{}` for `{}`",
+        &method,
+        &file_location
+    );
+    signer
+        .signed_url(method, &file_location, PRESIGNED_URL_EXPIRATION)
+        .await
+        .map(|url| url.to_string())
+}
+
+/// Writes the metadata file of a folder.
+/// If a parent etag or version is given, the metadata is updated only if it still matches,
+/// otherwise it is created only if it doesn't exist yet.
+async fn write_metadata<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    metadata_file: Vec<u8>,
+    parent_etag: Option<String>,
+    parent_version: Option<String>,
+) -> Result<PutResult, object_store::Error> {
     // We use a form of optimistic concurrency control. We could allow a more fine-grained
     // control over the single file, if the server would have a certain degree of access into the metadata file.
-    let metadata_location = get_location_for_metadata_file(&write_input.folder_entity);
-    let metadata_payload = PutPayload::from_bytes(write_input.metadata_file.into());
-    let put_result = if write_input.parent_etag.is_some() || write_input.parent_version.is_some() {
+    let metadata_location = get_location_for_metadata_file(folder_entity);
+    let metadata_payload = PutPayload::from_bytes(metadata_file.into());
+    let history_payload = metadata_payload.clone();
+    let put_result = if parent_etag.is_some() || parent_version.is_some() {
         log::info!(
             "Try to write a new version of the metadata file for folder `{}`",
-            &write_input.folder_entity.folder_id,
+            &folder_entity.folder_id,
         );
         let version = UpdateVersion {
-            e_tag: write_input.parent_etag,
-            version: write_input.parent_version,
+            e_tag: parent_etag,
+            version: parent_version,
         };
         log::debug!("Metadata version `{:?}`", &version);
         object_store
@@ -178,7 +1257,7 @@ pub async fn write<'a>(
     } else {
         log::info!(
             "Try creating the metadata object for the first time for folder `{}`",
-            &write_input.folder_entity.folder_id
+            &folder_entity.folder_id
         );
         object_store
             .put_opts(&metadata_location, metadata_payload, PutMode::Create.into())
@@ -192,40 +1271,231 @@ pub async fn write<'a>(
         .expect(
             "At least one of etag or version should be present after writing the metadata file!",
         );
-    let file_location = get_location_for_file(&write_input.folder_entity, write_input.file_id);
-    if let Some(file) = write_input.file_to_write {
-        log::debug!("Attempting to write file `{}`", &file_location);
-        let file_payload = PutPayload::from_bytes(file.into());
-        object_store.put(&file_location, file_payload).await?;
+    // The new version is already the head, so a failure to keep its copy must not fail the write.
+    if let Err(e) = record_metadata_version(object_store, folder_entity, history_payload).await {
+        log::error!(
+            "Couldn't keep a copy of the metadata of folder `{}` in its history: `{}`",
+            folder_entity.folder_id,
+            e
+        );
     }
-    Ok((put_result.e_tag, put_result.version))
+    Ok(put_result)
+}
+
+/// The current time in microseconds since the Unix epoch, identifying the versions in the history and the trash.
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_micros() as u64)
+}
+
+/// Keeps a copy of a version of the metadata file in the history of the folder.
+/// The versions are identified by the time they are written, in microseconds since the Unix epoch.
+async fn record_metadata_version<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    metadata_payload: PutPayload,
+) -> Result<PutResult, object_store::Error> {
+    let location = get_location_for_metadata_version(folder_entity, now_micros());
+    log::debug!("Attempting to record the metadata version `{}`", &location);
+    object_store.put(&location, metadata_payload).await
+}
+
+/// Lists the versions of the metadata file of a folder written after the given one, in chronological order.
+pub async fn list_metadata_versions<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    after: Option<u64>,
+) -> Result<Vec<(u64, ObjectMeta)>, object_store::Error> {
+    let prefix = get_location_for_metadata_history(folder_entity);
+    log::debug!("Attempting to list the metadata versions in `{}`", &prefix);
+    let objects: Vec<ObjectMeta> = object_store.list(Some(&prefix)).try_collect().await?;
+    let mut versions: Vec<(u64, ObjectMeta)> = objects
+        .into_iter()
+        .filter_map(|meta| {
+            let version_id = meta.location.filename()?.parse::<u64>().ok()?;
+            Some((version_id, meta))
+        })
+        .filter(|(version_id, _)| after.is_none_or(|after| *version_id > after))
+        .collect();
+    versions.sort_by_key(|(version_id, _)| *version_id);
+    Ok(versions)
+}
+
+/// Reads a version of the metadata file of a folder from its history.
+pub async fn read_metadata_from_history<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    version_id: u64,
+) -> Result<Vec<u8>, object_store::Error> {
+    let location = get_location_for_metadata_version(folder_entity, version_id);
+    log::debug!("Attempting to read the metadata version `{}`", &location);
+    let bytes = object_store.get(&location).await?.bytes().await?;
+    Ok(bytes.into())
 }
 
-/// Reads a file from the object store.
+/// Opens a file of the object store, the content can then be streamed from the returned [`GetResult`].
+/// If the file points to a blob, the content of the blob is returned with the etag and version of the file.
+pub async fn open_file<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    file_id: &str,
+) -> Result<GetResult, object_store::Error> {
+    let location = get_location_for_file(folder_entity, file_id);
+    log::debug!("Attempting to open `{}`", &location);
+    let file = object_store.get(&location).await?;
+    if file.meta.size != BLOB_POINTER_SIZE {
+        return Ok(file);
+    }
+    let meta = file.meta.clone();
+    let content = file.bytes().await?;
+    let Some(content_hash) = parse_blob_pointer(&content) else {
+        // Not a pointer, return the content that has already been read.
+        return Ok(GetResult {
+            range: 0..content.len(),
+            payload: GetResultPayload::Stream(stream::once(async { Ok(content) }).boxed()),
+            meta,
+            attributes: Default::default(),
+        });
+    };
+    let mut blob = object_store
+        .get(&get_location_for_blob(folder_entity, &content_hash))
+        .await?;
+    blob.meta.e_tag = meta.e_tag;
+    blob.meta.version = meta.version;
+    Ok(blob)
+}
+
+/// Reads a file from the object store, if it satisfies the conditions of the options.
 pub async fn read_file<'a>(
     object_store: &MutexGuard<'a, DynamicStore>,
     folder_entity: &FolderEntity,
     file_id: &str,
+    options: GetOptions,
 ) -> Result<(Vec<u8>, ObjectMeta), object_store::Error> {
     let location = get_location_for_file(folder_entity, file_id);
-    log::debug!("Attempting to read from `{}`", &location);
-    let result = object_store.get(&location).await?;
+    log::debug!("Attempting to read `{}` with `{:?}`", &location, &options);
+    let result = object_store.get_opts(&location, options).await?;
     let meta = result.meta.clone();
     let bytes = result.bytes().await?;
     Ok((bytes.into(), meta))
 }
 
-/// Reads the metadata of a folder.
+/// Lists all the files stored in a folder, excluding the metadata file, its history, the trash and the batch copies.
+pub async fn list_files<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+) -> Result<Vec<ObjectMeta>, object_store::Error> {
+    let prefix = Path::from(get_folder_name_prefix(folder_entity));
+    let history_prefix = get_location_for_metadata_history(folder_entity);
+    let trash_prefix = get_location_for_trash(folder_entity);
+    let batch_prefix = get_location_for_file(folder_entity, BATCH_FOLDER_NAME);
+    log::debug!("Attempting to list the files in `{}`", &prefix);
+    let blobs_prefix = get_location_for_blobs(folder_entity);
+    let files: Vec<ObjectMeta> = object_store.list(Some(&prefix)).try_collect().await?;
+    let mut listed = vec![];
+    for file in files
+        .into_iter()
+        .filter(|file| !file.location.filename().is_some_and(is_metadata_file_name))
+        .filter(|file| !file.location.prefix_matches(&history_prefix))
+        .filter(|file| !file.location.prefix_matches(&trash_prefix))
+        .filter(|file| !file.location.prefix_matches(&batch_prefix))
+        .filter(|file| !file.location.prefix_matches(&blobs_prefix))
+    {
+        listed.push(resolve_blob_size(object_store, folder_entity, file).await?);
+    }
+    Ok(listed)
+}
+
+/// Deletes all the objects of a folder, including the metadata file.
+pub async fn delete_folder<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+) -> Result<(), object_store::Error> {
+    let prefix = Path::from(get_folder_name_prefix(folder_entity));
+    log::debug!("Attempting to delete all the objects in `{}`", &prefix);
+    let locations = object_store
+        .list(Some(&prefix))
+        .map_ok(|object| object.location)
+        .boxed();
+    object_store
+        .delete_stream(locations)
+        .try_collect::<Vec<Path>>()
+        .await?;
+    Ok(())
+}
+
+/// Lists the ids of the folders having objects in the object store, in ascending order.
+pub async fn list_folder_ids<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+) -> Result<Vec<u64>, object_store::Error> {
+    log::debug!("Attempting to list the folders in the object store");
+    let listing = object_store.list_with_delimiter(None).await?;
+    let mut folder_ids: Vec<u64> = listing
+        .common_prefixes
+        .iter()
+        .filter_map(|prefix| prefix.filename()?.parse::<u64>().ok())
+        .collect();
+    folder_ids.sort();
+    Ok(folder_ids)
+}
+
+/// Recreates the metadata file of a folder from the latest version of its history.
+/// Returns whether the metadata file has been restored, i.e. if the history is not empty.
+pub async fn restore_latest_metadata<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+) -> Result<bool, object_store::Error> {
+    let Some((version_id, _)) = list_metadata_versions(object_store, folder_entity, None)
+        .await?
+        .pop()
+    else {
+        return Ok(false);
+    };
+    let metadata_file = read_metadata_from_history(object_store, folder_entity, version_id).await?;
+    init_metadata(object_store, folder_entity.clone(), metadata_file).await?;
+    Ok(true)
+}
+
+/// Reads the metadata of a folder, only if its etag satisfies the conditions of the `If-Match`
+/// and `If-None-Match` HTTP headers, which are evaluated by the object store.
 /// Do not deserialize the metadata file here, just return the bytes to the client.
-pub async fn read_metadata<'a>(
+/// Returns [`object_store::Error::Precondition`] if `if_match` fails, [`object_store::Error::NotModified`] if `if_none_match` does.
+pub async fn read_metadata_if<'a>(
     object_store: &MutexGuard<'a, DynamicStore>,
     folder_entity: &FolderEntity,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
 ) -> Result<(Vec<u8>, ObjectMeta), object_store::Error> {
-    read_file(object_store, &folder_entity, METADATA_FILE_NAME).await
+    let options = GetOptions {
+        if_match,
+        if_none_match,
+        ..Default::default()
+    };
+    read_file(object_store, folder_entity, METADATA_FILE_NAME, options).await
+}
+
+/// Returns the object metadata of the metadata file of a folder without reading its content,
+/// with the same conditions as [`read_metadata_if`].
+pub async fn head_metadata_if<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+    folder_entity: &FolderEntity,
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<ObjectMeta, object_store::Error> {
+    let location = get_location_for_metadata_file(folder_entity);
+    let options = GetOptions {
+        if_match,
+        if_none_match,
+        head: true,
+        ..Default::default()
+    };
+    log::debug!("Attempting to head `{}` with `{:?}`", &location, &options);
+    Ok(object_store.get_opts(&location, options).await?.meta)
 }
 
 /// Reads the metadata version of a folder.
-async fn read_metadata_version<'a>(
+pub async fn read_metadata_version<'a>(
     object_store: &MutexGuard<'a, DynamicStore>,
     folder_entity: &FolderEntity,
 ) -> Result<ObjectMeta, object_store::Error> {
@@ -237,6 +1507,16 @@ async fn read_metadata_version<'a>(
     object_store.head(&location).await
 }
 
+/// Check that the object store is reachable, looking up an object that is not expected to exist.
+pub async fn ping<'a>(
+    object_store: &MutexGuard<'a, DynamicStore>,
+) -> Result<(), object_store::Error> {
+    match object_store.head(&Path::from(".readyz")).await {
+        Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
 /// Get the location of a file in the object store, given the [`FolderEntity`] and the file id.
 fn get_location_for_file(folder_entity: &FolderEntity, file_id: &str) -> Path {
     Path::from(format!(
@@ -261,6 +1541,47 @@ fn get_location_for_metadata_file(folder_entity: &FolderEntity) -> Path {
     get_location_for_file(folder_entity, METADATA_FILE_NAME)
 }
 
+/// Get the location of the history of the metadata file of a folder.
+fn get_location_for_metadata_history(folder_entity: &FolderEntity) -> Path {
+    get_location_for_file(folder_entity, METADATA_HISTORY_FOLDER_NAME)
+}
+
+/// Get the location of a version of the metadata file of a folder.
+fn get_location_for_metadata_version(folder_entity: &FolderEntity, version_id: u64) -> Path {
+    get_location_for_metadata_history(folder_entity).child(version_id.to_string())
+}
+
+/// Get the location of the trash of a folder.
+fn get_location_for_trash(folder_entity: &FolderEntity) -> Path {
+    get_location_for_file(folder_entity, TRASH_FOLDER_NAME)
+}
+
+/// Get the location of the copy of a file replaced by a batch, see [`write_batch`].
+fn get_location_for_batch_copy(folder_entity: &FolderEntity, file_id: &str) -> Path {
+    get_location_for_file(folder_entity, BATCH_FOLDER_NAME).child(file_id)
+}
+
+/// Get the location of the blobs of a folder.
+fn get_location_for_blobs(folder_entity: &FolderEntity) -> Path {
+    get_location_for_file(folder_entity, BLOBS_FOLDER_NAME)
+}
+
+/// Get the location of a blob of a folder, given its content hash.
+fn get_location_for_blob(folder_entity: &FolderEntity, content_hash: &str) -> Path {
+    get_location_for_blobs(folder_entity).child(content_hash)
+}
+
+/// Get the location of a file in the trash of a folder, given the time it was deleted.
+fn get_location_for_trashed_file(
+    folder_entity: &FolderEntity,
+    file_id: &str,
+    deleted_at: u64,
+) -> Path {
+    get_location_for_trash(folder_entity)
+        .child(file_id)
+        .child(deleted_at.to_string())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -285,16 +1606,46 @@ mod tests {
 
     use super::*;
 
+    #[rocket::async_test]
+    async fn test_content_hash() {
+        let content = b"encrypted content";
+        assert_eq!(64, content_hash(content).len());
+        assert_ne!(content_hash(content), content_hash(b"other content"));
+        assert_eq!(
+            content_hash(content),
+            content_hash_of_reader(&content[..]).await.unwrap()
+        );
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            content_hash(b"")
+        );
+    }
+
+    /// The S3 storage of the docker-compose configuration.
+    fn s3_config() -> S3Config {
+        S3Config {
+            bucket: "test-bucket".to_string(),
+            endpoint: "https://localhost:4566".to_string(),
+            access_key_id: "test".to_string(),
+            secret_access_key: "test".to_string(),
+            max_retries: 1,
+            retry_timeout: 60,
+            allow_invalid_certificates: true,
+            dynamo_table: "test-table".to_string(),
+            dynamo_timeout: 10_000,
+            storage_class: None,
+            storage_class_after_days: 0,
+            noncurrent_storage_class: None,
+            noncurrent_after_days: 30,
+        }
+    }
+
     pub fn setup() -> DynamicStore {
         let _ = env_logger::builder().is_test(true).try_init();
         let config = StoreConfig {
             fs_fallback: true,
-            s3_storage: Some(S3Config {
-                bucket: "test-bucket".to_string(),
-                endpoint: "https://localhost:4566".to_string(),
-                access_key_id: "test".to_string(),
-                secret_access_key: "test".to_string(),
-            }),
+            s3_storage: Some(s3_config()),
+            ..Default::default()
         };
         initialise_object_store(config).unwrap()
     }
@@ -307,21 +1658,595 @@ mod tests {
         assert!(store.to_string().contains("test-bucket"));
     }
 
+    #[test]
+    fn test_s3_lifecycle() {
+        let mut config = s3_config();
+        assert!(S3Lifecycle::from_config(&config).unwrap().is_none());
+
+        config.storage_class = Some("GLACIER_IR".to_string());
+        config.noncurrent_storage_class = Some("DEEP_ARCHIVE".to_string());
+        let lifecycle = S3Lifecycle::from_config(&config).unwrap().unwrap();
+        assert!(lifecycle
+            .rules
+            .contains("<Tag><Key>ssf-object</Key><Value>content</Value></Tag>"));
+        assert!(lifecycle.rules.contains(
+            "<Transition><Days>0</Days><StorageClass>GLACIER_IR</StorageClass></Transition>"
+        ));
+        assert!(lifecycle.rules.contains(
+            "<NoncurrentDays>30</NoncurrentDays><StorageClass>DEEP_ARCHIVE</StorageClass>"
+        ));
+
+        // The current contents must stay readable without restoring them.
+        config.storage_class = Some("DEEP_ARCHIVE".to_string());
+        assert!(S3Lifecycle::from_config(&config).is_err());
+        config.storage_class = Some("STANDARD_IA".to_string());
+        assert!(S3Lifecycle::from_config(&config).is_err());
+        config.storage_class_after_days = 30;
+        assert!(S3Lifecycle::from_config(&config).is_ok());
+        config.noncurrent_storage_class = Some("UNKNOWN".to_string());
+        assert!(S3Lifecycle::from_config(&config).is_err());
+    }
+
     fn setup_local_fs() -> DynamicStore {
         let _ = env_logger::builder().is_test(true).try_init();
         let config = StoreConfig {
             fs_fallback: true,
             s3_storage: None,
+            ..Default::default()
+        };
+        initialise_object_store(config).unwrap()
+    }
+
+    /// The in-memory store supports the conditional updates, unlike the local file system.
+    fn setup_in_memory() -> DynamicStore {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let config = StoreConfig {
+            in_memory: true,
+            ..Default::default()
         };
         initialise_object_store(config).unwrap()
     }
 
+    #[tokio::test]
+    async fn test_presign() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let config = StoreConfig {
+            fs_fallback: true,
+            s3_storage: Some(s3_config()),
+            ..Default::default()
+        };
+        let signer = initialise_signer(&config).unwrap().unwrap();
+        let folder_entity = FolderEntity { folder_id: 42 };
+        let url = presign(&signer, &folder_entity, "file", None, Method::PUT)
+            .await
+            .unwrap();
+        assert!(url.starts_with("https://localhost:4566/test-bucket/42/file?"));
+        assert!(url.contains("X-Amz-Expires=900"));
+        assert!(url.contains("X-Amz-Signature="));
+        let content_hash = "a".repeat(64);
+        let url = presign(
+            &signer,
+            &folder_entity,
+            "file",
+            Some(&content_hash),
+            Method::GET,
+        )
+        .await
+        .unwrap();
+        assert!(url.starts_with(&format!(
+            "https://localhost:4566/test-bucket/42/.blobs/{}?",
+            content_hash
+        )));
+        let local_config = StoreConfig {
+            fs_fallback: true,
+            s3_storage: None,
+            ..Default::default()
+        };
+        assert!(initialise_signer(&local_config).unwrap().is_none());
+    }
+
     #[test]
     fn test_fallback_fs() {
         let store = setup_local_fs();
         assert!(store.to_string().contains("LocalFileSystem"));
     }
 
+    #[tokio::test]
+    async fn test_write_file_conflict() {
+        let store = Mutex::new(setup_in_memory());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let write_input =
+            |content: &'static [u8], parent_etag: &Option<String>, file_parent_etag| WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                file_to_write: Some(Box::new(content)),
+                metadata_file: content.to_vec(),
+                parent_etag: parent_etag.clone(),
+                parent_version: None,
+                file_parent_etag,
+            };
+        let (etag, _) = write(&store, write_input(b"first", &None, None))
+            .await
+            .unwrap();
+        let file_location = get_location_for_file(&folder_entity, "file");
+        let file_etag = store.head(&file_location).await.unwrap().e_tag;
+        // The file exists already, but its etag is not given.
+        let result = write(&store, write_input(b"second", &etag, None)).await;
+        assert!(matches!(result, Err(Error::AlreadyExists { .. })));
+        // The file changed in the meantime.
+        let result = write(
+            &store,
+            write_input(b"second", &etag, Some("other".to_string())),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Precondition { .. })));
+        // The metadata is left unchanged by the conflicts on the file.
+        let (metadata, _) = read_metadata_if(&store, &folder_entity, None, None)
+            .await
+            .unwrap();
+        assert_eq!(metadata, b"first");
+        write(&store, write_input(b"second", &etag, file_etag))
+            .await
+            .unwrap();
+        let content = store
+            .get(&file_location)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(content.as_ref(), b"second");
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let store = Mutex::new(setup_in_memory());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let read = |file_id: &'static str| {
+            let location = get_location_for_file(&folder_entity, file_id);
+            let store = &store;
+            async move {
+                match store.get(&location).await {
+                    Ok(result) => Some(result.bytes().await.unwrap().to_vec()),
+                    Err(Error::NotFound { .. }) => None,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        };
+        let (etag, _) = write_batch(
+            &store,
+            BatchWriteInput {
+                folder_entity: folder_entity.clone(),
+                files: vec![
+                    BatchFile {
+                        file_id: "first",
+                        content: Box::new(&b"first"[..]),
+                        file_parent_etag: None,
+                    },
+                    BatchFile {
+                        file_id: "second",
+                        content: Box::new(&b"second"[..]),
+                        file_parent_etag: None,
+                    },
+                ],
+                metadata_file: b"metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(read("first").await.unwrap(), b"first");
+        assert_eq!(read("second").await.unwrap(), b"second");
+        let first_etag = || async {
+            store
+                .head(&get_location_for_file(&folder_entity, "first"))
+                .await
+                .unwrap()
+                .e_tag
+        };
+        // A conflict on a file is detected before writing any of them.
+        let batch =
+            |parent_etag: Option<String>, file_parent_etag: Option<String>| BatchWriteInput {
+                folder_entity: folder_entity.clone(),
+                files: vec![
+                    BatchFile {
+                        file_id: "first",
+                        content: Box::new(&b"first-updated"[..]),
+                        file_parent_etag,
+                    },
+                    BatchFile {
+                        file_id: "third",
+                        content: Box::new(&b"third"[..]),
+                        file_parent_etag: None,
+                    },
+                ],
+                metadata_file: b"metadata-updated".to_vec(),
+                parent_etag,
+                parent_version: None,
+            };
+        let result = write_batch(&store, batch(etag.clone(), Some("other".to_string()))).await;
+        assert!(matches!(result, Err(Error::Precondition { .. })));
+        // A conflict on the metadata rolls back the files already written.
+        let result =
+            write_batch(&store, batch(Some("other".to_string()), first_etag().await)).await;
+        assert!(matches!(result, Err(Error::Precondition { .. })));
+        assert_eq!(read("first").await.unwrap(), b"first");
+        assert_eq!(read("third").await, None);
+        let (metadata, _) = read_metadata_if(&store, &folder_entity, None, None)
+            .await
+            .unwrap();
+        assert_eq!(metadata, b"metadata");
+        // The in-memory store doesn't derive the etag from the content, so the restored file has a new one.
+        write_batch(&store, batch(etag, first_etag().await))
+            .await
+            .unwrap();
+        assert_eq!(read("first").await.unwrap(), b"first-updated");
+        assert_eq!(read("third").await.unwrap(), b"third");
+        // The copies of the replaced files are not kept, nor listed.
+        let files = list_files(&store, &folder_entity).await.unwrap();
+        assert_eq!(3, files.len());
+        let batch_prefix = get_location_for_file(&folder_entity, BATCH_FOLDER_NAME);
+        let copies: Vec<ObjectMeta> = store.list(Some(&batch_prefix)).try_collect().await.unwrap();
+        assert!(copies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_history() {
+        let store = Mutex::new(setup_in_memory());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let (etag, version) = init_metadata(&store, folder_entity.clone(), b"first".to_vec())
+            .await
+            .unwrap();
+        write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                file_to_write: Some(Box::new(&b"test-file"[..])),
+                metadata_file: b"second".to_vec(),
+                parent_etag: etag,
+                parent_version: version,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let versions = list_metadata_versions(&store, &folder_entity, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|(_, meta)| meta.size)
+                .collect::<Vec<_>>(),
+            vec![b"first".len(), b"second".len()]
+        );
+        let after_first = list_metadata_versions(&store, &folder_entity, Some(versions[0].0))
+            .await
+            .unwrap();
+        assert_eq!(after_first.len(), 1);
+        assert_eq!(after_first[0].0, versions[1].0);
+        let first = read_metadata_from_history(&store, &folder_entity, versions[0].0)
+            .await
+            .unwrap();
+        assert_eq!(b"first".to_vec(), first);
+        // The history is not listed among the files.
+        let files = list_files(&store, &folder_entity).await.unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(Some("file"), files[0].location.filename());
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        let store = Mutex::new(setup_in_memory());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let (etag, version) = init_metadata(&store, folder_entity.clone(), b"empty".to_vec())
+            .await
+            .unwrap();
+        let (etag, version) = write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                file_to_write: Some(Box::new(&b"test-file"[..])),
+                metadata_file: b"with file".to_vec(),
+                parent_etag: etag,
+                parent_version: version,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let (etag, version) = delete(
+            &store,
+            DeleteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                deleted_at: now_micros(),
+                metadata_file: b"empty".to_vec(),
+                parent_etag: etag,
+                parent_version: version,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(list_files(&store, &folder_entity).await.unwrap().is_empty());
+        let trash = list_trash(&store, &folder_entity).await.unwrap();
+        assert_eq!(1, trash.len());
+        let (file_id, deleted_at, meta) = &trash[0];
+        assert_eq!("file", file_id);
+        assert_eq!(b"test-file".len(), meta.size);
+        let restore_input =
+            |deleted_at, etag: &Option<String>, version: &Option<String>| RestoreInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                deleted_at,
+                metadata_file: b"with file".to_vec(),
+                parent_etag: etag.clone(),
+                parent_version: version.clone(),
+            };
+        let not_found = restore(&store, restore_input(0, &etag, &version)).await;
+        assert!(matches!(not_found, Err(Error::NotFound { .. })));
+        restore(&store, restore_input(*deleted_at, &etag, &version))
+            .await
+            .unwrap();
+        assert!(list_trash(&store, &folder_entity).await.unwrap().is_empty());
+        let (content, _) = read_file(&store, &folder_entity, "file", GetOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(b"test-file".to_vec(), content);
+        // Only the files deleted before the given time are purged.
+        let (etag, version) = read_metadata_if(&store, &folder_entity, None, None)
+            .await
+            .map(|(_, meta)| (meta.e_tag, meta.version))
+            .unwrap();
+        delete(
+            &store,
+            DeleteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "file",
+                deleted_at: now_micros(),
+                metadata_file: b"empty".to_vec(),
+                parent_etag: etag,
+                parent_version: version,
+            },
+        )
+        .await
+        .unwrap();
+        let (_, deleted_at, _) = list_trash(&store, &folder_entity).await.unwrap()[0].clone();
+        assert_eq!(
+            0,
+            purge_trash(&store, &folder_entity, deleted_at)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            1,
+            purge_trash(&store, &folder_entity, deleted_at + 1)
+                .await
+                .unwrap()
+        );
+        assert!(list_trash(&store, &folder_entity).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        // The S3 storage of the default profile is ignored.
+        let config = StoreConfig {
+            fs_fallback: true,
+            in_memory: true,
+            s3_storage: Some(S3Config {
+                storage_class: Some("GLACIER_IR".to_string()),
+                ..s3_config()
+            }),
+            ..Default::default()
+        };
+        assert!(initialise_signer(&config).unwrap().is_none());
+        assert!(initialise_lifecycle(&config).unwrap().is_none());
+        let multipart_store = initialise_multipart_store(&config).unwrap().unwrap();
+        let store = initialise_object_store(config).unwrap();
+        assert!(store.to_string().contains("InMemory"));
+        // Resumable uploads are readable from the object store.
+        let location = Path::from("42/file");
+        let multipart_id = multipart_store.create_multipart(&location).await.unwrap();
+        let part = multipart_store
+            .put_part(
+                &location,
+                &multipart_id,
+                0,
+                PutPayload::from_static(b"content"),
+            )
+            .await
+            .unwrap();
+        multipart_store
+            .complete_multipart(&location, &multipart_id, vec![part])
+            .await
+            .unwrap();
+        let content = store.get(&location).await.unwrap().bytes().await.unwrap();
+        assert_eq!(content.as_ref(), b"content");
+    }
+
+    #[tokio::test]
+    async fn test_list_files() {
+        let store = Mutex::new(setup_local_fs());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let file_name = create_random_file_name();
+        write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: &file_name,
+                file_to_write: Some(Box::new(&b"test-file"[..])),
+                metadata_file: b"test-metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let files = list_files(&store, &folder_entity).await.unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(Some(file_name.as_str()), files[0].location.filename());
+        assert_eq!(b"test-file".len(), files[0].size);
+    }
+
+    #[tokio::test]
+    async fn test_read_metadata_if() {
+        let store = Mutex::new(setup_local_fs());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let (etag, _) = write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: "",
+                file_to_write: None,
+                metadata_file: b"test-metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let etag = etag.unwrap();
+        let not_modified = read_metadata_if(&store, &folder_entity, None, Some(etag.clone())).await;
+        assert!(matches!(not_modified, Err(Error::NotModified { .. })));
+        let (content, meta) = read_metadata_if(
+            &store,
+            &folder_entity,
+            Some(etag.clone()),
+            Some("other".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(content, b"test-metadata");
+        assert_eq!(meta.e_tag, Some(etag));
+        let precondition =
+            read_metadata_if(&store, &folder_entity, Some("other".to_string()), None).await;
+        assert!(matches!(precondition, Err(Error::Precondition { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_write_large_file_in_parts() {
+        let store = Mutex::new(setup_local_fs());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let file_name = create_random_file_name();
+        let content: Vec<u8> = (0..(2 * UPLOAD_PART_SIZE + 10))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: &file_name,
+                file_to_write: Some(Box::new(content.as_slice())),
+                metadata_file: b"test-metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let file = open_file(&store, &folder_entity, &file_name).await.unwrap();
+        assert_eq!(content.len(), file.meta.size);
+        let read: Vec<u8> = file
+            .into_stream()
+            .try_fold(vec![], |mut read, chunk| async move {
+                read.extend_from_slice(&chunk);
+                Ok(read)
+            })
+            .await
+            .unwrap();
+        assert!(read == content);
+    }
+
+    #[tokio::test]
+    async fn test_delete_folder() {
+        let store = Mutex::new(setup_local_fs());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let file_name = create_random_file_name();
+        write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: &file_name,
+                file_to_write: Some(Box::new(&b"test-file"[..])),
+                metadata_file: b"test-metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        delete_folder(&store, &folder_entity).await.unwrap();
+        assert!(list_files(&store, &folder_entity).await.unwrap().is_empty());
+        assert!(matches!(
+            read_metadata_if(&store, &folder_entity, None, None).await,
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restore_latest_metadata() {
+        let store = Mutex::new(setup_in_memory());
+        let store = store.lock().await;
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        init_metadata(&store, folder_entity.clone(), b"first".to_vec())
+            .await
+            .unwrap();
+        assert!(list_folder_ids(&store)
+            .await
+            .unwrap()
+            .contains(&folder_entity.folder_id));
+        store
+            .delete(&get_location_for_metadata_file(&folder_entity))
+            .await
+            .unwrap();
+        assert!(restore_latest_metadata(&store, &folder_entity)
+            .await
+            .unwrap());
+        let (metadata, _) = read_metadata_if(&store, &folder_entity, None, None)
+            .await
+            .unwrap();
+        assert_eq!(metadata, b"first");
+        let empty_folder = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        assert!(!restore_latest_metadata(&store, &empty_folder)
+            .await
+            .unwrap());
+    }
+
     /// You will need to start `Localstack` provided in services/docker-compose.yaml file to run this test.
     #[tokio::test]
     async fn test_write_file_with_metadata() {
@@ -333,10 +2258,11 @@ mod tests {
         let write_input = WriteInput {
             folder_entity: folder_entity.clone(),
             file_id: &file_name,
-            file_to_write: Some(b"test-file".to_vec()),
+            file_to_write: Some(Box::new(&b"test-file"[..])),
             metadata_file: b"test-metadata".to_vec(),
             parent_etag: None,
             parent_version: None,
+            file_parent_etag: None,
         };
         let store = store.lock().await;
         let result = write(&store, write_input).await.unwrap();
@@ -345,13 +2271,19 @@ mod tests {
         log::debug!("Metadata `{:?}`", metadata_version);
         assert_eq!(metadata_version.e_tag, result.0);
         assert_eq!(metadata_version.version, result.1);
+        let file_etag = store
+            .head(&get_location_for_file(&folder_entity, &file_name))
+            .await
+            .unwrap()
+            .e_tag;
         let conflict_write = WriteInput {
             folder_entity,
             file_id: &file_name,
-            file_to_write: Some(b"test-file-updated".to_vec()),
+            file_to_write: Some(Box::new(&b"test-file-updated"[..])),
             metadata_file: b"test-metadata-updated".to_vec(),
             parent_etag: Some("some-etag".to_string()),
             parent_version: Some("some-version".to_string()),
+            file_parent_etag: file_etag,
         };
         let result_2 = write(&store, conflict_write).await;
         assert!(result_2.is_err());
@@ -363,4 +2295,62 @@ mod tests {
             }
         }
     }
+
+    /// You will need to start `Localstack` provided in services/docker-compose.yaml file to run this test.
+    #[tokio::test]
+    async fn test_delete_file_with_metadata() {
+        let store = setup();
+        let store = Mutex::new(store);
+        let folder_entity = FolderEntity {
+            folder_id: create_random_file_id(),
+        };
+        let file_name = create_random_file_name();
+        let store = store.lock().await;
+        let (etag, version) = write(
+            &store,
+            WriteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: &file_name,
+                file_to_write: Some(Box::new(&b"test-file"[..])),
+                metadata_file: b"test-metadata".to_vec(),
+                parent_etag: None,
+                parent_version: None,
+                file_parent_etag: None,
+            },
+        )
+        .await
+        .unwrap();
+        let result = delete(
+            &store,
+            DeleteInput {
+                folder_entity: folder_entity.clone(),
+                file_id: &file_name,
+                deleted_at: now_micros(),
+                metadata_file: b"test-metadata-without-file".to_vec(),
+                parent_etag: etag,
+                parent_version: version,
+            },
+        )
+        .await
+        .unwrap();
+        let metadata_version = read_metadata_version(&store, &folder_entity).await.unwrap();
+        assert_eq!(metadata_version.e_tag, result.0);
+        match read_file(&store, &folder_entity, &file_name, GetOptions::default()).await {
+            Err(Error::NotFound { .. }) => (),
+            otherwise => panic!("The file should be deleted, got `{:?}`", otherwise),
+        }
+        let not_found = delete(
+            &store,
+            DeleteInput {
+                folder_entity,
+                file_id: &file_name,
+                deleted_at: now_micros(),
+                metadata_file: b"test-metadata-without-file".to_vec(),
+                parent_etag: result.0,
+                parent_version: result.1,
+            },
+        )
+        .await;
+        assert!(matches!(not_found, Err(Error::NotFound { .. })));
+    }
 }
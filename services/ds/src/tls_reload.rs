@@ -0,0 +1,115 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rocket::{fairing::AdHoc, tokio};
+
+/// The reload of the TLS certificate, loaded from the `tls_reload` table of the `DS_Rocket.toml` file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TlsReloadConfig {
+    /// How often to check whether the TLS certificate file changed, in seconds.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5 * 60
+}
+
+impl Default for TlsReloadConfig {
+    fn default() -> Self {
+        TlsReloadConfig {
+            interval: default_interval(),
+        }
+    }
+}
+
+/// Whether the server has been shut down to load a new TLS certificate, so that it must be launched again.
+#[derive(Clone, Default)]
+pub struct TlsReload(Arc<AtomicBool>);
+
+impl TlsReload {
+    pub fn new() -> Self {
+        TlsReload::default()
+    }
+
+    /// Whether a new TLS certificate is waiting to be loaded.
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A fairing watching the TLS certificate file, e.g. renewed by the PKI ahead of its expiry.
+/// Rocket only loads the certificate when it is launched: once the file changes, the server is shut down
+/// gracefully and a [`TlsReload`] is requested, so that the server is launched again with the new certificate.
+pub fn fairing(config: TlsReloadConfig, certs_path: Option<String>) -> AdHoc {
+    AdHoc::on_liftoff("TLS reload", move |rocket| {
+        Box::pin(async move {
+            let Some(certs_path) = certs_path else {
+                log::info!("The TLS certificate is not loaded from a file, it won't be reloaded");
+                return;
+            };
+            let Some(reload) = rocket.state::<TlsReload>() else {
+                log::error!(
+                    "The server state is not initialised, the TLS certificate won't be reloaded"
+                );
+                return;
+            };
+            let loaded = match tokio::fs::read(&certs_path).await {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    log::error!(
+                        "Couldn't read the TLS certificate `{}`, it won't be reloaded: `{}`",
+                        certs_path,
+                        e
+                    );
+                    return;
+                }
+            };
+            let reload = reload.clone();
+            let mut shutdown = rocket.shutdown();
+            let trigger = shutdown.clone();
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            match tokio::fs::read(&certs_path).await {
+                                Ok(current) if !current.is_empty() && current != loaded => {
+                                    log::info!("The TLS certificate changed, shutting down the server to load it");
+                                    reload.request();
+                                    trigger.notify();
+                                    break;
+                                }
+                                Ok(_) => {}
+                                Err(e) => log::error!("Couldn't read the TLS certificate `{}`: `{}`", certs_path, e),
+                            }
+                        },
+                        _ = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
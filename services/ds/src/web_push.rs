@@ -0,0 +1,398 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey},
+    hkdf::{self, Prk, Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rocket::{fairing::AdHoc, tokio};
+use rocket_db_pools::{sqlx, Database};
+use url::Url;
+
+use crate::{
+    db::{self, DbConn, PushSubscriptionEntity},
+    notifications::OfflineDelivery,
+    server::{Notification, NotificationEvent, OptionalWebPush, SenderSentEventQueue},
+};
+
+/// The size of the records of the encrypted push messages, a message is sent as a single record.
+const RECORD_SIZE: u32 = 4096;
+
+/// The maximum length of the endpoint of a subscription.
+const MAX_ENDPOINT_LENGTH: usize = 1000;
+
+/// How long a VAPID token is valid, the push services reject tokens valid for more than 24 hours.
+const VAPID_TOKEN_VALIDITY: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// The Web Push configuration, loaded from the `web_push` table of the `DS_Rocket.toml` file.
+/// Web Push is disabled if the table is missing.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct WebPushConfig {
+    /// The path of the PKCS#8 PEM file with the P-256 private key identifying the server to the push services (VAPID).
+    pub vapid_key: String,
+    /// The contact of the operator of the server, sent to the push services, e.g. `mailto:admin@example.com`.
+    pub subject: String,
+    /// How long the push services keep a message for an unreachable client, in seconds.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+fn default_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+/// Sends the Web Push messages, encrypted for each subscription (RFC 8291) and authenticated with VAPID (RFC 8292).
+pub struct WebPush {
+    key_pair: EcdsaKeyPair,
+    /// The public key of the server, base64url encoded, used by the clients to subscribe.
+    public_key: String,
+    subject: String,
+    ttl: u64,
+    client: reqwest::Client,
+}
+
+impl WebPush {
+    pub fn from_config(config: &WebPushConfig) -> Result<Self, String> {
+        let pem = std::fs::read_to_string(&config.vapid_key)
+            .map_err(|e| format!("Couldn't read the VAPID key `{}`: {}", config.vapid_key, e))?;
+        let pem = pem::parse(pem).map_err(|e| format!("Invalid VAPID key: {}", e))?;
+        let key_pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            pem.contents(),
+            &SystemRandom::new(),
+        )
+        .map_err(|e| format!("The VAPID key must be a PKCS#8 P-256 key: {}", e))?;
+        let public_key = URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref());
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| format!("Couldn't initialise the HTTP client: {}", e))?;
+        Ok(WebPush {
+            key_pair,
+            public_key,
+            subject: config.subject.clone(),
+            ttl: config.ttl,
+            client,
+        })
+    }
+
+    /// The public key of the server, base64url encoded.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// The value of the `Authorization` header for the push service of `endpoint`.
+    fn vapid_authorization(&self, endpoint: &Url) -> Result<String, String> {
+        let expiration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            + VAPID_TOKEN_VALIDITY;
+        let claims = rocket::serde::json::json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": expiration.as_secs(),
+            "sub": self.subject,
+        });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key_pair
+            .sign(&SystemRandom::new(), signing_input.as_bytes())
+            .map_err(|e| format!("Couldn't sign the VAPID token: {}", e))?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            self.public_key
+        ))
+    }
+
+    /// Push `payload` to a subscription, returning the status of the response of the push service.
+    async fn push(
+        &self,
+        subscription: &PushSubscriptionEntity,
+        payload: &[u8],
+    ) -> Result<reqwest::StatusCode, String> {
+        let endpoint = Url::parse(&subscription.endpoint).map_err(|e| e.to_string())?;
+        let p256dh = decode_key(&subscription.p256dh).ok_or("Invalid p256dh key")?;
+        let auth = decode_key(&subscription.auth).ok_or("Invalid auth secret")?;
+        let body = encrypt(&p256dh, &auth, payload)
+            .map_err(|_| "Couldn't encrypt the push message".to_string())?;
+        let response = self
+            .client
+            .post(endpoint.clone())
+            .header("Authorization", self.vapid_authorization(&endpoint)?)
+            .header("TTL", self.ttl.to_string())
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.status())
+    }
+}
+
+/// Check a subscription sent by a client, as returned by `PushSubscription.toJSON()` in the browsers.
+pub fn validate_subscription(endpoint: &str, p256dh: &str, auth: &str) -> Result<(), &'static str> {
+    if endpoint.len() > MAX_ENDPOINT_LENGTH {
+        return Err("The endpoint is too long.");
+    }
+    match Url::parse(endpoint) {
+        Ok(url) if url.scheme() == "https" => (),
+        _ => return Err("The endpoint must be an https URL."),
+    }
+    match decode_key(p256dh) {
+        Some(key) if key.len() == 65 && key[0] == 0x04 => (),
+        _ => {
+            return Err(
+                "The p256dh key must be an uncompressed P-256 public key, base64url encoded.",
+            )
+        }
+    }
+    match decode_key(auth) {
+        Some(secret) if secret.len() == 16 => Ok(()),
+        _ => Err("The auth secret must be 16 bytes, base64url encoded."),
+    }
+}
+
+fn decode_key(key: &str) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(key.trim_end_matches('=')).ok()
+}
+
+/// The output length of an HKDF expansion.
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn expand(prk: &Prk, info: &[u8], len: usize) -> Result<Vec<u8>, ring::error::Unspecified> {
+    let mut output = vec![0; len];
+    prk.expand(&[info], Len(len))?.fill(&mut output)?;
+    Ok(output)
+}
+
+/// Derive the content encryption key and the nonce of a message (RFC 8291, section 3.4).
+fn derive_key_and_nonce(
+    ecdh_secret: &[u8],
+    auth: &[u8],
+    ua_public: &[u8],
+    as_public: &[u8],
+    salt: &[u8],
+) -> Result<(LessSafeKey, Nonce), ring::error::Unspecified> {
+    let key_info = [b"WebPush: info\0", ua_public, as_public].concat();
+    let ikm = expand(
+        &Salt::new(HKDF_SHA256, auth).extract(ecdh_secret),
+        &key_info,
+        32,
+    )?;
+    let prk = Salt::new(HKDF_SHA256, salt).extract(&ikm);
+    let cek = expand(&prk, b"Content-Encoding: aes128gcm\0", 16)?;
+    let nonce = expand(&prk, b"Content-Encoding: nonce\0", 12)?;
+    Ok((
+        LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &cek)?),
+        Nonce::try_assume_unique_for_key(&nonce)?,
+    ))
+}
+
+/// Encrypt a push message for the client with public key `p256dh` and authentication secret `auth`,
+/// in a single `aes128gcm` record prefixed by its header (RFC 8188).
+fn encrypt(
+    p256dh: &[u8],
+    auth: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, ring::error::Unspecified> {
+    // The record holds the payload, its padding delimiter and the tag.
+    if payload.len() + 17 > RECORD_SIZE as usize {
+        return Err(ring::error::Unspecified);
+    }
+    let rng = SystemRandom::new();
+    let private_key = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)?;
+    let public_key = private_key.compute_public_key()?;
+    let mut salt = [0u8; 16];
+    rng.fill(&mut salt)?;
+    let ecdh_secret = agreement::agree_ephemeral(
+        private_key,
+        &UnparsedPublicKey::new(&agreement::ECDH_P256, p256dh),
+        |secret| secret.to_vec(),
+    )?;
+    let (key, nonce) =
+        derive_key_and_nonce(&ecdh_secret, auth, p256dh, public_key.as_ref(), &salt)?;
+    let mut record = [payload, &[2]].concat();
+    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut record)?;
+    Ok([
+        &salt[..],
+        &RECORD_SIZE.to_be_bytes(),
+        &[public_key.as_ref().len() as u8],
+        public_key.as_ref(),
+        &record,
+    ]
+    .concat())
+}
+
+/// Whether the receiver of the event should be woken up: the proposals and shares targeting it.
+fn is_pushed(event: NotificationEvent) -> bool {
+    matches!(
+        event,
+        NotificationEvent::Proposal
+            | NotificationEvent::Share
+            | NotificationEvent::Welcome
+            | NotificationEvent::Invitation
+    )
+}
+
+/// Delivers the notifications of the users without a connected client through their Web Push subscriptions.
+struct WebPushDelivery {
+    web_push: Arc<WebPush>,
+    pool: sqlx::AnyPool,
+}
+
+impl OfflineDelivery for WebPushDelivery {
+    fn deliver(&self, notification: &Notification) {
+        if !is_pushed(notification.event) {
+            return;
+        }
+        let web_push = self.web_push.clone();
+        let pool = self.pool.clone();
+        let notification = notification.clone();
+        tokio::spawn(async move {
+            let receiver = &notification.receiver;
+            let subscriptions = match db::list_push_subscriptions(receiver, &pool).await {
+                Ok(subscriptions) => subscriptions,
+                Err(e) => {
+                    log::error!(
+                        "Couldn't list the push subscriptions of `{}`: `{}`",
+                        receiver,
+                        e
+                    );
+                    return;
+                }
+            };
+            let payload = rocket::serde::json::to_string(&notification).expect("valid JSON");
+            for subscription in subscriptions {
+                match web_push.push(&subscription, payload.as_bytes()).await {
+                    Ok(status) if status.is_success() => {
+                        log::debug!("Pushed `{:?}` to `{}`", notification.event, receiver);
+                    }
+                    // The subscription expired or has been revoked.
+                    Ok(reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE) => {
+                        log::debug!(
+                            "Removing the expired push subscription `{}` of `{}`",
+                            subscription.subscription_id,
+                            receiver
+                        );
+                        if let Err(e) = db::delete_expired_push_subscription(
+                            subscription.subscription_id,
+                            &pool,
+                        )
+                        .await
+                        {
+                            log::error!("Couldn't remove the push subscription: `{}`", e);
+                        }
+                    }
+                    Ok(status) => log::warn!(
+                        "The push service rejected the message to `{}` with `{}`",
+                        receiver,
+                        status
+                    ),
+                    Err(e) => log::error!("Couldn't push the message to `{}`: `{}`", receiver, e),
+                }
+            }
+        });
+    }
+}
+
+/// A fairing registering Web Push as a delivery channel of the notifications of the offline users,
+/// if it is configured.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Web Push", |rocket| {
+        Box::pin(async move {
+            let Some(Some(web_push)) = rocket.state::<OptionalWebPush>() else {
+                log::info!("Web Push is disabled");
+                return;
+            };
+            let (Some(db), Some(queue)) = (
+                DbConn::fetch(rocket),
+                rocket.state::<SenderSentEventQueue>(),
+            ) else {
+                log::error!("The server state is not initialised, Web Push is disabled");
+                return;
+            };
+            queue.add_offline_delivery(Arc::new(WebPushDelivery {
+                web_push: web_push.clone(),
+                pool: db.0.clone(),
+            }));
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_validate_subscription() {
+        let p256dh = URL_SAFE_NO_PAD.encode([&[4u8][..], &[1u8; 64]].concat());
+        let auth = URL_SAFE_NO_PAD.encode([7u8; 16]);
+        let endpoint = "https://push.example.com/send/abc";
+        assert!(validate_subscription(endpoint, &p256dh, &auth).is_ok());
+        assert!(validate_subscription("http://push.example.com/send/abc", &p256dh, &auth).is_err());
+        assert!(validate_subscription(endpoint, &auth, &auth).is_err());
+        assert!(validate_subscription(endpoint, &p256dh, &p256dh).is_err());
+        let long_endpoint = format!("{}{}", endpoint, "a".repeat(MAX_ENDPOINT_LENGTH));
+        assert!(validate_subscription(&long_endpoint, &p256dh, &auth).is_err());
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let rng = SystemRandom::new();
+        // The key pair of the client.
+        let ua_private = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+        let ua_public = ua_private.compute_public_key().unwrap();
+        let auth = [9u8; 16];
+        let message = encrypt(ua_public.as_ref(), &auth, b"wake up").unwrap();
+        // Parse the header and decrypt the record as the client would do.
+        let (salt, rest) = message.split_at(16);
+        let (record_size, rest) = rest.split_at(4);
+        assert_eq!(RECORD_SIZE.to_be_bytes(), record_size);
+        assert_eq!(65, rest[0]);
+        let (as_public, record) = rest[1..].split_at(65);
+        let ecdh_secret = agreement::agree_ephemeral(
+            ua_private,
+            &UnparsedPublicKey::new(&agreement::ECDH_P256, as_public),
+            |secret| secret.to_vec(),
+        )
+        .unwrap();
+        let (key, nonce) =
+            derive_key_and_nonce(&ecdh_secret, &auth, ua_public.as_ref(), as_public, salt).unwrap();
+        let mut record = record.to_vec();
+        let plaintext = key.open_in_place(nonce, Aad::empty(), &mut record).unwrap();
+        assert_eq!(b"wake up\x02", plaintext);
+        assert!(encrypt(ua_public.as_ref(), &auth, &[0; RECORD_SIZE as usize]).is_err());
+    }
+}
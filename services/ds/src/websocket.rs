@@ -0,0 +1,121 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::{io, pin::Pin};
+
+use rocket::{
+    data::{IoHandler, IoStream},
+    futures::{SinkExt, StreamExt},
+    http::Status,
+    response::{self, Responder},
+    serde::json,
+    tokio::select,
+    Request, Response, Shutdown,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+use crate::{notifications::Subscription, server::Notification};
+
+/// A WebSocket connection pushing the [`Notification`]s of a single user.
+/// This is an alternative transport to server sent events, for clients sitting behind
+/// proxies that buffer the SSE responses.
+/// Each message is a JSON encoded [`Notification`], as for the SSE events.
+pub struct NotificationsWebSocket {
+    rx: Subscription,
+    shutdown: Shutdown,
+}
+
+impl NotificationsWebSocket {
+    /// Create a new connection for the subscription of an already authenticated user.
+    pub fn new(rx: Subscription, shutdown: Shutdown) -> Self {
+        NotificationsWebSocket { rx, shutdown }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for NotificationsWebSocket {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let is_upgrade = req
+            .headers()
+            .get("Upgrade")
+            .any(|protocol| protocol.eq_ignore_ascii_case("websocket"));
+        let key = match req.headers().get_one("Sec-WebSocket-Key") {
+            Some(key) if is_upgrade => key,
+            _ => {
+                log::debug!("Received a non WebSocket request on a WebSocket endpoint.");
+                return Err(Status::UpgradeRequired);
+            }
+        };
+        Response::build()
+            .raw_header("Sec-WebSocket-Version", "13")
+            .raw_header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()))
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for NotificationsWebSocket {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let NotificationsWebSocket {
+            mut rx,
+            mut shutdown,
+        } = *Pin::into_inner(self);
+        let receiver = rx.receiver().to_string();
+        let stream = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        let (mut sink, mut incoming) = stream.split();
+        loop {
+            let msg = select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => {
+                        log::debug!("WebSocket closing stream");
+                        break
+                    },
+                },
+                // Drive the incoming side so that pings are answered and closing handshakes detected.
+                frame = incoming.next() => match frame {
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        log::debug!("WebSocket error while reading from `{}`: {}", receiver, e);
+                        break
+                    },
+                },
+                _ = &mut shutdown => {
+                    log::debug!("WebSocket closing stream on shutdown");
+                    if let Ok(data) = json::to_string(&Notification::server_shutdown(&receiver)) {
+                        let _ = sink.send(Message::Text(data)).await;
+                    }
+                    break
+                },
+            };
+            log::debug!("WebSocket Notification: {:?}", msg);
+            let data = match json::to_string(&msg) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::error!("Couldn't serialise the notification: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = sink.send(Message::Text(data)).await {
+                log::debug!("WebSocket error while sending to `{}`: {}", receiver, e);
+                break;
+            }
+        }
+        let _ = sink.close().await;
+        Ok(())
+    }
+}
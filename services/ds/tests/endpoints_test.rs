@@ -12,18 +12,25 @@
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
 /// Attention! This module contains tests that interact with the database.
-/// You will need to run the `MySQL` database and `LocalStack` using the docker-compose.yaml configuration provided.
+/// You will need to run the `MySQL` database and `LocalStack` using the docker-compose.yaml configuration provided,
+/// or to select the in-memory `local` configuration profile with `ROCKET_PROFILE=local`.
 #[cfg(test)]
 mod test {
 
     use ds::init_server_from_config;
     use ds::server::{
-        CreateUserRequest, FetchKeyPackageRequest, FetchKeyPackageResponse, FolderFileResponse,
-        FolderResponse, ListFolderResponse, ListUsersResponse, UploadFileResponse,
+        AuditAction, CreateKeyPackageResponse, CreateUserRequest, ErrorBody,
+        FetchKeyPackageRequest, FetchKeyPackageResponse, FileUploadResponse, FolderFileResponse,
+        FolderResponse, FolderRole, GroupMessage, InvitationResponse, KeyPackageCountResponse,
+        ListAuditEventsResponse, ListDevicesResponse, ListFilesResponse, ListFolderMembersResponse,
+        ListFolderResponse, ListGroupMessagesResponse, ListInvitationsResponse,
+        ListMessageReceiptsResponse, ListMetadataVersionsResponse, ListShareLinksResponse,
+        ListTrashResponse, ListUsersResponse, ProposalResponse, ReadinessResponse, SessionResponse,
+        ShareLinkResponse, UploadFileResponse, UploadFilesResponse, WelcomeMessage,
     };
     use rand::distributions::{Alphanumeric, DistString};
     use rocket::form::validate::Contains;
-    use rocket::http::{ContentType, Status};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::blocking::Client;
 
     /// Create a random string.
@@ -48,12 +55,21 @@ mod test {
         // Some articles on the topic: https://wtjungle.com/blog/integration-testing-rocket-sqlx/
         let mut email = create_random_string(50).to_owned();
         email.push_str("@test.com");
+        (create_client_certificate(&email), email.to_string())
+    }
+
+    /// Create a new client certificate for the email, e.g. for another device of the same user.
+    fn create_client_certificate(email: &str) -> String {
         // This will try to load the state from the file system or create a new one if it fails.
-        let ca_ck = common::pki::init_ca();
+        let ca_ck = common::pki::init_ca(&common::pki::CaPaths::default());
         // Create a client certificate on the fly to test the server.
-        let (_, request) = common::crypto::mk_client_certificate_request_params(&email).unwrap();
+        let (_, request) = common::crypto::mk_client_certificate_request_params(
+            email,
+            common::crypto::KeyAlgorithm::default(),
+        )
+        .unwrap();
         let test_client_cert = common::crypto::sign_request(request, &ca_ck).unwrap();
-        (test_client_cert.pem(), email.to_string())
+        test_client_cert.pem()
     }
 
     /// Send a valid create user request and return the response.
@@ -76,9 +92,13 @@ mod test {
     }
 
     // Send a valid get users request and return the response body parsed.
-    fn list_users<'r>(client: &Client, client_credential_pem: &str) -> ListUsersResponse {
+    fn list_users<'r>(
+        client: &Client,
+        client_credential_pem: &str,
+        query: &str,
+    ) -> ListUsersResponse {
         let response = client
-            .get("/users")
+            .get(format!("/users?{}", query))
             .identity(client_credential_pem.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -113,11 +133,23 @@ mod test {
         let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
         let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Created);
-        let get_user_response_1 = list_users(&client, &client_credential_pem);
+        let get_user_response_1 = list_users(
+            &client,
+            &client_credential_pem,
+            &format!("prefix={}", email),
+        );
         assert!(get_user_response_1.emails.contains(&email));
         let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Conflict);
-        let get_user_response_2 = list_users(&client, &client_credential_pem);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "user_already_registered");
+        assert_eq!(error.retry_after, None);
+        let get_user_response_2 = list_users(
+            &client,
+            &client_credential_pem,
+            &format!("prefix={}", email),
+        );
         assert!(
             get_user_response_2
                 .emails
@@ -129,6 +161,132 @@ mod test {
         );
     }
 
+    #[test]
+    fn health_probes() {
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client.get("/healthz").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/readyz").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let readiness = response.into_json::<ReadinessResponse>().unwrap();
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn refresh_revocations_is_accepted() {
+        // The revocation list is not configured locally, the refresh request is accepted anyway.
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client.post("/revocations/refresh").dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+    }
+
+    #[test]
+    fn only_server_admins_reconcile() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client.post("/admin/reconcile").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .post("/admin/reconcile?repair=true")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "not_server_admin");
+    }
+
+    #[test]
+    fn push_subscriptions_require_web_push() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client.get("/push/vapid_public_key").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        // Web Push is not configured in the test profile.
+        let response = client
+            .get("/push/vapid_public_key")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotImplemented);
+        let response = client
+            .post("/users/push_subscriptions")
+            .header(ContentType::JSON)
+            .body(r#"{"endpoint":"https://push.example.com/abc","keys":{"p256dh":"a","auth":"b"}}"#)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotImplemented);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "web_push_unsupported");
+    }
+
+    #[test]
+    fn request_id_is_echoed() {
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client
+            .get("/healthz")
+            .header(Header::new("X-Request-Id", "share-flow-42"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("X-Request-Id"),
+            Some("share-flow-42")
+        );
+        // Invalid ids are replaced by a generated one.
+        let response = client
+            .get("/healthz")
+            .header(Header::new("X-Request-Id", "forged id"))
+            .dispatch();
+        let request_id = response.headers().get_one("X-Request-Id").unwrap();
+        assert_eq!(request_id.len(), 32);
+    }
+
+    #[test]
+    fn ws_notifications_requires_upgrade() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = client.get("/ws/notifications").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .get("/ws/notifications")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::UpgradeRequired);
+    }
+
+    #[test]
+    fn users_list_pages() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (other_credential_pem, other_email) = create_client_credentials();
+        let response = create_test_user(&client, &other_credential_pem, &other_email);
+        assert_eq!(response.status(), Status::Created);
+        let first_page = list_users(&client, &client_credential_pem, "limit=1");
+        assert_eq!(first_page.emails.len(), 1);
+        let next_cursor = first_page
+            .next_cursor
+            .expect("there are at least two users");
+        let second_page = list_users(
+            &client,
+            &client_credential_pem,
+            &format!("limit=1&cursor={}", next_cursor),
+        );
+        assert_eq!(second_page.emails.len(), 1);
+        assert!(second_page.emails[0] > first_page.emails[0]);
+        let filtered = list_users(
+            &client,
+            &client_credential_pem,
+            &format!("prefix={}", email),
+        );
+        assert_eq!(filtered.emails, vec![email]);
+        assert!(filtered.next_cursor.is_none());
+    }
+
     #[test]
     fn folders_unauthorized() {
         let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
@@ -203,6 +361,40 @@ mod test {
                 || *folder == create_response_content_2.id));
     }
 
+    #[test]
+    fn folders_list_pages_with_summary() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        for _ in 0..2 {
+            let response = post_folder_create(&client, &client_credential_pem);
+            assert_eq!(response.status(), Status::Created);
+        }
+        let response = client
+            .get("/folders?limit=1&summary=true")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let first_page = response.into_json::<ListFolderResponse>().unwrap();
+        assert_eq!(first_page.folders.len(), 1);
+        let summaries = first_page.summaries.expect("summaries requested");
+        assert_eq!(summaries[0].id, first_page.folders[0]);
+        assert_eq!(summaries[0].member_count, 1);
+        assert!(summaries[0].etag.is_some() || summaries[0].version.is_some());
+        let next_cursor = first_page.next_cursor.expect("there is a second folder");
+        let response = client
+            .get(format!("/folders?limit=1&cursor={}", next_cursor))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let second_page = response.into_json::<ListFolderResponse>().unwrap();
+        assert_eq!(second_page.folders.len(), 1);
+        assert!(second_page.folders[0] > first_page.folders[0]);
+        assert!(second_page.next_cursor.is_none());
+        assert!(second_page.summaries.is_none());
+    }
+
     fn get_folder_by_id<'r>(
         client: &'r Client,
         client_credential_pem: &str,
@@ -215,161 +407,1488 @@ mod test {
             .dispatch()
     }
 
-    fn remove_self_from_folder<'r>(
+    #[test]
+    fn get_folder_and_metadata_conditionally() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let etag = folder.etag.unwrap();
+        for path in [
+            format!("/folders/{}", folder.id),
+            format!("/folders/{}?include_metadata=false", folder.id),
+            format!("/folders/{}/metadatas", folder.id),
+        ] {
+            let get_with_header = |name: &'static str, value: &str| {
+                client
+                    .get(&path)
+                    .identity(client_credential_pem.as_bytes())
+                    .header(Header::new(name, value.to_string()))
+                    .dispatch()
+                    .status()
+            };
+            assert_eq!(get_with_header("If-None-Match", &etag), Status::NotModified);
+            assert_eq!(get_with_header("If-None-Match", "other"), Status::Ok);
+            assert_eq!(get_with_header("If-Match", &etag), Status::Ok);
+            assert_eq!(
+                get_with_header("If-Match", "other"),
+                Status::PreconditionFailed
+            );
+        }
+        let with_metadata = get_folder_by_id(&client, &client_credential_pem, folder.id)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        assert!(with_metadata.metadata_content.is_some());
+        let without_metadata = client
+            .get(format!("/folders/{}?include_metadata=false", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch()
+            .into_json::<FolderResponse>()
+            .unwrap();
+        assert!(without_metadata.metadata_content.is_none());
+        assert_eq!(without_metadata.etag, Some(etag));
+        assert_eq!(without_metadata.version, with_metadata.version);
+    }
+
+    fn set_folder_name<'r>(
         client: &'r Client,
         client_credential_pem: &str,
-        id: u64,
+        folder_id: u64,
+        display_blob: &[u8],
     ) -> rocket::local::blocking::LocalResponse<'r> {
-        let path = format!("/folders/{}", id);
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let body = [
+            b"--X-BOUNDARY".as_slice(),
+            br#"Content-Disposition: form-data; name="display_blob"; filename="name""#,
+            b"Content-Type: application/octet-stream",
+            b"",
+            display_blob,
+            b"--X-BOUNDARY--",
+            b"",
+        ]
+        .join(b"\r\n".as_slice());
         client
-            .delete(path)
+            .patch(format!("/folders/{}/name", folder_id))
             .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body)
             .dispatch()
     }
 
     #[test]
-    fn user_cannot_see_other_users_folder_but_shared_and_remove() {
+    fn set_folder_display_name() {
         let (client_credential_pem, email) = create_client_credentials();
+        let (other_credential_pem, other_email) = create_client_credentials();
         let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
         let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Created);
-        let (client_credential_pem_2, email_2) = create_client_credentials();
-        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        let response = create_test_user(&client, &other_credential_pem, &other_email);
         assert_eq!(response.status(), Status::Created);
-        let create_folder_response_1 = post_folder_create(&client, &client_credential_pem);
-        assert_eq!(create_folder_response_1.status(), Status::Created);
-        let create_response_content = create_folder_response_1
+        let folder = post_folder_create(&client, &client_credential_pem)
             .into_json::<FolderResponse>()
             .unwrap();
-        let create_folder_response_2 = post_folder_create(&client, &client_credential_pem_2);
-        assert_eq!(create_folder_response_2.status(), Status::Created);
-        let create_response_content_2 = create_folder_response_2
+        assert_eq!(folder.display_blob, None);
+        let response = set_folder_name(&client, &client_credential_pem, folder.id, b"encrypted");
+        assert_eq!(response.status(), Status::Ok);
+        let response = get_folder_by_id(&client, &client_credential_pem, folder.id)
             .into_json::<FolderResponse>()
             .unwrap();
+        assert_eq!(response.display_blob, Some(b"encrypted".to_vec()));
         let response = list_folders(&client, &client_credential_pem);
-        assert!(response
-            .folders
-            .iter()
-            .any(|folder| *folder == create_response_content.id));
-        assert_eq!(response.folders.len(), 1);
-        let response = list_folders(&client, &client_credential_pem_2);
-        assert!(response
+        let position = response
             .folders
             .iter()
-            .any(|folder| *folder == create_response_content_2.id));
-        assert_eq!(response.folders.len(), 1);
-        let response = get_folder_by_id(
-            &client,
-            &client_credential_pem,
-            create_response_content_2.id,
+            .position(|id| *id == folder.id)
+            .unwrap();
+        assert_eq!(
+            response.display_blobs[position],
+            Some(b"encrypted".to_vec())
         );
+        // Only the members can rename the folder.
+        let response = set_folder_name(&client, &other_credential_pem, folder.id, b"other");
         assert_eq!(response.status(), Status::NotFound);
-        let response =
-            get_folder_by_id(&client, &client_credential_pem, create_response_content.id);
+        let response = set_folder_name(&client, &client_credential_pem, folder.id, &[b'a'; 1025]);
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        // The names are encrypted, so they are arbitrary bytes.
+        let encrypted = [0xff, 0xfe, 0x00, 0x80, 0xc3];
+        let response = set_folder_name(&client, &client_credential_pem, folder.id, &encrypted);
         assert_eq!(response.status(), Status::Ok);
-        // Share the folder 1 with the second user.
-        let share_path = format!("/folders/{}", create_response_content.id);
-        let shared_response = client
-            .patch(share_path)
+        let response = get_folder_by_id(&client, &client_credential_pem, folder.id)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        assert_eq!(response.display_blob, Some(encrypted.to_vec()));
+        let response = list_folders(&client, &client_credential_pem);
+        let position = response
+            .folders
+            .iter()
+            .position(|id| *id == folder.id)
+            .unwrap();
+        assert_eq!(response.display_blobs[position], Some(encrypted.to_vec()));
+        // Sharing reads the folder with its members.
+        let response = client
+            .patch(format!("/folders/{}", folder.id))
             .identity(client_credential_pem.as_bytes())
             .body(
                 serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
-                    emails: vec![email_2],
+                    emails: vec![other_email],
                 })
                 .unwrap(),
             )
             .dispatch();
-        assert_eq!(shared_response.status(), Status::Ok);
-        let response = get_folder_by_id(
-            &client,
-            &client_credential_pem_2,
-            create_response_content.id,
-        );
         assert_eq!(response.status(), Status::Ok);
-        // let folder_response = response.into_json::<FolderResponse>().unwrap();
-        let folders = list_folders(&client, &client_credential_pem_2);
-        assert_eq!(folders.folders.len(), 2);
-        // Unshare folder 1 with the second user.
-        let remove_self_response = remove_self_from_folder(
-            &client,
-            &client_credential_pem_2,
-            create_response_content.id,
-        );
-        assert_eq!(remove_self_response.status(), Status::Ok);
-        let folders = list_folders(&client, &client_credential_pem_2);
-        assert_eq!(folders.folders.len(), 1);
-        let remove_self_response =
-            remove_self_from_folder(&client, &client_credential_pem, create_response_content.id);
-        assert_eq!(remove_self_response.status(), Status::Ok);
-        let response = get_folder_by_id(
-            &client,
-            &client_credential_pem_2,
-            create_response_content.id,
-        );
-        assert_eq!(response.status(), Status::NotFound);
-        let response =
-            get_folder_by_id(&client, &client_credential_pem, create_response_content.id);
-        assert_eq!(response.status(), Status::NotFound);
+        // An empty blob unsets the name.
+        let response = set_folder_name(&client, &client_credential_pem, folder.id, b"");
+        assert_eq!(response.status(), Status::Ok);
+        let response = get_folder_by_id(&client, &client_credential_pem, folder.id)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        assert_eq!(response.display_blob, None);
     }
 
     #[test]
-    fn upload_file_and_read_it_back_with_metadata_and_update() {
+    fn share_links() {
         let (client_credential_pem, email) = create_client_credentials();
+        let (other_credential_pem, other_email) = create_client_credentials();
         let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
         let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Created);
-        let create_folder_response_1 = post_folder_create(&client, &client_credential_pem);
-        assert_eq!(create_folder_response_1.status(), Status::Created);
-        let create_response_content = create_folder_response_1
+        let response = create_test_user(&client, &other_credential_pem, &other_email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
             .into_json::<FolderResponse>()
             .unwrap();
-        let folder_id = create_response_content.id;
-        let ct = "multipart/form-data; boundary=X-BOUNDARY"
-            .parse::<ContentType>()
+        let path = format!("/folders/{}/links", folder.id);
+        let response = client
+            .post(&path)
+            .identity(client_credential_pem.as_bytes())
+            .json(&serde_json::json!({ "expires_in": 3600 }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let link = response.into_json::<ShareLinkResponse>().unwrap();
+        // The link grants read-only access without a client certificate.
+        let metadatas = format!("/folders/{}/metadatas", folder.id);
+        let bearer = Header::new("Authorization", format!("Bearer {}", link.token));
+        let response = client.get(&metadatas).header(bearer.clone()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/metadatas", folder.id + 1))
+            .header(bearer.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get(&path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch()
+            .into_json::<ListShareLinksResponse>()
             .unwrap();
-        let body_multipart = &[
-            "--X-BOUNDARY",
-            r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
-            "Content-Type: text/plain",
-            "",
-            "README CONTENT",
-            "--X-BOUNDARY",
-            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
-            "Content-Type: text/plain",
-            "",
-            "METADATA CONTENT",
-            "--X-BOUNDARY--",
-            "",
-        ];
-        let body = body_multipart.join("\r\n");
-        let file_id = create_random_file_name();
-        // Upload the file without metadata etag and version and check that we get a conflict (due to the empty metadata file created at folder creation).
-        let conflict_response = client
-            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+        assert_eq!(response.links.len(), 1);
+        assert_eq!(response.links[0].link_id, link.link_id);
+        assert_eq!(response.links[0].creator, email);
+        // Only the members can create links.
+        let response = client
+            .post(&path)
+            .identity(other_credential_pem.as_bytes())
+            .json(&serde_json::json!({}))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(&path)
             .identity(client_credential_pem.as_bytes())
-            .header(ct.clone())
-            .body(body)
+            .json(&serde_json::json!({ "expires_in": 0 }))
             .dispatch();
-        assert_eq!(conflict_response.status(), Status::Conflict);
-        // Now upload the file with the correct metadata etag and version from the creation of the folder.
-        let etag_part = create_response_content
-            .etag
-            .clone()
-            .map_or("".to_string(), |etag| {
-                [
-                    "--X-BOUNDARY",
-                    r#"Content-Disposition: form-data; name="parent_etag""#,
-                    "",
-                    &etag,
-                ]
-                .join("\r\n")
-                .to_string()
-            });
-        let version_part =
-            create_response_content
-                .version
-                .clone()
-                .map_or("".to_string(), |version| {
+        assert_eq!(response.status(), Status::BadRequest);
+        let revoke = format!("{}/{}", path, link.link_id);
+        let response = client
+            .delete(&revoke)
+            .identity(other_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .delete(&revoke)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get(&metadatas).header(bearer).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn devices() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let list_devices = |credential_pem: &str| {
+            client
+                .get("/users/self/devices")
+                .identity(credential_pem.as_bytes())
+                .dispatch()
+                .into_json::<ListDevicesResponse>()
+                .unwrap()
+                .devices
+        };
+        // The device is registered on first use.
+        let devices = list_devices(&client_credential_pem);
+        assert_eq!(devices.len(), 1);
+        assert!(devices[0].current);
+        assert_eq!(
+            list_devices(&client_credential_pem)[0].device_id,
+            devices[0].device_id
+        );
+        // Another certificate of the same user is another device.
+        let other_device_pem = create_client_certificate(&email);
+        let other_devices = list_devices(&other_device_pem);
+        assert_eq!(other_devices.len(), 2);
+        assert_eq!(other_devices[0].device_id, devices[0].device_id);
+        assert!(!other_devices[0].current);
+        assert!(other_devices[1].current);
+        assert_ne!(other_devices[0].fingerprint, other_devices[1].fingerprint);
+    }
+
+    #[test]
+    fn sessions() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let response = client
+            .post("/session")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let session = response.into_json::<SessionResponse>().unwrap();
+        // The session authenticates the data plane requests without a client certificate.
+        let bearer = Header::new("Authorization", format!("Bearer {}", session.token));
+        let response = client
+            .get(format!("/folders/{}/files", folder.id))
+            .header(bearer.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/metadatas", folder.id))
+            .header(bearer.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // But not the other ones, nor the creation of another session.
+        let response = client.get("/folders").header(bearer.clone()).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client.post("/session").header(bearer).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get(format!("/folders/{}/files", folder.id))
+            .header(Header::new("Authorization", "Bearer invalid.token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn metadata_size_limit() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let post_metadata = |metadata: &str| {
+            let ct = "multipart/form-data; boundary=X-BOUNDARY"
+                .parse::<ContentType>()
+                .unwrap();
+            let body = [
+                parent_metadata_parts(&folder.etag, &folder.version).as_str(),
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+                "Content-Type: text/plain",
+                "",
+                metadata,
+                "--X-BOUNDARY--",
+                "",
+            ]
+            .join("\r\n");
+            client
+                .post(format!("/folders/{}/metadatas", folder.id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct)
+                .body(body)
+                .dispatch()
+        };
+        // The limit of `DS_Rocket.toml` is 16 MiB.
+        let response = post_metadata(&"M".repeat(16 * 1024 * 1024 + 1));
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "payload_too_large");
+        let response = post_metadata(&"M".repeat(64 * 1024));
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    #[test]
+    fn list_metadata_versions() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let body = [
+            parent_metadata_parts(&folder.etag, &folder.version).as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT UPDATED",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/metadatas", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let list_versions = |query: &str| {
+            let response = client
+                .get(format!(
+                    "/folders/{}/metadatas/versions{}",
+                    folder.id, query
+                ))
+                .identity(client_credential_pem.as_bytes())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response
+                .into_json::<ListMetadataVersionsResponse>()
+                .unwrap()
+        };
+        let all_versions = list_versions("");
+        assert_eq!(all_versions.versions.len(), 2);
+        assert_eq!(
+            all_versions.versions[1].size,
+            "METADATA CONTENT UPDATED".len()
+        );
+        assert!(all_versions.next_cursor.is_none());
+        let first_page = list_versions("?limit=1");
+        assert_eq!(first_page.versions.len(), 1);
+        assert_eq!(first_page.versions[0].id, all_versions.versions[0].id);
+        let second_page = list_versions(&format!(
+            "?limit=1&cursor={}",
+            first_page.next_cursor.unwrap()
+        ));
+        assert_eq!(second_page.versions.len(), 1);
+        assert_eq!(second_page.versions[0].id, all_versions.versions[1].id);
+        assert!(second_page.next_cursor.is_none());
+        // Only the members of the folder can list the versions.
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .get(format!("/folders/{}/metadatas/versions", folder.id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn rollback_metadata() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let body = [
+            parent_metadata_parts(&folder.etag, &folder.version).as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "CORRUPTED METADATA",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/metadatas", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let head = response.into_json::<UploadFileResponse>().unwrap();
+        let response = client
+            .get(format!("/folders/{}/metadatas/versions", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        let first_version = response
+            .into_json::<ListMetadataVersionsResponse>()
+            .unwrap()
+            .versions[0]
+            .id;
+        let rollback = |version_id: u64, etag: &Option<String>, version: &Option<String>| {
+            let body = [
+                parent_metadata_parts(etag, version).as_str(),
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="version_id""#,
+                "",
+                &version_id.to_string(),
+                "--X-BOUNDARY--",
+                "",
+            ]
+            .join("\r\n");
+            client
+                .post(format!("/folders/{}/metadatas/rollback", folder.id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .body(body)
+                .dispatch()
+        };
+        // The version to restore must exist.
+        let response = rollback(0, &head.etag, &head.version);
+        assert_eq!(response.status(), Status::NotFound);
+        // The current metadata must still match.
+        let response = rollback(first_version, &folder.etag, &folder.version);
+        assert_eq!(response.status(), Status::Conflict);
+        let response = rollback(first_version, &head.etag, &head.version);
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .get(format!("/folders/{}/metadatas", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let metadata = response.into_json::<FolderFileResponse>().unwrap();
+        assert_eq!(metadata.file, b"METADATA CONTENT");
+    }
+
+    fn remove_self_from_folder<'r>(
+        client: &'r Client,
+        client_credential_pem: &str,
+        id: u64,
+    ) -> rocket::local::blocking::LocalResponse<'r> {
+        let path = format!("/folders/{}", id);
+        client
+            .delete(path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch()
+    }
+
+    #[test]
+    fn delete_folder_with_content() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let create_folder_response = post_folder_create(&client, &client_credential_pem);
+        assert_eq!(create_folder_response.status(), Status::Created);
+        let folder = create_folder_response
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let response = client
+            .delete(format!("/folders/{}/content", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = get_folder_by_id(&client, &client_credential_pem, folder.id);
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(list_folders(&client, &client_credential_pem)
+            .folders
+            .is_empty());
+        let response = client
+            .delete(format!("/folders/{}/content", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn user_cannot_see_other_users_folder_but_shared_and_remove() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let create_folder_response_1 = post_folder_create(&client, &client_credential_pem);
+        assert_eq!(create_folder_response_1.status(), Status::Created);
+        let create_response_content = create_folder_response_1
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let create_folder_response_2 = post_folder_create(&client, &client_credential_pem_2);
+        assert_eq!(create_folder_response_2.status(), Status::Created);
+        let create_response_content_2 = create_folder_response_2
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let response = list_folders(&client, &client_credential_pem);
+        assert!(response
+            .folders
+            .iter()
+            .any(|folder| *folder == create_response_content.id));
+        assert_eq!(response.folders.len(), 1);
+        let response = list_folders(&client, &client_credential_pem_2);
+        assert!(response
+            .folders
+            .iter()
+            .any(|folder| *folder == create_response_content_2.id));
+        assert_eq!(response.folders.len(), 1);
+        let response = get_folder_by_id(
+            &client,
+            &client_credential_pem,
+            create_response_content_2.id,
+        );
+        assert_eq!(response.status(), Status::NotFound);
+        let response =
+            get_folder_by_id(&client, &client_credential_pem, create_response_content.id);
+        assert_eq!(response.status(), Status::Ok);
+        // Share the folder 1 with the second user.
+        let share_path = format!("/folders/{}", create_response_content.id);
+        let shared_response = client
+            .patch(share_path)
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let response = get_folder_by_id(
+            &client,
+            &client_credential_pem_2,
+            create_response_content.id,
+        );
+        assert_eq!(response.status(), Status::Ok);
+        // let folder_response = response.into_json::<FolderResponse>().unwrap();
+        let folders = list_folders(&client, &client_credential_pem_2);
+        assert_eq!(folders.folders.len(), 2);
+        // Unshare folder 1 with the second user.
+        let remove_self_response = remove_self_from_folder(
+            &client,
+            &client_credential_pem_2,
+            create_response_content.id,
+        );
+        assert_eq!(remove_self_response.status(), Status::Ok);
+        let folders = list_folders(&client, &client_credential_pem_2);
+        assert_eq!(folders.folders.len(), 1);
+        let remove_self_response =
+            remove_self_from_folder(&client, &client_credential_pem, create_response_content.id);
+        assert_eq!(remove_self_response.status(), Status::Ok);
+        let response = get_folder_by_id(
+            &client,
+            &client_credential_pem_2,
+            create_response_content.id,
+        );
+        assert_eq!(response.status(), Status::NotFound);
+        let response =
+            get_folder_by_id(&client, &client_credential_pem, create_response_content.id);
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn only_admins_can_share_folder() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_3, email_3) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_3, &email_3);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        assert_eq!(folder.role, Some(FolderRole::Admin));
+        let share = |credential_pem: &str, email: &str| {
+            client
+                .patch(format!("/folders/{}", folder.id))
+                .identity(credential_pem.as_bytes())
+                .body(
+                    serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                        emails: vec![email.to_string()],
+                    })
+                    .unwrap(),
+                )
+                .dispatch()
+                .status()
+        };
+        assert_eq!(share(&client_credential_pem, &email_2), Status::Ok);
+        // A member can't share the folder further.
+        assert_eq!(share(&client_credential_pem_2, &email_3), Status::Forbidden);
+        // A user without access can't share the folder.
+        assert_eq!(share(&client_credential_pem_3, &email_3), Status::NotFound);
+        let response = get_folder_by_id(&client, &client_credential_pem_2, folder.id);
+        assert_eq!(response.status(), Status::Ok);
+        let response = response.into_json::<FolderResponse>().unwrap();
+        assert_eq!(response.role, Some(FolderRole::Member));
+        let response = client
+            .get(format!("/folders/{}/users", folder.id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let members = response
+            .into_json::<ListFolderMembersResponse>()
+            .unwrap()
+            .members;
+        assert_eq!(members.len(), 2);
+        assert!(members
+            .iter()
+            .any(|member| member.email == email && member.role == FolderRole::Admin));
+        assert!(members
+            .iter()
+            .any(|member| member.email == email_2 && member.role == FolderRole::Member));
+        let response = client
+            .get(format!("/folders/{}/users", folder.id))
+            .identity(client_credential_pem_3.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn admin_removes_member_from_folder() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_3, email_3) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_3, &email_3);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2.clone(), email_3.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let remove = |credential_pem: &str, email: &str| {
+            client
+                .delete(format!("/folders/{}/users/{}", folder_id, email))
+                .identity(credential_pem.as_bytes())
+                .dispatch()
+                .status()
+        };
+        // Only the admin can remove other members.
+        assert_eq!(
+            remove(&client_credential_pem_2, &email_3),
+            Status::Forbidden
+        );
+        assert_eq!(remove(&client_credential_pem, &email), Status::BadRequest);
+        assert_eq!(remove(&client_credential_pem, &email_2), Status::Ok);
+        assert_eq!(remove(&client_credential_pem, &email_2), Status::NotFound);
+        let response = get_folder_by_id(&client, &client_credential_pem_2, folder_id);
+        assert_eq!(response.status(), Status::NotFound);
+        let response = get_folder_by_id(&client, &client_credential_pem_3, folder_id);
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn transfer_folder_ownership() {
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let mut users = vec![];
+        for _ in 0..3 {
+            let (client_credential_pem, email) = create_client_credentials();
+            let response = create_test_user(&client, &client_credential_pem, &email);
+            assert_eq!(response.status(), Status::Created);
+            users.push((client_credential_pem, email));
+        }
+        let [(pem, email), (pem_2, email_2), (_, email_3)] = &users[..] else {
+            unreachable!()
+        };
+        let folder_id = post_folder_create(&client, pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let transfer = |pem: &str, email: &str| {
+            client
+                .post(format!("/folders/{}/transfer", folder_id))
+                .identity(pem.as_bytes())
+                .header(ContentType::JSON)
+                .body(
+                    serde_json::to_string(&ds::server::TransferFolderRequest {
+                        email: email.to_string(),
+                    })
+                    .unwrap(),
+                )
+                .dispatch()
+                .status()
+        };
+        assert_eq!(transfer(pem_2, email), Status::Forbidden);
+        assert_eq!(transfer(pem, email), Status::BadRequest);
+        assert_eq!(transfer(pem, email_3), Status::NotFound);
+        assert_eq!(transfer(pem, email_2), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/users", folder_id))
+            .identity(pem.as_bytes())
+            .dispatch();
+        let members = response
+            .into_json::<ListFolderMembersResponse>()
+            .unwrap()
+            .members;
+        assert!(members
+            .iter()
+            .any(|member| member.email == *email && member.role == FolderRole::Member));
+        assert!(members
+            .iter()
+            .any(|member| member.email == *email_2 && member.role == FolderRole::Admin));
+        // The previous admin can't transfer the ownership anymore.
+        assert_eq!(transfer(pem, email_2), Status::Forbidden);
+    }
+
+    #[test]
+    fn admin_leaving_folder_promotes_member() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let response = client
+            .delete(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let members = client
+            .get(format!("/folders/{}/users", folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch()
+            .into_json::<ListFolderMembersResponse>()
+            .unwrap()
+            .members;
+        assert_eq!(1, members.len());
+        assert_eq!(email_2, members[0].email);
+        assert_eq!(FolderRole::Admin, members[0].role);
+    }
+
+    #[test]
+    fn non_member_leaving_folder_is_not_found() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let response = client
+            .delete(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .delete(format!("/folders/{}", u32::MAX))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        // The folder is left untouched for its member.
+        let response = get_folder_by_id(&client, &client_credential_pem, folder_id);
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn members_read_folder_audit_log() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let audit_path = format!("/folders/{}/audit", folder_id);
+        let response = client
+            .get(&audit_path)
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let response = client
+            .get(format!("{}?limit=1", audit_path))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let first_page = response.into_json::<ListAuditEventsResponse>().unwrap();
+        assert_eq!(first_page.events.len(), 1);
+        assert_eq!(first_page.events[0].action, AuditAction::FolderCreated);
+        assert_eq!(first_page.events[0].actor, email);
+        let response = client
+            .get(format!(
+                "{}?cursor={}",
+                audit_path,
+                first_page.next_cursor.unwrap()
+            ))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        let second_page = response.into_json::<ListAuditEventsResponse>().unwrap();
+        assert_eq!(second_page.events.len(), 1);
+        assert_eq!(second_page.events[0].action, AuditAction::FolderShared);
+        assert_eq!(second_page.events[0].target, Some(email_2));
+        assert!(second_page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn delete_own_account() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let private_folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", shared_folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let response = client
+            .delete("/users/self")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // The deleted user is not known anymore and can't register again.
+        let response = get_folder_by_id(&client, &client_credential_pem, private_folder_id);
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Forbidden);
+        // The shared folder is kept for the other member, without the email of the deleted user.
+        let response = get_folder_by_id(&client, &client_credential_pem_2, shared_folder_id);
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/users", shared_folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        let members = response.into_json::<ListFolderMembersResponse>().unwrap();
+        assert_eq!(members.members.len(), 1);
+        // The remaining member is promoted in place of the deleted admin.
+        assert_eq!(email_2, members.members[0].email);
+        assert_eq!(FolderRole::Admin, members.members[0].role);
+        let response = client
+            .get(format!("/folders/{}/audit", shared_folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        let audit = response.into_json::<ListAuditEventsResponse>().unwrap();
+        let actions = audit
+            .events
+            .iter()
+            .rev()
+            .take(2)
+            .map(|event| event.action)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                AuditAction::OwnershipTransferred,
+                AuditAction::MemberRemoved
+            ],
+            actions
+        );
+        assert!(audit
+            .events
+            .iter()
+            .all(|event| event.actor != email && event.target.as_ref() != Some(&email)));
+    }
+
+    #[test]
+    fn share_folder_publish_and_ack_welcome() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let welcome_body = [
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="email""#,
+            "",
+            &email_2,
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="proposal"; filename="welcome""#,
+            "Content-Type: application/octet-stream",
+            "",
+            "WELCOME",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        // The receiver is not a member of the folder yet.
+        let response = client
+            .patch(format!("/v2/folders/{}/welcomes", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&welcome_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/welcomes", folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .patch(format!("/v2/folders/{}/welcomes", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(&welcome_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/welcomes", folder_id))
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let welcome = response.into_json::<WelcomeMessage>().unwrap();
+        assert_eq!(welcome.folder_id, folder_id);
+        assert_eq!(welcome.payload, b"WELCOME");
+        let ack_path = format!("/folders/{}/welcomes/{}", folder_id, welcome.message_id);
+        let response = client
+            .delete(&ack_path)
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .delete(&ack_path)
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn invite_accept_and_decline() {
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let mut users = vec![];
+        for _ in 0..4 {
+            let (client_credential_pem, email) = create_client_credentials();
+            let response = create_test_user(&client, &client_credential_pem, &email);
+            assert_eq!(response.status(), Status::Created);
+            users.push((client_credential_pem, email));
+        }
+        let [(pem, _), (pem_2, email_2), (pem_3, email_3), (pem_4, email_4)] = &users[..] else {
+            unreachable!()
+        };
+        let folder_id = post_folder_create(&client, pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_3.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let invite = |pem: &str, email: &str| {
+            let body = [
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="email""#,
+                "",
+                email,
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="proposal"; filename="proposal""#,
+                "Content-Type: application/octet-stream",
+                "",
+                "ADD PROPOSAL",
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="welcome"; filename="welcome""#,
+                "Content-Type: application/octet-stream",
+                "",
+                "WELCOME",
+                "--X-BOUNDARY--",
+                "",
+            ]
+            .join("\r\n");
+            client
+                .post(format!("/folders/{}/invitations", folder_id))
+                .identity(pem.as_bytes())
+                .header(ct.clone())
+                .body(body)
+                .dispatch()
+        };
+        let list_invitations = |pem: &str| {
+            let response = client
+                .get("/invitations")
+                .identity(pem.as_bytes())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response
+                .into_json::<ListInvitationsResponse>()
+                .unwrap()
+                .invitations
+        };
+        // Only the admins can invite, and only the users that are not members yet.
+        assert_eq!(invite(pem_3, email_2).status(), Status::Forbidden);
+        assert_eq!(invite(pem, email_3).status(), Status::Conflict);
+        let response = invite(pem, email_2);
+        assert_eq!(response.status(), Status::Created);
+        let invitation_id = response
+            .into_json::<InvitationResponse>()
+            .unwrap()
+            .invitation_id;
+        assert_eq!(invite(pem, email_2).status(), Status::Conflict);
+        // The invited user is not a member until it accepts.
+        let response = get_folder_by_id(&client, pem_2, folder_id);
+        assert_eq!(response.status(), Status::NotFound);
+        let invitations = list_invitations(pem_2);
+        assert_eq!(invitations.len(), 1);
+        assert_eq!(invitations[0].id, invitation_id);
+        assert_eq!(invitations[0].folder_id, folder_id);
+        // Only the invited user can accept.
+        let response = client
+            .post(format!("/invitations/{}/accept", invitation_id))
+            .identity(pem_4.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(format!("/invitations/{}/accept", invitation_id))
+            .identity(pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // The proposal is published to the other members, but the inviter.
+        let response = response.into_json::<ProposalResponse>().unwrap();
+        assert_eq!(response.message_ids.len(), 1);
+        assert!(list_invitations(pem_2).is_empty());
+        let response = get_folder_by_id(&client, pem_2, folder_id);
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/folders/{}/welcomes", folder_id))
+            .identity(pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<WelcomeMessage>().unwrap().payload,
+            b"WELCOME"
+        );
+        // Decline an invitation.
+        let response = invite(pem, email_4);
+        let invitation_id = response
+            .into_json::<InvitationResponse>()
+            .unwrap()
+            .invitation_id;
+        let response = client
+            .delete(format!("/invitations/{}", invitation_id))
+            .identity(pem_4.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(list_invitations(pem_4).is_empty());
+        let response = get_folder_by_id(&client, pem_4, folder_id);
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    fn publish_proposal(
+        client: &Client,
+        client_credential_pem: &str,
+        folder_id: u64,
+        proposal: &str,
+    ) -> Vec<u64> {
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let body = [
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="proposal"; filename="proposal""#,
+            "Content-Type: application/octet-stream",
+            "",
+            proposal,
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/proposals", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response
+            .into_json::<ProposalResponse>()
+            .unwrap()
+            .message_ids
+    }
+
+    fn publish_application_message(
+        client: &Client,
+        client_credential_pem: &str,
+        folder_id: u64,
+        message_ids: &[u64],
+        payload: &str,
+    ) {
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let mut body = vec![];
+        for message_id in message_ids {
+            body.extend([
+                "--X-BOUNDARY".to_string(),
+                r#"Content-Disposition: form-data; name="message_ids""#.to_string(),
+                "".to_string(),
+                message_id.to_string(),
+            ]);
+        }
+        body.extend([
+            "--X-BOUNDARY".to_string(),
+            r#"Content-Disposition: form-data; name="payload"; filename="payload""#.to_string(),
+            "Content-Type: application/octet-stream".to_string(),
+            "".to_string(),
+            payload.to_string(),
+            "--X-BOUNDARY--".to_string(),
+            "".to_string(),
+        ]);
+        let response = client
+            .patch(format!("/folders/{}/proposals", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body.join("\r\n"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    #[test]
+    fn list_and_ack_pending_proposals() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let (client_credential_pem_2, email_2) = create_client_credentials();
+        let response = create_test_user(&client, &client_credential_pem_2, &email_2);
+        assert_eq!(response.status(), Status::Created);
+        let folder_id = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![email_2],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        // Three proposals, the last one is not consumable yet as its application message is missing.
+        let mut message_ids = vec![];
+        for proposal in ["P1", "P2", "P3"] {
+            let ids = publish_proposal(&client, &client_credential_pem, folder_id, proposal);
+            assert_eq!(ids.len(), 1);
+            message_ids.push(ids[0]);
+        }
+        for (message_id, payload) in message_ids.iter().zip(["A1", "A2"]) {
+            publish_application_message(
+                &client,
+                &client_credential_pem,
+                folder_id,
+                &[*message_id],
+                payload,
+            );
+        }
+        let list_proposals = |limit: &str| {
+            let response = client
+                .get(format!("/folders/{}/proposals/all{}", folder_id, limit))
+                .identity(client_credential_pem_2.as_bytes())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<ListGroupMessagesResponse>().unwrap()
+        };
+        let page = list_proposals("?limit=1");
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].message_id, message_ids[0]);
+        assert!(page.has_more);
+        let page = list_proposals("");
+        assert_eq!(
+            page.messages
+                .iter()
+                .map(|message| (
+                    message.payload.as_slice(),
+                    message.application_payload.as_slice()
+                ))
+                .collect::<Vec<_>>(),
+            vec![(&b"P1"[..], &b"A1"[..]), (&b"P2"[..], &b"A2"[..])]
+        );
+        assert!(page.has_more);
+        // The next proposals can be fetched before acking the previous ones.
+        let get_proposal = |after: &str| {
+            client
+                .get(format!("/folders/{}/proposals{}", folder_id, after))
+                .identity(client_credential_pem_2.as_bytes())
+                .dispatch()
+        };
+        let first = get_proposal("").into_json::<GroupMessage>().unwrap();
+        assert_eq!(first.message_id, message_ids[0]);
+        let next = get_proposal(&format!("?after={}", first.message_id))
+            .into_json::<GroupMessage>()
+            .unwrap();
+        assert_eq!(next.message_id, message_ids[1]);
+        assert_eq!(next.payload, b"P2");
+        let response = get_proposal(&format!("?after={}", next.message_id));
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let response = get_proposal(&format!("?after={}", message_ids[2]));
+        assert_eq!(response.status(), Status::NotFound);
+        let ack_path = format!("/folders/{}/proposals?up_to={}", folder_id, message_ids[1]);
+        let response = client
+            .delete(&ack_path)
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let page = list_proposals("");
+        assert!(page.messages.is_empty());
+        assert!(page.has_more);
+        let response = client
+            .delete(&ack_path)
+            .identity(client_credential_pem_2.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn message_receipts() {
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let users: Vec<(String, String)> = (0..3)
+            .map(|_| {
+                let (pem, email) = create_client_credentials();
+                let response = create_test_user(&client, &pem, &email);
+                assert_eq!(response.status(), Status::Created);
+                (pem, email)
+            })
+            .collect();
+        let (admin_pem, admin_email) = &users[0];
+        let (sender_pem, sender_email) = &users[1];
+        let (member_pem, member_email) = &users[2];
+        let folder_id = post_folder_create(&client, admin_pem)
+            .into_json::<FolderResponse>()
+            .unwrap()
+            .id;
+        let shared_response = client
+            .patch(format!("/folders/{}", folder_id))
+            .identity(admin_pem.as_bytes())
+            .body(
+                serde_json::to_string_pretty(&ds::server::ShareFolderRequest {
+                    emails: vec![sender_email.clone(), member_email.clone()],
+                })
+                .unwrap(),
+            )
+            .dispatch();
+        assert_eq!(shared_response.status(), Status::Ok);
+        let message_ids = publish_proposal(&client, sender_pem, folder_id, "P1");
+        assert_eq!(message_ids.len(), 2);
+        let receipts_path = format!(
+            "/folders/{}/proposals/{}/receipts",
+            folder_id, message_ids[1]
+        );
+        let list_receipts = |pem: &str| {
+            let response = client
+                .get(&receipts_path)
+                .identity(pem.as_bytes())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<ListMessageReceiptsResponse>().unwrap()
+        };
+        let receipts = list_receipts(sender_pem);
+        assert_eq!(&receipts.sender, sender_email);
+        let mut receivers: Vec<&String> = receipts.receipts.iter().map(|r| &r.receiver).collect();
+        receivers.sort();
+        let mut expected = vec![admin_email, member_email];
+        expected.sort();
+        assert_eq!(receivers, expected);
+        assert!(receipts.receipts.iter().all(|r| r.acked_at.is_none()));
+        // The admin processes the proposal.
+        let response = client
+            .delete(format!(
+                "/folders/{}/proposals?up_to={}",
+                folder_id,
+                message_ids.iter().max().unwrap()
+            ))
+            .identity(admin_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // The admins can see the receipts of the messages of the other members.
+        let receipts = list_receipts(admin_pem);
+        for receipt in &receipts.receipts {
+            assert_eq!(receipt.acked_at.is_some(), &receipt.receiver == admin_email);
+        }
+        // But not the other members.
+        let response = client
+            .get(&receipts_path)
+            .identity(member_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .get(format!(
+                "/folders/{}/proposals/{}/receipts",
+                folder_id,
+                message_ids.iter().max().unwrap() + 1000
+            ))
+            .identity(sender_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn upload_file_and_read_it_back_with_metadata_and_update() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let create_folder_response_1 = post_folder_create(&client, &client_credential_pem);
+        assert_eq!(create_folder_response_1.status(), Status::Created);
+        let create_response_content = create_folder_response_1
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let folder_id = create_response_content.id;
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let body_multipart = &[
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+            "Content-Type: text/plain",
+            "",
+            "README CONTENT",
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT",
+            "--X-BOUNDARY--",
+            "",
+        ];
+        let body = body_multipart.join("\r\n");
+        let file_id = create_random_file_name();
+        // Upload the file without metadata etag and version and check that we get a conflict (due to the empty metadata file created at folder creation).
+        let conflict_response = client
+            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(body)
+            .dispatch();
+        assert_eq!(conflict_response.status(), Status::Conflict);
+        // Now upload the file with the correct metadata etag and version from the creation of the folder.
+        let etag_part = create_response_content
+            .etag
+            .clone()
+            .map_or("".to_string(), |etag| {
+                [
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="parent_etag""#,
+                    "",
+                    &etag,
+                ]
+                .join("\r\n")
+                .to_string()
+            });
+        let version_part =
+            create_response_content
+                .version
+                .clone()
+                .map_or("".to_string(), |version| {
                     [
                         "--X-BOUNDARY",
                         r#"Content-Disposition: form-data; name="parent_version""#,
@@ -381,13 +1900,396 @@ mod test {
                 });
         log::debug!("ETAG PART: {:?}", etag_part);
         log::debug!("VERSION PART: {:?}", version_part);
-        let successful_upload_body_multipart = &[
+        let successful_upload_body_multipart = &[
+            etag_part.as_str(),
+            version_part.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+            "Content-Type: text/plain",
+            "",
+            "README CONTENT",
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT",
+            "--X-BOUNDARY--",
+            "",
+        ];
+        let response = client
+            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(successful_upload_body_multipart.join("\r\n"))
+            .dispatch();
+        // And verify that the file was uploaded successfully.
+        assert_eq!(response.status(), Status::Created);
+        let put_response: UploadFileResponse = response.into_json().unwrap();
+        put_response
+            .etag
+            .clone()
+            .or(put_response.version.clone())
+            .expect("etag or version should be present");
+        // Get the file back.
+        let response = client
+            .get(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Binary));
+        assert!(response.headers().contains("etag") || response.headers().contains("x-version"));
+        let file_etag = response.headers().get_one("etag").unwrap().to_string();
+        assert_eq!(response.into_bytes().unwrap(), b"README CONTENT");
+        // Read metadata file.
+        let response = client
+            .get(format!("/folders/{}/metadatas", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let folder_file_response: FolderFileResponse = response.into_json().unwrap();
+        // Check that the UploadFileResponse gave the correct etag and version.
+        /*  let metadata_etags = response
+            .headers()
+            .get(ETAG.as_str().to_lowercase().as_str());
+        let metadata_versions = response.headers().get("x-version");
+        let metadata_etags = metadata_etags.collect::<Vec<_>>();
+        let metadata_versions = metadata_versions.collect::<Vec<_>>();
+        assert_eq!(metadata_etags.len(), 1);
+        assert_eq!(metadata_versions.len(), 1);
+        assert!(metadata_etags.get(0).contains("") || metadata_etags.get(0).contains(""));
+        assert_eq!(
+            metadata_etags.concat(),
+            put_response.etag.as_ref().unwrap().to_string()
+        );
+        assert_eq!(
+            metadata_versions.concat(),
+            put_response
+                .version
+                .as_ref()
+                .unwrap_or(&"".to_string())
+                .to_string()
+        );
+        let bytes = response.into_bytes().unwrap();
+        assert_eq!(bytes, b"METADATA CONTENT");
+        */
+        assert_eq!(
+            String::from_utf8(folder_file_response.file).unwrap(),
+            "METADATA CONTENT".to_string()
+        );
+        assert!(folder_file_response.etag.is_some() || folder_file_response.version.is_some());
+        assert_eq!(put_response.version, folder_file_response.version);
+        assert_eq!(put_response.etag, folder_file_response.etag);
+        let etag_part = put_response.etag.clone().map_or("".to_string(), |etag| {
+            [
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="parent_etag""#,
+                "",
+                &etag,
+            ]
+            .join("\r\n")
+            .to_string()
+        });
+        let version_part = put_response
+            .version
+            .clone()
+            .map_or("".to_string(), |version| {
+                [
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="parent_version""#,
+                    "",
+                    &version,
+                ]
+                .join("\r\n")
+                .to_string()
+            });
+        log::debug!("ETAG PART: {:?}", etag_part);
+        log::debug!("VERSION PART: {:?}", version_part);
+        let body_multipart_2 = &[
+            etag_part.as_str(),
+            version_part.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+            "Content-Type: text/plain",
+            "",
+            "README CONTENT UPDATED",
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT UPDATED",
+            "--X-BOUNDARY--",
+            "",
+        ];
+        // The file already exists, so it can't be replaced without its etag.
+        let response = client
+            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(body_multipart_2.join("\r\n"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        let file_etag_part = [
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="file_parent_etag""#,
+            "",
+            &file_etag,
+        ]
+        .join("\r\n");
+        let body_2 = [&[file_etag_part.as_str()][..], body_multipart_2]
+            .concat()
+            .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(body_2)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let put_response_2: UploadFileResponse = response.into_json().unwrap();
+        put_response_2
+            .etag
+            .clone()
+            .or(put_response_2.version.clone())
+            .expect("etag or version should be present");
+        assert_ne!(
+            put_response_2.etag.or(put_response_2.version),
+            put_response.etag.or(put_response.version)
+        );
+        log::debug!("ETAG PART: {:?}", etag_part);
+        log::debug!("VERSION PART: {:?}", version_part);
+        let body_multipart_3 = &[
             etag_part.as_str(),
             version_part.as_str(),
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
             "Content-Type: text/plain",
             "",
+            "README CONTENT UPDATED",
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT UPDATED",
+            "--X-BOUNDARY--",
+            "",
+        ];
+        let body_3 = body_multipart_3.join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body_3)
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    /// Create the multipart body parts with the parent etag and version of the metadata.
+    fn parent_metadata_parts(etag: &Option<String>, version: &Option<String>) -> String {
+        let mut parts = vec![];
+        if let Some(etag) = etag {
+            parts.push(
+                [
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="parent_etag""#,
+                    "",
+                    etag,
+                ]
+                .join("\r\n"),
+            );
+        }
+        if let Some(version) = version {
+            parts.push(
+                [
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="parent_version""#,
+                    "",
+                    version,
+                ]
+                .join("\r\n"),
+            );
+        }
+        parts.join("\r\n")
+    }
+
+    /// Send a valid list files request and return the response body parsed.
+    fn list_files(
+        client: &Client,
+        client_credential_pem: &str,
+        folder_id: u64,
+    ) -> ListFilesResponse {
+        let response = client
+            .get(format!("/folders/{}/files", folder_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response
+            .into_json::<ListFilesResponse>()
+            .expect("Valid files list")
+    }
+
+    #[test]
+    fn upload_file_in_parts() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let file_id = create_random_file_name();
+        let uploads_path = format!("/folders/{}/files/{}/uploads", folder.id, file_id);
+        let response = client
+            .post(&uploads_path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let upload = response.into_json::<FileUploadResponse>().unwrap();
+        assert!(upload.parts.is_empty());
+        let upload_path = format!("{}/{}", uploads_path, upload.upload_id);
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let parent_parts = parent_metadata_parts(&folder.etag, &folder.version);
+        let complete_body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        // No parts uploaded yet.
+        let response = client
+            .post(format!("{}/complete", upload_path))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&complete_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .put(format!("{}/parts/0", upload_path))
+            .identity(client_credential_pem.as_bytes())
+            .header(ContentType::Binary)
+            .body("README CONTENT")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // Resume the upload.
+        let response = client
+            .get(&upload_path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<FileUploadResponse>().unwrap().parts,
+            vec![0]
+        );
+        let response = client
+            .post(format!("{}/complete", upload_path))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(&complete_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .get(&upload_path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .get(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().unwrap(), b"README CONTENT");
+    }
+
+    #[test]
+    fn upload_file_with_content_hash() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let file_id = create_random_file_name();
+        let upload = |content_hash: &str| {
+            let parent_parts = parent_metadata_parts(&folder.etag, &folder.version);
+            let body = [
+                parent_parts.as_str(),
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="content_hash""#,
+                "",
+                content_hash,
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+                "Content-Type: text/plain",
+                "",
+                "README CONTENT",
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+                "Content-Type: text/plain",
+                "",
+                "METADATA CONTENT",
+                "--X-BOUNDARY--",
+                "",
+            ]
+            .join("\r\n");
+            client
+                .post(format!("/folders/{}/files/{}", folder.id, file_id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .body(body)
+                .dispatch()
+        };
+        let content_hash: String = ring::digest::digest(&ring::digest::SHA256, b"README CONTENT")
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        // A corrupted upload is rejected and nothing is stored.
+        let response = upload(&"0".repeat(64));
+        assert_eq!(response.status(), Status::BadRequest);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "content_hash_mismatch");
+        assert!(list_files(&client, &client_credential_pem, folder.id)
+            .files
+            .is_empty());
+        let response = upload(&content_hash.to_uppercase());
+        assert_eq!(response.status(), Status::Created);
+        let put_response: UploadFileResponse = response.into_json().unwrap();
+        assert_eq!(Some(content_hash), put_response.content_hash);
+    }
+
+    #[test]
+    fn export_folder() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let (other_credential_pem, other_email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let response = create_test_user(&client, &other_credential_pem, &other_email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let file_id = create_random_file_name();
+        let parent_parts = parent_metadata_parts(&folder.etag, &folder.version);
+        let body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+            "Content-Type: text/plain",
+            "",
             "README CONTENT",
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
@@ -396,153 +2298,569 @@ mod test {
             "METADATA CONTENT",
             "--X-BOUNDARY--",
             "",
-        ];
+        ]
+        .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let path = format!("/folders/{}/export", folder.id);
+        let response = client
+            .get(&path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "x-tar"))
+        );
+        let archive = response.into_bytes().unwrap();
+        assert_eq!(archive.len() % 512, 0);
+        assert!(archive.ends_with(&[0u8; 1024]));
+        let contains = |needle: &[u8]| archive.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(format!("{}/metadata", folder.id).as_bytes()));
+        assert!(contains(format!("{}/{}", folder.id, file_id).as_bytes()));
+        assert!(contains(b"METADATA CONTENT"));
+        assert!(contains(b"README CONTENT"));
+        // Only the members can export the folder.
+        let response = client
+            .get(&path)
+            .identity(other_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn upload_same_content_twice() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let upload = |file_id: &str, etag: &Option<String>, version: &Option<String>| {
+            let parent_parts = parent_metadata_parts(etag, version);
+            let body = [
+                parent_parts.as_str(),
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+                "Content-Type: text/plain",
+                "",
+                "SHARED CONTENT",
+                "--X-BOUNDARY",
+                r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+                "Content-Type: text/plain",
+                "",
+                "METADATA CONTENT",
+                "--X-BOUNDARY--",
+                "",
+            ]
+            .join("\r\n");
+            let response = client
+                .post(format!("/folders/{}/files/{}", folder.id, file_id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .body(body)
+                .dispatch();
+            assert_eq!(response.status(), Status::Created);
+            response.into_json::<UploadFileResponse>().unwrap()
+        };
+        let first_file_id = create_random_file_name();
+        let second_file_id = create_random_file_name();
+        let first = upload(&first_file_id, &folder.etag, &folder.version);
+        let second = upload(&second_file_id, &first.etag, &first.version);
+        assert_eq!(first.content_hash, second.content_hash);
+        // The blobs are not listed, and the files have the size of their content.
+        let files = list_files(&client, &client_credential_pem, folder.id);
+        assert_eq!(2, files.files.len());
+        assert!(files
+            .files
+            .iter()
+            .all(|file| file.size == "SHARED CONTENT".len()));
+        let parent_parts = parent_metadata_parts(&second.etag, &second.version);
+        let delete_body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT WITHOUT FILE",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .delete(format!("/folders/{}/files/{}", folder.id, first_file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&delete_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // The content is still available to the other file.
+        let response = client
+            .get(format!("/folders/{}/files/{}", folder.id, second_file_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().unwrap(), b"SHARED CONTENT");
+    }
+
+    #[test]
+    fn forged_blob_pointer_does_not_release_blob() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let upload = |file_id: &str,
+                      file_parent_etag: Option<&str>,
+                      content: &str,
+                      etag: &Option<String>,
+                      version: &Option<String>| {
+            let mut parts = vec![parent_metadata_parts(etag, version)];
+            if let Some(file_parent_etag) = file_parent_etag {
+                parts.push(
+                    [
+                        "--X-BOUNDARY",
+                        r#"Content-Disposition: form-data; name="file_parent_etag""#,
+                        "",
+                        file_parent_etag,
+                    ]
+                    .join("\r\n"),
+                );
+            }
+            parts.push(
+                [
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
+                    "Content-Type: text/plain",
+                    "",
+                    content,
+                    "--X-BOUNDARY",
+                    r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+                    "Content-Type: text/plain",
+                    "",
+                    "METADATA CONTENT",
+                    "--X-BOUNDARY--",
+                    "",
+                ]
+                .join("\r\n"),
+            );
+            let response = client
+                .post(format!("/folders/{}/files/{}", folder.id, file_id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .body(parts.join("\r\n"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Created);
+            response.into_json::<UploadFileResponse>().unwrap()
+        };
+        let shared_file_id = create_random_file_name();
+        let shared = upload(
+            &shared_file_id,
+            None,
+            "SHARED CONTENT",
+            &folder.etag,
+            &folder.version,
+        );
+        // Another file is uploaded in parts with the content of a pointer to the blob of the first one.
+        let forged_file_id = create_random_file_name();
+        let uploads_path = format!("/folders/{}/files/{}/uploads", folder.id, forged_file_id);
+        let response = client
+            .post(&uploads_path)
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let upload_path = format!(
+            "{}/{}",
+            uploads_path,
+            response
+                .into_json::<FileUploadResponse>()
+                .unwrap()
+                .upload_id
+        );
+        let response = client
+            .put(format!("{}/parts/0", upload_path))
+            .identity(client_credential_pem.as_bytes())
+            .header(ContentType::Binary)
+            .body(format!("ssf-blob:sha256:{}", shared.content_hash.unwrap()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let parent_parts = parent_metadata_parts(&shared.etag, &shared.version);
+        let complete_body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
         let response = client
-            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .post(format!("{}/complete", upload_path))
             .identity(client_credential_pem.as_bytes())
             .header(ct.clone())
-            .body(successful_upload_body_multipart.join("\r\n"))
+            .body(&complete_body)
             .dispatch();
-        // And verify that the file was uploaded successfully.
         assert_eq!(response.status(), Status::Created);
-        let put_response: UploadFileResponse = response.into_json().unwrap();
-        put_response
-            .etag
-            .clone()
-            .or(put_response.version.clone())
-            .expect("etag or version should be present");
-        // Get the file back.
-        let response = client
-            .get(format!("/folders/{}/files/{}", folder_id, file_id))
-            .identity(client_credential_pem.as_bytes())
-            .dispatch();
-        assert_eq!(response.status(), Status::Ok);
-        let bytes: FolderFileResponse = response.into_json().unwrap();
-        assert_eq!(bytes.file, b"README CONTENT");
-        // Read metadata file.
+        let forged = response.into_json::<UploadFileResponse>().unwrap();
+        let forged_etag = list_files(&client, &client_credential_pem, folder.id)
+            .files
+            .into_iter()
+            .find(|file| file.file_id == forged_file_id)
+            .and_then(|file| file.etag)
+            .unwrap();
+        // Replacing the forged file doesn't release the blob it seems to point to.
+        upload(
+            &forged_file_id,
+            Some(&forged_etag),
+            "NEW CONTENT",
+            &forged.etag,
+            &forged.version,
+        );
         let response = client
-            .get(format!("/folders/{}/metadatas", folder_id))
+            .get(format!("/folders/{}/files/{}", folder.id, shared_file_id))
             .identity(client_credential_pem.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
-        let folder_file_response: FolderFileResponse = response.into_json().unwrap();
-        // Check that the UploadFileResponse gave the correct etag and version.
-        /*  let metadata_etags = response
-            .headers()
-            .get(ETAG.as_str().to_lowercase().as_str());
-        let metadata_versions = response.headers().get("x-version");
-        let metadata_etags = metadata_etags.collect::<Vec<_>>();
-        let metadata_versions = metadata_versions.collect::<Vec<_>>();
-        assert_eq!(metadata_etags.len(), 1);
-        assert_eq!(metadata_versions.len(), 1);
-        assert!(metadata_etags.get(0).contains("") || metadata_etags.get(0).contains(""));
-        assert_eq!(
-            metadata_etags.concat(),
-            put_response.etag.as_ref().unwrap().to_string()
-        );
-        assert_eq!(
-            metadata_versions.concat(),
-            put_response
-                .version
-                .as_ref()
-                .unwrap_or(&"".to_string())
-                .to_string()
-        );
-        let bytes = response.into_bytes().unwrap();
-        assert_eq!(bytes, b"METADATA CONTENT");
-        */
-        assert_eq!(
-            String::from_utf8(folder_file_response.file).unwrap(),
-            "METADATA CONTENT".to_string()
-        );
-        assert!(folder_file_response.etag.is_some() || folder_file_response.version.is_some());
-        assert_eq!(put_response.version, folder_file_response.version);
-        assert_eq!(put_response.etag, folder_file_response.etag);
-        let etag_part = put_response.etag.clone().map_or("".to_string(), |etag| {
-            [
-                "--X-BOUNDARY",
-                r#"Content-Disposition: form-data; name="parent_etag""#,
-                "",
-                &etag,
-            ]
-            .join("\r\n")
-            .to_string()
-        });
-        let version_part = put_response
-            .version
-            .clone()
-            .map_or("".to_string(), |version| {
+        assert_eq!(response.into_bytes().unwrap(), b"SHARED CONTENT");
+    }
+
+    #[test]
+    fn upload_files_in_batch() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let upload = |files: &[(&str, &str, Option<&str>)],
+                      etag: &Option<String>,
+                      version: &Option<String>| {
+            let mut body = vec![parent_metadata_parts(etag, version)];
+            for (index, (file_id, content, file_parent_etag)) in files.iter().enumerate() {
+                body.push(
+                    [
+                        "--X-BOUNDARY",
+                        &format!(
+                            r#"Content-Disposition: form-data; name="files[{}].file_id""#,
+                            index
+                        ),
+                        "",
+                        file_id,
+                        "--X-BOUNDARY",
+                        &format!(
+                            r#"Content-Disposition: form-data; name="files[{}].file"; filename="{}""#,
+                            index, file_id
+                        ),
+                        "Content-Type: text/plain",
+                        "",
+                        content,
+                    ]
+                    .join("\r\n"),
+                );
+                if let Some(file_parent_etag) = file_parent_etag {
+                    body.push(
+                        [
+                            "--X-BOUNDARY",
+                            &format!(
+                                r#"Content-Disposition: form-data; name="files[{}].file_parent_etag""#,
+                                index
+                            ),
+                            "",
+                            file_parent_etag,
+                        ]
+                        .join("\r\n"),
+                    );
+                }
+            }
+            body.push(
                 [
                     "--X-BOUNDARY",
-                    r#"Content-Disposition: form-data; name="parent_version""#,
+                    r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+                    "Content-Type: text/plain",
+                    "",
+                    "METADATA CONTENT",
+                    "--X-BOUNDARY--",
                     "",
-                    &version,
                 ]
-                .join("\r\n")
-                .to_string()
-            });
-        log::debug!("ETAG PART: {:?}", etag_part);
-        log::debug!("VERSION PART: {:?}", version_part);
-        let body_multipart_2 = &[
-            etag_part.as_str(),
-            version_part.as_str(),
+                .join("\r\n"),
+            );
+            client
+                .post(format!("/folders/{}/files", folder.id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .body(body.join("\r\n"))
+                .dispatch()
+        };
+        let read_file = |file_id: &str| {
+            let response = client
+                .get(format!("/folders/{}/files/{}", folder.id, file_id))
+                .identity(client_credential_pem.as_bytes())
+                .dispatch();
+            (response.status(), response.into_bytes())
+        };
+        let response = upload(
+            &[("first", "FIRST", None), ("second", "SECOND", None)],
+            &folder.etag,
+            &folder.version,
+        );
+        assert_eq!(response.status(), Status::Created);
+        let uploaded = response.into_json::<UploadFilesResponse>().unwrap();
+        assert_eq!(uploaded.content_hashes.len(), 2);
+        assert_eq!(read_file("first"), (Status::Ok, Some(b"FIRST".to_vec())));
+        assert_eq!(read_file("second"), (Status::Ok, Some(b"SECOND".to_vec())));
+        // A conflict on the metadata leaves all the files unchanged.
+        let first_etag = list_files(&client, &client_credential_pem, folder.id)
+            .files
+            .into_iter()
+            .find(|file| file.file_id == "first")
+            .and_then(|file| file.etag)
+            .unwrap();
+        let response = upload(
+            &[
+                ("first", "UPDATED", Some(&first_etag)),
+                ("third", "THIRD", None),
+            ],
+            &folder.etag,
+            &folder.version,
+        );
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(read_file("first"), (Status::Ok, Some(b"FIRST".to_vec())));
+        assert_eq!(read_file("third").0, Status::NotFound);
+        // The same file can't be written twice in a batch.
+        let response = upload(
+            &[("third", "THIRD", None), ("third", "THIRD", None)],
+            &uploaded.etag,
+            &uploaded.version,
+        );
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn retry_upload_with_idempotency_key() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let folder = post_folder_create(&client, &client_credential_pem)
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let parent_parts = parent_metadata_parts(&folder.etag, &folder.version);
+        let body = [
+            parent_parts.as_str(),
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
             "Content-Type: text/plain",
             "",
-            "README CONTENT UPDATED",
+            "README CONTENT",
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
             "Content-Type: text/plain",
             "",
-            "METADATA CONTENT UPDATED",
+            "METADATA CONTENT",
             "--X-BOUNDARY--",
             "",
-        ];
-        let body_2 = body_multipart_2.join("\r\n");
+        ]
+        .join("\r\n");
+        let file_id = create_random_file_name();
+        let upload = |file_id: &str, idempotency_key: &str| {
+            client
+                .post(format!("/folders/{}/files/{}", folder.id, file_id))
+                .identity(client_credential_pem.as_bytes())
+                .header(ct.clone())
+                .header(Header::new("Idempotency-Key", idempotency_key.to_string()))
+                .body(&body)
+                .dispatch()
+        };
+        let idempotency_key = create_random_string(16);
+        let response = upload(&file_id, &idempotency_key);
+        assert_eq!(response.status(), Status::Created);
+        assert!(response.headers().get_one("Idempotent-Replayed").is_none());
+        let first: UploadFileResponse = response.into_json().unwrap();
+        // The retry gets the same outcome, instead of a conflict on the metadata etag.
+        let response = upload(&file_id, &idempotency_key);
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            response.headers().get_one("Idempotent-Replayed"),
+            Some("true")
+        );
+        let retry: UploadFileResponse = response.into_json().unwrap();
+        assert_eq!(first.etag, retry.etag);
+        assert_eq!(first.version, retry.version);
+        assert_eq!(
+            1,
+            list_files(&client, &client_credential_pem, folder.id)
+                .files
+                .len()
+        );
+        // With another key, the request is processed again.
+        let response = upload(&file_id, &create_random_string(16));
+        assert_eq!(response.status(), Status::Conflict);
+        // The key can't be reused for another request.
+        let response = upload(&create_random_file_name(), &idempotency_key);
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "idempotency_key_reused");
+        // Nor for the same file with another content.
         let response = client
-            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .post(format!("/folders/{}/files/{}", folder.id, file_id))
             .identity(client_credential_pem.as_bytes())
             .header(ct.clone())
-            .body(body_2)
+            .header(Header::new("Idempotency-Key", idempotency_key.clone()))
+            .body(body.replace("README CONTENT", "OTHER CONTENT"))
             .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error = response.into_json::<ErrorBody>().unwrap();
+        assert_eq!(error.code, "idempotency_key_reused");
+    }
+
+    #[test]
+    fn upload_file_and_delete_it() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Created);
-        let put_response_2: UploadFileResponse = response.into_json().unwrap();
-        put_response_2
-            .etag
-            .clone()
-            .or(put_response_2.version.clone())
-            .expect("etag or version should be present");
-        assert_ne!(
-            put_response_2.etag.or(put_response_2.version),
-            put_response.etag.or(put_response.version)
-        );
-        log::debug!("ETAG PART: {:?}", etag_part);
-        log::debug!("VERSION PART: {:?}", version_part);
-        let body_multipart_3 = &[
-            etag_part.as_str(),
-            version_part.as_str(),
+        let create_folder_response = post_folder_create(&client, &client_credential_pem);
+        assert_eq!(create_folder_response.status(), Status::Created);
+        let folder = create_folder_response
+            .into_json::<FolderResponse>()
+            .unwrap();
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let file_id = create_random_file_name();
+        let parent_parts = parent_metadata_parts(&folder.etag, &folder.version);
+        let upload_body = &[
+            parent_parts.as_str(),
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="file"; filename="README.md""#,
             "Content-Type: text/plain",
             "",
-            "README CONTENT UPDATED",
+            "README CONTENT",
             "--X-BOUNDARY",
             r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
             "Content-Type: text/plain",
             "",
-            "METADATA CONTENT UPDATED",
+            "METADATA CONTENT",
             "--X-BOUNDARY--",
             "",
         ];
-        let body_3 = body_multipart_3.join("\r\n");
         let response = client
-            .post(format!("/folders/{}/files/{}", folder_id, file_id))
+            .post(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(upload_body.join("\r\n"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let put_response: UploadFileResponse = response.into_json().unwrap();
+        let files = list_files(&client, &client_credential_pem, folder.id);
+        assert_eq!(1, files.files.len());
+        assert_eq!(file_id, files.files[0].file_id);
+        let parent_parts = parent_metadata_parts(&put_response.etag, &put_response.version);
+        let delete_body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT WITHOUT FILE",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .delete(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&delete_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let delete_response: UploadFileResponse = response.into_json().unwrap();
+        let response = client
+            .get(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(list_files(&client, &client_credential_pem, folder.id)
+            .files
+            .is_empty());
+        let response = client
+            .delete(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&delete_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        // The deleted file is kept in the trash and can be restored.
+        let response = client
+            .get(format!("/folders/{}/trash", folder.id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let trash = response.into_json::<ListTrashResponse>().unwrap();
+        assert_eq!(1, trash.files.len());
+        assert_eq!(file_id, trash.files[0].file_id);
+        assert_eq!("README CONTENT".len(), trash.files[0].size);
+        let parent_parts = parent_metadata_parts(&delete_response.etag, &delete_response.version);
+        let deleted_at = trash.files[0].deleted_at.to_string();
+        let restore_body = [
+            parent_parts.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="deleted_at""#,
+            "",
+            deleted_at.as_str(),
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="metadata"; filename="Metadata.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "METADATA CONTENT",
+            "--X-BOUNDARY--",
+            "",
+        ]
+        .join("\r\n");
+        let response = client
+            .post(format!("/folders/{}/files/{}/restore", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .header(ct.clone())
+            .body(&restore_body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let response = client
+            .get(format!("/folders/{}/files/{}", folder.id, file_id))
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().unwrap(), b"README CONTENT");
+        // The file is not in the trash anymore.
+        let response = client
+            .post(format!("/folders/{}/files/{}/restore", folder.id, file_id))
             .identity(client_credential_pem.as_bytes())
             .header(ct)
-            .body(body_3)
+            .body(&restore_body)
             .dispatch();
-        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(response.status(), Status::NotFound);
     }
 
     fn post_key_package_create<'r>(
@@ -569,6 +2887,50 @@ mod test {
             .dispatch()
     }
 
+    #[test]
+    fn upload_key_packages_batch() {
+        let (client_credential_pem, email) = create_client_credentials();
+        let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
+        let response = create_test_user(&client, &client_credential_pem, &email);
+        assert_eq!(response.status(), Status::Created);
+        let ct = "multipart/form-data; boundary=X-BOUNDARY"
+            .parse::<ContentType>()
+            .unwrap();
+        let key_package_part = [
+            "--X-BOUNDARY",
+            r#"Content-Disposition: form-data; name="key_package"; filename="KeyPackage.txt""#,
+            "Content-Type: text/plain",
+            "",
+            "KEY PACKAGE",
+        ]
+        .join("\r\n");
+        let mut body_multipart = vec![key_package_part.as_str(); 3];
+        body_multipart.push("--X-BOUNDARY--");
+        let response = client
+            .post("/users/keys")
+            .identity(client_credential_pem.as_bytes())
+            .body(body_multipart.join("\r\n"))
+            .header(ct)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let response = response.into_json::<CreateKeyPackageResponse>().unwrap();
+        assert_eq!(response.key_package_ids.len(), 3);
+        assert_eq!(response.key_package_id, response.key_package_ids[0]);
+        assert_eq!(count_key_packages(&client, &client_credential_pem), 3);
+    }
+
+    fn count_key_packages(client: &Client, client_credential_pem: &str) -> u64 {
+        let response = client
+            .get("/users/keys/count")
+            .identity(client_credential_pem.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response
+            .into_json::<KeyPackageCountResponse>()
+            .expect("Valid key package count")
+            .count
+    }
+
     fn fetch_key_package<'r>(
         client: &'r Client,
         email: &str,
@@ -595,8 +2957,10 @@ mod test {
         let client = Client::tracked(init_server_from_config()).expect("valid rocket instance");
         let response = create_test_user(&client, &client_credential_pem, &email);
         assert_eq!(response.status(), Status::Created);
+        assert_eq!(count_key_packages(&client, &client_credential_pem), 0);
         let response = post_key_package_create(&client, &client_credential_pem);
         assert_eq!(response.status(), Status::Created);
+        assert_eq!(count_key_packages(&client, &client_credential_pem), 1);
         let create_folder_response_1 = post_folder_create(&client, &client_credential_pem);
         assert_eq!(create_folder_response_1.status(), Status::Created);
         let create_response_content_1 = create_folder_response_1
@@ -617,6 +2981,7 @@ mod test {
             String::from_utf8(response.payload).unwrap(),
             "KEY PACKAGE".to_string()
         );
+        assert_eq!(count_key_packages(&client, &client_credential_pem), 0);
     }
     // TODO: add test for post_metadata
 }
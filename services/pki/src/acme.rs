@@ -0,0 +1,825 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! A minimal [RFC 8555](https://www.rfc-editor.org/rfc/rfc8555) ACME server issuing client certificates
+//! for email identifiers, following the flow: new account, new order, challenge, finalize, certificate download.
+//!
+//! The accounts, orders and authorizations are kept in memory: orders are short lived and the clients
+//! can restart the flow, registering their account key again, if the server is restarted.
+//! As for `/ca/register`, the ownership of the email is not verified yet:
+//! the `email-reply-00` challenge is accepted as soon as the client responds to it.
+//! The endpoints are only mounted when enabled in the configuration, see [`AcmeConfig::enabled`].
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{
+        RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
+        ECDSA_P384_SHA384_FIXED, RSA_PKCS1_2048_8192_SHA256,
+    },
+};
+use rocket::{
+    http::{ContentType, Header, Status},
+    response::{self, Responder},
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::AcmeConfig;
+
+/// How long orders and authorizations are valid, in seconds.
+pub const ORDER_VALIDITY: u64 = 24 * 60 * 60;
+/// The type of the challenge of the email identifiers, see [RFC 8823](https://www.rfc-editor.org/rfc/rfc8823).
+pub const EMAIL_CHALLENGE: &str = "email-reply-00";
+/// The maximum number of unused nonces, the oldest are forgotten first.
+const MAX_NONCES: usize = 4096;
+/// The maximum length of an email, see the `certificates` table.
+const MAX_EMAIL_LENGTH: usize = 100;
+
+/// The path of the ACME directory.
+pub const DIRECTORY_PATH: &str = "/acme/directory";
+/// The path of the nonce resource.
+pub const NEW_NONCE_PATH: &str = "/acme/new-nonce";
+/// The path of the account creation resource.
+pub const NEW_ACCOUNT_PATH: &str = "/acme/new-account";
+/// The path of the order creation resource.
+pub const NEW_ORDER_PATH: &str = "/acme/new-order";
+/// The path prefix of the accounts, which URLs are the key identifiers of the requests.
+pub const ACCOUNT_PATH: &str = "/acme/account/";
+/// The path prefix of the orders.
+pub const ORDER_PATH: &str = "/acme/order/";
+/// The path prefix of the authorizations.
+pub const AUTHORIZATION_PATH: &str = "/acme/authz/";
+/// The path prefix of the challenges.
+pub const CHALLENGE_PATH: &str = "/acme/chall/";
+/// The path prefix of the certificates.
+pub const CERTIFICATE_PATH: &str = "/acme/cert/";
+
+/// An ACME error, returned as a [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) problem document.
+#[derive(Debug)]
+pub struct Problem {
+    status: Status,
+    /// The ACME error type, without the `urn:ietf:params:acme:error:` namespace.
+    kind: &'static str,
+    detail: String,
+}
+
+impl Problem {
+    pub fn new(status: Status, kind: &'static str, detail: impl Into<String>) -> Self {
+        Problem {
+            status,
+            kind,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn malformed(detail: impl Into<String>) -> Self {
+        Problem::new(Status::BadRequest, "malformed", detail)
+    }
+
+    pub fn unauthorized(detail: impl Into<String>) -> Self {
+        Problem::new(Status::Forbidden, "unauthorized", detail)
+    }
+
+    pub fn not_found(detail: impl Into<String>) -> Self {
+        Problem::new(Status::NotFound, "malformed", detail)
+    }
+
+    pub fn bad_csr(detail: impl Into<String>) -> Self {
+        Problem::new(Status::BadRequest, "badCSR", detail)
+    }
+
+    /// Whether the certificate request was rejected, the client can finalize the order with another one.
+    pub fn is_bad_csr(&self) -> bool {
+        self.kind == "badCSR"
+    }
+
+    pub fn rate_limited() -> Self {
+        Problem::new(
+            Status::TooManyRequests,
+            "rateLimited",
+            "Too many requests, retry later.",
+        )
+    }
+
+    pub fn server_internal() -> Self {
+        Problem::new(
+            Status::InternalServerError,
+            "serverInternal",
+            "Internal Server Error",
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ProblemDocument<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    detail: &'a str,
+    status: u16,
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_vec(&ProblemDocument {
+            kind: format!("urn:ietf:params:acme:error:{}", self.kind),
+            detail: &self.detail,
+            status: self.status.code,
+        })
+        .map_err(|_| Status::InternalServerError)?;
+        AcmeResponse::new(
+            self.status,
+            ContentType::new("application", "problem+json"),
+            body,
+        )
+        .respond_to(request)
+    }
+}
+
+/// A response of the ACME endpoints, carrying a fresh nonce for the next request of the client.
+pub struct AcmeResponse {
+    status: Status,
+    content_type: ContentType,
+    body: Vec<u8>,
+    location: Option<String>,
+    up: Option<String>,
+}
+
+impl AcmeResponse {
+    pub fn new(status: Status, content_type: ContentType, body: Vec<u8>) -> Self {
+        AcmeResponse {
+            status,
+            content_type,
+            body,
+            location: None,
+            up: None,
+        }
+    }
+
+    /// A JSON response.
+    pub fn json<T: Serialize>(status: Status, value: &T) -> Result<Self, Problem> {
+        let body = serde_json::to_vec(value).map_err(|_| Problem::server_internal())?;
+        Ok(AcmeResponse::new(status, ContentType::JSON, body))
+    }
+
+    /// Set the URL of the resource created or returned.
+    pub fn with_location(mut self, location: String) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    /// Set the URL of the parent resource, e.g. the authorization of a challenge.
+    pub fn with_up(mut self, up: String) -> Self {
+        self.up = Some(up);
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for AcmeResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let rocket = request.rocket();
+        let (Some(state), Some(config)) =
+            (rocket.state::<AcmeStateArc>(), rocket.state::<AcmeConfig>())
+        else {
+            log::error!("The ACME state is not managed by the server");
+            return Err(Status::InternalServerError);
+        };
+        let nonce = state.lock().unwrap().new_nonce();
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(self.content_type)
+            .header(Header::new("Replay-Nonce", nonce))
+            .header(Header::new("Cache-Control", "no-store"))
+            .header(Header::new(
+                "Link",
+                format!("<{}>;rel=\"index\"", config.url(DIRECTORY_PATH)),
+            ));
+        if let Some(location) = self.location {
+            response.header(Header::new("Location", location));
+        }
+        if let Some(up) = self.up {
+            response.raw_header_adjoin("Link", format!("<{}>;rel=\"up\"", up));
+        }
+        response.sized_body(self.body.len(), std::io::Cursor::new(self.body));
+        response.ok()
+    }
+}
+
+/// A public key in the JSON Web Key format, see [RFC 7517](https://www.rfc-editor.org/rfc/rfc7517).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kty")]
+pub enum Jwk {
+    EC { crv: String, x: String, y: String },
+    RSA { n: String, e: String },
+}
+
+impl Jwk {
+    /// The [RFC 7638](https://www.rfc-editor.org/rfc/rfc7638) thumbprint of the key, used as the account identifier.
+    pub fn thumbprint(&self) -> String {
+        // The required members in lexicographic order, without whitespaces.
+        let canonical = match self {
+            Jwk::EC { crv, x, y } => {
+                format!(r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#, crv, x, y)
+            }
+            Jwk::RSA { n, e } => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n),
+        };
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()))
+    }
+
+    /// Verify the signature of the message with the key, for the given JWS algorithm.
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), Problem> {
+        let decode = |value: &str| {
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|_| Problem::malformed("Invalid JWK encoding"))
+        };
+        let verified = match (alg, self) {
+            ("ES256", Jwk::EC { crv, x, y }) if crv == "P-256" => {
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, ec_point(&decode(x)?, &decode(y)?))
+                    .verify(message, signature)
+            }
+            ("ES384", Jwk::EC { crv, x, y }) if crv == "P-384" => {
+                UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, ec_point(&decode(x)?, &decode(y)?))
+                    .verify(message, signature)
+            }
+            ("RS256", Jwk::RSA { n, e }) => RsaPublicKeyComponents {
+                n: decode(n)?,
+                e: decode(e)?,
+            }
+            .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature),
+            _ => {
+                return Err(Problem::new(
+                    Status::BadRequest,
+                    "badSignatureAlgorithm",
+                    format!("Unsupported algorithm `{}`, use ES256, ES384 or RS256", alg),
+                ))
+            }
+        };
+        verified.map_err(|_| Problem::malformed("Invalid JWS signature"))
+    }
+}
+
+/// The uncompressed encoding of an elliptic curve point.
+fn ec_point(x: &[u8], y: &[u8]) -> Vec<u8> {
+    let mut point = Vec::with_capacity(1 + x.len() + y.len());
+    point.push(0x04);
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    point
+}
+
+/// A request of the client, in the flattened JWS JSON serialization.
+#[derive(Deserialize)]
+struct Jws {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct ProtectedHeader {
+    alg: String,
+    nonce: Option<String>,
+    url: String,
+    jwk: Option<Jwk>,
+    kid: Option<String>,
+}
+
+/// A request whose signature, nonce and URL have been verified.
+pub struct VerifiedRequest {
+    /// The identifier of the account signing the request, if the request is signed with the key of an account.
+    pub account: Option<String>,
+    /// The key signing the request, if embedded in the request.
+    pub jwk: Option<Jwk>,
+    /// The decoded payload, empty for the POST-as-GET requests.
+    pub payload: Vec<u8>,
+}
+
+impl VerifiedRequest {
+    /// The account of the request, signed with the key identifier of an account.
+    pub fn account(&self) -> Result<&str, Problem> {
+        self.account.as_deref().ok_or_else(|| {
+            Problem::malformed("The request must be signed with an account key identifier")
+        })
+    }
+
+    /// Deserialize the JSON payload.
+    pub fn payload<'a, T: Deserialize<'a>>(&'a self) -> Result<T, Problem> {
+        serde_json::from_slice(&self.payload)
+            .map_err(|e| Problem::malformed(format!("Invalid payload: {}", e)))
+    }
+
+    /// Whether the request is a POST-as-GET, fetching a resource.
+    pub fn is_post_as_get(&self) -> bool {
+        self.payload.is_empty()
+    }
+}
+
+/// The status of the ACME objects.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AcmeStatus {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+}
+
+/// An identifier of an order, only `email` identifiers are supported.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewAccountPayload {
+    #[serde(default)]
+    pub contact: Vec<String>,
+    #[serde(default)]
+    pub only_return_existing: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewOrderPayload {
+    pub identifiers: Vec<Identifier>,
+}
+
+#[derive(Deserialize)]
+pub struct FinalizePayload {
+    /// The base64url encoded DER certificate request.
+    pub csr: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryObject {
+    pub new_nonce: String,
+    pub new_account: String,
+    pub new_order: String,
+    pub meta: DirectoryMeta,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryMeta {
+    pub external_account_required: bool,
+}
+
+impl DirectoryObject {
+    pub fn new(config: &AcmeConfig) -> Self {
+        DirectoryObject {
+            new_nonce: config.url(NEW_NONCE_PATH),
+            new_account: config.url(NEW_ACCOUNT_PATH),
+            new_order: config.url(NEW_ORDER_PATH),
+            meta: DirectoryMeta {
+                external_account_required: false,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountObject {
+    pub status: AcmeStatus,
+    pub contact: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderObject {
+    pub status: AcmeStatus,
+    pub expires: String,
+    pub identifiers: Vec<Identifier>,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuthorizationObject {
+    pub identifier: Identifier,
+    pub status: AcmeStatus,
+    pub expires: String,
+    pub challenges: Vec<ChallengeObject>,
+}
+
+#[derive(Serialize)]
+pub struct ChallengeObject {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub url: String,
+    pub token: String,
+    pub status: AcmeStatus,
+}
+
+/// An ACME account, identified by the thumbprint of its key.
+pub struct Account {
+    pub jwk: Jwk,
+    pub contact: Vec<String>,
+}
+
+impl Account {
+    pub fn to_object(&self) -> AccountObject {
+        AccountObject {
+            status: AcmeStatus::Valid,
+            contact: self.contact.clone(),
+        }
+    }
+}
+
+/// An order of a certificate for an email, with a single authorization.
+pub struct Order {
+    pub account: String,
+    pub email: String,
+    pub status: AcmeStatus,
+    /// When the order expires, in seconds since the Unix epoch.
+    pub expires: u64,
+    pub authorization: String,
+    /// The PEM encoded certificate chain, once the order is finalized.
+    pub certificate: Option<String>,
+}
+
+impl Order {
+    pub fn to_object(&self, id: &str, config: &AcmeConfig) -> OrderObject {
+        OrderObject {
+            status: self.status,
+            expires: rfc3339(self.expires),
+            identifiers: vec![email_identifier(&self.email)],
+            authorizations: vec![
+                config.url(&format!("{}{}", AUTHORIZATION_PATH, self.authorization))
+            ],
+            finalize: config.url(&format!("{}{}/finalize", ORDER_PATH, id)),
+            certificate: self
+                .certificate
+                .as_ref()
+                .map(|_| config.url(&format!("{}{}", CERTIFICATE_PATH, id))),
+        }
+    }
+}
+
+/// The authorization of an account to get certificates for an email.
+pub struct Authorization {
+    pub account: String,
+    pub order: String,
+    pub email: String,
+    pub status: AcmeStatus,
+    /// When the authorization expires, in seconds since the Unix epoch.
+    pub expires: u64,
+    pub token: String,
+}
+
+impl Authorization {
+    pub fn to_object(&self, id: &str, config: &AcmeConfig) -> AuthorizationObject {
+        AuthorizationObject {
+            identifier: email_identifier(&self.email),
+            status: self.status,
+            expires: rfc3339(self.expires),
+            challenges: vec![self.challenge(id, config)],
+        }
+    }
+
+    /// The challenge of the authorization, sharing its identifier.
+    pub fn challenge(&self, id: &str, config: &AcmeConfig) -> ChallengeObject {
+        ChallengeObject {
+            kind: EMAIL_CHALLENGE,
+            url: config.url(&format!("{}{}", CHALLENGE_PATH, id)),
+            token: self.token.clone(),
+            status: self.status,
+        }
+    }
+}
+
+fn email_identifier(email: &str) -> Identifier {
+    Identifier {
+        kind: "email".to_string(),
+        value: email.to_string(),
+    }
+}
+
+fn rfc3339(seconds: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(seconds as i64)
+        .ok()
+        .and_then(|date| date.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+/// The state of the ACME server: the unused nonces, the accounts and the pending orders.
+#[derive(Default)]
+pub struct AcmeState {
+    nonces: HashSet<String>,
+    /// The nonces in order of creation, to forget the oldest ones.
+    nonces_queue: VecDeque<String>,
+    pub accounts: HashMap<String, Account>,
+    pub orders: HashMap<String, Order>,
+    pub authorizations: HashMap<String, Authorization>,
+}
+
+/// The type of the ACME state wrapped in an Arc and a Mutex.
+pub type AcmeStateArc = std::sync::Arc<std::sync::Mutex<AcmeState>>;
+
+impl AcmeState {
+    /// Create a new nonce, valid for a single request.
+    pub fn new_nonce(&mut self) -> String {
+        let nonce = random_id();
+        if self.nonces_queue.len() >= MAX_NONCES {
+            if let Some(oldest) = self.nonces_queue.pop_front() {
+                self.nonces.remove(&oldest);
+            }
+        }
+        self.nonces.insert(nonce.clone());
+        self.nonces_queue.push_back(nonce.clone());
+        nonce
+    }
+
+    /// Verify the JWS request sent to the given URL: its nonce, its URL and its signature,
+    /// either by the embedded key or by the key of the account identified by the key identifier.
+    pub fn verify_request(
+        &mut self,
+        body: &[u8],
+        url: &str,
+        config: &AcmeConfig,
+    ) -> Result<VerifiedRequest, Problem> {
+        let jws: Jws = serde_json::from_slice(body)
+            .map_err(|_| Problem::malformed("The request must be a flattened JWS"))?;
+        let header: ProtectedHeader = URL_SAFE_NO_PAD
+            .decode(&jws.protected)
+            .ok()
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or_else(|| Problem::malformed("Invalid JWS protected header"))?;
+        let nonce_used = header
+            .nonce
+            .as_ref()
+            .is_some_and(|nonce| self.nonces.remove(nonce));
+        if !nonce_used {
+            return Err(Problem::new(
+                Status::BadRequest,
+                "badNonce",
+                "Missing or already used nonce",
+            ));
+        }
+        if header.url != url {
+            return Err(Problem::unauthorized(format!(
+                "The request was signed for `{}`",
+                header.url
+            )));
+        }
+        let (account, jwk) = match (header.jwk, header.kid) {
+            (Some(jwk), None) => (None, jwk),
+            (None, Some(kid)) => {
+                let id = kid
+                    .strip_prefix(&config.url(ACCOUNT_PATH))
+                    .ok_or_else(|| Problem::malformed("Invalid key identifier"))?;
+                let account = self.accounts.get(id).ok_or_else(|| {
+                    Problem::new(
+                        Status::BadRequest,
+                        "accountDoesNotExist",
+                        "The account does not exist",
+                    )
+                })?;
+                (Some(id.to_string()), account.jwk.clone())
+            }
+            _ => {
+                return Err(Problem::malformed(
+                    "The request must be signed either with a JWK or a key identifier",
+                ))
+            }
+        };
+        let signature = URL_SAFE_NO_PAD
+            .decode(&jws.signature)
+            .map_err(|_| Problem::malformed("Invalid JWS signature encoding"))?;
+        let message = format!("{}.{}", jws.protected, jws.payload);
+        jwk.verify(&header.alg, message.as_bytes(), &signature)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(&jws.payload)
+            .map_err(|_| Problem::malformed("Invalid JWS payload encoding"))?;
+        let is_account = account.is_some();
+        Ok(VerifiedRequest {
+            account,
+            jwk: (!is_account).then_some(jwk),
+            payload,
+        })
+    }
+
+    /// Create an order for the email, with its authorization, returning the identifier of the order.
+    pub fn new_order(&mut self, account: &str, email: &str, now: u64) -> String {
+        self.prune(now);
+        let (order_id, authorization_id) = (random_id(), random_id());
+        let expires = now + ORDER_VALIDITY;
+        self.authorizations.insert(
+            authorization_id.clone(),
+            Authorization {
+                account: account.to_string(),
+                order: order_id.clone(),
+                email: email.to_string(),
+                status: AcmeStatus::Pending,
+                expires,
+                token: random_id(),
+            },
+        );
+        self.orders.insert(
+            order_id.clone(),
+            Order {
+                account: account.to_string(),
+                email: email.to_string(),
+                status: AcmeStatus::Pending,
+                expires,
+                authorization: authorization_id,
+                certificate: None,
+            },
+        );
+        order_id
+    }
+
+    /// Forget the orders that expired before being finalized, and their authorizations.
+    fn prune(&mut self, now: u64) {
+        self.orders
+            .retain(|_, order| order.expires > now || order.status == AcmeStatus::Valid);
+        let orders = &self.orders;
+        self.authorizations
+            .retain(|_, authorization| orders.contains_key(&authorization.order));
+    }
+}
+
+/// Validate the identifiers of a new order, returning the email to certify.
+pub fn order_email(identifiers: &[Identifier]) -> Result<String, Problem> {
+    let [identifier] = identifiers else {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "rejectedIdentifier",
+            "An order must have exactly one identifier",
+        ));
+    };
+    if identifier.kind != "email" {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "unsupportedIdentifier",
+            format!("Unsupported identifier type `{}`", identifier.kind),
+        ));
+    }
+    let email = identifier.value.trim();
+    let valid = email
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty());
+    if !valid || email.len() > MAX_EMAIL_LENGTH {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "rejectedIdentifier",
+            format!("Invalid email `{}`", email),
+        ));
+    }
+    Ok(email.to_string())
+}
+
+/// A random base64url identifier, for the nonces, the orders, the authorizations and the tokens.
+pub(crate) fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system random number generator is available");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    use super::*;
+
+    fn mk_key() -> (EcdsaKeyPair, Jwk) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        let point = key_pair.public_key().as_ref();
+        let jwk = Jwk::EC {
+            crv: "P-256".to_string(),
+            x: URL_SAFE_NO_PAD.encode(&point[1..33]),
+            y: URL_SAFE_NO_PAD.encode(&point[33..]),
+        };
+        (key_pair, jwk)
+    }
+
+    /// Creates a flattened JWS, signed with the key pair.
+    fn mk_jws(key_pair: &EcdsaKeyPair, header: serde_json::Value, payload: &str) -> Vec<u8> {
+        let protected = URL_SAFE_NO_PAD.encode(header.to_string());
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = key_pair
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .unwrap();
+        serde_json::json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        // The example of RFC 7638, section 3.1.
+        let jwk = Jwk::RSA {
+            n: "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".to_string(),
+            e: "AQAB".to_string(),
+        };
+        assert_eq!(
+            jwk.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn test_verify_request() {
+        let config = AcmeConfig::default();
+        let mut state = AcmeState::default();
+        let (key_pair, jwk) = mk_key();
+        let url = config.url(NEW_ACCOUNT_PATH);
+        let nonce = state.new_nonce();
+        let header = serde_json::json!({ "alg": "ES256", "nonce": nonce, "url": url, "jwk": jwk });
+        let request = mk_jws(&key_pair, header.clone(), r#"{"contact":[]}"#);
+
+        let verified = state.verify_request(&request, &url, &config).unwrap();
+        assert_eq!(verified.jwk, Some(jwk.clone()));
+        assert!(verified.account.is_none());
+        assert!(verified.payload::<NewAccountPayload>().is_ok());
+        // The nonce can be used only once.
+        assert!(state.verify_request(&request, &url, &config).is_err());
+
+        // The request is signed for another URL.
+        let nonce = state.new_nonce();
+        let header = serde_json::json!({ "alg": "ES256", "nonce": nonce, "url": url, "jwk": jwk });
+        let request = mk_jws(&key_pair, header, "");
+        assert!(state
+            .verify_request(&request, &config.url(NEW_ORDER_PATH), &config)
+            .is_err());
+
+        // The request is signed by another key.
+        let (other_key_pair, _) = mk_key();
+        let nonce = state.new_nonce();
+        let header = serde_json::json!({ "alg": "ES256", "nonce": nonce, "url": url, "jwk": jwk });
+        let request = mk_jws(&other_key_pair, header, "");
+        assert!(state.verify_request(&request, &url, &config).is_err());
+
+        // The request is signed with the key identifier of an account.
+        let id = jwk.thumbprint();
+        state.accounts.insert(
+            id.clone(),
+            Account {
+                jwk,
+                contact: Vec::new(),
+            },
+        );
+        let url = config.url(NEW_ORDER_PATH);
+        let nonce = state.new_nonce();
+        let kid = config.url(&format!("{}{}", ACCOUNT_PATH, id));
+        let header = serde_json::json!({ "alg": "ES256", "nonce": nonce, "url": url, "kid": kid });
+        let request = mk_jws(&key_pair, header, "");
+        let verified = state.verify_request(&request, &url, &config).unwrap();
+        assert_eq!(verified.account().unwrap(), id);
+        assert!(verified.is_post_as_get());
+    }
+
+    #[test]
+    fn test_order_email() {
+        let identifier = |kind: &str, value: &str| Identifier {
+            kind: kind.to_string(),
+            value: value.to_string(),
+        };
+        assert_eq!(
+            order_email(&[identifier("email", "test@test.com")]).unwrap(),
+            "test@test.com"
+        );
+        assert!(order_email(&[identifier("dns", "test.com")]).is_err());
+        assert!(order_email(&[identifier("email", "test")]).is_err());
+        assert!(order_email(&[]).is_err());
+        assert!(order_email(&[
+            identifier("email", "test@test.com"),
+            identifier("email", "other@test.com")
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The audit log of the PKI, recording every sign, verify and revoke operation with the requester, the email and
+//! serial number of the certificate, and the outcome, in the append-only `audit_log` table.
+//! The handlers describe their operation through the [`Audit`] request guard, and the [`AuditLogger`] fairing
+//! writes the event once the response is known, so that the failures are recorded as well.
+
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use common::crypto::retrieve_emails_from_x509_certificate;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    mtls::Certificate,
+    request::{FromRequest, Outcome},
+    FromFormField, Request, Response,
+};
+use rocket_db_pools::Database;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
+
+use crate::db::{insert_audit_event, AuditEventEntity, DbConn};
+
+/// The operations recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    /// A certificate was requested, by registration, renewal or ACME.
+    Sign,
+    /// A certificate was verified.
+    Verify,
+    /// A certificate was revoked, by its holder or an admin.
+    Revoke,
+}
+
+impl AuditOperation {
+    /// The name of the operation in the `audit_log` table.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOperation::Sign => "sign",
+            AuditOperation::Verify => "verify",
+            AuditOperation::Revoke => "revoke",
+        }
+    }
+}
+
+/// The details of the operation of a request, filled in by its handler.
+#[derive(Default)]
+struct AuditDetails {
+    operation: Option<AuditOperation>,
+    requester: Option<String>,
+    email: Option<String>,
+    serial: Option<String>,
+    outcome: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct AuditRecord(Mutex<AuditDetails>);
+
+/// A request guard to describe the operation of the request in the audit log.
+/// Nothing is recorded unless [`Audit::record`] is called.
+pub struct Audit<'r>(&'r AuditRecord);
+
+impl Audit<'_> {
+    fn update(&self, update: impl FnOnce(&mut AuditDetails)) {
+        update(&mut self.0 .0.lock().expect("Audit record corrupted!"));
+    }
+
+    /// Record the operation of the request.
+    pub fn record(&self, operation: AuditOperation) {
+        self.update(|details| details.operation = Some(operation));
+    }
+
+    /// Set the email of the certificate the operation is about, once known.
+    pub fn email(&self, email: &str) {
+        self.update(|details| details.email = Some(email.to_string()));
+    }
+
+    /// Set the hex encoded serial number of the certificate, once known.
+    pub fn serial(&self, serial: &str) {
+        self.update(|details| details.serial = Some(serial.to_string()));
+    }
+
+    /// Set the requester, when it is not identified by its client certificate, e.g. an ACME account.
+    pub fn requester(&self, requester: String) {
+        self.update(|details| details.requester = Some(requester));
+    }
+
+    /// Set the outcome of the operation, when the status of the response is not enough, e.g. for a verification.
+    pub fn outcome(&self, outcome: &'static str) {
+        self.update(|details| details.outcome = Some(outcome));
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Audit(request.local_cache(AuditRecord::default)))
+    }
+}
+
+/// A fairing writing the operations recorded by the handlers to the audit log, with the status of the response.
+/// The requester is identified by the emails of its client certificate, if any.
+pub struct AuditLogger;
+
+#[rocket::async_trait]
+impl Fairing for AuditLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit logger",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let details = std::mem::take(
+            &mut *req
+                .local_cache(AuditRecord::default)
+                .0
+                .lock()
+                .expect("Audit record corrupted!"),
+        );
+        let Some(operation) = details.operation else {
+            return;
+        };
+        let requester = match details.requester {
+            Some(requester) => Some(requester),
+            None => req
+                .guard::<Certificate<'_>>()
+                .await
+                .succeeded()
+                .and_then(|certificate| {
+                    X509Certificate::from_der(certificate.as_bytes())
+                        .ok()
+                        .map(|(_, x509)| retrieve_emails_from_x509_certificate(x509).join(","))
+                }),
+        };
+        let status = res.status();
+        let outcome = details.outcome.unwrap_or(if status.class().is_success() {
+            "success"
+        } else {
+            "failure"
+        });
+        let event = AuditEventEntity {
+            id: 0,
+            logged_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            operation: operation.as_str().to_string(),
+            requester,
+            requester_ip: req.client_ip().map(|ip| ip.to_string()),
+            email: details.email,
+            serial: details.serial,
+            status: status.code,
+            outcome: outcome.to_string(),
+        };
+        let Some(db) = DbConn::fetch(req.rocket()) else {
+            log::error!("The database pool is not initialised, the audit event is lost");
+            return;
+        };
+        if let Err(e) = insert_audit_event(&event, db).await {
+            log::error!(
+                "Couldn't write the `{}` operation about `{:?}` to the audit log: {:?}",
+                event.operation,
+                event.email,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_operation_names() {
+        for operation in [
+            AuditOperation::Sign,
+            AuditOperation::Verify,
+            AuditOperation::Revoke,
+        ] {
+            assert_eq!(
+                serde_json::to_string(&operation).unwrap(),
+                format!("\"{}\"", operation.as_str())
+            );
+        }
+    }
+}
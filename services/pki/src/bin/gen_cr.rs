@@ -13,12 +13,13 @@
 //
 use std::{env, error::Error, fs};
 
-use common::crypto::mk_client_certificate_request_params;
+use common::crypto::{mk_client_certificate_request_params, KeyAlgorithm};
 use log::info;
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    let (key_pair, signing_request) = mk_client_certificate_request_params("test@test.com")?;
+    let (key_pair, signing_request) =
+        mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())?;
     info!("Generated key pair: {:?}", key_pair.serialize_pem());
     info!("Generated signing request: {:?}", signing_request.pem()?);
     let _ = env::var("SAVE_TO_FILE").map(|_| {
@@ -11,73 +11,256 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use std::sync::{Arc, Mutex};
+use std::{collections::HashSet, sync::Arc};
 
-use common::pki::init_ca;
-use pki::{db, get_pki_server_credential_paths, init_ds_server, init_pki_server, server};
+use common::{
+    crypto::load_ca_with_key_pair,
+    pki::{init_issuing_ca, load_issuing_ca_chain, IssuingCa},
+};
+use pki::{
+    acme::AcmeStateArc,
+    audit::AuditLogger,
+    challenge::RegistrationChallengesArc,
+    db,
+    ds_notifier::{DsNotifier, OptionalDsNotifier},
+    init_ds_server, init_pki_server,
+    policy::IssuancePolicy,
+    rate_limit::{retry_after_header, RateLimiter},
+    renewal::{self, TlsReload},
+    server,
+    signer::{remote_key_pair, CommandSigner, KeyPairSigner, SignerArc},
+    webhooks::{OptionalWebhooks, Webhooks},
+    AcmeConfig, CaConfig, DsConfig, PathsConfig, PolicyConfig, RateLimitConfig, RenewalConfig,
+    ValidityConfig, WebhooksConfig,
+};
 use rocket::{
     config::{MutualTls, TlsConfig},
-    figment::providers::{Format, Toml},
+    fairing::AdHoc,
+    figment::providers::{Env, Format, Toml},
 };
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use rocket_db_pools::Database;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+/// The origins allowed by CORS when the `cors` table is missing from the configuration.
+const DEFAULT_ALLOWED_ORIGINS: [&str; 3] = [
+    "https://localhost:8000",
+    "https://localhost:8001",
+    "http://localhost:3000",
+];
+
 /// The entry point of the CA server.
 /// The server is a REST API that allows clients to register and verify their certificates.
 /// It requires TLS and can be configured with some endpoints protected with mutual TLS.
 /// See [`Certificate`](rocket::mtls::Certificate) for more information.
-#[rocket::launch]
-fn rocket() -> _ {
+/// The server is launched again after renewing its TLS certificate, see [`TlsReload`].
+#[rocket::main]
+async fn main() -> Result<(), rocket::Error> {
     env_logger::init();
+    loop {
+        let rocket = rocket().launch().await?;
+        if !rocket
+            .state::<TlsReload>()
+            .is_some_and(TlsReload::requested)
+        {
+            return Ok(());
+        }
+        log::info!("Launching the server again with the renewed TLS certificate");
+    }
+}
+
+/// Build the CA server from the configuration, loading the CA and the server certificates from the file system.
+fn rocket() -> rocket::Rocket<rocket::Build> {
+    let figment = rocket::Config::figment()
+        // Load the configuration file for the PKI server.
+        .merge(Toml::file("PKI_Rocket.toml").nested())
+        // Let the environment variables override the configuration file, e.g. `ROCKET_CORS`.
+        .merge(Env::prefixed("ROCKET_").global());
+    let ca_config = if figment.contains("ca") {
+        figment
+            .extract_inner::<CaConfig>("ca")
+            .expect("valid CA configuration")
+    } else {
+        CaConfig::default()
+    };
+    let paths = if figment.contains("paths") {
+        figment
+            .extract_inner::<PathsConfig>("paths")
+            .expect("valid paths configuration")
+    } else {
+        PathsConfig::default()
+    };
+    let ca_paths = paths.ca_paths();
+
     // Generate the CA certificate and key pair. Those are used to sign the certificates.
     // The server tries to store those certificates in the file system to be able to recover them
-    // if the server is restarted.
-    let ca_ck = init_ca();
-    let ca_cert_pem = ca_ck.cert.pem();
+    // if the server is restarted. With an intermediate CA, only the root CA certificate is needed.
+    // With an external signer, e.g. an HSM, the key never reaches the server and only the certificates are loaded.
+    let (chain, signer): (Vec<String>, SignerArc) = match &ca_config.signer {
+        Some(signer_config) => {
+            let chain = load_issuing_ca_chain(&ca_paths)
+                .expect("The certificate of the CA of the external signer!");
+            let signer =
+                CommandSigner::new(signer_config, &chain[0]).expect("A valid external signer!");
+            (chain, Arc::new(signer))
+        }
+        None => {
+            let ca = init_issuing_ca(&ca_paths, ca_config.intermediate);
+            let signer =
+                KeyPairSigner::new(&ca.certified_key.key_pair).expect("A supported CA key pair!");
+            (ca.chain, Arc::new(signer))
+        }
+    };
+    // Everything signed by the CA goes through the signer.
+    let certified_key = remote_key_pair(signer.clone())
+        .and_then(|key_pair| load_ca_with_key_pair(&chain[0], key_pair))
+        .expect("Error loading the CA certificate with the signer!");
+    let ca = IssuingCa {
+        certified_key,
+        chain,
+    };
+    // Trust the whole chain for the mutual TLS, the clients only present their own certificate.
+    let ca_chain_pem = ca.chain.concat();
+
+    let validity_config = if figment.contains("validity") {
+        figment
+            .extract_inner::<ValidityConfig>("validity")
+            .expect("valid validity configuration")
+    } else {
+        ValidityConfig::default()
+    };
 
     // Generate the server certificate and key pair. Those are used to setup the TLS connection.
     // The server certificate is signed by the CA certificate and can be lost if the server is restarted.
-    init_pki_server(&ca_ck);
+    init_pki_server(&ca, &validity_config, &paths);
 
     // Generate the DS (Delivery Service) server keys.
-    init_ds_server(&ca_ck);
+    init_ds_server(&ca, &validity_config, &paths);
+
+    // Notify the DS of the revocations, trusting the chain of the CA which signs its server certificate.
+    let ds_notifier: OptionalDsNotifier = if figment.contains("ds") {
+        let config = figment
+            .extract_inner::<DsConfig>("ds")
+            .expect("valid DS configuration");
+        Some(DsNotifier::from_config(&config, &ca.chain).expect("A valid DS configuration!"))
+    } else {
+        None
+    };
+
+    // Call the webhooks of the operators on the lifecycle events of the certificates.
+    let webhooks: OptionalWebhooks = if figment.contains("webhooks") {
+        let config = figment
+            .extract_inner::<WebhooksConfig>("webhooks")
+            .expect("valid webhooks configuration");
+        Some(Webhooks::from_config(&config, &ca.chain).expect("A valid webhooks configuration!"))
+    } else {
+        None
+    };
 
     // The CA server needs the CA certificate and key pair to sign the certificates and verify them.
-    let state = server::PkiState::new(ca_ck);
+    // Only the certificate requests for keys of the allowed algorithms are signed.
+    let key_algorithms = ca_config
+        .key_algorithms()
+        .expect("valid key algorithms in the CA configuration");
+    // The clients can only register certificates of the enabled profiles, e.g. not a server certificate.
+    let profiles = ca_config
+        .profiles()
+        .expect("valid profiles in the CA configuration");
+    let state = server::PkiState::new(ca.certified_key, signer, ca.chain, key_algorithms, profiles);
 
     // Create the state for the server to be used in the handlers. This holds the CA certificates as well
-    // as the storage for the certificates that are issued by the CA.
-    let shared_state = Arc::new(Mutex::new(state));
+    // as the storage for the certificates that are issued by the CA. The state is read-only, so that the
+    // verifications run concurrently with the signatures.
+    let shared_state = Arc::new(state);
 
     // Set the server TLS configuration to use the certificate signed by our CA for the server.
     // In production, we should request a certificate by let'sencrypt and use our CA only for the clients.
     // Also set our CA certificate as the CA for the mutual TLS.
-    let (pki_server_cert_path, pki_server_keys_path) = get_pki_server_credential_paths();
-    let tls_config = TlsConfig::from_paths(pki_server_cert_path, pki_server_keys_path)
-        .with_mutual(MutualTls::from_bytes(ca_cert_pem.as_bytes()));
-    let figment = rocket::Config::figment()
-        // Load the configuration file for the PKI server.
-        .merge(Toml::file("PKI_Rocket.toml").nested())
-        .merge((rocket::Config::TLS, tls_config));
+    let tls_config = TlsConfig::from_paths(&paths.server_cert, &paths.server_key)
+        .with_mutual(MutualTls::from_bytes(ca_chain_pem.as_bytes()));
+    let figment = figment.merge((rocket::Config::TLS, tls_config));
 
-    // TODO: configure through env variables.
-    let other_servers = vec![
-        "https://localhost:8000",
-        "https://localhost:8001",
-        "http://localhost:3000",
-    ];
-    let cors = CorsOptions::default()
-        .allowed_origins(AllowedOrigins::some_exact(&other_servers))
+    let cors_options = if figment.contains("cors") {
+        figment
+            .extract_inner::<CorsOptions>("cors")
+            .expect("valid CORS configuration")
+    } else {
+        CorsOptions::default().allowed_origins(AllowedOrigins::some_exact(&DEFAULT_ALLOWED_ORIGINS))
+    };
+    let acme_config = if figment.contains("acme") {
+        figment
+            .extract_inner::<AcmeConfig>("acme")
+            .expect("valid ACME configuration")
+    } else {
+        AcmeConfig::default()
+    };
+    let rate_limit_config = if figment.contains("rate_limit") {
+        figment
+            .extract_inner::<RateLimitConfig>("rate_limit")
+            .expect("valid rate limit configuration")
+    } else {
+        RateLimitConfig::default()
+    };
+    let renewal_config = if figment.contains("renewal") {
+        figment
+            .extract_inner::<RenewalConfig>("renewal")
+            .expect("valid renewal configuration")
+    } else {
+        RenewalConfig::default()
+    };
+    let policy_config = if figment.contains("policy") {
+        figment
+            .extract_inner::<PolicyConfig>("policy")
+            .expect("valid issuance policy configuration")
+    } else {
+        PolicyConfig::default()
+    };
+    let admins = figment
+        .extract_inner::<HashSet<String>>("admins")
+        .unwrap_or_default();
+    let cors = cors_options
         .to_cors()
         .expect("The CORS configuration is invalid.");
 
+    let acme_enabled = acme_config.enabled;
     // Initialise the rocket server also mounting the swagger-ui.
-    rocket::custom(figment)
+    let rocket = rocket::custom(figment)
         .attach(cors)
         .attach(db::DbConn::init())
+        .attach(retry_after_header())
+        .attach(AuditLogger)
         .manage(shared_state)
+        .manage(server::PkiAdmins(admins))
+        .manage(validity_config)
+        .manage(acme_config)
+        .manage(ds_notifier)
+        .manage(webhooks)
+        .manage(AcmeStateArc::default())
+        .manage(RegistrationChallengesArc::default())
+        .manage(RateLimiter::new(rate_limit_config))
+        .manage(IssuancePolicy::new(policy_config))
+        .manage(TlsReload::new())
+        .attach(renewal::fairing(renewal_config.clone(), paths.clone()))
+        .manage(renewal_config)
+        .manage(paths)
+        .attach(AdHoc::on_liftoff("Readiness check", |rocket| {
+            Box::pin(async move {
+                let Some(db) = db::DbConn::fetch(rocket) else {
+                    log::error!("The database pool is not initialised");
+                    return;
+                };
+                let readiness = server::check_readiness(db, rocket.config()).await;
+                if readiness.is_ready() {
+                    log::info!("The server is ready to serve requests");
+                } else {
+                    log::error!(
+                        "The server is not ready to serve requests: `{:?}`",
+                        readiness
+                    );
+                }
+            })
+        }))
         .mount(
             "/",
             SwaggerUi::new("/swagger-ui/<_..>")
@@ -87,10 +270,53 @@ fn rocket() -> _ {
             "/",
             rocket::routes![
                 server::openapi,
+                server::healthz,
+                server::readyz,
+                server::metrics,
                 server::get_ca_credential,
+                server::get_chain,
+                server::get_root_ca_der,
+                server::get_chain_pkcs7,
+                server::get_root_ca_json,
+                server::get_pins,
+                server::get_certificate_by_serial,
                 server::get_credential,
+                server::get_credential_by_fingerprint,
+                server::delete_credential,
+                server::register_challenge,
                 server::register,
+                server::renew,
+                server::est_cacerts,
+                server::est_simpleenroll,
                 server::verify,
+                server::verify_batch,
+                server::revoke,
+                server::get_crl,
+                server::ocsp,
+                server::get_signed_tree_head,
+                server::get_inclusion_proof,
+                server::get_consistency_proof,
+                server::get_log_entries,
+                server::get_audit_log,
             ],
-        )
+        );
+    if !acme_enabled {
+        return rocket;
+    }
+    rocket.mount(
+        "/",
+        rocket::routes![
+            server::acme_directory,
+            server::acme_new_nonce,
+            server::acme_head_new_nonce,
+            server::acme_new_account,
+            server::acme_account,
+            server::acme_new_order,
+            server::acme_order,
+            server::acme_authorization,
+            server::acme_challenge,
+            server::acme_finalize_order,
+            server::acme_certificate,
+        ],
+    )
 }
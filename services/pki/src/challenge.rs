@@ -0,0 +1,342 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The proof of possession of the key of the certificate requests sent to `/ca/register`.
+//! The client first obtains a random challenge for its email with `/ca/register/challenge`, then signs it with the
+//! private key of the certificate request, see [`common::crypto::sign_challenge`]. A challenge can be used for a
+//! single registration, so that a certificate request replayed by someone without its private key is rejected.
+//! The EST enrollment uses the same challenges, as the `challengePassword` attribute of the certificate request.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::crypto::{
+    retrieve_challenge_password_from_certificate_request, verify_challenge_signature,
+};
+use rocket::{http::Status, response::status::Custom};
+
+use crate::acme::random_id;
+
+/// How long a challenge can be used after being issued, in seconds.
+pub const CHALLENGE_LIFETIME: u64 = 5 * 60;
+/// The maximum number of unused challenges, no challenge is issued once it is reached until the oldest expire.
+const MAX_CHALLENGES: usize = 10_000;
+/// The maximum number of unused challenges of an email, the oldest of the email are forgotten first.
+const MAX_CHALLENGES_PER_EMAIL: usize = 5;
+
+/// A challenge issued for the registration of an email.
+struct Challenge {
+    email: String,
+    /// When the challenge expires, in seconds since the Unix epoch.
+    expires_at: u64,
+}
+
+/// The unused challenges of the registrations.
+#[derive(Default)]
+pub struct RegistrationChallenges {
+    challenges: HashMap<String, Challenge>,
+    /// The challenges in order of creation, and therefore of expiration, to forget the oldest ones.
+    queue: VecDeque<String>,
+    /// The number of unused challenges of each email.
+    pending: HashMap<String, usize>,
+}
+
+/// The type of the registration challenges wrapped in an Arc and a Mutex.
+pub type RegistrationChallengesArc = Arc<Mutex<RegistrationChallenges>>;
+
+impl RegistrationChallenges {
+    /// Create a new challenge for the registration of the email, forgetting the oldest challenge of the email
+    /// if it has too many unused ones, so that the challenges of the other emails are never forgotten.
+    /// Returns the challenge and when it expires, in seconds since the Unix epoch,
+    /// or `None` if there are too many unused challenges.
+    pub fn issue(&mut self, email: &str, now: u64) -> Option<(String, u64)> {
+        self.prune(now);
+        let email = email.to_lowercase();
+        let pending = self.pending.get(&email).copied().unwrap_or_default();
+        if pending >= MAX_CHALLENGES_PER_EMAIL {
+            let oldest = self.queue.iter().position(|queued| {
+                self.challenges
+                    .get(queued)
+                    .is_some_and(|issued| issued.email == email)
+            });
+            if let Some(oldest) = oldest.and_then(|oldest| self.queue.remove(oldest)) {
+                self.forget(&oldest);
+            }
+        } else if self.queue.len() >= MAX_CHALLENGES {
+            return None;
+        }
+        let challenge = random_id();
+        let expires_at = now + CHALLENGE_LIFETIME;
+        *self.pending.entry(email.clone()).or_default() += 1;
+        self.challenges
+            .insert(challenge.clone(), Challenge { email, expires_at });
+        self.queue.push_back(challenge.clone());
+        Some((challenge, expires_at))
+    }
+
+    /// Forget the expired challenges, which are at the front of the queue.
+    fn prune(&mut self, now: u64) {
+        while let Some(oldest) = self.queue.front() {
+            if self
+                .challenges
+                .get(oldest)
+                .is_some_and(|issued| now < issued.expires_at)
+            {
+                break;
+            }
+            if let Some(expired) = self.queue.pop_front() {
+                self.forget(&expired);
+            }
+        }
+    }
+
+    /// Forget the challenge, returning it if it was not used yet. The queue is left unchanged.
+    fn forget(&mut self, challenge: &str) -> Option<Challenge> {
+        let issued = self.challenges.remove(challenge)?;
+        if let Some(pending) = self.pending.get_mut(&issued.email) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending.remove(&issued.email);
+            }
+        }
+        Some(issued)
+    }
+
+    /// Use the challenge, whether it was issued for the email and it is not expired.
+    /// The challenge can't be used again, even if it doesn't match.
+    pub fn redeem(&mut self, challenge: &str, email: &str, now: u64) -> bool {
+        let Some(issued) = self.forget(challenge) else {
+            return false;
+        };
+        self.queue.retain(|queued| queued != challenge);
+        issued.email == email.to_lowercase() && now < issued.expires_at
+    }
+}
+
+/// Check the proof of possession of a registration: the challenge was issued for the email and not used yet,
+/// and the base64 encoded signature of the challenge is verified by the key of the certificate request.
+pub fn check_proof_of_possession(
+    challenges: &RegistrationChallengesArc,
+    email: &str,
+    certificate_request: &str,
+    challenge: &str,
+    challenge_signature: &str,
+    now: u64,
+) -> Result<(), Custom<String>> {
+    let signature = STANDARD.decode(challenge_signature).map_err(|_| {
+        Custom(
+            Status::BadRequest,
+            "The challenge signature is not base64 encoded".to_string(),
+        )
+    })?;
+    if !challenges.lock().unwrap().redeem(challenge, email, now) {
+        return Err(Custom(
+            Status::Forbidden,
+            "The registration challenge is unknown, expired or already used".to_string(),
+        ));
+    }
+    match verify_challenge_signature(certificate_request, challenge, &signature) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log::info!("Rejected the proof of possession of the key of `{}`", email);
+            Err(Custom(
+                Status::Forbidden,
+                "The challenge is not signed by the key of the certificate request".to_string(),
+            ))
+        }
+        Err(e) => Err(Custom(
+            Status::BadRequest,
+            format!("Invalid certificate request: {}", e),
+        )),
+    }
+}
+
+/// Check the proof of possession of an EST enrollment: the certificate request, whose signature is verified with
+/// its key, has as `challengePassword` attribute a challenge issued for the email and not used yet.
+pub fn check_challenge_password(
+    challenges: &RegistrationChallengesArc,
+    email: &str,
+    certificate_request: &str,
+    now: u64,
+) -> Result<(), Custom<String>> {
+    let challenge = match retrieve_challenge_password_from_certificate_request(certificate_request)
+    {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => {
+            return Err(Custom(
+                Status::Forbidden,
+                "The certificate request has no registration challenge".to_string(),
+            ))
+        }
+        Err(e) => {
+            return Err(Custom(
+                Status::BadRequest,
+                format!("Invalid certificate request: {}", e),
+            ))
+        }
+    };
+    if !challenges.lock().unwrap().redeem(&challenge, email, now) {
+        log::info!(
+            "Rejected the challenge password of the EST enrollment of `{}`",
+            email
+        );
+        return Err(Custom(
+            Status::Forbidden,
+            "The registration challenge is unknown, expired or already used".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{
+        mk_client_certificate_request_params, mk_client_certificate_request_with_challenge,
+        sign_challenge, KeyAlgorithm,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_redeem_challenge() {
+        let mut challenges = RegistrationChallenges::default();
+        let (challenge, expires_at) = challenges.issue("Test@test.com", 1_000).unwrap();
+        assert_eq!(expires_at, 1_000 + CHALLENGE_LIFETIME);
+        // A challenge is bound to the email and can be used once.
+        assert!(challenges.redeem(&challenge, "test@test.com", 1_001));
+        assert!(!challenges.redeem(&challenge, "test@test.com", 1_002));
+        let (challenge, _) = challenges.issue("test@test.com", 1_000).unwrap();
+        assert!(!challenges.redeem(&challenge, "other@test.com", 1_001));
+        assert!(!challenges.redeem(&challenge, "test@test.com", 1_001));
+        let (challenge, expires_at) = challenges.issue("test@test.com", 1_000).unwrap();
+        assert!(!challenges.redeem(&challenge, "test@test.com", expires_at));
+        assert!(challenges.challenges.is_empty() && challenges.queue.is_empty());
+        assert!(challenges.pending.is_empty());
+    }
+
+    #[test]
+    fn test_prune_and_cap_challenges() {
+        let mut challenges = RegistrationChallenges::default();
+        let (expired, _) = challenges.issue("other@test.com", 1_000).unwrap();
+        let (other, _) = challenges.issue("other@test.com", 1_100).unwrap();
+        // The expired challenges are forgotten.
+        let issued: Vec<String> = (0..MAX_CHALLENGES_PER_EMAIL)
+            .map(|_| {
+                challenges
+                    .issue("test@test.com", 1_000 + CHALLENGE_LIFETIME)
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        assert!(!challenges.challenges.contains_key(&expired));
+        // Only the oldest challenge of the email is forgotten once too many are unused.
+        challenges
+            .issue("test@test.com", 1_000 + CHALLENGE_LIFETIME)
+            .unwrap();
+        assert!(!challenges.challenges.contains_key(&issued[0]));
+        assert!(issued[1..]
+            .iter()
+            .all(|challenge| challenges.challenges.contains_key(challenge)));
+        assert!(challenges.redeem(&other, "other@test.com", 1_000 + CHALLENGE_LIFETIME));
+        // No challenge is issued once too many are unused, until they expire.
+        for i in 0..MAX_CHALLENGES {
+            challenges.issue(&format!("{}@test.com", i), 2_000).unwrap();
+        }
+        assert!(challenges.issue("new@test.com", 2_000).is_none());
+        assert!(challenges
+            .issue("new@test.com", 2_000 + CHALLENGE_LIFETIME)
+            .is_some());
+        assert_eq!(challenges.queue.len(), 1);
+        assert_eq!(challenges.challenges.len(), 1);
+        assert_eq!(challenges.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_check_challenge_password() {
+        let challenges = RegistrationChallengesArc::default();
+        let check = |challenge: &str| {
+            let (_, request) = mk_client_certificate_request_with_challenge(
+                "test@test.com",
+                KeyAlgorithm::default(),
+                challenge,
+            )
+            .unwrap();
+            check_challenge_password(&challenges, "test@test.com", &request.pem().unwrap(), 1_001)
+        };
+        let (challenge, _) = challenges
+            .lock()
+            .unwrap()
+            .issue("test@test.com", 1_000)
+            .unwrap();
+        assert!(check(&challenge).is_ok());
+        // The challenge can't be replayed, nor used for another email.
+        assert_eq!(check(&challenge).unwrap_err().0, Status::Forbidden);
+        let (challenge, _) = challenges
+            .lock()
+            .unwrap()
+            .issue("other@test.com", 1_000)
+            .unwrap();
+        assert_eq!(check(&challenge).unwrap_err().0, Status::Forbidden);
+        // A certificate request without challenge is rejected.
+        let (_, request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default()).unwrap();
+        assert_eq!(
+            check_challenge_password(&challenges, "test@test.com", &request.pem().unwrap(), 1_001)
+                .unwrap_err()
+                .0,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn test_check_proof_of_possession() {
+        let challenges = RegistrationChallengesArc::default();
+        let (key_pair, request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default()).unwrap();
+        let request = request.pem().unwrap();
+        let check = |challenge: &str, key_pair: &str| {
+            let signature = STANDARD.encode(sign_challenge(key_pair, challenge).unwrap());
+            check_proof_of_possession(
+                &challenges,
+                "test@test.com",
+                &request,
+                challenge,
+                &signature,
+                1_001,
+            )
+        };
+        let (challenge, _) = challenges
+            .lock()
+            .unwrap()
+            .issue("test@test.com", 1_000)
+            .unwrap();
+        assert!(check(&challenge, &key_pair.serialize_pem()).is_ok());
+        // The request is replayed with the challenge signed by another key.
+        let (challenge, _) = challenges
+            .lock()
+            .unwrap()
+            .issue("test@test.com", 1_000)
+            .unwrap();
+        let other = mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default())
+            .unwrap()
+            .0;
+        assert_eq!(
+            check(&challenge, &other.serialize_pem()).unwrap_err().0,
+            Status::Forbidden
+        );
+    }
+}
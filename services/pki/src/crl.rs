@@ -0,0 +1,229 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use rcgen::{
+    CertificateRevocationListParams, CertifiedKey, KeyIdMethod, KeyPair, RevokedCertParams,
+    SerialNumber,
+};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
+
+use crate::db::RevokedCertificateEntity;
+
+/// How long a CRL is valid, in seconds. The CRL is generated on each request, so the clients
+/// fetching it periodically always get a fresh one before it expires.
+pub const CRL_VALIDITY: u64 = 60 * 60;
+
+/// The reason of a revocation, as defined in [RFC 5280](https://www.rfc-editor.org/rfc/rfc5280#section-5.3.1).
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    #[default]
+    Unspecified,
+    KeyCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+}
+
+impl RevocationReason {
+    /// The code of the reason in the CRL, stored in the database.
+    pub fn code(self) -> u8 {
+        self.into_rcgen() as u8
+    }
+
+    /// Returns the reason with the given code, [`RevocationReason::Unspecified`] if unknown.
+    pub fn from_code(code: u8) -> Self {
+        [
+            RevocationReason::KeyCompromise,
+            RevocationReason::AffiliationChanged,
+            RevocationReason::Superseded,
+            RevocationReason::CessationOfOperation,
+        ]
+        .into_iter()
+        .find(|reason| reason.code() == code)
+        .unwrap_or_default()
+    }
+
+    fn into_rcgen(self) -> rcgen::RevocationReason {
+        match self {
+            RevocationReason::Unspecified => rcgen::RevocationReason::Unspecified,
+            RevocationReason::KeyCompromise => rcgen::RevocationReason::KeyCompromise,
+            RevocationReason::AffiliationChanged => rcgen::RevocationReason::AffiliationChanged,
+            RevocationReason::Superseded => rcgen::RevocationReason::Superseded,
+            RevocationReason::CessationOfOperation => rcgen::RevocationReason::CessationOfOperation,
+        }
+    }
+}
+
+/// Returns the serial number of a PEM encoded certificate.
+pub fn serial_of_certificate(pem_certificate: &str) -> Result<Vec<u8>, String> {
+    let der = pem::parse(pem_certificate).map_err(|e| e.to_string())?;
+    serial_of_der_certificate(der.contents())
+}
+
+/// Returns the serial number of a DER encoded certificate.
+pub fn serial_of_der_certificate(der_certificate: &[u8]) -> Result<Vec<u8>, String> {
+    let (_, certificate) = X509Certificate::from_der(der_certificate).map_err(|e| e.to_string())?;
+    Ok(certificate.raw_serial().to_vec())
+}
+
+/// Returns the hex encoded SHA-256 fingerprint of the DER encoded certificate.
+pub fn fingerprint_of_certificate(der_certificate: &[u8]) -> String {
+    hex(digest(&SHA256, der_certificate).as_ref())
+}
+
+/// Returns the SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the DER encoded certificate, the pin
+/// of the key of the certificate which does not change when the certificate is re-issued for the same key.
+pub fn spki_sha256_of_certificate(der_certificate: &[u8]) -> Result<Vec<u8>, String> {
+    let (_, certificate) = X509Certificate::from_der(der_certificate).map_err(|e| e.to_string())?;
+    Ok(digest(&SHA256, certificate.public_key().raw)
+        .as_ref()
+        .to_vec())
+}
+
+/// Returns the SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the PEM encoded key pair,
+/// the pin of the certificates to be issued for the key, see [`spki_sha256_of_certificate`].
+pub fn spki_sha256_of_key_pair(key_pair_pem: &str) -> Result<Vec<u8>, String> {
+    let key_pair = KeyPair::from_pem(key_pair_pem).map_err(|e| e.to_string())?;
+    Ok(digest(&SHA256, &key_pair.public_key_der())
+        .as_ref()
+        .to_vec())
+}
+
+/// Returns the lowercase hex encoding of the bytes, used for the serial numbers and the fingerprints in the database.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Creates the DER encoded CRL of the revoked certificates, signed by the CA.
+/// The CRL number is the time of the generation, so that it increases with each CRL.
+pub fn mk_crl(
+    ca_ck: &CertifiedKey,
+    revoked: &[RevokedCertificateEntity],
+    now: u64,
+) -> Result<Vec<u8>, String> {
+    let mut revoked_certs = Vec::with_capacity(revoked.len());
+    for certificate in revoked {
+        revoked_certs.push(RevokedCertParams {
+            serial_number: SerialNumber::from_slice(&serial_of_certificate(
+                &certificate.certificate,
+            )?),
+            revocation_time: date_time(certificate.revoked_at)?,
+            reason_code: Some(
+                RevocationReason::from_code(certificate.revocation_reason).into_rcgen(),
+            ),
+            invalidity_date: None,
+        });
+    }
+    let crl_number = now.to_be_bytes();
+    let first_digit = crl_number.iter().position(|byte| *byte != 0).unwrap_or(7);
+    let params = CertificateRevocationListParams {
+        this_update: date_time(now)?,
+        next_update: date_time(now + CRL_VALIDITY)?,
+        crl_number: SerialNumber::from_slice(&crl_number[first_digit..]),
+        issuing_distribution_point: None,
+        revoked_certs,
+        key_identifier_method: KeyIdMethod::Sha256,
+    };
+    let crl = params
+        .signed_by(&ca_ck.cert, &ca_ck.key_pair)
+        .map_err(|e| e.to_string())?;
+    Ok(crl.der().to_vec())
+}
+
+/// Converts seconds since the Unix epoch to a date.
+pub(crate) fn date_time(seconds: u64) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::from_unix_timestamp(seconds as i64).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{mk_client_certificate, mk_issuer_ca};
+    use x509_parser::revocation_list::CertificateRevocationList;
+
+    use super::*;
+
+    #[test]
+    fn test_mk_crl() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let client = mk_client_certificate(&ca_ck).unwrap();
+        let revoked = RevokedCertificateEntity {
+            certificate: client.cert.pem(),
+            revoked_at: 1_700_000_000,
+            revocation_reason: RevocationReason::KeyCompromise.code(),
+        };
+        let der = mk_crl(&ca_ck, &[revoked], 1_700_000_100).unwrap();
+        let (_, crl) = CertificateRevocationList::from_der(&der).unwrap();
+        let (_, ca) = X509Certificate::from_der(ca_ck.cert.der()).unwrap();
+        assert!(crl.verify_signature(ca.public_key()).is_ok());
+        let serials: Vec<&[u8]> = crl
+            .iter_revoked_certificates()
+            .map(|r| r.raw_serial())
+            .collect();
+        assert_eq!(
+            serials,
+            vec![serial_of_certificate(&client.cert.pem()).unwrap()]
+        );
+        assert_eq!(
+            crl.next_update().unwrap().timestamp(),
+            (1_700_000_100 + CRL_VALIDITY) as i64
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_of_certificate() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let fingerprint = fingerprint_of_certificate(ca_ck.cert.der());
+        assert_eq!(fingerprint.len(), 64);
+        assert!(fingerprint.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(
+            fingerprint,
+            fingerprint_of_certificate(mk_issuer_ca().unwrap().cert.der())
+        );
+    }
+
+    #[test]
+    fn test_spki_sha256_of_certificate() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let pin = spki_sha256_of_certificate(ca_ck.cert.der()).unwrap();
+        assert_eq!(pin.len(), 32);
+        assert_eq!(
+            pin,
+            digest(&SHA256, &ca_ck.key_pair.public_key_der())
+                .as_ref()
+                .to_vec()
+        );
+        assert!(spki_sha256_of_certificate(b"not a certificate").is_err());
+        assert_eq!(
+            spki_sha256_of_key_pair(&ca_ck.key_pair.serialize_pem()).unwrap(),
+            pin
+        );
+        assert!(spki_sha256_of_key_pair("not a key pair").is_err());
+    }
+
+    #[test]
+    fn test_revocation_reason_code() {
+        for reason in [
+            RevocationReason::Unspecified,
+            RevocationReason::KeyCompromise,
+            RevocationReason::Superseded,
+        ] {
+            assert_eq!(reason, RevocationReason::from_code(reason.code()));
+        }
+    }
+}
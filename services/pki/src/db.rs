@@ -11,8 +11,11 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
+use common::crypto::CertificateProfile;
 use rocket_db_pools::{sqlx, Connection, Database};
 
+use crate::metrics::METRICS;
+
 /// The database connection pool.
 // https://api.rocket.rs/v0.5/rocket_db_pools/
 #[derive(Database)]
@@ -24,34 +27,432 @@ pub struct DbConn(pub sqlx::MySqlPool);
 pub struct CertificateEntity {
     pub id: u64,
     pub email: String,
+    /// The label of the device of the user holding the certificate.
+    pub device: String,
+    /// The hex encoded SHA-256 fingerprint of the DER encoded certificate.
+    pub fingerprint: String,
+    /// The hex encoded SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the certificate.
+    pub spki_sha256: String,
+    /// The hex encoded serial number of the certificate.
+    pub serial: String,
+    pub certificate: String,
+    /// The name of the [`CertificateProfile`] the certificate was issued with.
+    pub profile: String,
+    /// The start of the validity period of the certificate, in seconds since the Unix epoch.
+    pub not_before: u64,
+    /// The end of the validity period of the certificate, in seconds since the Unix epoch.
+    pub not_after: u64,
+    /// The [`CertificateStatus`] of the certificate.
+    pub status: String,
+    /// When the certificate was revoked, in seconds since the Unix epoch.
+    pub revoked_at: Option<u64>,
+    /// The RFC 5280 reason code of the revocation.
+    pub revocation_reason: Option<u8>,
+    /// The hex encoded serial number of the revoked certificate of the device this certificate was re-issued for.
+    pub replaces: Option<String>,
+}
+
+/// The status of a certificate in the `certificates` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateStatus {
+    /// The certificate was not revoked, it may be expired.
+    Active,
+    /// The certificate was revoked, see the `revoked_at` and `revocation_reason` columns.
+    Revoked,
+}
+
+impl CertificateStatus {
+    /// The name of the status in the `certificates` table.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CertificateStatus::Active => "active",
+            CertificateStatus::Revoked => "revoked",
+        }
+    }
+}
+
+/// A certificate signed by the CA, with the details extracted to be stored in the `certificates` table.
+pub struct IssuedCertificate {
+    /// The PEM encoded certificate.
+    pub certificate: String,
+    /// The hex encoded SHA-256 fingerprint of the DER encoded certificate.
+    pub fingerprint: String,
+    /// The hex encoded SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the certificate.
+    pub spki_sha256: String,
+    /// The hex encoded serial number of the certificate.
+    pub serial: String,
+    /// The profile the certificate was issued with.
+    pub profile: CertificateProfile,
+    /// The start of the validity period of the certificate, in seconds since the Unix epoch.
+    pub not_before: u64,
+    /// The end of the validity period of the certificate, in seconds since the Unix epoch.
+    pub not_after: u64,
+}
+
+/// A revoked certificate, to be listed in the CRL.
+#[derive(sqlx::FromRow)]
+pub struct RevokedCertificateEntity {
+    pub certificate: String,
+    pub revoked_at: u64,
+    pub revocation_reason: u8,
+}
+
+/// An entry of the transparency log, stored in the `transparency_log` table.
+#[derive(sqlx::FromRow)]
+pub struct LogEntryEntity {
+    pub leaf_index: u64,
+    pub email: String,
     pub certificate: String,
+    /// When the certificate was logged, in milliseconds since the Unix epoch.
+    pub logged_at: u64,
 }
 
+/// An operation recorded in the append-only `audit_log` table, see the [`audit`](crate::audit) module.
+#[derive(sqlx::FromRow)]
+pub struct AuditEventEntity {
+    /// The position of the event in the log, assigned by the database.
+    pub id: u64,
+    /// When the operation was answered, in milliseconds since the Unix epoch.
+    pub logged_at: u64,
+    pub operation: String,
+    /// The emails of the client certificate of the requester, comma separated, or the ACME account.
+    pub requester: Option<String>,
+    pub requester_ip: Option<String>,
+    /// The email of the certificate the operation is about.
+    pub email: Option<String>,
+    /// The hex encoded serial number of the certificate the operation is about.
+    pub serial: Option<String>,
+    /// The HTTP status of the response.
+    pub status: u16,
+    pub outcome: String,
+}
+
+/// How many times to retry appending an entry to the transparency log when another entry took its index.
+const LOG_APPEND_ATTEMPTS: usize = 3;
+
 pub type DbConnection = Connection<DbConn>;
 
-/// Get the certificate by the email from the database.
-pub async fn get_certificate_by_email(
+/// Count the failed queries in the metrics, the constraint violations are expected and handled by the callers.
+fn count_error(e: &sqlx::Error) {
+    match e {
+        sqlx::Error::Database(e) if e.is_unique_violation() => {}
+        sqlx::Error::RowNotFound => {}
+        _ => METRICS.db_errors.inc(),
+    }
+}
+
+/// Check that the database is reachable.
+pub async fn ping(db: &DbConn) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1")
+        .execute(&**db)
+        .await
+        .inspect_err(count_error)
+        .map(|_| ())
+}
+
+/// Get the certificates of all the devices of the email which are not revoked, the most recent first.
+pub async fn get_certificates_by_email(
     email: &str,
     mut db: Connection<DbConn>,
-) -> Result<CertificateEntity, sqlx::Error> {
-    sqlx::query_as::<_, CertificateEntity>("SELECT * FROM certificates WHERE email = ?")
-        .bind(&email)
-        .fetch_one(&mut **db)
+) -> Result<Vec<CertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CertificateEntity>(
+        "SELECT * FROM certificates WHERE email = ? AND status = ? ORDER BY id DESC",
+    )
+    .bind(email)
+    .bind(CertificateStatus::Active.as_str())
+    .fetch_all(&mut **db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Find the latest certificate of a device of the email in the database, if any.
+/// The certificate may be revoked, the device has the previous ones only if they were re-issued after a revocation.
+pub async fn find_certificate_by_device(
+    email: &str,
+    device: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Option<CertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CertificateEntity>(
+        "SELECT * FROM certificates WHERE email = ? AND device = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(email)
+    .bind(device)
+    .fetch_optional(&mut ***db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Find the certificate by its hex encoded serial number in the database, if any.
+pub async fn find_certificate_by_serial(
+    serial: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Option<CertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CertificateEntity>("SELECT * FROM certificates WHERE serial = ?")
+        .bind(serial)
+        .fetch_optional(&mut ***db)
         .await
+        .inspect_err(count_error)
 }
 
-/// Insert the certificate in the database.
-/// If the email is already present, return an error.
-/// The email field in the database has a unique constraint.
+/// Find the certificate by its fingerprint in the database, if any.
+pub async fn find_certificate_by_fingerprint(
+    fingerprint: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Option<CertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CertificateEntity>("SELECT * FROM certificates WHERE fingerprint = ?")
+        .bind(fingerprint)
+        .fetch_optional(&mut ***db)
+        .await
+        .inspect_err(count_error)
+}
+
+/// Get the certificate of the public key with the hex encoded SHA-256 digest of its SubjectPublicKeyInfo.
+/// The active certificate is preferred if the key was certified more than once, then the most recent one.
+pub async fn find_certificate_by_spki_sha256(
+    spki_sha256: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Option<CertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, CertificateEntity>(
+        "SELECT * FROM certificates WHERE spki_sha256 = ? ORDER BY status = ? DESC, id DESC LIMIT 1",
+    )
+    .bind(spki_sha256)
+    .bind(CertificateStatus::Active.as_str())
+    .fetch_optional(&mut ***db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Insert the certificate of a device in the database, re-issued for the revoked certificate with the `replaces`
+/// serial number, if any.
+/// If the device of the email has already an active certificate, return an error.
+/// The email and the device of the active certificates in the database have a unique constraint.
 pub async fn insert_certificate(
     email: &str,
-    certificate: &str,
+    device: &str,
+    issued: &IssuedCertificate,
+    replaces: Option<&str>,
+    db: &mut Connection<DbConn>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO certificates (email, device, certificate, fingerprint, spki_sha256, serial, profile, not_before, not_after, status, replaces) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(email)
+    .bind(device)
+    .bind(&issued.certificate)
+    .bind(&issued.fingerprint)
+    .bind(&issued.spki_sha256)
+    .bind(&issued.serial)
+    .bind(issued.profile.name())
+    .bind(issued.not_before)
+    .bind(issued.not_after)
+    .bind(CertificateStatus::Active.as_str())
+    .bind(replaces)
+    .execute(&mut ***db)
+    .await
+    .inspect_err(count_error)
+    .map(|_| ())
+}
+
+/// Replace the certificate with the given id, if it is still the valid one with the given fingerprint.
+/// Returns false if the certificate was replaced or revoked in the meantime.
+pub async fn renew_certificate(
+    id: u64,
+    current_fingerprint: &str,
+    issued: &IssuedCertificate,
+    db: &mut Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query(
+        "UPDATE certificates SET certificate = ?, fingerprint = ?, spki_sha256 = ?, serial = ?, not_before = ?, not_after = ? \
+        WHERE id = ? AND fingerprint = ? AND status = ?",
+    )
+    .bind(&issued.certificate)
+    .bind(&issued.fingerprint)
+    .bind(&issued.spki_sha256)
+    .bind(&issued.serial)
+    .bind(issued.not_before)
+    .bind(issued.not_after)
+    .bind(id)
+    .bind(current_fingerprint)
+    .bind(CertificateStatus::Active.as_str())
+    .execute(&mut ***db)
+    .await
+    .inspect_err(count_error)
+    .map(|result| result.rows_affected() > 0)
+}
+
+/// Mark the certificate with the given id as revoked.
+/// Returns false if the certificate is already revoked.
+pub async fn revoke_certificate(
+    id: u64,
+    revoked_at: u64,
+    revocation_reason: u8,
+    db: &mut Connection<DbConn>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query(
+        "UPDATE certificates SET status = ?, revoked_at = ?, revocation_reason = ? WHERE id = ? AND status = ?",
+    )
+    .bind(CertificateStatus::Revoked.as_str())
+    .bind(revoked_at)
+    .bind(revocation_reason)
+    .bind(id)
+    .bind(CertificateStatus::Active.as_str())
+    .execute(&mut ***db)
+    .await
+    .inspect_err(count_error)
+    .map(|result| result.rows_affected() > 0)
+}
+
+/// List the end of the validity period of the certificates which are neither revoked nor expired at `now`,
+/// in seconds since the Unix epoch.
+pub async fn list_active_expirations(
+    now: u64,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<u64>, sqlx::Error> {
+    sqlx::query_scalar::<_, u64>(
+        "SELECT not_after FROM certificates WHERE status = ? AND not_after > ?",
+    )
+    .bind(CertificateStatus::Active.as_str())
+    .bind(now)
+    .fetch_all(&mut **db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// List all the revoked certificates.
+pub async fn list_revoked_certificates(
     mut db: Connection<DbConn>,
+) -> Result<Vec<RevokedCertificateEntity>, sqlx::Error> {
+    sqlx::query_as::<_, RevokedCertificateEntity>(
+        "SELECT certificate, revoked_at, revocation_reason FROM certificates WHERE status = ?",
+    )
+    .bind(CertificateStatus::Revoked.as_str())
+    .fetch_all(&mut **db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Append an entry to the transparency log, at the index following the last entry.
+pub async fn append_log_entry(
+    email: &str,
+    certificate: &str,
+    fingerprint: &str,
+    logged_at: u64,
+    leaf_hash: &[u8],
+    db: &mut Connection<DbConn>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO certificates (email, certificate) VALUES (?, ?)")
-        .bind(&email)
-        .bind(&certificate)
-        .execute(&mut **db)
+    let mut attempt = 1;
+    loop {
+        let result = sqlx::query(
+            "INSERT INTO transparency_log (leaf_index, email, certificate, fingerprint, logged_at, leaf_hash) \
+            SELECT COALESCE(MAX(leaf_index) + 1, 0), ?, ?, ?, ?, ? FROM transparency_log",
+        )
+        .bind(email)
+        .bind(certificate)
+        .bind(fingerprint)
+        .bind(logged_at)
+        .bind(leaf_hash)
+        .execute(&mut ***db)
         .await
-        .map(|_| ())
+        .inspect_err(count_error);
+        match result {
+            // A concurrent append took the same index.
+            Err(sqlx::Error::Database(e))
+                if e.is_unique_violation() && attempt < LOG_APPEND_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+/// List the leaf hashes of the first `tree_size` entries of the transparency log, or of all the entries if missing.
+pub async fn list_log_leaf_hashes(
+    tree_size: Option<u64>,
+    db: &mut Connection<DbConn>,
+) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+    sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT leaf_hash FROM transparency_log WHERE leaf_index < ? ORDER BY leaf_index",
+    )
+    .bind(tree_size.unwrap_or(u64::MAX))
+    .fetch_all(&mut ***db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Find the index in the transparency log of the certificate with the given fingerprint.
+pub async fn find_log_index_by_fingerprint(
+    fingerprint: &str,
+    db: &mut Connection<DbConn>,
+) -> Result<Option<u64>, sqlx::Error> {
+    sqlx::query_scalar::<_, u64>("SELECT leaf_index FROM transparency_log WHERE fingerprint = ?")
+        .bind(fingerprint)
+        .fetch_optional(&mut ***db)
+        .await
+        .inspect_err(count_error)
+}
+
+/// List the entries of the transparency log with index in `start..end`.
+pub async fn list_log_entries(
+    start: u64,
+    end: u64,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<LogEntryEntity>, sqlx::Error> {
+    sqlx::query_as::<_, LogEntryEntity>(
+        "SELECT leaf_index, email, certificate, logged_at FROM transparency_log WHERE leaf_index >= ? AND leaf_index < ? ORDER BY leaf_index",
+    )
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut **db)
+    .await
+    .inspect_err(count_error)
+}
+
+/// Append the event to the audit log, its `id` is ignored.
+pub async fn insert_audit_event(event: &AuditEventEntity, db: &DbConn) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO audit_log (logged_at, operation, requester, requester_ip, email, serial, status, outcome) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(event.logged_at)
+    .bind(&event.operation)
+    .bind(&event.requester)
+    .bind(&event.requester_ip)
+    .bind(&event.email)
+    .bind(&event.serial)
+    .bind(event.status)
+    .bind(&event.outcome)
+    .execute(&**db)
+    .await
+    .inspect_err(count_error)
+    .map(|_| ())
+}
+
+/// List at most `limit` events of the audit log preceding the event `before`, if any, the most recent first.
+/// The events can be filtered by email, serial number and operation.
+pub async fn list_audit_events(
+    email: Option<&str>,
+    serial: Option<&str>,
+    operation: Option<&str>,
+    before: Option<u64>,
+    limit: u64,
+    mut db: Connection<DbConn>,
+) -> Result<Vec<AuditEventEntity>, sqlx::Error> {
+    sqlx::query_as::<_, AuditEventEntity>(
+        "SELECT id, logged_at, operation, requester, requester_ip, email, serial, status, outcome FROM audit_log \
+        WHERE (? IS NULL OR email = ?) AND (? IS NULL OR serial = ?) AND (? IS NULL OR operation = ?) AND id < ? \
+        ORDER BY id DESC LIMIT ?",
+    )
+    .bind(email)
+    .bind(email)
+    .bind(serial)
+    .bind(serial)
+    .bind(operation)
+    .bind(operation)
+    .bind(before.unwrap_or(u64::MAX))
+    .bind(limit)
+    .fetch_all(&mut **db)
+    .await
+    .inspect_err(count_error)
 }
@@ -0,0 +1,63 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+use std::time::Duration;
+
+use crate::DsConfig;
+
+/// Notifies the DS (Delivery Service) of the revocations, so that it loads the CRL right away
+/// instead of waiting for its periodic refresh, and rejects the sessions of the revoked certificates.
+pub struct DsNotifier {
+    client: reqwest::Client,
+    refresh_url: String,
+}
+
+/// The optional DS notifier, to be used as managed state in Rocket. The DS is not notified if missing.
+pub type OptionalDsNotifier = Option<DsNotifier>;
+
+impl DsNotifier {
+    /// Create the notifier, trusting the PEM encoded chain of the CA for the TLS connection with the DS.
+    pub fn from_config(config: &DsConfig, ca_chain: &[String]) -> Result<Self, String> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_millis(config.timeout));
+        for pem in ca_chain {
+            let certificate = reqwest::Certificate::from_pem(pem.as_bytes())
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Couldn't initialise the HTTP client: {}", e))?;
+        Ok(DsNotifier {
+            client,
+            refresh_url: config.refresh_url.clone(),
+        })
+    }
+
+    /// Notify the DS of a revocation in the background, the revocation is effective in the CRL anyway.
+    pub fn notify_revocation(&self) {
+        let request = self.client.post(&self.refresh_url);
+        tokio::spawn(async move {
+            match request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(_) => log::debug!("Notified the DS of the revocation"),
+                Err(e) => log::warn!(
+                    "Couldn't notify the DS of the revocation, it will load the CRL at its next refresh: `{}`",
+                    e
+                ),
+            }
+        });
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The encodings of the Enrollment over Secure Transport (EST) endpoints, see [RFC 7030](https://www.rfc-editor.org/rfc/rfc7030).
+//! The certificates are returned as a base64 encoded PKCS#7 `certs-only` message, the degenerate `SignedData`
+//! of [RFC 5652](https://www.rfc-editor.org/rfc/rfc5652#section-5) without signers, and the certificate requests
+//! are received base64 encoded.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rocket::{
+    http::{ContentType, Header},
+    response::{self, Responder},
+    Request, Response,
+};
+use yasna::{models::ObjectIdentifier, Tag};
+
+/// The path prefix of the EST endpoints.
+pub const EST_PATH: &str = "/.well-known/est";
+
+/// The device of the certificates enrolled through EST.
+pub const EST_DEVICE: &str = "est";
+
+/// The `id-signedData` content type.
+const ID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+/// The `id-data` content type, of the empty content of a `certs-only` message.
+const ID_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+
+/// Creates the DER encoded PKCS#7 `certs-only` message carrying the DER encoded certificates.
+pub fn mk_certs_only(certificates: &[Vec<u8>]) -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer
+                .next()
+                .write_oid(&ObjectIdentifier::from_slice(ID_SIGNED_DATA));
+            writer.next().write_tagged(Tag::context(0), |writer| {
+                writer.write_sequence(|writer| {
+                    // The version is 1, as there are neither attribute certificates nor signers.
+                    writer.next().write_u8(1);
+                    writer.next().write_set_of(|_| {});
+                    writer.next().write_sequence(|writer| {
+                        writer
+                            .next()
+                            .write_oid(&ObjectIdentifier::from_slice(ID_DATA))
+                    });
+                    writer
+                        .next()
+                        .write_tagged_implicit(Tag::context(0), |writer| {
+                            writer.write_set_of(|writer| {
+                                for certificate in certificates {
+                                    writer.next().write_der(certificate);
+                                }
+                            })
+                        });
+                    writer.next().write_set_of(|_| {});
+                })
+            });
+        })
+    })
+}
+
+/// Decodes the base64 body of an EST request, the line breaks are ignored.
+pub fn decode_body(body: &[u8]) -> Result<Vec<u8>, String> {
+    let body = body
+        .iter()
+        .copied()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    STANDARD.decode(body).map_err(|e| e.to_string())
+}
+
+/// Converts the DER encoded certificate request of an EST request to PEM.
+pub fn certificate_request_pem(der: Vec<u8>) -> String {
+    pem::encode(&pem::Pem::new("CERTIFICATE REQUEST", der))
+}
+
+/// A base64 encoded PKCS#7 `certs-only` response of the EST endpoints.
+pub struct CertsOnlyResponse(pub Vec<u8>);
+
+impl<'r> Responder<'r, 'static> for CertsOnlyResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = STANDARD.encode(self.0);
+        Response::build()
+            .header(
+                ContentType::new("application", "pkcs7-mime")
+                    .with_params(("smime-type", "certs-only")),
+            )
+            .header(Header::new("Content-Transfer-Encoding", "base64"))
+            .sized_body(body.len(), std::io::Cursor::new(body))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{
+        mk_client_certificate, mk_client_certificate_request_params, mk_issuer_ca, KeyAlgorithm,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_certs_only() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let client = mk_client_certificate(&ca_ck).unwrap();
+        let certificates = vec![client.cert.der().to_vec(), ca_ck.cert.der().to_vec()];
+
+        let message = mk_certs_only(&certificates);
+        let parsed = yasna::parse_der(&message, |reader| {
+            reader.read_sequence(|reader| {
+                let content_type = reader.next().read_oid()?;
+                assert_eq!(content_type.components().as_slice(), ID_SIGNED_DATA);
+                reader.next().read_tagged(Tag::context(0), |reader| {
+                    reader.read_sequence(|reader| {
+                        assert_eq!(reader.next().read_u8()?, 1);
+                        reader.next().read_der()?;
+                        reader.next().read_der()?;
+                        let certificates = reader
+                            .next()
+                            .read_tagged_implicit(Tag::context(0), |reader| {
+                                reader.collect_set_of(|reader| reader.read_der())
+                            })?;
+                        reader.next().read_der()?;
+                        Ok(certificates)
+                    })
+                })
+            })
+        })
+        .unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert!(certificates.iter().all(|cert| parsed.contains(cert)));
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let (_, request) =
+            mk_client_certificate_request_params("test@test.com", KeyAlgorithm::default()).unwrap();
+        let der = request.der().to_vec();
+        // The clients usually wrap the base64 body in lines of 64 characters.
+        let encoded = STANDARD
+            .encode(&der)
+            .as_bytes()
+            .chunks(64)
+            .map(|line| String::from_utf8(line.to_vec()).unwrap())
+            .collect::<Vec<_>>()
+            .join("\r\n");
+
+        let decoded = decode_body(encoded.as_bytes()).unwrap();
+        assert_eq!(decoded, der);
+        let pem = pem::parse(certificate_request_pem(decoded)).unwrap();
+        assert_eq!(pem.tag(), "CERTIFICATE REQUEST");
+        assert_eq!(pem.contents(), der);
+        assert!(decode_body(b"not base64!").is_err());
+    }
+}
@@ -14,44 +14,415 @@
 
 use std::path::{self};
 
-use common::crypto::mk_server_certificate;
-use common::pki::write_file;
-use rcgen::CertifiedKey;
+use common::crypto::{
+    mk_ee_key_pair, mk_service_certificate_for_key, CertificateProfile, KeyAlgorithm, Validity,
+};
+use common::pki::{write_file, CaPaths, IssuingCa};
+use rcgen::{CertifiedKey, KeyPair};
+use serde::Deserialize;
+use time::{Duration, OffsetDateTime};
 
+pub mod acme;
+pub mod audit;
+pub mod challenge;
+pub mod crl;
 pub mod db;
+pub mod ds_notifier;
+pub mod est;
+pub mod metrics;
+pub mod ocsp;
+pub mod policy;
+pub mod rate_limit;
+pub mod renewal;
 pub mod server;
+pub mod signer;
+pub mod transparency;
+pub mod webhooks;
 
-/// The path to the server certificate file. It will be created if it does not exist.
+/// The default path to the server certificate file. It will be created if it does not exist.
 const PKI_SERVER_CERT_FILE_PATH: &str = "private/server/server_cert.pem";
-/// The path to the server key file. It will be created if it does not exist.
+/// The default path to the server key file. It will be created if it does not exist.
 const PKI_SERVER_KEY_FILE_PATH: &str = "private/server/server_keys.pem";
 
-/// The path to the DS (Delivery Service) server certificate file. It will be created if it does not exist.
+/// The default path to the DS (Delivery Service) server certificate file. It will be created if it does not exist.
 const DS_CERT_FILE_PATH: &str = "private/ds/ds_cert.pem";
-/// The path to the DS (Delivery Service) server key file. It will be created if it does not exist.
+/// The default path to the DS (Delivery Service) server key file. It will be created if it does not exist.
 const DS_KEY_FILE_PATH: &str = "private/ds/ds_keys.pem";
 
+/// The default path to the next PKI server key file, used by the next renewal. It will be created if it does not exist.
+const PKI_SERVER_NEXT_KEY_FILE_PATH: &str = "private/server/server_next_keys.pem";
+/// The default path to the next DS server key file, used by the next renewal. It will be created if it does not exist.
+const DS_NEXT_KEY_FILE_PATH: &str = "private/ds/ds_next_keys.pem";
+/// The default path to the certificate of the next CA, provided by the operators ahead of a rotation of the CA.
+const NEXT_CA_CERT_FILE_PATH: &str = "private/ca/next_ca_cert.pem";
+
+/// The paths of the credential files, loaded from the `paths` table of the `PKI_Rocket.toml` file, so that the
+/// services can run in containers with mounted secrets. The files missing from the table are under `private`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PathsConfig {
+    /// The root CA certificate.
+    pub ca_cert: String,
+    /// The root CA key pair.
+    pub ca_key: String,
+    /// The intermediate CA certificate, see [`CaConfig::intermediate`].
+    pub intermediate_cert: String,
+    /// The intermediate CA key pair.
+    pub intermediate_key: String,
+    /// The chain of the issuing CA, written at every start.
+    pub ca_chain: String,
+    /// The PKI server certificate, followed by the intermediate CA if any.
+    pub server_cert: String,
+    /// The PKI server key pair.
+    pub server_key: String,
+    /// The DS (Delivery Service) server certificate, followed by the intermediate CA if any.
+    pub ds_cert: String,
+    /// The DS (Delivery Service) server key pair.
+    pub ds_key: String,
+    /// The key pair of the next renewal of the PKI server certificate, generated in advance to publish its pin.
+    pub server_next_key: String,
+    /// The key pair of the next renewal of the DS server certificate, generated in advance to publish its pin.
+    pub ds_next_key: String,
+    /// The certificate of the CA replacing the root CA, if a rotation of the CA is planned.
+    pub next_ca_cert: String,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        let ca_paths = CaPaths::default();
+        PathsConfig {
+            ca_cert: ca_paths.cert,
+            ca_key: ca_paths.key,
+            intermediate_cert: ca_paths.intermediate_cert,
+            intermediate_key: ca_paths.intermediate_key,
+            ca_chain: ca_paths.chain,
+            server_cert: PKI_SERVER_CERT_FILE_PATH.to_string(),
+            server_key: PKI_SERVER_KEY_FILE_PATH.to_string(),
+            ds_cert: DS_CERT_FILE_PATH.to_string(),
+            ds_key: DS_KEY_FILE_PATH.to_string(),
+            server_next_key: PKI_SERVER_NEXT_KEY_FILE_PATH.to_string(),
+            ds_next_key: DS_NEXT_KEY_FILE_PATH.to_string(),
+            next_ca_cert: NEXT_CA_CERT_FILE_PATH.to_string(),
+        }
+    }
+}
+
+impl PathsConfig {
+    /// The paths of the files of the CA.
+    pub fn ca_paths(&self) -> CaPaths {
+        CaPaths {
+            cert: self.ca_cert.clone(),
+            key: self.ca_key.clone(),
+            intermediate_cert: self.intermediate_cert.clone(),
+            intermediate_key: self.intermediate_key.clone(),
+            chain: self.ca_chain.clone(),
+        }
+    }
+}
+
+/// The configuration of the issuing CA, loaded from the `ca` table of the `PKI_Rocket.toml` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaConfig {
+    /// Whether to create an intermediate CA signing the certificates, if missing, so that the root CA can be kept offline.
+    /// See [`init_issuing_ca`](common::pki::init_issuing_ca).
+    #[serde(default)]
+    pub intermediate: bool,
+    /// The names of the key algorithms accepted in the certificate requests, see [`KeyAlgorithm`].
+    #[serde(default = "default_key_algorithms")]
+    pub key_algorithms: Vec<String>,
+    /// The names of the profiles the clients can select in the registration requests, see [`CertificateProfile`].
+    #[serde(default = "default_profiles")]
+    pub profiles: Vec<String>,
+    /// The external signer holding the key of the issuing CA, if any. The CA key files are then neither read nor
+    /// generated, only the certificates of the CA are loaded, see [`load_issuing_ca_chain`](common::pki::load_issuing_ca_chain).
+    #[serde(default)]
+    pub signer: Option<SignerConfig>,
+}
+
+fn default_key_algorithms() -> Vec<String> {
+    KeyAlgorithm::ALL
+        .iter()
+        .map(|algorithm| algorithm.name().to_string())
+        .collect()
+}
+
+fn default_profiles() -> Vec<String> {
+    vec![CertificateProfile::ClientAuth.name().to_string()]
+}
+
+impl Default for CaConfig {
+    fn default() -> Self {
+        CaConfig {
+            intermediate: false,
+            key_algorithms: default_key_algorithms(),
+            profiles: default_profiles(),
+            signer: None,
+        }
+    }
+}
+
+impl CaConfig {
+    /// The key algorithms accepted in the certificate requests.
+    pub fn key_algorithms(&self) -> Result<Vec<KeyAlgorithm>, String> {
+        self.key_algorithms
+            .iter()
+            .map(|name| name.parse::<KeyAlgorithm>())
+            .collect()
+    }
+
+    /// The profiles the clients can select in the registration requests.
+    pub fn profiles(&self) -> Result<Vec<CertificateProfile>, String> {
+        self.profiles
+            .iter()
+            .map(|name| name.parse::<CertificateProfile>())
+            .collect()
+    }
+}
+
+/// The program signing with the key of the issuing CA, loaded from the `ca.signer` table of the `PKI_Rocket.toml` file.
+/// See [`CommandSigner`](signer::CommandSigner).
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignerConfig {
+    /// The program reading the message from its standard input and writing the signature to its standard output.
+    pub program: String,
+    /// The arguments of the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The configuration of the ACME endpoints, loaded from the `acme` table of the `PKI_Rocket.toml` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AcmeConfig {
+    /// Whether the ACME endpoints are mounted. They are disabled by default, as the `email-reply-00`
+    /// challenge doesn't verify the ownership of the email yet.
+    #[serde(default)]
+    pub enabled: bool,
+    /// The URL the clients reach the server at, the ACME requests are signed for the URLs under it.
+    #[serde(default = "default_acme_base_url")]
+    pub base_url: String,
+}
+
+fn default_acme_base_url() -> String {
+    "https://localhost:8000".to_string()
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            enabled: false,
+            base_url: default_acme_base_url(),
+        }
+    }
+}
+
+impl AcmeConfig {
+    /// The absolute URL of the resource at the given path.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url.trim_end_matches('/'), path)
+    }
+}
+
+/// The rate limits of the issuance and credential lookup endpoints, loaded from the `rate_limit` table of the
+/// `PKI_Rocket.toml` file. See [`RateLimiter`](rate_limit::RateLimiter).
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// The maximum number of requests about an email in each period.
+    #[serde(default = "default_per_email")]
+    pub per_email: u32,
+    /// The maximum number of requests from an IP address in each period.
+    #[serde(default = "default_per_ip")]
+    pub per_ip: u32,
+    /// The length of the period in seconds.
+    #[serde(default = "default_period")]
+    pub period: u64,
+}
+
+fn default_per_email() -> u32 {
+    10
+}
+
+fn default_per_ip() -> u32 {
+    60
+}
+
+fn default_period() -> u64 {
+    60
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_email: default_per_email(),
+            per_ip: default_per_ip(),
+            period: default_period(),
+        }
+    }
+}
+
+/// The issuance policy, loaded from the `policy` table of the `PKI_Rocket.toml` file.
+/// See [`IssuancePolicy`](policy::IssuancePolicy). Any email can obtain a certificate if the table is missing.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// The domains of the emails which can obtain a certificate, `*.example.com` for all the subdomains.
+    /// Any domain is allowed if empty.
+    pub allowed_domains: Vec<String>,
+    /// The domains of the emails which cannot obtain a certificate, even if allowed by `allowed_domains`.
+    pub denied_domains: Vec<String>,
+    /// The emails which can obtain a certificate whatever their domain.
+    pub allowed_emails: Vec<String>,
+    /// The emails which cannot obtain a certificate.
+    pub denied_emails: Vec<String>,
+}
+
+/// The notifications to the DS (Delivery Service), loaded from the `ds` table of the `PKI_Rocket.toml` file.
+/// The DS is not notified if the table is missing.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DsConfig {
+    /// The URL of the DS endpoint loading the CRL again, e.g. `https://localhost:8001/revocations/refresh`.
+    pub refresh_url: String,
+    /// The timeout of the notifications, in milliseconds.
+    #[serde(default = "default_ds_timeout")]
+    pub timeout: u64,
+}
+
+fn default_ds_timeout() -> u64 {
+    5000
+}
+
+/// The renewal of the server certificates of the PKI and of the DS, loaded from the `renewal` table of the
+/// `PKI_Rocket.toml` file. See [`renewal::fairing`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenewalConfig {
+    /// How many days before their expiry the server certificates are renewed.
+    #[serde(default = "default_renew_before_days")]
+    pub before_days: u32,
+    /// How often to check the validity of the server certificates, in seconds.
+    #[serde(default = "default_renewal_interval")]
+    pub interval: u64,
+}
+
+fn default_renew_before_days() -> u32 {
+    30
+}
+
+fn default_renewal_interval() -> u64 {
+    60 * 60
+}
+
+impl Default for RenewalConfig {
+    fn default() -> Self {
+        RenewalConfig {
+            before_days: default_renew_before_days(),
+            interval: default_renewal_interval(),
+        }
+    }
+}
+
+/// The webhooks called on the issuance, renewal and revocation of the certificates, loaded from the `webhooks` table
+/// of the `PKI_Rocket.toml` file. See [`Webhooks`](webhooks::Webhooks). No webhook is called if the table is missing.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhooksConfig {
+    /// The URLs the events are posted to.
+    pub urls: Vec<String>,
+    /// The secret shared with the receivers, the key of the HMAC signature of the payload.
+    pub secret: String,
+    /// The timeout of the calls, in milliseconds.
+    #[serde(default = "default_webhooks_timeout")]
+    pub timeout: u64,
+}
+
+fn default_webhooks_timeout() -> u64 {
+    5000
+}
+
+/// The validity periods of the issued certificates, loaded from the `validity` table of the `PKI_Rocket.toml` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidityConfig {
+    /// How long the client certificates are valid, in days, see [`CertificateProfile::ClientAuth`].
+    #[serde(default = "default_validity_days")]
+    pub client_days: u32,
+    /// How long the server certificates are valid, in days, see [`CertificateProfile::ServerAuth`] and
+    /// [`CertificateProfile::DsService`].
+    #[serde(default = "default_validity_days")]
+    pub server_days: u32,
+}
+
+fn default_validity_days() -> u32 {
+    365
+}
+
+impl Default for ValidityConfig {
+    fn default() -> Self {
+        ValidityConfig {
+            client_days: default_validity_days(),
+            server_days: default_validity_days(),
+        }
+    }
+}
+
+impl ValidityConfig {
+    /// The validity window of a client certificate issued now.
+    pub fn client_validity(&self) -> Validity {
+        validity_from_now(self.client_days)
+    }
+
+    /// The validity window of a server certificate issued now.
+    pub fn server_validity(&self) -> Validity {
+        validity_from_now(self.server_days)
+    }
+
+    /// The validity window of a certificate of the profile issued now.
+    pub fn validity(&self, profile: CertificateProfile) -> Validity {
+        match profile {
+            CertificateProfile::ClientAuth => self.client_validity(),
+            CertificateProfile::ServerAuth | CertificateProfile::DsService => {
+                self.server_validity()
+            }
+        }
+    }
+}
+
+fn validity_from_now(days: u32) -> Validity {
+    let not_before = OffsetDateTime::now_utc();
+    Validity {
+        not_before,
+        not_after: not_before + Duration::days(days.into()),
+    }
+}
+
 /// Create and persist the PKI server certificate and key pair.
-/// The server certificate is signed by the CA certificate.
+/// The server certificate is signed by the issuing CA certificate, the file contains the intermediate CA as well, if any.
 /// If the files are present, this is a no-op.
-pub fn init_pki_server(ca_ck: &CertifiedKey) {
+pub fn init_pki_server(ca: &IssuingCa, validity: &ValidityConfig, paths: &PathsConfig) {
     init_server(
-        ca_ck,
-        PKI_SERVER_CERT_FILE_PATH,
-        PKI_SERVER_KEY_FILE_PATH,
+        ca,
+        validity,
+        CertificateProfile::ServerAuth,
+        &paths.server_cert,
+        &paths.server_key,
         "PKI",
     );
 }
 
-/// Create and persist the DS (Delivery Service) server certificate and key pair.
-/// The server certificate is signed by the CA certificate.
+/// Create and persist the DS (Delivery Service) server certificate and key pair, with the `ds-service` profile.
+/// The server certificate is signed by the issuing CA certificate, the file contains the intermediate CA as well, if any.
 /// If the files are present, this is a no-op.
-pub fn init_ds_server(ca_ck: &CertifiedKey) {
-    init_server(ca_ck, DS_CERT_FILE_PATH, DS_KEY_FILE_PATH, "DS");
+pub fn init_ds_server(ca: &IssuingCa, validity: &ValidityConfig, paths: &PathsConfig) {
+    init_server(
+        ca,
+        validity,
+        CertificateProfile::DsService,
+        &paths.ds_cert,
+        &paths.ds_key,
+        "DS",
+    );
 }
 
 fn init_server(
-    ca_ck: &CertifiedKey,
+    ca: &IssuingCa,
+    validity: &ValidityConfig,
+    profile: CertificateProfile,
     server_cert_file_path: &str,
     server_key_file_path: &str,
     server_name: &str,
@@ -67,10 +438,9 @@ fn init_server(
     } else {
         log::info!("Generating the server certificate for `{}`.", server_name);
     }
-    let server_ck = mk_server_certificate(&ca_ck)
+    let (server_cert_pem, server_key_pair_pem) =
+        mk_server_credentials(&ca.certified_key, &ca.chain, profile, &validity.validity(profile))
         .expect(&format!("Error generating the server `{}` certificate and key pair, cannot proceed without a valid certificate to be used for TLS!", server_name));
-    let server_cert_pem = server_ck.cert.pem();
-    let server_key_pair_pem = server_ck.key_pair.serialize_pem();
     log::debug!(
         "`{}` server certificate and key pair created and signed by local CA: `{}`,`{}`",
         server_name,
@@ -87,15 +457,31 @@ fn init_server(
     ));
 }
 
-/// Returns the paths to the PKI server certificate and key pair.
-pub fn get_pki_server_credential_paths() -> (String, String) {
-    (
-        PKI_SERVER_CERT_FILE_PATH.to_string(),
-        PKI_SERVER_KEY_FILE_PATH.to_string(),
-    )
+/// Create a server certificate of the profile and its key pair, PEM encoded.
+/// The server certificate is signed by the issuing CA and followed by the intermediate CA, if any,
+/// as the clients trusting the root CA need it to build the chain.
+pub fn mk_server_credentials(
+    ca_ck: &CertifiedKey,
+    chain: &[String],
+    profile: CertificateProfile,
+    validity: &Validity,
+) -> Result<(String, String), rcgen::Error> {
+    mk_server_credentials_for_key(ca_ck, chain, profile, validity, mk_ee_key_pair()?)
 }
 
-/// Returns the paths to the DS (Delivery Service) server certificate and key pair.
-pub fn get_ds_server_credential_paths() -> (String, String) {
-    (DS_CERT_FILE_PATH.to_string(), DS_KEY_FILE_PATH.to_string())
+/// Create a server certificate of the profile for the key pair, PEM encoded with the key pair,
+/// see [`mk_server_credentials`].
+pub fn mk_server_credentials_for_key(
+    ca_ck: &CertifiedKey,
+    chain: &[String],
+    profile: CertificateProfile,
+    validity: &Validity,
+    key_pair: KeyPair,
+) -> Result<(String, String), rcgen::Error> {
+    let server_ck = mk_service_certificate_for_key(ca_ck, profile, validity, key_pair)?;
+    let intermediates = &chain[..chain.len().saturating_sub(1)];
+    Ok((
+        server_ck.cert.pem() + &intermediates.concat(),
+        server_ck.key_pair.serialize_pem(),
+    ))
 }
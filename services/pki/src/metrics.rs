@@ -0,0 +1,241 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The metrics of the PKI, exposed in the Prometheus text format by `GET /metrics`.
+//! The counters are process wide, like in the Prometheus clients, so that the database layer can count its errors
+//! without threading the metrics through every call.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The upper bounds of the buckets of the verification latency, in seconds.
+const VERIFICATION_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// The upper bounds of the buckets of the time to expiry of the active certificates, in seconds:
+/// a day, a week, 30, 90 and 180 days, a year.
+const EXPIRY_BUCKETS: [f64; 6] = [
+    86_400.0,
+    604_800.0,
+    2_592_000.0,
+    7_776_000.0,
+    15_552_000.0,
+    31_536_000.0,
+];
+
+/// The metrics of the PKI since the server started.
+pub static METRICS: Metrics = Metrics::new();
+
+/// A monotonic counter.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A histogram of durations with the buckets of [`VERIFICATION_BUCKETS`].
+pub struct Histogram {
+    buckets: [AtomicU64; VERIFICATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; VERIFICATION_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        // Only the first matching bucket is counted, the buckets are accumulated when rendered.
+        if let Some(bucket) = VERIFICATION_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+        {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The counters and histograms of the PKI.
+pub struct Metrics {
+    /// The certificates issued for a new device, by registration or ACME.
+    pub certificates_issued: Counter,
+    /// The certificates replacing the current certificate of a device, by renewal or ACME.
+    pub certificates_renewed: Counter,
+    /// The certificates revoked by their holder or an admin.
+    pub certificates_revoked: Counter,
+    /// The failed database queries, except for the constraint violations.
+    pub db_errors: Counter,
+    /// How long the verifications of the certificates take.
+    pub verification_duration: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            certificates_issued: Counter::new(),
+            certificates_renewed: Counter::new(),
+            certificates_revoked: Counter::new(),
+            db_errors: Counter::new(),
+            verification_duration: Histogram::new(),
+        }
+    }
+
+    /// Render the metrics in the Prometheus text format, with the time to expiry in seconds of the active
+    /// certificates, which is computed at each scrape.
+    pub fn render(&self, times_to_expiry: &[u64]) -> String {
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "pki_certificates_issued_total",
+            "Certificates issued for a new device.",
+            &self.certificates_issued,
+        );
+        write_counter(
+            &mut out,
+            "pki_certificates_renewed_total",
+            "Certificates replacing the current certificate of a device.",
+            &self.certificates_renewed,
+        );
+        write_counter(
+            &mut out,
+            "pki_certificates_revoked_total",
+            "Certificates revoked by their holder or an admin.",
+            &self.certificates_revoked,
+        );
+        write_counter(
+            &mut out,
+            "pki_db_errors_total",
+            "Failed database queries.",
+            &self.db_errors,
+        );
+        let histogram = &self.verification_duration;
+        write_histogram(
+            &mut out,
+            "pki_verification_duration_seconds",
+            "Duration of the verifications of the certificates.",
+            &VERIFICATION_BUCKETS,
+            &histogram
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect::<Vec<_>>(),
+            histogram.count.load(Ordering::Relaxed),
+            histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+        let mut expiry_buckets = [0; EXPIRY_BUCKETS.len()];
+        for time_to_expiry in times_to_expiry {
+            if let Some(bucket) = EXPIRY_BUCKETS
+                .iter()
+                .position(|bound| *time_to_expiry as f64 <= *bound)
+            {
+                expiry_buckets[bucket] += 1;
+            }
+        }
+        write_histogram(
+            &mut out,
+            "pki_certificate_time_to_expiry_seconds",
+            "Time to expiry of the active certificates, neither revoked nor expired, at the time of the scrape.",
+            &EXPIRY_BUCKETS,
+            &expiry_buckets,
+            times_to_expiry.len() as u64,
+            times_to_expiry.iter().sum::<u64>() as f64,
+        );
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+/// Write a histogram, the `counts` of each bucket are accumulated as the buckets include the lower ones.
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    bounds: &[f64],
+    counts: &[u64],
+    count: u64,
+    sum: f64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (bound, bucket_count) in bounds.iter().zip(counts) {
+        cumulative += bucket_count;
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.certificates_issued.inc();
+        metrics.certificates_issued.inc();
+        metrics
+            .verification_duration
+            .observe(Duration::from_millis(3));
+        metrics
+            .verification_duration
+            .observe(Duration::from_secs(10));
+        let rendered = metrics.render(&[3_600, 1_000_000, 100_000_000]);
+        assert!(rendered.contains(
+            "# TYPE pki_certificates_issued_total counter\npki_certificates_issued_total 2\n"
+        ));
+        assert!(rendered.contains("pki_certificates_revoked_total 0\n"));
+        assert!(rendered.contains("pki_verification_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(rendered.contains("pki_verification_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("pki_verification_duration_seconds_bucket{le=\"2.5\"} 1\n"));
+        assert!(rendered.contains("pki_verification_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("pki_verification_duration_seconds_sum 10.003\n"));
+        assert!(
+            rendered.contains("pki_certificate_time_to_expiry_seconds_bucket{le=\"86400\"} 1\n")
+        );
+        assert!(
+            rendered.contains("pki_certificate_time_to_expiry_seconds_bucket{le=\"2592000\"} 2\n")
+        );
+        assert!(rendered.contains("pki_certificate_time_to_expiry_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("pki_certificate_time_to_expiry_seconds_count 3\n"));
+    }
+}
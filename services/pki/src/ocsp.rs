@@ -0,0 +1,378 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! A minimal OCSP responder, see [RFC 6960](https://www.rfc-editor.org/rfc/rfc6960).
+//! The responses are signed directly by the CA, so the relying parties only need the CA certificate to check them.
+
+use common::crypto::KeyAlgorithm;
+use rcgen::CertifiedKey;
+use ring::digest::{digest, Algorithm, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
+use yasna::{
+    models::{GeneralizedTime, ObjectIdentifier},
+    DERWriter, Tag,
+};
+
+use crate::{
+    crl::{date_time, CRL_VALIDITY},
+    signer::Signer,
+};
+
+/// How long an OCSP response can be cached by the relying parties, in seconds.
+/// It is the validity of the CRL, so that both report the same revocations.
+pub const OCSP_VALIDITY: u64 = CRL_VALIDITY;
+
+/// The `id-pkix-ocsp-basic` response type.
+const ID_PKIX_OCSP_BASIC: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+/// The hash algorithms accepted in the requests.
+const ID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+const ID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+/// The signature algorithms of the CA key.
+const ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const ECDSA_WITH_SHA384: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+const ID_ED25519: &[u64] = &[1, 3, 101, 112];
+
+/// The status of an OCSP response, the status of the certificates is only given if successful.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcspResponseStatus {
+    Successful = 0,
+    MalformedRequest = 1,
+    InternalError = 2,
+}
+
+/// The identifier of a certificate in an OCSP request.
+#[derive(Debug, Clone)]
+pub struct CertId {
+    /// The DER encoding, echoed in the response.
+    der: Vec<u8>,
+    hash_algorithm: ObjectIdentifier,
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    serial: Vec<u8>,
+}
+
+impl CertId {
+    /// The raw serial number of the certificate.
+    pub fn serial(&self) -> &[u8] {
+        &self.serial
+    }
+
+    /// Whether the certificate is identified as issued by the CA.
+    pub fn is_issued_by(&self, ca_ck: &CertifiedKey) -> bool {
+        let algorithm = match self.hash_algorithm.components().as_slice() {
+            ID_SHA1 => &SHA1_FOR_LEGACY_USE_ONLY,
+            ID_SHA256 => &SHA256,
+            _ => return false,
+        };
+        issuer_hashes(ca_ck, algorithm).is_ok_and(|(name_hash, key_hash)| {
+            name_hash == self.issuer_name_hash && key_hash == self.issuer_key_hash
+        })
+    }
+}
+
+/// The status of a certificate in an OCSP response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertStatus {
+    Good,
+    /// Revoked at the given time, in seconds since the Unix epoch, with the RFC 5280 reason code.
+    Revoked {
+        revoked_at: u64,
+        reason: u8,
+    },
+    /// The certificate is not issued by the CA.
+    Unknown,
+}
+
+/// Parses a DER encoded OCSP request, returning the certificates it asks the status of.
+/// The signature of the request, if any, is ignored.
+pub fn parse_ocsp_request(der: &[u8]) -> Result<Vec<CertId>, String> {
+    let cert_ids = yasna::parse_der(der, |reader| {
+        reader.read_sequence(|reader| {
+            let cert_ids = reader.next().read_sequence(|reader| {
+                // The version, only v1 exists, and the requestor name.
+                reader
+                    .read_optional(|reader| reader.read_tagged(Tag::context(0), |r| r.read_u8()))?;
+                reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(1), |r| r.read_der())
+                })?;
+                let cert_ids = reader.next().collect_sequence_of(|reader| {
+                    reader.read_sequence(|reader| {
+                        let cert_id = reader.next().read_der()?;
+                        reader.read_optional(|reader| {
+                            reader.read_tagged(Tag::context(0), |r| r.read_der())
+                        })?;
+                        Ok(cert_id)
+                    })
+                })?;
+                reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(2), |r| r.read_der())
+                })?;
+                Ok(cert_ids)
+            })?;
+            reader.read_optional(|reader| reader.read_tagged(Tag::context(0), |r| r.read_der()))?;
+            Ok(cert_ids)
+        })
+    })
+    .map_err(|e| format!("Invalid OCSP request: {}", e))?;
+    cert_ids
+        .into_iter()
+        .map(|der| parse_cert_id(&der))
+        .collect()
+}
+
+fn parse_cert_id(der: &[u8]) -> Result<CertId, String> {
+    yasna::parse_der(der, |reader| {
+        reader.read_sequence(|reader| {
+            let hash_algorithm = reader.next().read_sequence(|reader| {
+                let oid = reader.next().read_oid()?;
+                reader.read_optional(|reader| reader.read_null())?;
+                Ok(oid)
+            })?;
+            Ok(CertId {
+                der: der.to_vec(),
+                hash_algorithm,
+                issuer_name_hash: reader.next().read_bytes()?,
+                issuer_key_hash: reader.next().read_bytes()?,
+                serial: reader.next().read_bigint_bytes()?.0,
+            })
+        })
+    })
+    .map_err(|e| format!("Invalid certificate identifier: {}", e))
+}
+
+/// Creates a DER encoded OCSP response without the status of the certificates, for the failures.
+pub fn mk_ocsp_error(status: OcspResponseStatus) -> Vec<u8> {
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| writer.next().write_enum(status as i64))
+    })
+}
+
+/// Creates a successful DER encoded OCSP response with the status of the certificates, signed by the CA.
+pub fn mk_ocsp_response(
+    ca_ck: &CertifiedKey,
+    signer: &dyn Signer,
+    statuses: &[(CertId, CertStatus)],
+    now: u64,
+) -> Result<Vec<u8>, String> {
+    let (_, key_hash) = issuer_hashes(ca_ck, &SHA1_FOR_LEGACY_USE_ONLY)?;
+    let this_update = GeneralizedTime::from_datetime(date_time(now)?);
+    let next_update = GeneralizedTime::from_datetime(date_time(now + OCSP_VALIDITY)?);
+    let mut revocation_times = Vec::with_capacity(statuses.len());
+    for (_, status) in statuses {
+        revocation_times.push(match status {
+            CertStatus::Revoked { revoked_at, .. } => {
+                Some(GeneralizedTime::from_datetime(date_time(*revoked_at)?))
+            }
+            _ => None,
+        });
+    }
+    let response_data = yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            // The responder is identified by the hash of the CA key.
+            writer
+                .next()
+                .write_tagged(Tag::context(2), |writer| writer.write_bytes(&key_hash));
+            writer.next().write_generalized_time(&this_update);
+            writer.next().write_sequence_of(|writer| {
+                for ((cert_id, status), revocation_time) in statuses.iter().zip(&revocation_times) {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_der(&cert_id.der);
+                        write_cert_status(writer.next(), status, revocation_time.as_ref());
+                        writer.next().write_generalized_time(&this_update);
+                        writer.next().write_tagged(Tag::context(0), |writer| {
+                            writer.write_generalized_time(&next_update)
+                        });
+                    });
+                }
+            });
+        })
+    });
+    let (signature_algorithm, signature) = sign(signer, &response_data)?;
+    let basic_response = yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_der(&response_data);
+            writer.next().write_sequence(|writer| {
+                writer
+                    .next()
+                    .write_oid(&ObjectIdentifier::from_slice(signature_algorithm))
+            });
+            writer
+                .next()
+                .write_bitvec_bytes(&signature, signature.len() * 8);
+        })
+    });
+    Ok(yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer
+                .next()
+                .write_enum(OcspResponseStatus::Successful as i64);
+            writer.next().write_tagged(Tag::context(0), |writer| {
+                writer.write_sequence(|writer| {
+                    writer
+                        .next()
+                        .write_oid(&ObjectIdentifier::from_slice(ID_PKIX_OCSP_BASIC));
+                    writer.next().write_bytes(&basic_response);
+                })
+            });
+        })
+    }))
+}
+
+fn write_cert_status(
+    writer: DERWriter,
+    status: &CertStatus,
+    revocation_time: Option<&GeneralizedTime>,
+) {
+    match (status, revocation_time) {
+        (CertStatus::Revoked { reason, .. }, Some(revocation_time)) => writer
+            .write_tagged_implicit(Tag::context(1), |writer| {
+                writer.write_sequence(|writer| {
+                    writer.next().write_generalized_time(revocation_time);
+                    writer
+                        .next()
+                        .write_tagged(Tag::context(0), |writer| writer.write_enum(*reason as i64));
+                })
+            }),
+        (CertStatus::Good, _) => {
+            writer.write_tagged_implicit(Tag::context(0), |writer| writer.write_null())
+        }
+        _ => writer.write_tagged_implicit(Tag::context(2), |writer| writer.write_null()),
+    }
+}
+
+/// The hashes of the CA name and public key, identifying the CA in the requests.
+fn issuer_hashes(
+    ca_ck: &CertifiedKey,
+    algorithm: &'static Algorithm,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let (_, ca) = X509Certificate::from_der(ca_ck.cert.der()).map_err(|e| e.to_string())?;
+    Ok((
+        digest(algorithm, ca.subject().as_raw()).as_ref().to_vec(),
+        digest(algorithm, &ca.public_key().subject_public_key.data)
+            .as_ref()
+            .to_vec(),
+    ))
+}
+
+/// Signs the message with the CA key, returning the signature algorithm and the signature.
+pub(crate) fn sign(
+    signer: &dyn Signer,
+    message: &[u8],
+) -> Result<(&'static [u64], Vec<u8>), String> {
+    let algorithm = match signer.algorithm() {
+        KeyAlgorithm::EcdsaP256 => ECDSA_WITH_SHA256,
+        KeyAlgorithm::EcdsaP384 => ECDSA_WITH_SHA384,
+        KeyAlgorithm::Ed25519 => ID_ED25519,
+    };
+    Ok((algorithm, signer.sign(message)?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{mk_client_certificate, mk_issuer_ca};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    use super::*;
+    use crate::signer::KeyPairSigner;
+
+    /// Creates an OCSP request for the certificate, identifying the CA with SHA-1 like most clients.
+    fn mk_ocsp_request(ca_ck: &CertifiedKey, serial: &[u8]) -> Vec<u8> {
+        let (name_hash, key_hash) = issuer_hashes(ca_ck, &SHA1_FOR_LEGACY_USE_ONLY).unwrap();
+        yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_sequence_of(|writer| {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_sequence(|writer| {
+                                writer.next().write_sequence(|writer| {
+                                    writer
+                                        .next()
+                                        .write_oid(&ObjectIdentifier::from_slice(ID_SHA1));
+                                    writer.next().write_null();
+                                });
+                                writer.next().write_bytes(&name_hash);
+                                writer.next().write_bytes(&key_hash);
+                                writer.next().write_bigint_bytes(serial, true);
+                            })
+                        })
+                    })
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn test_ocsp_response() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let client = mk_client_certificate(&ca_ck).unwrap();
+        let (_, x509) = X509Certificate::from_der(client.cert.der()).unwrap();
+        let request = mk_ocsp_request(&ca_ck, x509.raw_serial());
+
+        let cert_ids = parse_ocsp_request(&request).unwrap();
+        assert_eq!(cert_ids.len(), 1);
+        assert_eq!(cert_ids[0].serial(), x509.raw_serial());
+        assert!(cert_ids[0].is_issued_by(&ca_ck));
+        assert!(!cert_ids[0].is_issued_by(&mk_issuer_ca().unwrap()));
+
+        let status = CertStatus::Revoked {
+            revoked_at: 1_700_000_000,
+            reason: 1,
+        };
+        let signer = KeyPairSigner::new(&ca_ck.key_pair).unwrap();
+        let response = mk_ocsp_response(
+            &ca_ck,
+            &signer,
+            &[(cert_ids[0].clone(), status)],
+            1_700_000_100,
+        )
+        .unwrap();
+        let (response_status, basic_response) = yasna::parse_der(&response, |reader| {
+            reader.read_sequence(|reader| {
+                let status = reader.next().read_enum()?;
+                let basic_response = reader.next().read_tagged(Tag::context(0), |reader| {
+                    reader.read_sequence(|reader| {
+                        reader.next().read_oid()?;
+                        reader.next().read_bytes()
+                    })
+                })?;
+                Ok((status, basic_response))
+            })
+        })
+        .unwrap();
+        assert_eq!(response_status, OcspResponseStatus::Successful as i64);
+        let (response_data, signature) = yasna::parse_der(&basic_response, |reader| {
+            reader.read_sequence(|reader| {
+                let response_data = reader.next().read_der()?;
+                reader.next().read_der()?;
+                let (signature, _) = reader.next().read_bitvec_bytes()?;
+                Ok((response_data, signature))
+            })
+        })
+        .unwrap();
+        let public_key =
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, ca_ck.key_pair.public_key_raw());
+        assert!(public_key.verify(&response_data, &signature).is_ok());
+        let der = cert_ids[0].der.clone();
+        assert!(response_data.windows(der.len()).any(|window| window == der));
+    }
+
+    #[test]
+    fn test_ocsp_malformed_request() {
+        assert!(parse_ocsp_request(b"not a request").is_err());
+        assert_eq!(
+            mk_ocsp_error(OcspResponseStatus::MalformedRequest),
+            vec![0x30, 0x03, 0x0a, 0x01, 0x01]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The issuance policy of the PKI, restricting the emails which can obtain a certificate, e.g. to the domains of a
+//! single organization. The policy is evaluated before signing, by every issuance endpoint.
+
+use rocket::{http::Status, response::status::Custom};
+
+use crate::PolicyConfig;
+
+/// Decides which emails can obtain a certificate, the emails and domains are compared case-insensitively.
+/// The explicitly denied emails are rejected first, then the explicitly allowed emails are accepted,
+/// then the emails of the denied domains are rejected, and the others are accepted if their domain is allowed,
+/// or if no domain is listed as allowed.
+pub struct IssuancePolicy {
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+    allowed_emails: Vec<String>,
+    denied_emails: Vec<String>,
+}
+
+impl IssuancePolicy {
+    pub fn new(config: PolicyConfig) -> Self {
+        let normalize =
+            |values: Vec<String>| values.iter().map(|value| value.to_lowercase()).collect();
+        IssuancePolicy {
+            allowed_domains: normalize(config.allowed_domains),
+            denied_domains: normalize(config.denied_domains),
+            allowed_emails: normalize(config.allowed_emails),
+            denied_emails: normalize(config.denied_emails),
+        }
+    }
+
+    /// Whether the email can obtain a certificate.
+    pub fn allows(&self, email: &str) -> bool {
+        let email = email.to_lowercase();
+        let Some((_, domain)) = email.rsplit_once('@') else {
+            return false;
+        };
+        if self.denied_emails.contains(&email) {
+            return false;
+        }
+        if self.allowed_emails.contains(&email) {
+            return true;
+        }
+        if self
+            .denied_domains
+            .iter()
+            .any(|pattern| matches_domain(pattern, domain))
+        {
+            return false;
+        }
+        self.allowed_domains.is_empty()
+            || self
+                .allowed_domains
+                .iter()
+                .any(|pattern| matches_domain(pattern, domain))
+    }
+
+    /// Check that the email can obtain a certificate, answering `403 Forbidden` otherwise.
+    pub fn check(&self, email: &str) -> Result<(), Custom<String>> {
+        if self.allows(email) {
+            Ok(())
+        } else {
+            log::info!("Rejected the issuance for `{}` by the policy", email);
+            Err(Custom(
+                Status::Forbidden,
+                format!(
+                    "The email `{}` is not allowed to obtain a certificate by the issuance policy",
+                    email
+                ),
+            ))
+        }
+    }
+}
+
+/// Whether the domain matches the pattern, either the domain itself or `*.domain` for all its subdomains.
+fn matches_domain(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => domain
+            .strip_suffix(parent)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern == domain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_open_policy() {
+        let policy = IssuancePolicy::new(PolicyConfig::default());
+        assert!(policy.allows("alice@gmail.com"));
+        assert!(!policy.allows("not an email"));
+    }
+
+    #[test]
+    fn test_organization_policy() {
+        let policy = IssuancePolicy::new(PolicyConfig {
+            allowed_domains: strings(&["example.com", "*.example.org"]),
+            denied_domains: strings(&["legacy.example.org"]),
+            allowed_emails: strings(&["Contractor@Gmail.com"]),
+            denied_emails: strings(&["mallory@example.com"]),
+        });
+        assert!(policy.allows("alice@example.com"));
+        assert!(policy.allows("bob@EXAMPLE.COM"));
+        assert!(policy.allows("carol@eu.example.org"));
+        assert!(policy.allows("contractor@gmail.com"));
+        // The wildcard only matches the subdomains.
+        assert!(!policy.allows("dave@example.org"));
+        assert!(!policy.allows("dave@badexample.org"));
+        assert!(!policy.allows("erin@legacy.example.org"));
+        assert!(!policy.allows("mallory@example.com"));
+        assert!(!policy.allows("frank@gmail.com"));
+        assert_eq!(
+            policy.check("frank@gmail.com").unwrap_err().0,
+            Status::Forbidden
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! Rate limits of the issuance and credential lookup endpoints, so that the PKI can't be used as an oracle to
+//! enumerate the registered emails nor be spammed with certificate requests to sign.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::status::Custom,
+    Request,
+};
+
+use crate::RateLimitConfig;
+
+/// The number of tracked clients above which the expired windows are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The requests counted in the current period for a client.
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// How long a rate limited client has to wait before retrying, sent in the `Retry-After` header.
+#[derive(Default)]
+struct RetryAfter(OnceLock<Duration>);
+
+/// Limits the rate of the requests per IP address and per email, using fixed windows.
+/// The IP addresses share a single budget across all the limited endpoints.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request of the client identified by `key`, returning how long to wait if it exceeds the `limit`.
+    fn hit(&self, key: String, limit: u32, now: Instant) -> Result<(), Duration> {
+        let period = Duration::from_secs(self.config.period);
+        let mut windows = self.windows.lock().expect("Rate limiter state corrupted!");
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, window| now.duration_since(window.started) < period);
+        }
+        let window = windows.entry(key).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= period {
+            window.started = now;
+            window.count = 0;
+        }
+        window.count = window.count.saturating_add(1);
+        if window.count > limit {
+            Err(period.saturating_sub(now.duration_since(window.started)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A request guard counting the request against the rate limits, see [`RateLimit::check`].
+pub struct RateLimit<'r> {
+    limiter: &'r RateLimiter,
+    client_ip: Option<IpAddr>,
+    retry_after: &'r RetryAfter,
+}
+
+impl RateLimit<'_> {
+    /// Count the request for the client IP address and for the email it is about.
+    /// Returns `429 Too Many Requests` if any of the limits is exceeded, with a `Retry-After` header.
+    pub fn check(&self, email: &str) -> Result<(), Custom<String>> {
+        let now = Instant::now();
+        let mut result = Ok(());
+        if let Some(ip) = self.client_ip {
+            result = result.and(self.limiter.hit(
+                format!("ip:{}", ip),
+                self.limiter.config.per_ip,
+                now,
+            ));
+        }
+        result = result.and(self.limiter.hit(
+            format!("email:{}", email.to_lowercase()),
+            self.limiter.config.per_email,
+            now,
+        ));
+        result.map_err(|retry_after| {
+            log::debug!("Rate limiting a request about `{}`", email);
+            let _ = self.retry_after.0.set(retry_after);
+            Custom(
+                Status::TooManyRequests,
+                "Too many requests, retry later.".to_string(),
+            )
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limiter = request
+            .rocket()
+            .state::<RateLimiter>()
+            .expect("The rate limiter is managed by the server");
+        Outcome::Success(RateLimit {
+            limiter,
+            client_ip: request.client_ip(),
+            retry_after: request.local_cache(RetryAfter::default),
+        })
+    }
+}
+
+/// A fairing adding the `Retry-After` header to the rate limited responses.
+pub fn retry_after_header() -> AdHoc {
+    AdHoc::on_response("Retry-After header", |request, response| {
+        Box::pin(async move {
+            if let Some(retry_after) = request.local_cache(RetryAfter::default).0.get() {
+                // Round up, so that the client doesn't retry too early.
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response.set_raw_header("Retry-After", seconds.to_string());
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_fixed_window() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_email: 2,
+            per_ip: 2,
+            period: 60,
+        });
+        let now = Instant::now();
+        assert!(limiter.hit("a".to_string(), 2, now).is_ok());
+        assert!(limiter.hit("a".to_string(), 2, now).is_ok());
+        let retry_after = limiter
+            .hit("a".to_string(), 2, now + Duration::from_secs(20))
+            .unwrap_err();
+        assert_eq!(Duration::from_secs(40), retry_after);
+        // Other clients are not affected.
+        assert!(limiter.hit("b".to_string(), 2, now).is_ok());
+        // A new window starts after the period.
+        assert!(limiter
+            .hit("a".to_string(), 2, now + Duration::from_secs(60))
+            .is_ok());
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The renewal of the server certificates of the PKI and of the DS (Delivery Service) before they expire.
+//! The key pair of the next renewal is generated in advance, so that its pin is published by `/ca/pins` before
+//! it is used, giving the pinning clients a safe rotation path.
+//! Rocket loads the TLS certificate once, when it is launched: the PKI is shut down gracefully after renewing
+//! its own certificate and launched again by the `main` function with the new one, see [`TlsReload`].
+//! The DS loads its renewed certificate the same way, watching the files written by the PKI.
+
+use std::{
+    fs, path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use common::crypto::{mk_ee_key_pair, CertificateProfile};
+use rcgen::KeyPair;
+use rocket::{fairing::AdHoc, tokio};
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
+
+use crate::{
+    mk_server_credentials_for_key,
+    server::{sign_blocking, ServerStateArc},
+    PathsConfig, RenewalConfig, ValidityConfig,
+};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Whether the server has been shut down to load a renewed TLS certificate, so that it must be launched again.
+#[derive(Clone, Default)]
+pub struct TlsReload(Arc<AtomicBool>);
+
+impl TlsReload {
+    pub fn new() -> Self {
+        TlsReload::default()
+    }
+
+    /// Whether a renewed TLS certificate is waiting to be loaded.
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A server certificate renewed by the PKI.
+struct Server<'a> {
+    name: &'static str,
+    profile: CertificateProfile,
+    cert_path: &'a str,
+    key_path: &'a str,
+    /// The key pair of the next renewal.
+    next_key_path: &'a str,
+}
+
+/// A fairing spawning the background task that checks the remaining validity of the server certificates of the
+/// PKI and of the DS, and issues them again ahead of their expiry, see [`RenewalConfig`].
+/// Once its own certificate is renewed, the PKI is shut down and a [`TlsReload`] is requested.
+pub fn fairing(config: RenewalConfig, paths: PathsConfig) -> AdHoc {
+    AdHoc::on_liftoff("Server certificate renewal", move |rocket| {
+        Box::pin(async move {
+            let (Some(state), Some(validity), Some(reload)) = (
+                rocket.state::<ServerStateArc>(),
+                rocket.state::<ValidityConfig>(),
+                rocket.state::<TlsReload>(),
+            ) else {
+                log::error!(
+                    "The server state is not initialised, the server certificates won't be renewed"
+                );
+                return;
+            };
+            let state = state.clone();
+            let validity = validity.clone();
+            let reload = reload.clone();
+            let mut shutdown = rocket.shutdown();
+            let trigger = shutdown.clone();
+            let renew_before = i64::from(config.before_days) * SECONDS_PER_DAY;
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            if renew_server_certificates(&state, &validity, &paths, renew_before).await {
+                                log::info!("Shutting down the server to load the renewed TLS certificate");
+                                reload.request();
+                                trigger.notify();
+                                break;
+                            }
+                        },
+                        _ = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Renew the server certificates expiring in less than `renew_before` seconds.
+/// Returns whether the certificate of the PKI itself was renewed.
+async fn renew_server_certificates(
+    state: &ServerStateArc,
+    validity: &ValidityConfig,
+    paths: &PathsConfig,
+    renew_before: i64,
+) -> bool {
+    let servers = [
+        Server {
+            name: "PKI",
+            profile: CertificateProfile::ServerAuth,
+            cert_path: &paths.server_cert,
+            key_path: &paths.server_key,
+            next_key_path: &paths.server_next_key,
+        },
+        Server {
+            name: "DS",
+            profile: CertificateProfile::DsService,
+            cert_path: &paths.ds_cert,
+            key_path: &paths.ds_key,
+            next_key_path: &paths.ds_next_key,
+        },
+    ];
+    let mut pki_renewed = false;
+    for server in servers {
+        if !path::Path::new(server.next_key_path).exists() {
+            match replace_next_key(server.next_key_path) {
+                Ok(()) => log::info!("Generated the next `{}` server key pair", server.name),
+                Err(e) => log::error!(
+                    "Error generating the next `{}` server key pair: {}",
+                    server.name,
+                    e
+                ),
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let remaining = match validity_of_certificate_file(server.cert_path) {
+            Ok((_, not_after)) => not_after - now,
+            Err(e) => {
+                log::error!(
+                    "Error reading the `{}` server certificate: {}",
+                    server.name,
+                    e
+                );
+                continue;
+            }
+        };
+        if remaining > renew_before {
+            continue;
+        }
+        log::info!(
+            "The `{}` server certificate expires in {} seconds, renewing it",
+            server.name,
+            remaining
+        );
+        match renew_server_certificate(state, validity, &server).await {
+            Ok(()) => {
+                log::info!("Renewed the `{}` server certificate", server.name);
+                pki_renewed |= server.profile == CertificateProfile::ServerAuth;
+            }
+            Err(e) => log::error!(
+                "Error renewing the `{}` server certificate: {}",
+                server.name,
+                e
+            ),
+        }
+    }
+    pki_renewed
+}
+
+/// Issue the server certificate again with the next key pair, replacing the files, then generate a new next key pair.
+/// A new key pair is used if the next one can't be read.
+async fn renew_server_certificate(
+    state: &ServerStateArc,
+    validity: &ValidityConfig,
+    server: &Server<'_>,
+) -> Result<(), String> {
+    let profile = server.profile;
+    let validity = validity.validity(profile);
+    let key_pair = match fs::read_to_string(server.next_key_path)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| KeyPair::from_pem(&pem).map_err(|e| e.to_string()))
+    {
+        Ok(key_pair) => key_pair,
+        Err(e) => {
+            log::warn!(
+                "Couldn't read the next `{}` server key pair, using a new one: {}",
+                server.name,
+                e
+            );
+            mk_ee_key_pair().map_err(|e| e.to_string())?
+        }
+    };
+    let (cert_pem, key_pem) = sign_blocking(state, move |state| {
+        mk_server_credentials_for_key(&state.ca_cert, &state.chain, profile, &validity, key_pair)
+    })
+    .await?
+    .map_err(|e| e.to_string())?;
+    replace_file(server.key_path, &key_pem)?;
+    replace_file(server.cert_path, &cert_pem)?;
+    replace_next_key(server.next_key_path)
+}
+
+/// Generate the key pair of the next renewal.
+fn replace_next_key(next_key_path: &str) -> Result<(), String> {
+    let key_pair = mk_ee_key_pair().map_err(|e| e.to_string())?;
+    replace_file(next_key_path, &key_pair.serialize_pem())
+}
+
+/// Replace the content of the file at once, so that the servers never load a partially written file.
+fn replace_file(file_path: &str, content: &str) -> Result<(), String> {
+    let temporary = format!("{}.tmp", file_path);
+    fs::write(&temporary, content).map_err(|e| e.to_string())?;
+    fs::rename(&temporary, path::Path::new(file_path)).map_err(|e| e.to_string())
+}
+
+/// The validity window of the first certificate of the PEM file, as unix timestamps.
+pub fn validity_of_certificate_file(cert_path: &str) -> Result<(i64, i64), String> {
+    let pem = fs::read_to_string(cert_path).map_err(|e| e.to_string())?;
+    validity_of_pem(&pem)
+}
+
+/// The validity window of the first certificate of the PEM encoded chain, as unix timestamps.
+pub fn validity_of_pem(pem: &str) -> Result<(i64, i64), String> {
+    let certificate = pem::parse_many(pem)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No certificate in the PEM file".to_string())?;
+    let (_, certificate) =
+        X509Certificate::from_der(certificate.contents()).map_err(|e| e.to_string())?;
+    let validity = certificate.validity();
+    Ok((
+        validity.not_before.timestamp(),
+        validity.not_after.timestamp(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{mk_issuer_ca, Validity};
+    use time::{Duration, OffsetDateTime};
+
+    use super::*;
+
+    #[test]
+    fn test_validity_of_pem() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let not_before = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let validity = Validity {
+            not_before,
+            not_after: not_before + Duration::days(30),
+        };
+        let (cert_pem, _) = mk_server_credentials_for_key(
+            &ca_ck,
+            &[ca_ck.cert.pem()],
+            CertificateProfile::ServerAuth,
+            &validity,
+            mk_ee_key_pair().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            validity_of_pem(&cert_pem).unwrap(),
+            (1_700_000_000, 1_700_000_000 + 30 * SECONDS_PER_DAY)
+        );
+        assert!(validity_of_pem("not a certificate").is_err());
+    }
+}
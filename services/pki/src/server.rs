@@ -11,48 +11,323 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-use common::crypto::{check_signature, sign_request_from_pem_and_check_email};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use common::crypto::{
+    check_signature, check_validity, retrieve_emails_from_certificate,
+    retrieve_emails_from_certificate_request, retrieve_emails_from_x509_certificate,
+    sign_request_from_pem_with_profile, CertificateProfile, KeyAlgorithm,
+};
 use rocket::{
-    get, post,
-    response::status::{BadRequest, Conflict, Created, NotFound},
+    delete, get, head,
+    http::{ContentType, Status},
+    mtls::Certificate,
+    post,
+    response::status::{Created, Custom, NotFound},
     serde::json::Json,
-    State,
+    Config, State,
 };
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use utoipa::{OpenApi, ToSchema};
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
 
-use crate::db::{get_certificate_by_email, insert_certificate, DbConnection};
+use crate::{
+    acme::{
+        order_email, Account, AcmeResponse, AcmeStateArc, AcmeStatus, DirectoryObject,
+        FinalizePayload, NewAccountPayload, NewOrderPayload, Problem, ACCOUNT_PATH,
+        AUTHORIZATION_PATH, CERTIFICATE_PATH, CHALLENGE_PATH, NEW_ACCOUNT_PATH, NEW_ORDER_PATH,
+        ORDER_PATH,
+    },
+    audit::{Audit, AuditOperation},
+    challenge::{check_challenge_password, check_proof_of_possession, RegistrationChallengesArc},
+    crl::{
+        fingerprint_of_certificate, hex, mk_crl, serial_of_certificate, spki_sha256_of_certificate,
+        spki_sha256_of_key_pair, RevocationReason,
+    },
+    db::{
+        self, append_log_entry, find_certificate_by_device, find_certificate_by_fingerprint,
+        find_certificate_by_serial, find_certificate_by_spki_sha256, find_log_index_by_fingerprint,
+        get_certificates_by_email, insert_certificate, list_active_expirations, list_audit_events,
+        list_log_entries, list_log_leaf_hashes, list_revoked_certificates, renew_certificate,
+        revoke_certificate, CertificateEntity, DbConn, DbConnection, IssuedCertificate,
+    },
+    ds_notifier::OptionalDsNotifier,
+    est::{certificate_request_pem, decode_body, mk_certs_only, CertsOnlyResponse, EST_DEVICE},
+    metrics::METRICS,
+    ocsp::{
+        mk_ocsp_error, mk_ocsp_response, parse_ocsp_request, sign, CertStatus, OcspResponseStatus,
+    },
+    policy::IssuancePolicy,
+    rate_limit::RateLimit,
+    renewal::validity_of_certificate_file,
+    signer::{self_test, SignerArc},
+    transparency::{
+        consistency_proof, hash_from_slice, inclusion_proof, leaf_hash, root_hash,
+        tree_head_signature_input, Hash,
+    },
+    webhooks::{LifecycleEvent, OptionalWebhooks},
+    AcmeConfig, PathsConfig, RenewalConfig, ValidityConfig,
+};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
 
 /// The state of the server, maintains the CA certificate and CA key pair.
 pub struct PkiState {
     /// The CA certificate and key pair used to sign and verify the clients' certificates.
+    /// The key pair signs through the signer, see [`remote_key_pair`](crate::signer::remote_key_pair).
     pub(crate) ca_cert: rcgen::CertifiedKey,
+    /// The signer holding the CA key, for the OCSP responses and the tree heads of the transparency log.
+    pub(crate) signer: SignerArc,
+    /// The PEM encoded chain of the CA, from the issuing CA to the root CA.
+    pub(crate) chain: Vec<String>,
+    /// The key algorithms accepted in the certificate requests.
+    pub(crate) key_algorithms: Vec<KeyAlgorithm>,
+    /// The profiles the clients can select in the registration requests.
+    pub(crate) profiles: Vec<CertificateProfile>,
 }
 
 /// Implementation of the ServerState.
 impl PkiState {
     /// Create a new server state. Consume the CA certificate and key pair permissions.
-    pub fn new(ca_cert: rcgen::CertifiedKey) -> Self {
-        PkiState { ca_cert }
+    /// The signer holds the key of the CA certificate.
+    /// The chain starts with the PEM encoded certificate of the CA and ends with the root CA.
+    /// Only the certificate requests for keys of the given algorithms are signed, and the clients can only
+    /// register certificates of the given profiles.
+    pub fn new(
+        ca_cert: rcgen::CertifiedKey,
+        signer: SignerArc,
+        chain: Vec<String>,
+        key_algorithms: Vec<KeyAlgorithm>,
+        profiles: Vec<CertificateProfile>,
+    ) -> Self {
+        PkiState {
+            ca_cert,
+            signer,
+            chain,
+            key_algorithms,
+            profiles,
+        }
+    }
+
+    /// The PEM encoded certificate of the CA issuing the certificates, as signed by its own issuer.
+    pub(crate) fn ca_cert_pem(&self) -> String {
+        self.chain
+            .first()
+            .cloned()
+            .unwrap_or_else(|| self.ca_cert.cert.pem())
+    }
+
+    /// The DER encoded chain of the CA, from the CA issuing the certificates to the root CA.
+    pub(crate) fn chain_der(&self) -> Result<Vec<Vec<u8>>, pem::PemError> {
+        self.chain
+            .iter()
+            .map(|certificate| pem::parse(certificate).map(|pem| pem.into_contents()))
+            .collect()
+    }
+}
+
+/// The type of the server state wrapped in an Arc.
+/// The CA material is read-only, so the requests share it without locking.
+pub type ServerStateArc = Arc<PkiState>;
+
+/// A certificate signed by the CA for a certificate request.
+struct SignedCertificate {
+    /// The details of the certificate to be stored in the database.
+    issued: IssuedCertificate,
+    /// The DER encoded certificate.
+    der: Vec<u8>,
+    /// The PEM encoded chain of the CA, from the issuing CA to the root CA.
+    chain: Vec<String>,
+}
+
+/// The failures of [`sign_certificate_request`].
+enum SignError {
+    /// The certificate request is invalid, not for the email or for a key of a disallowed algorithm.
+    InvalidRequest(rcgen::Error),
+    /// The signed certificate could not be read, or the signing task failed.
+    Internal(String),
+}
+
+/// Run a signature with the CA key on a blocking thread, as the signer may call an external program,
+/// so that the other requests, e.g. the verifications, are not queued behind it.
+pub(crate) async fn sign_blocking<T: Send + 'static>(
+    state: &ServerStateArc,
+    sign: impl FnOnce(&PkiState) -> T + Send + 'static,
+) -> Result<T, String> {
+    let state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || sign(&state))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Sign the PEM encoded certificate request for the email with the profile, valid for the period of the profile.
+async fn sign_certificate_request(
+    certificate_request: &str,
+    email: &str,
+    profile: CertificateProfile,
+    state: &State<ServerStateArc>,
+    validity: &State<ValidityConfig>,
+) -> Result<SignedCertificate, SignError> {
+    let certificate_request = certificate_request.to_string();
+    let email = email.to_string();
+    let validity = validity.validity(profile);
+    sign_blocking(state, move |state| {
+        let cert = sign_request_from_pem_with_profile(
+            &certificate_request,
+            &state.ca_cert,
+            &email,
+            profile,
+            &validity,
+            &state.key_algorithms,
+        )
+        .map_err(SignError::InvalidRequest)?;
+        Ok(SignedCertificate {
+            issued: issued_certificate(&cert, profile).map_err(SignError::Internal)?,
+            der: cert.der().to_vec(),
+            chain: state.chain.clone(),
+        })
+    })
+    .await
+    .map_err(SignError::Internal)?
+}
+
+/// Map the failures of [`sign_certificate_request`] to the responses of the JSON endpoints.
+fn sign_error_response(error: SignError) -> Custom<String> {
+    match error {
+        SignError::InvalidRequest(e) => {
+            log::error!("Error signing the certificate: {:?}", e);
+            Custom(
+                Status::BadRequest,
+                "Error signing the certificate".to_string(),
+            )
+        }
+        SignError::Internal(e) => {
+            log::error!("Error reading the signed certificate: {}", e);
+            Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            )
+        }
     }
 }
 
-/// The type of the server state wrapped in an Arc and a Mutex.
-pub type ServerStateArc = Arc<Mutex<PkiState>>;
+/// The device of the certificates registered without a device label.
+pub const DEFAULT_DEVICE: &str = "default";
+/// The maximum length of a device label, see the `certificates` table.
+const MAX_DEVICE_LENGTH: usize = 64;
+
+/// The maximum number of transparency log entries returned at once.
+const MAX_LOG_ENTRIES: u64 = 256;
+
+/// The maximum number of events of the audit log returned at once.
+const MAX_AUDIT_EVENTS: u64 = 256;
+
+/// The maximum number of certificates verified by a single batch request.
+const MAX_VERIFY_BATCH: usize = 256;
+
+/// The emails of the PKI admins, allowed to revoke any certificate, loaded from the `admins` list of the `PKI_Rocket.toml` file.
+pub struct PkiAdmins(pub HashSet<String>);
+
+impl PkiAdmins {
+    /// Whether the client certificate belongs to an admin.
+    fn is_admin(&self, client_certificate: &Certificate<'_>) -> bool {
+        X509Certificate::from_der(client_certificate.as_bytes()).is_ok_and(|(_, x509)| {
+            retrieve_emails_from_x509_certificate(x509)
+                .iter()
+                .any(|email| self.0.contains(email))
+        })
+    }
+}
 
 /// Documentation in OpenAPI format.
 #[derive(OpenApi)]
 #[openapi(
-    paths(openapi, register, get_ca_credential, get_credential, verify),
+    paths(
+        openapi,
+        healthz,
+        readyz,
+        metrics,
+        register,
+        renew,
+        est_cacerts,
+        est_simpleenroll,
+        get_ca_credential,
+        get_chain,
+        get_root_ca_der,
+        get_chain_pkcs7,
+        get_root_ca_json,
+        get_pins,
+        get_certificate_by_serial,
+        get_credential,
+        get_credential_by_fingerprint,
+        register_challenge,
+        delete_credential,
+        verify,
+        verify_batch,
+        revoke,
+        get_crl,
+        ocsp,
+        get_signed_tree_head,
+        get_inclusion_proof,
+        get_consistency_proof,
+        get_log_entries,
+        get_audit_log,
+        acme_directory,
+        acme_new_nonce,
+        acme_head_new_nonce,
+        acme_new_account,
+        acme_account,
+        acme_new_order,
+        acme_order,
+        acme_authorization,
+        acme_challenge,
+        acme_finalize_order,
+        acme_certificate
+    ),
     components(schemas(
+        HealthResponse,
+        ReadinessResponse,
         RegisterRequest,
+        RegisterChallengeRequest,
+        RegisterChallengeResponse,
         GetCredentialRequest,
+        GetCredentialByFingerprintRequest,
         GetCredentialResponse,
+        GetChainResponse,
+        GetRootCaResponse,
+        Pin,
+        CertificatePins,
+        GetPinsResponse,
+        CertificateResponse,
+        GetClientCredentialResponse,
+        DeviceCertificate,
         RegisterResponse,
+        RenewRequest,
+        RenewResponse,
         VerifyRequest,
         VerifyResponse,
+        VerifyBatchRequest,
+        VerifyBatchResponse,
+        RevokeRequest,
+        RevokeResponse,
+        RevocationReason,
+        SignedTreeHead,
+        InclusionProofResponse,
+        ConsistencyProofResponse,
+        LogEntry,
+        GetLogEntriesResponse,
+        AuditOperation,
+        AuditEvent,
+        GetAuditLogResponse,
     ))
 )]
 pub struct OpenApiDoc;
@@ -70,6 +345,42 @@ pub struct RegisterRequest {
     pub certificate_request: String,
     /// The email contained in the [certificate_request].
     pub email: String,
+    /// The label of the device of the client, a client can register one certificate per device.
+    /// Defaults to `default`.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// The issuance profile of the certificate, among the ones enabled on the server: `client-auth`,
+    /// `server-auth` or `ds-service`. Defaults to `client-auth`.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The challenge obtained with `/ca/register/challenge` for the email.
+    pub challenge: String,
+    /// Base64 encoded signature of the [challenge] with the private key of the [certificate_request],
+    /// proving its possession.
+    pub challenge_signature: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterChallengeRequest {
+    /// The email to register.
+    pub email: String,
+}
+
+/// A challenge to sign with the private key of the certificate request sent to `/ca/register`.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RegisterChallengeResponse {
+    /// The random challenge, valid for a single registration of the email.
+    pub challenge: String,
+    /// When the challenge expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RenewRequest {
+    /// PEM encoded certificate request for the new key pair.
+    pub certificate_request: String,
+    /// The email of the current certificate, contained in the [certificate_request].
+    pub email: String,
 }
 
 #[derive(Deserialize, ToSchema)]
@@ -78,28 +389,291 @@ pub struct GetCredentialRequest {
     email: String,
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct GetCredentialByFingerprintRequest {
+    /// The SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the certificate, hex encoded or base64 encoded
+    /// as in the `pin-sha256` pins.
+    spki_sha256: String,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct VerifyRequest {
     /// PEM encoded client certificate.
     pub certificate: String,
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RevokeRequest {
+    /// The email of the client whose certificate is revoked.
+    pub email: String,
+    /// The label of the device holding the certificate, `default` if missing.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// The reason of the revocation, unspecified by default.
+    pub reason: Option<RevocationReason>,
+}
+
 #[derive(Serialize, ToSchema)]
 pub struct GetCredentialResponse {
     /// PEM encoded certificate.
     certificate: String,
 }
 
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetChainResponse {
+    /// PEM encoded certificates, from the CA issuing the certificates to the root CA.
+    pub certificates: Vec<String>,
+}
+
+/// The root CA, the trust anchor of the clients, with the pins of the keys of the chain.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetRootCaResponse {
+    /// PEM encoded certificate of the root CA.
+    pub certificate: String,
+    /// Base64 encoded DER certificate of the root CA.
+    pub der: String,
+    /// Base64 encoded SHA-256 digest of the SubjectPublicKeyInfo of the root CA, as in the `pin-sha256` pins.
+    pub spki_sha256: String,
+    /// Base64 encoded SHA-256 digests of the SubjectPublicKeyInfo of the chain, from the CA issuing the certificates
+    /// to the root CA.
+    pub chain_spki_sha256: Vec<String>,
+}
+
+/// The pin of a key, with the window it is used in.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct Pin {
+    /// Base64 encoded SHA-256 digest of the SubjectPublicKeyInfo, as in the `pin-sha256` pins.
+    pub spki_sha256: String,
+    /// When the key starts being used, in seconds since the Unix epoch.
+    pub not_before: i64,
+    /// When the key stops being used, in seconds since the Unix epoch.
+    pub not_after: i64,
+}
+
+/// The pin of the current key of a certificate and the pin of the key replacing it, if known.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct CertificatePins {
+    /// The certificate: `root-ca`, `intermediate-ca`, `pki` or `ds`.
+    pub name: String,
+    /// The pin of the current certificate, with its validity window.
+    pub current: Pin,
+    /// The pin of the next key, with the validity window of the next certificate of the CA, or the expected one
+    /// of the next renewal of the server certificates.
+    pub next: Option<Pin>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetPinsResponse {
+    /// The pins of the chain of the CA, from the CA issuing the certificates to the root CA,
+    /// then of the PKI and DS server certificates.
+    pub pins: Vec<CertificatePins>,
+}
+
+/// A certificate issued by the CA, with its revocation status.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct CertificateResponse {
+    /// The email of the client.
+    pub email: String,
+    /// The label of the device of the client.
+    pub device: String,
+    /// The hex encoded serial number.
+    pub serial: String,
+    /// The hex encoded SHA-256 fingerprint of the DER encoded certificate.
+    pub fingerprint: String,
+    /// The hex encoded SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the certificate.
+    pub spki_sha256: String,
+    /// PEM encoded certificate.
+    pub certificate: String,
+    /// The issuance profile of the certificate.
+    pub profile: String,
+    /// When the certificate was revoked, in seconds since the Unix epoch.
+    pub revoked_at: Option<u64>,
+    /// The reason of the revocation.
+    pub revocation_reason: Option<RevocationReason>,
+    /// The hex encoded serial number of the revoked certificate of the device this certificate was re-issued for.
+    pub replaces: Option<String>,
+}
+
+/// The certificate of a device of a client.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct DeviceCertificate {
+    /// The label of the device.
+    pub device: String,
+    /// The hex encoded SHA-256 fingerprint of the DER encoded certificate.
+    pub fingerprint: String,
+    /// PEM encoded certificate.
+    pub certificate: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetClientCredentialResponse {
+    /// PEM encoded certificate of the most recently registered device.
+    pub certificate: String,
+    /// The valid certificates of all the devices of the client, the most recent first.
+    pub certificates: Vec<DeviceCertificate>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct RegisterResponse {
     /// PEM encoded certificate.
     pub certificate: String,
+    /// PEM encoded certificates of the chain of the certificate, from the issuing CA to the root CA, as in `/ca/chain`.
+    pub chain: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RenewResponse {
+    /// PEM encoded certificate, replacing the current one.
+    pub certificate: String,
+    /// PEM encoded certificates of the chain of the certificate, from the issuing CA to the root CA, as in `/ca/chain`.
+    pub chain: Vec<String>,
+}
+
+/// The result of the health checks of the server, it is healthy only if all of them pass.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct HealthResponse {
+    /// Whether the key of the CA is loaded and signs for the CA certificate.
+    pub ca_key: bool,
+    /// Whether the database is reachable.
+    pub database: bool,
+    /// Whether the server certificate is within its validity window.
+    pub server_certificate: bool,
+    /// The number of days before the server certificate expires, negative once expired.
+    /// Missing if the server certificate can't be read.
+    pub server_certificate_days_to_expiry: Option<i64>,
+}
+
+impl HealthResponse {
+    pub fn is_healthy(&self) -> bool {
+        self.ca_key && self.database && self.server_certificate
+    }
+}
+
+/// The state of the dependencies of the server, it can serve requests only if all of them are available.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ReadinessResponse {
+    /// Whether the database is reachable.
+    pub database: bool,
+    /// Whether the TLS credentials are loaded.
+    pub tls: bool,
+}
+
+impl ReadinessResponse {
+    pub fn is_ready(&self) -> bool {
+        self.database && self.tls
+    }
 }
 
 #[derive(Serialize, ToSchema)]
 pub struct VerifyResponse {
-    /// Whether the certificate is valid.
+    /// Whether the certificate is valid: signed by the CA, within its validity period and not revoked.
     valid: bool,
+    /// Whether the certificate is signed by the CA, the other checks are skipped otherwise.
+    signed: bool,
+    /// Whether the certificate is signed by the CA but outside of its validity period.
+    expired: bool,
+    /// Whether the certificate is signed by the CA but revoked.
+    revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct VerifyBatchRequest {
+    /// PEM encoded client certificates.
+    pub certificates: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VerifyBatchResponse {
+    /// The verification of each certificate, in the order of the request.
+    results: Vec<VerifyResponse>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RevokeResponse {
+    /// When the certificate was revoked, in seconds since the Unix epoch.
+    pub revoked_at: u64,
+}
+
+/// The signed tree head of the transparency log, the root of the Merkle tree of all the issued certificates.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct SignedTreeHead {
+    /// The number of entries in the log.
+    pub tree_size: u64,
+    /// When the tree head was signed, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The hex encoded SHA-256 root hash of the Merkle tree.
+    pub root_hash: String,
+    /// The hex encoded DER signature of the tree head by the CA key, over
+    /// `version (0) || signature type (1) || timestamp || tree size || root hash` as in RFC 6962.
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct InclusionProofResponse {
+    /// The index of the certificate in the log.
+    pub leaf_index: u64,
+    /// The size of the tree the proof is for.
+    pub tree_size: u64,
+    /// The hex encoded hashes of the audit path, from the leaf to the root.
+    pub audit_path: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ConsistencyProofResponse {
+    /// The size of the older tree.
+    pub first: u64,
+    /// The size of the newer tree.
+    pub second: u64,
+    /// The hex encoded hashes proving that the older tree is a prefix of the newer one.
+    pub proof: Vec<String>,
+}
+
+/// An issuance logged in the transparency log.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct LogEntry {
+    /// The index of the entry in the log.
+    pub leaf_index: u64,
+    /// The email the certificate was issued for.
+    pub email: String,
+    /// PEM encoded certificate.
+    pub certificate: String,
+    /// When the certificate was logged, in milliseconds since the Unix epoch.
+    pub logged_at: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetLogEntriesResponse {
+    /// The entries of the log, in order.
+    pub entries: Vec<LogEntry>,
+}
+
+/// A sign, verify or revoke operation recorded in the audit log.
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct AuditEvent {
+    /// The position of the event in the log, increasing.
+    pub id: u64,
+    /// When the operation was answered, in milliseconds since the Unix epoch.
+    pub logged_at: u64,
+    /// The operation, `sign`, `verify` or `revoke`.
+    pub operation: String,
+    /// The emails of the client certificate of the requester, comma separated, or the ACME account.
+    pub requester: Option<String>,
+    /// The IP address of the requester.
+    pub requester_ip: Option<String>,
+    /// The email of the certificate the operation is about.
+    pub email: Option<String>,
+    /// The hex encoded serial number of the certificate the operation is about.
+    pub serial: Option<String>,
+    /// The HTTP status of the response.
+    pub status: u16,
+    /// `success` or `failure`, or the result of a verification: `valid`, `expired` or `invalid`.
+    pub outcome: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct GetAuditLogResponse {
+    /// The events of the audit log, the most recent first.
+    pub events: Vec<AuditEvent>,
 }
 
 /// Return JSON version of an OpenAPI schema
@@ -115,7 +689,129 @@ pub fn openapi() -> Json<utoipa::openapi::OpenApi> {
     Json(OpenApiDoc::openapi())
 }
 
-/// Return the CA's credential.
+/// Health probe, the CA key is loaded, the database is reachable and the server certificate is valid,
+/// e.g. to catch a `private/ca` volume restored without its key.
+/// The remaining validity of the server certificate is reported in days, to be monitored.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The server is healthy.", body = HealthResponse),
+        (status = 503, description = "Some of the checks failed.", body = HealthResponse),
+    )
+)]
+#[get("/healthz")]
+pub async fn healthz(
+    db: &DbConn,
+    state: &State<ServerStateArc>,
+    paths: &State<PathsConfig>,
+) -> Result<Json<HealthResponse>, Custom<Json<HealthResponse>>> {
+    let health = check_health(db, state, &paths.server_cert).await;
+    if health.is_healthy() {
+        Ok(Json(health))
+    } else {
+        Err(Custom(Status::ServiceUnavailable, Json(health)))
+    }
+}
+
+/// Check the CA key with a test signature, the database and the validity window of the server certificate.
+pub async fn check_health(
+    db: &DbConn,
+    state: &ServerStateArc,
+    server_cert_path: &str,
+) -> HealthResponse {
+    let ca_key = sign_blocking(state, |state| {
+        self_test(state.signer.as_ref(), &state.ca_cert_pem())
+    })
+    .await
+    .and_then(|result| result)
+    .inspect_err(|e| log::error!("The CA key is not loaded: `{}`", e))
+    .is_ok();
+    let database = db::ping(db)
+        .await
+        .inspect_err(|e| log::error!("The database is not reachable: `{}`", e))
+        .is_ok();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let validity = validity_of_certificate_file(server_cert_path)
+        .inspect_err(|e| log::error!("The server certificate can't be read: `{}`", e))
+        .ok();
+    HealthResponse {
+        ca_key,
+        database,
+        server_certificate: validity
+            .is_some_and(|(not_before, not_after)| not_before <= now && now < not_after),
+        server_certificate_days_to_expiry: validity
+            .map(|(_, not_after)| (not_after - now).div_euclid(SECONDS_PER_DAY)),
+    }
+}
+
+/// Readiness probe, the server can reach the database and its TLS credentials are loaded.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The server is ready to serve requests.", body = ReadinessResponse),
+        (status = 503, description = "Some of the dependencies of the server are not available.", body = ReadinessResponse),
+    )
+)]
+#[get("/readyz")]
+pub async fn readyz(
+    db: &DbConn,
+    config: &Config,
+) -> Result<Json<ReadinessResponse>, Custom<Json<ReadinessResponse>>> {
+    let readiness = check_readiness(db, config).await;
+    if readiness.is_ready() {
+        Ok(Json(readiness))
+    } else {
+        Err(Custom(Status::ServiceUnavailable, Json(readiness)))
+    }
+}
+
+/// Check the dependencies of the server, used by the readiness probe and during startup.
+pub async fn check_readiness(db: &DbConn, config: &Config) -> ReadinessResponse {
+    let database = db::ping(db)
+        .await
+        .inspect_err(|e| log::error!("The database is not reachable: `{}`", e))
+        .is_ok();
+    ReadinessResponse {
+        database,
+        tls: config.tls_enabled(),
+    }
+}
+
+/// Return the metrics of the server in the Prometheus text format.
+/// The time to expiry of the active certificates is computed on each request.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "The metrics of the server", content_type = "text/plain"),
+    )
+)]
+#[get("/metrics")]
+pub async fn metrics(db: DbConnection) -> Result<(ContentType, String), Status> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let expirations = list_active_expirations(now, db).await.map_err(|e| {
+        log::error!("Error listing the active certificates: {:?}", e);
+        Status::InternalServerError
+    })?;
+    let times_to_expiry = expirations
+        .iter()
+        .map(|not_after| not_after - now)
+        .collect::<Vec<_>>();
+    Ok((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        METRICS.render(&times_to_expiry),
+    ))
+}
+
+/// Return the CA's credential, the certificate of the CA issuing the clients' certificates.
 #[utoipa::path(
     get,
     path = "/ca/credential",
@@ -125,136 +821,2051 @@ pub fn openapi() -> Json<utoipa::openapi::OpenApi> {
 )]
 #[get("/ca/credential")]
 pub fn get_ca_credential(state: &State<ServerStateArc>) -> Json<GetCredentialResponse> {
-    let state = state.lock().unwrap();
     Json(GetCredentialResponse {
-        certificate: state.ca_cert.cert.pem(),
+        certificate: state.ca_cert_pem(),
     })
 }
 
-/// Return the client's credential bound to the email in the request.
+/// Return the chain of the CA, from the CA issuing the certificates, e.g. an intermediate CA, to the root CA.
 #[utoipa::path(
-    post, // As we are sending the email in the body, to avoid other users to understand who we are looking for
-    path = "/credential",
-    request_body = GetCredentialRequest,
+    get,
+    path = "/ca/chain",
     responses(
-        (status = 200, description = "client certificate", body = GetCredentialResponse),
-        (status = 404, description = "Not Found")
+        (status = 200, description = "CA chain", body = GetChainResponse)
     )
 )]
-#[post("/credential", data = "<request>")]
-pub async fn get_credential(
-    request: Json<GetCredentialRequest>,
-    db: DbConnection,
-) -> Result<Json<GetCredentialResponse>, NotFound<String>> {
-    get_certificate_by_email(&request.email, db)
-        .await
-        .map_or_else(
-            |e| {
-                log::debug!(
-                    "Couldn't find a certificate for `{}` in the DB: {:?}",
-                    &request.email,
-                    e
-                );
-                Err(NotFound(format!(
-                    "Requested client `{}` not yet registered",
-                    &request.email
-                )))
-            },
-            |cert| {
-                Ok(Json(GetCredentialResponse {
-                    certificate: cert.certificate,
-                }))
-            },
-        )
+#[get("/ca/chain")]
+pub fn get_chain(state: &State<ServerStateArc>) -> Json<GetChainResponse> {
+    Json(GetChainResponse {
+        certificates: state.chain.clone(),
+    })
 }
 
-/// Register a new client's public key with the CA.
-/// The client sends a certificate request in PEM format.
-/// The CA checks that the email in the certificate request is the same as the email in the register request.
+/// Return the DER encoded certificate of the root CA, e.g. to be installed as a trust anchor by the operating systems.
 #[utoipa::path(
-    post,
-    path = "/ca/register",
-    request_body = RegisterRequest,
+    get,
+    path = "/ca/root.der",
     responses(
-        (status = 201, description = "Registered client.", body = RegisterResponse),
-        (status = 400, description = "Bad Request"),
-        (status = 409, description = "Conflict"),
+        (status = 200, description = "DER encoded certificate of the root CA", content_type = "application/pkix-cert"),
+        (status = 500, description = "Internal Server Error"),
     )
 )]
-#[post("/ca/register", data = "<request>")]
-pub async fn register(
-    request: Json<RegisterRequest>,
-    state: &State<ServerStateArc>,
-    db: DbConnection,
-) -> Result<Created<Json<RegisterResponse>>, Result<Conflict<String>, BadRequest<String>>> {
-    // Shorten the lifetime of the state lock to not hold across the await boundaries.
-    let response = {
-        let state = state.lock().unwrap();
-        log::debug!("Received certificate request for email {:?}", request.email);
-        let cert = match sign_request_from_pem_and_check_email(
-            &request.certificate_request,
-            &state.ca_cert,
-            &request.email,
-        ) {
-            Ok(cert) => cert,
-            Err(e) => {
-                log::error!("Error signing the certificate: {:?}", e);
-                return Err(Err(BadRequest("Error signing the certificate".to_string())));
-            }
-        };
-        let response = RegisterResponse {
-            certificate: cert.pem(),
-        };
-        response
+#[get("/ca/root.der")]
+pub fn get_root_ca_der(state: &State<ServerStateArc>) -> Result<(ContentType, Vec<u8>), Status> {
+    let root = root_ca_der(state)?;
+    Ok((ContentType::new("application", "pkix-cert"), root))
+}
+
+/// Return the chain of the CA as a DER encoded PKCS#7 `certs-only` bundle, e.g. for the reverse proxies.
+/// The bundle holds the certificates from the CA issuing the certificates to the root CA.
+#[utoipa::path(
+    get,
+    path = "/ca/chain.p7c",
+    responses(
+        (status = 200, description = "DER encoded PKCS#7 certs-only message", content_type = "application/pkcs7-mime"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/chain.p7c")]
+pub fn get_chain_pkcs7(state: &State<ServerStateArc>) -> Result<(ContentType, Vec<u8>), Status> {
+    let certificates = state.chain_der().map_err(|e| {
+        log::error!("Error decoding the chain of the CA: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok((
+        ContentType::new("application", "pkcs7-mime").with_params(("smime-type", "certs-only")),
+        mk_certs_only(&certificates),
+    ))
+}
+
+/// Return the root CA as PEM and DER, with the SHA-256 pins of the SubjectPublicKeyInfo of the chain,
+/// so that the clients can pin the keys of the CA without decoding the certificates.
+#[utoipa::path(
+    get,
+    path = "/ca/root.json",
+    responses(
+        (status = 200, description = "Root CA with the pins of the chain", body = GetRootCaResponse),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/root.json")]
+pub fn get_root_ca_json(state: &State<ServerStateArc>) -> Result<Json<GetRootCaResponse>, Status> {
+    let chain = state.chain_der().map_err(|e| {
+        log::error!("Error decoding the chain of the CA: {}", e);
+        Status::InternalServerError
+    })?;
+    let chain_spki_sha256 = chain
+        .iter()
+        .map(|certificate| spki_sha256_of_certificate(certificate).map(|pin| STANDARD.encode(pin)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            log::error!("Error computing the pins of the chain of the CA: {}", e);
+            Status::InternalServerError
+        })?;
+    let (Some(certificate), Some(root), Some(spki_sha256)) =
+        (state.chain.last(), chain.last(), chain_spki_sha256.last())
+    else {
+        log::error!("The chain of the CA is empty");
+        return Err(Status::InternalServerError);
     };
-    let r = insert_certificate(&request.email, &response.certificate, db)
-        .await
-        .map_or_else(
-            |e| {
-                // Since we already performed validation on the request, we can assume the error is due to a duplicate email.
-                // The db schema should have a unique constraint on the email field.
-                log::error!("Error inserting the certificate in the DB: {:?}", e);
-                Err(Ok(Conflict("Client already registered".to_string())))
-            },
-            |_| {
-                log::debug!(
-                    "Registered client with email: `{}`, certificate `{:?}`",
-                    &request.email,
-                    response
-                );
-                let create_response = Created::new("https://localhost:8000/credential");
-                Ok(Created::body(create_response, Json(response)))
-            },
-        );
-    r
+    Ok(Json(GetRootCaResponse {
+        certificate: certificate.clone(),
+        der: STANDARD.encode(root),
+        spki_sha256: spki_sha256.clone(),
+        chain_spki_sha256,
+    }))
 }
 
-/// Verify a client's certificate.
-/// The client sends a certificate to be verified in PEM format.
+/// The DER encoded certificate of the root CA, the last of the chain.
+fn root_ca_der(state: &PkiState) -> Result<Vec<u8>, Status> {
+    state
+        .chain_der()
+        .ok()
+        .and_then(|mut chain| chain.pop())
+        .ok_or_else(|| {
+            log::error!("Error decoding the root CA of the chain");
+            Status::InternalServerError
+        })
+}
+
+/// Return the current and next SPKI SHA-256 pins of the chain of the CA and of the PKI and DS server certificates,
+/// with their validity windows. The clients pinning a key should trust its next pin as well, so that its rotation
+/// doesn't break them: the next key of a server certificate is generated ahead of its renewal, and the next CA is
+/// published once provided by the operators.
 #[utoipa::path(
-    post,
-    path = "/ca/verify",
-    request_body = VerifyRequest,
+    get,
+    path = "/ca/pins",
     responses(
-        (status = 200, description = "Whether the client's certificate is valid or not.", body = VerifyResponse),
+        (status = 200, description = "The pins of the CA and of the server certificates", body = GetPinsResponse),
+        (status = 500, description = "Internal Server Error"),
     )
 )]
-#[post("/ca/verify", data = "<request>")]
-pub async fn verify(
-    request: Json<VerifyRequest>,
+#[get("/ca/pins")]
+pub fn get_pins(
     state: &State<ServerStateArc>,
-) -> Json<VerifyResponse> {
-    let state = state.lock().unwrap();
-    log::debug!(
-        "Received certificate for verification: {:?}",
-        &request.certificate
-    );
-    let verified = match check_signature(&request.certificate, &state.ca_cert.cert.pem()) {
-        Ok(verified) => verified,
+    paths: &State<PathsConfig>,
+    validity: &State<ValidityConfig>,
+    renewal: &State<RenewalConfig>,
+) -> Result<Json<GetPinsResponse>, Status> {
+    let internal_error = |e: String| {
+        log::error!("Error computing the pins: {}", e);
+        Status::InternalServerError
+    };
+    let mut pins = Vec::new();
+    let last = state.chain.len().saturating_sub(1);
+    for (i, certificate) in state.chain.iter().enumerate() {
+        let (name, next) = if i == last {
+            ("root-ca", next_ca_pin(&paths.next_ca_cert))
+        } else {
+            ("intermediate-ca", None)
+        };
+        pins.push(CertificatePins {
+            name: name.to_string(),
+            current: certificate_pin(certificate).map_err(internal_error)?,
+            next,
+        });
+    }
+    let renew_before = i64::from(renewal.before_days) * SECONDS_PER_DAY;
+    for (name, profile, cert_path, next_key_path) in [
+        (
+            "pki",
+            CertificateProfile::ServerAuth,
+            &paths.server_cert,
+            &paths.server_next_key,
+        ),
+        (
+            "ds",
+            CertificateProfile::DsService,
+            &paths.ds_cert,
+            &paths.ds_next_key,
+        ),
+    ] {
+        let current = std::fs::read_to_string(cert_path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| certificate_pin(&pem))
+            .map_err(internal_error)?;
+        let validity = validity.validity(profile);
+        let lifetime = (validity.not_after - validity.not_before).whole_seconds();
+        let next = std::fs::read_to_string(next_key_path)
+            .ok()
+            .and_then(|pem| spki_sha256_of_key_pair(&pem).ok())
+            .map(|spki_sha256| {
+                let not_before = (current.not_after - renew_before).max(unix_time() as i64);
+                Pin {
+                    spki_sha256: STANDARD.encode(spki_sha256),
+                    not_before,
+                    not_after: not_before + lifetime,
+                }
+            });
+        pins.push(CertificatePins {
+            name: name.to_string(),
+            current,
+            next,
+        });
+    }
+    Ok(Json(GetPinsResponse { pins }))
+}
+
+/// The pin of the first certificate of the PEM encoded chain, with its validity window.
+fn certificate_pin(pem: &str) -> Result<Pin, String> {
+    let certificate = pem::parse_many(pem)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No certificate in the PEM file".to_string())?;
+    let (_, x509) = X509Certificate::from_der(certificate.contents()).map_err(|e| e.to_string())?;
+    let validity = x509.validity();
+    Ok(Pin {
+        spki_sha256: STANDARD.encode(spki_sha256_of_certificate(certificate.contents())?),
+        not_before: validity.not_before.timestamp(),
+        not_after: validity.not_after.timestamp(),
+    })
+}
+
+/// The pin of the next CA, if its certificate was provided by the operators.
+fn next_ca_pin(next_ca_cert_path: &str) -> Option<Pin> {
+    let pem = std::fs::read_to_string(next_ca_cert_path).ok()?;
+    certificate_pin(&pem)
+        .inspect_err(|e| log::error!("Error reading the certificate of the next CA: {}", e))
+        .ok()
+}
+
+/// Return the certificate with the given hex encoded serial number, including the revoked ones.
+#[utoipa::path(
+    get,
+    path = "/ca/certificates/{serial}",
+    params(
+        ("serial" = String, Path, description = "The hex encoded serial number of the certificate."),
+    ),
+    responses(
+        (status = 200, description = "The certificate", body = CertificateResponse),
+        (status = 404, description = "Not Found")
+    )
+)]
+#[get("/ca/certificates/<serial>")]
+pub async fn get_certificate_by_serial(
+    serial: &str,
+    mut db: DbConnection,
+) -> Result<Json<CertificateResponse>, NotFound<String>> {
+    let serial = serial.to_ascii_lowercase();
+    match find_certificate_by_serial(&serial, &mut db).await {
+        Ok(Some(certificate)) => Ok(Json(certificate_response(certificate))),
+        Ok(None) => Err(NotFound(format!("No certificate with serial `{}`", serial))),
         Err(e) => {
-            log::error!("Error verifying the certificate: {:?}", e);
-            false
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            Err(NotFound(format!("No certificate with serial `{}`", serial)))
+        }
+    }
+}
+
+fn certificate_response(certificate: CertificateEntity) -> CertificateResponse {
+    CertificateResponse {
+        email: certificate.email,
+        device: certificate.device,
+        serial: certificate.serial,
+        fingerprint: certificate.fingerprint,
+        spki_sha256: certificate.spki_sha256,
+        certificate: certificate.certificate,
+        profile: certificate.profile,
+        revoked_at: certificate.revoked_at,
+        revocation_reason: certificate
+            .revocation_reason
+            .map(RevocationReason::from_code),
+        replaces: certificate.replaces,
+    }
+}
+
+/// Return the client's credentials bound to the email in the request, one for each of its devices.
+#[utoipa::path(
+    post, // As we are sending the email in the body, to avoid other users to understand who we are looking for
+    path = "/credential",
+    request_body = GetCredentialRequest,
+    responses(
+        (status = 200, description = "client certificates", body = GetClientCredentialResponse),
+        (status = 404, description = "Not Found, or all the certificates are expired or revoked"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+    )
+)]
+#[post("/credential", data = "<request>")]
+pub async fn get_credential(
+    request: Json<GetCredentialRequest>,
+    rate_limit: RateLimit<'_>,
+    db: DbConnection,
+) -> Result<Json<GetClientCredentialResponse>, Custom<String>> {
+    rate_limit.check(&request.email)?;
+    let certificates = get_certificates_by_email(&request.email, db)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!(
+                "Couldn't read the certificates of `{}` from the DB: {:?}",
+                &request.email,
+                e
+            );
+            Vec::new()
+        });
+    let now = OffsetDateTime::now_utc();
+    let certificates: Vec<_> = certificates
+        .into_iter()
+        .filter(|cert| check_validity(&cert.certificate, now).unwrap_or(false))
+        .map(|cert| DeviceCertificate {
+            device: cert.device,
+            fingerprint: cert.fingerprint,
+            certificate: cert.certificate,
+        })
+        .collect();
+    match certificates.first() {
+        Some(latest) => Ok(Json(GetClientCredentialResponse {
+            certificate: latest.certificate.clone(),
+            certificates,
+        })),
+        None => {
+            log::debug!("Couldn't find a valid certificate for `{}`", &request.email);
+            Err(Custom(
+                Status::NotFound,
+                format!(
+                    "Requested client `{}` not yet registered or without valid certificates",
+                    &request.email
+                ),
+            ))
+        }
+    }
+}
+
+/// Return the certificate of the public key with the given SHA-256 digest of its SubjectPublicKeyInfo, e.g. to
+/// resolve the key of an MLS leaf node to the email of its owner. The active certificate is returned if the key was
+/// certified more than once, otherwise the most recent one, with its revocation status.
+#[utoipa::path(
+    post,
+    path = "/credential/by-fingerprint",
+    request_body = GetCredentialByFingerprintRequest,
+    responses(
+        (status = 200, description = "The certificate of the public key", body = CertificateResponse),
+        (status = 400, description = "Bad Request, the digest is neither hex nor base64 encoded SHA-256"),
+        (status = 404, description = "Not Found, no certificate was issued for the public key"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+    )
+)]
+#[post("/credential/by-fingerprint", data = "<request>")]
+pub async fn get_credential_by_fingerprint(
+    request: Json<GetCredentialByFingerprintRequest>,
+    rate_limit: RateLimit<'_>,
+    mut db: DbConnection,
+) -> Result<Json<CertificateResponse>, Custom<String>> {
+    let spki_sha256 = normalize_spki_sha256(&request.spki_sha256).ok_or_else(|| {
+        Custom(
+            Status::BadRequest,
+            "The `spki_sha256` must be a hex or base64 encoded SHA-256 digest".to_string(),
+        )
+    })?;
+    rate_limit.check(&spki_sha256)?;
+    match find_certificate_by_spki_sha256(&spki_sha256, &mut db).await {
+        Ok(Some(certificate)) => Ok(Json(certificate_response(certificate))),
+        Ok(None) => Err(Custom(
+            Status::NotFound,
+            format!("No certificate for the public key `{}`", spki_sha256),
+        )),
+        Err(e) => {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// The lowercase hex encoding of a SHA-256 digest, given either hex encoded or base64 encoded.
+fn normalize_spki_sha256(digest: &str) -> Option<String> {
+    let digest = digest.trim();
+    if digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Some(digest.to_ascii_lowercase());
+    }
+    STANDARD
+        .decode(digest)
+        .ok()
+        .filter(|bytes| bytes.len() == 32)
+        .map(|bytes| hex(&bytes))
+}
+
+/// Deregister the client certificate used for the mutual TLS authentication, e.g. when decommissioning a device.
+/// The certificate is revoked with the `cessation_of_operation` reason, its record is kept to list it in the CRL
+/// but it is not returned by `/credential` anymore. The DS is notified to reject the sessions of the certificate.
+#[utoipa::path(
+    delete,
+    path = "/credential",
+    responses(
+        (status = 200, description = "Revoked the client certificate.", body = RevokeResponse),
+        (status = 401, description = "Unauthorized, no client certificate"),
+        (status = 404, description = "Not Found, the client certificate is not registered"),
+        (status = 409, description = "Conflict, the certificate is already revoked"),
+    )
+)]
+#[delete("/credential")]
+pub async fn delete_credential(
+    client_certificate: Certificate<'_>,
+    ds_notifier: &State<OptionalDsNotifier>,
+    webhooks: &State<OptionalWebhooks>,
+    audit: Audit<'_>,
+    mut db: DbConnection,
+) -> Result<Json<RevokeResponse>, Custom<String>> {
+    audit.record(AuditOperation::Revoke);
+    let fingerprint = fingerprint_of_certificate(client_certificate.as_bytes());
+    let stored = match find_certificate_by_fingerprint(&fingerprint, &mut db).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            return Err(Custom(
+                Status::NotFound,
+                "The client certificate is not registered".to_string(),
+            ))
+        }
+        Err(e) => {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            return Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ));
+        }
+    };
+    audit.email(&stored.email);
+    audit.serial(&stored.serial);
+    let revoked_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let reason = RevocationReason::CessationOfOperation;
+    match revoke_certificate(stored.id, revoked_at, reason.code(), &mut db).await {
+        Ok(true) => {
+            METRICS.certificates_revoked.inc();
+            log::info!(
+                "Deregistered the certificate of `{}`, device `{}`",
+                stored.email,
+                stored.device
+            );
+            if let Some(ds_notifier) = ds_notifier.inner() {
+                ds_notifier.notify_revocation();
+            }
+            if let Some(webhooks) = webhooks.inner() {
+                webhooks.notify(&LifecycleEvent::revoked(&stored, reason));
+            }
+            Ok(Json(RevokeResponse { revoked_at }))
+        }
+        Ok(false) => Err(Custom(
+            Status::Conflict,
+            "Certificate already revoked".to_string(),
+        )),
+        Err(e) => {
+            log::error!("Error revoking the certificate in the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// Issue a challenge for the registration of an email, to be signed with the private key of the certificate request
+/// sent to `/ca/register`, or to be set as the challenge password of the certificate request enrolled with EST.
+/// The challenge expires after 5 minutes and can be used for a single registration. Issuing too many challenges
+/// for an email forgets its oldest ones.
+#[utoipa::path(
+    post,
+    path = "/ca/register/challenge",
+    request_body = RegisterChallengeRequest,
+    responses(
+        (status = 200, description = "The registration challenge.", body = RegisterChallengeResponse),
+        (status = 403, description = "Forbidden, the email is not allowed by the issuance policy"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+        (status = 503, description = "Service Unavailable, too many challenges are pending"),
+    )
+)]
+#[post("/ca/register/challenge", data = "<request>")]
+pub async fn register_challenge(
+    request: Json<RegisterChallengeRequest>,
+    rate_limit: RateLimit<'_>,
+    policy: &State<IssuancePolicy>,
+    challenges: &State<RegistrationChallengesArc>,
+) -> Result<Json<RegisterChallengeResponse>, Custom<String>> {
+    rate_limit.check(&request.email)?;
+    policy.check(&request.email)?;
+    let Some((challenge, expires_at)) = challenges
+        .lock()
+        .unwrap()
+        .issue(&request.email, unix_time())
+    else {
+        log::warn!("Too many pending registration challenges");
+        return Err(Custom(
+            Status::ServiceUnavailable,
+            "Too many pending registration challenges, retry later".to_string(),
+        ));
+    };
+    Ok(Json(RegisterChallengeResponse {
+        challenge,
+        expires_at,
+    }))
+}
+
+/// Register a new client's public key with the CA.
+/// The client sends a certificate request in PEM format, with the challenge obtained from `/ca/register/challenge`
+/// signed by the private key of the request, proving its possession.
+/// The CA checks that the email in the certificate request is the same as the email in the register request.
+/// A client can register a certificate for each of its devices.
+/// A device whose certificate was revoked can register again, the new certificate records the serial number
+/// of the revoked one it replaces.
+/// The key usages, extended key usages, Subject alt names and validity of the certificate are enforced by its profile.
+/// Only the emails allowed by the issuance policy can register.
+/// The certificate is appended to the transparency log before being returned.
+#[utoipa::path(
+    post,
+    path = "/ca/register",
+    request_body = RegisterRequest,
+    responses(
+        (status = 201, description = "Registered client.", body = RegisterResponse),
+        (status = 400, description = "Bad Request"),
+        (status = 403, description = "Forbidden, the profile is not enabled for the registrations, the email is not allowed by the issuance policy or the challenge is not signed by the key of the certificate request"),
+        (status = 409, description = "Conflict, the device of the client has already a certificate which is not revoked"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+        (status = 500, description = "Internal Server Error, the certificate could not be logged"),
+    )
+)]
+#[post("/ca/register", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn register(
+    request: Json<RegisterRequest>,
+    rate_limit: RateLimit<'_>,
+    audit: Audit<'_>,
+    state: &State<ServerStateArc>,
+    policy: &State<IssuancePolicy>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    challenges: &State<RegistrationChallengesArc>,
+    mut db: DbConnection,
+) -> Result<Created<Json<RegisterResponse>>, Custom<String>> {
+    audit.record(AuditOperation::Sign);
+    audit.email(&request.email);
+    rate_limit.check(&request.email)?;
+    policy.check(&request.email)?;
+    let device = request.device.as_deref().unwrap_or(DEFAULT_DEVICE);
+    if device.is_empty() || device.len() > MAX_DEVICE_LENGTH {
+        return Err(Custom(
+            Status::BadRequest,
+            format!(
+                "The device label must have between 1 and {} characters",
+                MAX_DEVICE_LENGTH
+            ),
+        ));
+    }
+    let profile = match request.profile.as_deref() {
+        Some(name) => name
+            .parse::<CertificateProfile>()
+            .map_err(|e| Custom(Status::BadRequest, e))?,
+        None => CertificateProfile::default(),
+    };
+    if !state.profiles.contains(&profile) {
+        return Err(Custom(
+            Status::Forbidden,
+            format!(
+                "The profile `{}` is not enabled for the registrations",
+                profile
+            ),
+        ));
+    }
+    check_proof_of_possession(
+        challenges,
+        &request.email,
+        &request.certificate_request,
+        &request.challenge,
+        &request.challenge_signature,
+        unix_time(),
+    )?;
+    log::debug!("Received certificate request for email {:?}", request.email);
+    let (response, _) = register_device(
+        &request.email,
+        device,
+        profile,
+        &request.certificate_request,
+        &audit,
+        state,
+        validity,
+        webhooks,
+        &mut db,
+    )
+    .await?;
+    let create_response = Created::new("https://localhost:8000/credential");
+    Ok(Created::body(create_response, Json(response)))
+}
+
+/// Sign the certificate request for the device of the email with the profile, then log and store the certificate.
+/// Only a revoked certificate of the device can be replaced, it is then linked to the new one.
+/// Returns the response of the registration and the DER encoded certificate.
+#[allow(clippy::too_many_arguments)]
+async fn register_device(
+    email: &str,
+    device: &str,
+    profile: CertificateProfile,
+    certificate_request: &str,
+    audit: &Audit<'_>,
+    state: &State<ServerStateArc>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    db: &mut DbConnection,
+) -> Result<(RegisterResponse, Vec<u8>), Custom<String>> {
+    let replaces = match find_certificate_by_device(email, device, db).await {
+        Ok(Some(current)) if current.revoked_at.is_none() => {
+            return Err(Custom(
+                Status::Conflict,
+                "Client device already registered".to_string(),
+            ))
+        }
+        Ok(current) => current.map(|revoked| revoked.serial),
+        Err(e) => {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            return Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ));
+        }
+    };
+    let SignedCertificate { issued, der, chain } =
+        sign_certificate_request(certificate_request, email, profile, state, validity)
+            .await
+            .map_err(sign_error_response)?;
+    let response = RegisterResponse {
+        certificate: issued.certificate.clone(),
+        chain,
+    };
+    audit.serial(&issued.serial);
+    log_issuance(email, &issued.certificate, &der, &issued.fingerprint, db).await?;
+    match insert_certificate(email, device, &issued, replaces.as_deref(), db).await {
+        Ok(()) => {
+            METRICS.certificates_issued.inc();
+            if let Some(webhooks) = webhooks.inner() {
+                webhooks.notify(&LifecycleEvent::issued(
+                    email,
+                    device,
+                    &issued,
+                    replaces.as_deref(),
+                ));
+            }
+            if let Some(replaces) = &replaces {
+                log::info!(
+                    "Re-issued the revoked certificate `{}` of `{}`, device `{}`",
+                    replaces,
+                    email,
+                    device
+                );
+            }
+            log::debug!(
+                "Registered client with email: `{}`, device `{}`, certificate `{:?}`",
+                email,
+                device,
+                response
+            );
+            Ok((response, der))
+        }
+        Err(e) => {
+            // Since we already performed validation on the request, we can assume the error is due to a duplicate device
+            // registered concurrently. The db schema should have a unique constraint on the email and active device fields.
+            log::error!("Error inserting the certificate in the DB: {:?}", e);
+            Err(Custom(
+                Status::Conflict,
+                "Client device already registered".to_string(),
+            ))
+        }
+    }
+}
+
+/// Renew a client's certificate, replacing it with a new one for the same email and device.
+/// The client authenticates with mutual TLS using its current certificate, that must not be revoked,
+/// and sends a certificate request in PEM format, usually for a new key pair.
+/// The new certificate is appended to the transparency log before being returned.
+#[utoipa::path(
+    post,
+    path = "/ca/renew",
+    request_body = RenewRequest,
+    responses(
+        (status = 200, description = "Renewed the client's certificate.", body = RenewResponse),
+        (status = 400, description = "Bad Request"),
+        (status = 401, description = "Unauthorized, no client certificate"),
+        (status = 403, description = "Forbidden, not the current valid certificate of the client or the email is not allowed by the issuance policy"),
+        (status = 404, description = "Not Found"),
+        (status = 409, description = "Conflict, the certificate was renewed or revoked concurrently"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+    )
+)]
+#[post("/ca/renew", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn renew(
+    request: Json<RenewRequest>,
+    client_certificate: Certificate<'_>,
+    rate_limit: RateLimit<'_>,
+    audit: Audit<'_>,
+    state: &State<ServerStateArc>,
+    policy: &State<IssuancePolicy>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    mut db: DbConnection,
+) -> Result<Json<RenewResponse>, Custom<String>> {
+    log::debug!("Received renewal request for email {:?}", request.email);
+    audit.record(AuditOperation::Sign);
+    audit.email(&request.email);
+    rate_limit.check(&request.email)?;
+    // The emails denied after the issuance can no longer renew their certificates.
+    policy.check(&request.email)?;
+    let current_fingerprint = fingerprint_of_certificate(client_certificate.as_bytes());
+    let current = match find_certificate_by_fingerprint(&current_fingerprint, &mut db).await {
+        Ok(Some(current)) => current,
+        Ok(None) => {
+            return Err(Custom(
+                Status::NotFound,
+                "The client certificate is not registered".to_string(),
+            ))
+        }
+        Err(e) => {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            return Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ));
+        }
+    };
+    if current.revoked_at.is_some() || current.email != request.email {
+        return Err(Custom(
+            Status::Forbidden,
+            "Only the holder of the current valid certificate can renew it".to_string(),
+        ));
+    }
+    // The certificate is renewed with the profile it was issued with.
+    let profile = current.profile.parse::<CertificateProfile>().map_err(|e| {
+        log::error!("Invalid profile of the certificate in the DB: {}", e);
+        Custom(
+            Status::InternalServerError,
+            "Internal Server Error".to_string(),
+        )
+    })?;
+    let SignedCertificate { issued, der, chain } = sign_certificate_request(
+        &request.certificate_request,
+        &request.email,
+        profile,
+        state,
+        validity,
+    )
+    .await
+    .map_err(sign_error_response)?;
+    audit.serial(&issued.serial);
+    log_issuance(
+        &request.email,
+        &issued.certificate,
+        &der,
+        &issued.fingerprint,
+        &mut db,
+    )
+    .await?;
+    match renew_certificate(current.id, &current_fingerprint, &issued, &mut db).await {
+        Ok(true) => {
+            METRICS.certificates_renewed.inc();
+            if let Some(webhooks) = webhooks.inner() {
+                webhooks.notify(&LifecycleEvent::renewed(
+                    &request.email,
+                    &current.device,
+                    &issued,
+                    &current.serial,
+                ));
+            }
+            log::debug!(
+                "Renewed the certificate of `{}`, device `{}`",
+                &request.email,
+                &current.device
+            );
+            Ok(Json(RenewResponse {
+                certificate: issued.certificate,
+                chain,
+            }))
+        }
+        Ok(false) => Err(Custom(
+            Status::Conflict,
+            "Certificate renewed or revoked concurrently".to_string(),
+        )),
+        Err(e) => {
+            log::error!("Error updating the certificate in the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// Return the chain of the CA as an EST `cacerts` response, see [RFC 7030](https://www.rfc-editor.org/rfc/rfc7030#section-4.1).
+/// The certificates are in a base64 encoded PKCS#7 `certs-only` message, from the issuing CA to the root CA.
+#[utoipa::path(
+    get,
+    path = "/.well-known/est/cacerts",
+    responses(
+        (status = 200, description = "Base64 encoded PKCS#7 certs-only message", content_type = "application/pkcs7-mime"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/.well-known/est/cacerts")]
+pub fn est_cacerts(state: &State<ServerStateArc>) -> Result<CertsOnlyResponse, Status> {
+    let certificates = state.chain_der().map_err(|e| {
+        log::error!("Error decoding the chain of the CA: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(CertsOnlyResponse(mk_certs_only(&certificates)))
+}
+
+/// Enroll a client with EST, see [RFC 7030](https://www.rfc-editor.org/rfc/rfc7030#section-4.2), e.g. from an MDM tool.
+/// The client sends a base64 encoded DER certificate request, with its email in the Subject alt names and
+/// the challenge obtained from `/ca/register/challenge` as its challenge password, proving the possession of the key.
+/// The certificate is registered as the `est` device of the email, as by `/ca/register`, and returned
+/// in a base64 encoded PKCS#7 `certs-only` message.
+#[utoipa::path(
+    post,
+    path = "/.well-known/est/simpleenroll",
+    request_body(content = String, description = "Base64 encoded DER certificate request", content_type = "application/pkcs10"),
+    responses(
+        (status = 200, description = "Base64 encoded PKCS#7 certs-only message with the certificate", content_type = "application/pkcs7-mime"),
+        (status = 400, description = "Bad Request"),
+        (status = 403, description = "Forbidden, the email is not allowed by the issuance policy or the challenge password is not a registration challenge of the email"),
+        (status = 409, description = "Conflict, the email has already a certificate enrolled with EST which is not revoked"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+    )
+)]
+#[post("/.well-known/est/simpleenroll", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn est_simpleenroll(
+    body: Vec<u8>,
+    rate_limit: RateLimit<'_>,
+    audit: Audit<'_>,
+    state: &State<ServerStateArc>,
+    policy: &State<IssuancePolicy>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    challenges: &State<RegistrationChallengesArc>,
+    mut db: DbConnection,
+) -> Result<CertsOnlyResponse, Custom<String>> {
+    audit.record(AuditOperation::Sign);
+    let certificate_request = decode_body(&body)
+        .map(certificate_request_pem)
+        .map_err(|e| {
+            log::debug!("Received an invalid EST certificate request: {}", e);
+            Custom(
+                Status::BadRequest,
+                "The certificate request must be base64 encoded DER".to_string(),
+            )
+        })?;
+    let email = retrieve_emails_from_certificate_request(&certificate_request)
+        .ok()
+        .and_then(|emails| emails.into_iter().next())
+        .ok_or_else(|| {
+            Custom(
+                Status::BadRequest,
+                "The certificate request must have an email in the Subject alt names".to_string(),
+            )
+        })?;
+    audit.email(&email);
+    rate_limit.check(&email)?;
+    policy.check(&email)?;
+    check_challenge_password(challenges, &email, &certificate_request, unix_time())?;
+    log::debug!("Received EST enrollment for email {:?}", email);
+    let (_, der) = register_device(
+        &email,
+        EST_DEVICE,
+        CertificateProfile::ClientAuth,
+        &certificate_request,
+        &audit,
+        state,
+        validity,
+        webhooks,
+        &mut db,
+    )
+    .await?;
+    Ok(CertsOnlyResponse(mk_certs_only(&[der])))
+}
+
+/// Verify a client's certificate.
+/// The client sends a certificate to be verified in PEM format.
+#[utoipa::path(
+    post,
+    path = "/ca/verify",
+    request_body = VerifyRequest,
+    responses(
+        (status = 200, description = "Whether the client's certificate is valid or not.", body = VerifyResponse),
+    )
+)]
+#[post("/ca/verify", data = "<request>")]
+pub async fn verify(
+    request: Json<VerifyRequest>,
+    state: &State<ServerStateArc>,
+    audit: Audit<'_>,
+    mut db: DbConnection,
+) -> Json<VerifyResponse> {
+    log::debug!(
+        "Received certificate for verification: {:?}",
+        &request.certificate
+    );
+    audit.record(AuditOperation::Verify);
+    if let Some(email) = retrieve_emails_from_certificate(&request.certificate)
+        .ok()
+        .and_then(|emails| emails.into_iter().next())
+    {
+        audit.email(&email);
+    }
+    if let Ok(serial) = serial_of_certificate(&request.certificate) {
+        audit.serial(&hex(&serial));
+    }
+    let response = verify_certificate(&request.certificate, state, &mut db).await;
+    audit.outcome(match response {
+        VerifyResponse { valid: true, .. } => "valid",
+        VerifyResponse { expired: true, .. } => "expired",
+        VerifyResponse { revoked: true, .. } => "revoked",
+        _ => "invalid",
+    });
+    Json(response)
+}
+
+/// Verify that the PEM encoded certificate is signed by the CA, not expired and not revoked.
+/// The duration of the verification is recorded in the metrics.
+async fn verify_certificate(
+    certificate: &str,
+    state: &State<ServerStateArc>,
+    db: &mut DbConnection,
+) -> VerifyResponse {
+    let started = Instant::now();
+    let response = check_certificate(certificate, state, db).await;
+    METRICS.verification_duration.observe(started.elapsed());
+    response
+}
+
+async fn check_certificate(
+    certificate: &str,
+    state: &State<ServerStateArc>,
+    db: &mut DbConnection,
+) -> VerifyResponse {
+    let invalid = VerifyResponse {
+        valid: false,
+        signed: false,
+        expired: false,
+        revoked: false,
+    };
+    let signed = match check_signature(certificate, &state.ca_cert_pem()) {
+        Ok(signed) => signed,
+        Err(e) => {
+            log::error!("Error verifying the certificate: {:?}", e);
+            false
+        }
+    };
+    if !signed {
+        return invalid;
+    }
+    let expired = !check_validity(certificate, OffsetDateTime::now_utc()).unwrap_or(false);
+    if expired {
+        log::debug!("The certificate is expired or not yet valid");
+    }
+    // A certificate signed by the CA is still not valid once revoked.
+    let Ok(der) = pem::parse(certificate) else {
+        return invalid;
+    };
+    let revoked = match find_certificate_by_fingerprint(
+        &fingerprint_of_certificate(der.contents()),
+        db,
+    )
+    .await
+    {
+        Ok(Some(stored)) if stored.revoked_at.is_some() => {
+            log::debug!(
+                "The certificate of `{}`, device `{}` is revoked",
+                stored.email,
+                stored.device
+            );
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            log::error!("Error checking the revocation of the certificate: {:?}", e);
+            return VerifyResponse {
+                signed,
+                expired,
+                ..invalid
+            };
+        }
+    };
+    VerifyResponse {
+        valid: !expired && !revoked,
+        signed,
+        expired,
+        revoked,
+    }
+}
+
+/// Verify a batch of clients' certificates at once, e.g. for the DS validating many reconnecting clients.
+/// Each certificate is verified as by `/ca/verify`, the results are in the order of the request.
+#[utoipa::path(
+    post,
+    path = "/ca/verify/batch",
+    request_body = VerifyBatchRequest,
+    responses(
+        (status = 200, description = "The verification of each certificate.", body = VerifyBatchResponse),
+        (status = 400, description = "Bad Request, too many certificates"),
+    )
+)]
+#[post("/ca/verify/batch", data = "<request>")]
+pub async fn verify_batch(
+    request: Json<VerifyBatchRequest>,
+    state: &State<ServerStateArc>,
+    audit: Audit<'_>,
+    mut db: DbConnection,
+) -> Result<Json<VerifyBatchResponse>, Custom<String>> {
+    if request.certificates.len() > MAX_VERIFY_BATCH {
+        return Err(Custom(
+            Status::BadRequest,
+            format!(
+                "At most {} certificates can be verified at once",
+                MAX_VERIFY_BATCH
+            ),
+        ));
+    }
+    audit.record(AuditOperation::Verify);
+    audit.outcome("batch");
+    let mut results = Vec::with_capacity(request.certificates.len());
+    for certificate in &request.certificates {
+        results.push(verify_certificate(certificate, state, &mut db).await);
+    }
+    Ok(Json(VerifyBatchResponse { results }))
+}
+
+/// Revoke a client's certificate, so that it is listed in the CRL.
+/// The caller authenticates with mutual TLS, either as the holder of the certificate or as an admin.
+#[utoipa::path(
+    post,
+    path = "/ca/revoke",
+    request_body = RevokeRequest,
+    responses(
+        (status = 200, description = "Revoked the client's certificate.", body = RevokeResponse),
+        (status = 401, description = "Unauthorized, no client certificate"),
+        (status = 403, description = "Forbidden, neither the holder of the certificate nor an admin"),
+        (status = 404, description = "Not Found"),
+        (status = 409, description = "Conflict, the certificate is already revoked"),
+    )
+)]
+#[post("/ca/revoke", data = "<request>")]
+pub async fn revoke(
+    request: Json<RevokeRequest>,
+    client_certificate: Certificate<'_>,
+    admins: &State<PkiAdmins>,
+    ds_notifier: &State<OptionalDsNotifier>,
+    webhooks: &State<OptionalWebhooks>,
+    audit: Audit<'_>,
+    mut db: DbConnection,
+) -> Result<Json<RevokeResponse>, Custom<String>> {
+    audit.record(AuditOperation::Revoke);
+    audit.email(&request.email);
+    let device = request.device.as_deref().unwrap_or(DEFAULT_DEVICE);
+    log::debug!(
+        "Received revocation request for email {:?}, device {:?}",
+        request.email,
+        device
+    );
+    let stored = match find_certificate_by_device(&request.email, device, &mut db).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => {
+            return Err(Custom(
+                Status::NotFound,
+                format!(
+                    "Requested client `{}` has no device `{}` registered",
+                    &request.email, device
+                ),
+            ))
+        }
+        Err(e) => {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            return Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ));
+        }
+    };
+    audit.serial(&stored.serial);
+    let is_holder = fingerprint_of_certificate(client_certificate.as_bytes()) == stored.fingerprint;
+    if !is_holder && !admins.is_admin(&client_certificate) {
+        return Err(Custom(
+            Status::Forbidden,
+            "Only the holder of the certificate or an admin can revoke it".to_string(),
+        ));
+    }
+    let revoked_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let reason = request.reason.unwrap_or_default();
+    match revoke_certificate(stored.id, revoked_at, reason.code(), &mut db).await {
+        Ok(true) => {
+            METRICS.certificates_revoked.inc();
+            log::info!(
+                "Revoked the certificate of `{}`, device `{}`: {:?}",
+                &request.email,
+                device,
+                reason
+            );
+            if let Some(ds_notifier) = ds_notifier.inner() {
+                ds_notifier.notify_revocation();
+            }
+            if let Some(webhooks) = webhooks.inner() {
+                webhooks.notify(&LifecycleEvent::revoked(&stored, reason));
+            }
+            Ok(Json(RevokeResponse { revoked_at }))
+        }
+        Ok(false) => Err(Custom(
+            Status::Conflict,
+            "Certificate already revoked".to_string(),
+        )),
+        Err(e) => {
+            log::error!("Error revoking the certificate in the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// Return the certificate revocation list (CRL) of the CA, DER encoded.
+/// The CRL is signed by the CA and generated on each request.
+#[utoipa::path(
+    get,
+    path = "/ca/crl",
+    responses(
+        (status = 200, description = "DER encoded CRL", content_type = "application/pkix-crl"),
+    )
+)]
+#[get("/ca/crl")]
+pub async fn get_crl(
+    state: &State<ServerStateArc>,
+    db: DbConnection,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let revoked = list_revoked_certificates(db).await.map_err(|e| {
+        log::error!("Error listing the revoked certificates: {:?}", e);
+        Status::InternalServerError
+    })?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let crl = sign_blocking(state, move |state| mk_crl(&state.ca_cert, &revoked, now))
+        .await
+        .and_then(|crl| crl)
+        .map_err(|e| {
+            log::error!("Error generating the CRL: {}", e);
+            Status::InternalServerError
+        })?;
+    Ok((ContentType::new("application", "pkix-crl"), crl))
+}
+
+/// Answer an OCSP request with the status of the certificates issued by the CA, see [RFC 6960](https://www.rfc-editor.org/rfc/rfc6960).
+/// The response is signed by the CA, the failures are reported in the OCSP response status.
+#[utoipa::path(
+    post,
+    path = "/ca/ocsp",
+    request_body(content = Vec<u8>, description = "DER encoded OCSP request", content_type = "application/ocsp-request"),
+    responses(
+        (status = 200, description = "DER encoded OCSP response", content_type = "application/ocsp-response"),
+    )
+)]
+#[post("/ca/ocsp", data = "<request>")]
+pub async fn ocsp(
+    request: Vec<u8>,
+    state: &State<ServerStateArc>,
+    mut db: DbConnection,
+) -> (ContentType, Vec<u8>) {
+    let content_type = ContentType::new("application", "ocsp-response");
+    let cert_ids = match parse_ocsp_request(&request) {
+        Ok(cert_ids) => cert_ids,
+        Err(e) => {
+            log::debug!("Received an invalid OCSP request: {}", e);
+            return (
+                content_type,
+                mk_ocsp_error(OcspResponseStatus::MalformedRequest),
+            );
         }
     };
-    Json(VerifyResponse { valid: verified })
+    let issued = cert_ids
+        .iter()
+        .map(|cert_id| cert_id.is_issued_by(&state.ca_cert))
+        .collect::<Vec<_>>();
+    let mut statuses = Vec::with_capacity(cert_ids.len());
+    for (cert_id, issued) in cert_ids.into_iter().zip(issued) {
+        let status = if !issued {
+            CertStatus::Unknown
+        } else {
+            match find_certificate_by_serial(&hex(cert_id.serial()), &mut db).await {
+                Ok(Some(certificate)) => match certificate.revoked_at {
+                    Some(revoked_at) => CertStatus::Revoked {
+                        revoked_at,
+                        reason: certificate.revocation_reason.unwrap_or_default(),
+                    },
+                    None => CertStatus::Good,
+                },
+                Ok(None) => CertStatus::Unknown,
+                Err(e) => {
+                    log::error!("Error reading the certificate from the DB: {:?}", e);
+                    return (
+                        content_type,
+                        mk_ocsp_error(OcspResponseStatus::InternalError),
+                    );
+                }
+            }
+        };
+        statuses.push((cert_id, status));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let response = sign_blocking(state, move |state| {
+        mk_ocsp_response(&state.ca_cert, state.signer.as_ref(), &statuses, now)
+    })
+    .await
+    .and_then(|response| response)
+    .unwrap_or_else(|e| {
+        log::error!("Error generating the OCSP response: {}", e);
+        mk_ocsp_error(OcspResponseStatus::InternalError)
+    });
+    (content_type, response)
+}
+
+/// Extract the details of a certificate signed by the CA to be stored in the database.
+fn issued_certificate(
+    cert: &rcgen::Certificate,
+    profile: CertificateProfile,
+) -> Result<IssuedCertificate, String> {
+    let (_, x509) = X509Certificate::from_der(cert.der()).map_err(|e| e.to_string())?;
+    let validity = x509.validity();
+    Ok(IssuedCertificate {
+        certificate: cert.pem(),
+        fingerprint: fingerprint_of_certificate(cert.der()),
+        spki_sha256: hex(&spki_sha256_of_certificate(cert.der())?),
+        serial: hex(x509.raw_serial()),
+        profile,
+        not_before: u64::try_from(validity.not_before.timestamp()).map_err(|e| e.to_string())?,
+        not_after: u64::try_from(validity.not_after.timestamp()).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Append the issuance of a certificate to the transparency log.
+/// Certificates are logged before being stored and returned, so that none is handed out without being logged.
+async fn log_issuance(
+    email: &str,
+    certificate: &str,
+    der_certificate: &[u8],
+    fingerprint: &str,
+    db: &mut DbConnection,
+) -> Result<(), Custom<String>> {
+    let logged_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let internal_error = || {
+        Custom(
+            Status::InternalServerError,
+            "Internal Server Error".to_string(),
+        )
+    };
+    let leaf_hash = leaf_hash(email, der_certificate, logged_at).map_err(|e| {
+        log::error!("Error hashing the transparency log entry: {}", e);
+        internal_error()
+    })?;
+    append_log_entry(email, certificate, fingerprint, logged_at, &leaf_hash, db)
+        .await
+        .map_err(|e| {
+            log::error!(
+                "Error appending the certificate to the transparency log: {:?}",
+                e
+            );
+            internal_error()
+        })
+}
+
+/// Load the leaf hashes of the transparency log, checking that the log has at least `tree_size` entries.
+async fn load_log_leaves(
+    tree_size: Option<u64>,
+    db: &mut DbConnection,
+) -> Result<Vec<Hash>, Custom<String>> {
+    let leaves = list_log_leaf_hashes(tree_size, db).await.map_err(|e| {
+        log::error!("Error reading the transparency log from the DB: {:?}", e);
+        Custom(
+            Status::InternalServerError,
+            "Internal Server Error".to_string(),
+        )
+    })?;
+    if tree_size.is_some_and(|size| size > leaves.len() as u64) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("The log has only {} entries", leaves.len()),
+        ));
+    }
+    leaves
+        .iter()
+        .map(|leaf| hash_from_slice(leaf))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            log::error!("Invalid leaf hash in the transparency log");
+            Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            )
+        })
+}
+
+/// Return the signed tree head of the transparency log of the issued certificates.
+/// The tree head is signed by the CA key, it can be verified with the public key of the CA certificate.
+#[utoipa::path(
+    get,
+    path = "/ca/log/sth",
+    responses(
+        (status = 200, description = "The signed tree head", body = SignedTreeHead),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/log/sth")]
+pub async fn get_signed_tree_head(
+    state: &State<ServerStateArc>,
+    mut db: DbConnection,
+) -> Result<Json<SignedTreeHead>, Custom<String>> {
+    let leaves = load_log_leaves(None, &mut db).await?;
+    let root_hash = root_hash(&leaves);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let tree_size = leaves.len() as u64;
+    let signature_input = tree_head_signature_input(timestamp, tree_size, &root_hash);
+    match sign_blocking(state, move |state| {
+        sign(state.signer.as_ref(), &signature_input).map(|(_, signature)| signature)
+    })
+    .await
+    .and_then(|signature| signature)
+    {
+        Ok(signature) => Ok(Json(SignedTreeHead {
+            tree_size,
+            timestamp,
+            root_hash: hex(&root_hash),
+            signature: hex(&signature),
+        })),
+        Err(e) => {
+            log::error!("Error signing the tree head: {}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// Return the proof that the certificate with the given fingerprint is included in the transparency log.
+/// The proof is for the tree with `tree_size` entries, the current tree if missing.
+#[utoipa::path(
+    get,
+    path = "/ca/log/inclusion",
+    params(
+        ("fingerprint" = String, Query, description = "The hex encoded SHA-256 fingerprint of the DER encoded certificate"),
+        ("tree_size" = Option<u64>, Query, description = "The size of the tree, the current size if missing"),
+    ),
+    responses(
+        (status = 200, description = "The inclusion proof", body = InclusionProofResponse),
+        (status = 400, description = "Bad Request, the log is smaller than the tree size"),
+        (status = 404, description = "Not Found, the certificate is not in the tree"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/log/inclusion?<fingerprint>&<tree_size>")]
+pub async fn get_inclusion_proof(
+    fingerprint: &str,
+    tree_size: Option<u64>,
+    mut db: DbConnection,
+) -> Result<Json<InclusionProofResponse>, Custom<String>> {
+    let fingerprint = fingerprint.to_ascii_lowercase();
+    let not_found = || {
+        Custom(
+            Status::NotFound,
+            format!(
+                "No certificate with fingerprint `{}` in the log",
+                fingerprint
+            ),
+        )
+    };
+    let leaf_index = match find_log_index_by_fingerprint(&fingerprint, &mut db).await {
+        Ok(Some(leaf_index)) => leaf_index,
+        Ok(None) => return Err(not_found()),
+        Err(e) => {
+            log::error!("Error reading the transparency log from the DB: {:?}", e);
+            return Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ));
+        }
+    };
+    let leaves = load_log_leaves(tree_size, &mut db).await?;
+    let audit_path = inclusion_proof(leaf_index as usize, &leaves).ok_or_else(not_found)?;
+    Ok(Json(InclusionProofResponse {
+        leaf_index,
+        tree_size: leaves.len() as u64,
+        audit_path: audit_path.iter().map(|hash| hex(hash)).collect(),
+    }))
+}
+
+/// Return the proof that the transparency log with `first` entries is a prefix of the log with `second` entries.
+/// Clients keep the last tree head they have seen and check that the log was only appended to since.
+#[utoipa::path(
+    get,
+    path = "/ca/log/consistency",
+    params(
+        ("first" = u64, Query, description = "The size of the older tree"),
+        ("second" = u64, Query, description = "The size of the newer tree"),
+    ),
+    responses(
+        (status = 200, description = "The consistency proof", body = ConsistencyProofResponse),
+        (status = 400, description = "Bad Request, invalid tree sizes"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/log/consistency?<first>&<second>")]
+pub async fn get_consistency_proof(
+    first: u64,
+    second: u64,
+    mut db: DbConnection,
+) -> Result<Json<ConsistencyProofResponse>, Custom<String>> {
+    if first == 0 || first > second {
+        return Err(Custom(
+            Status::BadRequest,
+            "The sizes must satisfy 0 < first <= second".to_string(),
+        ));
+    }
+    let leaves = load_log_leaves(Some(second), &mut db).await?;
+    let proof = consistency_proof(first as usize, &leaves).ok_or_else(|| {
+        Custom(
+            Status::BadRequest,
+            "The sizes must satisfy 0 < first <= second".to_string(),
+        )
+    })?;
+    Ok(Json(ConsistencyProofResponse {
+        first,
+        second,
+        proof: proof.iter().map(|hash| hex(hash)).collect(),
+    }))
+}
+
+/// Return the entries of the transparency log with index in `start..end`, at most 256 at once.
+/// Clients can monitor the log for certificates issued for their email without their knowledge.
+#[utoipa::path(
+    get,
+    path = "/ca/log/entries",
+    params(
+        ("start" = u64, Query, description = "The index of the first entry"),
+        ("end" = u64, Query, description = "The index following the last entry"),
+    ),
+    responses(
+        (status = 200, description = "The entries of the log", body = GetLogEntriesResponse),
+        (status = 400, description = "Bad Request, invalid range"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/log/entries?<start>&<end>")]
+pub async fn get_log_entries(
+    start: u64,
+    end: u64,
+    db: DbConnection,
+) -> Result<Json<GetLogEntriesResponse>, Custom<String>> {
+    if start >= end {
+        return Err(Custom(
+            Status::BadRequest,
+            "The range must satisfy start < end".to_string(),
+        ));
+    }
+    let end = end.min(start.saturating_add(MAX_LOG_ENTRIES));
+    match list_log_entries(start, end, db).await {
+        Ok(entries) => Ok(Json(GetLogEntriesResponse {
+            entries: entries
+                .into_iter()
+                .map(|entry| LogEntry {
+                    leaf_index: entry.leaf_index,
+                    email: entry.email,
+                    certificate: entry.certificate,
+                    logged_at: entry.logged_at,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!("Error reading the transparency log from the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// Return the events of the audit log, the most recent first, at most 256 at once.
+/// Only the admins can read the audit log, authenticating with mutual TLS.
+#[utoipa::path(
+    get,
+    path = "/ca/audit",
+    params(
+        ("email" = Option<String>, Query, description = "Only the events about the certificates of the email"),
+        ("serial" = Option<String>, Query, description = "Only the events about the certificate with the hex encoded serial number"),
+        ("operation" = Option<AuditOperation>, Query, description = "Only the events of the operation"),
+        ("before" = Option<u64>, Query, description = "Only the events preceding the one with this id, to page through the log"),
+        ("limit" = Option<u64>, Query, description = "The maximum number of events, at most 256"),
+    ),
+    responses(
+        (status = 200, description = "The events of the audit log", body = GetAuditLogResponse),
+        (status = 401, description = "Unauthorized, no client certificate"),
+        (status = 403, description = "Forbidden, not an admin"),
+        (status = 500, description = "Internal Server Error"),
+    )
+)]
+#[get("/ca/audit?<email>&<serial>&<operation>&<before>&<limit>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn get_audit_log(
+    email: Option<&str>,
+    serial: Option<&str>,
+    operation: Option<AuditOperation>,
+    before: Option<u64>,
+    limit: Option<u64>,
+    client_certificate: Certificate<'_>,
+    admins: &State<PkiAdmins>,
+    db: DbConnection,
+) -> Result<Json<GetAuditLogResponse>, Custom<String>> {
+    if !admins.is_admin(&client_certificate) {
+        return Err(Custom(
+            Status::Forbidden,
+            "Only the admins can read the audit log".to_string(),
+        ));
+    }
+    let serial = serial.map(|serial| serial.to_ascii_lowercase());
+    let limit = limit.unwrap_or(MAX_AUDIT_EVENTS).min(MAX_AUDIT_EVENTS);
+    match list_audit_events(
+        email,
+        serial.as_deref(),
+        operation.map(AuditOperation::as_str),
+        before,
+        limit,
+        db,
+    )
+    .await
+    {
+        Ok(events) => Ok(Json(GetAuditLogResponse {
+            events: events
+                .into_iter()
+                .map(|event| AuditEvent {
+                    id: event.id,
+                    logged_at: event.logged_at,
+                    operation: event.operation,
+                    requester: event.requester,
+                    requester_ip: event.requester_ip,
+                    email: event.email,
+                    serial: event.serial,
+                    status: event.status,
+                    outcome: event.outcome,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            log::error!("Error reading the audit log from the DB: {:?}", e);
+            Err(Custom(
+                Status::InternalServerError,
+                "Internal Server Error".to_string(),
+            ))
+        }
+    }
+}
+
+/// The seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// The ACME directory, listing the URLs of the ACME resources.
+#[utoipa::path(
+    get,
+    path = "/acme/directory",
+    responses(
+        (status = 200, description = "The ACME directory"),
+    )
+)]
+#[get("/acme/directory")]
+pub fn acme_directory(config: &State<AcmeConfig>) -> Json<DirectoryObject> {
+    Json(DirectoryObject::new(config))
+}
+
+/// Return a fresh nonce in the `Replay-Nonce` header, every ACME request must carry an unused nonce.
+#[utoipa::path(
+    get,
+    path = "/acme/new-nonce",
+    responses(
+        (status = 204, description = "A new nonce in the `Replay-Nonce` header"),
+    )
+)]
+#[get("/acme/new-nonce")]
+pub fn acme_new_nonce() -> AcmeResponse {
+    AcmeResponse::new(Status::NoContent, ContentType::Plain, Vec::new())
+}
+
+/// Return a fresh nonce in the `Replay-Nonce` header, as clients usually fetch it with a HEAD request.
+#[utoipa::path(
+    head,
+    path = "/acme/new-nonce",
+    responses(
+        (status = 200, description = "A new nonce in the `Replay-Nonce` header"),
+    )
+)]
+#[head("/acme/new-nonce")]
+pub fn acme_head_new_nonce() -> AcmeResponse {
+    AcmeResponse::new(Status::Ok, ContentType::Plain, Vec::new())
+}
+
+/// Create an ACME account for the key signing the request, or return the existing one.
+#[utoipa::path(
+    post,
+    path = "/acme/new-account",
+    request_body(content = String, description = "JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The existing account of the key"),
+        (status = 201, description = "Created the account"),
+        (status = 400, description = "Bad Request"),
+    )
+)]
+#[post("/acme/new-account", data = "<body>")]
+pub fn acme_new_account(
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let request = acme.verify_request(&body, &config.url(NEW_ACCOUNT_PATH), config)?;
+    let Some(jwk) = request.jwk.clone() else {
+        return Err(Problem::malformed(
+            "A new account request must be signed with a JWK",
+        ));
+    };
+    let payload: NewAccountPayload = request.payload()?;
+    let id = jwk.thumbprint();
+    let location = config.url(&format!("{}{}", ACCOUNT_PATH, id));
+    if let Some(account) = acme.accounts.get(&id) {
+        return Ok(AcmeResponse::json(Status::Ok, &account.to_object())?.with_location(location));
+    }
+    if payload.only_return_existing {
+        return Err(Problem::new(
+            Status::BadRequest,
+            "accountDoesNotExist",
+            "The account does not exist",
+        ));
+    }
+    let account = Account {
+        jwk,
+        contact: payload.contact,
+    };
+    let response =
+        AcmeResponse::json(Status::Created, &account.to_object())?.with_location(location);
+    acme.accounts.insert(id, account);
+    Ok(response)
+}
+
+/// Return the ACME account, updating its contacts if the request has a payload.
+#[utoipa::path(
+    post,
+    path = "/acme/account/{id}",
+    params(("id" = String, Path, description = "The account identifier")),
+    request_body(content = String, description = "JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The account"),
+        (status = 403, description = "Forbidden, not the key of the account"),
+    )
+)]
+#[post("/acme/account/<id>", data = "<body>")]
+pub fn acme_account(
+    id: &str,
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let url = config.url(&format!("{}{}", ACCOUNT_PATH, id));
+    let request = acme.verify_request(&body, &url, config)?;
+    if request.account()? != id {
+        return Err(Problem::unauthorized("Not the key of the account"));
+    }
+    let contact = if request.is_post_as_get() {
+        None
+    } else {
+        Some(request.payload::<NewAccountPayload>()?.contact)
+    };
+    let account = acme
+        .accounts
+        .get_mut(id)
+        .ok_or_else(|| Problem::not_found("No such account"))?;
+    if let Some(contact) = contact {
+        account.contact = contact;
+    }
+    AcmeResponse::json(Status::Ok, &account.to_object())
+}
+
+/// Create an order of a certificate for an email identifier, allowed by the issuance policy.
+#[utoipa::path(
+    post,
+    path = "/acme/new-order",
+    request_body(content = String, description = "JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 201, description = "Created the order"),
+        (status = 400, description = "Bad Request, only a single email identifier is supported"),
+        (status = 403, description = "Forbidden, the email is not allowed by the issuance policy"),
+    )
+)]
+#[post("/acme/new-order", data = "<body>")]
+pub fn acme_new_order(
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+    policy: &State<IssuancePolicy>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let request = acme.verify_request(&body, &config.url(NEW_ORDER_PATH), config)?;
+    let account = request.account()?;
+    let payload: NewOrderPayload = request.payload()?;
+    let email = order_email(&payload.identifiers)?;
+    if !policy.allows(&email) {
+        return Err(Problem::new(
+            Status::Forbidden,
+            "rejectedIdentifier",
+            format!(
+                "The email `{}` is not allowed by the issuance policy",
+                email
+            ),
+        ));
+    }
+    let id = acme.new_order(account, &email, unix_time());
+    let order = acme.orders[&id].to_object(&id, config);
+    Ok(AcmeResponse::json(Status::Created, &order)?
+        .with_location(config.url(&format!("{}{}", ORDER_PATH, id))))
+}
+
+/// Return an ACME order of the account.
+#[utoipa::path(
+    post,
+    path = "/acme/order/{id}",
+    params(("id" = String, Path, description = "The order identifier")),
+    request_body(content = String, description = "POST-as-GET JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The order"),
+        (status = 404, description = "Not Found"),
+    )
+)]
+#[post("/acme/order/<id>", data = "<body>")]
+pub fn acme_order(
+    id: &str,
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let url = config.url(&format!("{}{}", ORDER_PATH, id));
+    let request = acme.verify_request(&body, &url, config)?;
+    let account = request.account()?;
+    let order = acme
+        .orders
+        .get(id)
+        .filter(|order| order.account == account)
+        .ok_or_else(|| Problem::not_found("No such order"))?;
+    AcmeResponse::json(Status::Ok, &order.to_object(id, config))
+}
+
+/// Return an ACME authorization of the account, with its `email-reply-00` challenge.
+#[utoipa::path(
+    post,
+    path = "/acme/authz/{id}",
+    params(("id" = String, Path, description = "The authorization identifier")),
+    request_body(content = String, description = "POST-as-GET JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The authorization"),
+        (status = 404, description = "Not Found"),
+    )
+)]
+#[post("/acme/authz/<id>", data = "<body>")]
+pub fn acme_authorization(
+    id: &str,
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let url = config.url(&format!("{}{}", AUTHORIZATION_PATH, id));
+    let request = acme.verify_request(&body, &url, config)?;
+    let account = request.account()?;
+    let authorization = acme
+        .authorizations
+        .get(id)
+        .filter(|authorization| authorization.account == account)
+        .ok_or_else(|| Problem::not_found("No such authorization"))?;
+    AcmeResponse::json(Status::Ok, &authorization.to_object(id, config))
+}
+
+/// Return an ACME challenge of the account, or respond to it with a `{}` payload.
+/// The email ownership is not verified yet, as for `/ca/register`: responding validates the authorization.
+#[utoipa::path(
+    post,
+    path = "/acme/chall/{id}",
+    params(("id" = String, Path, description = "The challenge identifier")),
+    request_body(content = String, description = "JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The challenge"),
+        (status = 404, description = "Not Found"),
+    )
+)]
+#[post("/acme/chall/<id>", data = "<body>")]
+pub fn acme_challenge(
+    id: &str,
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let url = config.url(&format!("{}{}", CHALLENGE_PATH, id));
+    let request = acme.verify_request(&body, &url, config)?;
+    let account = request.account()?;
+    let authorization = acme
+        .authorizations
+        .get_mut(id)
+        .filter(|authorization| authorization.account == account)
+        .ok_or_else(|| Problem::not_found("No such challenge"))?;
+    if !request.is_post_as_get() && authorization.status == AcmeStatus::Pending {
+        let (authorization_status, order_status) = if authorization.expires > unix_time() {
+            (AcmeStatus::Valid, AcmeStatus::Ready)
+        } else {
+            (AcmeStatus::Invalid, AcmeStatus::Invalid)
+        };
+        authorization.status = authorization_status;
+        let order_id = authorization.order.clone();
+        if let Some(order) = acme.orders.get_mut(&order_id) {
+            order.status = order_status;
+        }
+    }
+    let challenge = acme.authorizations[id].challenge(id, config);
+    Ok(AcmeResponse::json(Status::Ok, &challenge)?
+        .with_up(config.url(&format!("{}{}", AUTHORIZATION_PATH, id))))
+}
+
+/// Finalize a ready ACME order with a certificate request for its email, issuing the certificate.
+/// Each account has a certificate per email, registered as the `acme-<account>` device of the email:
+/// the following orders replace it.
+#[utoipa::path(
+    post,
+    path = "/acme/order/{id}/finalize",
+    params(("id" = String, Path, description = "The order identifier")),
+    request_body(content = String, description = "JWS signed with the account key, with the base64url DER certificate request", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "The finalized order"),
+        (status = 400, description = "Bad Request, invalid certificate request"),
+        (status = 403, description = "Forbidden, the order is not ready"),
+        (status = 404, description = "Not Found"),
+        (status = 429, description = "Too Many Requests, retry after the `Retry-After` seconds"),
+    )
+)]
+#[post("/acme/order/<id>/finalize", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn acme_finalize_order(
+    id: &str,
+    body: Vec<u8>,
+    rate_limit: RateLimit<'_>,
+    audit: Audit<'_>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+    state: &State<ServerStateArc>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    mut db: DbConnection,
+) -> Result<AcmeResponse, Problem> {
+    // Shorten the lifetime of the ACME state lock to not hold across the await boundaries.
+    let (account, email, csr) = {
+        let mut acme = acme.lock().unwrap();
+        let url = config.url(&format!("{}{}/finalize", ORDER_PATH, id));
+        let request = acme.verify_request(&body, &url, config)?;
+        let account = request.account()?.to_string();
+        let payload: FinalizePayload = request.payload()?;
+        let order = acme
+            .orders
+            .get_mut(id)
+            .filter(|order| order.account == account)
+            .ok_or_else(|| Problem::not_found("No such order"))?;
+        if order.status != AcmeStatus::Ready {
+            return Err(Problem::new(
+                Status::Forbidden,
+                "orderNotReady",
+                "The order is not ready to be finalized",
+            ));
+        }
+        audit.record(AuditOperation::Sign);
+        audit.email(&order.email);
+        audit.requester(format!("acme:{}", account));
+        rate_limit
+            .check(&order.email)
+            .map_err(|_| Problem::rate_limited())?;
+        order.status = AcmeStatus::Processing;
+        (account, order.email.clone(), payload.csr)
+    };
+    let issued =
+        issue_acme_certificate(&account, &email, &csr, state, validity, webhooks, &mut db).await;
+    let mut acme = acme.lock().unwrap();
+    let order = acme
+        .orders
+        .get_mut(id)
+        .ok_or_else(Problem::server_internal)?;
+    match issued {
+        Ok(certificate) => {
+            if let Ok(serial) = serial_of_certificate(&certificate) {
+                audit.serial(&hex(&serial));
+            }
+            order.status = AcmeStatus::Valid;
+            order.certificate = Some(certificate);
+        }
+        Err(problem) => {
+            // The client can retry with another certificate request.
+            order.status = if problem.is_bad_csr() {
+                AcmeStatus::Ready
+            } else {
+                AcmeStatus::Invalid
+            };
+            return Err(problem);
+        }
+    }
+    Ok(
+        AcmeResponse::json(Status::Ok, &order.to_object(id, config))?
+            .with_location(config.url(&format!("{}{}", ORDER_PATH, id))),
+    )
+}
+
+/// Sign the certificate request of an ACME order, then log and store the certificate.
+/// Returns the PEM encoded certificate followed by the chain of the CA.
+async fn issue_acme_certificate(
+    account: &str,
+    email: &str,
+    csr: &str,
+    state: &State<ServerStateArc>,
+    validity: &State<ValidityConfig>,
+    webhooks: &State<OptionalWebhooks>,
+    db: &mut DbConnection,
+) -> Result<String, Problem> {
+    let device = format!("acme-{}", account);
+    let current = find_certificate_by_device(email, &device, db)
+        .await
+        .map_err(|e| {
+            log::error!("Error reading the certificate from the DB: {:?}", e);
+            Problem::server_internal()
+        })?;
+    if current
+        .as_ref()
+        .is_some_and(|current| current.revoked_at.is_some())
+    {
+        return Err(Problem::unauthorized(
+            "The certificate of the account for this email was revoked",
+        ));
+    }
+    let csr = URL_SAFE_NO_PAD
+        .decode(csr)
+        .map_err(|_| Problem::bad_csr("Invalid certificate request encoding"))?;
+    let csr = pem::encode(&pem::Pem::new("CERTIFICATE REQUEST", csr));
+    let signed =
+        sign_certificate_request(&csr, email, CertificateProfile::ClientAuth, state, validity)
+            .await;
+    let SignedCertificate { issued, der, chain } = match signed {
+        Ok(signed) => signed,
+        Err(SignError::InvalidRequest(e)) => {
+            log::debug!("Error signing the certificate: {:?}", e);
+            return Err(Problem::bad_csr(
+                "The certificate request must be for the email of the order, with a key of an allowed algorithm",
+            ));
+        }
+        Err(SignError::Internal(e)) => {
+            log::error!("Error reading the signed certificate: {}", e);
+            return Err(Problem::server_internal());
+        }
+    };
+    log_issuance(email, &issued.certificate, &der, &issued.fingerprint, db)
+        .await
+        .map_err(|_| Problem::server_internal())?;
+    let replaces = current.as_ref().map(|current| current.serial.clone());
+    let stored = match current {
+        Some(current) => renew_certificate(current.id, &current.fingerprint, &issued, db).await,
+        None => insert_certificate(email, &device, &issued, None, db)
+            .await
+            .map(|_| true),
+    };
+    match stored {
+        Ok(true) => {
+            let event = match &replaces {
+                Some(replaces) => {
+                    METRICS.certificates_renewed.inc();
+                    LifecycleEvent::renewed(email, &device, &issued, replaces)
+                }
+                None => {
+                    METRICS.certificates_issued.inc();
+                    LifecycleEvent::issued(email, &device, &issued, None)
+                }
+            };
+            if let Some(webhooks) = webhooks.inner() {
+                webhooks.notify(&event);
+            }
+            log::debug!(
+                "Issued an ACME certificate for `{}`, device `{}`",
+                email,
+                device
+            );
+            Ok(format!("{}{}", issued.certificate, chain.concat()))
+        }
+        Ok(false) => Err(Problem::new(
+            Status::Conflict,
+            "serverInternal",
+            "The certificate was replaced or revoked concurrently",
+        )),
+        Err(e) => {
+            log::error!("Error storing the certificate in the DB: {:?}", e);
+            Err(Problem::server_internal())
+        }
+    }
+}
+
+/// Download the certificate of a finalized ACME order, followed by the chain of the CA.
+#[utoipa::path(
+    post,
+    path = "/acme/cert/{id}",
+    params(("id" = String, Path, description = "The order identifier")),
+    request_body(content = String, description = "POST-as-GET JWS signed with the account key", content_type = "application/jose+json"),
+    responses(
+        (status = 200, description = "PEM encoded certificate chain", content_type = "application/pem-certificate-chain"),
+        (status = 404, description = "Not Found"),
+    )
+)]
+#[post("/acme/cert/<id>", data = "<body>")]
+pub fn acme_certificate(
+    id: &str,
+    body: Vec<u8>,
+    acme: &State<AcmeStateArc>,
+    config: &State<AcmeConfig>,
+) -> Result<AcmeResponse, Problem> {
+    let mut acme = acme.lock().unwrap();
+    let url = config.url(&format!("{}{}", CERTIFICATE_PATH, id));
+    let request = acme.verify_request(&body, &url, config)?;
+    let account = request.account()?;
+    let certificate = acme
+        .orders
+        .get(id)
+        .filter(|order| order.account == account)
+        .and_then(|order| order.certificate.clone())
+        .ok_or_else(|| Problem::not_found("No such certificate"))?;
+    Ok(AcmeResponse::new(
+        Status::Ok,
+        ContentType::new("application", "pem-certificate-chain"),
+        certificate.into_bytes(),
+    ))
 }
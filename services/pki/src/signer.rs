@@ -0,0 +1,313 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The signing backends holding the private key of the CA issuing the certificates.
+//! The certificates, the CRLs, the OCSP responses and the tree heads of the transparency log are all signed through
+//! a [`Signer`], so that production deployments can keep the key in an HSM or a KMS instead of `private/ca`.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+use common::crypto::KeyAlgorithm;
+use rcgen::{KeyPair, PublicKeyData, RemoteKeyPair, SignatureAlgorithm, SubjectPublicKeyInfo};
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        self, EcdsaKeyPair, Ed25519KeyPair, KeyPair as _, UnparsedPublicKey, VerificationAlgorithm,
+        ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P384_SHA384_ASN1_SIGNING,
+    },
+};
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
+
+use crate::SignerConfig;
+
+/// The message signed at startup to check that an external signer holds the key of the CA certificate.
+const SELF_TEST_MESSAGE: &[u8] = b"shared-folder PKI signer self-test";
+
+/// Signs with the private key of the CA.
+pub trait Signer: Send + Sync {
+    /// The algorithm of the key.
+    fn algorithm(&self) -> KeyAlgorithm;
+
+    /// The public key in the raw format of [`KeyPair::public_key_raw`].
+    fn public_key(&self) -> &[u8];
+
+    /// Signs the message. The ECDSA signatures are DER encoded, as in the certificates.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+/// The signer of the CA shared by the handlers.
+pub type SignerArc = Arc<dyn Signer>;
+
+enum RingKeyPair {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// Signs with a key pair held in memory, as loaded from the files of the CA.
+pub struct KeyPairSigner {
+    algorithm: KeyAlgorithm,
+    key_pair: RingKeyPair,
+    rng: SystemRandom,
+}
+
+impl KeyPairSigner {
+    /// Create the signer from a key pair generated or loaded by rcgen.
+    pub fn new(key_pair: &KeyPair) -> Result<Self, String> {
+        let algorithm = KeyAlgorithm::from_signature_algorithm(key_pair.algorithm())
+            .ok_or_else(|| "Unsupported CA key algorithm".to_string())?;
+        let rng = SystemRandom::new();
+        let pkcs8 = key_pair.serialized_der();
+        let key_pair = match algorithm {
+            KeyAlgorithm::EcdsaP256 => RingKeyPair::Ecdsa(
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &rng)
+                    .map_err(|e| e.to_string())?,
+            ),
+            KeyAlgorithm::EcdsaP384 => RingKeyPair::Ecdsa(
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, pkcs8, &rng)
+                    .map_err(|e| e.to_string())?,
+            ),
+            KeyAlgorithm::Ed25519 => RingKeyPair::Ed25519(
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(|e| e.to_string())?,
+            ),
+        };
+        Ok(KeyPairSigner {
+            algorithm,
+            key_pair,
+            rng,
+        })
+    }
+}
+
+impl Signer for KeyPairSigner {
+    fn algorithm(&self) -> KeyAlgorithm {
+        self.algorithm
+    }
+
+    fn public_key(&self) -> &[u8] {
+        match &self.key_pair {
+            RingKeyPair::Ecdsa(key_pair) => key_pair.public_key().as_ref(),
+            RingKeyPair::Ed25519(key_pair) => key_pair.public_key().as_ref(),
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        match &self.key_pair {
+            RingKeyPair::Ecdsa(key_pair) => key_pair
+                .sign(&self.rng, message)
+                .map(|signature| signature.as_ref().to_vec())
+                .map_err(|e| e.to_string()),
+            RingKeyPair::Ed25519(key_pair) => Ok(key_pair.sign(message).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Signs by running an external program, e.g. `pkcs11-tool` for a PKCS#11 HSM or the command line client of a KMS,
+/// so that the private key never reaches the server.
+/// The program reads the message from its standard input and writes the signature to its standard output.
+/// The public key is read from the certificate of the CA.
+pub struct CommandSigner {
+    program: String,
+    args: Vec<String>,
+    algorithm: KeyAlgorithm,
+    public_key: Vec<u8>,
+}
+
+impl CommandSigner {
+    /// Create the signer for the CA with the given PEM encoded certificate.
+    /// The program is run once to check that it signs with the key of the certificate.
+    pub fn new(config: &SignerConfig, ca_cert_pem: &str) -> Result<Self, String> {
+        let (algorithm, public_key) = public_key_of_certificate(ca_cert_pem)?;
+        let signer = CommandSigner {
+            program: config.program.clone(),
+            args: config.args.clone(),
+            algorithm,
+            public_key,
+        };
+        let signature = signer.sign(SELF_TEST_MESSAGE)?;
+        verify(algorithm, &signer.public_key, SELF_TEST_MESSAGE, &signature).map_err(|_| {
+            format!(
+                "The signer `{}` doesn't sign with the key of the CA certificate",
+                signer.program
+            )
+        })?;
+        Ok(signer)
+    }
+}
+
+impl Signer for CommandSigner {
+    fn algorithm(&self) -> KeyAlgorithm {
+        self.algorithm
+    }
+
+    fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Couldn't run the signer `{}`: {}", self.program, e))?;
+        // Close the standard input once the message is written, so that the program can sign it.
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| "The standard input of the signer is missing".to_string())?
+            .write_all(message)
+            .map_err(|e| format!("Couldn't write to the signer `{}`: {}", self.program, e))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Couldn't read from the signer `{}`: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "The signer `{}` failed with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Exposes a [`Signer`] as an rcgen key pair, to sign the certificates and the CRLs.
+struct RemoteSigner(SignerArc);
+
+impl RemoteKeyPair for RemoteSigner {
+    fn public_key(&self) -> &[u8] {
+        self.0.public_key()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        self.0.sign(msg).map_err(|e| {
+            log::error!("Error signing with the CA key: {}", e);
+            rcgen::Error::RemoteKeyError
+        })
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        self.0.algorithm().signature_algorithm()
+    }
+}
+
+/// The rcgen key pair signing through the given signer, see [`load_ca_with_key_pair`](common::crypto::load_ca_with_key_pair).
+pub fn remote_key_pair(signer: SignerArc) -> Result<KeyPair, rcgen::Error> {
+    KeyPair::from_remote(Box::new(RemoteSigner(signer)))
+}
+
+/// The key algorithm and the raw public key of the PEM encoded certificate.
+fn public_key_of_certificate(pem_certificate: &str) -> Result<(KeyAlgorithm, Vec<u8>), String> {
+    let der = pem::parse(pem_certificate).map_err(|e| e.to_string())?;
+    let (_, certificate) = X509Certificate::from_der(der.contents()).map_err(|e| e.to_string())?;
+    let spki =
+        SubjectPublicKeyInfo::from_der(certificate.public_key().raw).map_err(|e| e.to_string())?;
+    let algorithm = KeyAlgorithm::from_signature_algorithm(spki.algorithm())
+        .ok_or_else(|| "Unsupported CA key algorithm".to_string())?;
+    Ok((algorithm, spki.der_bytes().to_vec()))
+}
+
+/// Verifies the signature of the message with the raw public key.
+/// Checks that the signer holds the key of the PEM encoded CA certificate, signing a test message,
+/// e.g. that the key of `private/ca` was restored together with the certificate.
+pub fn self_test(signer: &dyn Signer, ca_cert_pem: &str) -> Result<(), String> {
+    let (algorithm, public_key) = public_key_of_certificate(ca_cert_pem)?;
+    let signature = signer.sign(SELF_TEST_MESSAGE)?;
+    verify(algorithm, &public_key, SELF_TEST_MESSAGE, &signature)
+        .map_err(|_| "The signer doesn't sign with the key of the CA certificate".to_string())
+}
+
+fn verify(
+    algorithm: KeyAlgorithm,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), ring::error::Unspecified> {
+    let verification_algorithm: &'static dyn VerificationAlgorithm = match algorithm {
+        KeyAlgorithm::EcdsaP256 => &signature::ECDSA_P256_SHA256_ASN1,
+        KeyAlgorithm::EcdsaP384 => &signature::ECDSA_P384_SHA384_ASN1,
+        KeyAlgorithm::Ed25519 => &signature::ED25519,
+    };
+    UnparsedPublicKey::new(verification_algorithm, public_key).verify(message, signature)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use common::crypto::{
+        check_signature, load_ca_with_key_pair, mk_client_certificate, mk_issuer_ca,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_key_pair_signer() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let signer = KeyPairSigner::new(&ca_ck.key_pair).unwrap();
+        assert_eq!(signer.algorithm(), KeyAlgorithm::EcdsaP256);
+        assert_eq!(signer.public_key(), ca_ck.key_pair.public_key_raw());
+        let signature = signer.sign(b"message").unwrap();
+        assert!(verify(
+            signer.algorithm(),
+            signer.public_key(),
+            b"message",
+            &signature
+        )
+        .is_ok());
+        assert_eq!(
+            public_key_of_certificate(&ca_ck.cert.pem()).unwrap(),
+            (KeyAlgorithm::EcdsaP256, signer.public_key().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_self_test() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let signer = KeyPairSigner::new(&ca_ck.key_pair).unwrap();
+        assert!(self_test(&signer, &ca_ck.cert.pem()).is_ok());
+        let other_ca_cert_pem = mk_issuer_ca().unwrap().cert.pem();
+        assert!(self_test(&signer, &other_ca_cert_pem).is_err());
+    }
+
+    #[test]
+    fn test_remote_key_pair_signs_certificates() {
+        let ca_ck = mk_issuer_ca().unwrap();
+        let signer: SignerArc = Arc::new(KeyPairSigner::new(&ca_ck.key_pair).unwrap());
+        let remote_ca_ck =
+            load_ca_with_key_pair(&ca_ck.cert.pem(), remote_key_pair(signer).unwrap()).unwrap();
+        let client_ck = mk_client_certificate(&remote_ca_ck).unwrap();
+        assert!(check_signature(&client_ck.cert.pem(), &ca_ck.cert.pem()).unwrap());
+    }
+
+    #[test]
+    fn test_command_signer_checks_the_key() {
+        let ca_cert_pem = mk_issuer_ca().unwrap().cert.pem();
+        // `cat` echoes the message instead of signing it.
+        let config = SignerConfig {
+            program: "cat".to_string(),
+            args: Vec::new(),
+        };
+        assert!(CommandSigner::new(&config, &ca_cert_pem).is_err());
+        let config = SignerConfig {
+            program: "false".to_string(),
+            args: Vec::new(),
+        };
+        assert!(CommandSigner::new(&config, &ca_cert_pem).is_err());
+    }
+}
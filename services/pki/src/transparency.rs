@@ -0,0 +1,307 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! An append-only log of the certificates issued by the CA, as a Merkle tree in the style of
+//! [RFC 6962](https://www.rfc-editor.org/rfc/rfc6962#section-2.1).
+//! Clients can monitor the log for certificates issued for their email that they did not request.
+use ring::digest::{digest, Context, SHA256};
+
+/// The SHA-256 hash of a leaf or of a node of the tree.
+pub type Hash = [u8; 32];
+
+/// The domain separation prefix of the leaf hashes.
+const LEAF_PREFIX: u8 = 0x00;
+/// The domain separation prefix of the node hashes.
+const NODE_PREFIX: u8 = 0x01;
+/// The version of the tree head signature and of the leaves.
+const VERSION: u8 = 0;
+/// The signature type of the tree head, as in RFC 6962.
+const TREE_HASH_SIGNATURE_TYPE: u8 = 1;
+
+/// Returns the hash of the leaf logging the issuance of the DER encoded certificate for the email,
+/// at the given time in milliseconds since the Unix epoch.
+/// The leaf data is `version || timestamp || u16 length || email || u24 length || certificate`.
+pub fn leaf_hash(email: &str, der_certificate: &[u8], logged_at: u64) -> Result<Hash, String> {
+    let email_length =
+        u16::try_from(email.len()).map_err(|_| "The email is too long".to_string())?;
+    if der_certificate.len() >= 1 << 24 {
+        return Err("The certificate is too long".to_string());
+    }
+    let mut context = Context::new(&SHA256);
+    context.update(&[LEAF_PREFIX, VERSION]);
+    context.update(&logged_at.to_be_bytes());
+    context.update(&email_length.to_be_bytes());
+    context.update(email.as_bytes());
+    context.update(&(der_certificate.len() as u32).to_be_bytes()[1..]);
+    context.update(der_certificate);
+    Ok(to_hash(context.finish().as_ref()))
+}
+
+/// Returns the hash of an inner node of the tree.
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut context = Context::new(&SHA256);
+    context.update(&[NODE_PREFIX]);
+    context.update(left);
+    context.update(right);
+    to_hash(context.finish().as_ref())
+}
+
+/// Converts a stored hash, returns `None` if it is not a SHA-256 hash.
+pub fn hash_from_slice(bytes: &[u8]) -> Option<Hash> {
+    bytes.try_into().ok()
+}
+
+fn to_hash(bytes: &[u8]) -> Hash {
+    hash_from_slice(bytes).expect("SHA-256 digests are 32 bytes long")
+}
+
+/// The largest power of two smaller than `n`, with `n > 1`.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Returns the root hash of the tree with the given leaves, the hash of the empty string if there are none.
+pub fn root_hash(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => to_hash(digest(&SHA256, &[]).as_ref()),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root_hash(&leaves[..k]), &root_hash(&leaves[k..]))
+        }
+    }
+}
+
+/// Returns the audit path of the leaf at the given index, from the leaf to the root.
+/// Returns `None` if the index is not in the tree.
+pub fn inclusion_proof(index: usize, leaves: &[Hash]) -> Option<Vec<Hash>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut proof = Vec::new();
+    path(index, leaves, &mut proof);
+    Some(proof)
+}
+
+fn path(index: usize, leaves: &[Hash], proof: &mut Vec<Hash>) {
+    let n = leaves.len();
+    if n <= 1 {
+        return;
+    }
+    let k = split(n);
+    if index < k {
+        path(index, &leaves[..k], proof);
+        proof.push(root_hash(&leaves[k..]));
+    } else {
+        path(index - k, &leaves[k..], proof);
+        proof.push(root_hash(&leaves[..k]));
+    }
+}
+
+/// Returns the proof that the tree with the first `size` leaves is a prefix of the tree with all the leaves.
+/// Returns `None` if `size` is zero or larger than the tree.
+pub fn consistency_proof(size: usize, leaves: &[Hash]) -> Option<Vec<Hash>> {
+    if size == 0 || size > leaves.len() {
+        return None;
+    }
+    let mut proof = Vec::new();
+    subproof(size, leaves, true, &mut proof);
+    Some(proof)
+}
+
+fn subproof(size: usize, leaves: &[Hash], complete: bool, proof: &mut Vec<Hash>) {
+    let n = leaves.len();
+    if size == n {
+        if !complete {
+            proof.push(root_hash(leaves));
+        }
+        return;
+    }
+    let k = split(n);
+    if size <= k {
+        subproof(size, &leaves[..k], complete, proof);
+        proof.push(root_hash(&leaves[k..]));
+    } else {
+        subproof(size - k, &leaves[k..], false, proof);
+        proof.push(root_hash(&leaves[..k]));
+    }
+}
+
+/// Returns the data signed by the CA in the signed tree head.
+/// It is `version || signature type || timestamp || tree size || root hash`, as in RFC 6962.
+pub fn tree_head_signature_input(timestamp: u64, tree_size: u64, root_hash: &Hash) -> Vec<u8> {
+    let mut input = Vec::with_capacity(2 + 8 + 8 + root_hash.len());
+    input.push(VERSION);
+    input.push(TREE_HASH_SIGNATURE_TYPE);
+    input.extend_from_slice(&timestamp.to_be_bytes());
+    input.extend_from_slice(&tree_size.to_be_bytes());
+    input.extend_from_slice(root_hash);
+    input
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n)
+            .map(|i| leaf_hash(&format!("user{}@test.com", i), &[i as u8; 8], i as u64).unwrap())
+            .collect()
+    }
+
+    /// Verifies an inclusion proof as in RFC 9162, section 2.1.3.2.
+    fn verify_inclusion(
+        index: usize,
+        size: usize,
+        leaf: &Hash,
+        proof: &[Hash],
+        root: &Hash,
+    ) -> bool {
+        if index >= size {
+            return false;
+        }
+        let (mut f_n, mut s_n) = (index, size - 1);
+        let mut r = *leaf;
+        for p in proof {
+            if s_n == 0 {
+                return false;
+            }
+            if f_n & 1 == 1 || f_n == s_n {
+                r = node_hash(p, &r);
+                while f_n & 1 == 0 && f_n != 0 {
+                    f_n >>= 1;
+                    s_n >>= 1;
+                }
+            } else {
+                r = node_hash(&r, p);
+            }
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+        s_n == 0 && r == *root
+    }
+
+    /// Verifies a consistency proof as in RFC 9162, section 2.1.4.2.
+    fn verify_consistency(
+        first: usize,
+        second: usize,
+        first_root: &Hash,
+        second_root: &Hash,
+        proof: &[Hash],
+    ) -> bool {
+        if first == second {
+            return proof.is_empty() && first_root == second_root;
+        }
+        let mut proof = proof.to_vec();
+        if first.is_power_of_two() {
+            proof.insert(0, *first_root);
+        }
+        let (mut f_n, mut s_n) = (first - 1, second - 1);
+        while f_n & 1 == 1 {
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+        let Some((first_hash, rest)) = proof.split_first() else {
+            return false;
+        };
+        let (mut f_r, mut s_r) = (*first_hash, *first_hash);
+        for c in rest {
+            if s_n == 0 {
+                return false;
+            }
+            if f_n & 1 == 1 || f_n == s_n {
+                f_r = node_hash(c, &f_r);
+                s_r = node_hash(c, &s_r);
+                while f_n & 1 == 0 && f_n != 0 {
+                    f_n >>= 1;
+                    s_n >>= 1;
+                }
+            } else {
+                s_r = node_hash(&s_r, c);
+            }
+            f_n >>= 1;
+            s_n >>= 1;
+        }
+        f_r == *first_root && s_r == *second_root && s_n == 0
+    }
+
+    #[test]
+    fn test_root_hash() {
+        let leaves = leaves(3);
+        assert_eq!(
+            root_hash(&[]).to_vec(),
+            digest(&SHA256, &[]).as_ref().to_vec()
+        );
+        assert_eq!(root_hash(&leaves[..1]), leaves[0]);
+        assert_eq!(
+            root_hash(&leaves),
+            node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2])
+        );
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        for size in 1..=17 {
+            let leaves = leaves(size);
+            let root = root_hash(&leaves);
+            for index in 0..size {
+                let proof = inclusion_proof(index, &leaves).unwrap();
+                assert!(verify_inclusion(index, size, &leaves[index], &proof, &root));
+                assert!(!verify_inclusion(
+                    index,
+                    size,
+                    &leaf_hash("shadow@test.com", &[], 0).unwrap(),
+                    &proof,
+                    &root
+                ));
+            }
+            assert!(inclusion_proof(size, &leaves).is_none());
+        }
+    }
+
+    #[test]
+    fn test_consistency_proofs() {
+        let all = leaves(17);
+        for second in 1..=all.len() {
+            let second_root = root_hash(&all[..second]);
+            for first in 1..=second {
+                let proof = consistency_proof(first, &all[..second]).unwrap();
+                let first_root = root_hash(&all[..first]);
+                assert!(verify_consistency(
+                    first,
+                    second,
+                    &first_root,
+                    &second_root,
+                    &proof
+                ));
+                if first < second {
+                    // A tree whose history was rewritten is not consistent.
+                    assert!(!verify_consistency(
+                        first,
+                        second,
+                        &all[second - 1],
+                        &second_root,
+                        &proof
+                    ));
+                }
+            }
+        }
+        assert!(consistency_proof(0, &all).is_none());
+        assert!(consistency_proof(18, &all).is_none());
+    }
+}
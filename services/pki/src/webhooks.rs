@@ -0,0 +1,234 @@
+// Copyright (C) 2024 Nicola Dardanis <nicdard@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free Software
+// Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+//
+//! The webhooks called on the lifecycle events of the certificates: issuance, renewal and revocation.
+//! The JSON payload is signed with HMAC-SHA256 and the secret shared with the receivers, the hex encoded signature
+//! is sent in the [`SIGNATURE_HEADER`] as `sha256=<signature>`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+use serde::Serialize;
+
+use crate::{
+    crl::{hex, RevocationReason},
+    db::{CertificateEntity, IssuedCertificate},
+    WebhooksConfig,
+};
+
+/// The header carrying the signature of the payload.
+pub const SIGNATURE_HEADER: &str = "X-PKI-Signature";
+
+/// The lifecycle events of the certificates.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEventKind {
+    /// A certificate was issued for a new device, or for a device whose certificate was revoked.
+    Issued,
+    /// The certificate of a device was replaced before being revoked.
+    Renewed,
+    /// A certificate was revoked.
+    Revoked,
+}
+
+/// The payload of the webhooks.
+#[derive(Serialize, Debug)]
+pub struct LifecycleEvent {
+    pub event: LifecycleEventKind,
+    pub email: String,
+    /// The label of the device of the user holding the certificate.
+    pub device: String,
+    /// The hex encoded serial number of the certificate.
+    pub serial: String,
+    /// The hex encoded SHA-256 fingerprint of the DER encoded certificate.
+    pub fingerprint: String,
+    /// The hex encoded serial number of the certificate replaced, the renewed one or the revoked one re-issued.
+    pub replaces: Option<String>,
+    /// The reason of a revocation.
+    pub reason: Option<RevocationReason>,
+    /// When the event happened, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl LifecycleEvent {
+    /// A certificate issued for the device, replacing its revoked certificate if any.
+    pub fn issued(
+        email: &str,
+        device: &str,
+        issued: &IssuedCertificate,
+        replaces: Option<&str>,
+    ) -> Self {
+        LifecycleEvent {
+            event: LifecycleEventKind::Issued,
+            email: email.to_string(),
+            device: device.to_string(),
+            serial: issued.serial.clone(),
+            fingerprint: issued.fingerprint.clone(),
+            replaces: replaces.map(str::to_string),
+            reason: None,
+            timestamp: now(),
+        }
+    }
+
+    /// A certificate issued for the device, replacing its current certificate.
+    pub fn renewed(email: &str, device: &str, issued: &IssuedCertificate, replaces: &str) -> Self {
+        LifecycleEvent {
+            event: LifecycleEventKind::Renewed,
+            replaces: Some(replaces.to_string()),
+            ..LifecycleEvent::issued(email, device, issued, None)
+        }
+    }
+
+    /// The stored certificate revoked for the reason.
+    pub fn revoked(revoked: &CertificateEntity, reason: RevocationReason) -> Self {
+        LifecycleEvent {
+            event: LifecycleEventKind::Revoked,
+            email: revoked.email.clone(),
+            device: revoked.device.clone(),
+            serial: revoked.serial.clone(),
+            fingerprint: revoked.fingerprint.clone(),
+            replaces: None,
+            reason: Some(reason),
+            timestamp: now(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Calls the webhooks configured by the operators, e.g. so that the DS invalidates the cached verifications
+/// or a user directory is kept in sync without polling.
+pub struct Webhooks {
+    client: reqwest::Client,
+    urls: Vec<String>,
+    key: hmac::Key,
+}
+
+/// The optional webhooks, to be used as managed state in Rocket. No webhook is called if missing.
+pub type OptionalWebhooks = Option<Webhooks>;
+
+impl Webhooks {
+    /// Create the webhooks, trusting the PEM encoded chain of the CA for the TLS connections on top of the
+    /// native roots, as the receivers may have a certificate issued by the PKI, like the DS.
+    pub fn from_config(config: &WebhooksConfig, ca_chain: &[String]) -> Result<Self, String> {
+        if config.secret.is_empty() {
+            return Err("The secret of the webhooks is required".to_string());
+        }
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_millis(config.timeout));
+        for pem in ca_chain {
+            let certificate = reqwest::Certificate::from_pem(pem.as_bytes())
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Couldn't initialise the HTTP client: {}", e))?;
+        Ok(Webhooks {
+            client,
+            urls: config.urls.clone(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+        })
+    }
+
+    /// Call the webhooks with the event in the background, the failures are only logged.
+    pub fn notify(&self, event: &LifecycleEvent) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Couldn't serialize the webhook payload: `{}`", e);
+                return;
+            }
+        };
+        let signature = sign(&self.key, &body);
+        for url in &self.urls {
+            let request = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone());
+            let url = url.clone();
+            let kind = event.event;
+            tokio::spawn(async move {
+                match request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                {
+                    Ok(_) => log::debug!("Called the webhook `{}` for the {:?} event", url, kind),
+                    Err(e) => log::warn!(
+                        "Couldn't call the webhook `{}` for the {:?} event: `{}`",
+                        url,
+                        kind,
+                        e
+                    ),
+                }
+            });
+        }
+    }
+}
+
+/// The value of the [`SIGNATURE_HEADER`] of the payload.
+pub fn sign(key: &hmac::Key, body: &[u8]) -> String {
+    format!("sha256={}", hex(hmac::sign(key, body).as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn issued() -> IssuedCertificate {
+        IssuedCertificate {
+            certificate: String::new(),
+            fingerprint: "ab".repeat(32),
+            spki_sha256: "cd".repeat(32),
+            serial: "01".to_string(),
+            profile: Default::default(),
+            not_before: 1_700_000_000,
+            not_after: 1_800_000_000,
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_event_payload() {
+        let event = LifecycleEvent::renewed("test@test.com", "laptop", &issued(), "02");
+        let payload = serde_json::to_value(&event).unwrap();
+        assert_eq!(payload["event"], "renewed");
+        assert_eq!(payload["email"], "test@test.com");
+        assert_eq!(payload["device"], "laptop");
+        assert_eq!(payload["serial"], "01");
+        assert_eq!(payload["replaces"], "02");
+        assert!(payload["reason"].is_null());
+    }
+
+    #[test]
+    fn test_sign() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let body = br#"{"event":"issued"}"#;
+        let signature = sign(&key, body);
+        let signature = signature.strip_prefix("sha256=").unwrap();
+        assert_eq!(signature.len(), 64);
+        let tag = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        assert!(hmac::verify(&key, body, &tag).is_ok());
+        assert!(hmac::verify(&key, b"tampered", &tag).is_err());
+    }
+}
@@ -23,7 +23,9 @@ DEFAULT CHARSET = UTF8;
 
 -- Table to store the folders
 CREATE TABLE folders (
-    folder_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY
+    folder_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    -- The display name of the folder, encrypted by the clients, empty if not set.
+    display_blob BLOB NOT NULL DEFAULT (x'')
     -- same folder_name could be used by different users.
     -- folder_name VARCHAR(36) NOT NULL,
 ) ENGINE =INNODB
@@ -33,6 +35,8 @@ DEFAULT CHARSET = UTF8;
 CREATE TABLE folders_users (
     folder_id INT UNSIGNED NOT NULL,
     user_email VARCHAR(100) NOT NULL,
+    -- Only the admins can share the folder and manage its members.
+    role ENUM('admin', 'member') NOT NULL DEFAULT 'member',
     FOREIGN KEY (folder_id) REFERENCES folders(folder_id),
     FOREIGN KEY (user_email) REFERENCES users(user_email),
     PRIMARY KEY (folder_id, user_email),
@@ -48,9 +52,12 @@ CREATE TABLE pending_group_messages (
     user_email VARCHAR(100) NOT NULL,
     payload BLOB NOT NULL,
     creator VARCHAR(100) NOT NULL,
+    -- Used to expire the messages of the users that don't come back.
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
     FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
-    INDEX ( user_email, folder_id )
+    INDEX ( user_email, folder_id ),
+    INDEX ( created_at )
 ) ENGINE =INNODB
 DEFAULT CHARSET = UTF8;
 
@@ -80,6 +87,150 @@ CREATE TABLE key_packages (
     key_package_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
     key_package BLOB,
     user_email VARCHAR(100) NOT NULL,
+    -- The end of the lifetime of the key package in seconds since the Unix epoch, NULL if it couldn't be parsed.
+    not_after BIGINT UNSIGNED NULL,
+    INDEX ( not_after ),
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Store the resumable file uploads in progress, each one mapped to a multipart upload of the object store.
+CREATE TABLE file_uploads (
+    upload_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    folder_id INT UNSIGNED NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    user_email VARCHAR(100) NOT NULL,
+    multipart_id VARCHAR(1024) NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
     FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE
 ) ENGINE =INNODB
 DEFAULT CHARSET = UTF8;
+
+-- Store the parts already uploaded for each resumable upload.
+CREATE TABLE file_upload_parts (
+    upload_id INT UNSIGNED NOT NULL,
+    part_number INT UNSIGNED NOT NULL,
+    content_id VARCHAR(1024) NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (upload_id, part_number),
+    FOREIGN KEY (upload_id) REFERENCES file_uploads(upload_id) ON DELETE CASCADE
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Store the size of each file, to enforce the storage quotas (`usage` is a reserved word).
+CREATE TABLE storage_usage (
+    folder_id INT UNSIGNED NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    -- The user that uploaded the last version of the file.
+    user_email VARCHAR(100) NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, file_id),
+    INDEX ( user_email )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Reference count of the deduplicated file contents of each folder, stored once by their SHA-256 hash.
+CREATE TABLE blobs (
+    folder_id INT UNSIGNED NOT NULL,
+    content_hash CHAR(64) NOT NULL,
+    ref_count INT UNSIGNED NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, content_hash)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- The blob each file points to, the references to the blobs are counted from these rows and never from the content
+-- of the objects. The files in the trash keep their row with the time of their deletion, 0 for the current files.
+CREATE TABLE file_blobs (
+    folder_id INT UNSIGNED NOT NULL,
+    file_id VARCHAR(255) NOT NULL,
+    deleted_at BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    content_hash CHAR(64) NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, file_id, deleted_at)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- The Web Push subscriptions of the users, to wake up their clients when they are not connected.
+-- The public key (P-256) and the authentication secret of the subscription are base64url encoded.
+CREATE TABLE push_subscriptions (
+    subscription_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    user_email VARCHAR(100) NOT NULL,
+    endpoint VARCHAR(1000) NOT NULL,
+    p256dh VARCHAR(100) NOT NULL,
+    auth VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT push_endpoint_unique UNIQUE (endpoint),
+    INDEX ( user_email )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- The latest epoch of the MLS groups of each folder accepted in a proposal, older proposals are rejected.
+CREATE TABLE group_epochs (
+    folder_id INT UNSIGNED NOT NULL,
+    group_id VARCHAR(40) NOT NULL,
+    epoch BIGINT UNSIGNED NOT NULL,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    PRIMARY KEY (folder_id, group_id)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- The read-only share links of the folders, only the SHA-256 hash of their bearer token is stored.
+-- The links stop working once they expire, or their creator leaves the folder.
+CREATE TABLE share_links (
+    link_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    folder_id INT UNSIGNED NOT NULL,
+    creator_email VARCHAR(100) NOT NULL,
+    token_hash CHAR(64) NOT NULL,
+    -- The expiration time, in seconds since the Unix epoch.
+    expires_at BIGINT UNSIGNED NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (creator_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT share_link_token_unique UNIQUE (token_hash),
+    INDEX ( folder_id )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Append-only log of the security-relevant events of each folder.
+-- The rows are not bound to the folders and users, so that the trail outlives them.
+CREATE TABLE audit_log (
+    event_id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    folder_id INT UNSIGNED NOT NULL,
+    actor_email VARCHAR(100) NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    -- The subject of the action, e.g. the file or the user the folder has been shared with.
+    target VARCHAR(255),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX ( folder_id, event_id )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Tombstones of the deleted accounts, so that their emails can't be registered again.
+-- Only a SHA-256 hash of the email is kept, the personal data of the user are removed.
+CREATE TABLE deleted_users (
+    email_hash CHAR(64) NOT NULL PRIMARY KEY,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Pending invitations to join a folder, the invitee becomes a member only once it accepts.
+-- The MLS proposal adding the invitee and its welcome message are published on acceptance, empty if missing.
+CREATE TABLE invitations (
+    invitation_id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    folder_id INT UNSIGNED NOT NULL,
+    inviter_email VARCHAR(100) NOT NULL,
+    invitee_email VARCHAR(100) NOT NULL,
+    proposal BLOB NOT NULL,
+    welcome BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (folder_id) REFERENCES folders(folder_id) ON DELETE CASCADE,
+    FOREIGN KEY (inviter_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    FOREIGN KEY (invitee_email) REFERENCES users(user_email) ON DELETE CASCADE,
+    CONSTRAINT folder_invitee_unique UNIQUE (folder_id, invitee_email),
+    INDEX ( invitee_email )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
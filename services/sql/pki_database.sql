@@ -20,10 +20,88 @@ CREATE TABLE certificates (
     -- However, addresses should fit in MAIL and RCPT command of 254 characters: https://www.rfc-editor.org/errata_search.php?rfc=3696&eid=1690
     -- We impose a stricter limit: https://stackoverflow.com/questions/1297272/how-long-should-sql-email-fields-be
     email VARCHAR(100) NOT NULL,
+    -- The label of the device holding the certificate, a user can register one certificate per device
+    device VARCHAR(64) NOT NULL DEFAULT 'default',
+    -- The hex encoded SHA-256 fingerprint of the DER encoded certificate
+    fingerprint CHAR(64) NOT NULL,
+    -- The hex encoded SHA-256 digest of the DER encoded SubjectPublicKeyInfo of the certificate, to look it up by key
+    spki_sha256 CHAR(64) NOT NULL,
+    -- The hex encoded serial number of the certificate, at most 20 bytes as per RFC 5280
+    serial VARCHAR(40) NOT NULL,
     -- The certificate in PEM format
     certificate TEXT NOT NULL,
+    -- The issuance profile of the certificate: `client-auth`, `server-auth` or `ds-service`
+    profile VARCHAR(16) NOT NULL DEFAULT 'client-auth',
+    -- The validity period of the certificate, in seconds since the Unix epoch
+    not_before BIGINT UNSIGNED NOT NULL,
+    not_after BIGINT UNSIGNED NOT NULL,
+    -- `active`, or `revoked` once revoked_at is set
+    status VARCHAR(16) NOT NULL DEFAULT 'active',
+    -- When the certificate was revoked, in seconds since the Unix epoch, NULL if it is valid
+    revoked_at BIGINT UNSIGNED NULL DEFAULT NULL,
+    -- The RFC 5280 reason code of the revocation
+    revocation_reason TINYINT UNSIGNED NULL DEFAULT NULL,
+    -- The serial number of the revoked certificate of the same device this certificate was re-issued for, if any
+    replaces VARCHAR(40) NULL DEFAULT NULL,
+    -- The device of the certificate while it is active, NULL once revoked, so that a revoked device can register again
+    active_device VARCHAR(64) AS (IF(status = 'active', device, NULL)) STORED,
     -- Create an index on the first 4 characters of the email to speed up queries
     INDEX( email(4) ),
-    CONSTRAINT email_unique UNIQUE (email)
+    -- List the revoked certificates for the CRL and the active ones by expiry
+    INDEX( status, not_after ),
+    -- Look up the certificates by public key, e.g. the key of an MLS leaf
+    INDEX( spki_sha256 ),
+    CONSTRAINT email_device_unique UNIQUE (email, active_device),
+    CONSTRAINT fingerprint_unique UNIQUE (fingerprint),
+    CONSTRAINT serial_unique UNIQUE (serial),
+    CONSTRAINT replaces_fk FOREIGN KEY (replaces) REFERENCES certificates (serial)
 ) ENGINE =INNODB
 DEFAULT CHARSET = UTF8;
+
+-- Append-only Merkle tree log of the issued certificates, see the `transparency` module of the PKI
+CREATE TABLE transparency_log (
+    -- The index of the leaf in the tree, starting from 0 without gaps
+    leaf_index BIGINT UNSIGNED NOT NULL PRIMARY KEY,
+    -- The email the certificate was issued for
+    email VARCHAR(100) NOT NULL,
+    -- The issued certificate in PEM format
+    certificate TEXT NOT NULL,
+    -- The hex encoded SHA-256 fingerprint of the DER encoded certificate
+    fingerprint CHAR(64) NOT NULL,
+    -- When the certificate was logged, in milliseconds since the Unix epoch
+    logged_at BIGINT UNSIGNED NOT NULL,
+    -- The hash of the leaf of the Merkle tree
+    leaf_hash BINARY(32) NOT NULL,
+    CONSTRAINT log_fingerprint_unique UNIQUE (fingerprint)
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Append-only log of the sign, verify and revoke operations, see the `audit` module of the PKI
+CREATE TABLE audit_log (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    -- When the operation was answered, in milliseconds since the Unix epoch
+    logged_at BIGINT UNSIGNED NOT NULL,
+    -- One of `sign`, `verify` and `revoke`
+    operation VARCHAR(16) NOT NULL,
+    -- The emails of the client certificate of the requester, comma separated, or the ACME account
+    requester TEXT NULL DEFAULT NULL,
+    -- The IP address of the requester
+    requester_ip VARCHAR(45) NULL DEFAULT NULL,
+    -- The email of the certificate the operation is about
+    email VARCHAR(100) NULL DEFAULT NULL,
+    -- The hex encoded serial number of the certificate the operation is about
+    serial VARCHAR(40) NULL DEFAULT NULL,
+    -- The HTTP status of the response
+    status SMALLINT UNSIGNED NOT NULL,
+    -- `success` or `failure`, or the result of a verification: `valid`, `expired`, `revoked`, `invalid` or `batch`
+    outcome VARCHAR(16) NOT NULL,
+    INDEX( email(4) ),
+    INDEX( serial )
+) ENGINE =INNODB
+DEFAULT CHARSET = UTF8;
+
+-- Reject the changes to the audit log, the events can only be appended
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log FOR EACH ROW
+    SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'The audit log is append-only';
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log FOR EACH ROW
+    SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'The audit log is append-only';
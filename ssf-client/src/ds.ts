@@ -11,7 +11,9 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-import { CrateService as dsclient } from './gen/clients/ds';
+import axios from 'axios';
+import { CrateService as dsclient, OpenAPI as dsOpenAPI } from './gen/clients/ds';
+import { request as __request } from './gen/clients/ds/core/request';
 import { PathLike, readFileSync } from 'fs';
 import { getClientCertificate, localIsValid } from './pki';
 import { randomString } from './protocol/commonCrypto';
@@ -178,13 +180,20 @@ export async function downloadFile(
   const metadataContent = new Uint8Array(
     metadata_content as unknown as ArrayBuffer
   );
+  // The server streams the raw file content, read it as binary instead of JSON.
   const encryptedFileContent = new Uint8Array(
-    (
-      await dsclient.getFile({
-        fileId,
-        folderId,
-      })
-    ).file as unknown as ArrayBuffer
+    await __request<ArrayBuffer>(
+      dsOpenAPI,
+      {
+        method: 'GET',
+        url: '/folders/{folder_id}/files/{file_id}',
+        path: {
+          folder_id: folderId,
+          file_id: fileId,
+        },
+      },
+      axios.create({ responseType: 'arraybuffer' })
+    )
   );
   // Decrypt the file.
   return protocolClient.readFile({
@@ -11,8 +11,17 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 //
-import { mkClientCertificateRequestParams, verifyCertificate } from 'common';
-import { CrateService as pkiclient } from './gen/clients/pki';
+import {
+  mkClientCertificateRequestParams,
+  signChallenge,
+  verifyCertificate,
+} from 'common';
+import {
+  OpenAPI,
+  RegisterResponse,
+  CrateService as pkiclient,
+} from './gen/clients/pki';
+import { request as __request } from './gen/clients/pki/core/request';
 import { loadCaTLSCredentials } from './protocol/authentication';
 
 /**
@@ -23,10 +32,28 @@ export async function createClientCertificate(
   email: string
 ): Promise<[string, string]> {
   const { keyPair, signingRequest } = mkClientCertificateRequestParams(email);
-  const { certificate } = await pkiclient.register({
-    requestBody: {
+  // Prove the possession of the private key of the certificate request by signing a challenge of the PKI.
+  const { challenge } = await __request<{ challenge: string }>(OpenAPI, {
+    method: 'POST',
+    url: '/ca/register/challenge',
+    body: { email },
+    mediaType: 'application/json',
+  });
+  const signature = signChallenge(keyPair, challenge);
+  const { certificate } = await __request<RegisterResponse>(OpenAPI, {
+    method: 'POST',
+    url: '/ca/register',
+    body: {
       email,
       certificate_request: signingRequest,
+      challenge,
+      challenge_signature: Buffer.from(signature).toString('base64'),
+    },
+    mediaType: 'application/json',
+    errors: {
+      400: 'Bad Request',
+      403: 'Forbidden',
+      409: 'Conflict',
     },
   });
   return [certificate, keyPair];
@@ -15,6 +15,22 @@ import { loadCaTLSCredentials, loadTLSCredentials } from './authentication';
 import { OpenAPI } from '../gen/clients/ds';
 import EventSource = require('eventsource');
 
+/**
+ * A notification pushed by the DS, see `Notification` in the DS OpenAPI schema.
+ */
+export type Notification = {
+  event:
+    | 'proposal'
+    | 'share'
+    | 'welcome'
+    | 'key_package_consumed'
+    | 'file_uploaded'
+    | 'file_deleted'
+    | 'state_reset';
+  folder_id?: number | null;
+  message_id?: number | null;
+};
+
 export function createSSENotificationReceiver(
   onmessage: (data: bigint) => void,
   mTlSOptions?: {
@@ -45,7 +61,9 @@ export function createSSENotificationReceiver(
     });
     receiver.addEventListener('message', (data: MessageEvent<string>) => {
       try {
-        onmessage(BigInt(data?.data));
+        const notification: Notification = JSON.parse(data?.data);
+        // -1 indicates that a key package has been consumed.
+        onmessage(BigInt(notification.folder_id ?? -1));
       } catch (e) {
         console.log(data);
         console.error(e);
@@ -82,6 +82,41 @@ cfg_if! {
                 .map_err(|e| e.to_string())
         }
 
+        /// Build the uid of one of the devices of a user, to be used in place of the plain email.
+        #[wasm_bindgen(js_name = mlsDeviceUid)]
+        pub fn mls_device_uid(email: &str, device_id: &str) -> Result<Vec<u8>, String> {
+            set_panic_hook();
+            mls::device_uid(email, device_id).map_err(|e| e.to_string())
+        }
+
+        /// Propose the addition of another device of the same user given its [`KeyPackage`] (given as an [`MlsMessage`]).
+        #[wasm_bindgen(js_name = mlsCgkaAddOwnDevice)]
+        pub async fn mls_cgka_add_own_device(uid: &[u8], group_id: &[u8], key_package_raw_msg: &[u8]) -> Result<AddProposalMessages, String> {
+            set_panic_hook();
+            mls::cgka_add_own_device(uid, group_id, key_package_raw_msg)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        /// List the device uids of a user that are members of the group.
+        #[wasm_bindgen(js_name = mlsCgkaListDevices)]
+        pub async fn mls_cgka_list_devices(uid: &[u8], group_id: &[u8], email: &str) -> Result<Vec<String>, String> {
+            set_panic_hook();
+            mls::cgka_list_devices(uid, group_id, email.as_bytes())
+                .await
+                .map(|devices| devices.iter().map(|d| String::from_utf8_lossy(d).to_string()).collect())
+                .map_err(|e| e.to_string())
+        }
+
+        /// Propose and commit the removal of a single device, without removing the other devices of the same user.
+        #[wasm_bindgen(js_name = mlsCgkaRemoveDevice)]
+        pub async fn mls_cgka_remove_device(uid: &[u8], group_id: &[u8], device_uid: &[u8]) -> Result<Vec<u8>, String> {
+            set_panic_hook();
+            mls::cgka_remove_device(uid, group_id, device_uid)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
         #[wasm_bindgen(js_name = mlsCgkaUpdateKeys)]
         pub async fn mls_cgka_update_proposal(uid: &[u8], group_id: &[u8]) -> Result<Vec<u8>, String> {
             set_panic_hook();
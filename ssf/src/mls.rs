@@ -228,6 +228,113 @@ pub async fn cgka_remove_proposal(
     t_msg
 }
 
+/// Separates the user identity (the email) from the device identifier inside a device scoped uid.
+/// Email domains cannot contain this character, so the last occurrence always delimits the device.
+const DEVICE_SEPARATOR: char = '#';
+
+/// Errors raised by the multi-device helpers.
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    #[error(transparent)]
+    Mls(#[from] MlsError),
+    #[error("Invalid device identifier `{0}`.")]
+    InvalidDeviceId(String),
+    #[error("The key package does not belong to a device of `{0}`.")]
+    ForeignDevice(String),
+    #[error("No device `{0}` found in the group.")]
+    DeviceNotFound(String),
+}
+
+/// Build the uid of a device of a user, used as the identity of the leaf in the group.
+/// The same email can then join a group from several devices, each one with its own leaf.
+pub fn device_uid(email: &str, device_id: &str) -> Result<Vec<u8>, DeviceError> {
+    if device_id.is_empty() || device_id.contains(DEVICE_SEPARATOR) {
+        return Err(DeviceError::InvalidDeviceId(device_id.to_string()));
+    }
+    Ok(format!("{email}{DEVICE_SEPARATOR}{device_id}").into_bytes())
+}
+
+/// Returns the user identity (the email) a uid belongs to.
+/// Legacy uids, without a device identifier, are the user identity themselves.
+pub fn user_of_uid(uid: &[u8]) -> Vec<u8> {
+    let uid = String::from_utf8_lossy(uid);
+    match uid.rsplit_once(DEVICE_SEPARATOR) {
+        Some((user, _)) => user.as_bytes().to_vec(),
+        None => uid.as_bytes().to_vec(),
+    }
+}
+
+/// Returns the identity stored in the basic credential of a signing identity, if any.
+fn basic_identity(signing_identity: &SigningIdentity) -> Option<Vec<u8>> {
+    signing_identity
+        .credential
+        .as_basic()
+        .map(|basic| basic.identifier.clone())
+}
+
+/// Add another device of the same user to the group, given its [`KeyPackage`] (as an [`MlsMessage`]).
+/// This works like [`cgka_add_proposal`] but refuses key packages generated by devices of other users.
+pub async fn cgka_add_own_device(
+    uid: &[u8],
+    group_id: &[u8],
+    key_package_raw_msg: &[u8],
+) -> Result<AddProposalMessages, DeviceError> {
+    let key_package_mls_msg = MlsMessage::from_bytes(key_package_raw_msg)?;
+    let user = user_of_uid(uid);
+    let is_own_device = key_package_mls_msg
+        .clone()
+        .into_key_package()
+        .and_then(|key_package| basic_identity(key_package.signing_identity()))
+        .is_some_and(|identity| identity != uid && user_of_uid(&identity) == user);
+    if !is_own_device {
+        return Err(DeviceError::ForeignDevice(
+            String::from_utf8_lossy(&user).to_string(),
+        ));
+    }
+    Ok(cgka_add_proposal(uid, group_id, key_package_raw_msg).await?)
+}
+
+/// List the uids of all the devices of the given user that are members of the group.
+pub async fn cgka_list_devices(
+    uid: &[u8],
+    group_id: &[u8],
+    user: &[u8],
+) -> Result<Vec<Vec<u8>>, DeviceError> {
+    let group = cgka_load_group(uid, group_id).await?;
+    Ok(group
+        .roster()
+        .members()
+        .iter()
+        .filter_map(|member| basic_identity(&member.signing_identity))
+        .filter(|identity| user_of_uid(identity) == user)
+        .collect())
+}
+
+/// Propose and commit the removal of a single device.
+/// The other devices of the same user remain in the group.
+pub async fn cgka_remove_device(
+    uid: &[u8],
+    group_id: &[u8],
+    device_uid: &[u8],
+) -> Result<Vec<u8>, DeviceError> {
+    let mut group = cgka_load_group(uid, group_id).await?;
+    let member = group
+        .roster()
+        .members()
+        .into_iter()
+        .find(|member| basic_identity(&member.signing_identity).as_deref() == Some(device_uid))
+        .ok_or_else(|| {
+            DeviceError::DeviceNotFound(String::from_utf8_lossy(device_uid).to_string())
+        })?;
+    let commit = group
+        .commit_builder()
+        .remove_member(member.index)?
+        .build()
+        .await?;
+    group.write_to_storage().await?;
+    Ok(commit.commit_message.to_bytes()?)
+}
+
 /// Propose and commit an update.
 /// Update proposals are not necessary in this implementation, as we always immediately commit afterwards.
 pub async fn cgka_update_proposal(uid: &[u8], group_id: &[u8]) -> Result<Vec<u8>, MlsError> {
@@ -384,8 +491,9 @@ mod test {
     use crate::{
         log,
         mls::{cgka_delete_pending_commit, cgka_load_group},
-        mls_cgka_add_proposal, mls_cgka_apply_pending_commit, mls_cgka_delete_pending_commit,
-        mls_cgka_init, mls_cgka_join_group, mls_cgka_update_proposal, mls_generate_key_package,
+        mls_cgka_add_own_device, mls_cgka_add_proposal, mls_cgka_apply_pending_commit,
+        mls_cgka_delete_pending_commit, mls_cgka_init, mls_cgka_join_group, mls_cgka_list_devices,
+        mls_cgka_remove_device, mls_cgka_update_proposal, mls_device_uid, mls_generate_key_package,
         mls_init_client,
         utils::set_panic_hook,
     };
@@ -424,6 +532,36 @@ mod test {
         Ok(())
     }
 
+    #[wasm_bindgen_test::wasm_bindgen_test]
+    async fn test_own_devices() -> Result<(), String> {
+        let laptop = mls_device_uid("alice@test.com", "laptop")?;
+        let phone = mls_device_uid("alice@test.com", "phone")?;
+        let group_id = vec![6u8, 7, 8];
+        mls_init_client(&laptop).await?;
+        mls_cgka_init(&laptop, &group_id).await?;
+        mls_init_client(&phone).await?;
+        let key_package = mls_generate_key_package(&phone).await?;
+        let proposal = mls_cgka_add_own_device(&laptop, &group_id, &key_package).await?;
+        mls_cgka_apply_pending_commit(&laptop, &group_id).await?;
+        mls_cgka_join_group(&phone, &proposal.welcome_msg).await?;
+        let devices = mls_cgka_list_devices(&laptop, &group_id, "alice@test.com").await?;
+        assert_eq!(2, devices.len());
+        // Devices of other users are refused.
+        let bob = mls_device_uid("bob@test.com", "laptop")?;
+        mls_init_client(&bob).await?;
+        let bob_key_package = mls_generate_key_package(&bob).await?;
+        assert!(
+            mls_cgka_add_own_device(&laptop, &group_id, &bob_key_package)
+                .await
+                .is_err()
+        );
+        mls_cgka_remove_device(&laptop, &group_id, &phone).await?;
+        mls_cgka_apply_pending_commit(&laptop, &group_id).await?;
+        let devices = mls_cgka_list_devices(&laptop, &group_id, "alice@test.com").await?;
+        assert_eq!(vec!["alice@test.com#laptop".to_string()], devices);
+        Ok(())
+    }
+
     #[wasm_bindgen_test::wasm_bindgen_test]
     async fn test_cgka_init() -> Result<(), MlsError> {
         set_panic_hook();